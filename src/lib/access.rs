@@ -16,17 +16,31 @@
 //
 
 use concread::cowcell::{CowCell, CowCellReadTxn, CowCellWriteTxn};
+use lru::LruCache;
+use rayon::prelude::*;
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::audit::AuditScope;
+use crate::constants::RESOLVED_FILTER_CACHE_SIZE;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryNormalised, EntryReduced, EntryValid};
 use crate::error::OperationError;
-use crate::filter::{Filter, FilterValid};
+use crate::filter::{Filter, FilterValid, FilterValidResolved};
+use crate::metrics::{self, Counter};
 use crate::modify::Modify;
 use crate::proto::v1::Filter as ProtoFilter;
+use crate::proto::v1::{
+    AccessControlProfileInfo, EffectivePermissions, MemoryUse, SearchTraceAccess,
+};
 use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
 
-use crate::event::{CreateEvent, DeleteEvent, EventOrigin, ModifyEvent, SearchEvent};
+use crate::event::{
+    CompareEvent, CreateEvent, DeleteEvent, Event, EventOrigin, ModifyEvent, SearchEvent,
+};
 
 // =========================================================================
 // PARSE ENTRY TO ACP, AND ACP MANAGEMENT
@@ -56,7 +70,7 @@ impl AccessControlSearch {
             value
                 .get_ava("acp_search_attr")
                 .ok_or(OperationError::InvalidACPState("Missing acp_search_attr"))
-                .map(|vs: &Vec<String>| vs.clone())
+                .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
         );
 
         let acp = AccessControlProfile::try_from(audit, qs, value)?;
@@ -79,17 +93,172 @@ impl AccessControlSearch {
             acp: AccessControlProfile {
                 name: name.to_string(),
                 uuid: uuid.to_string(),
-                receiver: receiver,
-                targetscope: targetscope,
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(targetscope),
             },
             attrs: attrs.split_whitespace().map(|s| s.to_string()).collect(),
         }
     }
 }
 
+// The right to test whether an attribute of an entry holds a value. This
+// never makes the attribute visible, so a client can check a value it already
+// knows (such as a group member) without being able to list them all.
+#[derive(Debug, Clone)]
+pub struct AccessControlCompare {
+    acp: AccessControlProfile,
+    attrs: Vec<String>,
+}
+
+impl AccessControlCompare {
+    pub fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", "access_control_compare") {
+            audit_log!(audit, "class access_control_compare not present.");
+            return Err(OperationError::InvalidACPState(
+                "Missing access_control_compare",
+            ));
+        }
+
+        let attrs = try_audit!(
+            audit,
+            value
+                .get_ava("acp_compare_attr")
+                .ok_or(OperationError::InvalidACPState("Missing acp_compare_attr"))
+                .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+        );
+
+        Ok(AccessControlCompare {
+            acp: AccessControlProfile::try_from(audit, qs, value)?,
+            attrs: attrs,
+        })
+    }
+
+    #[cfg(test)]
+    unsafe fn from_raw(
+        name: &str,
+        uuid: &str,
+        receiver: Filter<FilterValid>,
+        targetscope: Filter<FilterValid>,
+        attrs: &str,
+    ) -> Self {
+        AccessControlCompare {
+            acp: AccessControlProfile {
+                name: name.to_string(),
+                uuid: uuid.to_string(),
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(targetscope),
+            },
+            attrs: attrs.split_whitespace().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+// The right to read the persisted audit records of operations initiated by
+// entries in the targetscope. The records aren't entries, so this is the
+// only profile that grants it.
+#[derive(Debug, Clone)]
+pub struct AccessControlAuditRead {
+    acp: AccessControlProfile,
+}
+
+impl AccessControlAuditRead {
+    pub fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", "access_control_audit_read") {
+            audit_log!(audit, "class access_control_audit_read not present.");
+            return Err(OperationError::InvalidACPState(
+                "Missing access_control_audit_read",
+            ));
+        }
+
+        Ok(AccessControlAuditRead {
+            acp: AccessControlProfile::try_from(audit, qs, value)?,
+        })
+    }
+}
+
+// The right to run server operations that aren't about particular entries,
+// like a backup or a search trace. The operations are named by the profile,
+// and only its receiver is used.
+#[derive(Debug, Clone)]
+pub struct AccessControlOperation {
+    acp: AccessControlProfile,
+    operations: Vec<String>,
+}
+
+impl AccessControlOperation {
+    pub fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", "access_control_operation") {
+            audit_log!(audit, "class access_control_operation not present.");
+            return Err(OperationError::InvalidACPState(
+                "Missing access_control_operation",
+            ));
+        }
+
+        let operations = try_audit!(
+            audit,
+            value
+                .get_ava("acp_operation")
+                .ok_or(OperationError::InvalidACPState("Missing acp_operation"))
+        );
+
+        Ok(AccessControlOperation {
+            acp: AccessControlProfile::try_from(audit, qs, value)?,
+            operations: operations.iter().map(|v| v.to_string()).collect(),
+        })
+    }
+
+    #[cfg(test)]
+    unsafe fn from_raw(
+        name: &str,
+        uuid: &str,
+        receiver: Filter<FilterValid>,
+        operations: &str,
+    ) -> Self {
+        AccessControlOperation {
+            acp: AccessControlProfile {
+                name: name.to_string(),
+                uuid: uuid.to_string(),
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(filter_valid!(f_pres("class"))),
+            },
+            operations: operations
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AccessControlDelete {
     acp: AccessControlProfile,
+    // If not empty, the profile only covers entries with one of these classes,
+    // whatever else the targetscope matches.
+    classes: Vec<String>,
 }
 
 impl AccessControlDelete {
@@ -105,25 +274,46 @@ impl AccessControlDelete {
             ));
         }
 
+        let classes = value
+            .get_ava("acp_delete_class")
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+            .unwrap_or_else(|| Vec::new());
+
         Ok(AccessControlDelete {
             acp: AccessControlProfile::try_from(audit, qs, value)?,
+            classes: classes,
         })
     }
 
+    // Is the entry of a class this profile may cover?
+    fn class_match(&self, e: &Entry<EntryValid, EntryCommitted>) -> bool {
+        self.classes.is_empty()
+            || self
+                .classes
+                .iter()
+                .any(|c| e.attribute_value_pres("class", c.as_str()))
+    }
+
     #[cfg(test)]
     unsafe fn from_raw(
         name: &str,
         uuid: &str,
         receiver: Filter<FilterValid>,
         targetscope: Filter<FilterValid>,
+        classes: &str,
     ) -> Self {
         AccessControlDelete {
             acp: AccessControlProfile {
                 name: name.to_string(),
                 uuid: uuid.to_string(),
-                receiver: receiver,
-                targetscope: targetscope,
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(targetscope),
             },
+            classes: classes.split_whitespace().map(|s| s.to_string()).collect(),
         }
     }
 }
@@ -150,12 +340,12 @@ impl AccessControlCreate {
 
         let attrs = value
             .get_ava("acp_create_attr")
-            .map(|vs: &Vec<String>| vs.clone())
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
             .unwrap_or_else(|| Vec::new());
 
         let classes = value
             .get_ava("acp_create_class")
-            .map(|vs: &Vec<String>| vs.clone())
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
             .unwrap_or_else(|| Vec::new());
 
         Ok(AccessControlCreate {
@@ -178,8 +368,12 @@ impl AccessControlCreate {
             acp: AccessControlProfile {
                 name: name.to_string(),
                 uuid: uuid.to_string(),
-                receiver: receiver,
-                targetscope: targetscope,
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(targetscope),
             },
             classes: classes.split_whitespace().map(|s| s.to_string()).collect(),
             attrs: attrs.split_whitespace().map(|s| s.to_string()).collect(),
@@ -190,9 +384,55 @@ impl AccessControlCreate {
 #[derive(Debug, Clone)]
 pub struct AccessControlModify {
     acp: AccessControlProfile,
-    classes: Vec<String>,
+    addclasses: Vec<String>,
+    remclasses: Vec<String>,
     presattrs: Vec<String>,
     remattrs: Vec<String>,
+    // Optional per attribute constraints on which values may be added or
+    // removed. An attribute with no entry here may take any value.
+    presvalues: BTreeMap<String, Vec<String>>,
+    remvalues: BTreeMap<String, Vec<String>>,
+}
+
+// Parse "attr=value" constraints into a map of attr to allowed values.
+fn parse_value_constraints(
+    audit: &mut AuditScope,
+    values: Option<&Vec<Value>>,
+    err: &'static str,
+) -> Result<BTreeMap<String, Vec<String>>, OperationError> {
+    let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for v in values.map(|vs| vs.iter()).into_iter().flatten() {
+        let v = v.to_string();
+        let mut parts = v.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(a), Some(val)) if !a.is_empty() && !val.is_empty() => {
+                map.entry(a.to_lowercase())
+                    .or_insert_with(Vec::new)
+                    .push(val.to_string());
+            }
+            _ => {
+                audit_log!(audit, "Invalid value constraint {:?}", v);
+                return Err(OperationError::InvalidACPState(err));
+            }
+        }
+    }
+    Ok(map)
+}
+
+// Is this value allowed by an acp's constraints for the attribute? The value
+// "self" stands for the uuid of the entry making the change.
+fn modify_value_allowed(
+    constraints: &BTreeMap<String, Vec<String>>,
+    attr: &str,
+    value: &str,
+    self_uuid: &str,
+) -> bool {
+    match constraints.get(attr) {
+        None => true,
+        Some(vs) => vs
+            .iter()
+            .any(|v| v == value || (v == "self" && value == self_uuid)),
+    }
 }
 
 impl AccessControlModify {
@@ -210,24 +450,52 @@ impl AccessControlModify {
 
         let presattrs = value
             .get_ava("acp_modify_presentattr")
-            .map(|vs: &Vec<String>| vs.clone())
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
             .unwrap_or_else(|| Vec::new());
 
         let remattrs = value
             .get_ava("acp_modify_removedattr")
-            .map(|vs: &Vec<String>| vs.clone())
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
             .unwrap_or_else(|| Vec::new());
 
+        // acp_modify_class predates the split into add and remove, so for
+        // compatibility it grants both.
         let classes = value
             .get_ava("acp_modify_class")
-            .map(|vs: &Vec<String>| vs.clone())
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+            .unwrap_or_else(|| Vec::new());
+
+        let mut addclasses = value
+            .get_ava("acp_modify_class_add")
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+            .unwrap_or_else(|| Vec::new());
+        addclasses.extend(classes.iter().cloned());
+
+        let mut remclasses = value
+            .get_ava("acp_modify_class_remove")
+            .map(|vs: &Vec<Value>| vs.iter().map(|v| v.to_string()).collect::<Vec<_>>())
             .unwrap_or_else(|| Vec::new());
+        remclasses.extend(classes.into_iter());
+
+        let presvalues = parse_value_constraints(
+            audit,
+            value.get_ava("acp_modify_presentvalue"),
+            "Invalid acp_modify_presentvalue",
+        )?;
+        let remvalues = parse_value_constraints(
+            audit,
+            value.get_ava("acp_modify_removedvalue"),
+            "Invalid acp_modify_removedvalue",
+        )?;
 
         Ok(AccessControlModify {
             acp: AccessControlProfile::try_from(audit, qs, value)?,
-            classes: classes,
+            addclasses: addclasses,
+            remclasses: remclasses,
             presattrs: presattrs,
             remattrs: remattrs,
+            presvalues: presvalues,
+            remvalues: remvalues,
         })
     }
 
@@ -245,28 +513,74 @@ impl AccessControlModify {
             acp: AccessControlProfile {
                 name: name.to_string(),
                 uuid: uuid.to_string(),
-                receiver: receiver,
-                targetscope: targetscope,
+                deny: false,
+                mode: AccessControlMode::Enforce,
+                description: None,
+                ticket_refs: Vec::new(),
+                receiver: Arc::new(receiver),
+                targetscope: Arc::new(targetscope),
             },
-            classes: classes.split_whitespace().map(|s| s.to_string()).collect(),
+            addclasses: classes.split_whitespace().map(|s| s.to_string()).collect(),
+            remclasses: classes.split_whitespace().map(|s| s.to_string()).collect(),
             presattrs: presattrs
                 .split_whitespace()
                 .map(|s| s.to_string())
                 .collect(),
             remattrs: remattrs.split_whitespace().map(|s| s.to_string()).collect(),
+            presvalues: BTreeMap::new(),
+            remvalues: BTreeMap::new(),
         }
     }
 }
 
+// How a profile takes part in access decisions. LogOnly lets an operator stage
+// a new profile and watch what it would do, before enforcing it: the decision
+// is made both with and without it, and any difference is written to the
+// audit log, but only the enforced result is used.
+#[derive(Debug, Clone, PartialEq)]
+enum AccessControlMode {
+    Enforce,
+    LogOnly,
+    Disabled,
+}
+
 #[derive(Debug, Clone)]
 struct AccessControlProfile {
     name: String,
     uuid: String,
-    receiver: Filter<FilterValid>,
-    targetscope: Filter<FilterValid>,
+    // If true, this profile removes rights rather than granting them, and
+    // always takes precedence over any allow.
+    deny: bool,
+    mode: AccessControlMode,
+    // Why the profile exists, so reports can explain access and not only
+    // show it.
+    description: Option<String>,
+    ticket_refs: Vec<String>,
+    // Shared, so copying the acps for a write transaction doesn't copy the
+    // filters too.
+    receiver: Arc<Filter<FilterValid>>,
+    targetscope: Arc<Filter<FilterValid>>,
+}
+
+// Roughly how much memory some strings take.
+fn strs_size(v: &[String]) -> usize {
+    v.iter().map(|s| mem::size_of::<String>() + s.len()).sum()
+}
+
+fn value_constraints_size(m: &BTreeMap<String, Vec<String>>) -> usize {
+    m.iter().map(|(k, vs)| k.len() + strs_size(vs)).sum()
 }
 
 impl AccessControlProfile {
+    // Roughly how much memory the profile takes, leaving out its filters.
+    fn approx_size(&self) -> usize {
+        mem::size_of::<AccessControlProfile>()
+            + self.name.len()
+            + self.uuid.len()
+            + self.description.as_ref().map(|d| d.len()).unwrap_or(0)
+            + strs_size(&self.ticket_refs)
+    }
+
     fn try_from(
         audit: &mut AuditScope,
         qs: &QueryServerWriteTransaction,
@@ -307,7 +621,7 @@ impl AccessControlProfile {
         audit_log!(audit, "RAW receiver {:?}", receiver_raw);
         let receiver_f: ProtoFilter = try_audit!(
             audit,
-            serde_json::from_str(receiver_raw.as_str())
+            serde_json::from_str(receiver_raw.to_string().as_str())
                 .map_err(|_| OperationError::InvalidACPState("Invalid acp_receiver"))
         );
         let receiver_i = try_audit!(audit, Filter::from_rw(audit, &receiver_f, qs));
@@ -321,7 +635,7 @@ impl AccessControlProfile {
         audit_log!(audit, "RAW tscope {:?}", targetscope_raw);
         let targetscope_f: ProtoFilter = try_audit!(
             audit,
-            serde_json::from_str(targetscope_raw.as_str()).map_err(|e| {
+            serde_json::from_str(targetscope_raw.to_string().as_str()).map_err(|e| {
                 audit_log!(audit, "JSON error {:?}", e);
                 OperationError::InvalidACPState("Invalid acp_targetscope")
             })
@@ -334,13 +648,43 @@ impl AccessControlProfile {
                 .map_err(|e| OperationError::SchemaViolation(e))
         );
 
+        let deny = value.attribute_value_pres("class", "access_control_deny");
+
+        // Schema requires acp_enable on a stored profile, so this default
+        // only applies to profiles that never went through schema.
+        let mode = match value.get_ava_single_bool("acp_enable") {
+            Some(true) | None => AccessControlMode::Enforce,
+            Some(false) => match value.get_ava_single_bool("acp_log_only") {
+                Some(true) => AccessControlMode::LogOnly,
+                _ => AccessControlMode::Disabled,
+            },
+        };
+
         Ok(AccessControlProfile {
-            name: name.clone(),
+            name: name.to_string(),
             uuid: uuid.clone(),
-            receiver: receiver,
-            targetscope: targetscope,
+            deny: deny,
+            mode: mode,
+            description: value
+                .get_ava("description")
+                .and_then(|vs| vs.first().map(|v| v.to_string())),
+            ticket_refs: value
+                .get_ava("acp_ticket_ref")
+                .map(|vs| vs.iter().map(|v| v.to_string()).collect())
+                .unwrap_or_default(),
+            receiver: Arc::new(receiver),
+            targetscope: Arc::new(targetscope),
         })
     }
+
+    fn to_info(&self) -> AccessControlProfileInfo {
+        AccessControlProfileInfo {
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            description: self.description.clone(),
+            ticket_refs: self.ticket_refs.clone(),
+        }
+    }
 }
 
 // =========================================================================
@@ -354,6 +698,9 @@ pub struct AccessControlsInner {
     acps_create: BTreeMap<String, AccessControlCreate>,
     acps_modify: BTreeMap<String, AccessControlModify>,
     acps_delete: BTreeMap<String, AccessControlDelete>,
+    acps_compare: BTreeMap<String, AccessControlCompare>,
+    acps_audit_read: BTreeMap<String, AccessControlAuditRead>,
+    acps_operation: BTreeMap<String, AccessControlOperation>,
 }
 
 impl AccessControlsInner {
@@ -363,183 +710,725 @@ impl AccessControlsInner {
             acps_create: BTreeMap::new(),
             acps_modify: BTreeMap::new(),
             acps_delete: BTreeMap::new(),
+            acps_compare: BTreeMap::new(),
+            acps_audit_read: BTreeMap::new(),
+            acps_operation: BTreeMap::new(),
         }
     }
 }
 
-pub struct AccessControls {
-    inner: CowCell<AccessControlsInner>,
+// Each kind of access control is parsed from the profiles of its class, and
+// kept in its own set keyed by the profile uuid. This lets every set be
+// loaded and updated the same way.
+pub trait AccessControlKind: Sized {
+    const CLASS: &'static str;
+
+    fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError>;
+
+    fn uuid(&self) -> &str;
+
+    fn get_set_mut(inner: &mut AccessControlsInner) -> &mut BTreeMap<String, Self>;
 }
 
-pub trait AccessControlsTransaction {
-    fn get_inner(&self) -> &AccessControlsInner;
+macro_rules! impl_access_control_kind {
+    ($acp_type:ident, $class:expr, $set:ident) => {
+        impl AccessControlKind for $acp_type {
+            const CLASS: &'static str = $class;
+
+            fn try_from(
+                audit: &mut AuditScope,
+                qs: &QueryServerWriteTransaction,
+                value: &Entry<EntryValid, EntryCommitted>,
+            ) -> Result<Self, OperationError> {
+                $acp_type::try_from(audit, qs, value)
+            }
 
-    // Contains all the way to eval acps to entries
-    fn search_filter_entries(
-        &self,
-        audit: &mut AuditScope,
-        se: &SearchEvent,
-        entries: Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", se);
+            fn uuid(&self) -> &str {
+                self.acp.uuid.as_str()
+            }
 
-        // If this is an internal search, return our working set.
-        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
-            EventOrigin::Internal => {
-                audit_log!(audit, "Internal operation, bypassing access check");
-                // No need to check ACS
-                return Ok(entries);
+            fn get_set_mut(inner: &mut AccessControlsInner) -> &mut BTreeMap<String, Self> {
+                &mut inner.$set
             }
-            EventOrigin::User(e) => &e,
-        };
+        }
+    };
+}
 
-        // Some useful references we'll use for the remainder of the operation
-        let state = self.get_inner();
+impl_access_control_kind!(AccessControlSearch, "access_control_search", acps_search);
+impl_access_control_kind!(AccessControlCreate, "access_control_create", acps_create);
+impl_access_control_kind!(AccessControlModify, "access_control_modify", acps_modify);
+impl_access_control_kind!(AccessControlDelete, "access_control_delete", acps_delete);
+impl_access_control_kind!(AccessControlCompare, "access_control_compare", acps_compare);
+impl_access_control_kind!(
+    AccessControlAuditRead,
+    "access_control_audit_read",
+    acps_audit_read
+);
+impl_access_control_kind!(
+    AccessControlOperation,
+    "access_control_operation",
+    acps_operation
+);
 
-        // First get the set of acps that apply to this receiver
-        let related_acp: Vec<&AccessControlSearch> = state
-            .acps_search
-            .iter()
-            .filter_map(|(_, acs)| {
-                // Now resolve the receiver filter
-                // Okay, so in filter resolution, the primary error case
-                // is that we have a non-user in the event. We have already
-                // checked for this above BUT we should still check here
-                // properly just in case.
-                //
-                // In this case, we assume that if the event is internal
-                // that the receiver can NOT match because it has no selfuuid
-                // and can as a result, never return true. This leads to this
-                // acp not being considered in that case ... which should never
-                // happen because we already bypassed internal ops above!
-                //
-                // A possible solution is to change the filter resolve function
-                // such that it takes an entry, rather than an event, but that
-                // would create issues in search.
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&se.event) {
-                    Ok(f_res) => {
-                        if rec_entry.entry_match_no_index(&f_res) {
-                            Some(acs)
-                        } else {
-                            None
-                        }
-                    }
-                    Err(e) => {
-                        audit_log!(
-                            audit,
-                            "A internal filter was passed for resolution!?!? {:?}",
-                            e
-                        );
-                        None
-                    }
-                }
-            })
-            .collect();
+pub struct AccessControls {
+    inner: CowCell<AccessControlsInner>,
+}
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+type ResolvedFilters = RefCell<LruCache<(String, String), Rc<Filter<FilterValidResolved>>>>;
+
+// Resolved receiver and targetscope filters, keyed by the acp uuid and the
+// uuid of the event initiator. An operation tests the same few acps against
+// every candidate entry, so resolving each only once saves a great deal of
+// work on large result sets. The cache lives as long as the transaction, and
+// is emptied whenever the acps are updated. A long write transaction can
+// act for many identities, so the least recently used are dropped past
+// capacity.
+pub struct ResolvedFilterCache {
+    capacity: usize,
+    receivers: ResolvedFilters,
+    targetscopes: ResolvedFilters,
+}
 
-        // Get the set of attributes requested by this se filter. This is what we are
-        // going to access check.
-        let requested_attrs: BTreeSet<&str> = se.filter_orig.get_attr_set();
+impl ResolvedFilterCache {
+    fn new() -> Self {
+        Self::with_capacity(RESOLVED_FILTER_CACHE_SIZE)
+    }
 
-        // For each entry
-        let allowed_entries: Vec<Entry<EntryValid, EntryCommitted>> = entries
-            .into_iter()
-            .filter(|e| {
-                // For each acp
-                let allowed_attrs: BTreeSet<&str> = related_acp
-                    .iter()
-                    .filter_map(|acs| {
-                        let f_val = acs.acp.targetscope.clone();
-                        match f_val.resolve(&se.event) {
-                            Ok(f_res) => {
-                                // if it applies
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    // add search_attrs to allowed.
-                                    let r: Vec<&str> =
-                                        acs.attrs.iter().map(|s| s.as_str()).collect();
-                                    Some(r)
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    None
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    })
-                    .flatten()
-                    .collect();
+    fn with_capacity(capacity: usize) -> Self {
+        ResolvedFilterCache {
+            capacity: capacity,
+            receivers: RefCell::new(LruCache::new(capacity)),
+            targetscopes: RefCell::new(LruCache::new(capacity)),
+        }
+    }
 
-                audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
-                audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
-                audit_log!(audit, "requested attributes --> {:?}", requested_attrs);
+    fn resolve_in(
+        cache: &ResolvedFilters,
+        acp: &AccessControlProfile,
+        f: &Filter<FilterValid>,
+        ev: &Event,
+    ) -> Result<Rc<Filter<FilterValidResolved>>, OperationError> {
+        let key = match &ev.origin {
+            EventOrigin::User(e) => (acp.uuid.clone(), e.get_uuid().clone()),
+            // Internal events never reach acp evaluation, so don't bother
+            // caching for them.
+            EventOrigin::Internal => return f.resolve(ev).map(Rc::new),
+        };
 
-                // is attr set a subset of allowed set?
-                // true -> entry is allowed in result set
-                // false -> the entry is not allowed to be searched by this entity, so is
-                //          excluded.
-                requested_attrs.is_subset(&allowed_attrs)
-            })
-            .collect();
+        if let Some(f_res) = cache.borrow_mut().get(&key) {
+            metrics::incr(Counter::FilterCacheHit, 1);
+            return Ok(f_res.clone());
+        }
 
-        Ok(allowed_entries)
+        metrics::incr(Counter::FilterCacheMiss, 1);
+        let f_res = Rc::new(f.resolve(ev)?);
+        cache.borrow_mut().put(key, f_res.clone());
+        Ok(f_res)
     }
 
-    fn search_filter_entry_attributes(
+    fn resolve_receiver(
         &self,
-        audit: &mut AuditScope,
-        se: &SearchEvent,
-        entries: Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
-        /*
-         * Super similar to above (could even re-use some parts). Given a set of entries,
-         * reduce the attribute sets on them to "what is visible". This is ONLY called on
-         * the server edge, such that clients only see what they can, but internally,
-         * impersonate and such actually still get the whole entry back as not to break
-         * modify and co.
-         */
-        audit_log!(audit, "Access check and reduce for event: {:?}", se);
-
-        // If this is an internal search, do nothing. How this occurs in this
-        // interface is beyond me ....
-        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
-            EventOrigin::Internal => {
-                audit_log!(audit, "IMPOSSIBLE STATE: Internal search in external interface?! Returning empty for safety.");
-                // No need to check ACS
-                return Ok(Vec::new());
-            }
-            EventOrigin::User(e) => &e,
+        acp: &AccessControlProfile,
+        ev: &Event,
+    ) -> Result<Rc<Filter<FilterValidResolved>>, OperationError> {
+        Self::resolve_in(&self.receivers, acp, &acp.receiver, ev)
+    }
+
+    fn resolve_targetscope(
+        &self,
+        acp: &AccessControlProfile,
+        ev: &Event,
+    ) -> Result<Rc<Filter<FilterValidResolved>>, OperationError> {
+        Self::resolve_in(&self.targetscopes, acp, &acp.targetscope, ev)
+    }
+
+    fn clear(&self) {
+        *self.receivers.borrow_mut() = LruCache::new(self.capacity);
+        *self.targetscopes.borrow_mut() = LruCache::new(self.capacity);
+    }
+}
+
+// Of the search acps that apply to the receiver, find those that apply to this entry.
+fn search_scoped_acp<'a>(
+    audit: &mut AuditScope,
+    cache: &ResolvedFilterCache,
+    ev: &Event,
+    related_acp: &Vec<&'a AccessControlSearch>,
+    e: &Entry<EntryValid, EntryCommitted>,
+) -> Vec<&'a AccessControlSearch> {
+    related_acp
+        .iter()
+        .filter_map(|acs| match cache.resolve_targetscope(&acs.acp, ev) {
+            Ok(f_res) => {
+                if e.entry_match_no_index(&f_res) {
+                    audit_log!(audit, "entry {:?} matches acs {:?}", e.get_uuid(), acs);
+                    Some(*acs)
+                } else {
+                    audit_log!(
+                        audit,
+                        "entry {:?} DOES NOT match acs {:?}",
+                        e.get_uuid(),
+                        acs
+                    );
+                    None
+                }
+            }
+            Err(e) => {
+                audit_log!(
+                    audit,
+                    "A internal filter was passed for resolution!?!? {:?}",
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+// A pseudo attribute of acp_search_attr for the entry as a whole. Granting it
+// shows only that the entry exists - the attributes below - so a member can be
+// named without anything else of it being readable. Denying it hides the
+// entry, whatever other profiles grant.
+static ACP_SEARCH_ENTRY: &'static str = "entry";
+static ACP_SEARCH_ENTRY_ATTRS: [&'static str; 2] = ["uuid", "name"];
+
+// The attributes granted by a set of scoped search acps. Attributes named by a
+// deny profile are removed, regardless of how many allows grant them.
+fn search_allowed_attrs<'a>(scoped_acp: &Vec<&'a AccessControlSearch>) -> BTreeSet<&'a str> {
+    let allowed: BTreeSet<&str> = scoped_acp
+        .iter()
+        .filter(|acs| !acs.acp.deny)
+        .flat_map(|acs| acs.attrs.iter().map(|s| s.as_str()))
+        .collect();
+    let denied: BTreeSet<&str> = scoped_acp
+        .iter()
+        .filter(|acs| acs.acp.deny)
+        .flat_map(|acs| acs.attrs.iter().map(|s| s.as_str()))
+        .collect();
+    if denied.contains(ACP_SEARCH_ENTRY) {
+        return BTreeSet::new();
+    }
+    let mut allowed: BTreeSet<&str> = allowed.difference(&denied).map(|s| *s).collect();
+    if allowed.remove(ACP_SEARCH_ENTRY) {
+        allowed.extend(
+            ACP_SEARCH_ENTRY_ATTRS
+                .iter()
+                .filter(|a| !denied.contains(*a))
+                .map(|a| *a),
+        );
+    }
+    allowed
+}
+
+// The rights granted by a set of scoped modify acps for one of the modify attribute
+// lists. As with search, anything named by a deny profile is removed from the result.
+fn modify_allowed_set<'a, F>(scoped_acp: &Vec<&'a AccessControlModify>, f: F) -> BTreeSet<&'a str>
+where
+    F: Fn(&'a AccessControlModify) -> &'a Vec<String>,
+{
+    let allowed: BTreeSet<&str> = scoped_acp
+        .iter()
+        .filter(|acp| !acp.acp.deny)
+        .flat_map(|acp| f(*acp).iter().map(|v| v.as_str()))
+        .collect();
+    let denied: BTreeSet<&str> = scoped_acp
+        .iter()
+        .filter(|acp| acp.acp.deny)
+        .flat_map(|acp| f(*acp).iter().map(|v| v.as_str()))
+        .collect();
+    allowed.difference(&denied).map(|s| *s).collect()
+}
+
+// The values each allowed attribute is limited to. An attribute is only
+// limited if every allowing profile that grants it lists values for it -
+// otherwise any value may be changed, and it isn't included. "self" is given
+// as the uuid of the receiver, as it is when a modify is checked.
+fn modify_allowed_values<'a, F, G>(
+    scoped_acp: &Vec<&'a AccessControlModify>,
+    allowed: &BTreeSet<&str>,
+    f: F,
+    g: G,
+    self_uuid: &str,
+) -> BTreeMap<String, Vec<String>>
+where
+    F: Fn(&'a AccessControlModify) -> &'a Vec<String>,
+    G: Fn(&'a AccessControlModify) -> &'a BTreeMap<String, Vec<String>>,
+{
+    allowed
+        .iter()
+        .filter_map(|a| {
+            let mut values: BTreeSet<&str> = BTreeSet::new();
+            for acm in scoped_acp
+                .iter()
+                .filter(|acm| !acm.acp.deny && f(**acm).iter().any(|x| x == a))
+            {
+                let vs = g(*acm).get(*a)?;
+                values.extend(
+                    vs.iter()
+                        .map(|v| if v == "self" { self_uuid } else { v.as_str() }),
+                );
+            }
+            Some((
+                a.to_string(),
+                values.iter().map(|v| v.to_string()).collect(),
+            ))
+        })
+        .collect()
+}
+
+// Does this profile apply to the initiator of the event?
+fn acp_receiver_match(
+    audit: &mut AuditScope,
+    cache: &ResolvedFilterCache,
+    ev: &Event,
+    acp: &AccessControlProfile,
+    rec_entry: &Entry<EntryValid, EntryCommitted>,
+) -> bool {
+    match cache.resolve_receiver(acp, ev) {
+        Ok(f_res) => rec_entry.entry_match_no_index(&f_res),
+        Err(e) => {
+            audit_log!(
+                audit,
+                "A internal filter was passed for resolution!?!? {:?}",
+                e
+            );
+            false
+        }
+    }
+}
+
+// Does this profile's targetscope cover the entry?
+fn acp_targetscope_match(
+    audit: &mut AuditScope,
+    cache: &ResolvedFilterCache,
+    ev: &Event,
+    acp: &AccessControlProfile,
+    e: &Entry<EntryValid, EntryCommitted>,
+) -> bool {
+    match cache.resolve_targetscope(acp, ev) {
+        Ok(f_res) => e.entry_match_no_index(&f_res),
+        Err(e) => {
+            audit_log!(
+                audit,
+                "A internal filter was passed for resolution!?!? {:?}",
+                e
+            );
+            false
+        }
+    }
+}
+
+// Log-only profiles never change a decision - instead, when including them
+// would have given a different result, that is written to the audit log so
+// the operator can see what the profile will do once enforced.
+fn acp_log_only_report<T: std::fmt::Debug + PartialEq>(
+    audit: &mut AuditScope,
+    what: &str,
+    enforced: &T,
+    with_log_only: &T,
+) {
+    if enforced != with_log_only {
+        security_log!(
+            audit,
+            "log-only acps would change {}: {:?} -> {:?}",
+            what,
+            enforced,
+            with_log_only
+        );
+    }
+}
+
+// The search access checks for one event, worked out once and then applied to
+// each entry of the result set in turn.
+pub struct SearchAccess<'a> {
+    // The acps that apply to the receiver, with their resolved targetscopes.
+    // None for an internal event, which bypasses access controls.
+    related_acp: Option<Vec<(&'a AccessControlSearch, Rc<Filter<FilterValidResolved>>)>>,
+    requested_attrs: BTreeSet<&'a str>,
+    filter_orig_res: Option<Filter<FilterValidResolved>>,
+}
+
+// An entry that may be part of a result set, with the attributes the caller
+// can read of it, so that reducing it doesn't evaluate the acps again. None
+// for an internal search, which isn't limited.
+pub struct EntrySearchAllowed<'a> {
+    entry: Entry<EntryValid, EntryCommitted>,
+    allowed_attrs: Option<BTreeSet<&'a str>>,
+}
+
+impl<'a> EntrySearchAllowed<'a> {
+    pub fn into_entry(self) -> Entry<EntryValid, EntryCommitted> {
+        self.entry
+    }
+}
+
+// What the search acps decide for one entry. Working it out needs neither the
+// audit scope nor the filter cache, so many entries can be decided at once on
+// different threads, and the decisions logged afterwards.
+struct SearchDecision<'a> {
+    // Per related acp, whether its targetscope matches the entry.
+    matches: Vec<bool>,
+    allowed: bool,
+    allowed_attrs: BTreeSet<&'a str>,
+    // The same with log-only acps included, when any of them apply.
+    log_only: Option<(bool, BTreeSet<&'a str>)>,
+}
+
+fn search_decide<'a>(
+    targets: &[(&'a AccessControlSearch, &Filter<FilterValidResolved>)],
+    e: &Entry<EntryValid, EntryCommitted>,
+    requested_attrs: &BTreeSet<&str>,
+    filter_orig_res: Option<&Filter<FilterValidResolved>>,
+) -> SearchDecision<'a> {
+    let matches: Vec<bool> = targets
+        .iter()
+        .map(|(_, f_res)| e.entry_match_no_index(f_res))
+        .collect();
+    let scoped_acp: Vec<&AccessControlSearch> = targets
+        .iter()
+        .zip(matches.iter())
+        .filter(|(_, m)| **m)
+        .map(|((acs, _), _)| *acs)
+        .collect();
+    let enforced_acp: Vec<&AccessControlSearch> = scoped_acp
+        .iter()
+        .filter(|acs| acs.acp.mode == AccessControlMode::Enforce)
+        .map(|acs| *acs)
+        .collect();
+
+    let entry_allowed = |allowed_attrs: &BTreeSet<&str>| match filter_orig_res {
+        // Does the entry still match when it can only be seen
+        // through the allowed attributes?
+        Some(f_res) => e.entry_match_restricted(f_res, allowed_attrs),
+        // is attr set a subset of allowed set?
+        // true -> entry is allowed in result set
+        // false -> the entry is not allowed to be searched by this entity, so is
+        //          excluded.
+        None => requested_attrs.is_subset(allowed_attrs),
+    };
+
+    // An entry is only visible to those who can read something of it, so
+    // one that is denied as a whole is never returned.
+    let visible =
+        |allowed_attrs: &BTreeSet<&str>| !allowed_attrs.is_empty() && entry_allowed(allowed_attrs);
+
+    let allowed_attrs = search_allowed_attrs(&enforced_acp);
+    let allowed = visible(&allowed_attrs);
+    let log_only = if enforced_acp.len() != scoped_acp.len() {
+        let log_only_attrs = search_allowed_attrs(&scoped_acp);
+        Some((visible(&log_only_attrs), log_only_attrs))
+    } else {
+        None
+    };
+
+    SearchDecision {
+        matches: matches,
+        allowed: allowed,
+        allowed_attrs: allowed_attrs,
+        log_only: log_only,
+    }
+}
+
+impl<'a> SearchAccess<'a> {
+    fn targets(&self) -> Option<Vec<(&'a AccessControlSearch, &Filter<FilterValidResolved>)>> {
+        self.related_acp.as_ref().map(|related_acp| {
+            related_acp
+                .iter()
+                .map(|(acs, f_res)| (*acs, f_res.as_ref()))
+                .collect()
+        })
+    }
+
+    // May this entry be part of the result set? If so it comes back with the
+    // attributes the caller can read, ready to be reduced.
+    pub fn check_entry(
+        &self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryCommitted>,
+    ) -> Option<EntrySearchAllowed<'a>> {
+        let targets = match self.targets() {
+            Some(t) => t,
+            None => {
+                return Some(EntrySearchAllowed {
+                    entry: e,
+                    allowed_attrs: None,
+                })
+            }
+        };
+        let d = search_decide(
+            &targets,
+            &e,
+            &self.requested_attrs,
+            self.filter_orig_res.as_ref(),
+        );
+        self.apply_decision(audit, &targets, e, d)
+    }
+
+    // As check_entry for a whole result set. With parallel, the entries are
+    // decided on every core, then logged in their original order.
+    pub fn check_entries(
+        &self,
+        audit: &mut AuditScope,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+        parallel: bool,
+    ) -> Vec<EntrySearchAllowed<'a>> {
+        let targets = match self.targets() {
+            Some(t) => t,
+            None => {
+                return entries
+                    .into_iter()
+                    .map(|e| EntrySearchAllowed {
+                        entry: e,
+                        allowed_attrs: None,
+                    })
+                    .collect()
+            }
+        };
+        // Only borrow what can be shared between threads, not self.
+        let (requested_attrs, filter_orig_res) =
+            (&self.requested_attrs, self.filter_orig_res.as_ref());
+        let decide = |e: Entry<EntryValid, EntryCommitted>| {
+            let d = search_decide(&targets, &e, requested_attrs, filter_orig_res);
+            (e, d)
+        };
+        let decided: Vec<_> = if parallel {
+            entries.into_par_iter().map(&decide).collect()
+        } else {
+            entries.into_iter().map(&decide).collect()
+        };
+        decided
+            .into_iter()
+            .filter_map(|(e, d)| self.apply_decision(audit, &targets, e, d))
+            .collect()
+    }
+
+    fn apply_decision(
+        &self,
+        audit: &mut AuditScope,
+        targets: &[(&'a AccessControlSearch, &Filter<FilterValidResolved>)],
+        e: Entry<EntryValid, EntryCommitted>,
+        d: SearchDecision<'a>,
+    ) -> Option<EntrySearchAllowed<'a>> {
+        for ((acs, _), m) in targets.iter().zip(d.matches.iter()) {
+            if *m {
+                audit_log!(audit, "entry {:?} matches acs {:?}", e.get_uuid(), acs);
+            } else {
+                audit_log!(
+                    audit,
+                    "entry {:?} DOES NOT match acs {:?}",
+                    e.get_uuid(),
+                    acs
+                );
+            }
+        }
+
+        audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
+        audit_log!(audit, "allowed attributes   --> {:?}", d.allowed_attrs);
+        audit_log!(audit, "requested attributes --> {:?}", self.requested_attrs);
+
+        if let Some((log_only_allowed, log_only_attrs)) = &d.log_only {
+            acp_log_only_report(
+                audit,
+                format!("search of {}", e.get_uuid()).as_str(),
+                &d.allowed,
+                log_only_allowed,
+            );
+            acp_log_only_report(
+                audit,
+                format!("visible attributes of {}", e.get_uuid()).as_str(),
+                &d.allowed_attrs,
+                log_only_attrs,
+            );
+        }
+
+        if d.allowed {
+            Some(EntrySearchAllowed {
+                entry: e,
+                allowed_attrs: Some(d.allowed_attrs),
+            })
+        } else {
+            None
+        }
+    }
+
+    // Reduce the entry to the attributes the caller can see. This is ONLY
+    // done on the server edge, such that clients only see what they can, but
+    // internally, impersonate and such actually still get the whole entry
+    // back as not to break modify and co.
+    pub fn reduce_entry(
+        &self,
+        audit: &mut AuditScope,
+        a: EntrySearchAllowed<'a>,
+    ) -> Option<Entry<EntryReduced, EntryCommitted>> {
+        match a.allowed_attrs {
+            // TODO #69: The requested attributes are currently ALL ATTRIBUTES,
+            // so we actually work here to just remove things we CAN'T see instead.
+            Some(allowed_attrs) => Some(a.entry.reduce_attributes(allowed_attrs)),
+            None => {
+                audit_log!(audit, "IMPOSSIBLE STATE: Internal search in external interface?! Returning empty for safety.");
+                None
+            }
+        }
+    }
+}
+
+pub trait AccessControlsTransaction {
+    fn get_inner(&self) -> &AccessControlsInner;
+
+    fn get_filter_cache(&self) -> &ResolvedFilterCache;
+
+    // Roughly how much memory the parsed profiles of each kind take.
+    fn memory_use(&self) -> Vec<MemoryUse> {
+        let inner = self.get_inner();
+        let kind = |name: &str, count: usize, bytes: usize| MemoryUse {
+            name: name.to_string(),
+            count: count,
+            bytes: bytes,
+        };
+        vec![
+            kind(
+                "search",
+                inner.acps_search.len(),
+                inner
+                    .acps_search
+                    .values()
+                    .map(|a| a.acp.approx_size() + strs_size(&a.attrs))
+                    .sum(),
+            ),
+            kind(
+                "create",
+                inner.acps_create.len(),
+                inner
+                    .acps_create
+                    .values()
+                    .map(|a| a.acp.approx_size() + strs_size(&a.classes) + strs_size(&a.attrs))
+                    .sum(),
+            ),
+            kind(
+                "modify",
+                inner.acps_modify.len(),
+                inner
+                    .acps_modify
+                    .values()
+                    .map(|a| {
+                        a.acp.approx_size()
+                            + strs_size(&a.addclasses)
+                            + strs_size(&a.remclasses)
+                            + strs_size(&a.presattrs)
+                            + strs_size(&a.remattrs)
+                            + value_constraints_size(&a.presvalues)
+                            + value_constraints_size(&a.remvalues)
+                    })
+                    .sum(),
+            ),
+            kind(
+                "delete",
+                inner.acps_delete.len(),
+                inner
+                    .acps_delete
+                    .values()
+                    .map(|a| a.acp.approx_size() + strs_size(&a.classes))
+                    .sum(),
+            ),
+            kind(
+                "compare",
+                inner.acps_compare.len(),
+                inner
+                    .acps_compare
+                    .values()
+                    .map(|a| a.acp.approx_size() + strs_size(&a.attrs))
+                    .sum(),
+            ),
+            kind(
+                "audit_read",
+                inner.acps_audit_read.len(),
+                inner
+                    .acps_audit_read
+                    .values()
+                    .map(|a| a.acp.approx_size())
+                    .sum(),
+            ),
+            kind(
+                "operation",
+                inner.acps_operation.len(),
+                inner
+                    .acps_operation
+                    .values()
+                    .map(|a| a.acp.approx_size() + strs_size(&a.operations))
+                    .sum(),
+            ),
+        ]
+    }
+
+    // The receiver of every profile, by the profile's uuid.
+    fn receivers(&self) -> BTreeMap<String, Arc<Filter<FilterValid>>> {
+        let inner = self.get_inner();
+        inner
+            .acps_search
+            .values()
+            .map(|a| &a.acp)
+            .chain(inner.acps_create.values().map(|a| &a.acp))
+            .chain(inner.acps_modify.values().map(|a| &a.acp))
+            .chain(inner.acps_delete.values().map(|a| &a.acp))
+            .chain(inner.acps_compare.values().map(|a| &a.acp))
+            .chain(inner.acps_audit_read.values().map(|a| &a.acp))
+            .chain(inner.acps_operation.values().map(|a| &a.acp))
+            .map(|acp| (acp.uuid.clone(), acp.receiver.clone()))
+            .collect()
+    }
+
+    // Prepare the search access checks for an event, so they can be applied
+    // to entries one at a time as a search reads them.
+    fn search_access<'a>(
+        &'a self,
+        audit: &mut AuditScope,
+        se: &'a SearchEvent,
+    ) -> Result<SearchAccess<'a>, OperationError> {
+        security_log!(audit, "Access check for event: {:?}", se);
+
+        // If this is an internal search, there is nothing to prepare.
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
+            EventOrigin::Internal => {
+                audit_log!(audit, "Internal operation, bypassing access check");
+                return Ok(SearchAccess {
+                    related_acp: None,
+                    requested_attrs: BTreeSet::new(),
+                    filter_orig_res: None,
+                });
+            }
+            EventOrigin::User(e) => &e,
         };
 
         // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
+        let cache = self.get_filter_cache();
 
-        // Get the relevant acps for this receiver.
+        // First get the set of acps that apply to this receiver
         let related_acp: Vec<&AccessControlSearch> = state
             .acps_search
             .iter()
+            .filter(|(_, acs)| acs.acp.mode != AccessControlMode::Disabled)
             .filter_map(|(_, acs)| {
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&se.event) {
+                // Now resolve the receiver filter
+                // Okay, so in filter resolution, the primary error case
+                // is that we have a non-user in the event. We have already
+                // checked for this above BUT we should still check here
+                // properly just in case.
+                //
+                // In this case, we assume that if the event is internal
+                // that the receiver can NOT match because it has no selfuuid
+                // and can as a result, never return true. This leads to this
+                // acp not being considered in that case ... which should never
+                // happen because we already bypassed internal ops above!
+                //
+                // A possible solution is to change the filter resolve function
+                // such that it takes an entry, rather than an event, but that
+                // would create issues in search.
+                match cache.resolve_receiver(&acs.acp, &se.event) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -559,116 +1448,109 @@ pub trait AccessControlsTransaction {
             })
             .collect();
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+        security_log!(audit, "Related acs -> {:?}", related_acp);
+
+        // Each targetscope is resolved once here, rather than per entry. One
+        // that can't be resolved never matches.
+        let related_acp: Vec<(&AccessControlSearch, Rc<Filter<FilterValidResolved>>)> = related_acp
+            .into_iter()
+            .filter_map(|acs| match cache.resolve_targetscope(&acs.acp, &se.event) {
+                Ok(f_res) => Some((acs, f_res)),
+                Err(e) => {
+                    audit_log!(
+                        audit,
+                        "A internal filter was passed for resolution!?!? {:?}",
+                        e
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        // Get the set of attributes requested by this se filter. This is what we are
+        // going to access check.
+        let requested_attrs: BTreeSet<&str> = se.filter_orig.get_attr_set();
+
+        // In partial mode the original filter is re-applied per entry, using
+        // only the attributes that entry allows, so it has to be resolved.
+        let filter_orig_res = if se.partial {
+            Some(se.filter_orig.resolve(&se.event)?)
+        } else {
+            None
+        };
 
-        // Get the set of attributes requested by the caller
-        // TODO #69: This currently
-        // is ALL ATTRIBUTES, so we actually work here to just remove things we
-        // CAN'T see instead.
+        Ok(SearchAccess {
+            related_acp: Some(related_acp),
+            requested_attrs: requested_attrs,
+            filter_orig_res: filter_orig_res,
+        })
+    }
 
-        //  For each entry
-        let allowed_entries: Vec<Entry<EntryReduced, EntryCommitted>> = entries
+    // Contains all the way to eval acps to entries. Result sets of at least
+    // parallel_min entries are checked on every core.
+    fn search_filter_entries(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+        parallel_min: Option<usize>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let sa = self.search_access(audit, se)?;
+        let parallel = parallel_min
+            .map(|min| entries.len() >= min)
+            .unwrap_or(false);
+        Ok(sa
+            .check_entries(audit, entries, parallel)
             .into_iter()
-            .map(|e| {
-                // Get the set of attributes you can see
-                let allowed_attrs: BTreeSet<&str> = related_acp
-                    .iter()
-                    .filter_map(|acs| {
-                        let f_val = acs.acp.targetscope.clone();
-                        match f_val.resolve(&se.event) {
-                            Ok(f_res) => {
-                                // if it applies
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    // add search_attrs to allowed.
-                                    let r: Vec<&str> =
-                                        acs.attrs.iter().map(|s| s.as_str()).collect();
-                                    Some(r)
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    None
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    })
-                    .flatten()
-                    .collect();
-                // Remove all others that are present on the entry.
-                audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
-                audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
+            .map(|a| a.into_entry())
+            .collect())
+    }
 
-                // Now purge the attrs that are NOT in this.
-                e.reduce_attributes(allowed_attrs)
+    // Entries the caller can't see are dropped as well, as the readable
+    // attributes are only worked out along with that.
+    fn search_filter_entry_attributes(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+        let sa = self.search_access(audit, se)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|e| {
+                sa.check_entry(audit, e)
+                    .and_then(|a| sa.reduce_entry(audit, a))
             })
-            .collect();
-        Ok(allowed_entries)
+            .collect())
     }
 
-    fn modify_allow_operation(
+    // Explain which entries search_filter_entries would remove from a result set,
+    // and which acps were considered for them. This must stay in line with the
+    // logic above, but records the reasoning rather than applying it.
+    fn search_trace_entries(
         &self,
         audit: &mut AuditScope,
-        me: &ModifyEvent,
+        se: &SearchEvent,
         entries: &Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<bool, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", me);
-
-        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &me.event.origin {
+    ) -> Result<Vec<SearchTraceAccess>, OperationError> {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
             EventOrigin::Internal => {
-                // No need to check ACS
-                return Ok(true);
+                audit_log!(audit, "Internal operation, no access controls to trace");
+                return Ok(Vec::new());
             }
             EventOrigin::User(e) => &e,
         };
 
-        // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
+        let cache = self.get_filter_cache();
 
-        // Pre-check if the no-no purge class is present
-        if me.modlist.iter().fold(false, |acc, m| {
-            if acc {
-                return acc;
-            } else {
-                match m {
-                    Modify::Purged(a) => {
-                        if a == "class" {
-                            true
-                        } else {
-                            false
-                        }
-                    }
-                    _ => false,
-                }
-            }
-        }) {
-            audit_log!(audit, "Disallowing purge class in modification");
-            return Ok(false);
-        }
-
-        // Find the acps that relate to the caller.
-        let related_acp: Vec<&AccessControlModify> = state
-            .acps_modify
+        // The trace explains the enforced result, so log-only acps are left out.
+        let related_acp: Vec<&AccessControlSearch> = state
+            .acps_search
             .iter()
-            .filter_map(|(_, acs)| {
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&me.event) {
+            .filter(|(_, acs)| acs.acp.mode == AccessControlMode::Enforce)
+            .filter_map(
+                |(_, acs)| match cache.resolve_receiver(&acs.acp, &se.event) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -684,49 +1566,362 @@ pub trait AccessControlsTransaction {
                         );
                         None
                     }
-                }
-            })
+                },
+            )
             .collect();
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+        let requested_attrs: BTreeSet<&str> = se.filter_orig.get_attr_set();
 
-        // build two sets of "requested pres" and "requested rem"
-        let requested_pres: BTreeSet<&str> = me
-            .modlist
+        let filter_orig_res = if se.partial {
+            Some(se.filter_orig.resolve(&se.event)?)
+        } else {
+            None
+        };
+
+        let pruned: Vec<SearchTraceAccess> = entries
             .iter()
-            .filter_map(|m| match m {
-                Modify::Present(a, _) => Some(a.as_str()),
-                _ => None,
+            .filter_map(|e| {
+                let scoped_acp = search_scoped_acp(audit, cache, &se.event, &related_acp, e);
+                let allowed_attrs: BTreeSet<&str> = search_allowed_attrs(&scoped_acp);
+
+                let allowed = !allowed_attrs.is_empty()
+                    && match &filter_orig_res {
+                        Some(f_res) => e.entry_match_restricted(f_res, &allowed_attrs),
+                        None => requested_attrs.is_subset(&allowed_attrs),
+                    };
+
+                if allowed {
+                    None
+                } else {
+                    Some(SearchTraceAccess {
+                        uuid: e.get_uuid().clone(),
+                        acps: scoped_acp.iter().map(|acs| acs.acp.name.clone()).collect(),
+                        denied_attrs: requested_attrs
+                            .difference(&allowed_attrs)
+                            .map(|s| s.to_string())
+                            .collect(),
+                    })
+                }
             })
             .collect();
 
-        let requested_rem: BTreeSet<&str> = me
-            .modlist
+        Ok(pruned)
+    }
+
+    // Report what the initiator of the event may do to a single entry. This
+    // is the same evaluation as the operations above, but rather than asking
+    // "is this request allowed", it answers "what requests would be allowed",
+    // which is what you need when working out why an acp isn't doing what
+    // you expect.
+    fn access_effective_permissions(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        target: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<EffectivePermissions, OperationError> {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+            EventOrigin::Internal => {
+                // Internal operations bypass access controls, so there is
+                // nothing meaningful to report.
+                audit_log!(audit, "Internal operation, no access controls to evaluate");
+                return Err(OperationError::InvalidRequestState);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+
+        // Only what is in force is reported - log-only acps grant nothing.
+        // Search
+        let related_search: Vec<&AccessControlSearch> = state
+            .acps_search
             .iter()
-            .filter_map(|m| match m {
-                Modify::Removed(a, _) => Some(a.as_str()),
-                Modify::Purged(a) => Some(a.as_str()),
-                _ => None,
+            .filter(|(_, acs)| acs.acp.mode == AccessControlMode::Enforce)
+            .filter_map(|(_, acs)| {
+                if acp_receiver_match(audit, cache, ev, &acs.acp, rec_entry) {
+                    Some(acs)
+                } else {
+                    None
+                }
             })
             .collect();
+        let scoped_search = search_scoped_acp(audit, cache, ev, &related_search, target);
+        let search_attrs = search_allowed_attrs(&scoped_search);
 
-        // Build the set of classes that we to work on, only in terms of "addition". To remove
-        // I think we have no limit, but ... william of the future may find a problem with this
-        // policy.
-        let requested_classes: BTreeSet<&str> = me
+        // Modify
+        let scoped_modify: Vec<&AccessControlModify> = state
+            .acps_modify
+            .iter()
+            .filter(|(_, acm)| acm.acp.mode == AccessControlMode::Enforce)
+            .filter_map(|(_, acm)| {
+                if acp_receiver_match(audit, cache, ev, &acm.acp, rec_entry)
+                    && acp_targetscope_match(audit, cache, ev, &acm.acp, target)
+                {
+                    Some(acm)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        // Delete - any matching deny wins, otherwise one allow is enough.
+        let scoped_delete: Vec<&AccessControlDelete> = state
+            .acps_delete
+            .iter()
+            .filter(|(_, acd)| acd.acp.mode == AccessControlMode::Enforce)
+            .filter_map(|(_, acd)| {
+                if acp_receiver_match(audit, cache, ev, &acd.acp, rec_entry)
+                    && acp_targetscope_match(audit, cache, ev, &acd.acp, target)
+                    && acd.class_match(target)
+                {
+                    Some(acd)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let delete = scoped_delete.iter().any(|acd| !acd.acp.deny)
+            && !scoped_delete.iter().any(|acd| acd.acp.deny);
+
+        // Create - could an entry identical to this one be created?
+        let create_attrs: BTreeSet<&str> = target.get_ava_names();
+        let create_classes: BTreeSet<&str> = target.get_ava_set("class").unwrap_or_default();
+        let scoped_create: Vec<&AccessControlCreate> = state
+            .acps_create
+            .iter()
+            .filter(|(_, acc)| acc.acp.mode == AccessControlMode::Enforce)
+            .filter_map(|(_, acc)| {
+                if acp_receiver_match(audit, cache, ev, &acc.acp, rec_entry)
+                    && acp_targetscope_match(audit, cache, ev, &acc.acp, target)
+                {
+                    Some(acc)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let create_denied = scoped_create.iter().filter(|acc| acc.acp.deny).any(|acc| {
+            acc.attrs.iter().any(|a| create_attrs.contains(a.as_str()))
+                || acc
+                    .classes
+                    .iter()
+                    .any(|c| create_classes.contains(c.as_str()))
+        });
+        let create = !create_denied
+            && !create_classes.is_empty()
+            && scoped_create.iter().filter(|acc| !acc.acp.deny).any(|acc| {
+                let allowed_attrs: BTreeSet<&str> = acc.attrs.iter().map(|s| s.as_str()).collect();
+                let allowed_classes: BTreeSet<&str> =
+                    acc.classes.iter().map(|s| s.as_str()).collect();
+                create_attrs.is_subset(&allowed_attrs) && create_classes.is_subset(&allowed_classes)
+            });
+
+        let modify_present = modify_allowed_set(&scoped_modify, |acm| &acm.presattrs);
+        let modify_remove = modify_allowed_set(&scoped_modify, |acm| &acm.remattrs);
+        let self_uuid = rec_entry.get_uuid().as_str();
+        let modify_present_values = modify_allowed_values(
+            &scoped_modify,
+            &modify_present,
+            |acm| &acm.presattrs,
+            |acm| &acm.presvalues,
+            self_uuid,
+        );
+        let modify_remove_values = modify_allowed_values(
+            &scoped_modify,
+            &modify_remove,
+            |acm| &acm.remattrs,
+            |acm| &acm.remvalues,
+            self_uuid,
+        );
+
+        let to_vec =
+            |s: BTreeSet<&str>| -> Vec<String> { s.iter().map(|v| v.to_string()).collect() };
+
+        // A profile can cover several operations, so only list it once.
+        let profiles: BTreeMap<&str, &AccessControlProfile> = scoped_search
+            .iter()
+            .map(|acs| &acs.acp)
+            .chain(scoped_modify.iter().map(|acm| &acm.acp))
+            .chain(scoped_delete.iter().map(|acd| &acd.acp))
+            .chain(scoped_create.iter().map(|acc| &acc.acp))
+            .map(|acp| (acp.uuid.as_str(), acp))
+            .collect();
+
+        let ep = EffectivePermissions {
+            uuid: target.get_uuid().clone(),
+            search: to_vec(search_attrs),
+            modify_present: to_vec(modify_present),
+            modify_remove: to_vec(modify_remove),
+            modify_class_add: to_vec(modify_allowed_set(&scoped_modify, |acm| &acm.addclasses)),
+            modify_class_remove: to_vec(modify_allowed_set(&scoped_modify, |acm| &acm.remclasses)),
+            modify_present_values: modify_present_values,
+            modify_remove_values: modify_remove_values,
+            create: create,
+            delete: delete,
+            profiles: profiles.values().map(|acp| acp.to_info()).collect(),
+        };
+        audit_log!(audit, "Effective permissions -> {:?}", ep);
+        Ok(ep)
+    }
+
+    // For each entry, the receivers that may read at least one of its
+    // attributes, by their position in receivers. Only enforced profiles
+    // count, and deny profiles are taken into account as they are in search.
+    fn search_coverage(
+        &self,
+        audit: &mut AuditScope,
+        receivers: &[Event],
+        entries: &[Entry<EntryValid, EntryCommitted>],
+    ) -> Vec<Vec<usize>> {
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+
+        let related: Vec<Vec<&AccessControlSearch>> = receivers
+            .iter()
+            .map(|ev| match &ev.origin {
+                EventOrigin::User(rec_entry) => state
+                    .acps_search
+                    .values()
+                    .filter(|acs| acs.acp.mode == AccessControlMode::Enforce)
+                    .filter(|acs| acp_receiver_match(audit, cache, ev, &acs.acp, rec_entry))
+                    .collect(),
+                // Internal events bypass access controls, so aren't receivers.
+                EventOrigin::Internal => Vec::new(),
+            })
+            .collect();
+
+        entries
+            .iter()
+            .map(|e| {
+                receivers
+                    .iter()
+                    .zip(related.iter())
+                    .enumerate()
+                    .filter(|(_, (ev, rel))| {
+                        let scoped = search_scoped_acp(audit, cache, ev, rel, e);
+                        !search_allowed_attrs(&scoped).is_empty()
+                    })
+                    .map(|(i, _)| i)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn modify_allow_operation(
+        &self,
+        audit: &mut AuditScope,
+        me: &ModifyEvent,
+        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<bool, OperationError> {
+        security_log!(audit, "Access check for event: {:?}", me);
+
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &me.event.origin {
+            EventOrigin::Internal => {
+                // No need to check ACS
+                return Ok(true);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        // Some useful references we'll use for the remainder of the operation
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+
+        // Pre-check if the no-no purge class is present
+        if me.modlist.iter().fold(false, |acc, m| {
+            if acc {
+                return acc;
+            } else {
+                match m {
+                    Modify::Purged(a) => {
+                        if a == "class" {
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    _ => false,
+                }
+            }
+        }) {
+            security_log!(audit, "Disallowing purge class in modification");
+            return Ok(false);
+        }
+
+        // Find the acps that relate to the caller.
+        let related_acp: Vec<&AccessControlModify> = state
+            .acps_modify
+            .iter()
+            .filter(|(_, acs)| acs.acp.mode != AccessControlMode::Disabled)
+            .filter_map(
+                |(_, acs)| match cache.resolve_receiver(&acs.acp, &me.event) {
+                    Ok(f_res) => {
+                        if rec_entry.entry_match_no_index(&f_res) {
+                            Some(acs)
+                        } else {
+                            None
+                        }
+                    }
+                    Err(e) => {
+                        audit_log!(
+                            audit,
+                            "A internal filter was passed for resolution!?!? {:?}",
+                            e
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        security_log!(audit, "Related acs -> {:?}", related_acp);
+
+        // build two sets of "requested pres" and "requested rem"
+        let requested_pres: BTreeSet<&str> = me
+            .modlist
+            .iter()
+            .filter_map(|m| match m {
+                Modify::Present(a, _) => Some(a.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let requested_rem: BTreeSet<&str> = me
+            .modlist
+            .iter()
+            .filter_map(|m| match m {
+                Modify::Removed(a, _) => Some(a.as_str()),
+                Modify::Purged(a) => Some(a.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        // Build the sets of classes that we want to work on. Adding and removing a class
+        // are checked seperately, because being able to remove a class (IE posixaccount)
+        // has very different consequences to being able to add it.
+        let requested_add_classes: BTreeSet<&str> = me
             .modlist
             .iter()
             .filter_map(|m| match m {
                 Modify::Present(a, v) => {
                     if a.as_str() == "class" {
-                        Some(v.as_str())
+                        v.to_str()
                     } else {
                         None
                     }
                 }
+                _ => None,
+            })
+            .collect();
+
+        let requested_rem_classes: BTreeSet<&str> = me
+            .modlist
+            .iter()
+            .filter_map(|m| match m {
                 Modify::Removed(a, v) => {
                     if a.as_str() == "class" {
-                        Some(v.as_str())
+                        v.to_str()
                     } else {
                         None
                     }
@@ -737,79 +1932,138 @@ pub trait AccessControlsTransaction {
 
         audit_log!(audit, "Requested present set: {:?}", requested_pres);
         audit_log!(audit, "Requested remove set: {:?}", requested_rem);
-        audit_log!(audit, "Requested class set: {:?}", requested_classes);
+        audit_log!(
+            audit,
+            "Requested add class set: {:?}",
+            requested_add_classes
+        );
+        audit_log!(
+            audit,
+            "Requested remove class set: {:?}",
+            requested_rem_classes
+        );
 
-        let r = entries.iter().fold(true, |acc, e| {
-            if acc == false {
-                false
-            } else {
-                // For this entry, find the acp's that apply to it from the
-                // set that apply to the entry that is performing the operation
-                let scoped_acp: Vec<&AccessControlModify> = related_acp
-                    .iter()
-                    .filter_map(|acm: &&AccessControlModify| {
-                        // We are continually compiling and using these
-                        // in a tight loop, so this is a possible oppurtunity
-                        // to cache or handle these filters better - filter compiler
-                        // cache maybe?
-                        let f_val = acm.acp.targetscope.clone();
-                        match f_val.resolve(&me.event) {
-                            Ok(f_res) => {
-                                if e.entry_match_no_index(&f_res) {
-                                    Some(*acm)
-                                } else {
+        let check = |audit: &mut AuditScope, related_acp: &Vec<&AccessControlModify>| -> bool {
+            entries.iter().fold(true, |acc, e| {
+                if acc == false {
+                    false
+                } else {
+                    // For this entry, find the acp's that apply to it from the
+                    // set that apply to the entry that is performing the operation
+                    let scoped_acp: Vec<&AccessControlModify> = related_acp
+                        .iter()
+                        .filter_map(|acm: &&AccessControlModify| {
+                            match cache.resolve_targetscope(&acm.acp, &me.event) {
+                                Ok(f_res) => {
+                                    if e.entry_match_no_index(&f_res) {
+                                        Some(*acm)
+                                    } else {
+                                        None
+                                    }
+                                }
+                                Err(e) => {
+                                    audit_log!(
+                                        audit,
+                                        "A internal filter was passed for resolution!?!? {:?}",
+                                        e
+                                    );
                                     None
                                 }
                             }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    })
-                    .collect();
-                // Build the sets of classes, pres and rem we are allowed to modify, extend
-                // or use based on the set of matched acps.
-                let allowed_pres: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.presattrs.iter().map(|v| v.as_str()))
-                    .collect();
-
-                let allowed_rem: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.remattrs.iter().map(|v| v.as_str()))
-                    .collect();
-
-                let allowed_classes: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.classes.iter().map(|v| v.as_str()))
-                    .collect();
+                        })
+                        .collect();
+                    // Build the sets of classes, pres and rem we are allowed to modify, extend
+                    // or use based on the set of matched acps.
+                    let allowed_pres = modify_allowed_set(&scoped_acp, |acp| &acp.presattrs);
+                    let allowed_rem = modify_allowed_set(&scoped_acp, |acp| &acp.remattrs);
+                    let allowed_add_classes =
+                        modify_allowed_set(&scoped_acp, |acp| &acp.addclasses);
+                    let allowed_rem_classes =
+                        modify_allowed_set(&scoped_acp, |acp| &acp.remclasses);
+
+                    // Now check all the subsets are true. Remember, purge class
+                    // is already checked above.
+
+                    if !requested_pres.is_subset(&allowed_pres) {
+                        security_log!(audit, "requested_pres is not a subset of allowed");
+                        security_log!(audit, "{:?} !⊆ {:?}", requested_pres, allowed_pres);
+                        return false;
+                    }
+                    if !requested_rem.is_subset(&allowed_rem) {
+                        security_log!(audit, "requested_rem is not a subset of allowed");
+                        security_log!(audit, "{:?} !⊆ {:?}", requested_rem, allowed_rem);
+                        return false;
+                    }
+                    if !requested_add_classes.is_subset(&allowed_add_classes) {
+                        security_log!(audit, "requested_add_classes is not a subset of allowed");
+                        security_log!(
+                            audit,
+                            "{:?} !⊆ {:?}",
+                            requested_add_classes,
+                            allowed_add_classes
+                        );
+                        return false;
+                    }
+                    if !requested_rem_classes.is_subset(&allowed_rem_classes) {
+                        security_log!(audit, "requested_rem_classes is not a subset of allowed");
+                        security_log!(
+                            audit,
+                            "{:?} !⊆ {:?}",
+                            requested_rem_classes,
+                            allowed_rem_classes
+                        );
+                        return false;
+                    }
 
-                // Now check all the subsets are true. Remember, purge class
-                // is already checked above.
+                    // Where an allowing acp constrains the values of an attribute,
+                    // each value changed must be permitted by some acp that grants
+                    // the attribute. A purge removes every value, so it needs an
+                    // acp that grants the remove without any value constraint.
+                    let self_uuid = rec_entry.get_uuid().as_str();
+                    let allow_acp: Vec<&&AccessControlModify> =
+                        scoped_acp.iter().filter(|acm| !acm.acp.deny).collect();
+                    let values_allowed = me.modlist.iter().all(|m| match m {
+                        Modify::Present(a, v) => allow_acp.iter().any(|acm| {
+                            acm.presattrs.contains(a)
+                                && modify_value_allowed(
+                                    &acm.presvalues,
+                                    a,
+                                    &v.to_string(),
+                                    self_uuid,
+                                )
+                        }),
+                        Modify::Removed(a, v) => allow_acp.iter().any(|acm| {
+                            acm.remattrs.contains(a)
+                                && modify_value_allowed(
+                                    &acm.remvalues,
+                                    a,
+                                    &v.to_string(),
+                                    self_uuid,
+                                )
+                        }),
+                        Modify::Purged(a) => allow_acp
+                            .iter()
+                            .any(|acm| acm.remattrs.contains(a) && !acm.remvalues.contains_key(a)),
+                    });
+                    if !values_allowed {
+                        security_log!(audit, "requested values are not permitted by any acp");
+                        return false;
+                    }
+                    true
+                } // if acc == false
+            })
+        };
 
-                if !requested_pres.is_subset(&allowed_pres) {
-                    audit_log!(audit, "requested_pres is not a subset of allowed");
-                    audit_log!(audit, "{:?} !⊆ {:?}", requested_pres, allowed_pres);
-                    return false;
-                }
-                if !requested_rem.is_subset(&allowed_rem) {
-                    audit_log!(audit, "requested_rem is not a subset of allowed");
-                    audit_log!(audit, "{:?} !⊆ {:?}", requested_rem, allowed_rem);
-                    return false;
-                }
-                if !requested_classes.is_subset(&allowed_classes) {
-                    audit_log!(audit, "requested_classes is not a subset of allowed");
-                    audit_log!(audit, "{:?} !⊆ {:?}", requested_classes, allowed_classes);
-                    return false;
-                }
-                true
-            } // if acc == false
-        });
+        let enforced_acp: Vec<&AccessControlModify> = related_acp
+            .iter()
+            .filter(|acm| acm.acp.mode == AccessControlMode::Enforce)
+            .map(|acm| *acm)
+            .collect();
+        let r = check(audit, &enforced_acp);
+        if enforced_acp.len() != related_acp.len() {
+            let r_log_only = check(audit, &related_acp);
+            acp_log_only_report(audit, "modify decision", &r, &r_log_only);
+        }
         Ok(r)
     }
 
@@ -819,7 +2073,7 @@ pub trait AccessControlsTransaction {
         ce: &CreateEvent,
         entries: &Vec<Entry<EntryNormalised, EntryNew>>,
     ) -> Result<bool, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", ce);
+        security_log!(audit, "Access check for event: {:?}", ce);
 
         let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ce.event.origin {
             EventOrigin::Internal => {
@@ -831,14 +2085,15 @@ pub trait AccessControlsTransaction {
 
         // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
+        let cache = self.get_filter_cache();
 
         // Find the acps that relate to the caller.
         let related_acp: Vec<&AccessControlCreate> = state
             .acps_create
             .iter()
-            .filter_map(|(_, acs)| {
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&ce.event) {
+            .filter(|(_, acs)| acs.acp.mode != AccessControlMode::Disabled)
+            .filter_map(
+                |(_, acs)| match cache.resolve_receiver(&acs.acp, &ce.event) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -854,119 +2109,162 @@ pub trait AccessControlsTransaction {
                         );
                         None
                     }
-                }
-            })
+                },
+            )
             .collect();
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+        security_log!(audit, "Related acs -> {:?}", related_acp);
 
         // For each entry
-        let r = entries.iter().fold(true, |acc, e| {
-            if acc == false {
-                // We have already failed, move on.
-                false
-            } else {
-                // Build the set of requested classes and attrs here.
-                let create_attrs: BTreeSet<&str> = e.get_ava_names();
-                // If this is empty, we make an empty set, which is fine because
-                // the empty class set despite matching is_subset, will have the
-                // following effect:
-                // * there is no class on entry, so schema will fail
-                // * plugin-base will add object to give a class, but excess
-                //   attrs will cause fail (could this be a weakness?)
-                // * class is a "may", so this could be empty in the rules, so
-                //   if the accr is empty this would not be a true subset,
-                //   so this would "fail", but any content in the accr would
-                //   have to be validated.
-                //
-                // I still think if this is None, we should just fail here ...
-                // because it shouldn't be possible to match.
-
-                let create_classes: BTreeSet<&str> = match e.get_ava_set("class") {
-                    Some(s) => s,
-                    None => return false,
-                };
-
-                related_acp.iter().fold(false, |r_acc, accr| {
-                    if r_acc == true {
-                        // Already allowed, continue.
-                        r_acc
-                    } else {
-                        // Check to see if allowed.
-                        let f_val = accr.acp.targetscope.clone();
-                        match f_val.resolve(&ce.event) {
+        let check = |audit: &mut AuditScope, related_acp: &Vec<&AccessControlCreate>| -> bool {
+            entries.iter().fold(true, |acc, e| {
+                if acc == false {
+                    // We have already failed, move on.
+                    false
+                } else {
+                    // Build the set of requested classes and attrs here.
+                    let create_attrs: BTreeSet<&str> = e.get_ava_names();
+                    // If this is empty, we make an empty set, which is fine because
+                    // the empty class set despite matching is_subset, will have the
+                    // following effect:
+                    // * there is no class on entry, so schema will fail
+                    // * plugin-base will add object to give a class, but excess
+                    //   attrs will cause fail (could this be a weakness?)
+                    // * class is a "may", so this could be empty in the rules, so
+                    //   if the accr is empty this would not be a true subset,
+                    //   so this would "fail", but any content in the accr would
+                    //   have to be validated.
+                    //
+                    // I still think if this is None, we should just fail here ...
+                    // because it shouldn't be possible to match.
+
+                    let create_classes: BTreeSet<&str> = match e.get_ava_set("class") {
+                        Some(s) => s,
+                        None => return false,
+                    };
+
+                    // A matching deny rejects the entry if it names any of the classes
+                    // or attrs being created, no matter what the allows grant.
+                    let denied = related_acp.iter().filter(|accr| accr.acp.deny).any(|accr| {
+                        match cache.resolve_targetscope(&accr.acp, &ce.event) {
                             Ok(f_res) => {
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(audit, "entry {:?} matches acs {:?}", e, accr);
-                                    // It matches, so now we have to check attrs and classes.
-                                    // Remember, we have to match ALL requested attrs
-                                    // and classes to pass!
-                                    let allowed_attrs: BTreeSet<&str> =
-                                        accr.attrs.iter().map(|s| s.as_str()).collect();
-                                    let allowed_classes: BTreeSet<&str> =
-                                        accr.classes.iter().map(|s| s.as_str()).collect();
-
-                                    if !create_attrs.is_subset(&allowed_attrs) {
-                                        audit_log!(
-                                            audit,
-                                            "create_attrs is not a subset of allowed"
-                                        );
-                                        audit_log!(
-                                            audit,
-                                            "{:?} !⊆ {:?}",
-                                            create_attrs,
-                                            allowed_attrs
-                                        );
-                                        return false;
+                                e.entry_match_no_index(&f_res)
+                                    && (accr
+                                        .attrs
+                                        .iter()
+                                        .any(|a| create_attrs.contains(a.as_str()))
+                                        || accr
+                                            .classes
+                                            .iter()
+                                            .any(|c| create_classes.contains(c.as_str())))
+                            }
+                            // Fail closed if we can't work out the scope.
+                            Err(_) => true,
+                        }
+                    });
+                    if denied {
+                        security_log!(audit, "entry {:?} is denied by a deny acs", e);
+                        return false;
+                    }
+
+                    related_acp
+                        .iter()
+                        .filter(|accr| !accr.acp.deny)
+                        .fold(false, |r_acc, accr| {
+                            if r_acc == true {
+                                // Already allowed, continue.
+                                r_acc
+                            } else {
+                                // Check to see if allowed.
+                                match cache.resolve_targetscope(&accr.acp, &ce.event) {
+                                    Ok(f_res) => {
+                                        if e.entry_match_no_index(&f_res) {
+                                            audit_log!(
+                                                audit,
+                                                "entry {:?} matches acs {:?}",
+                                                e,
+                                                accr
+                                            );
+                                            // It matches, so now we have to check attrs and classes.
+                                            // Remember, we have to match ALL requested attrs
+                                            // and classes to pass!
+                                            let allowed_attrs: BTreeSet<&str> =
+                                                accr.attrs.iter().map(|s| s.as_str()).collect();
+                                            let allowed_classes: BTreeSet<&str> =
+                                                accr.classes.iter().map(|s| s.as_str()).collect();
+
+                                            if !create_attrs.is_subset(&allowed_attrs) {
+                                                security_log!(
+                                                    audit,
+                                                    "create_attrs is not a subset of allowed"
+                                                );
+                                                security_log!(
+                                                    audit,
+                                                    "{:?} !⊆ {:?}",
+                                                    create_attrs,
+                                                    allowed_attrs
+                                                );
+                                                return false;
+                                            }
+                                            if !create_classes.is_subset(&allowed_classes) {
+                                                security_log!(
+                                                    audit,
+                                                    "create_classes is not a subset of allowed"
+                                                );
+                                                security_log!(
+                                                    audit,
+                                                    "{:?} !⊆ {:?}",
+                                                    create_classes,
+                                                    allowed_classes
+                                                );
+                                                return false;
+                                            }
+
+                                            true
+                                        } else {
+                                            audit_log!(
+                                                audit,
+                                                "entry {:?} DOES NOT match acs {:?}",
+                                                e,
+                                                accr
+                                            );
+                                            // Does not match, fail this rule.
+                                            false
+                                        }
                                     }
-                                    if !create_classes.is_subset(&allowed_classes) {
-                                        audit_log!(
-                                            audit,
-                                            "create_classes is not a subset of allowed"
-                                        );
+                                    Err(e) => {
                                         audit_log!(
                                             audit,
-                                            "{:?} !⊆ {:?}",
-                                            create_classes,
-                                            allowed_classes
+                                            "A internal filter was passed for resolution!?!? {:?}",
+                                            e
                                         );
-                                        return false;
+                                        // Default to failing here.
+                                        false
                                     }
-
-                                    true
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e,
-                                        accr
-                                    );
-                                    // Does not match, fail this rule.
-                                    false
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                // Default to failing here.
-                                false
+                                } // match
                             }
-                        } // match
-                    }
-                })
-            }
-            //      Find the set of related acps for this entry.
-            //
-            //      For each "created" entry.
-            //          If the created entry is 100% allowed by this acp
-            //          IE: all attrs to be created AND classes match classes
-            //              allow
-            //          if no acp allows, fail operation.
-        });
+                        })
+                }
+                //      Find the set of related acps for this entry.
+                //
+                //      For each "created" entry.
+                //          If the created entry is 100% allowed by this acp
+                //          IE: all attrs to be created AND classes match classes
+                //              allow
+                //          if no acp allows, fail operation.
+            })
+        };
 
+        let enforced_acp: Vec<&AccessControlCreate> = related_acp
+            .iter()
+            .filter(|acc| acc.acp.mode == AccessControlMode::Enforce)
+            .map(|acc| *acc)
+            .collect();
+        let r = check(audit, &enforced_acp);
+        if enforced_acp.len() != related_acp.len() {
+            let r_log_only = check(audit, &related_acp);
+            acp_log_only_report(audit, "create decision", &r, &r_log_only);
+        }
         Ok(r)
     }
 
@@ -976,7 +2274,7 @@ pub trait AccessControlsTransaction {
         de: &DeleteEvent,
         entries: &Vec<Entry<EntryValid, EntryCommitted>>,
     ) -> Result<bool, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", de);
+        security_log!(audit, "Access check for event: {:?}", de);
 
         let rec_entry: &Entry<EntryValid, EntryCommitted> = match &de.event.origin {
             EventOrigin::Internal => {
@@ -988,14 +2286,15 @@ pub trait AccessControlsTransaction {
 
         // Some useful references we'll use for the remainder of the operation
         let state = self.get_inner();
+        let cache = self.get_filter_cache();
 
         // Find the acps that relate to the caller.
         let related_acp: Vec<&AccessControlDelete> = state
             .acps_delete
             .iter()
-            .filter_map(|(_, acs)| {
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&de.event) {
+            .filter(|(_, acs)| acs.acp.mode != AccessControlMode::Disabled)
+            .filter_map(
+                |(_, acs)| match cache.resolve_receiver(&acs.acp, &de.event) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -1011,115 +2310,287 @@ pub trait AccessControlsTransaction {
                         );
                         None
                     }
-                }
-            })
+                },
+            )
             .collect();
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+        security_log!(audit, "Related acs -> {:?}", related_acp);
 
         // For each entry
-        let r = entries.iter().fold(true, |acc, e| {
-            if acc == false {
-                // Any false, denies the whole operation.
-                false
-            } else {
-                related_acp.iter().fold(false, |r_acc, acd| {
-                    if r_acc == true {
-                        // If something allowed us to delete, skip doing silly work.
-                        r_acc
-                    } else {
-                        let f_val = acd.acp.targetscope.clone();
-                        match f_val.resolve(&de.event) {
-                            Ok(f_res) => {
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acd
-                                    );
-                                    // It matches, so we can delete this!
-                                    true
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acd
-                                    );
-                                    // Does not match, fail.
-                                    false
-                                }
+        let check = |audit: &mut AuditScope, related_acp: &Vec<&AccessControlDelete>| -> bool {
+            entries.iter().fold(true, |acc, e| {
+                if acc == false {
+                    // Any false, denies the whole operation.
+                    false
+                } else {
+                    // Any deny that covers the entry overrides the allows.
+                    let denied = related_acp
+                        .iter()
+                        .filter(|acd| acd.acp.deny && acd.class_match(e))
+                        .any(|acd| {
+                            match cache.resolve_targetscope(&acd.acp, &de.event) {
+                                Ok(f_res) => e.entry_match_no_index(&f_res),
+                                // Fail closed if we can't work out the scope.
+                                Err(_) => true,
                             }
-                            Err(e) => {
-                                audit_log!(
+                        });
+                    if denied {
+                        security_log!(audit, "entry {:?} is denied by a deny acs", e.get_uuid());
+                        return false;
+                    }
+
+                    related_acp
+                        .iter()
+                        .filter(|acd| !acd.acp.deny)
+                        .fold(false, |r_acc, acd| {
+                            if r_acc == true {
+                                // If something allowed us to delete, skip doing silly work.
+                                r_acc
+                            } else if !acd.class_match(e) {
+                                // Even if the targetscope matches, this profile
+                                // may not delete entries of this class.
+                                security_log!(
                                     audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
+                                    "entry {:?} is not of a class acs {:?} may delete",
+                                    e.get_uuid(),
+                                    acd
                                 );
-                                // Default to failing here.
                                 false
-                            }
-                        } // match
-                    } // else
-                }) // fold related_acp
-            } // if/else
-        });
+                            } else {
+                                match cache.resolve_targetscope(&acd.acp, &de.event) {
+                                    Ok(f_res) => {
+                                        if e.entry_match_no_index(&f_res) {
+                                            audit_log!(
+                                                audit,
+                                                "entry {:?} matches acs {:?}",
+                                                e.get_uuid(),
+                                                acd
+                                            );
+                                            // It matches, so we can delete this!
+                                            true
+                                        } else {
+                                            audit_log!(
+                                                audit,
+                                                "entry {:?} DOES NOT match acs {:?}",
+                                                e.get_uuid(),
+                                                acd
+                                            );
+                                            // Does not match, fail.
+                                            false
+                                        }
+                                    }
+                                    Err(e) => {
+                                        audit_log!(
+                                            audit,
+                                            "A internal filter was passed for resolution!?!? {:?}",
+                                            e
+                                        );
+                                        // Default to failing here.
+                                        false
+                                    }
+                                } // match
+                            } // else
+                        }) // fold related_acp
+                } // if/else
+            })
+        };
+
+        let enforced_acp: Vec<&AccessControlDelete> = related_acp
+            .iter()
+            .filter(|acd| acd.acp.mode == AccessControlMode::Enforce)
+            .map(|acd| *acd)
+            .collect();
+        let r = check(audit, &enforced_acp);
+        if enforced_acp.len() != related_acp.len() {
+            let r_log_only = check(audit, &related_acp);
+            acp_log_only_report(audit, "delete decision", &r, &r_log_only);
+        }
         Ok(r)
     }
-}
 
-pub struct AccessControlsWriteTransaction<'a> {
-    inner: CowCellWriteTxn<'a, AccessControlsInner>,
-}
+    // Which of these entries' audit records the initiator may read, or None
+    // when no audit read profile applies to them at all. As elsewhere, a deny
+    // whose targetscope matches an entry overrides the allows.
+    fn audit_read_allow_operation(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        origins: &[Entry<EntryValid, EntryCommitted>],
+    ) -> Result<Option<BTreeSet<String>>, OperationError> {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+            EventOrigin::Internal => {
+                return Ok(Some(origins.iter().map(|e| e.get_uuid().clone()).collect()));
+            }
+            EventOrigin::User(e) => &e,
+        };
 
-impl<'a> AccessControlsWriteTransaction<'a> {
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+
+        let related_acp: Vec<&AccessControlAuditRead> = state
+            .acps_audit_read
+            .values()
+            .filter(|acr| {
+                acr.acp.mode == AccessControlMode::Enforce
+                    && acp_receiver_match(audit, cache, ev, &acr.acp, rec_entry)
+            })
+            .collect();
+        security_log!(audit, "Related audit read acs -> {:?}", related_acp);
+        if !related_acp.iter().any(|acr| !acr.acp.deny) {
+            return Ok(None);
+        }
+
+        let allowed = origins
+            .iter()
+            .filter(|e| {
+                let scoped: Vec<&&AccessControlAuditRead> = related_acp
+                    .iter()
+                    .filter(|acr| acp_targetscope_match(audit, cache, ev, &acr.acp, e))
+                    .collect();
+                scoped.iter().any(|acr| !acr.acp.deny) && !scoped.iter().any(|acr| acr.acp.deny)
+            })
+            .map(|e| e.get_uuid().clone())
+            .collect();
+        Ok(Some(allowed))
+    }
+
+    // May the initiator run the named server operation? As elsewhere, a deny
+    // naming the operation overrides the allows.
+    fn operation_allow(&self, audit: &mut AuditScope, ev: &Event, operation: &str) -> bool {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+            EventOrigin::Internal => return true,
+            EventOrigin::User(e) => &e,
+        };
+
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+
+        let related_acp: Vec<&AccessControlOperation> = state
+            .acps_operation
+            .values()
+            .filter(|aco| {
+                aco.acp.mode == AccessControlMode::Enforce
+                    && aco.operations.iter().any(|o| o == operation)
+                    && acp_receiver_match(audit, cache, ev, &aco.acp, rec_entry)
+            })
+            .collect();
+        security_log!(audit, "Related {} acs -> {:?}", operation, related_acp);
+        related_acp.iter().any(|aco| !aco.acp.deny) && !related_acp.iter().any(|aco| aco.acp.deny)
+    }
+
+    // May the initiator compare a value of the attribute on this entry? Being
+    // able to read the attribute is enough, otherwise a compare acp must grant
+    // it. As with search, a deny naming the attribute overrides the allows.
+    fn compare_allow_operation(
+        &self,
+        audit: &mut AuditScope,
+        ce: &CompareEvent,
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<bool, OperationError> {
+        security_log!(audit, "Access check for event: {:?}", ce);
+
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ce.event.origin {
+            EventOrigin::Internal => {
+                // No need to check ACS
+                return Ok(true);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        let state = self.get_inner();
+        let cache = self.get_filter_cache();
+        let attr = ce.attr.as_str();
+
+        let check = |audit: &mut AuditScope, log_only: bool| -> bool {
+            let applies = |acp: &AccessControlProfile| {
+                acp.mode == AccessControlMode::Enforce
+                    || (log_only && acp.mode == AccessControlMode::LogOnly)
+            };
+
+            let related_search: Vec<&AccessControlSearch> = state
+                .acps_search
+                .values()
+                .filter(|acs| {
+                    applies(&acs.acp)
+                        && acp_receiver_match(audit, cache, &ce.event, &acs.acp, rec_entry)
+                })
+                .collect();
+            let scoped_search = search_scoped_acp(audit, cache, &ce.event, &related_search, e);
+            if search_allowed_attrs(&scoped_search).contains(attr) {
+                audit_log!(audit, "{} may be read, so may be compared", attr);
+                return true;
+            }
+
+            let scoped_compare: Vec<&AccessControlCompare> = state
+                .acps_compare
+                .values()
+                .filter(|acc| {
+                    applies(&acc.acp)
+                        && acc.attrs.iter().any(|a| a == attr)
+                        && acp_receiver_match(audit, cache, &ce.event, &acc.acp, rec_entry)
+                        && acp_targetscope_match(audit, cache, &ce.event, &acc.acp, e)
+                })
+                .collect();
+            audit_log!(audit, "Scoped compare acs -> {:?}", scoped_compare);
+            scoped_compare.iter().any(|acc| !acc.acp.deny)
+                && !scoped_compare.iter().any(|acc| acc.acp.deny)
+        };
+
+        let r = check(audit, false);
+        let log_only_present = state
+            .acps_search
+            .values()
+            .map(|a| &a.acp)
+            .chain(state.acps_compare.values().map(|a| &a.acp))
+            .any(|acp| acp.mode == AccessControlMode::LogOnly);
+        if log_only_present {
+            let r_log_only = check(audit, true);
+            acp_log_only_report(audit, "compare decision", &r, &r_log_only);
+        }
+        Ok(r)
+    }
+}
+
+pub struct AccessControlsWriteTransaction<'a> {
+    inner: CowCellWriteTxn<'a, AccessControlsInner>,
+    filter_cache: ResolvedFilterCache,
+}
+
+impl<'a> AccessControlsWriteTransaction<'a> {
     fn get_inner_mut(&mut self) -> &mut AccessControlsInner {
+        // Any change to the acps may invalidate what we have resolved.
+        self.filter_cache.clear();
         &mut self.inner
     }
 
     // We have a method to update each set, so that if an error
     // occurs we KNOW it's an error, rather than using errors as
     // part of the logic (IE try-parse-fail method).
-    pub fn update_search(&mut self, acps: Vec<AccessControlSearch>) -> Result<(), OperationError> {
+    pub fn update<T: AccessControlKind>(&mut self, acps: Vec<T>) -> Result<(), OperationError> {
         // Clear the existing tree. We don't care that we are wiping it
         // because we have the transactions to protect us from errors
         // to allow rollbacks.
-        let inner = self.get_inner_mut();
-        inner.acps_search.clear();
-        for acp in acps {
-            let uuid = acp.acp.uuid.clone();
-            inner.acps_search.insert(uuid, acp);
-        }
-        Ok(())
-    }
-
-    pub fn update_create(&mut self, acps: Vec<AccessControlCreate>) -> Result<(), OperationError> {
-        let inner = self.get_inner_mut();
-        inner.acps_create.clear();
+        let set = T::get_set_mut(self.get_inner_mut());
+        set.clear();
         for acp in acps {
-            let uuid = acp.acp.uuid.clone();
-            inner.acps_create.insert(uuid, acp);
+            set.insert(acp.uuid().to_string(), acp);
         }
         Ok(())
     }
 
-    pub fn update_modify(&mut self, acps: Vec<AccessControlModify>) -> Result<(), OperationError> {
-        let inner = self.get_inner_mut();
-        inner.acps_modify.clear();
-        for acp in acps {
-            let uuid = acp.acp.uuid.clone();
-            inner.acps_modify.insert(uuid, acp);
+    // Update only the acps whose uuids are in changed. Any of those that are
+    // not present in acps are no longer valid for this set and are removed.
+    pub fn update_partial<T: AccessControlKind>(
+        &mut self,
+        changed: &BTreeSet<String>,
+        acps: Vec<T>,
+    ) -> Result<(), OperationError> {
+        let set = T::get_set_mut(self.get_inner_mut());
+        for uuid in changed.iter() {
+            set.remove(uuid);
         }
-        Ok(())
-    }
-
-    pub fn update_delete(&mut self, acps: Vec<AccessControlDelete>) -> Result<(), OperationError> {
-        let inner = self.get_inner_mut();
-        inner.acps_delete.clear();
         for acp in acps {
-            let uuid = acp.acp.uuid.clone();
-            inner.acps_delete.insert(uuid, acp);
+            set.insert(acp.uuid().to_string(), acp);
         }
         Ok(())
     }
@@ -1134,6 +2605,10 @@ impl<'a> AccessControlsTransaction for AccessControlsWriteTransaction<'a> {
     fn get_inner(&self) -> &AccessControlsInner {
         &self.inner
     }
+
+    fn get_filter_cache(&self) -> &ResolvedFilterCache {
+        &self.filter_cache
+    }
 }
 
 // =========================================================================
@@ -1142,12 +2617,17 @@ impl<'a> AccessControlsTransaction for AccessControlsWriteTransaction<'a> {
 
 pub struct AccessControlsReadTransaction {
     inner: CowCellReadTxn<AccessControlsInner>,
+    filter_cache: ResolvedFilterCache,
 }
 
 impl AccessControlsTransaction for AccessControlsReadTransaction {
     fn get_inner(&self) -> &AccessControlsInner {
         &self.inner
     }
+
+    fn get_filter_cache(&self) -> &ResolvedFilterCache {
+        &self.filter_cache
+    }
 }
 
 // =========================================================================
@@ -1164,30 +2644,258 @@ impl AccessControls {
     pub fn read(&self) -> AccessControlsReadTransaction {
         AccessControlsReadTransaction {
             inner: self.inner.read(),
+            filter_cache: ResolvedFilterCache::new(),
         }
     }
 
     pub fn write(&self) -> AccessControlsWriteTransaction {
         AccessControlsWriteTransaction {
             inner: self.inner.write(),
+            filter_cache: ResolvedFilterCache::new(),
         }
     }
 }
 
+// =========================================================================
+// ACP simulation
+// =========================================================================
+
+// A set of acps to evaluate without installing them. These are parsed from
+// entries, so a proposal can be built from acp entries that are created in a
+// write transaction which is then never committed.
+//
+// Simulation is only driven from tests today, which is where policy checks in
+// ci run, so none of this is reachable from the server itself.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct AccessControlsProposal {
+    pub search: Vec<AccessControlSearch>,
+    pub create: Vec<AccessControlCreate>,
+    pub modify: Vec<AccessControlModify>,
+    pub delete: Vec<AccessControlDelete>,
+}
+
+impl AccessControlsProposal {
+    #[allow(dead_code)]
+    pub fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Self, OperationError> {
+        let mut proposal = AccessControlsProposal {
+            search: Vec::new(),
+            create: Vec::new(),
+            modify: Vec::new(),
+            delete: Vec::new(),
+        };
+        // An acp entry may carry more than one of these classes, so each is
+        // checked independently.
+        for e in entries {
+            if e.attribute_value_pres("class", "access_control_search") {
+                proposal
+                    .search
+                    .push(AccessControlSearch::try_from(audit, qs, e)?);
+            }
+            if e.attribute_value_pres("class", "access_control_create") {
+                proposal
+                    .create
+                    .push(AccessControlCreate::try_from(audit, qs, e)?);
+            }
+            if e.attribute_value_pres("class", "access_control_modify") {
+                proposal
+                    .modify
+                    .push(AccessControlModify::try_from(audit, qs, e)?);
+            }
+            if e.attribute_value_pres("class", "access_control_delete") {
+                proposal
+                    .delete
+                    .push(AccessControlDelete::try_from(audit, qs, e)?);
+            }
+        }
+        Ok(proposal)
+    }
+}
+
+// An operation to test against a proposal, with the entries it would act on.
+// For search these are the candidates before access controls are applied.
+#[allow(dead_code)]
+pub enum SimulatedOperation<'a> {
+    Search(&'a SearchEvent, Vec<Entry<EntryValid, EntryCommitted>>),
+    Create(&'a CreateEvent, Vec<Entry<EntryNormalised, EntryNew>>),
+    Modify(&'a ModifyEvent, Vec<Entry<EntryValid, EntryCommitted>>),
+    Delete(&'a DeleteEvent, Vec<Entry<EntryValid, EntryCommitted>>),
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct SimulationOutcome {
+    // For search, allowed means every candidate entry remains visible.
+    pub allowed: bool,
+    // The names of the acps that applied - the receiver matched the initiator
+    // and the targetscope covered at least one of the entries.
+    pub allowed_by: Vec<String>,
+    pub denied_by: Vec<String>,
+}
+
+// Split the acps that apply to an operation into allows and denies, by name.
+#[allow(dead_code)]
+fn simulate_applied<'a, I, F>(
+    audit: &mut AuditScope,
+    cache: &ResolvedFilterCache,
+    ev: &Event,
+    rec_entry: &Entry<EntryValid, EntryCommitted>,
+    acps: I,
+    covers: F,
+) -> (Vec<String>, Vec<String>)
+where
+    I: Iterator<Item = &'a AccessControlProfile>,
+    F: Fn(&mut AuditScope, &AccessControlProfile) -> bool,
+{
+    let (denies, allows): (Vec<&AccessControlProfile>, Vec<&AccessControlProfile>) = acps
+        .filter(|acp| acp_receiver_match(audit, cache, ev, *acp, rec_entry) && covers(audit, *acp))
+        .partition(|acp| acp.deny);
+    (
+        allows.into_iter().map(|acp| acp.name.clone()).collect(),
+        denies.into_iter().map(|acp| acp.name.clone()).collect(),
+    )
+}
+
+#[allow(dead_code)]
+fn simulate_operation<T: AccessControlsTransaction>(
+    audit: &mut AuditScope,
+    txn: &T,
+    op: &SimulatedOperation,
+) -> Result<SimulationOutcome, OperationError> {
+    let ev = match op {
+        SimulatedOperation::Search(se, _) => &se.event,
+        SimulatedOperation::Create(ce, _) => &ce.event,
+        SimulatedOperation::Modify(me, _) => &me.event,
+        SimulatedOperation::Delete(de, _) => &de.event,
+    };
+    // Internal operations bypass access controls, so simulating them says
+    // nothing about the proposal.
+    let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+        EventOrigin::Internal => return Err(OperationError::InvalidRequestState),
+        EventOrigin::User(e) => &e,
+    };
+
+    let state = txn.get_inner();
+    let cache = txn.get_filter_cache();
+    let covers_any = |audit: &mut AuditScope,
+                      acp: &AccessControlProfile,
+                      entries: &Vec<Entry<EntryValid, EntryCommitted>>| {
+        entries
+            .iter()
+            .any(|e| acp_targetscope_match(audit, cache, ev, acp, e))
+    };
+
+    let (allowed, (allowed_by, denied_by)) = match op {
+        SimulatedOperation::Search(se, entries) => {
+            let visible = txn.search_filter_entries(audit, se, entries.clone(), None)?;
+            (
+                visible.len() == entries.len(),
+                simulate_applied(
+                    audit,
+                    cache,
+                    ev,
+                    rec_entry,
+                    state.acps_search.values().map(|acs| &acs.acp),
+                    |audit, acp| covers_any(audit, acp, entries),
+                ),
+            )
+        }
+        SimulatedOperation::Create(ce, entries) => (
+            txn.create_allow_operation(audit, ce, entries)?,
+            simulate_applied(
+                audit,
+                cache,
+                ev,
+                rec_entry,
+                state.acps_create.values().map(|acc| &acc.acp),
+                |_, acp| match cache.resolve_targetscope(acp, ev) {
+                    Ok(f_res) => entries.iter().any(|e| e.entry_match_no_index(&f_res)),
+                    Err(_) => false,
+                },
+            ),
+        ),
+        SimulatedOperation::Modify(me, entries) => (
+            txn.modify_allow_operation(audit, me, entries)?,
+            simulate_applied(
+                audit,
+                cache,
+                ev,
+                rec_entry,
+                state.acps_modify.values().map(|acm| &acm.acp),
+                |audit, acp| covers_any(audit, acp, entries),
+            ),
+        ),
+        SimulatedOperation::Delete(de, entries) => (
+            txn.delete_allow_operation(audit, de, entries)?,
+            simulate_applied(
+                audit,
+                cache,
+                ev,
+                rec_entry,
+                state.acps_delete.values().map(|acd| &acd.acp),
+                |audit, acp| covers_any(audit, acp, entries),
+            ),
+        ),
+    };
+
+    Ok(SimulationOutcome {
+        allowed: allowed,
+        allowed_by: allowed_by,
+        denied_by: denied_by,
+    })
+}
+
+impl AccessControls {
+    // Evaluate a set of operations as though the proposal were the complete
+    // set of acps. The proposal is loaded into a scratch instance, so nothing
+    // the server is using is touched. This is intended for checking access
+    // policy changes (for example in ci) before they are applied.
+    #[allow(dead_code)]
+    pub fn simulate(
+        audit: &mut AuditScope,
+        proposal: AccessControlsProposal,
+        operations: &Vec<SimulatedOperation>,
+    ) -> Result<Vec<SimulationOutcome>, OperationError> {
+        let scratch = AccessControls::new();
+        let mut acw = scratch.write();
+        acw.update(proposal.search)?;
+        acw.update(proposal.create)?;
+        acw.update(proposal.modify)?;
+        acw.update(proposal.delete)?;
+        let acw = acw;
+
+        operations
+            .iter()
+            .map(|op| simulate_operation(audit, &acw, op))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::access::{
-        AccessControlCreate, AccessControlDelete, AccessControlModify, AccessControlProfile,
-        AccessControlSearch, AccessControls, AccessControlsTransaction,
+        AccessControlCompare, AccessControlCreate, AccessControlDelete, AccessControlMode,
+        AccessControlModify, AccessControlOperation, AccessControlProfile, AccessControlSearch,
+        AccessControls, AccessControlsProposal, AccessControlsTransaction, ResolvedFilterCache,
+        SimulatedOperation, SimulationOutcome,
     };
-    use crate::audit::AuditScope;
-    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced};
+    use crate::audit::{use_log_levels, AuditLevel, AuditScope, LogLevels};
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::rc::Rc;
+    use std::sync::Arc;
     // use crate::server::QueryServerWriteTransaction;
 
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, SearchEvent};
+    use crate::event::{CompareEvent, CreateEvent, DeleteEvent, Event, ModifyEvent, SearchEvent};
     // use crate::filter::Filter;
     // use crate::proto_v1::Filter as ProtoFilter;
-    use crate::constants::{JSON_ADMIN_V1, JSON_ANONYMOUS_V1, JSON_TESTPERSON1, JSON_TESTPERSON2};
+    use crate::constants::{
+        JSON_ADMIN_V1, JSON_ANONYMOUS_V1, JSON_TESTPERSON1, JSON_TESTPERSON2, UUID_ADMIN,
+    };
 
     macro_rules! acp_from_entry_err {
         (
@@ -1280,7 +2988,6 @@ mod tests {
                 AccessControlProfile
             );
 
-            // "\"Self\""
             acp_from_entry_ok!(
                 audit,
                 &qs_write,
@@ -1301,6 +3008,51 @@ mod tests {
                 }"#,
                 AccessControlProfile
             );
+
+            // Self in either filter is the initiator of the event, so this
+            // applies to each user, for their own entry.
+            acp_from_entry_ok!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile"],
+                        "name": ["acp_valid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "\"Self\""
+                        ],
+                        "acp_targetscope": [
+                            "{\"And\":[\"Self\",{\"Pres\":\"name\"}]}"
+                        ]
+                    }
+                }"#,
+                AccessControlProfile
+            );
+
+            let acp_deny = acp_from_entry_ok!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_deny"],
+                        "name": ["acp_valid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ]
+                    }
+                }"#,
+                AccessControlProfile
+            );
+            assert!(acp_deny.deny);
         })
     }
 
@@ -1353,6 +3105,51 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_access_acp_mode_parser() {
+        run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
+            let qs_write = qs.write();
+
+            let acp_mode = |audit: &mut AuditScope, enable: &str, log_only: &str| {
+                let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                    format!(
+                        r#"{{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "access_control_profile", "access_control_delete"],
+                            "name": ["acp_valid"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                            "acp_enable": ["{}"],
+                            "acp_log_only": ["{}"],
+                            "acp_receiver": [
+                                "{{\"Eq\":[\"name\",\"a\"]}}"
+                            ],
+                            "acp_targetscope": [
+                                "{{\"Eq\":[\"name\",\"a\"]}}"
+                            ]
+                        }}
+                    }}"#,
+                        enable, log_only
+                    )
+                    .as_str(),
+                )
+                .expect("json failure");
+                let ev1 = unsafe { e1.to_valid_committed() };
+                AccessControlDelete::try_from(audit, &qs_write, &ev1)
+                    .expect("Failed to parse acp")
+                    .acp
+                    .mode
+            };
+
+            assert!(acp_mode(audit, "true", "false") == AccessControlMode::Enforce);
+            // Enable wins over log only.
+            assert!(acp_mode(audit, "true", "true") == AccessControlMode::Enforce);
+            assert!(acp_mode(audit, "false", "true") == AccessControlMode::LogOnly);
+            assert!(acp_mode(audit, "false", "false") == AccessControlMode::Disabled);
+        })
+    }
+
     #[test]
     fn test_access_acp_search_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
@@ -1526,6 +3323,87 @@ mod tests {
                 }"#,
                 AccessControlModify
             );
+
+            let acm = acp_from_entry_ok!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_modify"],
+                        "name": ["acp_valid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_modify_class": ["object"],
+                        "acp_modify_class_add": ["person"],
+                        "acp_modify_class_remove": ["account"]
+                    }
+                }"#,
+                AccessControlModify
+            );
+            // The legacy attribute grants both add and remove.
+            assert!(acm.addclasses == vec!["person".to_string(), "object".to_string()]);
+            assert!(acm.remclasses == vec!["account".to_string(), "object".to_string()]);
+
+            let acm = acp_from_entry_ok!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_modify"],
+                        "name": ["acp_valid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_modify_presentattr": ["member"],
+                        "acp_modify_presentvalue": ["member=self"],
+                        "acp_modify_removedattr": ["member"],
+                        "acp_modify_removedvalue": ["member=self", "member=admin"]
+                    }
+                }"#,
+                AccessControlModify
+            );
+            assert!(acm.presvalues.get("member") == Some(&vec!["self".to_string()]));
+            // The values are kept sorted, as the entry holds them.
+            assert!(
+                acm.remvalues.get("member") == Some(&vec!["admin".to_string(), "self".to_string()])
+            );
+
+            // A constraint must name both the attribute and the value.
+            acp_from_entry_err!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_modify"],
+                        "name": ["acp_invalid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_modify_presentattr": ["member"],
+                        "acp_modify_presentvalue": ["member"]
+                    }
+                }"#,
+                AccessControlModify
+            );
         })
     }
 
@@ -1658,17 +3536,23 @@ mod tests {
         ) => {{
             let ac = AccessControls::new();
             let mut acw = ac.write();
-            acw.update_search($controls).expect("Failed to update");
+            acw.update($controls).expect("Failed to update");
             let acw = acw;
 
             let mut audit = AuditScope::new("test_acp_search");
+            let entries = $entries;
             let res = acw
-                .search_filter_entries(&mut audit, $se, $entries)
+                .search_filter_entries(&mut audit, $se, entries.clone(), None)
                 .expect("op failed");
             println!("result --> {:?}", res);
             println!("expect --> {:?}", $expect);
             // should be ok, and same as expect.
             assert!(res == $expect);
+            // Checking on every core must not change the result, or its order.
+            let res_par = acw
+                .search_filter_entries(&mut audit, $se, entries, Some(1))
+                .expect("op failed");
+            assert!(res_par == res);
         }};
     }
 
@@ -1755,6 +3639,68 @@ mod tests {
         test_acp_search!(&se_anon, vec![acp], r_set, ex_anon);
     }
 
+    #[test]
+    fn test_access_enforce_search_tag() {
+        run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
+            let qs_write = qs.write();
+
+            // Those tagged ops may read whatever is tagged prod. The filters
+            // are checked against schema as the profile is parsed.
+            let e_acp: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_search"],
+                        "name": ["acp_tag"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"tag\",\"OPS\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"tag\",\"prod\"]}"
+                        ],
+                        "acp_search_attr": ["name", "tag"]
+                    }
+                }"#,
+            )
+            .expect("json failure");
+            let acp = AccessControlSearch::try_from(audit, &qs_write, &unsafe {
+                e_acp.to_valid_committed()
+            })
+            .expect("Failed to parse acp");
+
+            let tagged = |name: &str, tag: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", "object");
+                e.add_ava("name", name);
+                e.add_ava("tag", tag);
+                unsafe { e.to_valid_committed() }
+            };
+            let prod = tagged("prod_host", "prod");
+            let staging = tagged("staging_host", "staging");
+            let r_set = vec![prod.clone(), staging];
+
+            let se_ops = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    tagged("operator", "ops"),
+                    filter_all!(f_pres("name")),
+                )
+            };
+            let expect_ops = vec![prod];
+            test_acp_search!(&se_ops, vec![acp.clone()], r_set.clone(), expect_ops);
+
+            let se_dev = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    tagged("developer", "dev"),
+                    filter_all!(f_pres("name")),
+                )
+            };
+            let expect_dev: Vec<Entry<EntryValid, EntryCommitted>> = Vec::new();
+            test_acp_search!(&se_dev, vec![acp], r_set, expect_dev);
+        })
+    }
+
     macro_rules! test_acp_search_reduce {
         (
             $se:expr,
@@ -1764,13 +3710,13 @@ mod tests {
         ) => {{
             let ac = AccessControls::new();
             let mut acw = ac.write();
-            acw.update_search($controls).expect("Failed to update");
+            acw.update($controls).expect("Failed to update");
             let acw = acw;
 
             let mut audit = AuditScope::new("test_acp_search_reduce");
             // We still have to reduce the entries to be sure that we are good.
             let res = acw
-                .search_filter_entries(&mut audit, $se, $entries)
+                .search_filter_entries(&mut audit, $se, $entries, None)
                 .expect("operation failed");
             // Now on the reduced entries, reduce the entries attrs.
             let reduced = acw
@@ -1836,18 +3782,156 @@ mod tests {
         test_acp_search_reduce!(&se_anon, vec![acp], r_set, ex_anon);
     }
 
-    macro_rules! test_acp_modify {
-        (
-            $me:expr,
-            $controls:expr,
-            $entries:expr,
-            $expect:expr
-        ) => {{
-            let ac = AccessControls::new();
-            let mut acw = ac.write();
-            acw.update_modify($controls).expect("Failed to update");
-            let acw = acw;
-
+    #[test]
+    fn test_access_enforce_search_entry() {
+        // Granting the entry shows its uuid and name, and nothing else can be
+        // read or searched on.
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let ex1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        let exv1 = unsafe { ex1.to_valid_committed() };
+        let ex_none: Vec<Entry<EntryValid, EntryCommitted>> = vec![];
+
+        let se_uuid = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63930")),
+            )
+        };
+        let se_class = unsafe {
+            SearchEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, filter_all!(f_pres("class")))
+        };
+
+        let acs_entry = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_entry",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "entry",
+            )
+        };
+        test_acp_search_reduce!(&se_uuid, vec![acs_entry.clone()], r_set.clone(), vec![exv1]);
+        test_acp_search!(
+            &se_class,
+            vec![acs_entry.clone()],
+            r_set.clone(),
+            ex_none.clone()
+        );
+
+        // Denying the entry hides it, whatever else is granted.
+        let acs_allow = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_allow",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3e",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name uuid class",
+            )
+        };
+        let mut acs_deny = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_deny",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3f",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "entry",
+            )
+        };
+        acs_deny.acp.deny = true;
+        test_acp_search!(
+            &se_uuid,
+            vec![acs_entry, acs_allow, acs_deny],
+            r_set,
+            ex_none
+        );
+    }
+
+    #[test]
+    fn test_access_enforce_search_partial() {
+        // Anonymous may only read name. Without partial mode, any filter that
+        // mentions class hides the entry. With it, class terms are undefined,
+        // so the entry is returned only if the name terms alone decide the match.
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let ex1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1_REDUCED).expect("json failure");
+        let exv1 = unsafe { ex1.to_valid_committed() };
+        let ex_none: Vec<Entry<EntryValid, EntryCommitted>> = vec![];
+
+        let acp = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+
+        let mut se_or = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_or!([f_eq("name", "testperson1"), f_pres("class")])),
+            )
+        };
+        let mut se_and = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_and!([f_eq("name", "testperson1"), f_pres("class")])),
+            )
+        };
+        let mut se_andnot = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_and!([
+                    f_eq("name", "testperson1"),
+                    f_andnot(f_eq("class", "group"))
+                ])),
+            )
+        };
+
+        test_acp_search!(&se_or, vec![acp.clone()], r_set.clone(), ex_none.clone());
+
+        se_or.partial = true;
+        se_and.partial = true;
+        se_andnot.partial = true;
+
+        // The name term decides the or, and only name is returned.
+        test_acp_search_reduce!(&se_or, vec![acp.clone()], r_set.clone(), vec![exv1]);
+        // An undefined term can't satisfy an and, even when negated.
+        test_acp_search!(&se_and, vec![acp.clone()], r_set.clone(), ex_none.clone());
+        test_acp_search!(&se_andnot, vec![acp], r_set, ex_none);
+    }
+
+    macro_rules! test_acp_modify {
+        (
+            $me:expr,
+            $controls:expr,
+            $entries:expr,
+            $expect:expr
+        ) => {{
+            let ac = AccessControls::new();
+            let mut acw = ac.write();
+            acw.update($controls).expect("Failed to update");
+            let acw = acw;
+
             let mut audit = AuditScope::new("test_acp_modify");
             let res = acw
                 .modify_allow_operation(&mut audit, $me, $entries)
@@ -1997,6 +4081,38 @@ mod tests {
         test_acp_modify!(&me_rem_class, vec![acp_no_class.clone()], &r_set, false);
         // test reject rem class, class in classes but not in pres attrs
         test_acp_modify!(&me_rem_class, vec![acp_deny.clone()], &r_set, false);
+
+        // May only add the account class, not remove it.
+        let mut acp_add_only = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_add_only",
+                "87bfe9b8-7600-431e-a492-1dde64bbc458",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "class",
+                "class",
+                "account",
+            )
+        };
+        acp_add_only.remclasses.clear();
+        // May only remove the account class, not add it.
+        let mut acp_rem_only = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_rem_only",
+                "87bfe9b8-7600-431e-a492-1dde64bbc459",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "class",
+                "class",
+                "account",
+            )
+        };
+        acp_rem_only.addclasses.clear();
+
+        test_acp_modify!(&me_pres_class, vec![acp_add_only.clone()], &r_set, true);
+        test_acp_modify!(&me_rem_class, vec![acp_add_only], &r_set, false);
+        test_acp_modify!(&me_pres_class, vec![acp_rem_only.clone()], &r_set, false);
+        test_acp_modify!(&me_rem_class, vec![acp_rem_only], &r_set, true);
     }
 
     macro_rules! test_acp_create {
@@ -2008,7 +4124,7 @@ mod tests {
         ) => {{
             let ac = AccessControls::new();
             let mut acw = ac.write();
-            acw.update_create($controls).expect("Failed to update");
+            acw.update($controls).expect("Failed to update");
             let acw = acw;
 
             let mut audit = AuditScope::new("test_acp_create");
@@ -2142,7 +4258,7 @@ mod tests {
         ) => {{
             let ac = AccessControls::new();
             let mut acw = ac.write();
-            acw.update_delete($controls).expect("Failed to update");
+            acw.update($controls).expect("Failed to update");
             let acw = acw;
 
             let mut audit = AuditScope::new("test_acp_delete");
@@ -2185,6 +4301,7 @@ mod tests {
                 filter_valid!(f_eq("name", "admin")),
                 // To delete testperson
                 filter_valid!(f_eq("name", "testperson1")),
+                "",
             )
         };
 
@@ -2193,4 +4310,974 @@ mod tests {
         // Test reject delete
         test_acp_delete!(&de_anon, vec![acp], &r_set, false);
     }
+
+    #[test]
+    fn test_access_enforce_delete_class() {
+        let mut e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        e1.add_ava("class", "person");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let de_admin = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+
+        // The targetscope covers everything, but only groups may be deleted.
+        let acp_group = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete_group",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_pres("name")),
+                "group",
+            )
+        };
+        test_acp_delete!(&de_admin, vec![acp_group.clone()], &r_set, false);
+
+        let acp_person = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete_person",
+                "87bfe9b8-7600-431e-a492-1dde64bbc454",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_pres("name")),
+                "person group",
+            )
+        };
+        test_acp_delete!(&de_admin, vec![acp_person.clone()], &r_set, true);
+
+        // A deny restricted to groups doesn't stop deleting a person.
+        let mut acp_deny = acp_group;
+        acp_deny.acp.deny = true;
+        test_acp_delete!(&de_admin, vec![acp_person, acp_deny], &r_set, true);
+    }
+
+    macro_rules! test_acp_compare {
+        (
+            $ce:expr,
+            $search:expr,
+            $controls:expr,
+            $entry:expr,
+            $expect:expr
+        ) => {{
+            let ac = AccessControls::new();
+            let mut acw = ac.write();
+            acw.update::<AccessControlSearch>($search)
+                .expect("Failed to update");
+            acw.update::<AccessControlCompare>($controls)
+                .expect("Failed to update");
+            let acw = acw;
+
+            let mut audit = AuditScope::new("test_acp_compare");
+            let res = acw
+                .compare_allow_operation(&mut audit, $ce, $entry)
+                .expect("op failed");
+            println!("result --> {:?}", res);
+            println!("expect --> {:?}", $expect);
+            assert!(res == $expect);
+        }};
+    }
+
+    #[test]
+    fn test_access_enforce_compare() {
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+
+        let ce_name = unsafe {
+            CompareEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930",
+                "name",
+                "testperson1",
+            )
+        };
+        let ce_class = unsafe {
+            CompareEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930",
+                "class",
+                "object",
+            )
+        };
+
+        let acp_search = unsafe {
+            AccessControlSearch::from_raw(
+                "test_search",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "class",
+            )
+        };
+        let acp = unsafe {
+            AccessControlCompare::from_raw(
+                "test_compare",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+
+        // Nothing granted.
+        test_acp_compare!(&ce_name, vec![], vec![], &ev1, false);
+        // Reading an attribute is enough to compare it.
+        test_acp_compare!(&ce_class, vec![acp_search.clone()], vec![], &ev1, true);
+        test_acp_compare!(&ce_name, vec![acp_search], vec![], &ev1, false);
+        // A compare acp grants only its own attributes.
+        test_acp_compare!(&ce_name, vec![], vec![acp.clone()], &ev1, true);
+        test_acp_compare!(&ce_class, vec![], vec![acp.clone()], &ev1, false);
+        // A deny overrides the allow.
+        let mut acp_deny = acp.clone();
+        acp_deny.acp.uuid = "87bfe9b8-7600-431e-a492-1dde64bbc457".to_string();
+        acp_deny.acp.deny = true;
+        test_acp_compare!(&ce_name, vec![], vec![acp, acp_deny], &ev1, false);
+    }
+
+    #[test]
+    fn test_access_enforce_operation() {
+        let ev_admin = unsafe {
+            SearchEvent::new_impersonate_entry_ser(JSON_ADMIN_V1, filter_all!(f_pres("class")))
+        };
+        let ev_anon = unsafe {
+            SearchEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, filter_all!(f_pres("class")))
+        };
+
+        let acp = unsafe {
+            AccessControlOperation::from_raw(
+                "test_operation",
+                "87bfe9b8-7600-431e-a492-1dde64bbc458",
+                filter_valid!(f_eq("name", "admin")),
+                "backup",
+            )
+        };
+
+        let check = |acps: Vec<AccessControlOperation>, ev: &SearchEvent, op: &str| {
+            let ac = AccessControls::new();
+            let mut acw = ac.write();
+            acw.update(acps).expect("Failed to update");
+            let mut audit = AuditScope::new("test_acp_operation");
+            acw.operation_allow(&mut audit, &ev.event, op)
+        };
+
+        // Nothing granted.
+        assert!(!check(vec![], &ev_admin, "backup"));
+        // Only to the receiver, and only the operations named.
+        assert!(check(vec![acp.clone()], &ev_admin, "backup"));
+        assert!(!check(vec![acp.clone()], &ev_anon, "backup"));
+        assert!(!check(vec![acp.clone()], &ev_admin, "replication"));
+        // A deny overrides the allow.
+        let mut acp_deny = acp.clone();
+        acp_deny.acp.uuid = "87bfe9b8-7600-431e-a492-1dde64bbc459".to_string();
+        acp_deny.acp.deny = true;
+        assert!(!check(vec![acp, acp_deny], &ev_admin, "backup"));
+    }
+
+    #[test]
+    fn test_access_enforce_log_only() {
+        // Only enforced acps may grant or deny - log-only and disabled acps
+        // must not change the outcome of any operation.
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let de_admin = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+
+        let acp = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+            )
+        };
+        let mut acp_log_only = acp.clone();
+        acp_log_only.acp.mode = AccessControlMode::LogOnly;
+        let mut acp_disabled = acp.clone();
+        acp_disabled.acp.mode = AccessControlMode::Disabled;
+
+        test_acp_delete!(&de_admin, vec![acp_log_only], &r_set, false);
+        test_acp_delete!(&de_admin, vec![acp_disabled], &r_set, false);
+
+        // A staged deny doesn't stop an enforced allow.
+        let mut acp_deny = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete_deny",
+                "87bfe9b8-7600-431e-a492-1dde64bbc454",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+            )
+        };
+        acp_deny.acp.deny = true;
+        acp_deny.acp.mode = AccessControlMode::LogOnly;
+        test_acp_delete!(&de_admin, vec![acp, acp_deny], &r_set, true);
+
+        // Search, where a log-only acp would expose the entry.
+        let se_admin = unsafe {
+            SearchEvent::new_impersonate_entry_ser(JSON_ADMIN_V1, filter_all!(f_pres("name")))
+        };
+        let mut acs_log_only = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+        acs_log_only.acp.mode = AccessControlMode::LogOnly;
+        let ex_none: Vec<Entry<EntryValid, EntryCommitted>> = vec![];
+        test_acp_search!(&se_admin, vec![acs_log_only], r_set, ex_none);
+    }
+
+    #[test]
+    fn test_access_enforce_deny() {
+        // A deny must always win over an allow that grants the same right.
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        // Search: class is denied, so only name remains visible, and a search
+        // that asserts on class can not see the entry at all.
+        let se_anon_name = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+        let se_anon_class = unsafe {
+            SearchEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, filter_all!(f_pres("class")))
+        };
+        let ex1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1_REDUCED).expect("json failure");
+        let exv1 = unsafe { ex1.to_valid_committed() };
+
+        let acs_allow = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_allow",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name class",
+            )
+        };
+        let mut acs_deny = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_deny",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3e",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "class",
+            )
+        };
+        acs_deny.acp.deny = true;
+
+        test_acp_search_reduce!(
+            &se_anon_name,
+            vec![acs_allow.clone(), acs_deny.clone()],
+            r_set.clone(),
+            vec![exv1]
+        );
+        let ex_none: Vec<Entry<EntryValid, EntryCommitted>> = vec![];
+        test_acp_search!(
+            &se_anon_class,
+            vec![acs_allow, acs_deny],
+            r_set.clone(),
+            ex_none
+        );
+
+        // Modify: name may be changed, but the deny removes the right to add
+        // the account class.
+        let me_pres = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("name", "value")]),
+            )
+        };
+        let me_pres_class = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("class", "account")]),
+            )
+        };
+        let acm_allow = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_allow",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name class",
+                "name class",
+                "account",
+            )
+        };
+        let mut acm_deny = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_deny",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+                "",
+                "account",
+            )
+        };
+        acm_deny.acp.deny = true;
+
+        test_acp_modify!(
+            &me_pres,
+            vec![acm_allow.clone(), acm_deny.clone()],
+            &r_set,
+            true
+        );
+        test_acp_modify!(&me_pres_class, vec![acm_allow, acm_deny], &r_set, false);
+
+        // Create: denying the account class rejects an otherwise allowed create.
+        let c1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TEST_CREATE_AC1).expect("json failure");
+        let cv1 = unsafe { c1.to_valid_normal() };
+        let c_set = vec![cv1];
+        let ce_admin = unsafe { CreateEvent::new_impersonate_entry_ser(JSON_ADMIN_V1, vec![]) };
+
+        let acc_allow = unsafe {
+            AccessControlCreate::from_raw(
+                "test_create",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "account",
+                "class name uuid",
+            )
+        };
+        let mut acc_deny = unsafe {
+            AccessControlCreate::from_raw(
+                "test_create_deny",
+                "87bfe9b8-7600-431e-a492-1dde64bbc457",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "account",
+                "",
+            )
+        };
+        acc_deny.acp.deny = true;
+
+        test_acp_create!(&ce_admin, vec![acc_allow.clone()], &c_set, true);
+        test_acp_create!(&ce_admin, vec![acc_allow, acc_deny], &c_set, false);
+
+        // Delete: a deny covering the entry blocks the delete.
+        let de_admin = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+        let acd_allow = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+            )
+        };
+        let mut acd_deny = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete_deny",
+                "87bfe9b8-7600-431e-a492-1dde64bbc458",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_pres("name")),
+                "",
+            )
+        };
+        acd_deny.acp.deny = true;
+
+        test_acp_delete!(&de_admin, vec![acd_allow.clone()], &r_set, true);
+        test_acp_delete!(&de_admin, vec![acd_allow, acd_deny], &r_set, false);
+    }
+
+    #[test]
+    fn test_access_effective_permissions() {
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+
+        let ev_admin = unsafe { Event::from_impersonate_entry_ser(JSON_ADMIN_V1) };
+        let ev_anon = unsafe { Event::from_impersonate_entry_ser(JSON_ANONYMOUS_V1) };
+
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update(vec![unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name uuid",
+            )
+        }])
+        .expect("Failed to update");
+        acw.update(vec![unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_allow",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+                "",
+                "account",
+            )
+        }])
+        .expect("Failed to update");
+        acw.update(vec![unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+            )
+        }])
+        .expect("Failed to update");
+        let acw = acw;
+
+        let mut audit = AuditScope::new("test_access_effective_permissions");
+        let ep = acw
+            .access_effective_permissions(&mut audit, &ev_admin, &ev1)
+            .expect("op failed");
+        assert!(ep.search == vec!["name".to_string(), "uuid".to_string()]);
+        assert!(ep.modify_present == vec!["name".to_string()]);
+        assert!(ep.modify_remove.is_empty());
+        assert!(ep.modify_class_add == vec!["account".to_string()]);
+        assert!(ep.modify_class_remove == vec!["account".to_string()]);
+        assert!(ep.delete);
+        // No create acps were loaded.
+        assert!(!ep.create);
+        // Each profile that applied is listed, once.
+        let names: Vec<&str> = ep.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert!(names == vec!["test_delete", "test_modify_allow", "test_acp"]);
+
+        // Anonymous is not the receiver of any of these.
+        let ep = acw
+            .access_effective_permissions(&mut audit, &ev_anon, &ev1)
+            .expect("op failed");
+        assert!(ep.search.is_empty());
+        assert!(ep.modify_present.is_empty());
+        assert!(!ep.delete);
+        assert!(!ep.create);
+        assert!(ep.profiles.is_empty());
+
+        // Value constraints are reported with the attributes they limit.
+        let mut acp_self = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_self",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "member name",
+                "member",
+                "",
+            )
+        };
+        acp_self
+            .presvalues
+            .insert("member".to_string(), vec!["self".to_string()]);
+        acp_self
+            .remvalues
+            .insert("member".to_string(), vec!["self".to_string()]);
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update(vec![acp_self.clone()])
+            .expect("Failed to update");
+        let ep = acw
+            .access_effective_permissions(&mut audit, &ev_admin, &ev1)
+            .expect("op failed");
+        let admin_uuid = UUID_ADMIN.to_string();
+        assert!(ep.modify_present == vec!["member".to_string(), "name".to_string()]);
+        assert!(ep.modify_present_values.len() == 1);
+        assert!(ep.modify_present_values.get("member") == Some(&vec![admin_uuid.clone()]));
+        assert!(ep.modify_remove_values.get("member") == Some(&vec![admin_uuid]));
+
+        // An unconstrained profile alongside lifts the limit.
+        let acp_all = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_all",
+                "87bfe9b8-7600-431e-a492-1dde64bbc457",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "member",
+                "",
+                "",
+            )
+        };
+        acw.update(vec![acp_self, acp_all])
+            .expect("Failed to update");
+        let ep = acw
+            .access_effective_permissions(&mut audit, &ev_admin, &ev1)
+            .expect("op failed");
+        assert!(ep.modify_present_values.is_empty());
+        assert!(ep.modify_remove_values.get("member").is_some());
+    }
+
+    #[test]
+    fn test_access_partial_update() {
+        let acp_a = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_a",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+        let acp_b = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp_b",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3e",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update(vec![acp_a.clone(), acp_b])
+            .expect("Failed to update");
+
+        // Changing b, but not supplying it, removes only b.
+        let mut changed = BTreeSet::new();
+        changed.insert("d38640c4-0254-49f9-99b7-8ba7d0233f3e".to_string());
+        acw.update_partial::<AccessControlSearch>(&changed, vec![])
+            .expect("Failed to update");
+        assert!(acw.get_inner().acps_search.len() == 1);
+        assert!(acw
+            .get_inner()
+            .acps_search
+            .contains_key("d38640c4-0254-49f9-99b7-8ba7d0233f3d"));
+
+        // Changing a with a new version replaces it in place.
+        let mut acp_a2 = acp_a;
+        acp_a2.attrs = vec!["name".to_string(), "uuid".to_string()];
+        changed.clear();
+        changed.insert("d38640c4-0254-49f9-99b7-8ba7d0233f3d".to_string());
+        acw.update_partial(&changed, vec![acp_a2])
+            .expect("Failed to update");
+        let inner = acw.get_inner();
+        assert!(inner.acps_search.len() == 1);
+        assert!(
+            inner
+                .acps_search
+                .get("d38640c4-0254-49f9-99b7-8ba7d0233f3d")
+                .expect("acp missing")
+                .attrs
+                .len()
+                == 2
+        );
+    }
+
+    #[test]
+    fn test_access_filter_cache() {
+        let acp = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+        let ev_admin = unsafe { Event::from_impersonate_entry_ser(JSON_ADMIN_V1) };
+
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update(vec![acp.clone()]).expect("Failed to update");
+
+        // The second resolution must come from the cache.
+        let f1 = acw
+            .get_filter_cache()
+            .resolve_targetscope(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        let f2 = acw
+            .get_filter_cache()
+            .resolve_targetscope(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        assert!(Rc::ptr_eq(&f1, &f2));
+        let r1 = acw
+            .get_filter_cache()
+            .resolve_receiver(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        let r2 = acw
+            .get_filter_cache()
+            .resolve_receiver(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        assert!(Rc::ptr_eq(&r1, &r2));
+        assert!(!Rc::ptr_eq(&f1, &r1));
+
+        // Reloading the acps invalidates what was resolved.
+        acw.update(vec![acp.clone()]).expect("Failed to update");
+        assert!(acw.get_filter_cache().targetscopes.borrow().len() == 0);
+        assert!(acw.get_filter_cache().receivers.borrow().len() == 0);
+        acw.commit().expect("Failed to commit");
+
+        // Past capacity, the least recently used filter is resolved again.
+        let ev_anon = unsafe { Event::from_impersonate_entry_ser(JSON_ANONYMOUS_V1) };
+        let cache = ResolvedFilterCache::with_capacity(1);
+        let a1 = cache
+            .resolve_targetscope(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        cache
+            .resolve_targetscope(&acp.acp, &ev_anon)
+            .expect("resolve failed");
+        let a2 = cache
+            .resolve_targetscope(&acp.acp, &ev_admin)
+            .expect("resolve failed");
+        assert!(!Rc::ptr_eq(&a1, &a2));
+        assert!(cache.targetscopes.borrow().len() == 1);
+
+        // A write copies the acps, but shares their filters with readers.
+        let acr = ac.read();
+        let mut acw = ac.write();
+        let key = "d38640c4-0254-49f9-99b7-8ba7d0233f3d";
+        let r_acp = &acr.get_inner().acps_search[key].acp;
+        let w_acp = &acw.get_inner_mut().acps_search[key].acp;
+        assert!(Arc::ptr_eq(&r_acp.receiver, &w_acp.receiver));
+        assert!(Arc::ptr_eq(&r_acp.targetscope, &w_acp.targetscope));
+    }
+
+    #[test]
+    fn test_access_enforce_modify_self() {
+        // "Users may modify their own displayname" as a single acp.
+        let e_admin: Entry<EntryValid, EntryNew> =
+            serde_json::from_str(JSON_ADMIN_V1).expect("json failure");
+        let ev_admin = unsafe { e_admin.to_valid_committed() };
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+
+        let me_self = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "admin")),
+                modlist!([m_pres("displayname", "value")]),
+            )
+        };
+        let me_other = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("displayname", "value")]),
+            )
+        };
+
+        let acp_self = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_self",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_self()),
+                filter_valid!(f_self()),
+                "displayname",
+                "displayname",
+                "",
+            )
+        };
+
+        test_acp_modify!(&me_self, vec![acp_self.clone()], &vec![ev_admin], true);
+        test_acp_modify!(&me_other, vec![acp_self], &vec![ev1], false);
+    }
+
+    #[test]
+    fn test_access_enforce_modify_managed() {
+        // "Anyone may modify the entries they manage" as a single acp.
+        let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object"],
+                    "name": ["testperson1"],
+                    "managed_by": ["00000000-0000-0000-0000-000000000000"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let e2: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON2).expect("json failure");
+        let ev2 = unsafe { e2.to_valid_committed() };
+
+        let me_admin_managed = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("displayname", "value")]),
+            )
+        };
+        let me_admin_other = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson2")),
+                modlist!([m_pres("displayname", "value")]),
+            )
+        };
+        // The same acp resolves differently for a different receiver.
+        let me_anon_managed = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("displayname", "value")]),
+            )
+        };
+
+        let acp_managed = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_managed",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_pres("name")),
+                filter_valid!(f_eq_self("managed_by")),
+                "displayname",
+                "displayname",
+                "",
+            )
+        };
+
+        test_acp_modify!(
+            &me_admin_managed,
+            vec![acp_managed.clone()],
+            &vec![ev1.clone()],
+            true
+        );
+        test_acp_modify!(
+            &me_admin_other,
+            vec![acp_managed.clone()],
+            &vec![ev2],
+            false
+        );
+        test_acp_modify!(&me_anon_managed, vec![acp_managed], &vec![ev1], false);
+    }
+
+    #[test]
+    fn test_access_enforce_modify_values() {
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        // Admin adding themself is allowed, but not anyone else.
+        let me_pres_self = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("member", "00000000-0000-0000-0000-000000000000")]),
+            )
+        };
+        let me_pres_other = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("member", "cc8e95b4-c24f-4d68-ba54-8bed76f63930")]),
+            )
+        };
+        let me_rem_self = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_remove("member", "00000000-0000-0000-0000-000000000000")]),
+            )
+        };
+        let me_purge = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_purge("member")]),
+            )
+        };
+
+        let mut acp_self = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_self",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "member",
+                "member",
+                "",
+            )
+        };
+        acp_self
+            .presvalues
+            .insert("member".to_string(), vec!["self".to_string()]);
+        acp_self
+            .remvalues
+            .insert("member".to_string(), vec!["self".to_string()]);
+
+        test_acp_modify!(&me_pres_self, vec![acp_self.clone()], &r_set, true);
+        test_acp_modify!(&me_pres_other, vec![acp_self.clone()], &r_set, false);
+        test_acp_modify!(&me_rem_self, vec![acp_self.clone()], &r_set, true);
+        // Purge would remove values other than self.
+        test_acp_modify!(&me_purge, vec![acp_self.clone()], &r_set, false);
+
+        // An unconstrained acp alongside still grants everything.
+        let acp_all = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_all",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "member",
+                "member",
+                "",
+            )
+        };
+        test_acp_modify!(
+            &me_pres_other,
+            vec![acp_self.clone(), acp_all.clone()],
+            &r_set,
+            true
+        );
+        test_acp_modify!(&me_purge, vec![acp_self, acp_all], &r_set, true);
+    }
+
+    #[test]
+    fn test_access_simulate() {
+        let e1: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json failure");
+        let ev1 = unsafe { e1.to_valid_committed() };
+        let r_set = vec![ev1.clone()];
+
+        let se_anon = unsafe {
+            SearchEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+        let me_admin = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+                modlist!([m_pres("class", "account")]),
+            )
+        };
+        let de_admin = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", "testperson1")),
+            )
+        };
+
+        let acs = unsafe {
+            AccessControlSearch::from_raw(
+                "test_acp",
+                "d38640c4-0254-49f9-99b7-8ba7d0233f3d",
+                filter_valid!(f_eq("name", "anonymous")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name",
+            )
+        };
+        let acm_allow = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_allow",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "name class",
+                "name class",
+                "account",
+            )
+        };
+        let mut acm_deny = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_deny",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson1")),
+                "",
+                "",
+                "account",
+            )
+        };
+        acm_deny.acp.deny = true;
+        // Applies to admin, but not to this entry.
+        let acm_other = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_other",
+                "87bfe9b8-7600-431e-a492-1dde64bbc457",
+                filter_valid!(f_eq("name", "admin")),
+                filter_valid!(f_eq("name", "testperson2")),
+                "name",
+                "name",
+                "",
+            )
+        };
+
+        let proposal = AccessControlsProposal {
+            search: vec![acs],
+            create: Vec::new(),
+            modify: vec![acm_allow, acm_deny, acm_other],
+            delete: Vec::new(),
+        };
+        let operations = vec![
+            SimulatedOperation::Search(&se_anon, r_set.clone()),
+            SimulatedOperation::Modify(&me_admin, r_set.clone()),
+            SimulatedOperation::Delete(&de_admin, r_set),
+        ];
+
+        let mut audit = AuditScope::new("test_access_simulate");
+        let outcomes =
+            AccessControls::simulate(&mut audit, proposal, &operations).expect("simulate failed");
+
+        assert_eq!(
+            outcomes,
+            vec![
+                SimulationOutcome {
+                    allowed: true,
+                    allowed_by: vec!["test_acp".to_string()],
+                    denied_by: Vec::new(),
+                },
+                SimulationOutcome {
+                    allowed: false,
+                    allowed_by: vec!["test_modify_allow".to_string()],
+                    denied_by: vec!["test_modify_deny".to_string()],
+                },
+                // Nothing grants delete, so it's denied without any acp.
+                SimulationOutcome {
+                    allowed: false,
+                    allowed_by: Vec::new(),
+                    denied_by: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_decisions_logged_when_off() {
+        let levels = Arc::new(LogLevels::new());
+        let mut off = BTreeMap::new();
+        off.insert("access".to_string(), AuditLevel::Off);
+        levels.set(&off);
+        use_log_levels(&levels);
+
+        // Turning the subsystem off can't hide why access was decided.
+        let mut audit = AuditScope::new("test_access_decisions_logged_when_off");
+        audit_log!(audit, "dropped event");
+        security_log!(audit, "kept decision");
+        let d = serde_json::to_string(&audit).expect("Json serialise failure");
+        assert!(!d.contains("dropped event"));
+        assert!(d.contains("kept decision"));
+    }
 }
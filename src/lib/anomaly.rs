@@ -0,0 +1,486 @@
+// Detection of unusual security events.
+//
+// Some events are expected now and then, but a burst of them means something
+// is wrong - a password being guessed, an account probing what it may touch,
+// or a mass delete. Each kind of event is counted over a rolling window, per
+// source or identity, and when a count goes over its threshold an alert is
+// sent to the subscribers of the change bus - the server log, and a webhook
+// if one is configured. Only the crossing alerts, so a sustained burst gives
+// one alert rather than one per event.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::async_log::EventLog;
+use crate::changes::{ChangeBus, ChangeSubscriber, ChangeSummary};
+use crate::clock::Clock;
+use crate::constants::{
+    ANOMALY_ACCESS_DENIED_WINDOW, ANOMALY_AUTH_FAILURE_WINDOW, ANOMALY_DELETE_WINDOW,
+    ANOMALY_MAX_KEYS, ANOMALY_WEBHOOK_TIMEOUT,
+};
+use crate::error::OperationError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum SecurityEventKind {
+    // Keyed by the address the attempt came from.
+    AuthFailure,
+    // Keyed by the uuid of the initiator.
+    AccessDenied,
+    // Counted over the whole server, by entry.
+    Delete,
+}
+
+// How many of each event may happen within its window before an alert. 0
+// turns detection of that kind off.
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyThresholds {
+    pub auth_failures: usize,
+    pub access_denied: usize,
+    pub deletes: usize,
+}
+
+impl AnomalyThresholds {
+    pub fn new() -> Self {
+        AnomalyThresholds {
+            auth_failures: 20,
+            access_denied: 50,
+            deletes: 1000,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.auth_failures > 0 || self.access_denied > 0 || self.deletes > 0
+    }
+
+    fn limit(&self, kind: SecurityEventKind) -> Option<(Duration, usize)> {
+        let (window, max) = match kind {
+            SecurityEventKind::AuthFailure => (ANOMALY_AUTH_FAILURE_WINDOW, self.auth_failures),
+            SecurityEventKind::AccessDenied => (ANOMALY_ACCESS_DENIED_WINDOW, self.access_denied),
+            SecurityEventKind::Delete => (ANOMALY_DELETE_WINDOW, self.deletes),
+        };
+        if max == 0 {
+            None
+        } else {
+            Some((Duration::from_secs(window), max))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub kind: SecurityEventKind,
+    pub key: String,
+    // The count within the window, and the threshold it went over.
+    pub count: usize,
+    pub threshold: usize,
+    pub window_secs: u64,
+}
+
+pub struct AnomalyDetector {
+    clock: Arc<dyn Clock>,
+    thresholds: AnomalyThresholds,
+    // When events happened, and how many at that time, by kind and key.
+    recent: Mutex<BTreeMap<(SecurityEventKind, String), VecDeque<(Duration, usize)>>>,
+    bus: Mutex<Option<ChangeBus>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(clock: Arc<dyn Clock>, thresholds: AnomalyThresholds) -> Self {
+        AnomalyDetector {
+            clock: clock,
+            thresholds: thresholds,
+            recent: Mutex::new(BTreeMap::new()),
+            bus: Mutex::new(None),
+        }
+    }
+
+    // Only the tests swap the clock, and keep the thresholds across it.
+    #[cfg(test)]
+    pub fn thresholds(&self) -> AnomalyThresholds {
+        self.thresholds
+    }
+
+    // Alerts are sent to the subscribers of this bus.
+    pub fn set_bus(&self, bus: ChangeBus) {
+        *self.bus.lock().expect("Anomaly bus lock poisoned") = Some(bus);
+    }
+
+    // Count n events of a kind against key. The alert, if this crossed the
+    // threshold, has already been sent - it's returned so the caller can note
+    // it in their own audit log.
+    pub fn record(&self, kind: SecurityEventKind, key: &str, n: usize) -> Option<Alert> {
+        let (window, max) = self.thresholds.limit(kind)?;
+        let now = self.clock.now();
+
+        let alert = {
+            let mut recent = self.recent.lock().expect("Anomaly lock poisoned");
+            // Sources that have gone quiet are only swept out when there are
+            // many of them, so a flood of new keys can't grow this forever.
+            if recent.len() > ANOMALY_MAX_KEYS {
+                let swept: BTreeMap<_, _> = recent
+                    .iter()
+                    .filter(|(_, times)| {
+                        times
+                            .back()
+                            .map(|(t, _)| *t + window > now)
+                            .unwrap_or(false)
+                    })
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                *recent = swept;
+            }
+
+            let times = recent
+                .entry((kind, key.to_string()))
+                .or_insert_with(VecDeque::new);
+            while times
+                .front()
+                .map(|(t, _)| *t + window <= now)
+                .unwrap_or(false)
+            {
+                times.pop_front();
+            }
+            let before: usize = times.iter().map(|(_, c)| c).sum();
+            times.push_back((now, n));
+            let after = before + n;
+
+            if before <= max && after > max {
+                Some(Alert {
+                    kind: kind,
+                    key: key.to_string(),
+                    count: after,
+                    threshold: max,
+                    window_secs: window.as_secs(),
+                })
+            } else {
+                None
+            }
+        };
+
+        if let Some(a) = &alert {
+            match &*self.bus.lock().expect("Anomaly bus lock poisoned") {
+                Some(bus) => bus.publish_alert(a.clone()),
+                None => warn!("Security alert with no subscribers: {:?}", a),
+            }
+        }
+        alert
+    }
+}
+
+// Writes every alert to the server log, as json so it can be picked out and
+// parsed by whatever watches the log.
+pub struct AlertLogger {
+    log: actix::Addr<EventLog>,
+}
+
+impl AlertLogger {
+    pub fn new(log: actix::Addr<EventLog>) -> Self {
+        AlertLogger { log: log }
+    }
+}
+
+impl ChangeSubscriber for AlertLogger {
+    fn id(&self) -> &'static str {
+        "alert_logger"
+    }
+
+    fn notify(&self, _changes: &[ChangeSummary]) {}
+
+    fn alert(&self, alert: &Alert) {
+        match serde_json::to_string(alert) {
+            Ok(s) => log_event!(self.log, "alert: {}", s),
+            Err(e) => log_event!(self.log, "alert: {:?} ({:?})", alert, e),
+        }
+    }
+}
+
+// Posts every alert as json to a url, for whatever pages the people who
+// should know. This runs on the bus thread, so it can block, but a slow
+// endpoint holds up the subscribers after it - the timeout bounds that.
+pub struct AlertWebhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl AlertWebhook {
+    pub fn new(url: &str) -> Result<Self, OperationError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(ANOMALY_WEBHOOK_TIMEOUT))
+            .build()
+            .map_err(|e| {
+                error!("Unable to build the alert webhook client -> {:?}", e);
+                OperationError::InvalidState
+            })?;
+        Ok(AlertWebhook {
+            url: url.to_string(),
+            client: client,
+        })
+    }
+}
+
+impl ChangeSubscriber for AlertWebhook {
+    fn id(&self) -> &'static str {
+        "alert_webhook"
+    }
+
+    fn notify(&self, _changes: &[ChangeSummary]) {}
+
+    fn alert(&self, alert: &Alert) {
+        // There's no one to return a failure to, so it's only logged. The
+        // alert itself is still in the log if the AlertLogger is running.
+        match self.client.post(self.url.as_str()).json(alert).send() {
+            Ok(r) => {
+                if !r.status().is_success() {
+                    error!("Alert webhook {} returned {}", self.url, r.status());
+                }
+            }
+            Err(e) => error!("Unable to send alert to {} -> {:?}", self.url, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::{
+        Alert, AlertWebhook, AnomalyDetector, AnomalyThresholds, SecurityEventKind,
+    };
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::changes::{ChangeBus, ChangeSubscriber, ChangeSummary};
+    use crate::clock::MockClock;
+    use crate::constants::JSON_ADMIN_V1;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::event::DeleteEvent;
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    struct TestSubscriber {
+        tx: Mutex<Sender<Alert>>,
+    }
+
+    impl ChangeSubscriber for TestSubscriber {
+        fn id(&self) -> &'static str {
+            "test_subscriber"
+        }
+
+        fn notify(&self, _changes: &[ChangeSummary]) {}
+
+        fn alert(&self, alert: &Alert) {
+            let _ = self.tx.lock().expect("lock poisoned").send(alert.clone());
+        }
+    }
+
+    #[test]
+    fn test_anomaly_threshold() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let ad = AnomalyDetector::new(
+            clock.clone(),
+            AnomalyThresholds {
+                auth_failures: 2,
+                access_denied: 0,
+                deletes: 10,
+            },
+        );
+
+        // Only crossing the threshold alerts.
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 1)
+            .is_none());
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 1)
+            .is_none());
+        let a = ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 1)
+            .expect("no alert");
+        assert!(a.count == 3 && a.threshold == 2 && a.key == "10.0.0.1");
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 1)
+            .is_none());
+        // Each source is counted on its own.
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.2", 1)
+            .is_none());
+
+        // Once the window has passed, the count starts again.
+        clock.advance(Duration::from_secs(301));
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 2)
+            .is_none());
+        assert!(ad
+            .record(SecurityEventKind::AuthFailure, "10.0.0.1", 1)
+            .is_some());
+
+        // A single large delete can cross on its own.
+        assert!(ad.record(SecurityEventKind::Delete, "all", 11).is_some());
+        // Nothing is counted for a kind that is turned off.
+        for _ in 0..100 {
+            assert!(ad
+                .record(SecurityEventKind::AccessDenied, "user", 1)
+                .is_none());
+        }
+    }
+    #[test]
+    fn test_anomaly_alert_bus() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let ad = AnomalyDetector::new(clock, AnomalyThresholds::new());
+        let (tx, rx) = channel();
+        ad.set_bus(ChangeBus::new(vec![Arc::new(TestSubscriber {
+            tx: Mutex::new(tx),
+        })]));
+
+        assert!(ad.record(SecurityEventKind::Delete, "all", 1000).is_none());
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(ad.record(SecurityEventKind::Delete, "all", 1).is_some());
+        let a = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No alert was received");
+        assert!(a.kind == SecurityEventKind::Delete && a.count == 1001);
+    }
+
+    #[test]
+    fn test_anomaly_delete_commit() {
+        let mut au = AuditScope::new("test_anomaly_delete_commit");
+        let be = Backend::new(&mut au, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut au).expect("Failed to init schema");
+        let mut qs = QueryServer::new(be, schema);
+        qs.initialise_helper(&mut au).expect("init failed!");
+        qs.set_anomaly_thresholds(AnomalyThresholds {
+            auth_failures: 0,
+            access_denied: 0,
+            deletes: 1,
+        });
+        let (tx, rx) = channel();
+        qs.set_change_bus(ChangeBus::new(vec![Arc::new(TestSubscriber {
+            tx: Mutex::new(tx),
+        })]));
+
+        let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["an_one"],
+                    "description": ["anomaly"],
+                    "displayname": ["an_one"]
+                }
+            }"#,
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["an_two"],
+                    "description": ["anomaly"],
+                    "displayname": ["an_two"]
+                }
+            }"#,
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": [
+                        "object",
+                        "access_control_profile",
+                        "access_control_search",
+                        "access_control_delete"
+                    ],
+                    "name": ["anomaly_acp_delete"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639e1"],
+                    "description": ["Anyone can find and delete the test people."],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Pres\":\"class\"}"],
+                    "acp_targetscope": ["{\"Eq\":[\"description\",\"anomaly\"]}"],
+                    "acp_search_attr": ["class", "description"]
+                }
+            }"#,
+        ]
+        .into_iter()
+        .map(|s| serde_json::from_str(s).expect("json failure"))
+        .collect();
+        let mut qs_write = qs.write();
+        assert!(qs_write.internal_create(&mut au, entries).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+
+        let de = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter!(f_eq("description", "anomaly")),
+            )
+        };
+        // A delete that is rolled back isn't counted.
+        {
+            let mut qs_write = qs.write();
+            assert!(qs_write.delete(&mut au, &de).is_ok());
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let mut qs_write = qs.write();
+        assert!(qs_write.delete(&mut au, &de).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        let a = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No alert was received");
+        assert!(a.kind == SecurityEventKind::Delete && a.count == 2);
+    }
+
+    #[test]
+    fn test_anomaly_alert_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+        let url = format!("http://{}/alert", listener.local_addr().expect("no addr"));
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Failed to accept");
+            let mut req = Vec::new();
+            let mut buf = [0; 4096];
+            // Read until the body the headers promised has all arrived.
+            loop {
+                let n = stream.read(&mut buf).expect("Failed to read");
+                req.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(req.as_slice()).to_string();
+                if let Some(split) = text.find("\r\n\r\n") {
+                    let len = text[..split]
+                        .lines()
+                        .filter_map(|l| {
+                            let l = l.to_lowercase();
+                            if l.starts_with("content-length:") {
+                                l["content-length:".len()..].trim().parse::<usize>().ok()
+                            } else {
+                                None
+                            }
+                        })
+                        .next()
+                        .unwrap_or(0);
+                    if text.len() >= split + 4 + len || n == 0 {
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+                        let _ = tx.send(text);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let hook = AlertWebhook::new(url.as_str()).expect("Failed to build webhook");
+        hook.alert(&Alert {
+            kind: SecurityEventKind::AuthFailure,
+            key: "10.0.0.1".to_string(),
+            count: 21,
+            threshold: 20,
+            window_secs: 300,
+        });
+        let req = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No request was received");
+        assert!(req.starts_with("POST /alert "));
+        let body = &req[req.find("\r\n\r\n").expect("no body") + 4..];
+        let v: serde_json::Value = serde_json::from_str(body).expect("body is not json");
+        assert!(v["kind"] == "AuthFailure" && v["key"] == "10.0.0.1" && v["count"] == 21);
+    }
+}
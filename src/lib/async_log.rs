@@ -1,6 +1,6 @@
 use actix::prelude::*;
 
-use crate::audit::AuditScope;
+use crate::audit::{append_audit_record, AuditScope};
 
 // Helper for internal logging.
 // Should only be used at startup/shutdown
@@ -25,11 +25,17 @@ macro_rules! log_event {
 // so that we don't msg unless it's the correct level?
 // Do we need config in the log macro?
 
-pub fn start() -> actix::Addr<EventLog> {
-    SyncArbiter::start(1, move || EventLog {})
+// Security relevant operations are also appended to the audit log at
+// audit_path, if it's given.
+pub fn start(audit_path: Option<String>) -> actix::Addr<EventLog> {
+    SyncArbiter::start(1, move || EventLog {
+        audit_path: audit_path.clone(),
+    })
 }
 
-pub struct EventLog {}
+pub struct EventLog {
+    audit_path: Option<String>,
+}
 
 impl Actor for EventLog {
     type Context = SyncContext<Self>;
@@ -66,7 +72,16 @@ impl Handler<AuditScope> for EventLog {
     type Result = ();
 
     fn handle(&mut self, event: AuditScope, _: &mut SyncContext<Self>) -> Self::Result {
-        info!("audit: {}", event);
+        // One json object per line, so the log can be consumed by other tools.
+        match event.to_json_line() {
+            Ok(line) => info!("audit: {}", line),
+            Err(e) => error!("audit: failed to serialise {}: {:?}", event.id(), e),
+        }
+        if let (Some(path), Some(record)) = (&self.audit_path, event.to_record()) {
+            if let Err(e) = append_audit_record(path.as_str(), &record) {
+                error!("audit: failed to keep {:?} in {}: {:?}", record, path, e);
+            }
+        }
     }
 }
 
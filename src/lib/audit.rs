@@ -1,28 +1,63 @@
 use actix::prelude::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 
-use chrono::offset::Utc;
-use chrono::DateTime;
+use chrono::offset::{FixedOffset, TimeZone, Utc};
+use chrono::{DateTime, NaiveDateTime};
 use serde_json;
+use uuid::Uuid;
+
+use crate::constants::{AUDIT_LOG_ROTATE_KEEP, AUDIT_LOG_ROTATE_SIZE};
+use crate::error::OperationError;
+use crate::proto::v1::AuditRecord;
 
 #[macro_export]
 macro_rules! audit_log {
     ($audit:expr, $($arg:tt)*) => ({
         use std::fmt;
+        use crate::audit::{log_level, AuditLevel};
+        // The level of the subsystem this is logged from, which can be
+        // changed while the server runs.
+        let level = log_level(module_path!());
+        if level != AuditLevel::Off {
+            let msg = fmt::format(format_args!($($arg)*));
+            if cfg!(test) || cfg!(debug_assertions) {
+                // debug!("DEBUG AUDIT ({}:{} {})-> ", file!(), line!(), $audit.id());
+                // debug!("line: {}", line!());
+                debug!("{}", msg)
+            }
+            if level == AuditLevel::Debug {
+                info!("{}: {}", module_path!(), msg)
+            }
+            $audit.log_event(msg)
+        }
+    })
+}
+
+// As audit_log!, for what the audit log must always hold whatever the level,
+// such as why an access control decision was made.
+#[macro_export]
+macro_rules! security_log {
+    ($audit:expr, $($arg:tt)*) => ({
+        use std::fmt;
+        use crate::audit::{log_level, AuditLevel};
+        let msg = fmt::format(format_args!($($arg)*));
         if cfg!(test) || cfg!(debug_assertions) {
-            // debug!("DEBUG AUDIT ({}:{} {})-> ", file!(), line!(), $audit.id());
-            // debug!($($arg)*)
-            // debug!("DEBUG AUDIT ({}:{} {})-> ", file!(), line!(), $audit.id());
-            // debug!("line: {}", line!());
-            debug!($($arg)*)
+            debug!("{}", msg)
+        }
+        if log_level(module_path!()) == AuditLevel::Debug {
+            info!("{}: {}", module_path!(), msg)
         }
-        $audit.log_event(
-            fmt::format(
-                format_args!($($arg)*)
-            )
-        )
+        $audit.log_event(msg)
     })
 }
 
@@ -86,6 +121,123 @@ macro_rules! try_audit {
     };
 }
 
+// The subsystems whose audit verbosity can be set while running, by the first
+// part of their module path. Everything else is always logged at Info.
+pub const LOG_SUBSYSTEMS: [&'static str; 4] = ["access", "be", "schema", "idm"];
+
+// Off drops a subsystem's audit events, Info keeps them in the audit scope, and
+// Debug also writes each to the server log as it happens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditLevel {
+    Off,
+    Info,
+    Debug,
+}
+
+impl AuditLevel {
+    fn from_usize(v: usize) -> Self {
+        match v {
+            0 => AuditLevel::Off,
+            2 => AuditLevel::Debug,
+            _ => AuditLevel::Info,
+        }
+    }
+
+    fn to_usize(&self) -> usize {
+        match self {
+            AuditLevel::Off => 0,
+            AuditLevel::Info => 1,
+            AuditLevel::Debug => 2,
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(AuditLevel::Off),
+            "info" => Some(AuditLevel::Info),
+            "debug" => Some(AuditLevel::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditLevel::Off => "off",
+            AuditLevel::Info => "info",
+            AuditLevel::Debug => "debug",
+        }
+    }
+}
+
+// The levels of a server, in the order of LOG_SUBSYSTEMS. These are checked
+// on every audit_log!, so are atomics rather than behind a lock.
+#[derive(Debug)]
+pub struct LogLevels([AtomicUsize; 4]);
+
+impl LogLevels {
+    pub fn new() -> Self {
+        LogLevels([
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+        ])
+    }
+
+    fn get(&self, module: &str) -> AuditLevel {
+        match subsystem_of(module) {
+            Some(i) => AuditLevel::from_usize(self.0[i].load(Ordering::Relaxed)),
+            None => AuditLevel::Info,
+        }
+    }
+
+    // Replace the levels in use. Subsystems that aren't given go back to
+    // Info.
+    pub fn set(&self, levels: &BTreeMap<String, AuditLevel>) {
+        for (i, s) in LOG_SUBSYSTEMS.iter().enumerate() {
+            let level = levels.get(*s).cloned().unwrap_or(AuditLevel::Info);
+            self.0[i].store(level.to_usize(), Ordering::Relaxed);
+        }
+    }
+}
+
+// audit_log! has no way to reach the server it is logging for, so a server
+// makes its levels the ones in use on a thread as it opens a transaction
+// there. Each server keeps its own, even with several in one process.
+thread_local! {
+    static THREAD_LOG_LEVELS: RefCell<Option<Arc<LogLevels>>> = RefCell::new(None);
+}
+
+pub fn use_log_levels(levels: &Arc<LogLevels>) {
+    THREAD_LOG_LEVELS.with(|l| *l.borrow_mut() = Some(levels.clone()));
+}
+
+fn subsystem_of(module: &str) -> Option<usize> {
+    module
+        .split("::")
+        .nth(1)
+        .and_then(|m| LOG_SUBSYSTEMS.iter().position(|s| *s == m))
+}
+
+pub fn log_level(module: &str) -> AuditLevel {
+    THREAD_LOG_LEVELS.with(|l| match &*l.borrow() {
+        Some(levels) => levels.get(module),
+        None => AuditLevel::Info,
+    })
+}
+
+// A level as stored in system_config, such as "be=debug".
+pub fn parse_log_level(v: &str) -> Option<(String, AuditLevel)> {
+    let mut parts = v.splitn(2, '=');
+    let subsystem = parts.next()?.trim();
+    let level = AuditLevel::from_str(parts.next()?.trim())?;
+    if LOG_SUBSYSTEMS.contains(&subsystem) {
+        Some((subsystem.to_string(), level))
+    } else {
+        None
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 enum AuditEvent {
     Log(AuditLog),
@@ -108,6 +260,25 @@ pub struct AuditScope {
     // to automatically annotate line numbers of code?
     time: String,
     name: String,
+    // Set for operations that came from a client, so the client can
+    // correlate their request with what the server recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // The uuid of the entry that initiated this, or "internal".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
+    // "success", or the error the operation was denied or failed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decision: Option<String>,
+    // What a modify changed, with credential values redacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modlist: Option<String>,
+    // Auth, acp and credential changes, and deletes, are kept in the audit
+    // log if one is configured.
+    #[serde(skip)]
+    security: bool,
     duration: Option<Duration>,
     events: Vec<AuditEvent>,
 }
@@ -134,15 +305,80 @@ impl AuditScope {
         AuditScope {
             time: datetime.to_rfc3339(),
             name: String::from(name),
+            request_id: None,
+            origin: None,
+            filter: None,
+            decision: None,
+            modlist: None,
+            security: false,
             duration: None,
             events: Vec::new(),
         }
     }
 
+    // An operation that was requested by a client, tagged with the id
+    // we return to them.
+    pub fn new_request(name: &str, request_id: Option<Uuid>) -> Self {
+        let mut au = AuditScope::new(name);
+        au.request_id = request_id.map(|u| u.to_hyphenated().to_string());
+        au
+    }
+
     pub fn id(&self) -> &str {
         self.name.as_str()
     }
 
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn set_origin(&mut self, origin: Option<&str>) {
+        self.origin = Some(origin.unwrap_or("internal").to_string());
+    }
+
+    pub fn set_filter<F: Debug>(&mut self, filter: &F) {
+        self.filter = Some(format!("{:?}", filter));
+    }
+
+    // The Debug of a modlist redacts credential values, so this is safe to
+    // keep.
+    pub fn set_modlist<M: Debug>(&mut self, modlist: &M) {
+        self.modlist = Some(format!("{:?}", modlist));
+    }
+
+    pub fn set_decision<T, E: Debug>(&mut self, res: &Result<T, E>) {
+        self.decision = Some(match res {
+            Ok(_) => "success".to_string(),
+            Err(e) => format!("{:?}", e),
+        });
+    }
+
+    pub fn set_security_relevant(&mut self) {
+        self.security = true;
+    }
+
+    // Only operations clients asked for are kept, as those are the ones
+    // someone is answerable for.
+    pub fn to_record(&self) -> Option<AuditRecord> {
+        match (&self.request_id, self.security) {
+            (Some(rid), true) => Some(AuditRecord {
+                time: self.time.clone(),
+                operation: self.name.clone(),
+                request_id: rid.clone(),
+                origin: self.origin.clone(),
+                filter: self.filter.clone(),
+                decision: self.decision.clone(),
+                modlist: self.modlist.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    // A single line of json, for writing the log out as json lines.
+    pub fn to_json_line(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
     pub fn set_duration(&mut self, diff: Duration) {
         self.duration = Some(diff);
     }
@@ -163,9 +399,125 @@ impl AuditScope {
     }
 }
 
+// The audit log is a file of records, one json object per line, that is
+// only ever appended to. Once it grows past AUDIT_LOG_ROTATE_SIZE it's moved
+// aside, named for when, so a search for recent records can skip it.
+pub fn append_audit_record(path: &str, record: &AuditRecord) -> Result<(), OperationError> {
+    rotate_audit_log(path, AUDIT_LOG_ROTATE_SIZE)?;
+    let line = serde_json::to_string(record).map_err(|_| OperationError::SerdeJsonError)?;
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|_| OperationError::FsError)?;
+    writeln!(f, "{}", line).map_err(|_| OperationError::FsError)
+}
+
+// The time a log was moved aside is the suffix of its new name.
+static ROTATED_SUFFIX_FORMAT: &'static str = "%Y%m%dT%H%M%S%.6fZ";
+
+fn rotate_audit_log(path: &str, max_size: u64) -> Result<(), OperationError> {
+    match fs::metadata(path) {
+        Ok(m) if m.len() >= max_size => {}
+        Ok(_) => return Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(_) => return Err(OperationError::FsError),
+    }
+    let now: DateTime<Utc> = SystemTime::now().into();
+    let rotated = format!("{}.{}", path, now.format(ROTATED_SUFFIX_FORMAT));
+    fs::rename(path, rotated).map_err(|_| OperationError::FsError)?;
+
+    // Only the newest are kept.
+    let rotated = rotated_audit_logs(path)?;
+    let excess = rotated.len().saturating_sub(AUDIT_LOG_ROTATE_KEEP);
+    rotated.iter().take(excess).for_each(|(_, p)| {
+        if let Err(e) = fs::remove_file(p) {
+            error!("audit: failed to remove {:?}: {:?}", p, e);
+        }
+    });
+    Ok(())
+}
+
+// The logs moved aside from path, oldest first, with when each was. None of
+// the records in one are newer than that.
+fn rotated_audit_logs(path: &str) -> Result<Vec<(DateTime<Utc>, PathBuf)>, OperationError> {
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => format!("{}.", n),
+        None => return Err(OperationError::FsError),
+    };
+    let mut rotated: Vec<(DateTime<Utc>, PathBuf)> = fs::read_dir(dir)
+        .map_err(|_| OperationError::FsError)?
+        .filter_map(|d| d.ok())
+        .filter_map(|d| {
+            let name = d.file_name();
+            let t = name
+                .to_str()
+                .filter(|n| n.starts_with(prefix.as_str()))
+                .and_then(|n| {
+                    NaiveDateTime::parse_from_str(&n[prefix.len()..], ROTATED_SUFFIX_FORMAT).ok()
+                })?;
+            Some((Utc.from_utc_datetime(&t), d.path()))
+        })
+        .collect();
+    rotated.sort();
+    Ok(rotated)
+}
+
+// The records in the audit log at path, and those moved aside from it. Logs
+// that were moved aside before since can't hold anything after it, so aren't
+// read.
+pub fn read_audit_records(
+    path: &str,
+    since: Option<DateTime<FixedOffset>>,
+) -> Result<Vec<AuditRecord>, OperationError> {
+    let mut paths: Vec<PathBuf> = rotated_audit_logs(path)?
+        .into_iter()
+        .filter(|(t, _)| since.map(|s| *t >= s.with_timezone(&Utc)).unwrap_or(true))
+        .map(|(_, p)| p)
+        .collect();
+    paths.push(PathBuf::from(path));
+    paths.iter().try_fold(Vec::new(), |mut records, p| {
+        records.extend(read_audit_file(p)?);
+        Ok(records)
+    })
+}
+
+fn read_audit_file(path: &Path) -> Result<Vec<AuditRecord>, OperationError> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        // Nothing has been recorded yet.
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(_) => return Err(OperationError::FsError),
+    };
+    BufReader::new(f)
+        .lines()
+        .filter(|l| l.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+        .map(|l| {
+            let l = l.map_err(|_| OperationError::FsError)?;
+            serde_json::from_str(l.as_str()).map_err(|_| OperationError::SerdeJsonError)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::audit::AuditScope;
+    use crate::audit::{
+        append_audit_record, log_level, parse_log_level, read_audit_records, rotate_audit_log,
+        rotated_audit_logs, subsystem_of, use_log_levels, AuditLevel, AuditScope, LogLevels,
+    };
+    use crate::error::OperationError;
+    use crate::modify::{Modify, ModifyList};
+    use crate::value::Value;
+    use chrono::DateTime;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::Arc;
+    use uuid::Uuid;
 
     // Create and remove. Perhaps add some core details?
     #[test]
@@ -175,4 +527,119 @@ mod tests {
         println!("{}", d);
     }
 
+    #[test]
+    fn test_audit_json_line() {
+        let rid = Uuid::new_v4();
+        let mut au = AuditScope::new_request("search", Some(rid));
+        au.set_origin(None);
+        au.set_filter(&"(eq name admin)");
+        au.set_decision::<(), _>(&Err(OperationError::AccessDenied));
+        let mut inner = AuditScope::new("search_ext");
+        inner.set_decision::<(), OperationError>(&Ok(()));
+        au.append_scope(inner);
+
+        let line = au.to_json_line().expect("Json serialise failure");
+        assert!(!line.contains('\n'));
+
+        let v: serde_json::Value = serde_json::from_str(&line).expect("Json parse failure");
+        assert!(v["request_id"] == rid.to_hyphenated().to_string());
+        assert!(v["origin"] == "internal");
+        assert!(v["decision"] == "AccessDenied");
+        assert!(v["events"][0]["Scope"]["decision"] == "success");
+        // Unset fields are left out of the record entirely.
+        assert!(v["events"][0]["Scope"].get("request_id").is_none());
+        assert!(au.request_id() == Some(rid.to_hyphenated().to_string().as_str()));
+    }
+
+    #[test]
+    fn test_audit_log_levels() {
+        assert!(subsystem_of("rsidm::be") == Some(1));
+        assert!(subsystem_of("rsidm::be::idl") == Some(1));
+        assert!(subsystem_of("rsidm::idm::authsession") == Some(3));
+        assert!(subsystem_of("rsidm::server") == None);
+        assert!(subsystem_of("rsidm") == None);
+
+        assert!(parse_log_level("be=debug") == Some(("be".to_string(), AuditLevel::Debug)));
+        assert!(parse_log_level("access = off") == Some(("access".to_string(), AuditLevel::Off)));
+        assert!(parse_log_level("server=debug") == None);
+        assert!(parse_log_level("be=loud") == None);
+        assert!(parse_log_level("be") == None);
+    }
+
+    #[test]
+    fn test_audit_log_levels_per_server() {
+        let a = Arc::new(LogLevels::new());
+        let b = Arc::new(LogLevels::new());
+        let mut levels = BTreeMap::new();
+        levels.insert("be".to_string(), AuditLevel::Debug);
+        a.set(&levels);
+
+        // Only the levels in use on this thread apply.
+        use_log_levels(&a);
+        assert!(log_level("rsidm::be") == AuditLevel::Debug);
+        use_log_levels(&b);
+        assert!(log_level("rsidm::be") == AuditLevel::Info);
+    }
+
+    #[test]
+    fn test_audit_record_modlist_redacted() {
+        let mut au = AuditScope::new_request("modify", Some(Uuid::new_v4()));
+        au.set_security_relevant();
+        au.set_modlist(&ModifyList::new_list(vec![
+            Modify::Present("displayname".to_string(), Value::from("Test Person")),
+            Modify::Present("password".to_string(), Value::from("hunter2")),
+        ]));
+        let record = au.to_record().expect("no record");
+        let modlist = record.modlist.expect("no modlist");
+        assert!(modlist.contains("Test Person"));
+        assert!(modlist.contains("password"));
+        assert!(!modlist.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_audit_log_rotate() {
+        let path = "./.audit_rotate_test.json";
+        let clean = || {
+            let _ = fs::remove_file(path);
+            rotated_audit_logs(path)
+                .expect("list failed")
+                .iter()
+                .for_each(|(_, p)| {
+                    let _ = fs::remove_file(p);
+                });
+        };
+        clean();
+        let mut au = AuditScope::new_request("delete", Some(Uuid::new_v4()));
+        au.set_security_relevant();
+        let record = au.to_record().expect("no record");
+
+        // Nothing to move aside yet.
+        assert!(rotate_audit_log(path, 1).is_ok());
+        assert!(rotated_audit_logs(path).expect("list failed").is_empty());
+
+        assert!(append_audit_record(path, &record).is_ok());
+        assert!(rotate_audit_log(path, 1).is_ok());
+        assert!(append_audit_record(path, &record).is_ok());
+        let rotated = rotated_audit_logs(path).expect("list failed");
+        assert!(rotated.len() == 1);
+
+        // Both are searched, unless the one moved aside is too old to matter.
+        assert!(read_audit_records(path, None).expect("read failed").len() == 2);
+        let since = DateTime::parse_from_rfc3339("2019-07-01T10:00:00+00:00").expect("bad time");
+        assert!(
+            read_audit_records(path, Some(since))
+                .expect("read failed")
+                .len()
+                == 2
+        );
+        let after = rotated[0].0 + chrono::Duration::seconds(1);
+        let since = DateTime::parse_from_rfc3339(after.to_rfc3339().as_str()).expect("bad time");
+        assert!(
+            read_audit_records(path, Some(since))
+                .expect("read failed")
+                .len()
+                == 1
+        );
+        clean();
+    }
 }
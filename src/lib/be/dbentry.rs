@@ -1,16 +1,61 @@
 use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbEntryV1 {
     pub attrs: BTreeMap<String, Vec<String>>,
 }
 
+// The stored form of a typed value. This is kept apart from Value so that
+// the in memory type can change without changing what is on disk.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DbValueV1 {
+    U8(String),
+    I8(String),
+    PR(String),
+    UU(Uuid),
+    BO(bool),
+    SY(String),
+    IN(String),
+    RF(Uuid),
+    JF(String),
+    // An RFC3339 time in UTC.
+    DT(String),
+    UR(String),
+    IT(i64),
+    // A tagged ssh public key, as "tag: key".
+    SK(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbEntryV2 {
+    pub attrs: BTreeMap<String, Vec<DbValueV1>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbCsnV1 {
+    pub ts: Duration,
+    // Missing from csns stored before replicas were recorded.
+    #[serde(default)]
+    pub rid: Option<Uuid>,
+}
+
+// V3 adds the csn of the last change to each attribute.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbEntryV3 {
+    pub attrs: BTreeMap<String, Vec<DbValueV1>>,
+    pub csns: BTreeMap<String, DbCsnV1>,
+}
+
 // REMEMBER: If you add a new version here, you MUST
 // update entry.rs into_dbentry to export to the latest
 // type always!!
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DbEntryVers {
     V1(DbEntryV1),
+    V2(DbEntryV2),
+    V3(DbEntryV3),
 }
 
 // This is actually what we store into the DB.
@@ -18,3 +63,19 @@ pub enum DbEntryVers {
 pub struct DbEntry {
     pub ent: DbEntryVers,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbBackupV1 {
+    // What was indexed, as (attribute, index type).
+    pub idxmeta: Vec<(String, String)>,
+    // The changelog position the backup was taken at.
+    pub changelog_seq: i64,
+    pub entries: Vec<DbEntry>,
+}
+
+// The content of a backup file. Backups taken before this was versioned are
+// a bare list of entries, and can still be restored.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DbBackup {
+    V1(DbBackupV1),
+}
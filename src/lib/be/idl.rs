@@ -1 +1,137 @@
+// ID lists.
+//
+// An index maps an attribute, index type and key to the ids of the entries
+// holding it. Resolving a filter against the indexes gives an IDL - the set
+// of entries the filter could match - so a search only has to load those,
+// rather than every entry in id2entry.
+//
+// On disk, an id list is kept as runs of consecutive ids. Entries are given
+// ids in order, so the lists of keys most entries share, like class=object,
+// shrink to a handful of runs.
 
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IDL {
+    // The indexes can't narrow this down. Every entry must be tested.
+    ALLIDS,
+    // At least every match, but maybe more. Each must still be tested.
+    Partial(BTreeSet<u64>),
+    // Exactly the matches.
+    Indexed(BTreeSet<u64>),
+}
+
+impl IDL {
+    // Entries in both.
+    pub fn and(self, other: IDL) -> IDL {
+        match (self, other) {
+            (IDL::ALLIDS, IDL::ALLIDS) => IDL::ALLIDS,
+            // The other term is still to be tested on these.
+            (IDL::ALLIDS, IDL::Indexed(s))
+            | (IDL::ALLIDS, IDL::Partial(s))
+            | (IDL::Indexed(s), IDL::ALLIDS)
+            | (IDL::Partial(s), IDL::ALLIDS) => IDL::Partial(s),
+            (IDL::Indexed(a), IDL::Indexed(b)) => IDL::Indexed(&a & &b),
+            (IDL::Indexed(a), IDL::Partial(b))
+            | (IDL::Partial(a), IDL::Indexed(b))
+            | (IDL::Partial(a), IDL::Partial(b)) => IDL::Partial(&a & &b),
+        }
+    }
+
+    // Entries in either.
+    pub fn or(self, other: IDL) -> IDL {
+        match (self, other) {
+            (IDL::ALLIDS, _) | (_, IDL::ALLIDS) => IDL::ALLIDS,
+            (IDL::Indexed(a), IDL::Indexed(b)) => IDL::Indexed(&a | &b),
+            (IDL::Indexed(a), IDL::Partial(b))
+            | (IDL::Partial(a), IDL::Indexed(b))
+            | (IDL::Partial(a), IDL::Partial(b)) => IDL::Partial(&a | &b),
+        }
+    }
+
+    // Entries in self that are not in other. Only an exact other can be
+    // removed - otherwise we'd drop entries it doesn't actually match.
+    pub fn andnot(self, other: IDL) -> IDL {
+        match (self, other) {
+            (IDL::ALLIDS, _) => IDL::ALLIDS,
+            (IDL::Indexed(a), IDL::Indexed(b)) => IDL::Indexed(&a - &b),
+            (IDL::Partial(a), IDL::Indexed(b)) => IDL::Partial(&a - &b),
+            (IDL::Indexed(a), _) | (IDL::Partial(a), _) => IDL::Partial(a),
+        }
+    }
+}
+
+// The stored form of an id list: (first id, count) for each run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DbIdl(Vec<(u64, u64)>);
+
+impl DbIdl {
+    pub fn new(ids: &BTreeSet<u64>) -> Self {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for id in ids.iter() {
+            match runs.last_mut() {
+                Some((first, count)) if *first + *count == *id => *count += 1,
+                _ => runs.push((*id, 1)),
+            }
+        }
+        DbIdl(runs)
+    }
+
+    // How many ids this holds, without expanding the runs.
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|(_, count)| *count as usize).sum()
+    }
+
+    pub fn to_ids(&self) -> BTreeSet<u64> {
+        self.0
+            .iter()
+            .flat_map(|(first, count)| *first..*first + *count)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DbIdl, IDL};
+    use std::collections::BTreeSet;
+
+    fn ids(v: &[u64]) -> BTreeSet<u64> {
+        v.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_idl_and_or() {
+        let a = IDL::Indexed(ids(&[1, 2, 3]));
+        let b = IDL::Indexed(ids(&[2, 3, 4]));
+        assert!(a.clone().and(b.clone()) == IDL::Indexed(ids(&[2, 3])));
+        assert!(a.clone().or(b.clone()) == IDL::Indexed(ids(&[1, 2, 3, 4])));
+        // Anything not exact makes the result need testing.
+        assert!(a.clone().and(IDL::ALLIDS) == IDL::Partial(ids(&[1, 2, 3])));
+        assert!(a.clone().or(IDL::Partial(ids(&[5]))) == IDL::Partial(ids(&[1, 2, 3, 5])));
+        assert!(a.clone().or(IDL::ALLIDS) == IDL::ALLIDS);
+        assert!(IDL::ALLIDS.and(IDL::ALLIDS) == IDL::ALLIDS);
+    }
+
+    #[test]
+    fn test_idl_andnot() {
+        let a = IDL::Indexed(ids(&[1, 2, 3]));
+        assert!(a.clone().andnot(IDL::Indexed(ids(&[2]))) == IDL::Indexed(ids(&[1, 3])));
+        // We can't know which of these to remove.
+        assert!(a.clone().andnot(IDL::Partial(ids(&[2]))) == IDL::Partial(ids(&[1, 2, 3])));
+        assert!(a.clone().andnot(IDL::ALLIDS) == IDL::Partial(ids(&[1, 2, 3])));
+        assert!(IDL::ALLIDS.andnot(IDL::Indexed(ids(&[2]))) == IDL::ALLIDS);
+    }
+
+    #[test]
+    fn test_dbidl_runs() {
+        let v = ids(&[1, 2, 3, 4, 7, 9, 10]);
+        let dbidl = DbIdl::new(&v);
+        assert!(dbidl == DbIdl(vec![(1, 4), (7, 1), (9, 2)]));
+        assert!(dbidl.count() == 7);
+        assert!(dbidl.to_ids() == v);
+        // A key every entry holds is one run, however many there are.
+        let all: BTreeSet<u64> = (1..100_001).collect();
+        assert!(DbIdl::new(&all) == DbIdl(vec![(1, 100_000)]));
+        assert!(DbIdl::new(&BTreeSet::new()).count() == 0);
+    }
+}
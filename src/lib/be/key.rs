@@ -0,0 +1,144 @@
+// Keys for encrypting the database at rest.
+//
+// This relies on sqlite being built with sqlcipher (the "sqlcipher" feature).
+// Plain sqlite ignores the key pragmas it doesn't know, and would happily
+// write an unencrypted database, so without that feature any attempt to use
+// a key is refused rather than ignored.
+
+use r2d2::CustomizeConnection;
+use rusqlite::Connection;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::audit::AuditScope;
+use crate::error::OperationError;
+
+// A raw 256 bit key. We use raw keys rather than passphrases, so sqlcipher
+// skips key derivation - which it would otherwise redo for every pooled
+// connection.
+#[derive(Clone)]
+pub struct DbKey {
+    key: [u8; 32],
+}
+
+impl DbKey {
+    pub fn from_hex(hex: &str) -> Result<Self, OperationError> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(OperationError::InvalidDbKey(
+                "key must be 64 hex characters",
+            ));
+        }
+        let mut key = [0; 32];
+        for (i, k) in key.iter_mut().enumerate() {
+            *k = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| OperationError::InvalidDbKey("key must be 64 hex characters"))?;
+        }
+        Ok(DbKey { key: key })
+    }
+
+    // The value for PRAGMA key and PRAGMA rekey, in sqlcipher's raw key form.
+    fn pragma_value(&self) -> String {
+        let hex: String = self.key.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("\"x'{}'\"", hex)
+    }
+
+    pub(crate) fn apply(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch(format!("PRAGMA key = {};", self.pragma_value()).as_str())
+    }
+
+    pub(crate) fn rekey(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch(format!("PRAGMA rekey = {};", self.pragma_value()).as_str())
+    }
+}
+
+// Never show key material in logs.
+impl fmt::Debug for DbKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DbKey(<redacted>)")
+    }
+}
+
+// Where the database key comes from. The key file and config sources are
+// provided here, but anything able to hand back a key - such as a kms
+// client - can implement this.
+pub trait DbKeyProvider {
+    fn get_key(&self, audit: &mut AuditScope) -> Result<DbKey, OperationError>;
+}
+
+impl DbKeyProvider for DbKey {
+    fn get_key(&self, _audit: &mut AuditScope) -> Result<DbKey, OperationError> {
+        Ok(self.clone())
+    }
+}
+
+// A file holding the key as hex. It should be readable only by the server.
+pub struct DbKeyFile {
+    path: PathBuf,
+}
+
+impl DbKeyFile {
+    pub fn new(path: PathBuf) -> Self {
+        DbKeyFile { path: path }
+    }
+}
+
+impl DbKeyProvider for DbKeyFile {
+    fn get_key(&self, audit: &mut AuditScope) -> Result<DbKey, OperationError> {
+        let hex = try_audit!(
+            audit,
+            fs::read_to_string(&self.path),
+            "Unable to read db key file {:?}",
+            OperationError::FsError
+        );
+        DbKey::from_hex(hex.as_str())
+    }
+}
+
+// Keys each connection as the pool opens it.
+#[derive(Debug)]
+pub(crate) struct DbKeyCustomizer {
+    key: DbKey,
+}
+
+impl DbKeyCustomizer {
+    pub(crate) fn new(key: DbKey) -> Self {
+        DbKeyCustomizer { key: key }
+    }
+}
+
+impl CustomizeConnection<Connection, rusqlite::Error> for DbKeyCustomizer {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        self.key.apply(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DbKey;
+    use crate::error::OperationError;
+
+    #[test]
+    fn test_db_key_from_hex() {
+        let k =
+            DbKey::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n")
+                .expect("Failed to parse key");
+        assert!(
+            k.pragma_value()
+                == "\"x'000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f'\""
+        );
+        assert!(format!("{:?}", k) == "DbKey(<redacted>)");
+
+        // Too short
+        assert!(
+            DbKey::from_hex("0001").unwrap_err()
+                == OperationError::InvalidDbKey("key must be 64 hex characters")
+        );
+        // Not hex
+        assert!(DbKey::from_hex(
+            "zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+        )
+        .is_err());
+    }
+}
@@ -1,22 +1,37 @@
 //! Db executor actor
 
-use r2d2::Pool;
+use r2d2::{CustomizeConnection, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::types::ToSql;
-use rusqlite::NO_PARAMS;
+use rayon::prelude::*;
+use rusqlite::types::{ToSql, ValueRef};
+use rusqlite::{Connection, NO_PARAMS};
 use serde_cbor;
 use serde_json;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::convert::TryFrom;
 use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
 
 use crate::audit::AuditScope;
-use crate::be::dbentry::DbEntry;
+use crate::be::dbentry::{DbBackup, DbBackupV1, DbEntry};
+use crate::be::idl::{DbIdl, IDL};
+use crate::be::key::{DbKey, DbKeyCustomizer, DbKeyProvider};
+use crate::changes::ChangeOp;
+use crate::config::{DbDurability, DbSync};
+use crate::constants::IDL_ALLIDS_THRESHOLD;
+use crate::csn::Csn;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
 use crate::error::{ConsistencyError, OperationError};
-use crate::filter::{Filter, FilterValidResolved};
+use crate::filter::{Filter, FilterResolved, FilterValidResolved};
+use crate::schema::IndexType;
 
 pub mod dbentry;
 mod idl;
+pub mod key;
 mod mem_be;
 mod sqlite_be;
 
@@ -27,109 +42,720 @@ struct IdEntry {
     data: Vec<u8>,
 }
 
+impl IdEntry {
+    fn to_entry(&self) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+        entry_from_raw(self.id, self.data.as_slice())
+    }
+}
+
+// Who made a change, and when. This is kept with the change in the changelog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeOrigin {
+    pub csn: Csn,
+    // The uuid of the account that made the change, or None if the server
+    // made it itself.
+    pub initiator: Option<String>,
+}
+
+impl ChangeOrigin {
+    pub fn internal(csn: Csn) -> Self {
+        ChangeOrigin {
+            csn: csn,
+            initiator: None,
+        }
+    }
+}
+
+// One change, as read back from the changelog. This only says that the entry
+// changed - its state is read from the entry itself. The op is what was done
+// to the stored entry: deleting an entry recycles it, which is a modify, and
+// it's only deleted once its tombstone is purged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangelogEntry {
+    pub seq: i64,
+    pub uuid: String,
+    pub op: ChangeOp,
+    pub origin: ChangeOrigin,
+}
+
+// A csn's time is kept as nanoseconds since the epoch, so sqlite can order
+// and compare them. This lasts until 2262. The replica is kept beside it, and
+// is missing for changes from before replicas were recorded.
+fn csn_to_db(csn: &Csn) -> i64 {
+    let ts = csn.ts();
+    (ts.as_secs() as i64) * 1_000_000_000 + i64::from(ts.subsec_nanos())
+}
+
+fn csn_from_db(ns: i64, rid: Option<String>) -> Csn {
+    let ns = if ns < 0 { 0 } else { ns as u64 };
+    let rid = rid
+        .and_then(|r| Uuid::parse_str(r.as_str()).ok())
+        .unwrap_or_else(Uuid::nil);
+    Csn::new(
+        Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32),
+        rid,
+    )
+}
+
+fn change_op_to_db(op: ChangeOp) -> &'static str {
+    match op {
+        ChangeOp::Create => "create",
+        ChangeOp::Modify => "modify",
+        ChangeOp::Delete => "delete",
+    }
+}
+
+// Changes from before the op was recorded are taken as modifies - the entry
+// is read as it is now either way.
+fn change_op_from_db(op: Option<String>) -> ChangeOp {
+    match op.as_ref().map(|s| s.as_str()) {
+        Some("create") => ChangeOp::Create,
+        Some("delete") => ChangeOp::Delete,
+        _ => ChangeOp::Modify,
+    }
+}
+
+// Decode an entry from its stored bytes. A search hands this the blob while
+// it is still in sqlite's buffer, so a mapped page is read without a copy.
+fn entry_from_raw(
+    id: i64,
+    data: &[u8],
+) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+    let db_e: DbEntry = serde_cbor::from_slice(data).map_err(|_| OperationError::SerdeCborError)?;
+    let id = u64::try_from(id).map_err(|_| OperationError::InvalidEntryID)?;
+    Entry::from_dbentry(db_e, id).ok_or(OperationError::CorruptedEntry)
+}
+
+// Let sqlite memory map the database file, so reads come from the page cache
+// instead of a read syscall per page. This is set per connection.
+#[derive(Debug)]
+struct DbMmapCustomizer {
+    mmap_size: u64,
+}
+
+impl CustomizeConnection<Connection, rusqlite::Error> for DbMmapCustomizer {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        // The pragma returns the size sqlite settled on, which may be capped
+        // by how it was built.
+        conn.query_row(
+            format!("PRAGMA mmap_size = {}", self.mmap_size).as_str(),
+            NO_PARAMS,
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|_| ())
+    }
+}
+
+fn sync_pragma(s: DbSync) -> &'static str {
+    match s {
+        DbSync::Full => "PRAGMA synchronous = FULL",
+        DbSync::Normal => "PRAGMA synchronous = NORMAL",
+        DbSync::Off => "PRAGMA synchronous = OFF",
+    }
+}
+
+// How many rows of id2entry a search reads at a time.
+const SEARCH_BATCH_SIZE: i64 = 256;
+
+// An entry read from id2entry, or why it couldn't be.
+type IdRow = (
+    i64,
+    Result<Entry<EntryValid, EntryCommitted>, OperationError>,
+);
+
+// The entries of id2entry after last_id, in id order, a batch at a time.
+fn read_id2entry_batch(
+    au: &mut AuditScope,
+    conn: &Connection,
+    last_id: i64,
+) -> Result<Vec<IdRow>, OperationError> {
+    read_id2entry_batch_of(au, conn, last_id, SEARCH_BATCH_SIZE)
+}
+
+fn read_id2entry_batch_of(
+    au: &mut AuditScope,
+    conn: &Connection,
+    last_id: i64,
+    limit: i64,
+) -> Result<Vec<IdRow>, OperationError> {
+    let mut stmt = try_audit!(
+        au,
+        conn.prepare(
+            "SELECT id, data FROM id2entry WHERE id > :last_id ORDER BY id ASC LIMIT :limit"
+        ),
+        "SQLite Error {:?}",
+        OperationError::SQLiteError
+    );
+    let id2entry_iter = try_audit!(
+        au,
+        stmt.query_map_named(&[(":last_id", &last_id), (":limit", &limit)], |row| {
+            let id: i64 = row.get(0);
+            let e = match row.get_raw(1) {
+                ValueRef::Blob(data) => entry_from_raw(id, data),
+                _ => Err(OperationError::SerdeCborError),
+            };
+            (id, e)
+        }),
+        "SQLite Error {:?}",
+        OperationError::SQLiteError
+    );
+    let mut rows = Vec::new();
+    for row in id2entry_iter {
+        rows.push(try_audit!(
+            au,
+            row,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        ));
+    }
+    Ok(rows)
+}
+
+// The entries with these ids. An id with no entry is skipped.
+fn read_id2entry_ids(
+    au: &mut AuditScope,
+    conn: &Connection,
+    ids: &[i64],
+) -> Result<Vec<IdRow>, OperationError> {
+    let mut stmt = try_audit!(
+        au,
+        conn.prepare("SELECT data FROM id2entry WHERE id = :id"),
+        "SQLite Error {:?}",
+        OperationError::SQLiteError
+    );
+    let mut rows = Vec::with_capacity(ids.len());
+    for id in ids {
+        let mut id_rows = try_audit!(
+            au,
+            stmt.query_named(&[(":id", id)]),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        match id_rows.next() {
+            Some(Ok(row)) => {
+                let e = match row.get_raw(0) {
+                    ValueRef::Blob(data) => entry_from_raw(*id, data),
+                    _ => Err(OperationError::SerdeCborError),
+                };
+                rows.push((*id, e));
+            }
+            None => {
+                audit_log!(au, "Index refers to missing entry {}", id);
+            }
+            Some(Err(e)) => {
+                audit_log!(au, "SQLite Error {:?}", e);
+                return Err(OperationError::SQLiteError);
+            }
+        }
+    }
+    Ok(rows)
+}
+
+// The key held for an attribute being present.
+const IDX_PRES_KEY: &'static str = "_";
+// A substring index holds every run of this many characters of a value. A
+// substring filter at least this long can use it.
+const IDX_SUB_LEN: usize = 3;
+
+fn idx_sub_keys(v: &str) -> Vec<String> {
+    let chars: Vec<char> = v.chars().collect();
+    chars
+        .windows(IDX_SUB_LEN)
+        .map(|w| w.iter().collect())
+        .collect()
+}
+
+// Every index key this entry should be found under.
+fn idx_keys<STATE>(
+    idxmeta: &BTreeSet<(String, IndexType)>,
+    e: &Entry<EntryValid, STATE>,
+) -> BTreeSet<(String, IndexType, String)> {
+    let mut keys = BTreeSet::new();
+    for (attr, itype) in idxmeta.iter() {
+        let vs = match e.get_ava(attr.as_str()) {
+            Some(vs) => vs,
+            None => continue,
+        };
+        match itype {
+            IndexType::EQUALITY => vs.iter().for_each(|v| {
+                keys.insert((attr.clone(), itype.clone(), v.to_string()));
+            }),
+            IndexType::PRESENCE => {
+                keys.insert((attr.clone(), itype.clone(), IDX_PRES_KEY.to_string()));
+            }
+            IndexType::SUBSTRING => vs.iter().for_each(|v| {
+                idx_sub_keys(v.to_string().as_str())
+                    .into_iter()
+                    .for_each(|k| {
+                        keys.insert((attr.clone(), itype.clone(), k));
+                    })
+            }),
+        }
+    }
+    keys
+}
+
+// Walks the candidate entries in id order, keeping the entries that match the
+// filter. The candidates are those the indexes gave, or all of id2entry if
+// they couldn't help. An entry that can't be read is left out, rather than
+// failing the whole search. It's moved to quarantine at the next startup.
+pub struct BackendSearchIter<'a> {
+    conn: &'a Connection,
+    filt: Filter<FilterValidResolved>,
+    // The ids still to read, when the indexes narrowed the search.
+    candidates: Option<VecDeque<i64>>,
+    // Whether the entries read still need testing against the filter.
+    test: bool,
+    // How many entries are read at a time, and how many there must be in a
+    // batch to test it on every core. See Backend::set_parallel_search_min.
+    batch_size: i64,
+    parallel_min: Option<usize>,
+    last_id: i64,
+    matched: VecDeque<Entry<EntryValid, EntryCommitted>>,
+    done: bool,
+}
+
+impl<'a> BackendSearchIter<'a> {
+    // The next matching entry, or None once id2entry is exhausted. After an
+    // error, nothing more is returned.
+    pub fn next_entry(
+        &mut self,
+        au: &mut AuditScope,
+    ) -> Option<Result<Entry<EntryValid, EntryCommitted>, OperationError>> {
+        while self.matched.is_empty() && !self.done {
+            if let Err(e) = self.read_batch(au) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.matched.pop_front().map(Ok)
+    }
+
+    fn read_batch(&mut self, au: &mut AuditScope) -> Result<(), OperationError> {
+        let rows = match self.candidates.as_mut() {
+            Some(ids) => {
+                let batch: Vec<i64> = (0..self.batch_size)
+                    .filter_map(|_| ids.pop_front())
+                    .collect();
+                if ids.is_empty() {
+                    self.done = true;
+                }
+                read_id2entry_ids(au, self.conn, batch.as_slice())?
+            }
+            None => {
+                let rows = read_id2entry_batch_of(au, self.conn, self.last_id, self.batch_size)?;
+                if (rows.len() as i64) < self.batch_size {
+                    self.done = true;
+                }
+                rows
+            }
+        };
+
+        let (test, filt) = (self.test, &self.filt);
+        let check = |(id, e): IdRow| {
+            let e = e.map(|e| {
+                if !test || e.entry_match_no_index(filt) {
+                    Some(e)
+                } else {
+                    None
+                }
+            });
+            (id, e)
+        };
+        let parallel = match self.parallel_min {
+            Some(min) => test && rows.len() >= min,
+            None => false,
+        };
+        let checked: Vec<_> = if parallel {
+            rows.into_par_iter().map(&check).collect()
+        } else {
+            rows.into_iter().map(&check).collect()
+        };
+
+        for (id, e) in checked {
+            self.last_id = id;
+            match e {
+                Ok(Some(e)) => self.matched.push_back(e),
+                Ok(None) => {}
+                Err(e) => {
+                    audit_log!(au, "Skipping damaged entry {} -> {:?}", id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct Backend {
     pool: Pool<SqliteConnectionManager>,
+    path: String,
+    durability: DbDurability,
+    // Set while a bulk import runs with syncing off. Shared by every clone.
+    relaxed: Arc<AtomicBool>,
+    parallel_min: Option<usize>,
+    idl_max: usize,
+    // This replica's id, which every csn it makes carries.
+    rid: Uuid,
 }
 
 pub struct BackendReadTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    parallel_min: Option<usize>,
+    idl_max: usize,
 }
 
 pub struct BackendWriteTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    parallel_min: Option<usize>,
+    idl_max: usize,
+    // The id lists changed in this txn. Each is written once, at commit,
+    // however many entries touched it.
+    idl_pending: RefCell<BTreeMap<(String, IndexType, String), BTreeSet<u64>>>,
+    // The highest id in id2entry, once a create has looked it up.
+    id_max: Cell<Option<i64>>,
 }
 
 pub trait BackendTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
+    fn get_parallel_min(&self) -> Option<usize>;
+
+    // An index key with more ids than this is treated as ALLIDS when
+    // resolving a filter. See Backend::set_idl_allids_threshold.
+    fn get_idl_max(&self) -> usize;
+
+    // An id list this txn has changed but not yet written.
+    fn get_idl_pending(
+        &self,
+        _attr: &str,
+        _itype: &IndexType,
+        _key: &str,
+    ) -> Option<BTreeSet<u64>> {
+        None
+    }
+
+    // Write out any id lists held back, before the idx table is read whole.
+    fn flush_idls(&self, _au: &mut AuditScope) -> Result<(), OperationError> {
+        Ok(())
+    }
+
     // Take filter, and AuditScope ref?
     fn search(
         &self,
         au: &mut AuditScope,
         filt: &Filter<FilterValidResolved>,
     ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        // Do things
-        // Alloc a vec for the entries.
         // TODO #8: Make this actually a good size for the result set ...
-        // TODO #8: Actually compute indexes here.
-        // So to make this use indexes, we can use the filter type and
-        // destructure it to work out what we need to actually search (if
-        // possible) to create the candidate set.
-        // Unlike DS, even if we don't get the index back, we can just pass
-        // to the in-memory filter test and be done.
+        // The indexes give us the candidate set. Unlike DS, even if we don't
+        // get the index back, we can just pass to the in-memory filter test
+        // and be done.
         audit_segment!(au, || {
-            // Do a final optimise of the filter
-            let filt = filt.optimise();
-            audit_log!(au, "filter optimised to --> {:?}", filt);
+            let mut entries: Vec<Entry<EntryValid, EntryCommitted>> = Vec::new();
+            let mut iter = self.search_iter(au, filt.clone())?;
+            while let Some(r) = iter.next_entry(au) {
+                entries.push(r?);
+            }
 
-            let mut raw_entries: Vec<IdEntry> = Vec::new();
-            {
-                // Actually do a search now!
-                // read them all
-                let mut stmt = try_audit!(
-                    au,
-                    self.get_conn().prepare("SELECT id, data FROM id2entry"),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
-                let id2entry_iter = try_audit!(
-                    au,
-                    stmt.query_map(NO_PARAMS, |row| IdEntry {
-                        id: row.get(0),
-                        data: row.get(1),
-                    }),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
+            Ok(entries)
+        })
+    }
 
-                for row in id2entry_iter {
-                    // audit_log!(au, "raw entry: {:?}", row);
-                    raw_entries.push(try_audit!(
-                        au,
-                        row,
-                        "SQLite Error {:?}",
-                        OperationError::SQLiteError
-                    ));
+    // As search, but the matching entries are read and returned a batch at a
+    // time, so a large result set is never held in memory all at once.
+    fn search_iter(
+        &self,
+        au: &mut AuditScope,
+        filt: Filter<FilterValidResolved>,
+    ) -> Result<BackendSearchIter, OperationError> {
+        let idxmeta = self.get_idxmeta(au)?;
+        // Do a final optimise of the filter
+        let filt = self.filter_optimise(au, &filt, &idxmeta)?;
+        audit_log!(au, "filter optimised to --> {:?}", filt);
+
+        let idl = self.filter2idl(au, filt.to_inner(), &idxmeta)?;
+        audit_log!(au, "filter resolved to idl --> {:?}", idl);
+
+        let (candidates, test) = match idl {
+            IDL::ALLIDS => (None, true),
+            IDL::Partial(ids) => (Some(ids), true),
+            IDL::Indexed(ids) => (Some(ids), false),
+        };
+        let candidates: Option<VecDeque<i64>> =
+            candidates.map(|ids| ids.into_iter().map(|id| id as i64).collect());
+        let done = candidates
+            .as_ref()
+            .map(|ids| ids.is_empty())
+            .unwrap_or(false);
+
+        let parallel_min = self.get_parallel_min();
+        let batch_size = parallel_min
+            .map(|min| (min as i64).max(SEARCH_BATCH_SIZE))
+            .unwrap_or(SEARCH_BATCH_SIZE);
+
+        Ok(BackendSearchIter {
+            conn: self.get_conn(),
+            filt: filt,
+            candidates: candidates,
+            test: test,
+            batch_size: batch_size,
+            parallel_min: parallel_min,
+            last_id: 0,
+            matched: VecDeque::new(),
+            done: done,
+        })
+    }
+
+    // What is indexed, as (attribute, index type).
+    fn get_idxmeta(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<BTreeSet<(String, IndexType)>, OperationError> {
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn().prepare("SELECT attr, itype FROM idxmeta"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let rows = try_audit!(
+            au,
+            stmt.query_map(NO_PARAMS, |row| -> (String, String) {
+                (row.get(0), row.get(1))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut idxmeta = BTreeSet::new();
+        for row in rows {
+            let (attr, itype) =
+                try_audit!(au, row, "SQLite Error {:?}", OperationError::SQLiteError);
+            let itype = try_audit!(
+                au,
+                IndexType::try_from(itype.as_str()),
+                "Invalid index type {:?}",
+                OperationError::CorruptedIndex(attr)
+            );
+            idxmeta.insert((attr, itype));
+        }
+        Ok(idxmeta)
+    }
+
+    // The ids of the entries with this key in an index.
+    fn get_idl(
+        &self,
+        au: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+        key: &str,
+    ) -> Result<BTreeSet<u64>, OperationError> {
+        if let Some(idl) = self.get_idl_pending(attr, itype, key) {
+            return Ok(idl);
+        }
+        self.get_dbidl(au, attr, itype, key).map(|idl| idl.to_ids())
+    }
+
+    // As get_idl, but None if the key holds more than max ids. A stored
+    // list is counted before its runs are expanded.
+    fn get_idl_bounded(
+        &self,
+        au: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+        key: &str,
+        max: usize,
+    ) -> Result<Option<BTreeSet<u64>>, OperationError> {
+        let count = match self.get_idl_pending(attr, itype, key) {
+            Some(idl) => {
+                if idl.len() <= max {
+                    return Ok(Some(idl));
                 }
+                idl.len()
             }
-            // Do other things
-            // Now, de-serialise the raw_entries back to entries, and populate their ID's
+            None => {
+                let idl = self.get_dbidl(au, attr, itype, key)?;
+                if idl.count() <= max {
+                    return Ok(Some(idl.to_ids()));
+                }
+                idl.count()
+            }
+        };
+        audit_log!(
+            au,
+            "{} {:?} {} holds {} ids, more than {} - treating as ALLIDS",
+            attr,
+            itype,
+            key,
+            count,
+            max
+        );
+        Ok(None)
+    }
 
-            let entries: Result<Vec<Entry<EntryValid, EntryCommitted>>, _> = raw_entries
-                .iter()
-                .filter_map(|id_ent| {
-                    // We need the matches here to satisfy the filter map
-                    let db_e = match serde_cbor::from_slice(id_ent.data.as_slice())
-                        .map_err(|_| OperationError::SerdeCborError)
-                    {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
+    // The stored id list of a key, as runs.
+    fn get_dbidl(
+        &self,
+        au: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+        key: &str,
+    ) -> Result<DbIdl, OperationError> {
+        let itype = itype.to_string();
+        let r: Result<Vec<u8>, _> = self.get_conn().query_row_named(
+            "SELECT idl FROM idx WHERE attr = :attr AND itype = :itype AND key = :key",
+            &[(":attr", &attr), (":itype", &itype), (":key", &key)],
+            |row| row.get(0),
+        );
+        match r {
+            Ok(data) => serde_cbor::from_slice(data.as_slice()).map_err(|e| {
+                audit_log!(au, "Invalid idl for {} {} {} -> {:?}", attr, itype, key, e);
+                OperationError::CorruptedIndex(attr.to_string())
+            }),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(DbIdl::default()),
+            Err(e) => {
+                audit_log!(au, "SQLite Error {:?}", e);
+                Err(OperationError::SQLiteError)
+            }
+        }
+    }
+
+    // Work out which entries a filter could match from the indexes.
+    fn filter2idl(
+        &self,
+        au: &mut AuditScope,
+        f: &FilterResolved,
+        idxmeta: &BTreeSet<(String, IndexType)>,
+    ) -> Result<IDL, OperationError> {
+        let indexed = |attr: &String, itype: IndexType| idxmeta.contains(&(attr.clone(), itype));
+        // A key too common to be worth loading narrows nothing.
+        let max = self.get_idl_max();
+        Ok(match f {
+            FilterResolved::Eq(attr, value) => {
+                if indexed(attr, IndexType::EQUALITY) {
+                    self.get_idl_bounded(
+                        au,
+                        attr.as_str(),
+                        &IndexType::EQUALITY,
+                        value.to_string().as_str(),
+                        max,
+                    )?
+                    .map(IDL::Indexed)
+                    .unwrap_or(IDL::ALLIDS)
+                } else {
+                    IDL::ALLIDS
+                }
+            }
+            FilterResolved::Pres(attr) => {
+                if indexed(attr, IndexType::PRESENCE) {
+                    self.get_idl_bounded(
+                        au,
+                        attr.as_str(),
+                        &IndexType::PRESENCE,
+                        IDX_PRES_KEY,
+                        max,
+                    )?
+                    .map(IDL::Indexed)
+                    .unwrap_or(IDL::ALLIDS)
+                } else {
+                    IDL::ALLIDS
+                }
+            }
+            FilterResolved::Sub(attr, sub) => {
+                let keys: Vec<String> = sub.parts().flat_map(|p| idx_sub_keys(p)).collect();
+                if indexed(attr, IndexType::SUBSTRING) && !keys.is_empty() {
+                    // Having every run of each part doesn't mean having the
+                    // parts, in order and in place, so these must be tested.
+                    let mut idl = IDL::ALLIDS;
+                    for k in keys {
+                        if let Some(ids) = self.get_idl_bounded(
+                            au,
+                            attr.as_str(),
+                            &IndexType::SUBSTRING,
+                            k.as_str(),
+                            max,
+                        )? {
+                            idl = idl.and(IDL::Partial(ids));
+                        }
+                    }
+                    idl
+                } else {
+                    IDL::ALLIDS
+                }
+            }
+            FilterResolved::Or(l) => {
+                // An empty or matches nothing.
+                let mut idl = IDL::Indexed(BTreeSet::new());
+                for f in l.iter() {
+                    idl = idl.or(self.filter2idl(au, f, idxmeta)?);
+                    if idl == IDL::ALLIDS {
+                        break;
+                    }
+                }
+                idl
+            }
+            FilterResolved::And(l) => {
+                // The not terms can only be removed from the rest, so they
+                // are done last.
+                let mut idl: Option<IDL> = None;
+                for f in l.iter() {
+                    if let FilterResolved::AndNot(_) = f {
+                        continue;
+                    }
+                    let f_idl = self.filter2idl(au, f, idxmeta)?;
+                    let r = match idl {
+                        Some(idl) => idl.and(f_idl),
+                        None => f_idl,
                     };
-                    let id = match u64::try_from(id_ent.id)
-                        .map_err(|_| OperationError::InvalidEntryID)
-                    {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
+                    // Nothing left for the remaining terms to narrow, so
+                    // don't load their idls.
+                    let empty = match &r {
+                        IDL::Indexed(ids) | IDL::Partial(ids) => ids.is_empty(),
+                        IDL::ALLIDS => false,
                     };
-                    let e =
-                        match Entry::from_dbentry(db_e, id).ok_or(OperationError::CorruptedEntry) {
-                            Ok(v) => v,
-                            Err(e) => return Some(Err(e)),
-                        };
-                    if e.entry_match_no_index(&filt) {
-                        Some(Ok(e))
-                    } else {
-                        None
+                    idl = Some(r);
+                    if empty {
+                        break;
                     }
-                })
-                .collect();
+                }
+                let mut idl = idl.unwrap_or(IDL::ALLIDS);
+                for f in l.iter() {
+                    if let FilterResolved::AndNot(f) = f {
+                        idl = idl.andnot(self.filter2idl(au, f, idxmeta)?);
+                    }
+                }
+                idl
+            }
+            // There is no ordered index yet.
+            FilterResolved::Ge(_, _) | FilterResolved::Le(_, _) => IDL::ALLIDS,
+            // On its own, a not can match any entry the indexes don't list.
+            FilterResolved::AndNot(_) => IDL::ALLIDS,
+        })
+    }
 
-            entries
+    // How many entries a single term could match, going by the size of its
+    // idl. Terms the indexes can't answer could match every entry.
+    fn filter_estimate(
+        &self,
+        au: &mut AuditScope,
+        f: &FilterResolved,
+        idxmeta: &BTreeSet<(String, IndexType)>,
+    ) -> Result<usize, OperationError> {
+        Ok(match self.filter2idl(au, f, idxmeta)? {
+            IDL::ALLIDS => usize::max_value(),
+            IDL::Partial(ids) | IDL::Indexed(ids) => ids.len(),
         })
     }
 
+    // Reorder the filter so its most selective terms are resolved first.
+    fn filter_optimise(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+        idxmeta: &BTreeSet<(String, IndexType)>,
+    ) -> Result<Filter<FilterValidResolved>, OperationError> {
+        filt.optimise_cost(&mut |f: &FilterResolved| self.filter_estimate(au, f, idxmeta))
+    }
+
     /// Given a filter, assert some condition exists.
     /// Basically, this is a specialised case of search, where we don't need to
     /// load any candidates if they match. This is heavily used in uuid
@@ -163,13 +789,423 @@ pub trait BackendTransaction {
         }
     }
 
+    // The uuids of entries changed after the changelog position seq, and the
+    // position of the latest change. Passing that back in later gets the
+    // changes made since this call.
+    fn changelog_since(
+        &self,
+        au: &mut AuditScope,
+        seq: i64,
+    ) -> Result<(BTreeSet<String>, i64), OperationError> {
+        if let Some((trimmed_seq, _)) = self.changelog_trimmed(au)? {
+            if seq < trimmed_seq {
+                audit_log!(au, "changelog since {} was trimmed to {}", seq, trimmed_seq);
+                return Err(OperationError::ChangelogTrimmed);
+            }
+        }
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn()
+                .prepare("SELECT seq, uuid FROM changelog WHERE seq > :seq"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let changes = try_audit!(
+            au,
+            stmt.query_map_named(&[(":seq", &seq)], |row| -> (i64, String) {
+                (row.get(0), row.get(1))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+
+        let mut max_seq = seq;
+        let mut uuids = BTreeSet::new();
+        for change in changes {
+            let (c_seq, uuid) =
+                try_audit!(au, change, "SQLite Error {:?}", OperationError::SQLiteError);
+            if c_seq > max_seq {
+                max_seq = c_seq;
+            }
+            uuids.insert(uuid);
+        }
+        audit_log!(au, "changelog since {} -> {} changes", seq, uuids.len());
+        Ok((uuids, max_seq))
+    }
+
+    // Every change after the changelog position seq, in the order they were
+    // made, with what each was and who made it. Unlike changelog_since, a
+    // change to an entry is listed every time it was made.
+    fn changelog_entries_since(
+        &self,
+        au: &mut AuditScope,
+        seq: i64,
+    ) -> Result<Vec<ChangelogEntry>, OperationError> {
+        if let Some((trimmed_seq, _)) = self.changelog_trimmed(au)? {
+            if seq < trimmed_seq {
+                audit_log!(au, "changelog since {} was trimmed to {}", seq, trimmed_seq);
+                return Err(OperationError::ChangelogTrimmed);
+            }
+        }
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn().prepare(
+                "SELECT seq, uuid, op, csn, initiator, rid FROM changelog WHERE seq > :seq ORDER BY seq"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let changes = try_audit!(
+            au,
+            stmt.query_map_named(&[(":seq", &seq)], |row| ChangelogEntry {
+                seq: row.get(0),
+                uuid: row.get(1),
+                op: change_op_from_db(row.get(2)),
+                origin: ChangeOrigin {
+                    csn: csn_from_db(row.get(3), row.get(5)),
+                    initiator: row.get(4),
+                },
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let changes: Result<Vec<_>, _> = changes.collect();
+        let changes = try_audit!(
+            au,
+            changes,
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        audit_log!(au, "changelog since {} -> {} changes", seq, changes.len());
+        Ok(changes)
+    }
+
+    // The seq and csn of the latest change removed from the changelog, if it
+    // has ever been trimmed. Nothing at or before these can be read back.
+    fn changelog_trimmed(&self, au: &mut AuditScope) -> Result<Option<(i64, i64)>, OperationError> {
+        let r: Result<(i64, i64), _> = self.get_conn().query_row(
+            "SELECT seq, csn FROM changelog_trim WHERE id = 0",
+            NO_PARAMS,
+            |row| (row.get(0), row.get(1)),
+        );
+        match r {
+            Ok(trimmed) => Ok(Some(trimmed)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => {
+                audit_log!(au, "SQLite Error {:?}", e);
+                Err(OperationError::SQLiteError)
+            }
+        }
+    }
+
+    // The position of the latest change in the changelog.
+    fn changelog_max_seq(&self, au: &mut AuditScope) -> Result<i64, OperationError> {
+        let max_seq: Option<i64> = try_audit!(
+            au,
+            self.get_conn()
+                .query_row("SELECT MAX(seq) FROM changelog", NO_PARAMS, |row| row
+                    .get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(max_seq.unwrap_or(0))
+    }
+
+    // The changelog position that everything derived from the entries - the
+    // indexes, memberof and the server's caches - was last known to be up to
+    // date with. None if it has never been recorded.
+    fn recovery_seq(&self, au: &mut AuditScope) -> Result<Option<i64>, OperationError> {
+        let seq: Option<i64> = try_audit!(
+            au,
+            self.get_conn()
+                .query_row("SELECT MAX(seq) FROM recovery", NO_PARAMS, |row| row.get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Ok(seq)
+    }
+
+    // The id of this replica, made when the database was. It stays with the
+    // database, so a restore keeps the one it had.
+    fn replica_id(&self, au: &mut AuditScope) -> Result<Uuid, OperationError> {
+        let rid: String = try_audit!(
+            au,
+            self.get_conn()
+                .query_row("SELECT rid FROM replica WHERE id = 0", NO_PARAMS, |row| row
+                    .get(0)),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        Uuid::parse_str(rid.as_str()).map_err(|e| {
+            audit_log!(au, "Invalid replica id {:?} -> {:?}", rid, e);
+            OperationError::InvalidDBState
+        })
+    }
+
+    // How much the entries hold, as (count, approximate bytes), by the
+    // attributes holding the values and by the classes of the entries.
+    // Damaged entries can't be measured, so are left out.
+    fn entry_sizes(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<
+        (
+            BTreeMap<String, (usize, usize)>,
+            BTreeMap<String, (usize, usize)>,
+        ),
+        OperationError,
+    > {
+        let mut attrs: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        let mut classes: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        let mut last_id = 0;
+        loop {
+            let rows = read_id2entry_batch(au, self.get_conn(), last_id)?;
+            let read = rows.len() as i64;
+            for (id, e) in rows {
+                last_id = id;
+                let e = match e {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+                let mut e_bytes = 0;
+                for (attr, vs) in e.avas() {
+                    let bytes = attr.len() + vs.iter().map(|v| v.approx_size()).sum::<usize>();
+                    let a = attrs.entry(attr.clone()).or_insert((0, 0));
+                    a.0 += vs.len();
+                    a.1 += bytes;
+                    e_bytes += bytes;
+                }
+                if let Some(cs) = e.get_ava("class") {
+                    for c in cs {
+                        let c = classes.entry(c.to_string()).or_insert((0, 0));
+                        c.0 += 1;
+                        c.1 += e_bytes;
+                    }
+                }
+            }
+            if read < SEARCH_BATCH_SIZE {
+                break;
+            }
+        }
+        Ok((attrs, classes))
+    }
+
+    // The number of keys in each attribute's indexes, and the bytes of the
+    // keys and their id lists.
+    fn idx_sizes(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<BTreeMap<String, (usize, usize)>, OperationError> {
+        self.flush_idls(au)?;
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn().prepare(
+                "SELECT attr, COUNT(*), SUM(LENGTH(key) + LENGTH(idl)) FROM idx GROUP BY attr"
+            ),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let rows = try_audit!(
+            au,
+            stmt.query_map(NO_PARAMS, |row| -> (String, i64, i64) {
+                (row.get(0), row.get(1), row.get(2))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut sizes = BTreeMap::new();
+        for row in rows {
+            let (attr, count, bytes) =
+                try_audit!(au, row, "SQLite Error {:?}", OperationError::SQLiteError);
+            sizes.insert(attr, (count as usize, bytes as usize));
+        }
+        Ok(sizes)
+    }
+
+    // Every index key, with the ids under it.
+    fn idx_all(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<Vec<((String, IndexType, String), BTreeSet<u64>)>, OperationError> {
+        self.flush_idls(au)?;
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn()
+                .prepare("SELECT attr, itype, key, idl FROM idx"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let rows = try_audit!(
+            au,
+            stmt.query_map(NO_PARAMS, |row| -> (String, String, String, Vec<u8>) {
+                (row.get(0), row.get(1), row.get(2), row.get(3))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut keys = Vec::new();
+        for row in rows {
+            let (attr, itype, k, data) =
+                try_audit!(au, row, "SQLite Error {:?}", OperationError::SQLiteError);
+            let itype = try_audit!(
+                au,
+                IndexType::try_from(itype.as_str()),
+                "Invalid index type {:?}",
+                OperationError::CorruptedIndex(attr)
+            );
+            let idl: DbIdl = try_audit!(
+                au,
+                serde_cbor::from_slice(data.as_slice()),
+                "Invalid idl {:?}",
+                OperationError::CorruptedIndex(attr)
+            );
+            keys.push(((attr, itype, k), idl.to_ids()));
+        }
+        Ok(keys)
+    }
+
+    // Check every index against the entries, as a rebuild would make them.
+    // An entry that can't be read is reported by verify, not here.
+    fn verify_indexes(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        let idxmeta = match self.get_idxmeta(au) {
+            Ok(m) => m,
+            Err(_) => return vec![Err(ConsistencyError::BackendQueryFailure)],
+        };
+
+        let mut expect: BTreeMap<(String, IndexType, String), BTreeSet<u64>> = BTreeMap::new();
+        let mut last_id = 0;
+        loop {
+            let rows = match read_id2entry_batch(au, self.get_conn(), last_id) {
+                Ok(r) => r,
+                Err(_) => return vec![Err(ConsistencyError::BackendQueryFailure)],
+            };
+            let read = rows.len() as i64;
+            for (id, e) in rows {
+                last_id = id;
+                if let Ok(e) = e {
+                    for key in idx_keys(&idxmeta, &e) {
+                        expect
+                            .entry(key)
+                            .or_insert_with(BTreeSet::new)
+                            .insert(id as u64);
+                    }
+                }
+            }
+            if read < SEARCH_BATCH_SIZE {
+                break;
+            }
+        }
+
+        let stored = match self.idx_all(au) {
+            Ok(s) => s,
+            Err(_) => return vec![Err(ConsistencyError::BackendQueryFailure)],
+        };
+        let mut results = Vec::new();
+        for (key, idl) in stored {
+            let want = expect.remove(&key).unwrap_or_else(BTreeSet::new);
+            let (attr, itype, k) = key;
+            for id in want.difference(&idl) {
+                results.push(Err(ConsistencyError::IndexKeyMissing(
+                    attr.clone(),
+                    itype.to_string(),
+                    k.clone(),
+                    *id,
+                )));
+            }
+            for id in idl.difference(&want) {
+                results.push(Err(ConsistencyError::IndexKeyStale(
+                    attr.clone(),
+                    itype.to_string(),
+                    k.clone(),
+                    *id,
+                )));
+            }
+        }
+        // Whatever is left has no stored key at all.
+        for ((attr, itype, k), want) in expect {
+            for id in want {
+                results.push(Err(ConsistencyError::IndexKeyMissing(
+                    attr.clone(),
+                    itype.to_string(),
+                    k.clone(),
+                    id,
+                )));
+            }
+        }
+        results
+    }
+
+    // Any entry in quarantine is an inconsistency - it was removed from the
+    // database because it couldn't be read.
     fn verify(&self) -> Vec<Result<(), ConsistencyError>> {
-        Vec::new()
+        let mut stmt = match self.get_conn().prepare("SELECT id FROM quarantine") {
+            Ok(s) => s,
+            Err(_) => return vec![Err(ConsistencyError::BackendQueryFailure)],
+        };
+        let ids = match stmt.query_map(NO_PARAMS, |row| row.get::<_, i64>(0)) {
+            Ok(ids) => ids,
+            Err(_) => return vec![Err(ConsistencyError::BackendQueryFailure)],
+        };
+        ids.map(|id| match id {
+            Ok(id) => Err(ConsistencyError::EntryQuarantined(id as u64)),
+            Err(_) => Err(ConsistencyError::BackendQueryFailure),
+        })
+        .collect()
+    }
+
+    // The ids of quarantined entries, and why each was quarantined.
+    fn quarantine_list(&self, au: &mut AuditScope) -> Result<Vec<(u64, String)>, OperationError> {
+        let mut stmt = try_audit!(
+            au,
+            self.get_conn()
+                .prepare("SELECT id, reason FROM quarantine ORDER BY id"),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let rows = try_audit!(
+            au,
+            stmt.query_map(NO_PARAMS, |row| -> (i64, String) {
+                (row.get(0), row.get(1))
+            }),
+            "SQLite Error {:?}",
+            OperationError::SQLiteError
+        );
+        let mut list = Vec::new();
+        for row in rows {
+            let (id, reason) =
+                try_audit!(au, row, "SQLite Error {:?}", OperationError::SQLiteError);
+            list.push((id as u64, reason));
+        }
+        Ok(list)
+    }
+
+    // The raw stored bytes of a quarantined entry, for forensics.
+    fn quarantine_get(&self, au: &mut AuditScope, id: u64) -> Result<Vec<u8>, OperationError> {
+        let id = try_audit!(
+            au,
+            i64::try_from(id),
+            "Invalid entry id {:?}",
+            OperationError::InvalidEntryID
+        );
+        self.get_conn()
+            .query_row_named(
+                "SELECT data FROM quarantine WHERE id = :id",
+                &[(":id", &id)],
+                |row| row.get(0),
+            )
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => OperationError::NoMatchingEntries,
+                e => {
+                    audit_log!(au, "SQLite Error {:?}", e);
+                    OperationError::SQLiteError
+                }
+            })
     }
 
     fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
         // load all entries into RAM, may need to change this later
         // if the size of the database compared to RAM is an issue
+        self.flush_idls(audit)?;
         let mut raw_entries: Vec<IdEntry> = Vec::new();
 
         {
@@ -205,7 +1241,20 @@ pub trait BackendTransaction {
 
         let entries = entries?;
 
-        let serialized_entries = serde_json::to_string_pretty(&entries);
+        // This is all read in the one transaction, so it's consistent even
+        // while the server carries on writing.
+        let idxmeta = self
+            .get_idxmeta(audit)?
+            .into_iter()
+            .map(|(attr, itype)| (attr, itype.to_string()))
+            .collect();
+        let changelog_seq = self.changelog_max_seq(audit)?;
+
+        let serialized_entries = serde_json::to_string_pretty(&DbBackup::V1(DbBackupV1 {
+            idxmeta: idxmeta,
+            changelog_seq: changelog_seq,
+            entries: entries,
+        }));
 
         let serialized_entries_str = try_audit!(
             audit,
@@ -243,7 +1292,11 @@ impl Drop for BackendReadTransaction {
 }
 
 impl BackendReadTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        parallel_min: Option<usize>,
+        idl_max: usize,
+    ) -> Self {
         // Start the transaction
         debug!("Starting BE RO txn ...");
         // I'm happy for this to be an expect, because this is a huge failure
@@ -256,6 +1309,8 @@ impl BackendReadTransaction {
         BackendReadTransaction {
             committed: false,
             conn: conn,
+            parallel_min: parallel_min,
+            idl_max: idl_max,
         }
     }
 }
@@ -264,9 +1319,23 @@ impl BackendTransaction for BackendReadTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
         &self.conn
     }
+
+    fn get_parallel_min(&self) -> Option<usize> {
+        self.parallel_min
+    }
+
+    fn get_idl_max(&self) -> usize {
+        self.idl_max
+    }
 }
 
 static DBV_ID2ENTRY: &'static str = "id2entry";
+static DBV_CHANGELOG: &'static str = "changelog";
+static DBV_QUARANTINE: &'static str = "quarantine";
+static DBV_INDEX: &'static str = "index";
+// The version of the data model, as moved on by the query server's
+// migrations rather than by setup.
+static DBV_DATA: &'static str = "data";
 
 impl Drop for BackendWriteTransaction {
     // Abort
@@ -284,10 +1353,41 @@ impl BackendTransaction for BackendWriteTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
         &self.conn
     }
+
+    fn get_parallel_min(&self) -> Option<usize> {
+        self.parallel_min
+    }
+
+    fn get_idl_max(&self) -> usize {
+        self.idl_max
+    }
+
+    fn get_idl_pending(&self, attr: &str, itype: &IndexType, key: &str) -> Option<BTreeSet<u64>> {
+        self.idl_pending
+            .borrow()
+            .get(&(attr.to_string(), itype.clone(), key.to_string()))
+            .cloned()
+    }
+
+    fn flush_idls(&self, au: &mut AuditScope) -> Result<(), OperationError> {
+        let pending = std::mem::replace(&mut *self.idl_pending.borrow_mut(), BTreeMap::new());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        audit_log!(au, "Writing {} pending index keys", pending.len());
+        for (key, idl) in pending.iter() {
+            self.idl_store(au, key, idl)?;
+        }
+        Ok(())
+    }
 }
 
 impl BackendWriteTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        parallel_min: Option<usize>,
+        idl_max: usize,
+    ) -> Self {
         // Start the transaction
         debug!("Starting BE WR txn ...");
         conn.execute("BEGIN TRANSACTION", NO_PARAMS)
@@ -295,6 +1395,10 @@ impl BackendWriteTransaction {
         BackendWriteTransaction {
             committed: false,
             conn: conn,
+            parallel_min: parallel_min,
+            idl_max: idl_max,
+            idl_pending: RefCell::new(BTreeMap::new()),
+            id_max: Cell::new(None),
         }
     }
 
@@ -321,13 +1425,18 @@ impl BackendWriteTransaction {
         })
     }
 
+    // Store the entries, returning the id each was given. This doesn't index
+    // them.
     fn internal_create(
         &self,
         au: &mut AuditScope,
         dbentries: &Vec<DbEntry>,
-    ) -> Result<(), OperationError> {
+    ) -> Result<Vec<i64>, OperationError> {
         // Get the max id from the db. We store this ourselves to avoid max() calls.
-        let mut id_max = self.get_id2entry_max_id()?;
+        let mut id_max = match self.id_max.get() {
+            Some(id_max) => id_max,
+            None => self.get_id2entry_max_id()?,
+        };
 
         let ser_entries: Result<Vec<IdEntry>, _> = dbentries
             .iter()
@@ -344,11 +1453,13 @@ impl BackendWriteTransaction {
             .collect();
 
         let ser_entries = ser_entries?;
+        self.id_max.set(Some(id_max));
+        let ids = ser_entries.iter().map(|ser_entry| ser_entry.id).collect();
         {
             let mut stmt = try_audit!(
                 au,
                 self.conn
-                    .prepare("INSERT INTO id2entry (id, data) VALUES (:id, :data)"),
+                    .prepare_cached("INSERT INTO id2entry (id, data) VALUES (:id, :data)"),
                 "rusqlite error {:?}",
                 OperationError::SQLiteError
             );
@@ -358,8 +1469,8 @@ impl BackendWriteTransaction {
                 try_audit!(
                     au,
                     stmt.execute_named(&[
-                        (":id", &ser_entry.id as &ToSql),
-                        (":data", &ser_entry.data as &ToSql)
+                        (":id", &ser_entry.id as &dyn ToSql),
+                        (":data", &ser_entry.data as &dyn ToSql)
                     ]),
                     "rusqlite error {:?}",
                     OperationError::SQLiteError
@@ -367,12 +1478,213 @@ impl BackendWriteTransaction {
             }
         }
 
+        Ok(ids)
+    }
+
+    // Add or remove an id under an index key.
+    fn idl_update(
+        &self,
+        au: &mut AuditScope,
+        key: &(String, IndexType, String),
+        id: u64,
+        add: bool,
+    ) -> Result<(), OperationError> {
+        if !self.idl_pending.borrow().contains_key(key) {
+            let (attr, itype, k) = key;
+            let idl = self.get_idl(au, attr.as_str(), itype, k.as_str())?;
+            self.idl_pending.borrow_mut().insert(key.clone(), idl);
+        }
+        if let Some(idl) = self.idl_pending.borrow_mut().get_mut(key) {
+            if add {
+                idl.insert(id);
+            } else {
+                idl.remove(&id);
+            }
+        }
         Ok(())
     }
 
+    fn idl_store(
+        &self,
+        au: &mut AuditScope,
+        key: &(String, IndexType, String),
+        idl: &BTreeSet<u64>,
+    ) -> Result<(), OperationError> {
+        // Written directly, so anything held back for this key is stale.
+        self.idl_pending.borrow_mut().remove(key);
+        let (attr, itype, k) = key;
+        let itype = itype.to_string();
+        if idl.is_empty() {
+            try_audit!(
+                au,
+                self.conn
+                    .prepare_cached(
+                        "DELETE FROM idx WHERE attr = :attr AND itype = :itype AND key = :key"
+                    )
+                    .and_then(|mut stmt| stmt.execute_named(&[
+                        (":attr", attr),
+                        (":itype", &itype),
+                        (":key", k)
+                    ])),
+                "rusqlite error {:?}",
+                OperationError::SQLiteError
+            );
+        } else {
+            let data =
+                serde_cbor::to_vec(&DbIdl::new(idl)).map_err(|_| OperationError::SerdeCborError)?;
+            try_audit!(
+                au,
+                self.conn
+                    .prepare_cached(
+                        "INSERT OR REPLACE INTO idx (attr, itype, key, idl) VALUES (:attr, :itype, :key, :idl)"
+                    )
+                    .and_then(|mut stmt| stmt.execute_named(&[
+                        (":attr", attr as &dyn ToSql),
+                        (":itype", &itype as &dyn ToSql),
+                        (":key", k as &dyn ToSql),
+                        (":idl", &data as &dyn ToSql),
+                    ])),
+                "rusqlite error {:?}",
+                OperationError::SQLiteError
+            );
+        }
+        Ok(())
+    }
+
+    // Move an entry from the index keys it was under to the ones it is now.
+    fn idx_update(
+        &self,
+        au: &mut AuditScope,
+        id: i64,
+        pre: &BTreeSet<(String, IndexType, String)>,
+        post: &BTreeSet<(String, IndexType, String)>,
+    ) -> Result<(), OperationError> {
+        let id = u64::try_from(id).map_err(|_| OperationError::InvalidEntryID)?;
+        for key in pre.difference(post) {
+            self.idl_update(au, key, id, false)?;
+        }
+        for key in post.difference(pre) {
+            self.idl_update(au, key, id, true)?;
+        }
+        Ok(())
+    }
+
+    // The index keys of the entries as they are stored now.
+    fn idx_keys_stored(
+        &self,
+        au: &mut AuditScope,
+        idxmeta: &BTreeSet<(String, IndexType)>,
+        ids: &[i64],
+    ) -> Result<BTreeMap<i64, BTreeSet<(String, IndexType, String)>>, OperationError> {
+        let mut stored = BTreeMap::new();
+        for (id, e) in read_id2entry_ids(au, &self.conn, ids)? {
+            let e = try_audit!(au, e);
+            stored.insert(id, idx_keys(idxmeta, &e));
+        }
+        Ok(stored)
+    }
+
+    // Record that these entries changed, for consumers that sync from the
+    // changelog. This is only a uuid - they read the current state themself.
+    fn changelog_append<'a, I>(
+        &self,
+        au: &mut AuditScope,
+        op: ChangeOp,
+        origin: &ChangeOrigin,
+        uuids: I,
+    ) -> Result<(), OperationError>
+    where
+        I: Iterator<Item = &'a String>,
+    {
+        let mut stmt = try_audit!(
+            au,
+            self.conn.prepare(
+                "INSERT INTO changelog (uuid, op, csn, initiator, rid) VALUES (:uuid, :op, :csn, :initiator, :rid)"
+            ),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        let op = change_op_to_db(op);
+        let csn = csn_to_db(&origin.csn);
+        let rid = origin.csn.rid().to_string();
+        for uuid in uuids {
+            try_audit!(
+                au,
+                stmt.execute_named(&[
+                    (":uuid", uuid),
+                    (":op", &op),
+                    (":csn", &csn),
+                    (":initiator", &origin.initiator),
+                    (":rid", &rid)
+                ]),
+                "rusqlite error {:?}",
+                OperationError::SQLiteError
+            );
+        }
+        Ok(())
+    }
+
+    // Remove the changes made before the csn. Changes the recovery point
+    // hasn't passed are always kept, as the next start may need them. Once
+    // trimmed, asking for changes from before what was removed is an error,
+    // so a consumer that fell that far behind knows to sync in full. Returns
+    // how many changes were removed.
+    pub fn changelog_trim(
+        &self,
+        au: &mut AuditScope,
+        before: &Csn,
+    ) -> Result<usize, OperationError> {
+        let before = csn_to_db(before);
+        let recovery_seq = self.recovery_seq(au)?.unwrap_or(0);
+        let (max_seq, max_csn): (Option<i64>, Option<i64>) = try_audit!(
+            au,
+            self.conn.query_row_named(
+                "SELECT MAX(seq), MAX(csn) FROM changelog WHERE csn < :csn AND seq <= :seq",
+                &[(":csn", &before), (":seq", &recovery_seq)],
+                |row| (row.get(0), row.get(1))
+            ),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        let (max_seq, max_csn) = match (max_seq, max_csn) {
+            (Some(s), Some(c)) => (s, c),
+            _ => {
+                audit_log!(au, "changelog trim -> nothing to remove");
+                return Ok(0);
+            }
+        };
+        let removed = try_audit!(
+            au,
+            self.conn.execute_named(
+                "DELETE FROM changelog WHERE csn < :csn AND seq <= :seq",
+                &[(":csn", &before), (":seq", &recovery_seq)],
+            ),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        // Keep the furthest trimmed to, as an earlier change may have a later
+        // csn than the one given if the clock was stepped back.
+        let (max_seq, max_csn) = match self.changelog_trimmed(au)? {
+            Some((s, c)) => (max_seq.max(s), max_csn.max(c)),
+            None => (max_seq, max_csn),
+        };
+        try_audit!(
+            au,
+            self.conn.execute_named(
+                "INSERT OR REPLACE INTO changelog_trim (id, seq, csn) VALUES (0, :seq, :csn)",
+                &[(":seq", &max_seq), (":csn", &max_csn)],
+            ),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        audit_log!(au, "changelog trim -> removed {} changes", removed);
+        Ok(removed)
+    }
+
     pub fn create(
         &self,
         au: &mut AuditScope,
+        origin: &ChangeOrigin,
         entries: &Vec<Entry<EntryValid, EntryNew>>,
     ) -> Result<(), OperationError> {
         // figured we would want a audit_segment to wrap internal_create so when doing profiling we can
@@ -392,15 +1704,27 @@ impl BackendWriteTransaction {
 
             let dbentries: Vec<_> = entries.iter().map(|e| e.into_dbentry()).collect();
 
-            self.internal_create(au, &dbentries)
+            let ids = self.internal_create(au, &dbentries)?;
+
+            let idxmeta = self.get_idxmeta(au)?;
+            let none = BTreeSet::new();
+            for (id, e) in ids.into_iter().zip(entries.iter()) {
+                self.idx_update(au, id, &none, &idx_keys(&idxmeta, e))?;
+            }
 
-            // TODO #8: update indexes (as needed)
+            self.changelog_append(
+                au,
+                ChangeOp::Create,
+                origin,
+                entries.iter().map(|e| e.get_uuid()),
+            )
         })
     }
 
     pub fn modify(
         &self,
         au: &mut AuditScope,
+        origin: &ChangeOrigin,
         entries: &Vec<Entry<EntryValid, EntryCommitted>>,
     ) -> Result<(), OperationError> {
         if entries.is_empty() {
@@ -453,12 +1777,17 @@ impl BackendWriteTransaction {
             return Err(OperationError::InvalidEntryState);
         }
 
+        // Find what the entries are indexed under before we replace them.
+        let idxmeta = self.get_idxmeta(au)?;
+        let ids: Vec<i64> = ser_entries.iter().map(|ser_ent| ser_ent.id).collect();
+        let pre_keys = self.idx_keys_stored(au, &idxmeta, ids.as_slice())?;
+
         // Now, given the list of id's, update them
         {
             let mut stmt = try_audit!(
                 au,
                 self.conn
-                    .prepare("UPDATE id2entry SET data = :data WHERE id = :id"),
+                    .prepare_cached("UPDATE id2entry SET data = :data WHERE id = :id"),
                 "RusqliteError: {:?}",
                 OperationError::SQLiteError
             );
@@ -473,12 +1802,24 @@ impl BackendWriteTransaction {
             }
         }
 
-        Ok(())
+        let none = BTreeSet::new();
+        for (id, e) in ids.into_iter().zip(entries.iter()) {
+            let pre = pre_keys.get(&id).unwrap_or(&none);
+            self.idx_update(au, id, pre, &idx_keys(&idxmeta, e))?;
+        }
+
+        self.changelog_append(
+            au,
+            ChangeOp::Modify,
+            origin,
+            entries.iter().map(|e| e.get_uuid()),
+        )
     }
 
     pub fn delete(
         &self,
         au: &mut AuditScope,
+        origin: &ChangeOrigin,
         entries: &Vec<Entry<EntryValid, EntryCommitted>>,
     ) -> Result<(), OperationError> {
         // Perform a search for the entries --> This is a problem for the caller
@@ -514,6 +1855,9 @@ impl BackendWriteTransaction {
                 return Err(OperationError::InvalidEntryState);
             }
 
+            let idxmeta = self.get_idxmeta(au)?;
+            let pre_keys = self.idx_keys_stored(au, &idxmeta, id_list.as_slice())?;
+
             // Now, given the list of id's, delete them.
             {
                 // SQL doesn't say if the thing "does or does not exist anymore". As a result,
@@ -521,7 +1865,8 @@ impl BackendWriteTransaction {
                 // probably okay with this.
                 let mut stmt = try_audit!(
                     au,
-                    self.conn.prepare("DELETE FROM id2entry WHERE id = :id"),
+                    self.conn
+                        .prepare_cached("DELETE FROM id2entry WHERE id = :id"),
                     "SQLite Error {:?}",
                     OperationError::SQLiteError
                 );
@@ -532,22 +1877,342 @@ impl BackendWriteTransaction {
                 }
             }
 
+            let none = BTreeSet::new();
+            for (id, pre) in pre_keys.iter() {
+                self.idx_update(au, *id, pre, &none)?;
+            }
+
+            self.changelog_append(
+                au,
+                ChangeOp::Delete,
+                origin,
+                entries.iter().map(|e| e.get_uuid()),
+            )
+        })
+    }
+
+    pub unsafe fn purge(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // remove all entries from database
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM id2entry", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        // and everything the indexes said about them.
+        self.idl_pending.borrow_mut().clear();
+        self.id_max.set(None);
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM idx", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
+
+        Ok(())
+    }
+
+    // Set what should be indexed. Returns whether that changed - if it did,
+    // the indexes must be rebuilt with reindex.
+    pub fn update_idxmeta(
+        &self,
+        au: &mut AuditScope,
+        idxmeta: BTreeSet<(String, IndexType)>,
+    ) -> Result<bool, OperationError> {
+        if self.get_idxmeta(au)? == idxmeta {
+            return Ok(false);
+        }
+        audit_log!(au, "Index configuration changed -> {:?}", idxmeta);
+        try_audit!(
+            au,
+            self.conn.execute("DELETE FROM idxmeta", NO_PARAMS),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        for (attr, itype) in idxmeta.iter() {
+            try_audit!(
+                au,
+                self.conn.execute_named(
+                    "INSERT INTO idxmeta (attr, itype) VALUES (:attr, :itype)",
+                    &[(":attr", attr), (":itype", &itype.to_string())],
+                ),
+                "rusqlite error {:?}",
+                OperationError::SQLiteError
+            );
+        }
+        Ok(true)
+    }
+
+    // Throw away the indexes, and build them again from every entry.
+    pub fn reindex(&self, au: &mut AuditScope) -> Result<(), OperationError> {
+        audit_segment!(au, || {
+            let idxmeta = self.get_idxmeta(au)?;
+            self.idl_pending.borrow_mut().clear();
+            try_audit!(
+                au,
+                self.conn.execute("DELETE FROM idx", NO_PARAMS),
+                "rusqlite error {:?}",
+                OperationError::SQLiteError
+            );
+
+            // Build the idls in memory, so each is written once.
+            let mut idls: BTreeMap<(String, IndexType, String), BTreeSet<u64>> = BTreeMap::new();
+            let mut last_id = 0;
+            loop {
+                let rows = read_id2entry_batch(au, &self.conn, last_id)?;
+                let read = rows.len() as i64;
+                for (id, e) in rows {
+                    last_id = id;
+                    match e {
+                        Ok(e) => idx_keys(&idxmeta, &e).into_iter().for_each(|k| {
+                            idls.entry(k)
+                                .or_insert_with(BTreeSet::new)
+                                .insert(id as u64);
+                        }),
+                        Err(e) => audit_log!(au, "Not indexing damaged entry {} -> {:?}", id, e),
+                    }
+                }
+                if read < SEARCH_BATCH_SIZE {
+                    break;
+                }
+            }
+
+            audit_log!(au, "Writing {} index keys", idls.len());
+            for (key, idl) in idls.iter() {
+                self.idl_store(au, key, idl)?;
+            }
             Ok(())
         })
     }
 
-    pub unsafe fn purge(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // remove all entries from database
+    pub fn set_recovery_seq(&self, au: &mut AuditScope, seq: i64) -> Result<(), OperationError> {
         try_audit!(
-            audit,
-            self.conn.execute("DELETE FROM id2entry", NO_PARAMS),
-            "rustqlite error {:?}",
+            au,
+            self.conn.execute_named(
+                "INSERT OR REPLACE INTO recovery (id, seq) VALUES (0, :seq)",
+                &[(":seq", &seq)],
+            ),
+            "rusqlite error {:?}",
             OperationError::SQLiteError
         );
+        Ok(())
+    }
+
+    pub fn get_data_version(&self) -> i64 {
+        self.get_db_version_key(DBV_DATA)
+    }
 
+    pub fn set_data_version(
+        &self,
+        au: &mut AuditScope,
+        version: i64,
+    ) -> Result<(), OperationError> {
+        try_audit!(
+            au,
+            self.conn.execute_named(
+                "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :version)",
+                &[(":id", &DBV_DATA), (":version", &version)],
+            ),
+            "rusqlite error {:?}",
+            OperationError::SQLiteError
+        );
         Ok(())
     }
 
+    // Write every entry back in the current DbEntry version, after passing it
+    // through f. Older versions are still read, but this means they never
+    // need to be again. This isn't a change to the entries, so it isn't
+    // added to the changelog, but what they're indexed under is brought up
+    // to date. Damaged entries are left for quarantine. Returns how many
+    // were written.
+    pub fn rewrite_entries<F>(&self, au: &mut AuditScope, f: F) -> Result<usize, OperationError>
+    where
+        F: Fn(Entry<EntryValid, EntryCommitted>) -> Entry<EntryValid, EntryCommitted>,
+    {
+        audit_segment!(au, || {
+            let idxmeta = self.get_idxmeta(au)?;
+            let none = BTreeSet::new();
+            let mut stmt = try_audit!(
+                au,
+                self.conn
+                    .prepare_cached("UPDATE id2entry SET data = :data WHERE id = :id"),
+                "RusqliteError: {:?}",
+                OperationError::SQLiteError
+            );
+            let mut written = 0;
+            let mut last_id = 0;
+            loop {
+                let rows = read_id2entry_batch(au, &self.conn, last_id)?;
+                let read = rows.len() as i64;
+                // The keys each entry is indexed under now. A damaged entry
+                // is skipped below, so it has none.
+                let pre_keys: BTreeMap<i64, BTreeSet<(String, IndexType, String)>> = rows
+                    .iter()
+                    .filter_map(|(id, e)| e.as_ref().ok().map(|e| (*id, idx_keys(&idxmeta, e))))
+                    .collect();
+                for (id, e) in rows {
+                    last_id = id;
+                    let e = match e {
+                        Ok(e) => f(e),
+                        Err(e) => {
+                            audit_log!(au, "Not rewriting damaged entry {} -> {:?}", id, e);
+                            continue;
+                        }
+                    };
+                    let data = try_audit!(
+                        au,
+                        serde_cbor::to_vec(&e.into_dbentry()),
+                        "CBOR Error {:?}",
+                        OperationError::SerdeCborError
+                    );
+                    try_audit!(
+                        au,
+                        stmt.execute_named(&[(":id", &id), (":data", &data)]),
+                        "RusqliteError: {:?}",
+                        OperationError::SQLiteError
+                    );
+                    let pre = pre_keys.get(&id).unwrap_or(&none);
+                    self.idx_update(au, id, pre, &idx_keys(&idxmeta, &e))?;
+                    written += 1;
+                }
+                if read < SEARCH_BATCH_SIZE {
+                    break;
+                }
+            }
+            Ok(written)
+        })
+    }
+
+    // Check the index keys of the entries with these uuids against the
+    // entries themselves, and put right any that are missing or left over.
+    // An id that is no longer in id2entry is taken out of every key, as it
+    // may have been a deleted entry. Returns how many keys were repaired.
+    pub fn reindex_uuids(
+        &self,
+        au: &mut AuditScope,
+        uuids: &BTreeSet<String>,
+    ) -> Result<usize, OperationError> {
+        audit_segment!(au, || {
+            let idxmeta = self.get_idxmeta(au)?;
+
+            let mut expect: BTreeMap<u64, BTreeSet<(String, IndexType, String)>> = BTreeMap::new();
+            let mut live: BTreeSet<u64> = BTreeSet::new();
+            let mut last_id = 0;
+            loop {
+                let rows = read_id2entry_batch(au, &self.conn, last_id)?;
+                let read = rows.len() as i64;
+                for (id, e) in rows {
+                    last_id = id;
+                    live.insert(id as u64);
+                    if let Ok(e) = e {
+                        if uuids.contains(e.get_uuid()) {
+                            expect.insert(id as u64, idx_keys(&idxmeta, &e));
+                        }
+                    }
+                }
+                if read < SEARCH_BATCH_SIZE {
+                    break;
+                }
+            }
+
+            let mut repaired = 0;
+            // What's left once the stored keys are seen is what's missing.
+            let mut missing = expect.clone();
+            for (key, idl) in self.idx_all(au)? {
+                let fixed: BTreeSet<u64> = idl
+                    .iter()
+                    .cloned()
+                    .filter(|id| {
+                        live.contains(id)
+                            && expect
+                                .get(id)
+                                .map(|keys| keys.contains(&key))
+                                .unwrap_or(true)
+                    })
+                    .collect();
+                for id in idl.iter() {
+                    if let Some(keys) = missing.get_mut(id) {
+                        keys.remove(&key);
+                    }
+                }
+                if fixed != idl {
+                    audit_log!(
+                        au,
+                        "recovery: removed {:?} from index {:?}",
+                        idl.difference(&fixed).collect::<Vec<_>>(),
+                        key
+                    );
+                    self.idl_store(au, &key, &fixed)?;
+                    repaired += 1;
+                }
+            }
+            for (id, keys) in missing {
+                for key in keys {
+                    audit_log!(au, "recovery: added {} to index {:?}", id, key);
+                    self.idl_update(au, &key, id, true)?;
+                    repaired += 1;
+                }
+            }
+            Ok(repaired)
+        })
+    }
+
+    // Move any entry that can no longer be read out of id2entry, keeping its
+    // raw bytes so they can be examined later. Returns the ids moved.
+    pub fn quarantine_damaged(&self, audit: &mut AuditScope) -> Result<Vec<u64>, OperationError> {
+        let raw_entries: Vec<IdEntry> = {
+            let mut stmt = try_audit!(
+                audit,
+                self.conn.prepare("SELECT id, data FROM id2entry"),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            let id2entry_iter = try_audit!(
+                audit,
+                stmt.query_map(NO_PARAMS, |row| IdEntry {
+                    id: row.get(0),
+                    data: row.get(1),
+                }),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            id2entry_iter.filter_map(Result::ok).collect()
+        };
+
+        let mut moved = Vec::new();
+        for id_ent in raw_entries {
+            let reason = match id_ent.to_entry() {
+                Ok(_) => continue,
+                Err(e) => format!("{:?}", e),
+            };
+            audit_log!(audit, "Quarantining entry {} -> {}", id_ent.id, reason);
+            try_audit!(
+                audit,
+                self.conn.execute_named(
+                    "INSERT OR REPLACE INTO quarantine (id, data, reason) VALUES(:id, :data, :reason)",
+                    &[
+                        (":id", &id_ent.id),
+                        (":data", &id_ent.data),
+                        (":reason", &reason),
+                    ],
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            try_audit!(
+                audit,
+                self.conn.execute_named(
+                    "DELETE FROM id2entry WHERE id = :id",
+                    &[(":id", &id_ent.id)]
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+            moved.push(id_ent.id as u64);
+        }
+        Ok(moved)
+    }
+
     pub fn restore(&self, audit: &mut AuditScope, src_path: &str) -> Result<(), OperationError> {
         // load all entries into RAM, may need to change this later
         // if the size of the database compared to RAM is an issue
@@ -561,18 +2226,58 @@ impl BackendWriteTransaction {
         );
 
         try_audit!(audit, unsafe { self.purge(audit) });
+        // Nothing quarantined refers to the restored data.
+        try_audit!(
+            audit,
+            self.conn.execute("DELETE FROM quarantine", NO_PARAMS),
+            "rustqlite error {:?}",
+            OperationError::SQLiteError
+        );
 
-        let entries_option: Result<Vec<DbEntry>, serde_json::Error> =
-            serde_json::from_str(&serialized_string);
-
-        let entries = try_audit!(
+        let backup = match serde_json::from_str::<DbBackup>(&serialized_string) {
+            Ok(DbBackup::V1(b)) => b,
+            Err(_) => {
+                // An unversioned backup. What it indexed wasn't kept, so
+                // that stays as it is.
+                let entries_option: Result<Vec<DbEntry>, serde_json::Error> =
+                    serde_json::from_str(&serialized_string);
+                let entries = try_audit!(
+                    audit,
+                    entries_option,
+                    "serde_json error {:?}",
+                    OperationError::SerdeJsonError
+                );
+                DbBackupV1 {
+                    idxmeta: Vec::new(),
+                    changelog_seq: 0,
+                    entries: entries,
+                }
+            }
+        };
+        audit_log!(
             audit,
-            entries_option,
-            "serde_json error {:?}",
-            OperationError::SerdeJsonError
+            "Restoring {} entries from changelog position {}",
+            backup.entries.len(),
+            backup.changelog_seq
         );
 
-        self.internal_create(audit, &entries)?;
+        if !backup.idxmeta.is_empty() {
+            let idxmeta: Result<BTreeSet<(String, IndexType)>, _> = backup
+                .idxmeta
+                .into_iter()
+                .map(|(attr, itype)| {
+                    IndexType::try_from(itype.as_str())
+                        .map(|itype| (attr, itype))
+                        .map_err(|_| OperationError::SerdeJsonError)
+                })
+                .collect();
+            self.update_idxmeta(audit, idxmeta?)?;
+        }
+
+        // The changelog is not rewritten by a restore, so sync consumers
+        // won't see the difference. They must resync from scratch after one.
+        self.internal_create(audit, &backup.entries)?;
+        self.reindex(audit)?;
 
         let vr = self.verify();
         if vr.len() == 0 {
@@ -580,12 +2285,16 @@ impl BackendWriteTransaction {
         } else {
             Err(OperationError::ConsistencyError(vr))
         }
-        // TODO #8: run re-index after db is restored
     }
 
     pub fn commit(mut self) -> Result<(), OperationError> {
         debug!("Commiting BE txn");
         assert!(!self.committed);
+        let mut au = AuditScope::new("be_commit");
+        if let Err(e) = self.flush_idls(&mut au) {
+            println!("{}", au);
+            return Err(e);
+        }
         self.committed = true;
         self.conn
             .execute("COMMIT TRANSACTION", NO_PARAMS)
@@ -612,15 +2321,22 @@ impl BackendWriteTransaction {
         }
     }
 
-    pub fn setup(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    pub fn setup(&self, audit: &mut AuditScope, wal: bool) -> Result<(), OperationError> {
         {
-            // Enable WAL mode, which is just faster and better.
+            // Enable WAL mode, which is just faster and better. It's only
+            // turned off for filesystems that can't share memory between
+            // processes.
             //
             // We have to use stmt + prepare because execute can't handle
             // the "wal" row on result when this works!
+            let journal_mode = if wal {
+                "PRAGMA journal_mode=WAL;"
+            } else {
+                "PRAGMA journal_mode=DELETE;"
+            };
             let mut wal_stmt = try_audit!(
                 audit,
-                self.conn.prepare("PRAGMA journal_mode=WAL;"),
+                self.conn.prepare(journal_mode),
                 "sqlite error {:?}",
                 OperationError::SQLiteError
             );
@@ -692,10 +2408,252 @@ impl BackendWriteTransaction {
                 OperationError::SQLiteError
             );
 
-            // NOTE: Indexing is configured in a different step!
-            // Indexing uses a db version flag to represent the version
-            // of the indexes representation on disk in case we change
-            // it.
+            // The changelog has its own version, so it can change shape
+            // without touching id2entry.
+            let mut dbv_changelog = self.get_db_version_key(DBV_CHANGELOG);
+            audit_log!(audit, "dbv_changelog initial == {}", dbv_changelog);
+
+            if dbv_changelog == 0 {
+                // AUTOINCREMENT so that a seq is never reused, even once rows
+                // are removed - a consumer's token must never skip changes.
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        "CREATE TABLE IF NOT EXISTS changelog (
+                            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                            uuid TEXT NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_changelog = 1;
+                audit_log!(audit, "dbv_changelog migrated -> {}", dbv_changelog);
+            }
+            if dbv_changelog == 1 {
+                // A single row, holding the changelog position recovery
+                // starts from.
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        "CREATE TABLE IF NOT EXISTS recovery (
+                            id INTEGER PRIMARY KEY,
+                            seq INTEGER NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_changelog = 2;
+                audit_log!(audit, "dbv_changelog migrated -> {}", dbv_changelog);
+            }
+            if dbv_changelog == 2 {
+                // What each change was, when, and who made it. Earlier
+                // changes have no csn, so are the first to be trimmed.
+                try_audit!(
+                    audit,
+                    self.conn.execute_batch(
+                        "ALTER TABLE changelog ADD COLUMN op TEXT;
+                        ALTER TABLE changelog ADD COLUMN csn INTEGER NOT NULL DEFAULT 0;
+                        ALTER TABLE changelog ADD COLUMN initiator TEXT;
+                        CREATE INDEX IF NOT EXISTS changelog_csn_idx ON changelog (csn);
+                        CREATE TABLE IF NOT EXISTS changelog_trim (
+                            id INTEGER PRIMARY KEY,
+                            seq INTEGER NOT NULL,
+                            csn INTEGER NOT NULL
+                        );
+                        ",
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_changelog = 3;
+                audit_log!(audit, "dbv_changelog migrated -> {}", dbv_changelog);
+            }
+            if dbv_changelog == 3 {
+                // Which replica made each change, and this one's id, which
+                // its changes are made with from now on.
+                try_audit!(
+                    audit,
+                    self.conn.execute_batch(
+                        "ALTER TABLE changelog ADD COLUMN rid TEXT;
+                        CREATE TABLE IF NOT EXISTS replica (
+                            id INTEGER PRIMARY KEY,
+                            rid TEXT NOT NULL
+                        );
+                        ",
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                try_audit!(
+                    audit,
+                    self.conn.execute_named(
+                        "INSERT INTO replica (id, rid) VALUES (0, :rid)",
+                        &[(":rid", &Uuid::new_v4().to_string())],
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_changelog = 4;
+                audit_log!(audit, "dbv_changelog migrated -> {}", dbv_changelog);
+            }
+
+            try_audit!(
+                audit,
+                self.conn.execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_changelog)",
+                    &[(":id", &DBV_CHANGELOG), (":dbv_changelog", &dbv_changelog)],
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+
+            // Entries that can't be deserialised are moved here, keeping
+            // the raw bytes and why they failed.
+            let mut dbv_quarantine = self.get_db_version_key(DBV_QUARANTINE);
+            audit_log!(audit, "dbv_quarantine initial == {}", dbv_quarantine);
+
+            if dbv_quarantine == 0 {
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        "CREATE TABLE IF NOT EXISTS quarantine (
+                            id INTEGER PRIMARY KEY ASC,
+                            data BLOB NOT NULL,
+                            reason TEXT NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_quarantine = 1;
+                audit_log!(audit, "dbv_quarantine migrated -> {}", dbv_quarantine);
+            }
+
+            try_audit!(
+                audit,
+                self.conn.execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_quarantine)",
+                    &[
+                        (":id", &DBV_QUARANTINE),
+                        (":dbv_quarantine", &dbv_quarantine)
+                    ],
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+
+            // What is indexed is set by the query server from schema, with
+            // update_idxmeta. Here we only make sure there's somewhere to
+            // keep it, and the indexes themselves.
+            let mut dbv_index = self.get_db_version_key(DBV_INDEX);
+            audit_log!(audit, "dbv_index initial == {}", dbv_index);
+
+            if dbv_index == 0 {
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        "CREATE TABLE IF NOT EXISTS idxmeta (
+                            attr TEXT NOT NULL,
+                            itype TEXT NOT NULL,
+                            PRIMARY KEY (attr, itype)
+                        )
+                        ",
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                // idl is the cbor of the ids holding the key, as a DbIdl.
+                try_audit!(
+                    audit,
+                    self.conn.execute(
+                        "CREATE TABLE IF NOT EXISTS idx (
+                            attr TEXT NOT NULL,
+                            itype TEXT NOT NULL,
+                            key TEXT NOT NULL,
+                            idl BLOB NOT NULL,
+                            PRIMARY KEY (attr, itype, key)
+                        )
+                        ",
+                        NO_PARAMS,
+                    ),
+                    "sqlite error {:?}",
+                    OperationError::SQLiteError
+                );
+                dbv_index = 1;
+                audit_log!(audit, "dbv_index migrated -> {}", dbv_index);
+            }
+            // Id lists were a cbor set of every id. They're now kept as runs.
+            if dbv_index == 1 {
+                let rows: Vec<(String, String, String, Vec<u8>)> = {
+                    let mut stmt = try_audit!(
+                        audit,
+                        self.conn.prepare("SELECT attr, itype, key, idl FROM idx"),
+                        "sqlite error {:?}",
+                        OperationError::SQLiteError
+                    );
+                    let rows = try_audit!(
+                        audit,
+                        stmt.query_map(NO_PARAMS, |row| -> (String, String, String, Vec<u8>) {
+                            (row.get(0), row.get(1), row.get(2), row.get(3))
+                        }),
+                        "sqlite error {:?}",
+                        OperationError::SQLiteError
+                    );
+                    let rows: Result<Vec<_>, _> = rows.collect();
+                    try_audit!(
+                        audit,
+                        rows,
+                        "sqlite error {:?}",
+                        OperationError::SQLiteError
+                    )
+                };
+                for (attr, itype, key, data) in rows {
+                    let ids: BTreeSet<u64> = try_audit!(
+                        audit,
+                        serde_cbor::from_slice(data.as_slice()),
+                        "Invalid idl {:?}",
+                        OperationError::CorruptedIndex(attr)
+                    );
+                    let data = serde_cbor::to_vec(&DbIdl::new(&ids))
+                        .map_err(|_| OperationError::SerdeCborError)?;
+                    try_audit!(
+                        audit,
+                        self.conn.execute_named(
+                            "UPDATE idx SET idl = :idl WHERE attr = :attr AND itype = :itype AND key = :key",
+                            &[
+                                (":idl", &data as &dyn ToSql),
+                                (":attr", &attr as &dyn ToSql),
+                                (":itype", &itype as &dyn ToSql),
+                                (":key", &key as &dyn ToSql),
+                            ],
+                        ),
+                        "sqlite error {:?}",
+                        OperationError::SQLiteError
+                    );
+                }
+                dbv_index = 2;
+                audit_log!(audit, "dbv_index migrated -> {}", dbv_index);
+            }
+
+            try_audit!(
+                audit,
+                self.conn.execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_index)",
+                    &[(":id", &DBV_INDEX), (":dbv_index", &dbv_index)],
+                ),
+                "sqlite error {:?}",
+                OperationError::SQLiteError
+            );
+
             Ok(())
         }
     }
@@ -704,6 +2662,78 @@ impl BackendWriteTransaction {
 // In the future this will do the routing between the chosen backends etc.
 impl Backend {
     pub fn new(audit: &mut AuditScope, path: &str, pool_size: u32) -> Result<Self, OperationError> {
+        Self::new_inner(audit, path, pool_size, None, 0, DbDurability::new())
+    }
+
+    // As new, but sqlite may memory map up to mmap_size bytes of the
+    // database for reads. 0 leaves mapping off.
+    pub fn new_mmap(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        mmap_size: u64,
+        durability: DbDurability,
+    ) -> Result<Self, OperationError> {
+        Self::new_inner(audit, path, pool_size, None, mmap_size, durability)
+    }
+
+    // Open a database that is encrypted at rest. A new database is created
+    // encrypted with this key.
+    pub fn new_encrypted(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        provider: &dyn DbKeyProvider,
+        durability: DbDurability,
+    ) -> Result<Self, OperationError> {
+        if !cfg!(feature = "sqlcipher") {
+            audit_log!(
+                audit,
+                "A db key was given, but sqlcipher support is not built in"
+            );
+            return Err(OperationError::InvalidDbKey(
+                "sqlcipher support is not built in",
+            ));
+        }
+        let key = provider.get_key(audit)?;
+        Self::check_key(audit, path, &key)?;
+        Self::new_inner(audit, path, pool_size, Some(key), 0, durability)
+    }
+
+    // sqlcipher only notices a wrong key when it first reads a page, and the
+    // pool would keep retrying the connection until it times out. Check it up
+    // front, so a bad key is a clear error.
+    fn check_key(audit: &mut AuditScope, path: &str, key: &DbKey) -> Result<(), OperationError> {
+        let conn = try_audit!(
+            audit,
+            Connection::open(path),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        try_audit!(
+            audit,
+            key.apply(&conn),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        conn.query_row("SELECT count(*) FROM sqlite_master", NO_PARAMS, |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|_| ())
+        .map_err(|e| {
+            audit_log!(audit, "Unable to decrypt database: {:?}", e);
+            OperationError::InvalidDbKey("unable to decrypt database")
+        })
+    }
+
+    fn new_inner(
+        audit: &mut AuditScope,
+        path: &str,
+        pool_size: u32,
+        key: Option<DbKey>,
+        mmap_size: u64,
+        durability: DbDurability,
+    ) -> Result<Self, OperationError> {
         // this has a ::memory() type, but will path == "" work?
         audit_segment!(audit, || {
             let manager = SqliteConnectionManager::file(path);
@@ -715,31 +2745,95 @@ impl Backend {
             } else {
                 builder1.max_size(pool_size)
             };
+            let builder2 = match key {
+                Some(key) => builder2.connection_customizer(Box::new(DbKeyCustomizer::new(key))),
+                // sqlcipher can't map pages it has to decrypt, so mapping is
+                // only for plain databases.
+                None if mmap_size > 0 => {
+                    builder2.connection_customizer(Box::new(DbMmapCustomizer { mmap_size }))
+                }
+                None => builder2,
+            };
             // Look at max_size and thread_pool here for perf later
             let pool = builder2.build(manager).expect("Failed to create pool");
-            let be = Backend { pool: pool };
+            let wal = durability.wal;
+            let mut be = Backend {
+                pool: pool,
+                path: path.to_string(),
+                durability: durability,
+                relaxed: Arc::new(AtomicBool::new(false)),
+                parallel_min: None,
+                idl_max: IDL_ALLIDS_THRESHOLD,
+                rid: Uuid::nil(),
+            };
 
             // Now complete our setup with a txn
             let r = {
                 let be_txn = be.write();
-                be_txn.setup(audit).and_then(|_| be_txn.commit())
+                be_txn
+                    .setup(audit, wal)
+                    .and_then(|_| be_txn.quarantine_damaged(audit))
+                    .and_then(|moved| {
+                        if moved.len() > 0 {
+                            audit_log!(audit, "Quarantined damaged entries: {:?}", moved);
+                        }
+                        be_txn.commit()
+                    })
             };
+            // The write's connection is only given back once it's dropped.
+            let r = r.and_then(|_| be.read().replica_id(audit));
 
             audit_log!(audit, "be new setup: {:?}", r);
 
             match r {
-                Ok(_) => Ok(be),
+                Ok(rid) => {
+                    be.rid = rid;
+                    Ok(be)
+                }
                 Err(e) => Err(e),
             }
         })
     }
 
-    pub fn read(&self) -> BackendReadTransaction {
-        let conn = self
-            .pool
-            .get()
-            .expect("Unable to get connection from pool!!!");
-        BackendReadTransaction::new(conn)
+    pub fn read(&self) -> BackendReadTransaction {
+        let conn = self
+            .pool
+            .get()
+            .expect("Unable to get connection from pool!!!");
+        BackendReadTransaction::new(conn, self.parallel_min, self.idl_max)
+    }
+
+    pub fn replica_id(&self) -> Uuid {
+        self.rid
+    }
+
+    // Give this replica another id, so tests can choose which of two wins
+    // a tie.
+    #[cfg(test)]
+    pub fn set_replica_id(&mut self, rid: Uuid) {
+        self.rid = rid;
+    }
+
+    // Searches that scan at least this many entries without the indexes
+    // narrowing them test them against the filter on every core, this many
+    // at a time. None keeps every search on one thread.
+    pub fn set_parallel_search_min(&mut self, min: Option<usize>) {
+        self.parallel_min = min;
+    }
+
+    // An index key holding more than this many ids doesn't narrow a search:
+    // its entries are tested like any other, instead of its list being
+    // loaded into memory.
+    pub fn set_idl_allids_threshold(&mut self, max: usize) {
+        self.idl_max = max;
+    }
+
+    fn synchronous(&self) -> DbSync {
+        if self.relaxed.load(Ordering::Acquire) {
+            DbSync::Off
+        } else {
+            self.durability.synchronous
+        }
     }
 
     pub fn write(&self) -> BackendWriteTransaction {
@@ -747,7 +2841,109 @@ impl Backend {
             .pool
             .get()
             .expect("Unable to get connection from pool!!!");
-        BackendWriteTransaction::new(conn)
+        // Pooled connections keep whatever the last writer set, and this
+        // can't be changed inside a transaction, so set it every time.
+        conn.execute_batch(sync_pragma(self.synchronous()))
+            .expect("Unable to set synchronous!");
+        BackendWriteTransaction::new(conn, self.parallel_min, self.idl_max)
+    }
+
+    // Stop syncing commits to disk, for a bulk import. A crash before
+    // restore_durability can lose or corrupt anything written meanwhile.
+    pub fn relax_durability(&self) {
+        self.relaxed.store(true, Ordering::Release);
+    }
+
+    // Go back to the configured syncing, and flush everything written while
+    // it was relaxed.
+    pub fn restore_durability(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.relaxed.store(false, Ordering::Release);
+        self.checkpoint(audit, true)?;
+        if self.path == "" {
+            return Ok(());
+        }
+        let f = try_audit!(
+            audit,
+            fs::File::open(self.path.as_str()),
+            "io error {:?}",
+            OperationError::FsError
+        );
+        try_audit!(
+            audit,
+            f.sync_all(),
+            "io error {:?}",
+            OperationError::FsError
+        );
+        Ok(())
+    }
+
+    // Copy the write-ahead log into the database, which syncs both. truncate
+    // also empties the log, waiting for readers to finish with it.
+    pub fn checkpoint(&self, audit: &mut AuditScope, truncate: bool) -> Result<(), OperationError> {
+        let conn = try_audit!(
+            audit,
+            self.pool.get(),
+            "pool error {:?}",
+            OperationError::BackendEngine
+        );
+        try_audit!(
+            audit,
+            conn.execute_batch(sync_pragma(self.synchronous())),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        let mode = if truncate {
+            "PRAGMA wal_checkpoint(TRUNCATE)"
+        } else {
+            "PRAGMA wal_checkpoint(PASSIVE)"
+        };
+        // (busy, log pages, pages checkpointed). Without WAL this is a no-op.
+        let r = try_audit!(
+            audit,
+            conn.query_row(mode, NO_PARAMS, |row| (
+                row.get::<_, i64>(0),
+                row.get::<_, i64>(2)
+            )),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        audit_log!(audit, "checkpoint (busy, pages) -> {:?}", r);
+        Ok(())
+    }
+
+    // Write every entry to a file, from a single read transaction. Writers
+    // aren't held up, so this is safe to run against a live server.
+    pub fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
+        self.read().backup(audit, dst_path)
+    }
+
+    // Replace the content of the database with a backup. Nothing is changed
+    // unless all of it loads and verifies.
+    pub fn restore(&self, audit: &mut AuditScope, src_path: &str) -> Result<(), OperationError> {
+        let be_txn = self.write();
+        be_txn
+            .restore(audit, src_path)
+            .and_then(|_| be_txn.commit())
+    }
+
+    // Re-encrypt the database with a new key. Other pooled connections still
+    // hold the old key after this, so it must only be run while the server
+    // is offline, and the backend dropped afterwards.
+    pub fn rekey(&self, audit: &mut AuditScope, new_key: &DbKey) -> Result<(), OperationError> {
+        let conn = try_audit!(
+            audit,
+            self.pool.get(),
+            "pool error {:?}",
+            OperationError::BackendEngine
+        );
+        try_audit!(
+            audit,
+            new_key.rekey(&conn),
+            "sqlite error {:?}",
+            OperationError::SQLiteError
+        );
+        audit_log!(audit, "Database rekeyed");
+        Ok(())
     }
 }
 
@@ -756,20 +2952,54 @@ impl Clone for Backend {
         // Make another Be and close the pool.
         Backend {
             pool: self.pool.clone(),
+            path: self.path.clone(),
+            durability: self.durability.clone(),
+            relaxed: self.relaxed.clone(),
+            parallel_min: self.parallel_min,
+            idl_max: self.idl_max,
+            rid: self.rid,
         }
     }
 }
 
 // What are the possible actions we'll recieve here?
 
+// Files written by tests go to the temp dir, not the tree the tests run from.
+#[cfg(test)]
+pub(crate) fn test_file_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("rsidm_{}_{}", std::process::id(), name))
+        .to_str()
+        .expect("Invalid temp path")
+        .to_string()
+}
+
 #[cfg(test)]
 mod tests {
 
+    use rusqlite::NO_PARAMS;
+    use std::collections::{BTreeMap, BTreeSet};
     use std::fs;
+    use std::time::Duration;
+    use uuid::Uuid;
 
     use super::super::audit::AuditScope;
+    use super::super::changes::ChangeOp;
+    use super::super::config::{DbDurability, DbSync};
+    use super::super::csn::Csn;
     use super::super::entry::{Entry, EntryInvalid, EntryNew};
-    use super::{Backend, BackendTransaction, BackendWriteTransaction, OperationError};
+    use super::super::schema::IndexType;
+    use super::dbentry::{DbBackup, DbEntry, DbEntryV1, DbEntryVers};
+    use super::idl::IDL;
+    use super::key::DbKey;
+    use super::{
+        test_file_path, Backend, BackendTransaction, BackendWriteTransaction, ChangeOrigin,
+        ConsistencyError, Filter, FilterValidResolved, OperationError,
+    };
+
+    fn test_origin() -> ChangeOrigin {
+        ChangeOrigin::internal(Csn::new(Duration::from_secs(1), Uuid::nil()))
+    }
 
     macro_rules! run_test {
         ($test_fn:expr) => {{
@@ -821,7 +3051,7 @@ mod tests {
         run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
             audit_log!(audit, "Simple Create");
 
-            let empty_result = be.create(audit, &Vec::new());
+            let empty_result = be.create(audit, &test_origin(), &Vec::new());
             audit_log!(audit, "{:?}", empty_result);
             assert_eq!(empty_result, Err(OperationError::EmptyRequest));
 
@@ -830,7 +3060,7 @@ mod tests {
             e.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
             let e = unsafe { e.to_valid_new() };
 
-            let single_result = be.create(audit, &vec![e.clone()]);
+            let single_result = be.create(audit, &test_origin(), &vec![e.clone()]);
 
             assert!(single_result.is_ok());
 
@@ -849,7 +3079,7 @@ mod tests {
             e.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
             let e = unsafe { e.to_valid_new() };
 
-            let single_result = be.create(audit, &vec![e.clone()]);
+            let single_result = be.create(audit, &test_origin(), &vec![e.clone()]);
             assert!(single_result.is_ok());
             // Test a simple EQ search
 
@@ -882,7 +3112,7 @@ mod tests {
             let ve1 = unsafe { e1.clone().to_valid_new() };
             let ve2 = unsafe { e2.clone().to_valid_new() };
 
-            assert!(be.create(audit, &vec![ve1, ve2]).is_ok());
+            assert!(be.create(audit, &test_origin(), &vec![ve1, ve2]).is_ok());
             assert!(entry_exists!(audit, be, e1));
             assert!(entry_exists!(audit, be, e2));
 
@@ -902,9 +3132,9 @@ mod tests {
             // This is now impossible due to the state machine design.
             // However, with some unsafe ....
             let ue1 = unsafe { e1.clone().to_valid_committed() };
-            assert!(be.modify(audit, &vec![ue1]).is_err());
+            assert!(be.modify(audit, &test_origin(), &vec![ue1]).is_err());
             // Modify none
-            assert!(be.modify(audit, &vec![]).is_err());
+            assert!(be.modify(audit, &test_origin(), &vec![]).is_err());
 
             // Make some changes to r1, r2.
             r1.add_ava("desc", "modified");
@@ -916,13 +3146,15 @@ mod tests {
             let vr2 = unsafe { r2.to_valid_committed() };
 
             // Modify single
-            assert!(be.modify(audit, &vec![vr1.clone()]).is_ok());
+            assert!(be.modify(audit, &test_origin(), &vec![vr1.clone()]).is_ok());
             // Assert no other changes
             assert!(entry_attr_pres!(audit, be, vr1, "desc"));
             assert!(!entry_attr_pres!(audit, be, vr2, "desc"));
 
             // Modify both
-            assert!(be.modify(audit, &vec![vr1.clone(), vr2.clone()]).is_ok());
+            assert!(be
+                .modify(audit, &test_origin(), &vec![vr1.clone(), vr2.clone()])
+                .is_ok());
 
             assert!(entry_attr_pres!(audit, be, vr1, "desc"));
             assert!(entry_attr_pres!(audit, be, vr2, "desc"));
@@ -951,7 +3183,9 @@ mod tests {
             let ve2 = unsafe { e2.clone().to_valid_new() };
             let ve3 = unsafe { e3.clone().to_valid_new() };
 
-            assert!(be.create(audit, &vec![ve1, ve2, ve3]).is_ok());
+            assert!(be
+                .create(audit, &test_origin(), &vec![ve1, ve2, ve3])
+                .is_ok());
             assert!(entry_exists!(audit, be, e1));
             assert!(entry_exists!(audit, be, e2));
             assert!(entry_exists!(audit, be, e3));
@@ -967,11 +3201,11 @@ mod tests {
             let r3 = results.remove(0);
 
             // Delete one
-            assert!(be.delete(audit, &vec![r1.clone()]).is_ok());
+            assert!(be.delete(audit, &test_origin(), &vec![r1.clone()]).is_ok());
             assert!(!entry_exists!(audit, be, r1));
 
             // delete none (no match filter)
-            assert!(be.delete(audit, &vec![]).is_err());
+            assert!(be.delete(audit, &test_origin(), &vec![]).is_err());
 
             // Delete with no id
             // WARNING: Normally, this isn't possible, but we are pursposefully breaking
@@ -982,24 +3216,265 @@ mod tests {
 
             let ve4 = unsafe { e4.clone().to_valid_committed() };
 
-            assert!(be.delete(audit, &vec![ve4]).is_err());
+            assert!(be.delete(audit, &test_origin(), &vec![ve4]).is_err());
 
             assert!(entry_exists!(audit, be, r2));
             assert!(entry_exists!(audit, be, r3));
 
             // delete batch
-            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
+            assert!(be
+                .delete(audit, &test_origin(), &vec![r2.clone(), r3.clone()])
+                .is_ok());
 
             assert!(!entry_exists!(audit, be, r2));
             assert!(!entry_exists!(audit, be, r3));
 
             // delete none (no entries left)
             // see fn delete for why this is ok, not err
-            assert!(be.delete(audit, &vec![r2.clone(), r3.clone()]).is_ok());
+            assert!(be
+                .delete(audit, &test_origin(), &vec![r2.clone(), r3.clone()])
+                .is_ok());
+        });
+    }
+
+    #[test]
+    fn test_changelog() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            // Nothing has happened yet.
+            assert!(be.changelog_max_seq(audit) == Ok(0));
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", "alice");
+            e2.add_ava("uuid", "4b6228ab-1dbe-42a4-a9f5-f6368222438e");
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve1, ve2]).is_ok());
+
+            let (changed, seq) = be.changelog_since(audit, 0).expect("changelog failed");
+            assert!(changed.len() == 2);
+            assert!(be.changelog_max_seq(audit) == Ok(seq));
+
+            // No changes since the latest seq.
+            let (changed, seq_b) = be.changelog_since(audit, seq).expect("changelog failed");
+            assert!(changed.is_empty());
+            assert!(seq_b == seq);
+
+            let mut results = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search");
+            let r1 = results.remove(0);
+            let r2 = results.remove(0);
+
+            let mut m1 = r1.invalidate();
+            m1.add_ava("desc", "modified");
+            let vm1 = unsafe { m1.to_valid_committed() };
+            assert!(be.modify(audit, &test_origin(), &vec![vm1]).is_ok());
+            assert!(be.delete(audit, &test_origin(), &vec![r2.clone()]).is_ok());
+
+            // Only the modified and deleted entries, each once.
+            let (changed, seq_c) = be.changelog_since(audit, seq).expect("changelog failed");
+            assert!(changed.len() == 2);
+            assert!(changed.contains("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            assert!(changed.contains("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            assert!(seq_c > seq);
+        });
+    }
+
+    #[test]
+    fn test_changelog_csn_trim() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+
+            let c1 = Csn::new(Duration::new(10, 5), Uuid::nil());
+            let c2 = Csn::new(Duration::new(20, 0), Uuid::new_v4());
+            let admin = ChangeOrigin {
+                csn: c2,
+                initiator: Some("00000000-0000-0000-0000-000000000000".to_string()),
+            };
+            assert!(be
+                .create(audit, &ChangeOrigin::internal(c1), &vec![ve1])
+                .is_ok());
+            let r1 = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Failed to search")
+                .remove(0);
+            assert!(be.modify(audit, &admin, &vec![r1]).is_ok());
+
+            let changes = be
+                .changelog_entries_since(audit, 0)
+                .expect("changelog failed");
+            assert!(changes.len() == 2);
+            assert!(changes[0].op == ChangeOp::Create);
+            assert!(changes[0].origin == ChangeOrigin::internal(c1));
+            assert!(changes[1].op == ChangeOp::Modify);
+            assert!(changes[1].origin == admin);
+            assert!(changes[0].seq < changes[1].seq);
+            assert!(be.changelog_max_seq(audit) == Ok(changes[1].seq));
+
+            // Strictly after the position.
+            let changes = be
+                .changelog_entries_since(audit, changes[0].seq)
+                .expect("changelog failed");
+            assert!(changes.len() == 1 && changes[0].origin.csn == c2);
+
+            // Nothing is trimmed past the recovery point.
+            assert!(be.changelog_trim(audit, &c2) == Ok(0));
+            let seq = be.changelog_max_seq(audit).expect("changelog failed");
+            assert!(be.set_recovery_seq(audit, seq).is_ok());
+
+            assert!(be.changelog_trim(audit, &c2) == Ok(1));
+            assert!(be.changelog_trim(audit, &c2) == Ok(0));
+            // What was trimmed can't be asked for, but what remains can.
+            assert!(be.changelog_entries_since(audit, 0) == Err(OperationError::ChangelogTrimmed));
+            assert!(be.changelog_since(audit, 0) == Err(OperationError::ChangelogTrimmed));
+            let changes = be
+                .changelog_entries_since(audit, seq - 1)
+                .expect("changelog failed");
+            assert!(changes.len() == 1 && changes[0].op == ChangeOp::Modify);
+            let (changed, _) = be
+                .changelog_since(audit, seq - 1)
+                .expect("changelog failed");
+            assert!(changed.len() == 1);
+        });
+    }
+
+    #[test]
+    fn test_quarantine_damaged() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve1]).is_ok());
+
+            // Damage the store behind the backend's back.
+            let garbage: Vec<u8> = vec![0xff, 0x00, 0x13, 0x37];
+            assert!(be
+                .conn
+                .execute_named(
+                    "INSERT INTO id2entry (id, data) VALUES(:id, :data)",
+                    &[(":id", &100), (":data", &garbage)],
+                )
+                .is_ok());
+
+            // The damaged entry doesn't stop us searching the rest.
+            let filt = unsafe { filter_resolved!(f_pres("userid")) };
+            let results = be.search(audit, &filt).expect("Failed to search");
+            assert!(results.len() == 1);
+
+            assert!(be.quarantine_list(audit) == Ok(Vec::new()));
+            assert!(be.quarantine_damaged(audit) == Ok(vec![100]));
+            // Nothing more to move.
+            assert!(be.quarantine_damaged(audit) == Ok(Vec::new()));
+
+            let list = be.quarantine_list(audit).expect("Failed to list");
+            assert!(list.len() == 1);
+            assert!(list[0] == (100, "SerdeCborError".to_string()));
+            assert!(be.quarantine_get(audit, 100) == Ok(garbage));
+            assert!(be.quarantine_get(audit, 101) == Err(OperationError::NoMatchingEntries));
+
+            assert!(be.verify() == vec![Err(ConsistencyError::EntryQuarantined(100))]);
+
+            let results = be.search(audit, &filt).expect("Failed to search");
+            assert!(results.len() == 1);
+        });
+    }
+
+    #[test]
+    fn test_rewrite_entries() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            assert!(be.get_data_version() == 0);
+            assert!(be.set_data_version(audit, 3).is_ok());
+            assert!(be.get_data_version() == 3);
+
+            // An entry as it was stored before values were typed.
+            let mut attrs = BTreeMap::new();
+            attrs.insert("userid".to_string(), vec!["william".to_string()]);
+            attrs.insert(
+                "uuid".to_string(),
+                vec!["db237e8a-0079-4b8c-8a56-593b22aa44d1".to_string()],
+            );
+            let v1 = serde_cbor::to_vec(&DbEntry {
+                ent: DbEntryVers::V1(DbEntryV1 { attrs: attrs }),
+            })
+            .expect("Serialise failed!");
+            let garbage: Vec<u8> = vec![0xff, 0x00, 0x13, 0x37];
+            assert!(be
+                .conn
+                .execute_named(
+                    "INSERT INTO id2entry (id, data) VALUES(:id, :data)",
+                    &[(":id", &1), (":data", &v1)],
+                )
+                .is_ok());
+            assert!(be
+                .conn
+                .execute_named(
+                    "INSERT INTO id2entry (id, data) VALUES(:id, :data)",
+                    &[(":id", &2), (":data", &garbage)],
+                )
+                .is_ok());
+
+            // Only the entry that can be read is written back.
+            assert!(be.rewrite_entries(audit, |e| e) == Ok(1));
+            let data: Vec<u8> = be
+                .conn
+                .query_row("SELECT data FROM id2entry WHERE id = 1", NO_PARAMS, |row| {
+                    row.get(0)
+                })
+                .expect("Read failed!");
+            let dbe: DbEntry = serde_cbor::from_slice(data.as_slice()).expect("Parse failed!");
+            match dbe.ent {
+                DbEntryVers::V3(_) => {}
+                _ => panic!("Not rewritten!"),
+            }
+
+            let filt = unsafe { filter_resolved!(f_eq("userid", "william")) };
+            assert!(be.search(audit, &filt).map(|r| r.len()) == Ok(1));
         });
     }
 
-    pub static DB_BACKUP_FILE_NAME: &'static str = "./.backup_test.db";
+    #[test]
+    fn test_be_parallel_search() {
+        let mut audit = AuditScope::new("run_test");
+        let mut be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        be.set_parallel_search_min(Some(2));
+        let be = be.write();
+
+        let entries: Vec<_> = (0..5)
+            .map(|i| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("userid", format!("user{}", i).as_str());
+                e.add_ava(
+                    "uuid",
+                    format!("db237e8a-0079-4b8c-8a56-593b22aa44d{}", i).as_str(),
+                );
+                if i % 2 == 0 {
+                    e.add_ava("description", "even");
+                }
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        assert!(be.create(&mut audit, &test_origin(), &entries).is_ok());
+
+        // Not indexed, so every entry is read and tested against the filter.
+        let filt = unsafe { filter_resolved!(f_eq("description", "even")) };
+        let results = be.search(&mut audit, &filt).expect("Failed to search");
+        let names: Vec<_> = results
+            .iter()
+            .filter_map(|e| e.get_ava_single("userid"))
+            .filter_map(|v| v.to_str())
+            .collect();
+        assert!(names == vec!["user0", "user2", "user4"]);
+        assert!(be.commit().is_ok());
+    }
 
     #[test]
     fn test_backup_restore() {
@@ -1021,12 +3496,15 @@ mod tests {
             let ve2 = unsafe { e2.clone().to_valid_new() };
             let ve3 = unsafe { e3.clone().to_valid_new() };
 
-            assert!(be.create(audit, &vec![ve1, ve2, ve3]).is_ok());
+            assert!(be
+                .create(audit, &test_origin(), &vec![ve1, ve2, ve3])
+                .is_ok());
             assert!(entry_exists!(audit, be, e1));
             assert!(entry_exists!(audit, be, e2));
             assert!(entry_exists!(audit, be, e3));
 
-            let result = fs::remove_file(DB_BACKUP_FILE_NAME);
+            let path = test_file_path("backup_test.db");
+            let result = fs::remove_file(&path);
 
             match result {
                 Err(e) => {
@@ -1040,10 +3518,499 @@ mod tests {
                 _ => (),
             }
 
-            be.backup(audit, DB_BACKUP_FILE_NAME)
-                .expect("Backup failed!");
-            be.restore(audit, DB_BACKUP_FILE_NAME)
-                .expect("Restore failed!");
+            be.backup(audit, path.as_str()).expect("Backup failed!");
+            be.restore(audit, path.as_str()).expect("Restore failed!");
+            let _ = fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn test_backup_versions() {
+        let mut audit = AuditScope::new("test_backup_versions");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        let idxmeta: BTreeSet<(String, IndexType)> =
+            vec![("userid".to_string(), IndexType::EQUALITY)]
+                .into_iter()
+                .collect();
+
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("userid", "william");
+        e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+        {
+            let be_txn = be.write();
+            assert!(be_txn.update_idxmeta(&mut audit, idxmeta.clone()) == Ok(true));
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            assert!(be_txn
+                .create(&mut audit, &test_origin(), &vec![ve1])
+                .is_ok());
+            assert!(be_txn.commit().is_ok());
+        }
+
+        // The backup records its version and what was indexed.
+        let path = test_file_path("backup_versions_test.db");
+        be.backup(&mut audit, path.as_str()).expect("Backup failed!");
+        let content = fs::read_to_string(path.as_str()).expect("Read failed!");
+        let entries = match serde_json::from_str::<DbBackup>(&content).expect("Not versioned!") {
+            DbBackup::V1(b) => {
+                assert!(b.idxmeta == vec![("userid".to_string(), "EQUALITY".to_string())]);
+                assert!(b.entries.len() == 1);
+                b.entries
+            }
+        };
+
+        // So a restore brings the index configuration back too.
+        {
+            let be_txn = be.write();
+            assert!(be_txn.update_idxmeta(&mut audit, BTreeSet::new()) == Ok(true));
+            assert!(be_txn.commit().is_ok());
+        }
+        be.restore(&mut audit, path.as_str()).expect("Restore failed!");
+        assert!(be.read().get_idxmeta(&mut audit) == Ok(idxmeta));
+
+        // Backups from before versioning are still accepted.
+        fs::write(
+            path.as_str(),
+            serde_json::to_string(&entries).expect("Serialise failed!"),
+        )
+        .expect("Write failed!");
+        be.restore(&mut audit, path.as_str()).expect("Restore failed!");
+        {
+            let be_txn = be.write();
+            assert!(entry_exists!(&mut audit, be_txn, e1));
+        }
+
+        // A file that isn't a backup changes nothing.
+        fs::write(path.as_str(), "{}").expect("Write failed!");
+        assert!(be.restore(&mut audit, path.as_str()).is_err());
+        {
+            let be_txn = be.write();
+            assert!(entry_exists!(&mut audit, be_txn, e1));
+        }
+        let _ = fs::remove_file(&path);
+        println!("{}", audit);
+    }
+
+    #[test]
+    fn test_index_deferred() {
+        let mut audit = AuditScope::new("test_index_deferred");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        let idxmeta: BTreeSet<(String, IndexType)> =
+            vec![("userid".to_string(), IndexType::EQUALITY)]
+                .into_iter()
+                .collect();
+        let stored_keys = |be_txn: &BackendWriteTransaction| -> i64 {
+            be_txn
+                .conn
+                .query_row("SELECT COUNT(*) FROM idx", NO_PARAMS, |row| row.get(0))
+                .expect("Failed to count index keys")
+        };
+
+        {
+            let be_txn = be.write();
+            assert!(be_txn.update_idxmeta(&mut audit, idxmeta.clone()) == Ok(true));
+            let entries: Vec<_> = (0..10)
+                .map(|i| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("userid", &format!("user{}", i % 2));
+                    e.add_ava("uuid", &format!("{:08x}-0079-4b8c-8a56-593b22aa44d1", i));
+                    unsafe { e.to_valid_new() }
+                })
+                .collect();
+            assert!(be_txn.create(&mut audit, &test_origin(), &entries).is_ok());
+
+            // Nothing reaches the idx table before commit, but this txn's
+            // searches already see the new ids.
+            assert!(stored_keys(&be_txn) == 0);
+            let filt = unsafe { filter_resolved!(f_eq("userid", "user0")) };
+            assert!(
+                be_txn.filter2idl(&mut audit, filt.to_inner(), &idxmeta)
+                    == Ok(IDL::Indexed(vec![1, 3, 5, 7, 9].into_iter().collect()))
+            );
+            assert!(be_txn.commit().is_ok());
+        }
+
+        // Each key was written once, and matches the entries.
+        let be_txn = be.write();
+        assert!(stored_keys(&be_txn) == 2);
+        assert!(be_txn.verify_indexes(&mut audit).iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_index_allids_threshold() {
+        let mut audit = AuditScope::new("test_index_allids_threshold");
+        let mut be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        be.set_idl_allids_threshold(2);
+        let idxmeta: BTreeSet<(String, IndexType)> =
+            vec![("userid".to_string(), IndexType::EQUALITY)]
+                .into_iter()
+                .collect();
+
+        let be_txn = be.write();
+        assert!(be_txn.update_idxmeta(&mut audit, idxmeta.clone()) == Ok(true));
+        let entries: Vec<_> = ["common", "common", "common", "rare"]
+            .iter()
+            .enumerate()
+            .map(|(i, userid)| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("userid", userid);
+                e.add_ava("uuid", &format!("{:08x}-0079-4b8c-8a56-593b22aa44d1", i));
+                unsafe { e.to_valid_new() }
+            })
+            .collect();
+        assert!(be_txn.create(&mut audit, &test_origin(), &entries).is_ok());
+        assert!(be_txn.commit().is_ok());
+
+        let be_txn = be.read();
+        let idl = |audit: &mut AuditScope, filt: Filter<FilterValidResolved>| {
+            be_txn
+                .filter2idl(audit, filt.to_inner(), &idxmeta)
+                .expect("filter2idl failed")
+        };
+        // Too common to load, so every entry is tested instead.
+        assert!(
+            idl(&mut audit, unsafe {
+                filter_resolved!(f_eq("userid", "common"))
+            }) == IDL::ALLIDS
+        );
+        assert!(
+            idl(&mut audit, unsafe {
+                filter_resolved!(f_eq("userid", "rare"))
+            }) == IDL::Indexed(vec![4].into_iter().collect())
+        );
+        // The search still finds exactly the matches.
+        let filt = unsafe { filter_resolved!(f_eq("userid", "common")) };
+        assert!(be_txn.search(&mut audit, &filt).map(|r| r.len()) == Ok(3));
+    }
+
+    #[test]
+    fn test_index() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", "claire");
+            e2.add_ava("uuid", "4b6228ab-1dbe-42a4-a9f5-f6368222438e");
+
+            // Entries that exist before the index does are picked up by the
+            // reindex.
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve1]).is_ok());
+
+            let idxmeta: BTreeSet<(String, IndexType)> = vec![
+                ("userid".to_string(), IndexType::EQUALITY),
+                ("userid".to_string(), IndexType::PRESENCE),
+                ("userid".to_string(), IndexType::SUBSTRING),
+            ]
+            .into_iter()
+            .collect();
+            assert!(be.update_idxmeta(audit, idxmeta.clone()) == Ok(true));
+            assert!(be.update_idxmeta(audit, idxmeta.clone()) == Ok(false));
+            assert!(be.reindex(audit).is_ok());
+
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve2]).is_ok());
+
+            let idl = |audit: &mut AuditScope, filt: Filter<FilterValidResolved>| {
+                be.filter2idl(audit, filt.to_inner(), &idxmeta)
+                    .expect("filter2idl failed")
+            };
+            let ids = |v: &[u64]| v.iter().cloned().collect::<BTreeSet<u64>>();
+
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_eq("userid", "william"))
+                }) == IDL::Indexed(ids(&[1]))
+            );
+            assert!(
+                idl(audit, unsafe { filter_resolved!(f_pres("userid")) })
+                    == IDL::Indexed(ids(&[1, 2]))
+            );
+            assert!(
+                idl(audit, unsafe { filter_resolved!(f_sub("userid", "lai")) })
+                    == IDL::Partial(ids(&[2]))
+            );
+            // Too short to use the substring index.
+            assert!(idl(audit, unsafe { filter_resolved!(f_sub("userid", "li")) }) == IDL::ALLIDS);
+            // uuid isn't indexed, so the result needs testing.
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_and!([f_eq("userid", "william"), f_pres("uuid")]))
+                }) == IDL::Partial(ids(&[1]))
+            );
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_and!([
+                        f_pres("userid"),
+                        f_andnot(f_eq("userid", "claire"))
+                    ]))
+                }) == IDL::Indexed(ids(&[1]))
+            );
+
+            // The equality narrows to one entry, so it's resolved before the
+            // presence, and the unindexed uuid is tested last.
+            let opt = be
+                .filter_optimise(
+                    audit,
+                    unsafe {
+                        &filter_resolved!(f_and!([
+                            f_pres("uuid"),
+                            f_pres("userid"),
+                            f_eq("userid", "william")
+                        ]))
+                    },
+                    &idxmeta,
+                )
+                .expect("optimise failed");
+            assert!(
+                opt == unsafe {
+                    filter_resolved!(f_and!([
+                        f_eq("userid", "william"),
+                        f_pres("userid"),
+                        f_pres("uuid")
+                    ]))
+                }
+            );
+
+            // A partial result is still filtered down to the matches.
+            let r = be
+                .search(audit, unsafe { &filter_resolved!(f_sub("userid", "lli")) })
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+
+            // Modify moves the entry between keys.
+            let mut r1 = be
+                .search(audit, unsafe {
+                    &filter_resolved!(f_eq("userid", "william"))
+                })
+                .expect("Search failed!")
+                .remove(0)
+                .invalidate();
+            r1.purge_ava("userid");
+            r1.add_ava("userid", "wilfred");
+            let vr1 = unsafe { r1.to_valid_committed() };
+            assert!(be.modify(audit, &test_origin(), &vec![vr1.clone()]).is_ok());
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_eq("userid", "william"))
+                }) == IDL::Indexed(ids(&[]))
+            );
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_eq("userid", "wilfred"))
+                }) == IDL::Indexed(ids(&[1]))
+            );
+
+            // Delete removes it from all of them.
+            assert!(be.delete(audit, &test_origin(), &vec![vr1]).is_ok());
+            assert!(
+                idl(audit, unsafe { filter_resolved!(f_pres("userid")) })
+                    == IDL::Indexed(ids(&[2]))
+            );
+
+            // A rebuild gives the same result as keeping them up to date.
+            assert!(be.reindex(audit).is_ok());
+            assert!(
+                idl(audit, unsafe { filter_resolved!(f_pres("userid")) })
+                    == IDL::Indexed(ids(&[2]))
+            );
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_eq("userid", "wilfred"))
+                }) == IDL::Indexed(ids(&[]))
+            );
+        });
+    }
+
+    #[test]
+    fn test_reindex_uuids() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", "claire");
+            e2.add_ava("uuid", "4b6228ab-1dbe-42a4-a9f5-f6368222438e");
+
+            let idxmeta: BTreeSet<(String, IndexType)> =
+                vec![("userid".to_string(), IndexType::EQUALITY)]
+                    .into_iter()
+                    .collect();
+            assert!(be.update_idxmeta(audit, idxmeta.clone()) == Ok(true));
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve1, ve2]).is_ok());
+
+            assert!(be.recovery_seq(audit) == Ok(None));
+            assert!(be.set_recovery_seq(audit, 5).is_ok());
+            assert!(be.recovery_seq(audit) == Ok(Some(5)));
+
+            // Leave the indexes as if the entries had changed without them.
+            let key = |v: &str| ("userid".to_string(), IndexType::EQUALITY, v.to_string());
+            assert!(be.idl_update(audit, &key("william"), 1, false).is_ok());
+            assert!(be.idl_update(audit, &key("william"), 2, true).is_ok());
+            assert!(be.idl_update(audit, &key("claire"), 9, true).is_ok());
+
+            let uuids: BTreeSet<String> = vec![
+                "db237e8a-0079-4b8c-8a56-593b22aa44d1".to_string(),
+                "4b6228ab-1dbe-42a4-a9f5-f6368222438e".to_string(),
+            ]
+            .into_iter()
+            .collect();
+            assert!(be.reindex_uuids(audit, &uuids) == Ok(3));
+
+            let idl = |audit: &mut AuditScope, filt: Filter<FilterValidResolved>| {
+                be.filter2idl(audit, filt.to_inner(), &idxmeta)
+                    .expect("filter2idl failed")
+            };
+            let ids = |v: &[u64]| v.iter().cloned().collect::<BTreeSet<u64>>();
+            assert!(
+                idl(audit, unsafe {
+                    filter_resolved!(f_eq("userid", "william"))
+                }) == IDL::Indexed(ids(&[1]))
+            );
+            assert!(
+                idl(audit, unsafe { filter_resolved!(f_eq("userid", "claire")) })
+                    == IDL::Indexed(ids(&[2]))
+            );
+            // Nothing is left to repair.
+            assert!(be.reindex_uuids(audit, &uuids) == Ok(0));
+        });
+    }
+
+    #[test]
+    fn test_verify_indexes() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", "william");
+            e1.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+
+            let idxmeta: BTreeSet<(String, IndexType)> = vec![
+                ("userid".to_string(), IndexType::EQUALITY),
+                ("userid".to_string(), IndexType::PRESENCE),
+            ]
+            .into_iter()
+            .collect();
+            assert!(be.update_idxmeta(audit, idxmeta).is_ok());
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            assert!(be.create(audit, &test_origin(), &vec![ve1]).is_ok());
+            assert!(be.verify_indexes(audit).is_empty());
+
+            let key = |v: &str| ("userid".to_string(), IndexType::EQUALITY, v.to_string());
+            assert!(be.idl_update(audit, &key("william"), 1, false).is_ok());
+            assert!(be.idl_update(audit, &key("claire"), 1, true).is_ok());
+            let r = be.verify_indexes(audit);
+            assert!(r.len() == 2);
+            assert!(r.contains(&Err(ConsistencyError::IndexKeyMissing(
+                "userid".to_string(),
+                "EQUALITY".to_string(),
+                "william".to_string(),
+                1
+            ))));
+            assert!(r.contains(&Err(ConsistencyError::IndexKeyStale(
+                "userid".to_string(),
+                "EQUALITY".to_string(),
+                "claire".to_string(),
+                1
+            ))));
         });
     }
+
+    #[test]
+    fn test_be_mmap_search() {
+        let mut audit = AuditScope::new("run_test");
+        let path = "./.mmap_test.db";
+        let _ = fs::remove_file(path);
+
+        let be = Backend::new_mmap(&mut audit, path, 2, 1 << 20, DbDurability::new())
+            .expect("Failed to setup backend");
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("userid", "william");
+        e.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+        let e = unsafe { e.to_valid_new() };
+        {
+            let be_txn = be.write();
+            assert!(be_txn
+                .create(&mut audit, &test_origin(), &vec![e.clone()])
+                .is_ok());
+            assert!(be_txn.commit().is_ok());
+        }
+
+        // Read it back on a fresh, mapped connection.
+        let be_txn = be.read();
+        assert!(entry_exists!(&mut audit, be_txn, e));
+        drop(be_txn);
+        drop(be);
+        let _ = fs::remove_file(path);
+        println!("{}", audit);
+    }
+
+    #[test]
+    fn test_be_relax_durability() {
+        let mut audit = AuditScope::new("run_test");
+        let path = "./.durability_test.db";
+        let _ = fs::remove_file(path);
+
+        let mut durability = DbDurability::new();
+        durability.synchronous = DbSync::Normal;
+        let be =
+            Backend::new_mmap(&mut audit, path, 2, 0, durability).expect("Failed to setup backend");
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("userid", "william");
+        e.add_ava("uuid", "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+        let e = unsafe { e.to_valid_new() };
+
+        // A clone sees the relaxed mode, as the import does.
+        be.clone().relax_durability();
+        {
+            let be_txn = be.write();
+            let sync: i64 = be_txn
+                .get_conn()
+                .query_row("PRAGMA synchronous", NO_PARAMS, |row| row.get(0))
+                .expect("sqlite error");
+            assert!(sync == 0);
+            assert!(be_txn
+                .create(&mut audit, &test_origin(), &vec![e.clone()])
+                .is_ok());
+            assert!(be_txn.commit().is_ok());
+        }
+        assert!(be.restore_durability(&mut audit).is_ok());
+        {
+            let be_txn = be.write();
+            let sync: i64 = be_txn
+                .get_conn()
+                .query_row("PRAGMA synchronous", NO_PARAMS, |row| row.get(0))
+                .expect("sqlite error");
+            assert!(sync == 1);
+        }
+        drop(be);
+
+        // And it's all there when the database is opened again.
+        let be = Backend::new(&mut audit, path, 1).expect("Failed to setup backend");
+        let be_txn = be.read();
+        assert!(entry_exists!(&mut audit, be_txn, e));
+        drop(be_txn);
+        drop(be);
+        let _ = fs::remove_file(path);
+        println!("{}", audit);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_be_encrypted_requires_sqlcipher() {
+        // Without sqlcipher the key would be silently ignored, so it must
+        // be refused instead.
+        let mut audit = AuditScope::new("run_test");
+        let key =
+            DbKey::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .expect("Failed to parse key");
+        assert!(
+            Backend::new_encrypted(&mut audit, "", 1, &key, DbDurability::new()).err()
+                == Some(OperationError::InvalidDbKey(
+                    "sqlcipher support is not built in"
+                ))
+        );
+    }
 }
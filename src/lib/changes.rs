@@ -0,0 +1,238 @@
+// Notification of committed changes.
+//
+// Other parts of the server (metrics, webhooks, notifications) want to know
+// when entries change, but they must not slow down or be able to fail a
+// write. A write transaction collects a short summary of each entry it
+// creates, modifies or deletes, and once the transaction has committed the
+// summaries are handed to the change bus. The bus has a single thread of its
+// own, which passes them to each subscriber in turn, so the writer never waits
+// on a subscriber. A slow subscriber does delay the ones after it.
+// Aborted transactions send nothing.
+//
+// Security alerts from anomaly detection travel the same way, so anything
+// that forwards changes elsewhere can forward those too.
+
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::anomaly::Alert;
+use crate::async_log::EventLog;
+use crate::entry::{Entry, EntryValid};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeOp {
+    Create,
+    Modify,
+    Delete,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeSummary {
+    pub uuid: String,
+    pub classes: Vec<String>,
+    pub op: ChangeOp,
+}
+
+impl ChangeSummary {
+    pub fn new<STATE>(e: &Entry<EntryValid, STATE>, op: ChangeOp) -> Self {
+        ChangeSummary {
+            uuid: e.get_uuid().clone(),
+            classes: e
+                .get_ava("class")
+                .map(|vs| vs.iter().map(|v| v.to_string()).collect())
+                .unwrap_or_else(Vec::new),
+            op: op,
+        }
+    }
+}
+
+pub trait ChangeSubscriber: Send + Sync {
+    fn id(&self) -> &'static str;
+
+    // Called once per committed transaction, with every change it made.
+    fn notify(&self, changes: &[ChangeSummary]);
+
+    // Called for each security alert. Most subscribers only want changes.
+    fn alert(&self, _alert: &Alert) {}
+}
+
+enum BusMessage {
+    Changes(Vec<ChangeSummary>),
+    Alert(Alert),
+}
+
+#[derive(Clone)]
+pub struct ChangeBus {
+    tx: Arc<Mutex<Sender<BusMessage>>>,
+}
+
+impl ChangeBus {
+    pub fn new(subscribers: Vec<Arc<dyn ChangeSubscriber>>) -> Self {
+        let (tx, rx) = channel::<BusMessage>();
+        // This ends when the last clone of the bus is dropped.
+        thread::spawn(move || {
+            for msg in rx.iter() {
+                match msg {
+                    BusMessage::Changes(changes) => subscribers.iter().for_each(|s| {
+                        debug!("Sending {} changes to {}", changes.len(), s.id());
+                        s.notify(changes.as_slice())
+                    }),
+                    BusMessage::Alert(alert) => subscribers.iter().for_each(|s| {
+                        debug!("Sending alert to {}", s.id());
+                        s.alert(&alert)
+                    }),
+                }
+            }
+        });
+        ChangeBus {
+            tx: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    pub fn publish(&self, changes: Vec<ChangeSummary>) {
+        // The transaction has already committed, so there is nothing to undo
+        // if this fails. All we can do is say so.
+        if self.send(BusMessage::Changes(changes)).is_err() {
+            error!("Unable to publish changes, the change bus has stopped");
+        }
+    }
+
+    pub fn publish_alert(&self, alert: Alert) {
+        if self.send(BusMessage::Alert(alert)).is_err() {
+            error!("Unable to publish alert, the change bus has stopped");
+        }
+    }
+
+    fn send(&self, msg: BusMessage) -> Result<(), ()> {
+        self.tx
+            .lock()
+            .map_err(|_| ())
+            .and_then(|tx| tx.send(msg).map_err(|_| ()))
+    }
+}
+
+// Writes every change to the server log.
+pub struct ChangeLogger {
+    log: actix::Addr<EventLog>,
+}
+
+impl ChangeLogger {
+    pub fn new(log: actix::Addr<EventLog>) -> Self {
+        ChangeLogger { log: log }
+    }
+}
+
+impl ChangeSubscriber for ChangeLogger {
+    fn id(&self) -> &'static str {
+        "change_logger"
+    }
+
+    fn notify(&self, changes: &[ChangeSummary]) {
+        changes.iter().for_each(|c| {
+            log_event!(self.log, "change: {:?} {} {:?}", c.op, c.uuid, c.classes);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::changes::{ChangeBus, ChangeOp, ChangeSubscriber, ChangeSummary};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::event::{CreateEvent, DeleteEvent};
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct TestSubscriber {
+        tx: Mutex<Sender<Vec<ChangeSummary>>>,
+    }
+
+    impl ChangeSubscriber for TestSubscriber {
+        fn id(&self) -> &'static str {
+            "test_subscriber"
+        }
+
+        fn notify(&self, changes: &[ChangeSummary]) {
+            let _ = self
+                .tx
+                .lock()
+                .expect("lock poisoned")
+                .send(changes.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_change_bus_publish() {
+        let (tx, rx) = channel();
+        let bus = ChangeBus::new(vec![Arc::new(TestSubscriber { tx: Mutex::new(tx) })]);
+        bus.publish(vec![ChangeSummary {
+            uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+            classes: vec!["object".to_string(), "person".to_string()],
+            op: ChangeOp::Create,
+        }]);
+        let changes = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No changes were received");
+        assert!(changes.len() == 1);
+        assert!(changes[0].op == ChangeOp::Create);
+        assert!(changes[0].uuid == "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+    }
+
+    #[test]
+    fn test_change_bus_commit() {
+        let mut au = AuditScope::new("test_change_bus_commit");
+        let be = Backend::new(&mut au, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut au).expect("Failed to init schema");
+        let mut qs = QueryServer::new(be, schema);
+        qs.initialise_helper(&mut au).expect("init failed!");
+
+        let (tx, rx) = channel();
+        qs.set_change_bus(ChangeBus::new(vec![Arc::new(TestSubscriber {
+            tx: Mutex::new(tx),
+        })]));
+
+        let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "person"],
+                "name": ["testperson1"],
+                "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                "description": ["testperson"],
+                "displayname": ["testperson1"]
+            }
+        }"#,
+        )
+        .expect("json failure");
+        let ce = CreateEvent::new_internal(vec![e]);
+        let de = unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1"))) };
+
+        // Nothing is sent for a transaction that doesn't commit.
+        {
+            let mut qs_write = qs.write();
+            assert!(qs_write.create(&mut au, &ce).is_ok());
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // A committed transaction sends all of its changes together.
+        let mut qs_write = qs.write();
+        assert!(qs_write.create(&mut au, &ce).is_ok());
+        assert!(qs_write.delete(&mut au, &de).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        let changes = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No changes were received");
+        assert!(changes.len() == 2);
+        assert!(changes[0].op == ChangeOp::Create);
+        assert!(changes[0].classes.contains(&"person".to_string()));
+        assert!(changes[1].op == ChangeOp::Delete);
+        assert!(changes[1].uuid == "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+        println!("{}", au);
+    }
+}
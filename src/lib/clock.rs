@@ -0,0 +1,70 @@
+// Where the server gets the time from.
+//
+// Anything that records or compares times - change csns, purge windows, and
+// later expiry and lockouts - asks the clock the server was given, rather
+// than the system directly. Tests can then set and advance the time instead
+// of sleeping on it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+use std::sync::Mutex;
+
+pub trait Clock: Send + Sync {
+    // Time since the unix epoch, in UTC.
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        // A clock before 1970 is broken beyond anything we can do about it
+        // here, so treat it as the epoch.
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    }
+}
+
+// A clock that only moves when it's told to.
+#[cfg(test)]
+pub struct MockClock {
+    now: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: Duration) -> Self {
+        MockClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().expect("Clock lock poisoned");
+        *now += d;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().expect("Clock lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, MockClock, SystemClock};
+    use std::time::Duration;
+
+    #[test]
+    fn test_clock_mock() {
+        let c = MockClock::new(Duration::from_secs(10));
+        assert!(c.now() == Duration::from_secs(10));
+        c.advance(Duration::from_secs(5));
+        assert!(c.now() == Duration::from_secs(15));
+        assert!(SystemClock.now() > c.now());
+    }
+}
@@ -5,6 +5,26 @@ pub static PURGE_TIMEOUT: u64 = 60;
 #[cfg(not(test))]
 pub static PURGE_TIMEOUT: u64 = 3600;
 
+// How often, in seconds, each replication agreement pulls from its supplier.
+pub static REPL_INTERVAL: u64 = 60;
+// Seconds to wait on a supplier for its changes before giving up on a pull.
+pub static REPL_FETCH_TIMEOUT: u64 = 30;
+
+// How many notifications a subscriber may fall behind by before its
+// subscription is ended, and how many subscriptions each account may have.
+pub static SUBSCRIPTION_BUFFER: usize = 256;
+pub static SUBSCRIPTION_MAX_PER_USER: usize = 8;
+// How often, in seconds, subscriptions are ended whose sessions have.
+pub static SUBSCRIPTION_EXPIRY_INTERVAL: u64 = 60;
+
+// How many ldap connections are served at once, and how many more may wait
+// for one of those to close. Any beyond that are closed as they arrive.
+pub static LDAP_MAX_CONNECTIONS: usize = 64;
+pub static LDAP_MAX_PENDING: usize = 64;
+// Seconds a connection may go without sending a request, or without
+// reading our response, before it's closed.
+pub static LDAP_IDLE_TIMEOUT: u64 = 300;
+
 pub static UUID_ADMIN: &'static str = "00000000-0000-0000-0000-000000000000";
 pub static JSON_ADMIN_V1: &'static str = r#"{
     "valid": {
@@ -20,7 +40,7 @@ pub static JSON_ADMIN_V1: &'static str = r#"{
     }
 }"#;
 
-pub static _UUID_IDM_ADMINS: &'static str = "00000000-0000-0000-0000-000000000001";
+pub static UUID_IDM_ADMINS: &'static str = "00000000-0000-0000-0000-000000000001";
 pub static JSON_IDM_ADMINS_V1: &'static str = r#"{
     "valid": {
         "uuid": "00000000-0000-0000-0000-000000000001"
@@ -35,6 +55,22 @@ pub static JSON_IDM_ADMINS_V1: &'static str = r#"{
     }
 }"#;
 
+// The service accounts of radius servers, which read the radius secrets of
+// accounts to check them, see idm_radius_servers_acp_read.
+pub static UUID_IDM_RADIUS_SERVERS: &'static str = "00000000-0000-0000-0000-000000000002";
+pub static JSON_IDM_RADIUS_SERVERS_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-000000000002"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["group", "object"],
+        "name": ["idm_radius_servers"],
+        "uuid": ["00000000-0000-0000-0000-000000000002"],
+        "description": ["Builtin IDM Group for radius servers."]
+    }
+}"#;
+
 pub static _UUID_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffffff000001";
 pub static JSON_SYSTEM_INFO_V1: &'static str = r#"{
     "valid": {
@@ -70,128 +106,2353 @@ pub static JSON_IDM_ADMINS_ACP_SEARCH_V1: &'static str = r#"{
         ],
         "acp_search_attr": ["name", "class", "uuid"]
     }
-}"#;
+}"#;
+
+pub static _UUID_IDM_ADMINS_ACP_REVIVE_V1: &'static str = "00000000-0000-0000-0000-ffffff000003";
+pub static JSON_IDM_ADMINS_ACP_REVIVE_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000003"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_admins_acp_revive"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000003"],
+        "description": ["Builtin IDM Administrators Access Controls."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"recycled\"]}"
+        ],
+        "acp_modify_removedattr": ["class"],
+        "acp_modify_class_remove": ["recycled"]
+    }
+}"#;
+
+pub static _UUID_IDM_SELF_ACP_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000004";
+pub static JSON_IDM_SELF_ACP_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000004"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search"],
+        "name": ["idm_self_acp_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000004"],
+        "description": ["Builtin IDM Control for self read - required for whoami."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "\"Self\""
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_search_attr": ["name", "uuid", "account_valid_from", "account_expire", "ssh_publickey"]
+    }
+}"#;
+
+// The limits themselves are optional attributes, so that migrations don't
+// reset them. When one isn't set, the default below applies.
+pub static UUID_SYSTEM_CONFIG: &'static str = "00000000-0000-0000-0000-ffffff000005";
+pub static JSON_SYSTEM_CONFIG_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000005"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "system_config"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000005"],
+        "description": ["System wide configuration."]
+    }
+}"#;
+
+// Hosts renew their own service secret, so they may read and replace it -
+// but only on their own entry. Administrators may rotate the secret of any
+// host, for when one has been rebuilt or its secret has leaked.
+pub static _UUID_IDM_HOST_ACP_SECRET_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000006";
+pub static JSON_IDM_HOST_ACP_SECRET_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000006"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search"],
+        "name": ["idm_host_acp_secret_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000006"],
+        "description": ["Builtin IDM Control for hosts to read their own service secret."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"class\",\"host\"]}"
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_search_attr": ["name", "uuid", "service_secret", "service_secret_expiry"]
+    }
+}"#;
+
+pub static _UUID_IDM_HOST_ACP_SECRET_ROTATE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000007";
+pub static JSON_IDM_HOST_ACP_SECRET_ROTATE_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000007"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_host_acp_secret_rotate"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000007"],
+        "description": ["Builtin IDM Control for hosts to rotate their own service secret."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"class\",\"host\"]}"
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_modify_removedattr": ["service_secret", "service_secret_expiry"],
+        "acp_modify_presentattr": ["service_secret", "service_secret_expiry"]
+    }
+}"#;
+
+pub static _UUID_IDM_ADMINS_ACP_HOST_SECRET_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000008";
+pub static JSON_IDM_ADMINS_ACP_HOST_SECRET_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000008"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_admins_acp_host_secret"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000008"],
+        "description": ["Builtin IDM Administrators Access Controls."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"host\"]}"
+        ],
+        "acp_modify_removedattr": ["service_secret", "service_secret_expiry"],
+        "acp_modify_presentattr": ["service_secret", "service_secret_expiry"]
+    }
+}"#;
+
+pub static _UUID_IDM_ADMINS_ACP_REPLICATION_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000009";
+pub static JSON_IDM_ADMINS_ACP_REPLICATION_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000009"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_create",
+            "access_control_modify",
+            "access_control_delete"
+        ],
+        "name": ["idm_admins_acp_replication"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000009"],
+        "description": ["Builtin IDM Administrators Access Controls for replication agreements."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"replication_agreement\"]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "description", "repl_supplier", "repl_user", "repl_seq"],
+        "acp_create_class": ["object", "replication_agreement"],
+        "acp_create_attr": ["class", "name", "uuid", "description", "repl_supplier", "repl_user", "repl_seq"],
+        "acp_modify_removedattr": ["description", "repl_supplier", "repl_user", "repl_seq"],
+        "acp_modify_presentattr": ["description", "repl_supplier", "repl_user", "repl_seq"]
+    }
+}"#;
+
+// Password hashes are never returned by a search, to anyone, whatever else
+// grants them. Hashes can still be replaced, by their owner or idm_admins,
+// but anonymous is left without one, as it authenticates with nothing.
+pub static _UUID_IDM_ACP_PASSWORD_DENY_V1: &'static str = "00000000-0000-0000-0000-ffffff00000a";
+pub static JSON_IDM_ACP_PASSWORD_DENY_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000a"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search", "access_control_deny"],
+        "name": ["idm_acp_password_deny"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000a"],
+        "description": ["Builtin IDM Control preventing the read of password hashes, break glass secrets and credential reset tokens."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_search_attr": ["password", "break_glass_secret", "credential_reset_token"]
+    }
+}"#;
+
+pub static _UUID_IDM_SELF_ACP_PASSWORD_V1: &'static str = "00000000-0000-0000-0000-ffffff00000b";
+pub static JSON_IDM_SELF_ACP_PASSWORD_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000b"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_self_acp_password"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000b"],
+        "description": ["Builtin IDM Control for accounts to set their own password."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_modify_removedattr": ["password"],
+        "acp_modify_presentattr": ["password"]
+    }
+}"#;
+
+pub static _UUID_IDM_ADMINS_ACP_PASSWORD_V1: &'static str = "00000000-0000-0000-0000-ffffff00000c";
+pub static JSON_IDM_ADMINS_ACP_PASSWORD_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000c"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_admins_acp_password"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000c"],
+        "description": ["Builtin IDM Administrators Access Controls for resetting passwords."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_modify_removedattr": ["password"],
+        "acp_modify_presentattr": ["password"]
+    }
+}"#;
+
+// Administrators may turn the audit logging of a subsystem up or down while
+// the server runs, to look into a live problem.
+pub static _UUID_IDM_ADMINS_ACP_LOG_LEVEL_V1: &'static str = "00000000-0000-0000-0000-ffffff00000d";
+pub static JSON_IDM_ADMINS_ACP_LOG_LEVEL_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000d"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_admins_acp_log_level"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000d"],
+        "description": ["Builtin IDM Administrators Access Controls for audit log levels."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffff000005\"]}"
+        ],
+        "acp_search_attr": ["log_level"],
+        "acp_modify_removedattr": ["log_level"],
+        "acp_modify_presentattr": ["log_level"]
+    }
+}"#;
+
+// Administrators set when accounts start and stop working, for onboarding
+// and offboarding. Whether one has expired is only set by the server.
+pub static _UUID_IDM_ADMINS_ACP_ACCOUNT_VALIDITY_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff00000e";
+pub static JSON_IDM_ADMINS_ACP_ACCOUNT_VALIDITY_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000e"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_admins_acp_account_validity"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000e"],
+        "description": ["Builtin IDM Administrators Access Controls for account validity."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_search_attr": ["account_valid_from", "account_expire", "account_expired"],
+        "acp_modify_removedattr": ["account_valid_from", "account_expire"],
+        "acp_modify_presentattr": ["account_valid_from", "account_expire"]
+    }
+}"#;
+
+// Members of idm_admins may read the audit records of every operation.
+pub static _UUID_IDM_ADMINS_ACP_AUDIT_READ_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff00000f";
+pub static JSON_IDM_ADMINS_ACP_AUDIT_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00000f"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_audit_read"
+        ],
+        "name": ["idm_admins_acp_audit_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00000f"],
+        "description": ["Builtin IDM Administrators Access Controls for the audit log."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Pres\":\"class\"}"
+        ]
+    }
+}"#;
+
+// Members of idm_admins may run the server operations that aren't about
+// particular entries, and so aren't covered by the other profiles.
+pub static _UUID_IDM_ADMINS_ACP_OPERATION_V1: &'static str = "00000000-0000-0000-0000-ffffff00001b";
+pub static JSON_IDM_ADMINS_ACP_OPERATION_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00001b"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_operation"
+        ],
+        "name": ["idm_admins_acp_operation"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00001b"],
+        "description": ["Builtin IDM Administrators Access Controls for server operations."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_operation": [
+            "search_trace",
+            "acp_coverage",
+            "memory_report",
+            "backup",
+            "delete_preview",
+            "replication"
+        ]
+    }
+}"#;
+
+// Group managers, directly or through a group, may read and change the
+// membership of the groups they manage. Approving a join request is a
+// modify as the manager, so this is what grants it.
+pub static _UUID_IDM_GROUP_MANAGERS_ACP_MEMBER_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff00001c";
+pub static JSON_IDM_GROUP_MANAGERS_ACP_MEMBER_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00001c"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_group_managers_acp_member"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00001c"],
+        "description": ["Builtin IDM Control for group managers to change membership."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"group\"]},{\"Eq\":[\"group_manager\",\"%self_or_memberof%\"]}]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "member", "group_manager"],
+        "acp_modify_removedattr": ["member"],
+        "acp_modify_presentattr": ["member"]
+    }
+}"#;
+
+// Releases before the operation metrics were kept only in memory wrote them
+// to this entry, readable by this profile. A migration removes both.
+pub static UUID_SYSTEM_STATS: &'static str = "00000000-0000-0000-0000-ffffff000010";
+pub static UUID_IDM_ADMINS_ACP_STATS_V1: &'static str = "00000000-0000-0000-0000-ffffff000011";
+
+// Administrators may define attributes and classes while the server runs.
+// The system schema is kept safe from them by the protected plugin.
+pub static _UUID_IDM_ADMINS_ACP_SCHEMA_V1: &'static str = "00000000-0000-0000-0000-ffffff000012";
+pub static JSON_IDM_ADMINS_ACP_SCHEMA_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000012"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_create",
+            "access_control_modify",
+            "access_control_delete"
+        ],
+        "name": ["idm_admins_acp_schema"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000012"],
+        "description": ["Builtin IDM Administrators Access Controls for managing schema."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Or\":[{\"Eq\":[\"class\",\"attributetype\"]},{\"Eq\":[\"class\",\"classtype\"]}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "description", "multivalue", "unique", "index", "syntax",
+            "systemmay", "may", "systemmust", "must"
+        ],
+        "acp_create_class": ["object", "attributetype", "classtype"],
+        "acp_create_attr": [
+            "class", "name", "uuid", "description", "multivalue", "unique", "index", "syntax",
+            "may", "must"
+        ],
+        "acp_modify_removedattr": ["description", "index", "may", "must"],
+        "acp_modify_presentattr": ["description", "index", "may", "must"]
+    }
+}"#;
+
+// The domain this server belongs to. Unlike the other builtin entries there's
+// no template for it, as its domain_uuid is made when the domain is first
+// set up, and its name comes from the server configuration.
+pub static UUID_DOMAIN_INFO: &'static str = "00000000-0000-0000-0000-ffffff000013";
+// The version a new domain starts at.
+pub static DOMAIN_VERSION: &'static str = "1";
+
+// Accounts manage their own ssh public keys. Hosts read the keys of any
+// account, to answer sshd's AuthorizedKeysCommand.
+pub static _UUID_IDM_SELF_ACP_SSH_PUBLICKEY_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000014";
+pub static JSON_IDM_SELF_ACP_SSH_PUBLICKEY_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000014"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_modify"],
+        "name": ["idm_self_acp_ssh_publickey"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000014"],
+        "description": ["Builtin IDM Control for accounts to manage their own ssh public keys."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_modify_removedattr": ["ssh_publickey"],
+        "acp_modify_presentattr": ["ssh_publickey"]
+    }
+}"#;
+
+pub static _UUID_IDM_HOST_ACP_SSH_PUBLICKEY_READ_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000015";
+pub static JSON_IDM_HOST_ACP_SSH_PUBLICKEY_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000015"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search"],
+        "name": ["idm_host_acp_ssh_publickey_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000015"],
+        "description": ["Builtin IDM Control for hosts to read the ssh public keys of accounts."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"class\",\"host\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"account\"]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "ssh_publickey"]
+    }
+}"#;
+
+// Accounts read and regenerate their own radius secret, to set up their
+// devices. Radius servers read the secret of any account, to check it.
+pub static _UUID_IDM_SELF_ACP_RADIUS_SECRET_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000016";
+pub static JSON_IDM_SELF_ACP_RADIUS_SECRET_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000016"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_self_acp_radius_secret"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000016"],
+        "description": ["Builtin IDM Control for accounts to manage their own radius secret."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_targetscope": [
+            "\"Self\""
+        ],
+        "acp_search_attr": ["class", "radius_secret"],
+        "acp_modify_removedattr": ["radius_secret"],
+        "acp_modify_presentattr": ["radius_secret"]
+    }
+}"#;
+
+pub static _UUID_IDM_RADIUS_SERVERS_ACP_READ_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000017";
+pub static JSON_IDM_RADIUS_SERVERS_ACP_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000017"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search"],
+        "name": ["idm_radius_servers_acp_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000017"],
+        "description": ["Builtin IDM Control for radius servers to read radius secrets."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000002\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "radius_secret"]
+    }
+}"#;
+
+// Administrators manage the oauth2 resource servers, including their
+// secrets. Resource servers read enough of an account to build the claims of
+// its tokens - what they can't read never reaches them.
+pub static _UUID_IDM_ADMINS_ACP_OAUTH2_V1: &'static str = "00000000-0000-0000-0000-ffffff000018";
+pub static JSON_IDM_ADMINS_ACP_OAUTH2_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000018"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_create",
+            "access_control_modify",
+            "access_control_delete"
+        ],
+        "name": ["idm_admins_acp_oauth2"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000018"],
+        "description": ["Builtin IDM Administrators Access Controls for oauth2 resource servers."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"oauth2_resource_server\"]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "description", "displayname", "oauth2_rs_redirect_uri",
+            "oauth2_rs_scope_map", "oauth2_rs_basic_secret"
+        ],
+        "acp_create_class": ["object", "oauth2_resource_server"],
+        "acp_create_attr": [
+            "class", "name", "uuid", "description", "displayname", "oauth2_rs_redirect_uri",
+            "oauth2_rs_scope_map", "oauth2_rs_basic_secret"
+        ],
+        "acp_modify_removedattr": [
+            "description", "displayname", "oauth2_rs_redirect_uri", "oauth2_rs_scope_map",
+            "oauth2_rs_basic_secret"
+        ],
+        "acp_modify_presentattr": [
+            "description", "displayname", "oauth2_rs_redirect_uri", "oauth2_rs_scope_map",
+            "oauth2_rs_basic_secret"
+        ]
+    }
+}"#;
+
+pub static _UUID_IDM_OAUTH2_RS_ACP_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000019";
+pub static JSON_IDM_OAUTH2_RS_ACP_READ_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff000019"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search"],
+        "name": ["idm_oauth2_rs_acp_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000019"],
+        "description": ["Builtin IDM Control for oauth2 resource servers to read the claims of accounts."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"class\",\"oauth2_resource_server\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "displayname", "mail"]
+    }
+}"#;
+
+// Administrators, or accounts made members of idm_admins for an automated
+// flow, may issue credential reset tokens. Only the token's hash is stored,
+// and no one can read it back.
+pub static _UUID_IDM_ADMINS_ACP_CREDENTIAL_RESET_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff00001a";
+pub static JSON_IDM_ADMINS_ACP_CREDENTIAL_RESET_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffff00001a"
+    },
+    "state": null,
+    "attrs": {
+        "class": [
+            "object",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_admins_acp_credential_reset"],
+        "uuid": ["00000000-0000-0000-0000-ffffff00001a"],
+        "description": ["Builtin IDM Administrators Access Controls for issuing credential reset tokens."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"AndNot\":{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
+        ],
+        "acp_search_attr": ["class", "name", "uuid", "credential_reset_expiry"],
+        "acp_modify_removedattr": ["credential_reset_token", "credential_reset_expiry"],
+        "acp_modify_presentattr": ["credential_reset_token", "credential_reset_expiry"]
+    }
+}"#;
+
+// Anonymous searches may return this many entries, and there may be this
+// many of them a minute from each source.
+pub static ANON_SEARCH_MAX_RESULTS: usize = 128;
+pub static ANON_SEARCH_MAX_OPS: usize = 60;
+// Rate limits sweep out quiet keys once they are tracking this many.
+pub static RATELIMIT_MAX_KEYS: usize = 16384;
+
+// How many resolved receiver and targetscope filters a transaction keeps,
+// each. Enough for every acp of a few hundred identities.
+pub static RESOLVED_FILTER_CACHE_SIZE: usize = 4096;
+
+// How many entries internal_modify_filter loads and passes through the
+// plugins at a time.
+pub static INTERNAL_MODIFY_BATCH_SIZE: usize = 256;
+
+// The deepest nesting, and the most terms, a filter from a client may have.
+pub static FILTER_MAX_DEPTH: usize = 16;
+pub static FILTER_MAX_TERMS: usize = 256;
+
+// The most entries any one search may return. Paged searches can walk past
+// this, a page at a time.
+pub static SEARCH_MAX_RESULTS: usize = 4096;
+
+// An index key holding more ids than this doesn't narrow a search. Its
+// entries are tested against the filter instead, so a key like class=object
+// is never loaded into memory whole.
+pub static IDL_ALLIDS_THRESHOLD: usize = 65536;
+
+// How many names a typeahead gives when not asked for a number, and the most
+// it will give.
+pub static TYPEAHEAD_DEFAULT_RESULTS: usize = 10;
+pub static TYPEAHEAD_MAX_RESULTS: usize = 50;
+
+// The most entry uuids listed in each part of an acp coverage report. The
+// counts by class always cover every entry checked.
+pub static ACP_COVERAGE_MAX_LISTED: usize = 1024;
+// How many entries an acp coverage report reads and checks at a time.
+pub static ACP_COVERAGE_BATCH_SIZE: usize = 256;
+
+// Seconds over which each kind of security event is counted, and how many
+// sources or identities are tracked before quiet ones are swept out.
+pub static ANOMALY_AUTH_FAILURE_WINDOW: u64 = 300;
+pub static ANOMALY_ACCESS_DENIED_WINDOW: u64 = 3600;
+pub static ANOMALY_DELETE_WINDOW: u64 = 3600;
+pub static ANOMALY_MAX_KEYS: usize = 16384;
+// Seconds to wait on the alert webhook before giving up on an alert.
+pub static ANOMALY_WEBHOOK_TIMEOUT: u64 = 10;
+
+// The size in bytes the audit log grows to before it's moved aside, and how
+// many of those that were moved aside are kept.
+pub static AUDIT_LOG_ROTATE_SIZE: u64 = 16 * 1024 * 1024;
+pub static AUDIT_LOG_ROTATE_KEEP: usize = 8;
+
+// Changes to these are credential changes, and are kept in the audit log.
+pub static CREDENTIAL_ATTRS: [&'static str; 8] = [
+    "password",
+    "ssh_publickey",
+    "cert_mapping",
+    "service_secret",
+    "break_glass_secret",
+    "credential_reset_token",
+    "radius_secret",
+    "oauth2_rs_basic_secret",
+];
+
+// How long a rotated host service secret is valid for, in seconds. Host
+// agents are expected to rotate well before this runs out.
+pub static HOST_SECRET_LIFETIME: u64 = 30 * 24 * 3600;
+
+// How long a break glass secret, and the session from it, lasts when no
+// lifetime is given, and the longest it may be, in seconds.
+pub static BREAK_GLASS_DEFAULT_LIFETIME: u64 = 900;
+pub static BREAK_GLASS_MAX_LIFETIME: u64 = 4 * 3600;
+
+// How long a credential reset token lasts when no lifetime is given, and the
+// longest it may be, in seconds.
+pub static CREDENTIAL_RESET_DEFAULT_LIFETIME: u64 = 24 * 3600;
+pub static CREDENTIAL_RESET_MAX_LIFETIME: u64 = 7 * 24 * 3600;
+// Tokens can be redeemed without authenticating, so attempts are limited to
+// this many per window, in seconds, across the whole server.
+pub static CREDENTIAL_RESET_MAX_ATTEMPTS: usize = 10;
+pub static CREDENTIAL_RESET_WINDOW: u64 = 60;
+
+// The pbkdf2 iterations of new password hashes. Existing hashes keep the
+// count they were made with.
+pub static PASSWORD_PBKDF2_ITERATIONS: usize = 10000;
+
+// How long an oauth2 authorisation code may wait to be exchanged, and how
+// long the tokens it's exchanged for last, in seconds.
+pub static OAUTH2_CODE_LIFETIME: u64 = 60;
+pub static OAUTH2_TOKEN_LIFETIME: u64 = 3600;
+
+// Names that a rename may not take, or take away from the builtins that hold
+// them. Clients and documentation refer to these entries by name.
+pub static RESERVED_NAMES: &'static [&'static str] = &["admin", "anonymous", "idm_admins"];
+
+pub static UUID_DOES_NOT_EXIST: &'static str = "00000000-0000-0000-0000-fffffffffffe";
+
+pub static UUID_ANONYMOUS: &'static str = "00000000-0000-0000-0000-ffffffffffff";
+pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+    "valid": {
+        "uuid": "00000000-0000-0000-0000-ffffffffffff"
+    },
+    "state": null,
+    "attrs": {
+        "class": ["account", "object"],
+        "name": ["anonymous"],
+        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
+        "description": ["Anonymous access account."],
+        "displayname": ["Anonymous"]
+    }
+}"#;
+
+// Core
+// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
+pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
+pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
+pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
+pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
+pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
+pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
+pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
+pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
+pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
+pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
+pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
+pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
+pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
+pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
+pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
+pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
+pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
+pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
+pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
+pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
+pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000023";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000024";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS_ADD: &'static str =
+    "00000000-0000-0000-0000-ffff00000047";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS_REMOVE: &'static str =
+    "00000000-0000-0000-0000-ffff00000048";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTVALUE: &'static str =
+    "00000000-0000-0000-0000-ffff00000050";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDVALUE: &'static str =
+    "00000000-0000-0000-0000-ffff00000051";
+pub static UUID_SCHEMA_ATTR_ACP_LOG_ONLY: &'static str = "00000000-0000-0000-0000-ffff00000052";
+pub static UUID_SCHEMA_ATTR_ACP_TICKET_REF: &'static str = "00000000-0000-0000-0000-ffff00000053";
+pub static UUID_SCHEMA_ATTR_ACP_DELETE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000058";
+pub static UUID_SCHEMA_ATTR_ACP_TEST_ALLOW: &'static str = "00000000-0000-0000-0000-ffff00000059";
+pub static UUID_SCHEMA_ATTR_ACP_TEST_DENY: &'static str = "00000000-0000-0000-0000-ffff00000060";
+pub static UUID_SCHEMA_ATTR_UNIQUE: &'static str = "00000000-0000-0000-0000-ffff00000061";
+pub static UUID_SCHEMA_ATTR_ALIAS: &'static str = "00000000-0000-0000-0000-ffff00000080";
+pub static UUID_SCHEMA_ATTR_DOMAIN_NAME: &'static str = "00000000-0000-0000-0000-ffff00000081";
+pub static UUID_SCHEMA_ATTR_DOMAIN_UUID: &'static str = "00000000-0000-0000-0000-ffff00000082";
+pub static UUID_SCHEMA_ATTR_DOMAIN_FEATURE: &'static str = "00000000-0000-0000-0000-ffff00000083";
+pub static UUID_SCHEMA_ATTR_ACP_COMPARE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000067";
+pub static UUID_SCHEMA_ATTR_ACP_OPERATION: &'static str = "00000000-0000-0000-0000-ffff00000096";
+
+pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
+pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
+pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
+pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
+    "00000000-0000-0000-0000-ffff00000029";
+pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
+
+pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
+pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
+pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
+pub static UUID_SCHEMA_CLASS_DOMAIN_INFO: &'static str = "00000000-0000-0000-0000-ffff00000084";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
+    "00000000-0000-0000-0000-ffff00000034";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
+    "00000000-0000-0000-0000-ffff00000035";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
+    "00000000-0000-0000-0000-ffff00000036";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
+    "00000000-0000-0000-0000-ffff00000037";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
+    "00000000-0000-0000-0000-ffff00000038";
+pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DENY: &'static str =
+    "00000000-0000-0000-0000-ffff00000049";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_COMPARE: &'static str =
+    "00000000-0000-0000-0000-ffff00000068";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_AUDIT_READ: &'static str =
+    "00000000-0000-0000-0000-ffff0000007d";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_OPERATION: &'static str =
+    "00000000-0000-0000-0000-ffff00000097";
+
+// system supplementary
+pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
+pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000040"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The publicly visible display name of this person"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "displayname"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000040"
+      ]
+    }
+}"#;
+pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
+pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000041"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "mail addresses of the object"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "mail"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "unique": [
+        "true"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000041"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
+pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000042"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "alias": [
+        "sshpublickey"
+      ],
+      "description": [
+        "SSH public keys of the object, each tagged as \"tag: key\""
+      ],
+      "index": [],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "ssh_publickey"
+      ],
+      "syntax": [
+        "SSHKEY"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000042"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_PASSWORD: &'static str = "00000000-0000-0000-0000-ffff00000043";
+pub static JSON_SCHEMA_ATTR_PASSWORD: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000043"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "password hash material of the object for authentication"
+      ],
+      "index": [],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "password"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000043"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_GROUP_MANAGER: &'static str = "00000000-0000-0000-0000-ffff00000054";
+pub static JSON_SCHEMA_ATTR_GROUP_MANAGER: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000054"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The accounts or groups that can approve requests to join this group"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "group_manager"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000054"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_JOIN_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000055";
+pub static JSON_SCHEMA_ATTR_JOIN_GROUP: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000055"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The group a join request asks for membership of"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "join_group"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000055"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_JOIN_REQUESTER: &'static str = "00000000-0000-0000-0000-ffff00000056";
+pub static JSON_SCHEMA_ATTR_JOIN_REQUESTER: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000056"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The account asking to join a group"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "join_requester"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000056"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_PERSON: &'static str = "00000000-0000-0000-0000-ffff00000044";
+pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000044"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a person"
+      ],
+      "name": [
+        "person"
+      ],
+      "systemmay": [
+        "mail",
+        "memberof",
+        "tag"
+      ],
+      "systemmust": [
+        "displayname",
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000044"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000045";
+pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000045"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a group"
+      ],
+      "name": [
+        "group"
+      ],
+      "systemmay": [
+        "member",
+        "group_manager",
+        "tag"
+      ],
+      "systemmust": [
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000045"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_CLASS_ACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000046";
+pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000046"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a person"
+      ],
+      "name": [
+        "account"
+      ],
+      "systemmay": [
+        "password",
+        "ssh_publickey",
+        "radius_secret",
+        "tag",
+        "cert_mapping",
+        "break_glass_secret",
+        "break_glass_expiry",
+        "credential_reset_token",
+        "credential_reset_expiry",
+        "managed_by",
+        "account_valid_from",
+        "account_expire",
+        "account_expired"
+      ],
+      "systemmust": [
+        "displayname",
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000046"
+      ]
+    }
+  }
+"#;
+
+// A pending request from join_requester to become a member of join_group. The
+// references are not required, as refint removes them if either is deleted.
+pub static UUID_SCHEMA_CLASS_GROUP_JOIN_REQUEST: &'static str =
+    "00000000-0000-0000-0000-ffff00000057";
+pub static JSON_SCHEMA_CLASS_GROUP_JOIN_REQUEST: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000057"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A request to be added to a group, waiting on the group's managers"
+      ],
+      "name": [
+        "group_join_request"
+      ],
+      "systemmay": [
+        "join_group",
+        "join_requester"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000057"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_ANON_SEARCH_MAX_RESULTS: &'static str =
+    "00000000-0000-0000-0000-ffff00000062";
+pub static JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_RESULTS: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000062"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The most entries an anonymous search may return"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "anon_search_max_results"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000062"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_ANON_SEARCH_MAX_OPS: &'static str =
+    "00000000-0000-0000-0000-ffff00000063";
+pub static JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_OPS: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000063"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "How many anonymous searches the server allows a minute from each source"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "anon_search_max_ops"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000063"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_ANON_SEARCH_ALLOW_SUBSTRING: &'static str =
+    "00000000-0000-0000-0000-ffff00000064";
+pub static JSON_SCHEMA_ATTR_ANON_SEARCH_ALLOW_SUBSTRING: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000064"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If anonymous searches may use substring filters"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "anon_search_allow_substring"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000064"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_SYSTEM_CONFIG: &'static str = "00000000-0000-0000-0000-ffff00000065";
+pub static JSON_SCHEMA_CLASS_SYSTEM_CONFIG: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000065"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "System wide configuration"
+      ],
+      "name": [
+        "system_config"
+      ],
+      "systemmay": [
+        "anon_search_max_results",
+        "anon_search_max_ops",
+        "anon_search_allow_substring",
+        "log_level"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000065"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_TAG: &'static str = "00000000-0000-0000-0000-ffff00000066";
+pub static JSON_SCHEMA_ATTR_TAG: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000066"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Labels such as an environment or sensitivity, for access controls to match on"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "tag"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000066"
+      ]
+    }
+  }
+"#;
+
+// Which client certificates may authenticate as an account. Each value is
+// one of "sha256:<fingerprint>" for an exact certificate, "subject:<dn>",
+// with the dn escaped as in RFC 4514, or "san:<dns name or email>".
+pub static UUID_SCHEMA_ATTR_CERT_MAPPING: &'static str = "00000000-0000-0000-0000-ffff00000069";
+pub static JSON_SCHEMA_ATTR_CERT_MAPPING: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000069"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Client certificates that may authenticate as this account"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "cert_mapping"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000069"
+      ]
+    }
+  }
+"#;
+
+// The shared secret a host uses for its services, and when it runs out. The
+// secret is only readable by the host itself, see idm_host_acp_secret_read.
+pub static UUID_SCHEMA_ATTR_SERVICE_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000070";
+pub static JSON_SCHEMA_ATTR_SERVICE_SECRET: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000070"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The current service secret of a host"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "service_secret"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000070"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_SERVICE_SECRET_EXPIRY: &'static str =
+    "00000000-0000-0000-0000-ffff00000071";
+pub static JSON_SCHEMA_ATTR_SERVICE_SECRET_EXPIRY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000071"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "When the service secret of a host expires"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "service_secret_expiry"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000071"
+      ]
+    }
+  }
+"#;
+
+// A machine that authenticates as itself, and holds a service secret.
+pub static UUID_SCHEMA_CLASS_HOST: &'static str = "00000000-0000-0000-0000-ffff00000072";
+pub static JSON_SCHEMA_CLASS_HOST: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000072"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a host"
+      ],
+      "name": [
+        "host"
+      ],
+      "systemmay": [
+        "service_secret",
+        "service_secret_expiry"
+      ],
+      "systemmust": [
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000072"
+      ]
+    }
+  }
+"#;
+
+// A server this one pulls changes from, and how far it has got.
+pub static UUID_SCHEMA_ATTR_REPL_SUPPLIER: &'static str = "00000000-0000-0000-0000-ffff00000073";
+pub static JSON_SCHEMA_ATTR_REPL_SUPPLIER: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000073"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The url of the server a replication agreement pulls changes from"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "repl_supplier"
+      ],
+      "syntax": [
+        "URL"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000073"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_REPL_USER: &'static str = "00000000-0000-0000-0000-ffff00000074";
+pub static JSON_SCHEMA_ATTR_REPL_USER: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000074"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The uuid of the account a replication consumer acts as on its supplier"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "repl_user"
+      ],
+      "syntax": [
+        "UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000074"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_REPL_CSN: &'static str = "00000000-0000-0000-0000-ffff00000075";
+pub static JSON_SCHEMA_ATTR_REPL_CSN: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000075"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The csn a replication consumer had applied up to, before repl_seq replaced it"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "repl_csn"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000075"
+      ]
+    }
+  }
+"#;
+
+// How far through its supplier's changelog a replication consumer has
+// applied.
+pub static UUID_SCHEMA_ATTR_REPL_SEQ: &'static str = "00000000-0000-0000-0000-ffff00000098";
+pub static JSON_SCHEMA_ATTR_REPL_SEQ: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000098"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The position in its supplier's changelog a replication consumer has applied up to"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "repl_seq"
+      ],
+      "syntax": [
+        "INTEGER"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000098"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_REPLICATION_AGREEMENT: &'static str =
+    "00000000-0000-0000-0000-ffff00000076";
+pub static JSON_SCHEMA_CLASS_REPLICATION_AGREEMENT: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000076"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A supplier of changes this server replicates from"
+      ],
+      "name": [
+        "replication_agreement"
+      ],
+      "systemmay": [
+        "description",
+        "repl_seq"
+      ],
+      "systemmust": [
+        "name",
+        "repl_supplier",
+        "repl_user"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000076"
+      ]
+    }
+  }
+"#;
+
+// A secret that lets an account in without its usual credentials, until the
+// expiry. No access profile grants these, so they are only ever set by the
+// server itself, from the command line on the host.
+pub static UUID_SCHEMA_ATTR_BREAK_GLASS_SECRET: &'static str =
+    "00000000-0000-0000-0000-ffff00000077";
+pub static JSON_SCHEMA_ATTR_BREAK_GLASS_SECRET: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000077"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Hash of the emergency access secret of an account"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "break_glass_secret"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000077"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_BREAK_GLASS_EXPIRY: &'static str =
+    "00000000-0000-0000-0000-ffff00000078";
+pub static JSON_SCHEMA_ATTR_BREAK_GLASS_EXPIRY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000078"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "When the emergency access secret of an account, and sessions from it, expire"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "break_glass_expiry"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000078"
+      ]
+    }
+  }
+"#;
+
+// Values are "subsystem=level", such as "be=debug".
+pub static UUID_SCHEMA_ATTR_LOG_LEVEL: &'static str = "00000000-0000-0000-0000-ffff00000079";
+pub static JSON_SCHEMA_ATTR_LOG_LEVEL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000079"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The audit log level of a server subsystem"
+      ],
+      "index": [],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "log_level"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000079"
+      ]
+    }
+  }
+"#;
+
+// An account can't be authenticated as, or used by an existing session,
+// before account_valid_from or from account_expire on.
+pub static UUID_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &'static str =
+    "00000000-0000-0000-0000-ffff0000007a";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000007a"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "When the account may first be used"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "account_valid_from"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000007a"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_ACCOUNT_EXPIRE: &'static str = "00000000-0000-0000-0000-ffff0000007b";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000007b"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "When the account stops working"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "account_expire"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000007b"
+      ]
+    }
+  }
+"#;
+
+// Set by the server on accounts past their account_expire, so they can be
+// found and cleaned up.
+pub static UUID_SCHEMA_ATTR_ACCOUNT_EXPIRED: &'static str = "00000000-0000-0000-0000-ffff0000007c";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_EXPIRED: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000007c"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If the account has expired"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "account_expired"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000007c"
+      ]
+    }
+  }
+"#;
+
+// Values are "name=value", such as "backend_read_count=12". Nothing writes
+// these now, but stats entries from older releases are still read.
+pub static UUID_SCHEMA_ATTR_STAT: &'static str = "00000000-0000-0000-0000-ffff0000007e";
+pub static JSON_SCHEMA_ATTR_STAT: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000007e"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "An operation metric of the server"
+      ],
+      "index": [],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "stat"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000007e"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_SYSTEM_STATS: &'static str = "00000000-0000-0000-0000-ffff0000007f";
+pub static JSON_SCHEMA_CLASS_SYSTEM_STATS: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000007f"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Operation metrics of the server"
+      ],
+      "name": [
+        "system_stats"
+      ],
+      "systemmay": [
+        "stat"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000007f"
+      ]
+    }
+  }
+"#;
+
+// The secret an account uses with radius, such as for wifi with EAP. Radius
+// methods like MSCHAPv2 need the secret itself, so unlike a password it's
+// kept as it is, and is only readable by radius servers and the account.
+pub static UUID_SCHEMA_ATTR_RADIUS_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000085";
+pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000085"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The radius secret of an account"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "radius_secret"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000085"
+      ]
+    }
+  }
+"#;
+
+// The redirect uris an oauth2 resource server may send authorisation codes
+// to. A request naming any other is refused, so codes can't be sent to a
+// site that isn't the resource server.
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_REDIRECT_URI: &'static str =
+    "00000000-0000-0000-0000-ffff00000086";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_REDIRECT_URI: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000086"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A redirect uri of an oauth2 resource server"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "oauth2_rs_redirect_uri"
+      ],
+      "syntax": [
+        "URL"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000086"
+      ]
+    }
+  }
+"#;
+
+// Which scopes the members of a group may be given by an oauth2 resource
+// server, as "<group uuid> <scope> <scope> ...". An account with none can't
+// use the resource server at all.
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP: &'static str =
+    "00000000-0000-0000-0000-ffff00000087";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000087"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The scopes an oauth2 resource server gives the members of a group"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "oauth2_rs_scope_map"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000087"
+      ]
+    }
+  }
+"#;
+
+// The client secret of an oauth2 resource server. Its tokens are signed with
+// it, so like a radius secret it's kept as it is.
+pub static UUID_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET: &'static str =
+    "00000000-0000-0000-0000-ffff00000088";
+pub static JSON_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000088"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The client secret of an oauth2 resource server"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "oauth2_rs_basic_secret"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000088"
+      ]
+    }
+  }
+"#;
+
+// A relying party of the oauth2 and openid connect provider. Its name is the
+// client id.
+pub static UUID_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER: &'static str =
+    "00000000-0000-0000-0000-ffff00000089";
+pub static JSON_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000089"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of an oauth2 resource server"
+      ],
+      "name": [
+        "oauth2_resource_server"
+      ],
+      "systemmay": [
+        "displayname",
+        "oauth2_rs_scope_map"
+      ],
+      "systemmust": [
+        "name",
+        "oauth2_rs_redirect_uri",
+        "oauth2_rs_basic_secret"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000089"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_CREDENTIAL_RESET_TOKEN: &'static str =
+    "00000000-0000-0000-0000-ffff0000008a";
+pub static JSON_SCHEMA_ATTR_CREDENTIAL_RESET_TOKEN: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000008a"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Hash of the one time token that may set the credentials of an account"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "credential_reset_token"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000008a"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_ATTR_CREDENTIAL_RESET_EXPIRY: &'static str =
+    "00000000-0000-0000-0000-ffff0000008b";
+pub static JSON_SCHEMA_ATTR_CREDENTIAL_RESET_EXPIRY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff0000008b"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "When the credential reset token of an account expires"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "credential_reset_expiry"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000008b"
+      ]
+    }
+  }
+"#;
 
-pub static _UUID_IDM_ADMINS_ACP_REVIVE_V1: &'static str = "00000000-0000-0000-0000-ffffff000003";
-pub static JSON_IDM_ADMINS_ACP_REVIVE_V1: &'static str = r#"{
+pub static UUID_SCHEMA_ATTR_DYNGROUP_FILTER: &'static str = "00000000-0000-0000-0000-ffff0000008c";
+pub static JSON_SCHEMA_ATTR_DYNGROUP_FILTER: &'static str = r#"
+  {
     "valid": {
-        "uuid": "00000000-0000-0000-0000-ffffff000003"
+      "uuid": "00000000-0000-0000-0000-ffff0000008c"
     },
     "state": null,
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_modify"],
-        "name": ["idm_admins_acp_revive"],
-        "uuid": ["00000000-0000-0000-0000-ffffff000003"],
-        "description": ["Builtin IDM Administrators Access Controls."],
-        "acp_enable": ["true"],
-        "acp_receiver": [
-            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
-        ],
-        "acp_targetscope": [
-            "{\"Eq\":[\"class\",\"recycled\"]}"
-        ],
-        "acp_modify_removedattr": ["class"],
-        "acp_modify_class": ["recycled"]
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The filter whose matching entries are the members of a dynamic group"
+      ],
+      "index": [],
+      "multivalue": [
+        "false"
+      ],
+      "name": [
+        "dyngroup_filter"
+      ],
+      "syntax": [
+        "JSON_FILTER"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000008c"
+      ]
     }
-}"#;
+  }
+"#;
 
-pub static _UUID_IDM_SELF_ACP_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000004";
-pub static JSON_IDM_SELF_ACP_READ_V1: &'static str = r#"{
+pub static JSON_SCHEMA_ATTR_MANAGED_BY: &'static str = r#"
+  {
     "valid": {
-        "uuid": "00000000-0000-0000-0000-ffffff000004"
+      "uuid": "00000000-0000-0000-0000-ffff0000008e"
     },
     "state": null,
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_search"],
-        "name": ["idm_self_acp_read"],
-        "uuid": ["00000000-0000-0000-0000-ffffff000004"],
-        "description": ["Builtin IDM Control for self read - required for whoami."],
-        "acp_enable": ["true"],
-        "acp_receiver": [
-            "\"Self\""
-        ],
-        "acp_targetscope": [
-            "\"Self\""
-        ],
-        "acp_search_attr": ["name", "uuid"]
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The accounts responsible for this account, such as its manager"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "name": [
+        "managed_by"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000008e"
+      ]
     }
-}"#;
-
-pub static UUID_DOES_NOT_EXIST: &'static str = "00000000-0000-0000-0000-fffffffffffe";
+  }
+"#;
 
-pub static UUID_ANONYMOUS: &'static str = "00000000-0000-0000-0000-ffffffffffff";
-pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+pub static UUID_SCHEMA_CLASS_DYNGROUP: &'static str = "00000000-0000-0000-0000-ffff0000008d";
+pub static JSON_SCHEMA_CLASS_DYNGROUP: &'static str = r#"
+  {
     "valid": {
-        "uuid": "00000000-0000-0000-0000-ffffffffffff"
+      "uuid": "00000000-0000-0000-0000-ffff0000008d"
     },
     "state": null,
     "attrs": {
-        "class": ["account", "object"],
-        "name": ["anonymous"],
-        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
-        "description": ["Anonymous access account."],
-        "displayname": ["Anonymous"]
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "A group whose members are the entries matching a filter"
+      ],
+      "name": [
+        "dyngroup"
+      ],
+      "systemmust": [
+        "dyngroup_filter"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff0000008d"
+      ]
     }
-}"#;
-
-// Core
-// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
-pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
-pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
-pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
-pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
-pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
-pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
-pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
-pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
-pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
-pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
-pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
-pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
-pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
-pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
-pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
-pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
-pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
-pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
-pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
-pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
-pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000023";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000024";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
-
-pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
-pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
-pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
-pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
-    "00000000-0000-0000-0000-ffff00000029";
-pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
-
-pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
-pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
-pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
-    "00000000-0000-0000-0000-ffff00000034";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
-    "00000000-0000-0000-0000-ffff00000035";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
-    "00000000-0000-0000-0000-ffff00000036";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
-    "00000000-0000-0000-0000-ffff00000037";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
-    "00000000-0000-0000-0000-ffff00000038";
-pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+  }
+"#;
 
-// system supplementary
-pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
-pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
+pub static JSON_SCHEMA_ATTR_TEMPLATE_FILTER: &'static str = r#"
+  {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000040"
+      "uuid": "00000000-0000-0000-0000-ffff0000008f"
     },
     "state": null,
     "attrs": {
@@ -201,30 +2462,29 @@ pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
         "attributetype"
       ],
       "description": [
-        "The publicly visible display name of this person"
-      ],
-      "index": [
-        "EQUALITY"
+        "The filter matching the entries an entry template keeps companions for"
       ],
+      "index": [],
       "multivalue": [
         "false"
       ],
       "name": [
-        "displayname"
+        "template_filter"
       ],
       "syntax": [
-        "UTF8STRING"
+        "JSON_FILTER"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000040"
+        "00000000-0000-0000-0000-ffff0000008f"
       ]
     }
-}"#;
-pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
-pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
+  }
+"#;
+
+pub static JSON_SCHEMA_ATTR_TEMPLATE_CLASS: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000041"
+      "uuid": "00000000-0000-0000-0000-ffff00000090"
     },
     "state": null,
     "attrs": {
@@ -234,31 +2494,29 @@ pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
         "attributetype"
       ],
       "description": [
-        "mail addresses of the object"
-      ],
-      "index": [
-        "EQUALITY"
+        "The classes of the companions of an entry template"
       ],
+      "index": [],
       "multivalue": [
         "true"
       ],
       "name": [
-        "mail"
+        "template_class"
       ],
       "syntax": [
-        "UTF8STRING"
+        "UTF8STRING_INSENSITIVE"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000041"
+        "00000000-0000-0000-0000-ffff00000090"
       ]
     }
   }
 "#;
-pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
-pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
+
+pub static JSON_SCHEMA_ATTR_TEMPLATE_LINK: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000042"
+      "uuid": "00000000-0000-0000-0000-ffff00000091"
     },
     "state": null,
     "attrs": {
@@ -268,29 +2526,29 @@ pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
         "attributetype"
       ],
       "description": [
-        "SSH public keys of the object"
+        "The attributes the companions of an entry template copy from their origin"
       ],
       "index": [],
       "multivalue": [
         "true"
       ],
       "name": [
-        "ssh_publickey"
+        "template_link"
       ],
       "syntax": [
-        "UTF8STRING"
+        "UTF8STRING_INSENSITIVE"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000042"
+        "00000000-0000-0000-0000-ffff00000091"
       ]
     }
   }
 "#;
-pub static UUID_SCHEMA_ATTR_PASSWORD: &'static str = "00000000-0000-0000-0000-ffff00000043";
-pub static JSON_SCHEMA_ATTR_PASSWORD: &'static str = r#"
+
+pub static JSON_SCHEMA_ATTR_MANAGED_ORIGIN: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000043"
+      "uuid": "00000000-0000-0000-0000-ffff00000092"
     },
     "state": null,
     "attrs": {
@@ -300,64 +2558,65 @@ pub static JSON_SCHEMA_ATTR_PASSWORD: &'static str = r#"
         "attributetype"
       ],
       "description": [
-        "password hash material of the object for authentication"
+        "The entry a managed entry is kept for"
+      ],
+      "index": [
+        "EQUALITY"
       ],
-      "index": [],
       "multivalue": [
-        "true"
+        "false"
       ],
       "name": [
-        "password"
+        "managed_origin"
       ],
       "syntax": [
-        "UTF8STRING"
+        "REFERENCE_UUID"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000043"
+        "00000000-0000-0000-0000-ffff00000092"
       ]
     }
   }
 "#;
 
-pub static UUID_SCHEMA_CLASS_PERSON: &'static str = "00000000-0000-0000-0000-ffff00000044";
-pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
+pub static JSON_SCHEMA_ATTR_MANAGED_TEMPLATE: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000044"
+      "uuid": "00000000-0000-0000-0000-ffff00000093"
     },
     "state": null,
     "attrs": {
       "class": [
         "object",
         "system",
-        "classtype"
+        "attributetype"
       ],
       "description": [
-        "Object representation of a person"
+        "The entry template a managed entry is kept by"
       ],
-      "name": [
-        "person"
+      "index": [
+        "EQUALITY"
       ],
-      "systemmay": [
-        "mail",
-        "memberof"
+      "multivalue": [
+        "false"
       ],
-      "systemmust": [
-        "displayname",
-        "name"
+      "name": [
+        "managed_template"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000044"
+        "00000000-0000-0000-0000-ffff00000093"
       ]
     }
   }
 "#;
 
-pub static UUID_SCHEMA_CLASS_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000045";
-pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
+pub static JSON_SCHEMA_CLASS_ENTRY_TEMPLATE: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000045"
+      "uuid": "00000000-0000-0000-0000-ffff00000094"
     },
     "state": null,
     "attrs": {
@@ -367,28 +2626,30 @@ pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
         "classtype"
       ],
       "description": [
-        "Object representation of a group"
+        "A description of companion entries to keep for each entry matching a filter"
       ],
       "name": [
-        "group"
+        "entry_template"
       ],
       "systemmay": [
-        "member"
+        "template_link"
       ],
       "systemmust": [
-        "name"
+        "name",
+        "template_filter",
+        "template_class"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000045"
+        "00000000-0000-0000-0000-ffff00000094"
       ]
     }
   }
 "#;
-pub static UUID_SCHEMA_CLASS_ACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000046";
-pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
+
+pub static JSON_SCHEMA_CLASS_MANAGED_ENTRY: &'static str = r#"
   {
     "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000046"
+      "uuid": "00000000-0000-0000-0000-ffff00000095"
     },
     "state": null,
     "attrs": {
@@ -398,21 +2659,17 @@ pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
         "classtype"
       ],
       "description": [
-        "Object representation of a person"
+        "A companion entry kept by an entry template for its origin"
       ],
       "name": [
-        "account"
-      ],
-      "systemmay": [
-        "password",
-        "ssh_publickey"
+        "managed_entry"
       ],
       "systemmust": [
-        "displayname",
-        "name"
+        "managed_origin",
+        "managed_template"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000046"
+        "00000000-0000-0000-0000-ffff00000095"
       ]
     }
   }
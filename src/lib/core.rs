@@ -2,35 +2,296 @@
 use actix::Actor;
 use actix_web::middleware::session::{self, RequestSession};
 use actix_web::{
-    error, http, middleware, App, Error, HttpMessage, HttpRequest, HttpResponse, Result, State,
+    error, http, middleware, App, Error, Form, HttpMessage, HttpRequest, HttpResponse, Path, Query,
+    Result, State,
 };
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::{future, Future, Stream};
+use serde::de::{DeserializeOwned, Deserializer, IgnoredAny, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use time::Duration;
+use url::percent_encoding::percent_decode;
 
-use crate::config::Configuration;
+use crate::config::{Configuration, DbKeySource, DbSync};
 
 // SearchResult
+use crate::anomaly::AnomalyThresholds;
 use crate::async_log;
 use crate::audit::AuditScope;
+use crate::be::key::{DbKey, DbKeyFile, DbKeyProvider};
 use crate::be::{Backend, BackendTransaction};
 use crate::error::OperationError;
+use crate::filter::FilterLimits;
+use crate::idm::clientcert::ClientCertVerifier;
 use crate::interval::IntervalActor;
+use crate::ldap;
+use crate::metrics;
 use crate::proto::v1::actors::QueryServerV1;
-use crate::proto::v1::messages::{AuthMessage, WhoamiMessage};
+use crate::proto::v1::messages::{
+    AuthMessage, Oauth2AuthoriseMessage, RequestMessage, ScimMessage, SshKeysMessage,
+    SubscribeMessage, WhoamiMessage,
+};
 use crate::proto::v1::{
-    AuthRequest, AuthState, CreateRequest, DeleteRequest, ModifyRequest, SearchRequest,
-    UserAuthToken,
+    AcpCoverageRequest, AuditLogRequest, AuthRequest, AuthState, BackupRequest, BatchRequest,
+    CompareRequest, CreateRequest, CredentialResetIssueRequest, CredentialResetRedeemRequest,
+    DeletePreviewRequest, DeleteRequest, EffectivePermissionsRequest, GroupJoinCreateRequest,
+    GroupJoinDecideRequest, GroupJoinListRequest, HostSecretRotateRequest, LogLevelRequest,
+    MemoryReportRequest, ModifyRequest, Oauth2AuthoriseRequest, Oauth2ErrorResponse,
+    Oauth2TokenRequest, RadiusSecretReadRequest, RadiusSecretRegenerateRequest, RenameRequest,
+    ReplChangesRequest, ReviveRecycledRequest, SearchRecycledRequest, SearchRequest,
+    SubscribeRequest, SyncRequest, TypeaheadRequest, UserAuthToken,
 };
 use crate::schema::Schema;
+use crate::scim::{self, ScimOp, ScimResourceType};
 use crate::server::QueryServer;
 
 use uuid::Uuid;
 
 struct AppState {
     qe: actix::Addr<QueryServerV1>,
-    max_size: usize,
+    limits: RequestLimits,
+    strict: bool,
+    client_cert: Option<ClientCertHeader>,
+}
+
+// TLS is terminated by a proxy in front of us, which verifies the client's
+// certificate and passes it on, url encoded, in this header. A client could
+// set the header itself, so it's only believed from the proxies.
+#[derive(Debug, Clone)]
+struct ClientCertHeader {
+    name: String,
+    proxies: Vec<IpAddr>,
+}
+
+impl ClientCertHeader {
+    fn extract(&self, req: &HttpRequest<AppState>) -> Option<Vec<u8>> {
+        let peer = req.peer_addr()?;
+        if !self.proxies.contains(&peer.ip()) {
+            return None;
+        }
+        let value = req.headers().get(self.name.as_str())?;
+        Some(percent_decode(value.as_bytes()).collect())
+    }
+}
+
+// How large a request may be. These are checked as the request is read and
+// decoded, so an oversized one is refused before it costs more memory, or
+// reaches the server at all.
+#[derive(Debug, Clone, Copy)]
+struct RequestLimits {
+    max_bytes: usize,
+    max_entries: usize,
+    max_modlist: usize,
+}
+
+// The parts of a request that are counted against the limits. These are read
+// from the raw body before it's decoded, so only the array lengths are kept,
+// and everything else is skipped over without being built.
+struct Count(usize);
+
+impl<'de> Deserialize<'de> for Count {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct CountVisitor;
+
+        impl<'de> Visitor<'de> for CountVisitor {
+            type Value = Count;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<Count, A::Error> {
+                let mut n = 0;
+                while seq.next_element::<IgnoredAny>()?.is_some() {
+                    n += 1;
+                }
+                Ok(Count(n))
+            }
+        }
+
+        d.deserialize_seq(CountVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateShape {
+    entries: Count,
+}
+
+#[derive(Deserialize)]
+struct ModifyListShape {
+    mods: Count,
+}
+
+#[derive(Deserialize)]
+struct ModifyShape {
+    modlist: ModifyListShape,
+}
+
+#[derive(Deserialize)]
+enum BatchOperationShape {
+    Create(Count),
+    Modify(IgnoredAny, ModifyListShape),
+    ModifyUuid(IgnoredAny, ModifyListShape),
+    Delete(IgnoredAny),
+}
+
+#[derive(Deserialize)]
+struct BatchShape {
+    operations: Vec<BatchOperationShape>,
+}
+
+// A body that doesn't have the expected shape is let through, and is refused
+// by the full decode instead.
+fn read_shape<T: DeserializeOwned>(body: &[u8]) -> Option<T> {
+    serde_json::from_slice(body).ok()
+}
+
+fn check_entries(n: usize, limits: &RequestLimits) -> std::result::Result<(), OperationError> {
+    if n > limits.max_entries {
+        Err(OperationError::TooManyEntries(limits.max_entries))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_modlist(n: usize, limits: &RequestLimits) -> std::result::Result<(), OperationError> {
+    if n > limits.max_modlist {
+        Err(OperationError::ModlistTooLong(limits.max_modlist))
+    } else {
+        Ok(())
+    }
+}
+
+trait LimitedRequest {
+    // Most requests have nothing in them to count.
+    fn check_limits(
+        _body: &[u8],
+        _limits: &RequestLimits,
+    ) -> std::result::Result<(), OperationError> {
+        Ok(())
+    }
+}
+
+impl LimitedRequest for CreateRequest {
+    fn check_limits(
+        body: &[u8],
+        limits: &RequestLimits,
+    ) -> std::result::Result<(), OperationError> {
+        match read_shape::<CreateShape>(body) {
+            Some(s) => check_entries(s.entries.0, limits),
+            None => Ok(()),
+        }
+    }
+}
+
+impl LimitedRequest for ModifyRequest {
+    fn check_limits(
+        body: &[u8],
+        limits: &RequestLimits,
+    ) -> std::result::Result<(), OperationError> {
+        match read_shape::<ModifyShape>(body) {
+            Some(s) => check_modlist(s.modlist.mods.0, limits),
+            None => Ok(()),
+        }
+    }
+}
+
+// A batch is held to the same limits as the requests it could be split into,
+// with each operation counting as an entry.
+impl LimitedRequest for BatchRequest {
+    fn check_limits(
+        body: &[u8],
+        limits: &RequestLimits,
+    ) -> std::result::Result<(), OperationError> {
+        let shape = match read_shape::<BatchShape>(body) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let entries: usize = shape
+            .operations
+            .iter()
+            .map(|op| match op {
+                BatchOperationShape::Create(entries) => entries.0,
+                _ => 1,
+            })
+            .sum();
+        check_entries(entries, limits)?;
+        shape.operations.iter().try_for_each(|op| match op {
+            BatchOperationShape::Modify(_, ml) | BatchOperationShape::ModifyUuid(_, ml) => {
+                check_modlist(ml.mods.0, limits)
+            }
+            _ => Ok(()),
+        })
+    }
+}
+
+impl LimitedRequest for DeleteRequest {}
+impl LimitedRequest for RenameRequest {}
+impl LimitedRequest for HostSecretRotateRequest {}
+impl LimitedRequest for RadiusSecretRegenerateRequest {}
+impl LimitedRequest for RadiusSecretReadRequest {}
+impl LimitedRequest for CredentialResetIssueRequest {}
+impl LimitedRequest for CredentialResetRedeemRequest {}
+impl LimitedRequest for DeletePreviewRequest {}
+impl LimitedRequest for SearchRequest {}
+impl LimitedRequest for SearchRecycledRequest {}
+impl LimitedRequest for TypeaheadRequest {}
+impl LimitedRequest for ReviveRecycledRequest {}
+impl LimitedRequest for EffectivePermissionsRequest {}
+impl LimitedRequest for CompareRequest {}
+impl LimitedRequest for SyncRequest {}
+impl LimitedRequest for SubscribeRequest {}
+impl LimitedRequest for ReplChangesRequest {}
+impl LimitedRequest for MemoryReportRequest {}
+impl LimitedRequest for LogLevelRequest {}
+impl LimitedRequest for AcpCoverageRequest {}
+impl LimitedRequest for BackupRequest {}
+impl LimitedRequest for AuditLogRequest {}
+impl LimitedRequest for GroupJoinCreateRequest {}
+impl LimitedRequest for GroupJoinListRequest {}
+impl LimitedRequest for GroupJoinDecideRequest {}
+impl LimitedRequest for AuthRequest {}
+
+// Refuse a request, with the reason in the body as for any other failure.
+fn reject_request(status: http::StatusCode, e: OperationError) -> Error {
+    let cause = format!("{:?}", e);
+    error::InternalError::from_response(cause, HttpResponse::build(status).json(e)).into()
+}
+
+// Add a chunk of the request body, unless it would go over the limit.
+fn read_chunk(
+    mut body: BytesMut,
+    chunk: &[u8],
+    limits: &RequestLimits,
+) -> std::result::Result<BytesMut, Error> {
+    if (body.len() + chunk.len()) > limits.max_bytes {
+        Err(reject_request(
+            http::StatusCode::PAYLOAD_TOO_LARGE,
+            OperationError::RequestTooLarge(limits.max_bytes),
+        ))
+    } else {
+        body.extend_from_slice(chunk);
+        Ok(body)
+    }
+}
+
+// Where a request came from. Only the address, as the port changes with each
+// connection.
+fn request_source(req: &HttpRequest<AppState>) -> Option<String> {
+    req.connection_info().remote().map(|r| {
+        r.parse::<SocketAddr>()
+            .map(|a| a.ip().to_string())
+            .unwrap_or_else(|_| r.to_string())
+    })
 }
 
 fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
@@ -43,12 +304,44 @@ fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
     }
 }
 
+// Decode a request body. When strict, any field in the body that the request
+// type doesn't define is an error, so a typo like "attr" for "attrs" is reported
+// rather than silently dropped.
+fn decode_request<T>(
+    body: &[u8],
+    strict: bool,
+    limits: &RequestLimits,
+) -> std::result::Result<T, Error>
+where
+    T: DeserializeOwned + LimitedRequest,
+{
+    T::check_limits(body, limits)
+        .map_err(|e| reject_request(http::StatusCode::PAYLOAD_TOO_LARGE, e))?;
+    let mut unknown = Vec::new();
+    let mut de = serde_json::Deserializer::from_slice(body);
+    let obj: T = serde_ignored::deserialize(&mut de, |path| unknown.push(path.to_string()))
+        .and_then(|obj| de.end().map(|_| obj))
+        .map_err(|e| error::ErrorBadRequest(format!("Json Decode Failed: {:?}", e)))?;
+    if strict && !unknown.is_empty() {
+        return Err(error::ErrorBadRequest(format!(
+            "Unknown fields in request: {:?}",
+            unknown
+        )));
+    }
+    Ok(obj)
+}
+
+// Every response carries the id of the request, which is also recorded in
+// the audit log for the operation.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
 macro_rules! json_event_post {
     ($req:expr, $state:expr, $event_type:ty, $message_type:ty) => {{
         // This is copied every request. Is there a better way?
         // The issue is the fold move takes ownership of state if
         // we don't copy this here
-        let max_size = $state.max_size;
+        let limits = $state.limits;
+        let source = request_source(&$req);
 
         // HttpRequest::payload() is stream of Bytes objects
         $req.payload()
@@ -57,46 +350,44 @@ macro_rules! json_event_post {
             .from_err()
             // `fold` will asynchronously read each chunk of the request body and
             // call supplied closure, then it resolves to result of closure
-            .fold(BytesMut::new(), move |mut body, chunk| {
+            .fold(BytesMut::new(), move |body, chunk| {
                 // limit max size of in-memory payload
-                if (body.len() + chunk.len()) > max_size {
-                    Err(error::ErrorBadRequest("overflow"))
-                } else {
-                    body.extend_from_slice(&chunk);
-                    Ok(body)
-                }
+                read_chunk(body, &chunk, &limits)
             })
             // `Future::and_then` can be used to merge an asynchronous workflow with a
             // synchronous workflow
             .and_then(
-                move |body| -> Box<Future<Item = HttpResponse, Error = Error>> {
+                move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
                     // body is loaded, now we can deserialize serde-json
                     // let r_obj = serde_json::from_slice::<SearchRequest>(&body);
-                    let r_obj = serde_json::from_slice::<$message_type>(&body);
+                    let r_obj = decode_request::<$message_type>(&body, $state.strict, &limits);
 
                     // Send to the db for handling
                     match r_obj {
                         Ok(obj) => {
+                            let request_id = Uuid::new_v4();
+                            let rid = request_id.to_hyphenated().to_string();
                             let res = $state
                                 .qe
                                 .send(
                                     // Could make this a .into_inner() and move?
                                     // event::SearchEvent::new(obj.filter),
                                     // <($event_type)>::from_request(obj),
-                                    obj,
+                                    RequestMessage::new(request_id, obj).with_source(source),
                                 )
                                 .from_err()
-                                .and_then(|res| match res {
-                                    Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
-                                    Err(e) => Ok(HttpResponse::InternalServerError().json(e)),
+                                .and_then(move |res| match res {
+                                    Ok(event_result) => Ok(HttpResponse::Ok()
+                                        .header(REQUEST_ID_HEADER, rid)
+                                        .json(event_result)),
+                                    Err(e) => Ok(HttpResponse::InternalServerError()
+                                        .header(REQUEST_ID_HEADER, rid)
+                                        .json(e)),
                                 });
 
                             Box::new(res)
                         }
-                        Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
-                            "Json Decode Failed: {:?}",
-                            e
-                        )))),
+                        Err(e) => Box::new(future::err(e)),
                     }
                 },
             )
@@ -112,14 +403,26 @@ macro_rules! json_event_get {
 
         // New event, feed current auth data from the token to it.
         let obj = <($message_type)>::new(uat);
+        let request_id = Uuid::new_v4();
+        let rid = request_id.to_hyphenated().to_string();
 
-        let res = $state.qe.send(obj).from_err().and_then(|res| match res {
-            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
-            Err(e) => match e {
-                OperationError::NotAuthenticated => Ok(HttpResponse::Unauthorized().json(e)),
-                _ => Ok(HttpResponse::InternalServerError().json(e)),
-            },
-        });
+        let res = $state
+            .qe
+            .send(RequestMessage::new(request_id, obj))
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(event_result) => Ok(HttpResponse::Ok()
+                    .header(REQUEST_ID_HEADER, rid)
+                    .json(event_result)),
+                Err(e) => match e {
+                    OperationError::NotAuthenticated => Ok(HttpResponse::Unauthorized()
+                        .header(REQUEST_ID_HEADER, rid)
+                        .json(e)),
+                    _ => Ok(HttpResponse::InternalServerError()
+                        .header(REQUEST_ID_HEADER, rid)
+                        .json(e)),
+                },
+            });
 
         Box::new(res)
     }};
@@ -151,6 +454,159 @@ fn search(
     json_event_post!(req, state, SearchEvent, SearchRequest)
 }
 
+fn typeahead(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, TypeaheadEvent, TypeaheadRequest)
+}
+
+fn search_recycled(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, SearchEvent, SearchRecycledRequest)
+}
+
+fn revive_recycled(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ReviveRecycledEvent, ReviveRecycledRequest)
+}
+
+fn effective_permissions(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        EffectivePermissionsEvent,
+        EffectivePermissionsRequest
+    )
+}
+
+fn rename(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, RenameEvent, RenameRequest)
+}
+
+fn host_secret_rotate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, HostSecretRotateEvent, HostSecretRotateRequest)
+}
+
+fn radius_secret_regenerate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        RadiusSecretRegenerateEvent,
+        RadiusSecretRegenerateRequest
+    )
+}
+
+fn radius_secret_read(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, RadiusSecretReadEvent, RadiusSecretReadRequest)
+}
+
+fn credential_reset_issue(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        CredentialResetIssueEvent,
+        CredentialResetIssueRequest
+    )
+}
+
+fn credential_reset_redeem(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ModifyEvent, CredentialResetRedeemRequest)
+}
+
+fn compare(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, CompareEvent, CompareRequest)
+}
+
+fn batch(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, BatchEvent, BatchRequest)
+}
+
+fn delete_preview(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, DeletePreviewEvent, DeletePreviewRequest)
+}
+
+fn repl_changes(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, ReplChangesEvent, ReplChangesRequest)
+}
+
+fn memory_report(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, MemoryReportEvent, MemoryReportRequest)
+}
+
+fn log_level(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, LogLevelEvent, LogLevelRequest)
+}
+
+fn acp_coverage(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, AcpCoverageEvent, AcpCoverageRequest)
+}
+
+fn backup(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, BackupEvent, BackupRequest)
+}
+
+fn audit_log(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, AuditLogEvent, AuditLogRequest)
+}
+
+fn sync(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, SyncEvent, SyncRequest)
+}
+
+fn group_join_create(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, GroupJoinCreateEvent, GroupJoinCreateRequest)
+}
+
+fn group_join_list(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, GroupJoinListEvent, GroupJoinListRequest)
+}
+
+fn group_join_decide(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, GroupJoinDecideEvent, GroupJoinDecideRequest)
+}
+
 fn whoami(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
@@ -158,28 +614,389 @@ fn whoami(
     json_event_get!(req, state, WhoamiEvent, WhoamiMessage)
 }
 
+// An account's ssh public keys in authorized_keys form, one per line, for
+// sshd's AuthorizedKeysCommand to use as it is. The caller needs a session,
+// such as a host's, that may read the keys.
+fn ssh_keys(
+    (req, state, name): (HttpRequest<AppState>, State<AppState>, Path<String>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let request_id = Uuid::new_v4();
+    let rid = request_id.to_hyphenated().to_string();
+
+    state
+        .qe
+        .send(RequestMessage::new(
+            request_id,
+            SshKeysMessage::new(uat, name.into_inner()),
+        ))
+        .from_err()
+        .and_then(move |res| {
+            let mut resp = match &res {
+                Ok(_) => HttpResponse::Ok(),
+                Err(OperationError::NotAuthenticated) => HttpResponse::Unauthorized(),
+                Err(OperationError::NoMatchingEntries) => HttpResponse::NotFound(),
+                Err(_) => HttpResponse::InternalServerError(),
+            };
+            let body = match res {
+                Ok(keys) => keys.iter().map(|k| format!("{}\n", k)).collect::<String>(),
+                Err(e) => format!("{:?}\n", e),
+            };
+            Ok(resp
+                .header(REQUEST_ID_HEADER, rid)
+                .content_type("text/plain")
+                .body(body))
+        })
+}
+
+// Oauth2 errors are given as rfc6749 5.2 has them, rather than as the
+// operation error.
+fn oauth2_error_response(e: OperationError, rid: String) -> HttpResponse {
+    match e {
+        OperationError::Oauth2(code) => {
+            let mut resp = if code == "invalid_client" {
+                HttpResponse::Unauthorized()
+            } else {
+                HttpResponse::BadRequest()
+            };
+            resp.header(REQUEST_ID_HEADER, rid)
+                .json(Oauth2ErrorResponse {
+                    error: code.to_string(),
+                })
+        }
+        OperationError::NotAuthenticated => HttpResponse::Unauthorized()
+            .header(REQUEST_ID_HEADER, rid)
+            .json(e),
+        _ => HttpResponse::InternalServerError()
+            .header(REQUEST_ID_HEADER, rid)
+            .json(e),
+    }
+}
+
+// The authorisation endpoint, rfc6749 3.1. The browser needs a session from
+// /v1/auth, and is redirected back to the resource server with a code.
+fn oauth2_authorise(
+    (req, state, query): (
+        HttpRequest<AppState>,
+        State<AppState>,
+        Query<Oauth2AuthoriseRequest>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let uat = get_current_user(&req);
+    let request_id = Uuid::new_v4();
+    let rid = request_id.to_hyphenated().to_string();
+
+    state
+        .qe
+        .send(RequestMessage::new(
+            request_id,
+            Oauth2AuthoriseMessage::new(uat, query.into_inner()),
+        ))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(redirect) => Ok(HttpResponse::Found()
+                .header(REQUEST_ID_HEADER, rid)
+                .header(http::header::LOCATION, redirect)
+                .finish()),
+            Err(e) => Ok(oauth2_error_response(e, rid)),
+        })
+}
+
+// The client id and secret from http basic authentication, rfc6749 2.3.1.
+// Each is form encoded before they are joined.
+fn oauth2_basic_auth(req: &HttpRequest<AppState>) -> Option<(String, String)> {
+    let v = req
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    if !v.starts_with("Basic ") {
+        return None;
+    }
+    let decoded = openssl::base64::decode_block(v["Basic ".len()..].trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':').map(|p| {
+        percent_decode(p.replace('+', " ").as_bytes())
+            .decode_utf8()
+            .map(|s| s.to_string())
+    });
+    match (parts.next(), parts.next()) {
+        (Some(Ok(id)), Some(Ok(secret))) => Some((id, secret)),
+        _ => None,
+    }
+}
+
+// The token endpoint, rfc6749 3.2, which resource servers exchange codes at.
+fn oauth2_token(
+    (req, state, form): (
+        HttpRequest<AppState>,
+        State<AppState>,
+        Form<Oauth2TokenRequest>,
+    ),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let mut token_req = form.into_inner();
+    if let Some((id, secret)) = oauth2_basic_auth(&req) {
+        token_req.client_id = Some(id);
+        token_req.client_secret = Some(secret);
+    }
+    let request_id = Uuid::new_v4();
+    let rid = request_id.to_hyphenated().to_string();
+
+    state
+        .qe
+        .send(RequestMessage::new(request_id, token_req))
+        .from_err()
+        .and_then(move |res| match res {
+            // Tokens must never be cached, rfc6749 5.1.
+            Ok(tr) => Ok(HttpResponse::Ok()
+                .header(REQUEST_ID_HEADER, rid)
+                .header(http::header::CACHE_CONTROL, "no-store")
+                .header(http::header::PRAGMA, "no-cache")
+                .json(tr)),
+            Err(e) => Ok(oauth2_error_response(e, rid)),
+        })
+}
+
+// A persistent search. The response doesn't end: each notification is sent
+// as a line of json as it happens, until the client goes away.
+fn subscribe(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let limits = state.limits;
+    let uat = get_current_user(&req);
+
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |body, chunk| {
+            read_chunk(body, &chunk, &limits)
+        })
+        .and_then(
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let obj = match decode_request::<SubscribeRequest>(&body, state.strict, &limits) {
+                    Ok(obj) => obj,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                let request_id = Uuid::new_v4();
+                let rid = request_id.to_hyphenated().to_string();
+                let res = state
+                    .qe
+                    .send(RequestMessage::new(
+                        request_id,
+                        SubscribeMessage::new(uat, obj),
+                    ))
+                    .from_err()
+                    .and_then(move |res| match res {
+                        Ok(rx) => {
+                            let lines = rx
+                                .map(|n| {
+                                    let mut line = serde_json::to_vec(&n).unwrap_or_default();
+                                    line.push(b'\n');
+                                    Bytes::from(line)
+                                })
+                                .map_err(|_| error::ErrorInternalServerError("subscription ended"));
+                            Ok(HttpResponse::Ok()
+                                .header(REQUEST_ID_HEADER, rid)
+                                .content_type("application/x-ndjson")
+                                .streaming(lines))
+                        }
+                        Err(OperationError::NotAuthenticated) => Ok(HttpResponse::Unauthorized()
+                            .header(REQUEST_ID_HEADER, rid)
+                            .json(OperationError::NotAuthenticated)),
+                        Err(e) => Ok(HttpResponse::InternalServerError()
+                            .header(REQUEST_ID_HEADER, rid)
+                            .json(e)),
+                    });
+                Box::new(res)
+            },
+        )
+}
+
+// SCIM responses and errors are both application/scim+json, rfc7644 3.1.
+fn scim_response(
+    rid: String,
+    ok: http::StatusCode,
+    res: std::result::Result<Option<serde_json::Value>, OperationError>,
+) -> HttpResponse {
+    let (status, body) = match res {
+        Ok(body) => (ok, body),
+        Err(e) => {
+            let (status, body) = scim::error_response(&e);
+            (
+                http::StatusCode::from_u16(status)
+                    .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR),
+                Some(body),
+            )
+        }
+    };
+    let mut resp = HttpResponse::build(status);
+    resp.header(REQUEST_ID_HEADER, rid)
+        .content_type("application/scim+json");
+    match body {
+        Some(body) => resp.body(body.to_string()),
+        None => resp.finish(),
+    }
+}
+
+// Send a request on the Users or Groups endpoint, as the holder of the
+// session.
+fn scim_send(
+    req: &HttpRequest<AppState>,
+    state: &State<AppState>,
+    resource: &str,
+    ok: http::StatusCode,
+    op: std::result::Result<ScimOp, OperationError>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let request_id = Uuid::new_v4();
+    let rid = request_id.to_hyphenated().to_string();
+    let rt = match ScimResourceType::from_path(resource) {
+        Some(rt) => rt,
+        None => {
+            return Box::new(future::ok(scim_response(
+                rid,
+                ok,
+                Err(OperationError::NoMatchingEntries),
+            )))
+        }
+    };
+    let op = match op {
+        Ok(op) => op,
+        Err(e) => return Box::new(future::ok(scim_response(rid, ok, Err(e)))),
+    };
+    let uat = get_current_user(req);
+    Box::new(
+        state
+            .qe
+            .send(RequestMessage::new(
+                request_id,
+                ScimMessage::new(uat, rt, op),
+            ))
+            .from_err()
+            .and_then(move |res| Ok(scim_response(rid, ok, res))),
+    )
+}
+
+// The resource in a SCIM request body.
+fn scim_body(
+    req: &HttpRequest<AppState>,
+    state: &State<AppState>,
+) -> impl Future<Item = std::result::Result<serde_json::Value, OperationError>, Error = Error> {
+    let limits = state.limits;
+    req.payload()
+        .from_err()
+        .fold(BytesMut::new(), move |body, chunk| {
+            read_chunk(body, &chunk, &limits)
+        })
+        .map(|body| {
+            serde_json::from_slice(&body)
+                .map_err(|_| OperationError::InvalidScimRequest("body is not json"))
+        })
+}
+
+fn scim_list(
+    (req, state, resource): (HttpRequest<AppState>, State<AppState>, Path<String>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let filter = req.query().get("filter").cloned();
+    scim_send(
+        &req,
+        &state,
+        resource.as_str(),
+        http::StatusCode::OK,
+        Ok(ScimOp::List(filter)),
+    )
+}
+
+fn scim_get(
+    (req, state, path): (
+        HttpRequest<AppState>,
+        State<AppState>,
+        Path<(String, String)>,
+    ),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (resource, id) = path.into_inner();
+    scim_send(
+        &req,
+        &state,
+        resource.as_str(),
+        http::StatusCode::OK,
+        Ok(ScimOp::Get(id)),
+    )
+}
+
+fn scim_create(
+    (req, state, resource): (HttpRequest<AppState>, State<AppState>, Path<String>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    Box::new(scim_body(&req, &state).and_then(move |r| {
+        scim_send(
+            &req,
+            &state,
+            resource.as_str(),
+            http::StatusCode::CREATED,
+            r.map(ScimOp::Create),
+        )
+    }))
+}
+
+fn scim_patch(
+    (req, state, path): (
+        HttpRequest<AppState>,
+        State<AppState>,
+        Path<(String, String)>,
+    ),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (resource, id) = path.into_inner();
+    Box::new(scim_body(&req, &state).and_then(move |r| {
+        scim_send(
+            &req,
+            &state,
+            resource.as_str(),
+            http::StatusCode::OK,
+            r.map(|patch| ScimOp::Patch(id, patch)),
+        )
+    }))
+}
+
+fn scim_delete(
+    (req, state, path): (
+        HttpRequest<AppState>,
+        State<AppState>,
+        Path<(String, String)>,
+    ),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let (resource, id) = path.into_inner();
+    scim_send(
+        &req,
+        &state,
+        resource.as_str(),
+        http::StatusCode::NO_CONTENT,
+        Ok(ScimOp::Delete(id)),
+    )
+}
+
+// Only timings and counts are exposed here, nothing about entries, so like
+// the usual prometheus exporter it doesn't need authentication to scrape.
+fn prometheus_metrics(_req: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::snapshot().to_prometheus())
+}
+
 // We probably need an extract auth or similar to handle the different
 // types (cookie, bearer), and to generic this over get/post.
 
 fn auth(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    let max_size = state.max_size;
+    let limits = state.limits;
 
     req.payload()
         .from_err()
-        .fold(BytesMut::new(), move |mut body, chunk| {
+        .fold(BytesMut::new(), move |body, chunk| {
             // limit max size of in-memory payload
-            if (body.len() + chunk.len()) > max_size {
-                Err(error::ErrorBadRequest("overflow"))
-            } else {
-                body.extend_from_slice(&chunk);
-                Ok(body)
-            }
+            read_chunk(body, &chunk, &limits)
         })
         .and_then(
-            move |body| -> Box<Future<Item = HttpResponse, Error = Error>> {
-                let r_obj = serde_json::from_slice::<AuthRequest>(&body);
+            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let r_obj = decode_request::<AuthRequest>(&body, state.strict, &limits);
 
                 // Send to the db for action
                 match r_obj {
@@ -199,59 +1016,63 @@ fn auth(
                             }
                         };
 
-                        let auth_msg = AuthMessage::new(obj, maybe_sessionid);
+                        let source = request_source(&req);
+                        let client_cert = state.client_cert.as_ref().and_then(|h| h.extract(&req));
+                        let auth_msg = AuthMessage::new(obj, maybe_sessionid, source, client_cert);
+                        let request_id = Uuid::new_v4();
+                        let rid = request_id.to_hyphenated().to_string();
 
                         // We probably need to know if we allocate the cookie, that this is a
                         // new session, and in that case, anything *except* authrequest init is
                         // invalid.
-                        let res =
-                            state
-                                .qe
-                                .send(auth_msg)
-                                .from_err()
-                                .and_then(move |res| match res {
-                                    Ok(ar) => {
-                                        match &ar.state {
-                                            AuthState::Success(uat) => {
-                                                // Remove the auth-session-id
-                                                req.session().remove("auth-session-id");
-                                                // Set the uat into the cookie
-                                                match req.session().set("uat", uat) {
-                                                    Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                    Err(_) => {
-                                                        Ok(HttpResponse::InternalServerError()
-                                                            .json(()))
-                                                    }
-                                                }
-                                            }
-                                            AuthState::Denied(_) => {
-                                                // Remove the auth-session-id
-                                                req.session().remove("auth-session-id");
-                                                Ok(HttpResponse::Ok().json(ar))
+                        let res = state
+                            .qe
+                            .send(RequestMessage::new(request_id, auth_msg))
+                            .from_err()
+                            .and_then(move |res| match res {
+                                Ok(ar) => {
+                                    match &ar.state {
+                                        AuthState::Success(uat) => {
+                                            // Remove the auth-session-id
+                                            req.session().remove("auth-session-id");
+                                            // Set the uat into the cookie
+                                            match req.session().set("uat", uat) {
+                                                Ok(_) => Ok(HttpResponse::Ok()
+                                                    .header(REQUEST_ID_HEADER, rid)
+                                                    .json(ar)),
+                                                Err(_) => Ok(HttpResponse::InternalServerError()
+                                                    .header(REQUEST_ID_HEADER, rid)
+                                                    .json(())),
                                             }
-                                            AuthState::Continue(_) => {
-                                                // Ensure the auth-session-id is set
-                                                match req
-                                                    .session()
-                                                    .set("auth-session-id", ar.sessionid)
-                                                {
-                                                    Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                    Err(_) => {
-                                                        Ok(HttpResponse::InternalServerError()
-                                                            .json(()))
-                                                    }
-                                                }
+                                        }
+                                        AuthState::Denied(_) => {
+                                            // Remove the auth-session-id
+                                            req.session().remove("auth-session-id");
+                                            Ok(HttpResponse::Ok()
+                                                .header(REQUEST_ID_HEADER, rid)
+                                                .json(ar))
+                                        }
+                                        AuthState::Continue(_) => {
+                                            // Ensure the auth-session-id is set
+                                            match req.session().set("auth-session-id", ar.sessionid)
+                                            {
+                                                Ok(_) => Ok(HttpResponse::Ok()
+                                                    .header(REQUEST_ID_HEADER, rid)
+                                                    .json(ar)),
+                                                Err(_) => Ok(HttpResponse::InternalServerError()
+                                                    .header(REQUEST_ID_HEADER, rid)
+                                                    .json(())),
                                             }
                                         }
                                     }
-                                    Err(e) => Ok(HttpResponse::InternalServerError().json(e)),
-                                });
+                                }
+                                Err(e) => Ok(HttpResponse::InternalServerError()
+                                    .header(REQUEST_ID_HEADER, rid)
+                                    .json(e)),
+                            });
                         Box::new(res)
                     }
-                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
-                        "Json Decode Failed: {:?}",
-                        e
-                    )))),
+                    Err(e) => Box::new(future::err(e)),
                 }
             },
         )
@@ -260,7 +1081,40 @@ fn auth(
 fn setup_backend(config: &Configuration) -> Result<Backend, OperationError> {
     let mut audit_be = AuditScope::new("backend_setup");
     let pool_size: u32 = config.threads as u32;
-    let be = Backend::new(&mut audit_be, config.db_path.as_str(), pool_size);
+    let path = config.db_path.as_str();
+    let durability = config.db_durability.clone();
+    let mut be = match &config.db_key {
+        None => Backend::new_mmap(
+            &mut audit_be,
+            path,
+            pool_size,
+            config.db_mmap_size,
+            durability,
+        ),
+        Some(DbKeySource::Key(hex)) => DbKey::from_hex(hex.as_str()).and_then(|key| {
+            Backend::new_encrypted(&mut audit_be, path, pool_size, &key, durability)
+        }),
+        Some(DbKeySource::File(p)) => {
+            let provider = DbKeyFile::new(PathBuf::from(p));
+            Backend::new_encrypted(&mut audit_be, path, pool_size, &provider, durability)
+        }
+    };
+    if config.db_key.is_some() && config.db_mmap_size > 0 {
+        // sqlcipher has to decrypt every page it reads, so it never maps them.
+        warn!("db_mmap_size is ignored for an encrypted database");
+    }
+    let d = &config.db_durability;
+    if d.synchronous == DbSync::Off {
+        warn!("db synchronous is off, a power loss may corrupt the database");
+    }
+    if d.group_commit_ms > 0 && !(d.wal && d.synchronous == DbSync::Normal) {
+        // Every commit is already synced, or never is.
+        warn!("db_group_commit_ms only applies to wal with synchronous normal");
+    }
+    if let Ok(be) = be.as_mut() {
+        be.set_parallel_search_min(config.parallel_search_min);
+        be.set_idl_allids_threshold(config.idl_allids_threshold);
+    }
     // debug!
     debug!("{}", audit_be);
     be
@@ -276,8 +1130,7 @@ pub fn backup_server_core(config: Configuration, dst_path: &str) {
     };
     let mut audit = AuditScope::new("backend_backup");
 
-    let be_ro_txn = be.read();
-    let r = be_ro_txn.backup(&mut audit, dst_path);
+    let r = be.backup(&mut audit, dst_path);
     debug!("{}", audit);
     match r {
         Ok(_) => info!("Backup success!"),
@@ -286,7 +1139,6 @@ pub fn backup_server_core(config: Configuration, dst_path: &str) {
             std::process::exit(1);
         }
     };
-    // Let the txn abort, even on success.
 }
 
 pub fn restore_server_core(config: Configuration, dst_path: &str) {
@@ -298,11 +1150,21 @@ pub fn restore_server_core(config: Configuration, dst_path: &str) {
         }
     };
     let mut audit = AuditScope::new("backend_restore");
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
 
-    let be_wr_txn = be.write();
-    let r = be_wr_txn
+    // Through the query server, so the entries are checked against schema
+    // before anything is committed.
+    let mut server_write_txn = server.write();
+    let r = server_write_txn
         .restore(&mut audit, dst_path)
-        .and_then(|_| be_wr_txn.commit());
+        .and_then(|_| server_write_txn.commit(&mut audit));
     debug!("{}", audit);
 
     match r {
@@ -314,6 +1176,75 @@ pub fn restore_server_core(config: Configuration, dst_path: &str) {
     };
 }
 
+pub fn rekey_server_core(config: Configuration, new_key_path: &str) {
+    // sqlcipher can only change the key of a database that is already
+    // encrypted - it can't encrypt a plain one in place.
+    if config.db_key.is_none() {
+        error!("Rekey requires the current db key");
+        std::process::exit(1);
+    }
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let mut audit = AuditScope::new("backend_rekey");
+
+    let r = DbKeyFile::new(PathBuf::from(new_key_path))
+        .get_key(&mut audit)
+        .and_then(|key| be.rekey(&mut audit, &key));
+    debug!("{}", audit);
+
+    match r {
+        Ok(_) => info!("Rekey success! Update the db key to the new key before restarting."),
+        Err(e) => {
+            error!("Rekey failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// List the entries that were quarantined because they couldn't be read. With
+// an id, write that entry's raw stored bytes to dst_path instead.
+pub fn quarantine_server_core(config: Configuration, dump: Option<(u64, &str)>) {
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let mut audit = AuditScope::new("backend_quarantine");
+
+    let be_ro_txn = be.read();
+    let r = match dump {
+        Some((id, dst_path)) => be_ro_txn.quarantine_get(&mut audit, id).and_then(|data| {
+            fs::write(dst_path, data).map_err(|e| {
+                audit_log!(audit, "fs::write {:?}", e);
+                OperationError::FsError
+            })
+        }),
+        None => be_ro_txn.quarantine_list(&mut audit).map(|list| {
+            if list.len() == 0 {
+                info!("No entries are quarantined");
+            }
+            for (id, reason) in list {
+                info!("{} -> {}", id, reason);
+            }
+        }),
+    };
+    debug!("{}", audit);
+    match r {
+        Ok(_) => {}
+        Err(e) => {
+            error!("Quarantine failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
 pub fn verify_server_core(config: Configuration) {
     let mut audit = AuditScope::new("server_verify");
     // Setup the be
@@ -335,28 +1266,246 @@ pub fn verify_server_core(config: Configuration) {
     let server = QueryServer::new(be, schema_mem);
 
     // Run verifications.
-    let r = server.verify(&mut audit);
+    let report = server.verify_report(&mut audit);
 
     debug!("{}", audit);
 
-    if r.len() == 0 {
+    for c in report.checks.iter() {
+        if c.skipped {
+            info!("{}: skipped", c.check);
+        } else if c.errors.is_empty() {
+            info!("{}: ok", c.check);
+        } else {
+            error!("{}: {} problems", c.check, c.errors.len());
+            for er in c.errors.iter() {
+                error!("{}: {:?}", c.check, er);
+            }
+        }
+    }
+    match serde_json::to_string(&report) {
+        Ok(s) => info!("verify report: {}", s),
+        Err(e) => error!("Unable to serialise the verify report: {:?}", e),
+    }
+
+    if report.is_ok() {
         std::process::exit(0);
     } else {
-        for er in r {
-            error!("{:?}", er);
-        }
         std::process::exit(1);
     }
 
     // Now add IDM server verifications?
 }
 
+// Write every live entry to dst_path as json, one per line.
+pub fn export_server_core(config: Configuration, dst_path: &str) {
+    let mut audit = AuditScope::new("server_export");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
+
+    let r = server.read().export(&mut audit, dst_path);
+    debug!("{}", audit);
+
+    match r {
+        Ok(n) => info!("Export success! {} entries", n),
+        Err(e) => {
+            error!("Export failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// Create the entries written by export. The server is initialised first, so
+// the schema and builtin entries are in place to check them against.
+pub fn import_server_core(config: Configuration, src_path: &str) {
+    let mut audit = AuditScope::new("server_import");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let mut server = QueryServer::new(be.clone(), schema_mem);
+    server.set_domain_name(config.domain.as_str());
+    if let Err(e) = server.initialise_helper(&mut audit) {
+        debug!("{}", audit);
+        error!("Failed to initialise server: {:?}", e);
+        std::process::exit(1);
+    }
+
+    // The import is one transaction that can be run again if it's lost, so
+    // don't sync as it goes, only once it's done.
+    be.relax_durability();
+    let mut server_write_txn = server.write();
+    let r = server_write_txn
+        .import(&mut audit, src_path)
+        .and_then(|n| server_write_txn.commit(&mut audit).map(|_| n))
+        .and_then(|n| be.restore_durability(&mut audit).map(|_| n));
+    debug!("{}", audit);
+
+    match r {
+        Ok(n) => info!("Import success! {} entries created", n),
+        Err(e) => {
+            error!("Import failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// Give an account a new random password, and print it. This is how the first
+// password for admin is set.
+pub fn recover_account_core(config: Configuration, name: &str) {
+    let mut audit = AuditScope::new("recover_account");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let mut server = QueryServer::new(be, schema_mem);
+    server.set_domain_name(config.domain.as_str());
+    if let Err(e) = server.initialise_helper(&mut audit) {
+        debug!("{}", audit);
+        error!("Failed to initialise server: {:?}", e);
+        std::process::exit(1);
+    }
+
+    let mut server_write_txn = server.write();
+    let r = server_write_txn
+        .recover_account(&mut audit, name)
+        .and_then(|pw| server_write_txn.commit(&mut audit).map(|_| pw));
+    debug!("{}", audit);
+
+    match r {
+        Ok(pw) => {
+            info!("Password of {} reset", name);
+            println!("{}", pw);
+        }
+        Err(e) => {
+            error!("Account recovery failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// Mint a break glass secret for an account, and print it with its expiry. This
+// only works here on the host, as nothing over the network may set one.
+pub fn break_glass_core(config: Configuration, name: &str, lifetime_secs: u64) {
+    let mut audit = AuditScope::new("break_glass");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let mut server = QueryServer::new(be, schema_mem);
+    server.set_domain_name(config.domain.as_str());
+    if let Err(e) = server.initialise_helper(&mut audit) {
+        debug!("{}", audit);
+        error!("Failed to initialise server: {:?}", e);
+        std::process::exit(1);
+    }
+
+    let mut server_write_txn = server.write();
+    let r = server_write_txn
+        .break_glass(
+            &mut audit,
+            name,
+            std::time::Duration::from_secs(lifetime_secs),
+        )
+        .and_then(|r| server_write_txn.commit(&mut audit).map(|_| r));
+    debug!("{}", audit);
+
+    match r {
+        Ok((secret, expiry)) => {
+            warn!("Break glass secret issued for {} until {}", name, expiry);
+            println!("{}", secret);
+            println!("{}", expiry);
+        }
+        Err(e) => {
+            error!("Break glass failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// Rebuild the indexes from the stored entries, for when they are suspected
+// to be wrong.
+pub fn reindex_server_core(config: Configuration) {
+    let mut audit = AuditScope::new("server_reindex");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
+
+    let mut server_write_txn = server.write();
+    let r = server_write_txn
+        .reindex(&mut audit)
+        .and_then(|_| server_write_txn.commit(&mut audit));
+    debug!("{}", audit);
+
+    match r {
+        Ok(_) => info!("Reindex success!"),
+        Err(e) => {
+            error!("Reindex failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
 pub fn create_server_core(config: Configuration) {
     // Until this point, we probably want to write to the log macro fns.
 
     // The log server is started on it's own thread, and is contacted
     // asynchronously.
-    let log_addr = async_log::start();
+    let log_addr = async_log::start(config.audit_log_path.clone());
     log_event!(log_addr, "Starting rsidm with configuration: {:?}", config);
 
     // Similar, create a stats thread which aggregates statistics from the
@@ -371,8 +1520,64 @@ pub fn create_server_core(config: Configuration) {
         }
     };
 
+    // Without a CA, no client certificate is ever trusted.
+    let client_ca = match &config.client_ca {
+        Some(p) => match fs::read(p)
+            .map_err(|e| {
+                error!("Failed to read client CA {}: {:?}", p, e);
+                OperationError::InvalidState
+            })
+            .and_then(|pem| ClientCertVerifier::from_pem(pem.as_slice()))
+        {
+            Ok(v) => Some(v),
+            Err(_) => return,
+        },
+        None => None,
+    };
+    let client_cert = match (&config.client_ca, &config.client_cert_header) {
+        (Some(_), Some(name)) => {
+            let proxies: Result<Vec<IpAddr>, _> = config
+                .client_cert_proxies
+                .iter()
+                .map(|p| p.parse::<IpAddr>())
+                .collect();
+            match proxies {
+                Ok(proxies) => Some(ClientCertHeader {
+                    name: name.clone(),
+                    proxies: proxies,
+                }),
+                Err(e) => {
+                    error!("Invalid client certificate proxy address: {:?}", e);
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+
     // Start the query server with the given be path: future config
-    let server_addr = match QueryServerV1::start(log_addr.clone(), be, config.threads) {
+    let server_addr = match QueryServerV1::start(
+        log_addr.clone(),
+        be,
+        config.domain.as_str(),
+        config.threads,
+        config.acp_require_metadata,
+        FilterLimits {
+            max_depth: config.filter_max_depth,
+            max_terms: config.filter_max_terms,
+        },
+        config.search_max_results,
+        config.log_changes,
+        AnomalyThresholds {
+            auth_failures: config.anomaly_auth_failures,
+            access_denied: config.anomaly_access_denied,
+            deletes: config.anomaly_deletes,
+        },
+        config.anomaly_webhook.clone(),
+        client_ca,
+        config.backup_path.clone(),
+        config.audit_log_path.clone(),
+    ) {
         Ok(addr) => addr,
         Err(e) => {
             println!(
@@ -384,10 +1589,51 @@ pub fn create_server_core(config: Configuration) {
     };
 
     // Setup timed events
-    let _int_addr = IntervalActor::new(server_addr.clone()).start();
+    let group_commit = match config.db_durability.group_commit_ms {
+        0 => None,
+        ms => Some(std::time::Duration::from_millis(ms)),
+    };
+    let _int_addr = IntervalActor::new(
+        server_addr.clone(),
+        std::time::Duration::from_secs(config.recycle_window),
+        std::time::Duration::from_secs(config.tombstone_window),
+        group_commit,
+    )
+    .start();
+
+    if let Some(address) = &config.ldap_address {
+        let tls = match (&config.ldap_tls_chain, &config.ldap_tls_key) {
+            (Some(chain), Some(key)) => match ldap::tls_acceptor(chain.as_str(), key.as_str()) {
+                Ok(a) => Some(a),
+                Err(e) => {
+                    error!("Failed to load the ldap TLS chain and key -> {:?}", e);
+                    return;
+                }
+            },
+            (None, None) => None,
+            _ => {
+                error!("ldap TLS needs both a chain and a key");
+                return;
+            }
+        };
+        if let Err(e) = ldap::start(
+            address.as_str(),
+            server_addr.clone(),
+            config.maximum_request,
+            tls,
+        ) {
+            error!("Failed to start ldap on {} -> {:?}", address, e);
+            return;
+        }
+    }
 
-    // Copy the max size
-    let max_size = config.maximum_request;
+    // Copy the limits
+    let limits = RequestLimits {
+        max_bytes: config.maximum_request,
+        max_entries: config.maximum_create_entries,
+        max_modlist: config.maximum_modlist,
+    };
+    let strict = config.strict_requests;
     let secure_cookies = config.secure_cookies;
     // let domain = config.domain.clone();
     let cookie_key: [u8; 32] = config.cookie_key.clone();
@@ -396,7 +1642,9 @@ pub fn create_server_core(config: Configuration) {
     actix_web::server::new(move || {
         App::with_state(AppState {
             qe: server_addr.clone(),
-            max_size: max_size,
+            limits: limits,
+            strict: strict,
+            client_cert: client_cert.clone(),
         })
         // Connect all our end points here.
         .middleware(middleware::Logger::default())
@@ -443,16 +1691,159 @@ pub fn create_server_core(config: Configuration) {
         .resource("/v1/delete", |r| {
             r.method(http::Method::POST).with_async(delete)
         })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "name": "newname", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/rename
+        .resource("/v1/rename", |r| {
+            r.method(http::Method::POST).with_async(rename)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "operations": [{ "ModifyUuid": ["...", { "mods": [{ "Purged": "description" }] }] }], "user_uuid": "..."}'  http://127.0.0.1:8080/v1/batch
+        .resource("/v1/batch", |r| {
+            r.method(http::Method::POST).with_async(batch)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "filter" : { "Eq": ["name", "testgroup"] }, "user_uuid": "..."}'  http://127.0.0.1:8080/v1/delete/preview
+        .resource("/v1/delete/preview", |r| {
+            r.method(http::Method::POST).with_async(delete_preview)
+        })
         // curl --header "Content-Type: application/json" --request POST --data '{ "filter" : { "Eq": ["class", "user"] }}'  http://127.0.0.1:8080/v1/search
         .resource("/v1/search", |r| {
             r.method(http::Method::POST).with_async(search)
         })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "prefix": "test", "class": "group", "limit": 10, "user_uuid": "..."}'  http://127.0.0.1:8080/v1/typeahead
+        .resource("/v1/typeahead", |r| {
+            r.method(http::Method::POST).with_async(typeahead)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "filter" : { "Eq": ["name", "testgroup"] }, "user_uuid": "..."}'  http://127.0.0.1:8080/v1/recycled/search
+        .resource("/v1/recycled/search", |r| {
+            r.method(http::Method::POST).with_async(search_recycled)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "filter" : { "Eq": ["name", "testgroup"] }, "user_uuid": "..."}'  http://127.0.0.1:8080/v1/recycled/revive
+        .resource("/v1/recycled/revive", |r| {
+            r.method(http::Method::POST).with_async(revive_recycled)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/access/effective
+        .resource("/v1/access/effective", |r| {
+            r.method(http::Method::POST)
+                .with_async(effective_permissions)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "attr": "member", "value": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/compare
+        .resource("/v1/compare", |r| {
+            r.method(http::Method::POST).with_async(compare)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "user_uuid": "..." }'  http://127.0.0.1:8080/v1/diagnostics/memory
+        .resource("/v1/diagnostics/memory", |r| {
+            r.method(http::Method::POST).with_async(memory_report)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "levels": [{ "subsystem": "be", "level": "debug" }], "user_uuid": "..." }'  http://127.0.0.1:8080/v1/diagnostics/log_level
+        .resource("/v1/diagnostics/log_level", |r| {
+            r.method(http::Method::POST).with_async(log_level)
+        })
+        // Leave out sample to check every entry.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "sample": 1000, "user_uuid": "..." }'  http://127.0.0.1:8080/v1/diagnostics/acp_coverage
+        .resource("/v1/diagnostics/acp_coverage", |r| {
+            r.method(http::Method::POST).with_async(acp_coverage)
+        })
+        // Hosts call this for themselves to renew their service secret before it expires.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/host/secret/rotate
+        .resource("/v1/host/secret/rotate", |r| {
+            r.method(http::Method::POST).with_async(host_secret_rotate)
+        })
+        // Accounts call this for themselves, and get the new secret back.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/radius/secret/regenerate
+        .resource("/v1/radius/secret/regenerate", |r| {
+            r.method(http::Method::POST)
+                .with_async(radius_secret_regenerate)
+        })
+        // Radius servers call this as an account authenticates to them.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "name": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/radius/secret
+        .resource("/v1/radius/secret", |r| {
+            r.method(http::Method::POST).with_async(radius_secret_read)
+        })
+        // Gives a token that sets the target's password once, by default within a day.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "target_uuid": "...", "lifetime": 3600, "user_uuid": "..." }'  http://127.0.0.1:8080/v1/credential/reset/issue
+        .resource("/v1/credential/reset/issue", |r| {
+            r.method(http::Method::POST)
+                .with_async(credential_reset_issue)
+        })
+        // Needs no session, the token is what allows it.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "token": "...", "password": "..." }'  http://127.0.0.1:8080/v1/credential/reset/redeem
+        .resource("/v1/credential/reset/redeem", |r| {
+            r.method(http::Method::POST)
+                .with_async(credential_reset_redeem)
+        })
+        // Written to a new file in the configured backup_path, named in the response.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "user_uuid": "..." }'  http://127.0.0.1:8080/v1/backup
+        .resource("/v1/backup", |r| {
+            r.method(http::Method::POST).with_async(backup)
+        })
+        // Every term is optional. Needs audit_log_path in the server configuration.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "operation": "auth", "since": "2019-07-01T10:00:00+00:00", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/audit/log
+        .resource("/v1/audit/log", |r| {
+            r.method(http::Method::POST).with_async(audit_log)
+        })
+        // Leave out the token for the initial sync, then send the token from the last response.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "token": "12", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/sync
+        .resource("/v1/sync", |r| {
+            r.method(http::Method::POST).with_async(sync)
+        })
+        // Pulled by the consumers of replication agreements. Leave out since to be sent every entry.
+        // curl --header "Content-Type: application/json" --request POST --data '{ "since": "2019-07-01T10:00:00.000000001+00:00", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/replication/changes
+        .resource("/v1/replication/changes", |r| {
+            r.method(http::Method::POST).with_async(repl_changes)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "group": "idm_admins", "description": "...", "user_uuid": "..." }'  http://127.0.0.1:8080/v1/group/join
+        .resource("/v1/group/join", |r| {
+            r.method(http::Method::POST).with_async(group_join_create)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "user_uuid": "..." }'  http://127.0.0.1:8080/v1/group/join/list
+        .resource("/v1/group/join/list", |r| {
+            r.method(http::Method::POST).with_async(group_join_list)
+        })
+        // curl --header "Content-Type: application/json" --request POST --data '{ "request_uuid": "...", "approve": true, "user_uuid": "..." }'  http://127.0.0.1:8080/v1/group/join/decide
+        .resource("/v1/group/join/decide", |r| {
+            r.method(http::Method::POST).with_async(group_join_decide)
+        })
+        // curl -b /tmp/cookie.jar http://127.0.0.1:8080/v1/account/william/ssh_keys
+        .resource("/v1/account/{name}/ssh_keys", |r| {
+            r.method(http::Method::GET).with_async(ssh_keys)
+        })
+        // Visited by the browser, as the resource server sends it here.
+        // curl -b /tmp/cookie.jar 'http://127.0.0.1:8080/oauth2/authorise?response_type=code&client_id=...&redirect_uri=...&scope=openid%20profile&state=...'
+        .resource("/oauth2/authorise", |r| {
+            r.method(http::Method::GET).with_async(oauth2_authorise)
+        })
+        // curl -u client_id:secret --data 'grant_type=authorization_code&code=...&redirect_uri=...'  http://127.0.0.1:8080/oauth2/token
+        .resource("/oauth2/token", move |r| {
+            // The form isn't read through read_chunk, so bound it here.
+            r.method(http::Method::POST)
+                .with_async_config(oauth2_token, move |cfg| {
+                    ((cfg.0).2).limit(limits.max_bytes);
+                })
+        })
+        // curl -N -b /tmp/cookie.jar --header "Content-Type: application/json" --request POST --data '{ "filter": { "Eq": ["class", "group"] }, "attrs": ["name", "member"] }'  http://127.0.0.1:8080/v1/subscribe
+        .resource("/v1/subscribe", |r| {
+            r.method(http::Method::POST).with_async(subscribe)
+        })
+        // For provisioning sources, with a session that may change accounts
+        // and groups.
+        // curl -b /tmp/cookie.jar 'http://127.0.0.1:8080/scim/v2/Users?filter=userName%20eq%20%22william%22'
+        .resource("/scim/v2/{resource}", |r| {
+            r.method(http::Method::GET).with_async(scim_list);
+            r.method(http::Method::POST).with_async(scim_create)
+        })
+        // curl -b /tmp/cookie.jar --request PATCH --data '{ "Operations": [{ "op": "add", "path": "members", "value": [{ "value": "..." }] }] }'  http://127.0.0.1:8080/scim/v2/Groups/...
+        .resource("/scim/v2/{resource}/{id}", |r| {
+            r.method(http::Method::GET).with_async(scim_get);
+            r.method(http::Method::PATCH).with_async(scim_patch);
+            r.method(http::Method::DELETE).with_async(scim_delete)
+        })
+        // curl http://127.0.0.1:8080/metrics
+        .resource("/metrics", |r| {
+            r.method(http::Method::GET).f(prometheus_metrics)
+        })
         // This is one of the times we need cookies :)
         // curl -b /tmp/cookie.jar -c /tmp/cookie.jar --header "Content-Type: application/json" --request POST --data '{ "state" : { "Init": ["Anonymous", []] }}'  http://127.0.0.1:8080/v1/auth
         .resource("/v1/auth", |r| {
             r.method(http::Method::POST).with_async(auth)
         })
-        // Add an ldap compat search function type?
         /*
         .resource("/v1/list/{class_list}", |r| {
             r.method(http::Method::GET).with(class_list)
@@ -463,3 +1854,91 @@ pub fn create_server_core(config: Configuration) {
     .expect("Failed to initialise server!")
     .start();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_request, read_chunk, RequestLimits};
+    use crate::proto::v1::{BatchRequest, CreateRequest, ModifyRequest};
+    use actix_web::http;
+    use bytes::BytesMut;
+
+    static LIMITS: RequestLimits = RequestLimits {
+        max_bytes: 64,
+        max_entries: 1,
+        max_modlist: 1,
+    };
+
+    #[test]
+    fn test_request_limits() {
+        let body = r#"{
+            "entries": [{"attrs": {"name": ["a"]}}, {"attrs": {"name": ["b"]}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS).is_err());
+        let body = r#"{
+            "entries": [{"attrs": {"name": ["a"]}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS).is_ok());
+
+        let body = r#"{
+            "filter": {"Pres": "class"},
+            "modlist": {"mods": [{"Purged": "name"}, {"Purged": "description"}]},
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<ModifyRequest>(body.as_bytes(), true, &LIMITS).is_err());
+
+        // Each operation of a batch counts against the entry limit.
+        let body = r#"{
+            "operations": [{"Delete": {"Pres": "a"}}, {"Delete": {"Pres": "b"}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<BatchRequest>(body.as_bytes(), true, &LIMITS).is_err());
+
+        // The counts are checked before the entries are decoded, so a body
+        // with too many of them is refused for its size, not its content.
+        let body = r#"{"entries": [1, 2], "user_uuid": ""}"#;
+        let e = decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS)
+            .expect_err("too many entries");
+        assert_eq!(
+            e.as_response_error().error_response().status(),
+            http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // The body is refused as soon as it's too big, before it's decoded.
+        let body = read_chunk(BytesMut::new(), &[0; 48], &LIMITS).expect("under the limit");
+        assert!(read_chunk(body, &[0; 48], &LIMITS).is_err());
+    }
+
+    #[test]
+    fn test_request_unknown_fields() {
+        static LIMITS: RequestLimits = RequestLimits {
+            max_bytes: 1024,
+            max_entries: 8,
+            max_modlist: 8,
+        };
+
+        // A correct request decodes.
+        let body = r#"{
+            "entries": [{"attrs": {"class": ["person"], "name": ["testperson"]}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS).is_ok());
+
+        // But a typo of attrs is refused, as is a field no request has,
+        // unless the server has been told to be lenient.
+        let body = r#"{
+            "entries": [{"attrs": {}, "attr": {"name": ["testperson"]}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000"
+        }"#;
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS).is_err());
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), false, &LIMITS).is_ok());
+        let body = r#"{
+            "entries": [{"attrs": {"name": ["testperson"]}}],
+            "user_uuid": "00000000-0000-0000-0000-000000000000",
+            "extra": true
+        }"#;
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), true, &LIMITS).is_err());
+        assert!(decode_request::<CreateRequest>(body.as_bytes(), false, &LIMITS).is_ok());
+    }
+}
@@ -0,0 +1,111 @@
+// Change sequence numbers.
+//
+// A csn records when a change was made, so that changes to the same entry can
+// be put in order. Every change made in a write transaction is given the csn
+// the transaction started with, and each attribute of an entry keeps the csn
+// of the last change that touched it. Replication uses these to settle
+// conflicting changes, and they give clients an entry's last_modified time.
+//
+// Two servers can make changes at the same instant, so a csn also names the
+// replica that made it. Csns order by time, then replica, so every server
+// settles such a conflict the same way.
+
+#[cfg(test)]
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Csn {
+    // Time since the unix epoch.
+    ts: Duration,
+    // The replica the change was made on.
+    rid: Uuid,
+}
+
+impl Csn {
+    #[cfg(test)]
+    pub fn now() -> Self {
+        Csn {
+            ts: SystemClock.now(),
+            rid: Uuid::nil(),
+        }
+    }
+
+    pub fn new(ts: Duration, rid: Uuid) -> Self {
+        Csn { ts: ts, rid: rid }
+    }
+
+    pub fn ts(&self) -> Duration {
+        self.ts
+    }
+
+    pub fn rid(&self) -> &Uuid {
+        &self.rid
+    }
+
+    // Only the time, for showing to clients.
+
+    pub fn to_rfc3339(&self) -> String {
+        DateTime::<Utc>::from(UNIX_EPOCH + self.ts).to_rfc3339()
+    }
+
+    // Csns are sent between servers as the time, to the nanosecond, and
+    // the replica, as "<rfc3339>/<uuid>".
+    pub fn to_repl_string(&self) -> String {
+        format!("{}/{}", self.to_rfc3339(), self.rid)
+    }
+
+    // Times before the epoch aren't csns. One without a replica is from
+    // before they were recorded, and has the nil one.
+    pub fn from_repl_str(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(2, '/');
+        let dt = DateTime::parse_from_rfc3339(parts.next()?)
+            .ok()?
+            .with_timezone(&Utc);
+        let rid = match parts.next() {
+            Some(r) => Uuid::parse_str(r).ok()?,
+            None => Uuid::nil(),
+        };
+        let secs = dt.timestamp();
+        if secs < 0 {
+            return None;
+        }
+        Some(Csn {
+            ts: Duration::new(secs as u64, dt.timestamp_subsec_nanos()),
+            rid: rid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csn::Csn;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_csn_order() {
+        let r1 = Uuid::parse_str("00000000-0000-0000-0000-000000000001").expect("invalid uuid");
+        let r2 = Uuid::parse_str("00000000-0000-0000-0000-000000000002").expect("invalid uuid");
+        let a = Csn::new(Duration::from_secs(1), Uuid::nil());
+        let b = Csn::new(Duration::new(1, 5), r1);
+        assert!(a < b);
+        assert!(Csn::now() > b);
+        // The same instant on two replicas.
+        assert!(Csn::new(Duration::new(1, 5), r2) > b);
+        assert!(Csn::new(Duration::new(1, 6), Uuid::nil()) > Csn::new(Duration::new(1, 5), r2));
+
+        assert!(a.to_rfc3339() == "1970-01-01T00:00:01+00:00");
+        assert!(
+            b.to_repl_string()
+                == "1970-01-01T00:00:01.000000005+00:00/00000000-0000-0000-0000-000000000001"
+        );
+        assert!(Csn::from_repl_str(b.to_repl_string().as_str()) == Some(b));
+        assert!(Csn::from_repl_str("1970-01-01T10:00:01+10:00") == Some(a));
+        assert!(Csn::from_repl_str("1970-01-01T00:00:01+00:00/nope").is_none());
+        assert!(Csn::from_repl_str("1969-12-31T00:00:00+00:00").is_none());
+        assert!(Csn::from_repl_str("yesterday").is_none());
+    }
+}
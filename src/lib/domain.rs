@@ -0,0 +1,129 @@
+// The domain this server belongs to, from the domain_info entry.
+//
+// The entry is made the first time the server is initialised, with a new
+// domain uuid and the domain name from the configuration. After that the
+// entry is the authority - like the schema it is read back whenever it
+// changes, so principal names, replication and tokens can consult it without
+// a search.
+
+use crate::audit::AuditScope;
+use crate::constants::UUID_DOES_NOT_EXIST;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::error::OperationError;
+use crate::proto::v1::DomainInfo as ProtoDomainInfo;
+
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainInfo {
+    pub name: String,
+    pub uuid: String,
+    pub version: String,
+    pub features: BTreeSet<String>,
+}
+
+impl DomainInfo {
+    // What is known before the domain_info entry has been read, which is
+    // only while the server is being initialised.
+    pub fn new() -> Self {
+        DomainInfo {
+            name: String::from("localhost"),
+            uuid: UUID_DOES_NOT_EXIST.to_string(),
+            version: String::from("0"),
+            features: BTreeSet::new(),
+        }
+    }
+
+    pub fn try_from(
+        audit: &mut AuditScope,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", "domain_info") {
+            audit_log!(audit, "class domain_info not present");
+            return Err(OperationError::InvalidDomainState("missing domain_info"));
+        }
+        let single = |attr: &str, missing: &'static str| {
+            value
+                .get_ava_single(attr)
+                .map(|v| v.to_string())
+                .ok_or(OperationError::InvalidDomainState(missing))
+        };
+
+        Ok(DomainInfo {
+            name: try_audit!(audit, single("domain_name", "missing domain_name")),
+            uuid: try_audit!(audit, single("domain_uuid", "missing domain_uuid")),
+            version: try_audit!(audit, single("version", "missing version")),
+            features: value.get_ava_opt("domain_feature").into_iter().collect(),
+        })
+    }
+
+    // The principal name of the entry with this name.
+    pub fn spn(&self, name: &str) -> String {
+        format!("{}@{}", name, self.name)
+    }
+
+    pub fn to_proto(&self) -> ProtoDomainInfo {
+        ProtoDomainInfo {
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            version: self.version.clone(),
+            features: self.features.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::domain::DomainInfo;
+    use crate::entry::{Entry, EntryNew, EntryValid};
+    use crate::error::OperationError;
+
+    #[test]
+    fn test_domain_info_from_entry() {
+        let mut audit = AuditScope::new("test_domain_info_from_entry");
+        let e: Entry<EntryValid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": {
+                "uuid": "00000000-0000-0000-0000-ffffff000013"
+            },
+            "state": null,
+            "attrs": {
+                "class": ["object", "system", "domain_info"],
+                "uuid": ["00000000-0000-0000-0000-ffffff000013"],
+                "domain_name": ["example.com"],
+                "domain_uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                "domain_feature": ["b", "a"],
+                "version": ["1"]
+            }
+        }"#,
+        )
+        .expect("json parse failure");
+        let di = DomainInfo::try_from(&mut audit, &unsafe { e.to_valid_committed() })
+            .expect("invalid domain_info");
+        assert!(di.name == "example.com");
+        assert!(di.uuid == "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+        assert!(di.spn("alice") == "alice@example.com");
+        assert!(di.to_proto().features == vec!["a".to_string(), "b".to_string()]);
+
+        let e: Entry<EntryValid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": {
+                "uuid": "00000000-0000-0000-0000-ffffff000013"
+            },
+            "state": null,
+            "attrs": {
+                "class": ["object", "domain_info"],
+                "uuid": ["00000000-0000-0000-0000-ffffff000013"],
+                "domain_name": ["example.com"],
+                "version": ["1"]
+            }
+        }"#,
+        )
+        .expect("json parse failure");
+        assert!(
+            DomainInfo::try_from(&mut audit, &unsafe { e.to_valid_committed() })
+                == Err(OperationError::InvalidDomainState("missing domain_uuid"))
+        );
+    }
+}
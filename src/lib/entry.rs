@@ -1,26 +1,28 @@
 // use serde_json::{Error, Value};
 use crate::audit::AuditScope;
+use crate::csn::Csn;
 use crate::error::{OperationError, SchemaError};
-use crate::filter::{Filter, FilterInvalid, FilterResolved, FilterValidResolved};
+use crate::filter::{Filter, FilterInvalid, FilterResolved, FilterValidResolved, SubMatch};
 use crate::modify::{Modify, ModifyInvalid, ModifyList, ModifyValid};
 use crate::proto::v1::Entry as ProtoEntry;
+use crate::proto::v1::RedactedAttrs;
+use crate::proto::v1::ReplEntry;
 use crate::schema::{IndexType, SyntaxType};
 use crate::schema::{SchemaAttribute, SchemaClass, SchemaTransaction};
 use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
 
-use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
+use crate::be::dbentry::{DbCsnV1, DbEntry, DbEntryV3, DbEntryVers, DbValueV1};
 
+use std::cmp::Ordering;
 use std::collections::btree_map::{Iter as BTreeIter, IterMut as BTreeIterMut};
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::iter::ExactSizeIterator;
 use std::slice::Iter as SliceIter;
+use std::sync::Arc;
 
-use std::convert::TryFrom;
-use std::str::FromStr;
-
-#[cfg(test)]
 use uuid::Uuid;
 
 // make a trait entry for everything to adhere to?
@@ -52,17 +54,19 @@ use uuid::Uuid;
 
 pub struct EntryClasses<'a> {
     size: usize,
-    inner: Option<SliceIter<'a, String>>,
+    inner: Option<SliceIter<'a, Value>>,
     // _p: &'a PhantomData<()>,
 }
 
 impl<'a> Iterator for EntryClasses<'a> {
-    type Item = &'a String;
+    type Item = &'a str;
 
+    // Classes are always strings, so a value that isn't is yielded as the
+    // empty string, which no class in schema will match.
     #[inline]
-    fn next(&mut self) -> Option<(&'a String)> {
+    fn next(&mut self) -> Option<(&'a str)> {
         match self.inner.iter_mut().next() {
-            Some(i) => i.next(),
+            Some(i) => i.next().map(|v| v.to_str().unwrap_or("")),
             None => None,
         }
     }
@@ -83,15 +87,15 @@ impl<'a> ExactSizeIterator for EntryClasses<'a> {
 }
 
 pub struct EntryAvas<'a> {
-    inner: BTreeIter<'a, String, Vec<String>>,
+    inner: BTreeIter<'a, String, Arc<Vec<Value>>>,
 }
 
 impl<'a> Iterator for EntryAvas<'a> {
-    type Item = (&'a String, &'a Vec<String>);
+    type Item = (&'a String, &'a Vec<Value>);
 
     #[inline]
-    fn next(&mut self) -> Option<(&'a String, &'a Vec<String>)> {
-        self.inner.next()
+    fn next(&mut self) -> Option<(&'a String, &'a Vec<Value>)> {
+        self.inner.next().map(|(k, vs)| (k, vs.as_ref()))
     }
 
     #[inline]
@@ -101,15 +105,15 @@ impl<'a> Iterator for EntryAvas<'a> {
 }
 
 pub struct EntryAvasMut<'a> {
-    inner: BTreeIterMut<'a, String, Vec<String>>,
+    inner: BTreeIterMut<'a, String, Arc<Vec<Value>>>,
 }
 
 impl<'a> Iterator for EntryAvasMut<'a> {
-    type Item = (&'a String, &'a mut Vec<String>);
+    type Item = (&'a String, &'a mut Vec<Value>);
 
     #[inline]
-    fn next(&mut self) -> Option<(&'a String, &'a mut Vec<String>)> {
-        self.inner.next()
+    fn next(&mut self) -> Option<(&'a String, &'a mut Vec<Value>)> {
+        self.inner.next().map(|(k, vs)| (k, Arc::make_mut(vs)))
     }
 
     #[inline]
@@ -156,13 +160,45 @@ pub struct EntryNormalised;
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct EntryReduced;
 
-#[derive(Debug, Serialize, Deserialize)]
+// The values of each attribute are shared between copies of an entry, and
+// only copied when one of the copies changes them. Entries are cloned a lot
+// on their way through an operation, and this way a large group's members
+// aren't copied each time.
+#[derive(Serialize, Deserialize)]
 pub struct Entry<VALID, STATE> {
     valid: VALID,
     state: STATE,
+    attrs: BTreeMap<String, Arc<Vec<Value>>>,
+    // The csn of the last change to each attribute. An attribute that has
+    // been purged keeps its csn, so we know when it went away.
+    #[serde(default)]
+    csns: BTreeMap<String, Csn>,
+}
+
+// The external form of an entry, for fixtures, exports and tooling. Only the
+// attributes are kept - what the server has learnt about the entry, like
+// whether it passed schema or its id in the database, is worked out again
+// when it is read back. Anything else in the json is refused, so a typo'd
+// field can't go unnoticed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExternalEntry {
     attrs: BTreeMap<String, Vec<String>>,
 }
 
+// Entries are logged on their way through an operation, before the plugins
+// have hashed any credential in them.
+impl<VALID: std::fmt::Debug, STATE: std::fmt::Debug> std::fmt::Debug for Entry<VALID, STATE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Entry")
+            .field("valid", &self.valid)
+            .field("state", &self.state)
+            .field("attrs", &RedactedAttrs(&self.attrs))
+            .field("csns", &self.csns)
+            .finish()
+    }
+}
+
 impl<STATE> std::fmt::Display for Entry<EntryValid, STATE> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.get_uuid())
@@ -170,13 +206,13 @@ impl<STATE> std::fmt::Display for Entry<EntryValid, STATE> {
 }
 
 impl Entry<EntryInvalid, EntryNew> {
-    #[cfg(test)]
     pub fn new() -> Self {
         Entry {
             // This means NEVER COMMITED
             valid: EntryInvalid,
             state: EntryNew,
             attrs: BTreeMap::new(),
+            csns: BTreeMap::new(),
         }
     }
 
@@ -196,26 +232,35 @@ impl Entry<EntryInvalid, EntryNew> {
 
         // Somehow we need to take the tree of e attrs, and convert
         // all ref types to our types ...
-        let map2: Result<BTreeMap<String, Vec<String>>, OperationError> = e
-            .attrs
-            .iter()
-            .map(|(k, v)| {
-                let nv: Result<Vec<_>, _> =
-                    v.iter().map(|vr| qs.clone_value(audit, &k, vr)).collect();
-                match nv {
-                    Ok(mut nvi) => {
-                        nvi.sort_unstable();
-                        Ok((k.clone(), nvi))
-                    }
-                    Err(e) => Err(e),
+        //
+        // Every attribute and value is checked against schema here, so that
+        // nothing invalid reaches the plugins. As with validate, all of the
+        // problems are reported together.
+        let schema = qs.get_schema();
+        let mut errs = Vec::new();
+        let mut x = BTreeMap::new();
+        for (k, v) in e.attrs.iter() {
+            let k_norm = schema.normalise_attr_name(k);
+            if !schema.get_attributes().contains_key(&k_norm) {
+                errs.push(SchemaError::InvalidAttribute(k_norm));
+                continue;
+            }
+            let mut nv = Vec::with_capacity(v.len());
+            for vr in v.iter() {
+                match qs.clone_value(audit, k, vr) {
+                    Ok(cv) => nv.push(Value::from(cv)),
+                    Err(OperationError::SchemaViolation(se)) => errs.push(se),
+                    Err(e) => return Err(e),
                 }
-            })
-            .collect();
+            }
+            nv.sort_unstable();
+            x.insert(k.clone(), Arc::new(nv));
+        }
 
-        let x = match map2 {
-            Ok(v) => v,
-            Err(e) => return Err(e),
-        };
+        if !errs.is_empty() {
+            audit_log!(audit, "from_proto_entry: schema violations {:?}", errs);
+            return Err(OperationError::EntrySchemaViolation(errs));
+        }
 
         Ok(Entry {
             // For now, we do a straight move, and we sort the incoming data
@@ -223,24 +268,116 @@ impl Entry<EntryInvalid, EntryNew> {
             state: EntryNew,
             valid: EntryInvalid,
             attrs: x,
+            csns: BTreeMap::new(),
+        })
+    }
+
+    // Read an entry from its external json form. Every attribute must be in
+    // schema, and every value valid for its attribute's syntax - the values
+    // are typed here, so nothing is left to guess later. The entry still has
+    // to be validated before it can be used.
+    pub fn from_external_json(
+        s: &str,
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Self, OperationError> {
+        let ext: ExternalEntry =
+            serde_json::from_str(s).map_err(|_| OperationError::SerdeJsonError)?;
+        Ok(Entry {
+            valid: EntryInvalid,
+            state: EntryNew,
+            attrs: typed_attrs(&ext.attrs, schema)?,
+            csns: BTreeMap::new(),
         })
     }
+
+    // An entry from a replication supplier that we don't hold yet. It keeps
+    // the supplier's csns, so that later changes, made here or there, are
+    // ordered against them.
+    pub fn from_repl_entry(
+        re: &ReplEntry,
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Self, OperationError> {
+        let attrs = match &re.attrs {
+            Some(a) => typed_attrs(a, schema)?,
+            None => {
+                return Err(OperationError::ReplicationFailed(format!(
+                    "{} has been purged",
+                    re.uuid
+                )))
+            }
+        };
+        Ok(Entry {
+            valid: EntryInvalid,
+            state: EntryNew,
+            attrs: attrs,
+            csns: repl_csns(re)?,
+        })
+    }
+}
+
+// Type the string values of an entry by the syntax of their attributes.
+fn typed_attrs(
+    attrs: &BTreeMap<String, Vec<String>>,
+    schema: &dyn SchemaTransaction,
+) -> Result<BTreeMap<String, Arc<Vec<Value>>>, OperationError> {
+    let schema_attributes = schema.get_attributes();
+    let mut typed: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (k, vs) in attrs.iter() {
+        // An alias and the name it stands for may both be given.
+        let k = schema.normalise_attr_name(k);
+        let schema_a = schema_attributes.get(&k).ok_or_else(|| {
+            OperationError::SchemaViolation(SchemaError::InvalidAttribute(k.clone()))
+        })?;
+        let vs = vs
+            .iter()
+            .map(|v| Value::new(&schema_a.syntax, v.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(OperationError::SchemaViolation)?;
+        typed.entry(k).or_insert_with(Vec::new).extend(vs);
+    }
+    for vs in typed.values_mut() {
+        vs.sort_unstable();
+        vs.dedup();
+    }
+    Ok(share_attrs(typed))
+}
+
+fn share_attrs(attrs: BTreeMap<String, Vec<Value>>) -> BTreeMap<String, Arc<Vec<Value>>> {
+    attrs.into_iter().map(|(k, vs)| (k, Arc::new(vs))).collect()
+}
+
+fn repl_csns(re: &ReplEntry) -> Result<BTreeMap<String, Csn>, OperationError> {
+    re.csns
+        .iter()
+        .map(|(k, c)| match Csn::from_repl_str(c.as_str()) {
+            Some(csn) => Ok((k.clone(), csn)),
+            None => Err(OperationError::ReplicationFailed(format!(
+                "invalid csn {} for {} of {}",
+                c, k, re.uuid
+            ))),
+        })
+        .collect()
 }
 
 impl<STATE> Entry<EntryNormalised, STATE> {
+    // Check the entry against schema. Rather than stopping at the first
+    // problem, every missing must attribute, attribute not allowed by the
+    // entry's classes and invalid value is reported, so that the caller can
+    // fix them all at once. Problems with uuid and class are still returned
+    // on their own, as without them we can't work out what else to check.
     pub fn validate(
         self,
-        schema: &SchemaTransaction,
-    ) -> Result<Entry<EntryValid, STATE>, SchemaError> {
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Entry<EntryValid, STATE>, Vec<SchemaError>> {
         let schema_classes = schema.get_classes();
         let schema_attributes = schema.get_attributes();
 
         let uuid: String = match &self.attrs.get("uuid") {
             Some(vs) => match vs.first() {
                 Some(uuid) => uuid.to_string(),
-                None => return Err(SchemaError::MissingMustAttribute("uuid".to_string())),
+                None => return Err(vec![SchemaError::MissingMustAttribute("uuid".to_string())]),
             },
-            None => return Err(SchemaError::MissingMustAttribute("uuid".to_string())),
+            None => return Err(vec![SchemaError::MissingMustAttribute("uuid".to_string())]),
         };
 
         // Build the new valid entry ...
@@ -248,21 +385,25 @@ impl<STATE> Entry<EntryNormalised, STATE> {
             valid: EntryValid { uuid },
             state: self.state,
             attrs: self.attrs,
+            csns: self.csns,
         };
         // Now validate it!
+        let mut errs: Vec<SchemaError> = Vec::new();
 
         // We scope here to limit the time of borrow of ne.
         {
             // First, check we have class on the object ....
             if !ne.attribute_pres("class") {
                 debug!("Missing attribute class");
-                return Err(SchemaError::InvalidClass);
+                return Err(vec![SchemaError::InvalidClass]);
             }
 
             // Do we have extensible?
             let extensible = ne.attribute_value_pres("class", "extensibleobject");
 
-            let entry_classes = ne.classes().ok_or(SchemaError::InvalidClass)?;
+            let entry_classes = ne
+                .classes()
+                .ok_or_else(|| vec![SchemaError::InvalidClass])?;
             let entry_classes_size = entry_classes.len();
 
             let classes: Vec<&SchemaClass> = entry_classes
@@ -271,7 +412,7 @@ impl<STATE> Entry<EntryNormalised, STATE> {
 
             if classes.len() != entry_classes_size {
                 debug!("Class on entry not found in schema?");
-                return Err(SchemaError::InvalidClass);
+                return Err(vec![SchemaError::InvalidClass]);
             };
 
             // What this is really doing is taking a set of classes, and building an
@@ -286,14 +427,22 @@ impl<STATE> Entry<EntryNormalised, STATE> {
             //
             // NOTE: We still need this on extensible, because we still need to satisfy
             // our other must conditions as well!
-            let must: Result<Vec<&SchemaAttribute>, _> = classes
+            //
+            // Several classes can require the same attribute, so this is a map
+            // to only report each missing one once.
+            let must: Result<BTreeMap<&String, &SchemaAttribute>, Vec<SchemaError>> = classes
                 .iter()
                 // Join our class systemmmust + must into one iter
                 .flat_map(|cls| cls.systemmust.iter().chain(cls.must.iter()))
                 .map(|s| {
                     // This should NOT fail - if it does, it means our schema is
                     // in an invalid state!
-                    Ok(schema_attributes.get(s).ok_or(SchemaError::Corrupted)?)
+                    Ok((
+                        s,
+                        schema_attributes
+                            .get(s)
+                            .ok_or(vec![SchemaError::Corrupted])?,
+                    ))
                 })
                 .collect();
 
@@ -301,10 +450,10 @@ impl<STATE> Entry<EntryNormalised, STATE> {
 
             // Check that all must are inplace
             //   for each attr in must, check it's present on our ent
-            for attr in must {
+            for attr in must.values() {
                 let avas = ne.get_ava(&attr.name);
                 if avas.is_none() {
-                    return Err(SchemaError::MissingMustAttribute(attr.name.clone()));
+                    errs.push(SchemaError::MissingMustAttribute(attr.name.clone()));
                 }
             }
 
@@ -314,18 +463,14 @@ impl<STATE> Entry<EntryNormalised, STATE> {
                         Some(a_schema) => {
                             // Now, for each type we do a *full* check of the syntax
                             // and validity of the ava.
-                            let r = a_schema.validate_ava(avas);
-                            match r {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    debug!("Failed to validate: {}", attr_name);
-                                    return Err(e);
-                                }
+                            if let Err(e) = a_schema.validate_ava(avas) {
+                                debug!("Failed to validate: {}", attr_name);
+                                errs.push(e);
                             }
                         }
                         None => {
                             debug!("Invalid Attribute for extensible object");
-                            return Err(SchemaError::InvalidAttribute);
+                            errs.push(SchemaError::InvalidAttribute(attr_name.clone()));
                         }
                     }
                 }
@@ -333,7 +478,7 @@ impl<STATE> Entry<EntryNormalised, STATE> {
                 // We clone string here, but it's so we can check all
                 // the values in "may" ar here - so we can't avoid this look up. What we
                 // could do though, is have &String based on the schemaattribute though?;
-                let may: Result<HashMap<&String, &SchemaAttribute>, _> = classes
+                let may: Result<HashMap<&String, &SchemaAttribute>, Vec<SchemaError>> = classes
                     .iter()
                     // Join our class systemmmust + must + systemmay + may into one.
                     .flat_map(|cls| {
@@ -346,18 +491,17 @@ impl<STATE> Entry<EntryNormalised, STATE> {
                     .map(|s| {
                         // This should NOT fail - if it does, it means our schema is
                         // in an invalid state!
-                        Ok((s, schema_attributes.get(s).ok_or(SchemaError::Corrupted)?))
+                        Ok((
+                            s,
+                            schema_attributes
+                                .get(s)
+                                .ok_or(vec![SchemaError::Corrupted])?,
+                        ))
                     })
                     .collect();
 
                 let may = may?;
 
-                // TODO #70: Error needs to say what is missing
-                // We need to return *all* missing attributes, not just the first error
-                // we find. This will probably take a rewrite of the function definition
-                // to return a result<_, vec<schemaerror>> and for the schema errors to take
-                // information about what is invalid. It's pretty nontrivial.
-
                 // Check that any other attributes are in may
                 //   for each attr on the object, check it's in the may+must set
                 for (attr_name, avas) in ne.avas() {
@@ -366,26 +510,26 @@ impl<STATE> Entry<EntryNormalised, STATE> {
                         Some(a_schema) => {
                             // Now, for each type we do a *full* check of the syntax
                             // and validity of the ava.
-                            let r = a_schema.validate_ava(avas);
-                            match r {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    debug!("Failed to validate: {}", attr_name);
-                                    return Err(e);
-                                }
+                            if let Err(e) = a_schema.validate_ava(avas) {
+                                debug!("Failed to validate: {}", attr_name);
+                                errs.push(e);
                             }
                         }
                         None => {
                             debug!("Invalid Attribute for may+must set");
-                            return Err(SchemaError::InvalidAttribute);
+                            errs.push(SchemaError::InvalidAttribute(attr_name.clone()));
                         }
                     }
                 }
             }
         } // unborrow ne.
 
-        // Well, we got here, so okay!
-        Ok(ne)
+        if errs.is_empty() {
+            // Well, we got here, so okay!
+            Ok(ne)
+        } else {
+            Err(errs)
+        }
     }
 
     pub fn invalidate(self) -> Entry<EntryInvalid, STATE> {
@@ -393,6 +537,7 @@ impl<STATE> Entry<EntryNormalised, STATE> {
             valid: EntryInvalid,
             state: self.state,
             attrs: self.attrs,
+            csns: self.csns,
         }
     }
 
@@ -404,7 +549,7 @@ impl<STATE> Entry<EntryNormalised, STATE> {
 impl<STATE> Entry<EntryInvalid, STATE> {
     // This is only used in tests today, but I don't want to cfg test it.
     #[allow(dead_code)]
-    fn get_uuid(&self) -> Option<&String> {
+    fn get_uuid(&self) -> Option<&Value> {
         match self.attrs.get("uuid") {
             Some(vs) => vs.first(),
             None => None,
@@ -413,68 +558,73 @@ impl<STATE> Entry<EntryInvalid, STATE> {
 
     pub fn normalise(
         self,
-        schema: &SchemaTransaction,
+        schema: &dyn SchemaTransaction,
     ) -> Result<Entry<EntryNormalised, STATE>, SchemaError> {
         let Entry {
             valid: _,
             state,
             attrs,
+            csns,
         } = self;
 
         let schema_attributes = schema.get_attributes();
 
-        // This should never fail!
-        let schema_attr_name = match schema_attributes.get("name") {
-            Some(v) => v,
-            None => {
-                return Err(SchemaError::Corrupted);
-            }
-        };
-
-        let mut new_attrs = BTreeMap::new();
+        let mut new_attrs: BTreeMap<String, Vec<Value>> = BTreeMap::new();
 
         // First normalise - this checks and fixes our UUID format
         // but should not remove multiple values.
         for (attr_name, avas) in attrs.iter() {
-            let attr_name_normal: String = schema_attr_name.normalise_value(attr_name);
+            // Aliases and differently cased names end up under one name, so
+            // their values are merged.
+            let attr_name_normal: String = schema.normalise_attr_name(attr_name);
             // Get the needed schema type
             let schema_a_r = schema_attributes.get(&attr_name_normal);
 
-            let mut avas_normal: Vec<String> = match schema_a_r {
+            let avas_normal: Vec<Value> = match schema_a_r {
                 Some(schema_a) => {
                     avas.iter()
                         .map(|av| {
-                            // normalise those based on schema?
-                            schema_a.normalise_value(av)
+                            // Type the value for its syntax. If it isn't valid
+                            // we keep it as is, and validate reports it.
+                            schema_a.to_value(av).unwrap_or_else(|_| {
+                                Value::from(schema_a.normalise_value(&av.to_string()))
+                            })
                         })
                         .collect()
                 }
-                None => avas.clone(),
+                None => avas.as_ref().clone(),
             };
 
-            // Ensure they are ordered property, with no dupes.
-            avas_normal.sort_unstable();
-            avas_normal.dedup();
+            new_attrs
+                .entry(attr_name_normal)
+                .or_insert_with(Vec::new)
+                .extend(avas_normal);
+        }
 
-            // Should never fail!
-            let _ = new_attrs.insert(attr_name_normal, avas_normal);
+        // Ensure they are ordered property, with no dupes.
+        for avas in new_attrs.values_mut() {
+            avas.sort_unstable();
+            avas.dedup();
         }
 
         Ok(Entry {
             valid: EntryNormalised,
             state: state,
-            attrs: new_attrs,
+            attrs: share_attrs(new_attrs),
+            csns: csns,
         })
     }
 
     pub fn validate(
         self,
-        schema: &SchemaTransaction,
-    ) -> Result<Entry<EntryValid, STATE>, SchemaError> {
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Entry<EntryValid, STATE>, Vec<SchemaError>> {
         // We need to clone before we start, as well be mutating content.
         // We destructure:
 
-        self.normalise(schema).and_then(|e| e.validate(schema))
+        self.normalise(schema)
+            .map_err(|e| vec![e])
+            .and_then(|e| e.validate(schema))
     }
 }
 
@@ -489,6 +639,7 @@ where
             valid: self.valid.clone(),
             state: self.state,
             attrs: self.attrs.clone(),
+            csns: self.csns.clone(),
         }
     }
 }
@@ -506,6 +657,7 @@ impl Entry<EntryInvalid, EntryCommitted> {
             },
             state: EntryNew,
             attrs: self.attrs,
+            csns: self.csns,
         }
     }
 }
@@ -519,11 +671,12 @@ impl Entry<EntryInvalid, EntryNew> {
                 uuid: self.get_uuid().expect("Invalid uuid").to_string(),
             },
             state: EntryNew,
+            csns: self.csns,
             attrs: self
                 .attrs
                 .into_iter()
                 .map(|(k, mut v)| {
-                    v.sort_unstable();
+                    Arc::make_mut(&mut v).sort_unstable();
                     (k, v)
                 })
                 .collect(),
@@ -535,11 +688,12 @@ impl Entry<EntryInvalid, EntryNew> {
         Entry {
             valid: EntryNormalised,
             state: EntryNew,
+            csns: self.csns,
             attrs: self
                 .attrs
                 .into_iter()
                 .map(|(k, mut v)| {
-                    v.sort_unstable();
+                    Arc::make_mut(&mut v).sort_unstable();
                     (k, v)
                 })
                 .collect(),
@@ -556,11 +710,12 @@ impl Entry<EntryInvalid, EntryNew> {
                     .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string()),
             },
             state: EntryCommitted { id: 0 },
+            csns: self.csns,
             attrs: self
                 .attrs
                 .into_iter()
                 .map(|(k, mut v)| {
-                    v.sort_unstable();
+                    Arc::make_mut(&mut v).sort_unstable();
                     (k, v)
                 })
                 .collect(),
@@ -576,11 +731,12 @@ impl Entry<EntryInvalid, EntryCommitted> {
                 uuid: self.get_uuid().expect("Invalid uuid").to_string(),
             },
             state: self.state,
+            csns: self.csns,
             attrs: self
                 .attrs
                 .into_iter()
                 .map(|(k, mut v)| {
-                    v.sort_unstable();
+                    Arc::make_mut(&mut v).sort_unstable();
                     (k, v)
                 })
                 .collect(),
@@ -594,11 +750,12 @@ impl Entry<EntryValid, EntryNew> {
         Entry {
             valid: self.valid,
             state: EntryCommitted { id: 0 },
+            csns: self.csns,
             attrs: self
                 .attrs
                 .into_iter()
                 .map(|(k, mut v)| {
-                    v.sort_unstable();
+                    Arc::make_mut(&mut v).sort_unstable();
                     (k, v)
                 })
                 .collect(),
@@ -621,41 +778,174 @@ impl Entry<EntryValid, EntryCommitted> {
         self.attrs == rhs.attrs
     }
 
-    pub fn to_tombstone(&self) -> Self {
+    // The tombstone records csn as when its class changed, so that purging
+    // can tell how long it has been a tombstone.
+    pub fn to_tombstone(&self, csn: &Csn) -> Self {
         // Duplicate this to a tombstone entry.
-        let class_ava = vec!["object".to_string(), "tombstone".to_string()];
+        let class_ava = vec![
+            Value::Iutf8("object".to_string()),
+            Value::Iutf8("tombstone".to_string()),
+        ];
+
+        let mut attrs_new: BTreeMap<String, Arc<Vec<Value>>> = BTreeMap::new();
 
-        let mut attrs_new: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let uuid_v = match self.attrs.get("uuid").and_then(|vs| vs.first()) {
+            Some(v) => v.clone(),
+            None => Value::from(self.valid.uuid.clone()),
+        };
+        attrs_new.insert("uuid".to_string(), Arc::new(vec![uuid_v]));
+        attrs_new.insert("class".to_string(), Arc::new(class_ava));
 
-        attrs_new.insert("uuid".to_string(), vec![self.valid.uuid.clone()]);
-        attrs_new.insert("class".to_string(), class_ava);
+        let mut csns_new: BTreeMap<String, Csn> = self
+            .csns
+            .iter()
+            .filter(|(k, _)| attrs_new.contains_key(k.as_str()))
+            .map(|(k, c)| (k.clone(), *c))
+            .collect();
+        csns_new.insert("class".to_string(), *csn);
 
         Entry {
             valid: self.valid.clone(),
             state: self.state,
             attrs: attrs_new,
+            csns: csns_new,
         }
     }
 
-    pub fn get_id(&self) -> u64 {
-        self.state.id
+    // Would this entry still match once it no longer held any of these
+    // values? This is how an entry looks after the entries it refers to are
+    // deleted, and referential integrity has removed the references.
+    pub fn entry_match_no_index_without(
+        &self,
+        filter: &Filter<FilterValidResolved>,
+        removed: &BTreeSet<String>,
+    ) -> bool {
+        let mut e = self.clone();
+        e.attrs = e
+            .attrs
+            .into_iter()
+            .filter_map(|(attr, mut vs)| {
+                if vs.iter().any(|v| removed.contains(&v.to_string())) {
+                    Arc::make_mut(&mut vs).retain(|v| !removed.contains(&v.to_string()));
+                }
+                if vs.is_empty() {
+                    None
+                } else {
+                    Some((attr, vs))
+                }
+            })
+            .collect();
+        e.entry_match_no_index_inner(filter.to_inner())
     }
 
-    pub fn from_dbentry(db_e: DbEntry, id: u64) -> Option<Self> {
-        let attrs = match db_e.ent {
-            DbEntryVers::V1(v1) => v1.attrs,
+    // The entry as a replication supplier sends it.
+    pub fn to_repl_entry(&self) -> ReplEntry {
+        ReplEntry {
+            uuid: self.get_uuid().clone(),
+            attrs: Some(
+                self.attrs
+                    .iter()
+                    .map(|(k, vs)| (k.clone(), vs.iter().map(|v| v.to_string()).collect()))
+                    .collect(),
+            ),
+            csns: self
+                .csns
+                .iter()
+                .map(|(k, c)| (k.clone(), c.to_repl_string()))
+                .collect(),
+        }
+    }
+
+    // Merge a supplier's copy of this entry into ours, taking each attribute
+    // from whichever side changed it last. On a tie ours is kept - the same
+    // csn is the same change, seen twice. None if the supplier has nothing
+    // newer than we do.
+    pub fn repl_merge(
+        &self,
+        re: &ReplEntry,
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Option<Entry<EntryInvalid, EntryCommitted>>, OperationError> {
+        let r_attrs = match &re.attrs {
+            Some(a) => typed_attrs(a, schema)?,
+            None => BTreeMap::new(),
         };
+        let mut e = self.clone().invalidate();
+        let mut changed = false;
+        for (k, rc) in repl_csns(re)? {
+            if self.csns.get(&k).map(|lc| *lc < rc).unwrap_or(true) {
+                match r_attrs.get(&k) {
+                    Some(vs) => e.attrs.insert(k.clone(), vs.clone()),
+                    None => e.attrs.remove(&k),
+                };
+                e.csns.insert(k, rc);
+                changed = true;
+            }
+        }
+        Ok(if changed { Some(e) } else { None })
+    }
+
+    fn from_dbvalues(
+        db_attrs: BTreeMap<String, Vec<DbValueV1>>,
+    ) -> Option<BTreeMap<String, Arc<Vec<Value>>>> {
+        db_attrs
+            .into_iter()
+            .map(|(k, vs)| {
+                let vs: Option<Vec<Value>> = vs
+                    .into_iter()
+                    .map(|dv| match dv {
+                        DbValueV1::U8(s) => Some(Value::Utf8(s)),
+                        DbValueV1::I8(s) => Some(Value::Iutf8(s)),
+                        DbValueV1::PR(s) => Some(Value::Principal(s)),
+                        DbValueV1::UU(u) => Some(Value::Uuid(u)),
+                        DbValueV1::BO(b) => Some(Value::Bool(b)),
+                        DbValueV1::SY(s) => Value::new(&SyntaxType::SYNTAX_ID, &s).ok(),
+                        DbValueV1::IN(s) => Value::new(&SyntaxType::INDEX_ID, &s).ok(),
+                        DbValueV1::RF(u) => Some(Value::Reference(u)),
+                        DbValueV1::JF(s) => Some(Value::JsonFilter(s)),
+                        DbValueV1::DT(s) => Value::new(&SyntaxType::DATETIME, &s).ok(),
+                        DbValueV1::UR(s) => Value::new(&SyntaxType::URL, &s).ok(),
+                        DbValueV1::IT(i) => Some(Value::Integer(i)),
+                        DbValueV1::SK(s) => Value::new(&SyntaxType::SSHKEY, &s).ok(),
+                    })
+                    .collect();
+                vs.map(|vs| (k, Arc::new(vs)))
+            })
+            .collect()
+    }
+
+    pub fn from_dbentry(db_e: DbEntry, id: u64) -> Option<Self> {
+        // Entries from before V3 have no csns. They gain them as they change.
+        let (attrs, csns): (BTreeMap<String, Arc<Vec<Value>>>, BTreeMap<String, Csn>) =
+            match db_e.ent {
+                // V1 entries are untyped, and are typed again when next modified.
+                DbEntryVers::V1(v1) => (
+                    v1.attrs
+                        .into_iter()
+                        .map(|(k, vs)| (k, Arc::new(vs.into_iter().map(Value::from).collect())))
+                        .collect(),
+                    BTreeMap::new(),
+                ),
+                DbEntryVers::V2(v2) => (Self::from_dbvalues(v2.attrs)?, BTreeMap::new()),
+                DbEntryVers::V3(v3) => (
+                    Self::from_dbvalues(v3.attrs)?,
+                    v3.csns
+                        .into_iter()
+                        .map(|(k, dc)| (k, Csn::new(dc.ts, dc.rid.unwrap_or_else(Uuid::nil))))
+                        .collect(),
+                ),
+            };
 
         let uuid: String = match attrs.get("uuid") {
             Some(vs) => vs.first(),
             None => None,
         }?
-        .clone();
+        .to_string();
 
         Some(Entry {
             valid: EntryValid { uuid: uuid },
             state: EntryCommitted { id },
             attrs: attrs,
+            csns: csns,
         })
     }
 
@@ -665,6 +955,7 @@ impl Entry<EntryValid, EntryCommitted> {
             valid: EntryReduced,
             state: self.state,
             attrs: self.attrs,
+            csns: self.csns,
         }
     }
 
@@ -678,8 +969,14 @@ impl Entry<EntryValid, EntryCommitted> {
             valid: _s_valid,
             state: s_state,
             attrs: s_attrs,
+            csns: s_csns,
         } = self;
 
+        let f_csns: BTreeMap<_, _> = s_csns
+            .into_iter()
+            .filter(|(k, _)| allowed_attrs.contains(k.as_str()))
+            .collect();
+
         let f_attrs: BTreeMap<_, _> = s_attrs
             .into_iter()
             .filter_map(|(k, v)| {
@@ -695,6 +992,7 @@ impl Entry<EntryValid, EntryCommitted> {
             valid: EntryReduced,
             state: s_state,
             attrs: f_attrs,
+            csns: f_csns,
         }
     }
 
@@ -710,8 +1008,16 @@ impl Entry<EntryValid, EntryCommitted> {
     pub(crate) fn get_ava_opt_index(&self, attr: &str) -> Result<Vec<IndexType>, ()> {
         match self.attrs.get(attr) {
             Some(av) => {
-                let r: Result<Vec<_>, _> =
-                    av.iter().map(|v| IndexType::try_from(v.as_str())).collect();
+                let r: Result<Vec<_>, _> = av
+                    .iter()
+                    .map(|v| match v.to_index() {
+                        Some(i) => Ok(i.clone()),
+                        None => Value::new(&SyntaxType::INDEX_ID, &v.to_string())
+                            .ok()
+                            .and_then(|v| v.to_index().cloned())
+                            .ok_or(()),
+                    })
+                    .collect();
                 r
             }
             None => Ok(Vec::new()),
@@ -721,14 +1027,24 @@ impl Entry<EntryValid, EntryCommitted> {
     /// Get a bool from an ava
     pub fn get_ava_single_bool(&self, attr: &str) -> Option<bool> {
         match self.get_ava_single(attr) {
-            Some(a) => bool::from_str(a.as_str()).ok(),
+            Some(a) => match a.to_bool() {
+                Some(b) => Some(b),
+                None => Value::new(&SyntaxType::BOOLEAN, &a.to_string())
+                    .ok()
+                    .and_then(|v| v.to_bool()),
+            },
             None => None,
         }
     }
 
     pub fn get_ava_single_syntax(&self, attr: &str) -> Option<SyntaxType> {
         match self.get_ava_single(attr) {
-            Some(a) => SyntaxType::try_from(a.as_str()).ok(),
+            Some(a) => match a.to_syntax() {
+                Some(s) => Some(s.clone()),
+                None => Value::new(&SyntaxType::SYNTAX_ID, &a.to_string())
+                    .ok()
+                    .and_then(|v| v.to_syntax().cloned()),
+            },
             None => None,
         }
     }
@@ -739,7 +1055,7 @@ impl Entry<EntryValid, EntryCommitted> {
     /// are aware of the consequences.
     pub(crate) fn get_ava_opt(&self, attr: &str) -> Vec<String> {
         match self.attrs.get(attr) {
-            Some(a) => a.clone(),
+            Some(a) => a.iter().map(|v| v.to_string()).collect(),
             None => Vec::new(),
         }
     }
@@ -757,8 +1073,45 @@ impl<STATE> Entry<EntryValid, STATE> {
         // into proper structures, and they themself emit/modify entries?
 
         DbEntry {
-            ent: DbEntryVers::V1(DbEntryV1 {
-                attrs: self.attrs.clone(),
+            ent: DbEntryVers::V3(DbEntryV3 {
+                attrs: self
+                    .attrs
+                    .iter()
+                    .map(|(k, vs)| {
+                        let dvs = vs
+                            .iter()
+                            .map(|v| match v {
+                                Value::Utf8(s) => DbValueV1::U8(s.clone()),
+                                Value::Iutf8(s) => DbValueV1::I8(s.clone()),
+                                Value::Principal(s) => DbValueV1::PR(s.clone()),
+                                Value::Uuid(u) => DbValueV1::UU(u.clone()),
+                                Value::Bool(b) => DbValueV1::BO(*b),
+                                Value::Syntax(s) => DbValueV1::SY(s.to_string()),
+                                Value::Index(i) => DbValueV1::IN(i.to_string()),
+                                Value::Reference(u) => DbValueV1::RF(u.clone()),
+                                Value::JsonFilter(s) => DbValueV1::JF(s.clone()),
+                                Value::DateTime(dt) => DbValueV1::DT(dt.to_rfc3339()),
+                                Value::Url(u) => DbValueV1::UR(u.as_str().to_string()),
+                                Value::Integer(i) => DbValueV1::IT(*i),
+                                Value::SshKey(_, _) => DbValueV1::SK(v.to_string()),
+                            })
+                            .collect();
+                        (k.clone(), dvs)
+                    })
+                    .collect(),
+                csns: self
+                    .csns
+                    .iter()
+                    .map(|(k, c)| {
+                        (
+                            k.clone(),
+                            DbCsnV1 {
+                                ts: c.ts(),
+                                rid: Some(*c.rid()),
+                            },
+                        )
+                    })
+                    .collect(),
             }),
         }
     }
@@ -768,6 +1121,7 @@ impl<STATE> Entry<EntryValid, STATE> {
             valid: EntryInvalid,
             state: self.state,
             attrs: self.attrs,
+            csns: self.csns,
         }
     }
 
@@ -779,6 +1133,71 @@ impl<STATE> Entry<EntryValid, STATE> {
         self.entry_match_no_index_inner(filter.to_inner())
     }
 
+    // Explain why this entry does not match a filter term. Returns None if the
+    // entry matches, else the most specific term that caused the failure. For
+    // and terms this is the first failing child, but an or or andnot can only
+    // fail as a whole.
+    pub fn entry_match_trace<'a>(&self, filter: &'a FilterResolved) -> Option<&'a FilterResolved> {
+        match filter {
+            FilterResolved::And(l) => l.iter().filter_map(|f| self.entry_match_trace(f)).next(),
+            _ => {
+                if self.entry_match_no_index_inner(filter) {
+                    None
+                } else {
+                    Some(filter)
+                }
+            }
+        }
+    }
+
+    // Match a filter as though the entry only had the attributes in allowed.
+    // Terms on other attributes are undefined rather than false, like ldap,
+    // so that an andnot of something you can't see can't reveal it either.
+    pub fn entry_match_restricted(
+        &self,
+        filter: &Filter<FilterValidResolved>,
+        allowed: &BTreeSet<&str>,
+    ) -> bool {
+        self.entry_match_restricted_inner(filter.to_inner(), allowed) == Some(true)
+    }
+
+    fn entry_match_restricted_inner(
+        &self,
+        filter: &FilterResolved,
+        allowed: &BTreeSet<&str>,
+    ) -> Option<bool> {
+        match filter {
+            FilterResolved::Eq(attr, _)
+            | FilterResolved::Sub(attr, _)
+            | FilterResolved::Pres(attr)
+            | FilterResolved::Ge(attr, _)
+            | FilterResolved::Le(attr, _) => {
+                if allowed.contains(attr.as_str()) {
+                    Some(self.entry_match_no_index_inner(filter))
+                } else {
+                    None
+                }
+            }
+            // true wins, otherwise any undefined term makes the or undefined.
+            FilterResolved::Or(l) => l.iter().fold(Some(false), |acc, f| match acc {
+                Some(true) => acc,
+                _ => match self.entry_match_restricted_inner(f, allowed) {
+                    Some(false) => acc,
+                    r => r,
+                },
+            }),
+            // false wins, otherwise any undefined term makes the and undefined.
+            FilterResolved::And(l) => l.iter().fold(Some(true), |acc, f| match acc {
+                Some(false) => acc,
+                _ => match self.entry_match_restricted_inner(f, allowed) {
+                    Some(true) => acc,
+                    r => r,
+                },
+            }),
+            FilterResolved::AndNot(f) => self.entry_match_restricted_inner(f, allowed).map(|r| !r),
+        }
+    }
+
     pub fn filter_from_attrs(&self, attrs: &Vec<String>) -> Option<Filter<FilterInvalid>> {
         // Because we are a valid entry, a filter we create still may not
         // be valid because the internal server entry templates are still
@@ -791,13 +1210,13 @@ impl<STATE> Entry<EntryValid, STATE> {
 
         // Take name: (a, b), name: (c, d) -> (name, a), (name, b), (name, c), (name, d)
 
-        let mut pairs: Vec<(&str, &str)> = Vec::new();
+        let mut pairs: Vec<(&str, String)> = Vec::new();
 
         for attr in attrs {
             match self.attrs.get(attr) {
                 Some(values) => {
-                    for v in values {
-                        pairs.push((attr, v))
+                    for v in values.iter() {
+                        pairs.push((attr, v.to_string()))
                     }
                 }
                 None => return None,
@@ -806,15 +1225,15 @@ impl<STATE> Entry<EntryValid, STATE> {
 
         Some(filter_all!(f_and(
             pairs
-                .into_iter()
-                .map(|(attr, value)| f_eq(attr, value))
+                .iter()
+                .map(|(attr, value)| f_eq(attr, value.as_str()))
                 .collect()
         )))
     }
 
     pub fn gen_modlist_assert(
         &self,
-        schema: &SchemaTransaction,
+        schema: &dyn SchemaTransaction,
     ) -> Result<ModifyList<ModifyInvalid>, SchemaError> {
         // Create a modlist from this entry. We make this assuming we want the entry
         // to have this one as a subset of values. This means if we have single
@@ -852,13 +1271,60 @@ impl<STATE> Entry<EntryValid, STATE> {
                 // A schema error happened, fail the whole operation.
                 Err(e) => return Err(e),
             }
-            for v in vs {
+            for v in vs.iter() {
                 mods.push_mod(Modify::Present(k.clone(), v.clone()));
             }
         }
 
         Ok(mods)
     }
+
+    // The modifications that turn this entry into other. Where other keeps
+    // some of an attribute's values, the rest are removed one by one. Where it
+    // keeps none of them, the attribute is purged, which also keeps single
+    // value attributes valid when their value is replaced. As with
+    // gen_modlist_assert, uuid is never part of the result.
+    #[allow(dead_code)]
+    pub fn diff<OSTATE>(&self, other: &Entry<EntryValid, OSTATE>) -> ModifyList<ModifyInvalid> {
+        let mut mods = ModifyList::new();
+
+        for (k, vs) in self.attrs.iter() {
+            if k == "uuid" {
+                continue;
+            }
+            match other.attrs.get(k) {
+                Some(ovs) => {
+                    if vs.iter().any(|v| ovs.contains(v)) {
+                        vs.iter()
+                            .filter(|v| !ovs.contains(v))
+                            .for_each(|v| mods.push_mod(Modify::Removed(k.clone(), v.clone())));
+                    } else {
+                        mods.push_mod(Modify::Purged(k.clone()));
+                    }
+                    ovs.iter()
+                        .filter(|v| !vs.contains(v))
+                        .for_each(|v| mods.push_mod(Modify::Present(k.clone(), v.clone())));
+                }
+                None => mods.push_mod(Modify::Purged(k.clone())),
+            }
+        }
+
+        for (k, ovs) in other.attrs.iter() {
+            if k == "uuid" || self.attrs.contains_key(k) {
+                continue;
+            }
+            ovs.iter()
+                .for_each(|v| mods.push_mod(Modify::Present(k.clone(), v.clone())));
+        }
+
+        mods
+    }
+}
+
+impl<VALID> Entry<VALID, EntryCommitted> {
+    pub fn get_id(&self) -> u64 {
+        self.state.id
+    }
 }
 
 impl Entry<EntryReduced, EntryCommitted> {
@@ -869,14 +1335,37 @@ impl Entry<EntryReduced, EntryCommitted> {
         // for the conversion as algorithmically it may be
         // better to do this from the outside view. This can
         // of course be identified and changed ...
-        ProtoEntry {
-            attrs: self.attrs.clone(),
+        let mut attrs: BTreeMap<String, Vec<String>> = self
+            .attrs
+            .iter()
+            .map(|(k, vs)| (k.clone(), vs.iter().map(|v| v.to_string()).collect()))
+            .collect();
+        // last_modified is operational - it's not in schema, and can't be set
+        // or searched for. As the entry has already been reduced, it's the
+        // last change to an attribute the caller can see, so it doesn't give
+        // away changes to ones they can't.
+        if let Some(csn) = self.last_modified() {
+            attrs.insert("last_modified".to_string(), vec![csn.to_rfc3339()]);
         }
+        ProtoEntry { attrs: attrs }
     }
 }
 
 // impl<STATE> Entry<EntryValid, STATE> {
 impl<VALID, STATE> Entry<VALID, STATE> {
+    // The entry in its external json form, with each value as its
+    // normalised string.
+    pub fn to_external_json(&self) -> Result<String, OperationError> {
+        let ext = ExternalEntry {
+            attrs: self
+                .attrs
+                .iter()
+                .map(|(k, vs)| (k.clone(), vs.iter().map(|v| v.to_string()).collect()))
+                .collect(),
+        };
+        serde_json::to_string(&ext).map_err(|_| OperationError::SerdeJsonError)
+    }
+
     /*
      * WARNING: Should these TODO move to EntryValid only?
      * I've tried to do this once, but the issue is that there
@@ -884,20 +1373,22 @@ impl<VALID, STATE> Entry<VALID, STATE> {
      * relies on the ability to get ava. I think we may not be
      * able to do so "easily".
      */
-    pub fn get_ava(&self, attr: &str) -> Option<&Vec<String>> {
-        self.attrs.get(attr)
+    pub fn get_ava(&self, attr: &str) -> Option<&Vec<Value>> {
+        self.attrs.get(attr).map(|vs| vs.as_ref())
     }
 
+    // The string values of an attribute. Values that aren't strings, like
+    // uuids, are left out.
     pub fn get_ava_set(&self, attr: &str) -> Option<BTreeSet<&str>> {
         self.get_ava(attr).map(|vs| {
             // Map the vec to a BTreeSet instead.
-            let r: BTreeSet<&str> = vs.iter().map(|a| a.as_str()).collect();
+            let r: BTreeSet<&str> = vs.iter().filter_map(|a| a.to_str()).collect();
             r
         })
     }
 
     // Returns NONE if there is more than ONE!!!!
-    pub fn get_ava_single(&self, attr: &str) -> Option<&String> {
+    pub fn get_ava_single(&self, attr: &str) -> Option<&Value> {
         match self.attrs.get(attr) {
             Some(vs) => {
                 if vs.len() != 1 {
@@ -910,6 +1401,16 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    /// The csn of the last change to attr.
+    pub fn get_csn(&self, attr: &str) -> Option<&Csn> {
+        self.csns.get(attr)
+    }
+
+    /// The csn of the latest change to any attribute.
+    pub fn last_modified(&self) -> Option<&Csn> {
+        self.csns.values().max()
+    }
+
     pub fn get_ava_names(&self) -> BTreeSet<&str> {
         // Get the set of all attribute names in the entry
         let r: BTreeSet<&str> = self.attrs.keys().map(|a| a.as_str()).collect();
@@ -934,8 +1435,12 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         // that the equality here of the raw values MUST be correct.
         // We also normalise filters, to ensure that their values are
         // syntax valid and will correctly match here with our indexes.
+        self.attribute_equality_value(attr, &Value::from(value))
+    }
+
+    pub fn attribute_equality_value(&self, attr: &str, value: &Value) -> bool {
         match self.attrs.get(attr) {
-            Some(v_list) => match v_list.binary_search(&value.to_string()) {
+            Some(v_list) => match v_list.binary_search(value) {
                 Ok(_) => true,
                 Err(_) => false,
             },
@@ -943,6 +1448,24 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    // Does any value of attr sort at or after (ge) or at or before the given
+    // value?
+    pub fn attribute_ordered(&self, attr: &str, value: &Value, ge: bool) -> bool {
+        let outside = if ge {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| match v.cmp_ordered(value) {
+                Some(o) => o != outside,
+                None => false,
+            }),
+            None => false,
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn attribute_substring(&self, attr: &str, subvalue: &str) -> bool {
         match self.attrs.get(attr) {
             Some(v_list) => v_list
@@ -952,6 +1475,25 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    // Does any value of attr match the substring assertion? This is what a
+    // filter uses, and the others are in terms of it.
+    pub fn attribute_submatch(&self, attr: &str, sub: &SubMatch) -> bool {
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| v.matches_sub(sub)),
+            None => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn attribute_startswith(&self, attr: &str, prefix: &str) -> bool {
+        self.attribute_submatch(attr, &SubMatch::starts_with(prefix.to_string()))
+    }
+
+    #[allow(dead_code)]
+    pub fn attribute_endswith(&self, attr: &str, suffix: &str) -> bool {
+        self.attribute_submatch(attr, &SubMatch::ends_with(suffix.to_string()))
+    }
+
     pub fn classes(&self) -> Option<EntryClasses> {
         // Get the class vec, if any?
         // How do we indicate "empty?"
@@ -973,16 +1515,14 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         // Go through the filter components and check them in the entry.
         // This is recursive!!!!
         match filter {
-            FilterResolved::Eq(attr, value) => {
-                self.attribute_equality(attr.as_str(), value.as_str())
-            }
-            FilterResolved::Sub(attr, subvalue) => {
-                self.attribute_substring(attr.as_str(), subvalue.as_str())
-            }
+            FilterResolved::Eq(attr, value) => self.attribute_equality_value(attr.as_str(), value),
+            FilterResolved::Sub(attr, sub) => self.attribute_submatch(attr.as_str(), sub),
             FilterResolved::Pres(attr) => {
                 // Given attr, is is present in the entry?
                 self.attribute_pres(attr.as_str())
             }
+            FilterResolved::Ge(attr, value) => self.attribute_ordered(attr.as_str(), value, true),
+            FilterResolved::Le(attr, value) => self.attribute_ordered(attr.as_str(), value, false),
             FilterResolved::Or(l) => l.iter().fold(false, |acc, f| {
                 // Check with ftweedal about or filter zero len correctness.
                 if acc {
@@ -1013,12 +1553,16 @@ where
     // If this already exists, we silently drop the event? Is that an
     // acceptable interface?
     pub fn add_ava(&mut self, attr: &str, value: &str) {
+        self.add_ava_value(attr, Value::from(value))
+    }
+
+    pub fn add_ava_value(&mut self, attr: &str, value: Value) {
         // How do we make this turn into an ok / err?
         self.attrs
             .entry(attr.to_string())
             .and_modify(|v| {
                 // Here we need to actually do a check/binary search ...
-                match v.binary_search(&value.to_string()) {
+                match v.binary_search(&value) {
                     // It already exists, done!
                     Ok(_) => {}
                     Err(idx) => {
@@ -1026,23 +1570,24 @@ where
                         // Is there a better way?
                         //
                         // I think it's only run once anyway, so non-issue?
-                        v.insert(idx, value.to_string())
+                        Arc::make_mut(v).insert(idx, value.clone())
                     }
                 }
             })
-            .or_insert(vec![value.to_string()]);
+            .or_insert_with(|| Arc::new(vec![value]));
     }
 
     pub fn remove_ava(&mut self, attr: &str, value: &str) {
-        // It would be great to remove these extra allocations, but they
-        // really don't cost much :(
-        let mv = value.to_string();
+        self.remove_ava_value(attr, &Value::from(value))
+    }
+
+    pub fn remove_ava_value(&mut self, attr: &str, mv: &Value) {
         self.attrs.entry(attr.to_string()).and_modify(|v| {
             // Here we need to actually do a check/binary search ...
-            match v.binary_search(&mv) {
+            match v.binary_search(mv) {
                 // It exists, rm it.
                 Ok(idx) => {
-                    v.remove(idx);
+                    Arc::make_mut(v).remove(idx);
                 }
                 // It does not exist, move on.
                 Err(_) => {}
@@ -1055,9 +1600,9 @@ where
     }
 
     /// Overwrite the existing avas.
-    pub fn set_avas(&mut self, attr: &str, values: Vec<String>) {
+    pub fn set_avas(&mut self, attr: &str, values: Vec<Value>) {
         // Overwrite the existing value
-        let _ = self.attrs.insert(attr.to_string(), values);
+        let _ = self.attrs.insert(attr.to_string(), Arc::new(values));
     }
 
     pub fn avas_mut(&mut self) -> EntryAvasMut {
@@ -1068,20 +1613,36 @@ where
 
     // Should this be schemaless, relying on checks of the modlist, and the entry validate after?
     // YES. Makes it very cheap.
-    pub fn apply_modlist(&mut self, modlist: &ModifyList<ModifyValid>) {
+    pub fn apply_modlist(&mut self, modlist: &ModifyList<ModifyValid>, csn: &Csn) {
         // -> Result<Entry<EntryInvalid, STATE>, OperationError> {
         // Apply a modlist, generating a new entry that conforms to the changes.
         // This is effectively clone-and-transform
 
         // mutate
         for modify in modlist {
-            match modify {
-                Modify::Present(a, v) => self.add_ava(a.as_str(), v.as_str()),
-                Modify::Removed(a, v) => self.remove_ava(a.as_str(), v.as_str()),
-                Modify::Purged(a) => self.purge_ava(a.as_str()),
-            }
+            let a = match modify {
+                Modify::Present(a, v) => {
+                    self.add_ava_value(a.as_str(), v.clone());
+                    a
+                }
+                Modify::Removed(a, v) => {
+                    self.remove_ava_value(a.as_str(), v);
+                    a
+                }
+                Modify::Purged(a) => {
+                    self.purge_ava(a.as_str());
+                    a
+                }
+            };
+            self.csns.insert(a.clone(), *csn);
         }
     }
+
+    // Mark every attribute as changed at csn, as when the entry is created.
+    pub fn set_csn_all(&mut self, csn: &Csn) {
+        let csns: BTreeMap<String, Csn> = self.attrs.keys().map(|k| (k.clone(), *csn)).collect();
+        self.csns = csns;
+    }
 }
 
 impl<VALID, STATE> PartialEq for Entry<VALID, STATE> {
@@ -1104,35 +1665,39 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
     fn from(s: &SchemaAttribute) -> Self {
         // Convert an Attribute to an entry ... make it good!
         let uuid_str = s.uuid.to_hyphenated().to_string();
-        let uuid_v = vec![uuid_str.clone()];
+        let uuid_v = vec![Value::Uuid(s.uuid.clone())];
 
-        let name_v = vec![s.name.clone()];
-        let desc_v = vec![s.description.clone()];
+        let name_v = vec![Value::Iutf8(s.name.clone())];
+        let desc_v = vec![Value::Utf8(s.description.clone())];
 
-        let multivalue_v = vec![if s.multivalue {
-            "true".to_string()
-        } else {
-            "false".to_string()
-        }];
+        let multivalue_v = vec![Value::Bool(s.multivalue)];
+        let unique_v = vec![Value::Bool(s.unique)];
 
-        let index_v: Vec<_> = s.index.iter().map(|i| i.to_string()).collect();
+        let mut index_v: Vec<_> = s.index.iter().map(|i| Value::Index(i.clone())).collect();
+        index_v.sort_unstable();
 
-        let syntax_v = vec![s.syntax.to_string()];
+        let syntax_v = vec![Value::Syntax(s.syntax.clone())];
 
         // Build the BTreeMap of the attributes relevant
-        let mut attrs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut attrs: BTreeMap<String, Vec<Value>> = BTreeMap::new();
         attrs.insert("name".to_string(), name_v);
         attrs.insert("description".to_string(), desc_v);
         attrs.insert("uuid".to_string(), uuid_v);
         attrs.insert("multivalue".to_string(), multivalue_v);
+        attrs.insert("unique".to_string(), unique_v);
         attrs.insert("index".to_string(), index_v);
         attrs.insert("syntax".to_string(), syntax_v);
+        if !s.alias.is_empty() {
+            let mut alias_v: Vec<_> = s.alias.iter().map(|a| Value::Iutf8(a.clone())).collect();
+            alias_v.sort_unstable();
+            attrs.insert("alias".to_string(), alias_v);
+        }
         attrs.insert(
             "class".to_string(),
             vec![
-                "object".to_string(),
-                "system".to_string(),
-                "attributetype".to_string(),
+                Value::Iutf8("attributetype".to_string()),
+                Value::Iutf8("object".to_string()),
+                Value::Iutf8("system".to_string()),
             ],
         );
 
@@ -1143,7 +1708,8 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
                 uuid: uuid_str.clone(),
             },
             state: EntryNew,
-            attrs: attrs,
+            attrs: share_attrs(attrs),
+            csns: BTreeMap::new(),
         }
     }
 }
@@ -1151,40 +1717,63 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
 impl From<&SchemaClass> for Entry<EntryValid, EntryNew> {
     fn from(s: &SchemaClass) -> Self {
         let uuid_str = s.uuid.to_hyphenated().to_string();
-        let uuid_v = vec![uuid_str.clone()];
+        let uuid_v = vec![Value::Uuid(s.uuid.clone())];
 
-        let name_v = vec![s.name.clone()];
-        let desc_v = vec![s.description.clone()];
+        let name_v = vec![Value::Iutf8(s.name.clone())];
+        let desc_v = vec![Value::Utf8(s.description.clone())];
+
+        let mut systemmay_v: Vec<_> = s
+            .systemmay
+            .iter()
+            .map(|a| Value::Iutf8(a.clone()))
+            .collect();
+        systemmay_v.sort_unstable();
+        let mut systemmust_v: Vec<_> = s
+            .systemmust
+            .iter()
+            .map(|a| Value::Iutf8(a.clone()))
+            .collect();
+        systemmust_v.sort_unstable();
 
-        let mut attrs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut attrs: BTreeMap<String, Vec<Value>> = BTreeMap::new();
         attrs.insert("name".to_string(), name_v);
         attrs.insert("description".to_string(), desc_v);
         attrs.insert("uuid".to_string(), uuid_v);
         attrs.insert(
             "class".to_string(),
             vec![
-                "object".to_string(),
-                "system".to_string(),
-                "classtype".to_string(),
+                Value::Iutf8("classtype".to_string()),
+                Value::Iutf8("object".to_string()),
+                Value::Iutf8("system".to_string()),
             ],
         );
-        attrs.insert("systemmay".to_string(), s.systemmay.clone());
-        attrs.insert("systemmust".to_string(), s.systemmust.clone());
+        attrs.insert("systemmay".to_string(), systemmay_v);
+        attrs.insert("systemmust".to_string(), systemmust_v);
 
         Entry {
             valid: EntryValid {
                 uuid: uuid_str.clone(),
             },
             state: EntryNew,
-            attrs: attrs,
+            attrs: share_attrs(attrs),
+            csns: BTreeMap::new(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::audit::AuditScope;
+    use crate::csn::Csn;
     use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::{OperationError, SchemaError};
+    use crate::filter::SubMatch;
     use crate::modify::{Modify, ModifyList};
+    use crate::schema::{Schema, SyntaxType};
+    use crate::value::Value;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
     // use serde_json;
 
     #[test]
@@ -1246,6 +1835,49 @@ mod tests {
         assert!(!e.attribute_substring("userid", "wl"));
     }
 
+    #[test]
+    fn test_entry_anchored_substring() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+
+        e.add_ava("userid", "william");
+
+        assert!(e.attribute_startswith("userid", "will"));
+        assert!(e.attribute_startswith("userid", "william"));
+        assert!(!e.attribute_startswith("userid", "liam"));
+        assert!(e.attribute_endswith("userid", "liam"));
+        assert!(!e.attribute_endswith("userid", "will"));
+        assert!(!e.attribute_startswith("nonexist", "will"));
+
+        // The parts must come in order, and not share characters.
+        let sub = |start: &str, any: &[&str], end: &str| SubMatch {
+            start: Some(start.to_string()),
+            any: any.iter().map(|a| a.to_string()).collect(),
+            end: Some(end.to_string()),
+        };
+        assert!(e.attribute_submatch("userid", &sub("w", &["l", "l"], "m")));
+        assert!(e.attribute_submatch("userid", &sub("wi", &[], "iam")));
+        assert!(!e.attribute_submatch("userid", &sub("will", &[], "lliam")));
+        assert!(!e.attribute_submatch("userid", &sub("w", &["a", "i"], "m")));
+    }
+
+    #[test]
+    fn test_entry_ordered() {
+        let dt = |s: &str| Value::new(&SyntaxType::DATETIME, s).expect("Invalid datetime");
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+
+        e.add_ava_value("last_auth", dt("2019-06-01T12:00:00+10:00"));
+        e.add_ava("userid", "william");
+
+        // The offset is taken into account, not the string form.
+        assert!(e.attribute_ordered("last_auth", &dt("2019-06-01T02:00:00Z"), true));
+        assert!(!e.attribute_ordered("last_auth", &dt("2019-06-01T02:00:01Z"), true));
+        assert!(e.attribute_ordered("last_auth", &dt("2019-06-01T02:00:00Z"), false));
+        assert!(!e.attribute_ordered("last_auth", &dt("2019-06-01T01:59:59Z"), false));
+        // Values without an order never match.
+        assert!(!e.attribute_ordered("userid", &Value::from("a"), true));
+        assert!(!e.attribute_ordered("nonexist", &dt("2019-06-01T02:00:00Z"), true));
+    }
+
     #[test]
     fn test_entry_apply_modlist() {
         // Test application of changes to an entry.
@@ -1255,11 +1887,11 @@ mod tests {
         let mods = unsafe {
             ModifyList::new_valid_list(vec![Modify::Present(
                 String::from("attr"),
-                String::from("value"),
+                Value::from("value"),
             )])
         };
 
-        e.apply_modlist(&mods);
+        e.apply_modlist(&mods, &Csn::now());
 
         // Assert the changes are there
         assert!(e.attribute_equality("attr", "value"));
@@ -1268,4 +1900,159 @@ mod tests {
         // Assert purge on single/multi/empty value
         // Assert removed on value that exists and doesn't exist
     }
+
+    #[test]
+    fn test_entry_shared_values() {
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("member", "a");
+        e1.add_ava("member", "b");
+        e1.add_ava("userid", "william");
+
+        // A copy shares the values until it changes them.
+        let mut e2 = e1.clone();
+        assert!(Arc::ptr_eq(&e1.attrs["member"], &e2.attrs["member"]));
+        e2.add_ava("member", "c");
+        assert!(!Arc::ptr_eq(&e1.attrs["member"], &e2.attrs["member"]));
+        assert!(Arc::ptr_eq(&e1.attrs["userid"], &e2.attrs["userid"]));
+        assert!(!e1.attribute_equality("member", "c"));
+        assert!(e2.attribute_equality("member", "c"));
+    }
+
+    #[test]
+    fn test_entry_csn() {
+        let c1 = Csn::new(Duration::from_secs(1), Uuid::nil());
+        let c2 = Csn::new(Duration::from_secs(2), Uuid::nil());
+
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+        e.add_ava("userid", "william");
+        e.set_csn_all(&c1);
+
+        let mods = unsafe {
+            ModifyList::new_valid_list(vec![
+                Modify::Present(String::from("attr"), Value::from("value")),
+                Modify::Purged(String::from("userid")),
+            ])
+        };
+        e.apply_modlist(&mods, &c2);
+
+        assert!(e.get_csn("uuid") == Some(&c1));
+        assert!(e.get_csn("attr") == Some(&c2));
+        // Purging still records when the attribute went away.
+        assert!(e.get_csn("userid") == Some(&c2));
+        assert!(e.last_modified() == Some(&c2));
+
+        // The csns are kept by the db.
+        let e = unsafe { e.to_valid_committed() };
+        let e2 = Entry::from_dbentry(e.into_dbentry(), 1).expect("Failed to load dbentry");
+        assert!(e2.get_csn("uuid") == Some(&c1));
+        assert!(e2.get_csn("userid") == Some(&c2));
+    }
+
+    #[test]
+    fn test_entry_external_json() {
+        let mut audit = AuditScope::new("test_entry_external_json");
+        let schema_outer = Schema::new(&mut audit).expect("failed to create schema");
+        let schema = schema_outer.read();
+
+        let e: Entry<EntryInvalid, EntryNew> = Entry::from_external_json(
+            r#"{
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "name": ["Test_Attr"],
+                    "uuid": ["CC8E95B4-C24F-4D68-BA54-8BED76F63930"],
+                    "multivalue": ["true"],
+                    "description": ["b", "a", "b"]
+                }
+            }"#,
+            &schema,
+        )
+        .expect("failed to import");
+        // Values are typed and normalised as they are read.
+        assert!(e.attribute_equality("name", "test_attr"));
+        assert!(
+            e.get_ava("multivalue")
+                .and_then(|vs| vs.first())
+                .and_then(|v| v.to_bool())
+                == Some(true)
+        );
+        assert!(e.get_ava("description").map(|v| v.len()) == Some(2));
+
+        // What goes out comes back the same.
+        let json = e.to_external_json().expect("failed to export");
+        assert!(!json.contains("valid") && !json.contains("state"));
+        let e2: Entry<EntryInvalid, EntryNew> =
+            Entry::from_external_json(json.as_str(), &schema).expect("failed to import");
+        assert!(e == e2);
+
+        // The internal form, attributes not in schema and invalid values are
+        // all refused.
+        assert!(
+            Entry::from_external_json(r#"{"valid": null, "state": null, "attrs": {}}"#, &schema)
+                == Err(OperationError::SerdeJsonError)
+        );
+        assert!(
+            Entry::from_external_json(r#"{"attrs": {"nonexist": ["a"]}}"#, &schema)
+                == Err(OperationError::SchemaViolation(
+                    SchemaError::InvalidAttribute("nonexist".to_string())
+                ))
+        );
+        assert!(
+            Entry::from_external_json(r#"{"attrs": {"uuid": ["not a uuid"]}}"#, &schema)
+                == Err(OperationError::SchemaViolation(
+                    SchemaError::InvalidAttributeSyntax
+                ))
+        );
+    }
+
+    #[test]
+    fn test_entry_diff() {
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+        e1.add_ava("class", "person");
+        e1.add_ava("name", "william");
+        e1.add_ava("description", "old");
+        e1.add_ava("mail", "a@example.com");
+        e1.add_ava("mail", "b@example.com");
+        let e1 = unsafe { e1.to_valid_new() };
+
+        let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e2.add_ava("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63931");
+        e2.add_ava("class", "person");
+        e2.add_ava("name", "claire");
+        e2.add_ava("mail", "b@example.com");
+        e2.add_ava("mail", "c@example.com");
+        e2.add_ava("displayname", "Claire");
+        let e2 = unsafe { e2.to_valid_new() };
+
+        assert!(e1.diff(&e1).len() == 0);
+
+        // description purged, mail a removed and c present, name purged and
+        // replaced, displayname present. class is unchanged and uuid is left
+        // alone.
+        let mods = e1.diff(&e2);
+        assert!(mods.len() == 6);
+
+        let mods = unsafe { mods.to_valid() };
+        let mut e1 = e1.invalidate();
+        e1.apply_modlist(&mods, &Csn::now());
+        assert!(e1.attribute_equality("name", "claire"));
+        assert!(!e1.attribute_pres("description"));
+        assert!(!e1.attribute_equality("mail", "a@example.com"));
+        assert!(e1.attribute_equality("mail", "c@example.com"));
+        assert!(e1.attribute_equality("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63930"));
+    }
+
+    #[test]
+    fn test_entry_debug_redacts_credentials() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("name", "william");
+        e.add_ava("password", "correct horse");
+        let out = format!("{:?}", e);
+        assert!(out.contains("william"));
+        assert!(!out.contains("correct horse"));
+
+        let m = Modify::Present("password".to_string(), Value::from("correct horse"));
+        assert!(!format!("{:?}", m).contains("correct horse"));
+    }
 }
@@ -5,8 +5,21 @@ pub enum SchemaError {
     NotImplemented,
     InvalidClass,
     MissingMustAttribute(String),
-    InvalidAttribute,
+    InvalidAttribute(String),
     InvalidAttributeSyntax,
+    // A value that isn't valid for its attribute's syntax - the attribute,
+    // and the value.
+    InvalidValue(String, String),
+    // Several values for an attribute that may only have one - the attribute,
+    // and how many it was given. The values aren't echoed back, as they may
+    // be secrets.
+    MultipleValues(String, usize),
+    // The value that isn't a url, and why.
+    InvalidUrl(String, String),
+    // The value that isn't a tagged ssh public key, and why.
+    InvalidSshKey(String, String),
+    // An ordering filter on an attribute whose syntax has no order.
+    UnorderedSyntax(String),
     EmptyFilter,
     Corrupted,
 }
@@ -17,10 +30,16 @@ pub enum OperationError {
     Backend,
     NoMatchingEntries,
     CorruptedEntry,
+    // The index of this attribute can't be read.
+    CorruptedIndex(String),
     ConsistencyError(Vec<Result<(), ConsistencyError>>),
     SchemaViolation(SchemaError),
+    // An entry failed schema, with everything that is wrong with it.
+    EntrySchemaViolation(Vec<SchemaError>),
     Plugin,
     FilterGeneration,
+    // A filter string couldn't be parsed, and why.
+    InvalidFilter(String),
     FilterUUIDResolution,
     InvalidDBState,
     InvalidEntryID,
@@ -30,6 +49,7 @@ pub enum OperationError {
     InvalidACPState(&'static str),
     InvalidSchemaState(&'static str),
     InvalidAccountState(&'static str),
+    InvalidDomainState(&'static str),
     BackendEngine,
     SQLiteError, //(RusqliteError)
     FsError,
@@ -40,6 +60,51 @@ pub enum OperationError {
     InvalidAuthState(&'static str),
     InvalidSessionState,
     SystemProtectedObject,
+    InvalidDbKey(&'static str),
+    // A unique attribute value is already held by the entry with this uuid.
+    Duplicate(String),
+    // A rename to or from one of the reserved names.
+    ReservedName(String),
+    // A rename to a name that is already held. Only the name is given, as the
+    // holder may not be visible to the caller.
+    NameInUse(String),
+    // A request went over one of the configured limits, which is given.
+    RequestTooLarge(usize),
+    TooManyEntries(usize),
+    ModlistTooLong(usize),
+    // Anonymous searches are held to stricter limits, from system_config.
+    RateLimited,
+    SizeLimitExceeded(usize),
+    // A paged search was given a cookie it didn't issue.
+    InvalidPagingCookie,
+    SubstringNotPermitted,
+    // A filter nests too deeply or has too many terms.
+    FilterTooComplex,
+    // Online backups need a backup_path in the server configuration.
+    BackupNotConfigured,
+    // Reading the audit log needs an audit_log_path in the server configuration.
+    AuditLogNotConfigured,
+    // The changes asked for have been trimmed from the changelog.
+    ChangelogTrimmed,
+    // A replication supplier couldn't be reached, or sent something we can't
+    // apply.
+    ReplicationFailed(String),
+    // Hashing a credential failed.
+    CryptographyError,
+    // An oauth2 request was refused, with the rfc6749 error code to return.
+    Oauth2(&'static str),
+    // An ldap message we can't decode, and why.
+    InvalidLdapMessage(&'static str),
+    // A SCIM request we can't make sense of, and why.
+    InvalidScimRequest(&'static str),
+    // A credential reset token that is malformed, spent, expired or wrong.
+    // Which is never said, so tokens can't be probed.
+    InvalidCredentialResetToken,
+    // An account may only have this many subscriptions open at once.
+    TooManySubscriptions(usize),
+    // A dyngroup_filter that can't be used as a filter, and why.
+    InvalidDynGroupFilter(&'static str),
+    InvalidEntryTemplate(&'static str),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -47,10 +112,29 @@ pub enum ConsistencyError {
     Unknown,
     // Class, Attribute
     SchemaClassMissingAttribute(String, String),
+    // Attribute, and an alias of it that is also another attribute's name
+    // or alias.
+    SchemaAliasConflict(String, String),
     QueryServerSearchFailure,
     EntryUuidCorrupt(u64),
     UuidIndexCorrupt(String),
     UuidNotUnique(String),
     RefintNotUpheld(u64),
     MemberOfInvalid(u64),
+    DynGroupMemberInvalid(u64),
+    ManagedEntryInvalid(u64),
+    BackendQueryFailure,
+    EntryQuarantined(u64),
+    // Acp uuid, the test case that did not hold.
+    AcpTestFailed(String, String),
+    // Attribute, the value held by more than one entry.
+    AttrNotUnique(String, String),
+    // Attribute, index type, key and the entry id it should or shouldn't
+    // be under.
+    IndexKeyMissing(String, String, String, u64),
+    IndexKeyStale(String, String, String, u64),
+    // The entry id, and how it doesn't fit the schema.
+    EntrySchemaInvalid(u64, Vec<SchemaError>),
+    // The entry id of a tombstone that holds more than it should.
+    TombstoneInvalid(u64),
 }
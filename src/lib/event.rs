@@ -1,36 +1,50 @@
-use crate::audit::AuditScope;
+use crate::audit::{parse_log_level, AuditLevel, AuditScope};
+use crate::constants::{TYPEAHEAD_DEFAULT_RESULTS, TYPEAHEAD_MAX_RESULTS, UUID_ANONYMOUS};
+use crate::domain::DomainInfo;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
 use crate::filter::{Filter, FilterValid};
+use crate::idm::account::ValidityWindow;
+use crate::proto::v1::DomainInfo as ProtoDomainInfo;
 use crate::proto::v1::Entry as ProtoEntry;
+use crate::proto::v1::Filter as ProtoFilter;
+use crate::proto::v1::ModifyList as ProtoModifyList;
 use crate::proto::v1::{
-    AuthCredential, AuthResponse, AuthState, AuthStep, CreateRequest, DeleteRequest, ModifyRequest,
-    ReviveRecycledRequest, SearchRequest, SearchResponse, UserAuthToken, WhoamiResponse,
+    AcpCoverageRequest, AuditLogRequest, AuditRecord, AuthCredential, AuthResponse, AuthState,
+    AuthStep, BackupRequest, BatchOperation, BatchRequest, CompareRequest, CreateRequest,
+    CredentialResetIssueRequest, DeletePreviewRequest, DeleteRequest, EffectivePermissionsRequest,
+    GroupJoinCreateRequest, GroupJoinDecideRequest, GroupJoinListRequest, HostSecretRotateRequest,
+    LogLevelRequest, MemoryReportRequest, ModifyRequest, Oauth2TokenRequest,
+    RadiusSecretReadRequest, RadiusSecretRegenerateRequest, RenameRequest, ReplChangesRequest,
+    ReviveRecycledRequest, SearchPaging, SearchRecycledRequest, SearchRequest, SearchResponse,
+    SearchTrace, SyncRequest, TypeaheadRequest, UserAuthToken, WhoamiResponse,
 };
 // use error::OperationError;
-use crate::error::OperationError;
+use crate::error::{OperationError, SchemaError};
 use crate::modify::{ModifyList, ModifyValid};
 use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
 
-use crate::proto::v1::messages::AuthMessage;
-// Bring in schematransaction trait for validate
-// use crate::schema::SchemaTransaction;
+use crate::proto::v1::messages::{AuthMessage, Oauth2AuthoriseMessage};
+use crate::schema::SchemaTransaction;
 
 // Only used for internal tests
 #[cfg(test)]
 use crate::filter::FilterInvalid;
 #[cfg(test)]
 use crate::modify::ModifyInvalid;
-#[cfg(test)]
-use crate::proto::v1::SearchRecycledRequest;
 
 use actix::prelude::*;
+use chrono::{DateTime, FixedOffset};
+use std::collections::BTreeMap;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct SearchResult {
     entries: Vec<ProtoEntry>,
+    trace: Option<SearchTrace>,
+    cookie: Option<String>,
 }
 
 impl SearchResult {
@@ -45,13 +59,50 @@ impl SearchResult {
                     e.into_pe()
                 })
                 .collect(),
+            trace: None,
+            cookie: None,
         }
     }
 
+    // Build the result as a streaming search yields its entries, so each is
+    // only held in its reduced form until it has been converted.
+    pub fn new_iter<I>(entries: I) -> Result<Self, OperationError>
+    where
+        I: Iterator<Item = Result<Entry<EntryReduced, EntryCommitted>, OperationError>>,
+    {
+        let entries: Result<Vec<ProtoEntry>, OperationError> =
+            entries.map(|r| r.map(|e| e.into_pe())).collect();
+        Ok(SearchResult {
+            entries: entries?,
+            trace: None,
+            cookie: None,
+        })
+    }
+
+    pub fn new_page(
+        entries: Vec<Entry<EntryReduced, EntryCommitted>>,
+        cookie: Option<String>,
+    ) -> Self {
+        let mut sr = Self::new(entries);
+        sr.cookie = cookie;
+        sr
+    }
+
+    pub fn new_trace(
+        entries: Vec<Entry<EntryReduced, EntryCommitted>>,
+        trace: SearchTrace,
+    ) -> Self {
+        let mut sr = Self::new(entries);
+        sr.trace = Some(trace);
+        sr
+    }
+
     // Consume self into a search response
     pub fn response(self) -> SearchResponse {
         SearchResponse {
             entries: self.entries,
+            trace: self.trace,
+            cookie: self.cookie,
         }
     }
 }
@@ -70,6 +121,40 @@ pub enum EventOrigin {
     // Replication,
 }
 
+// An account's sessions end with its validity window, not just its
+// authentication, so each request is checked too.
+fn check_account_validity(
+    audit: &mut AuditScope,
+    e: &Entry<EntryValid, EntryCommitted>,
+    now: Duration,
+) -> Result<(), OperationError> {
+    if ValidityWindow::from_entry(e).contains(now) {
+        Ok(())
+    } else {
+        audit_log!(audit, "Account {} is not valid at this time", e.get_uuid());
+        Err(OperationError::NotAuthenticated)
+    }
+}
+
+// Break glass sessions end with their secret. An expiry we can't read is
+// treated as passed.
+fn check_session_expiry(
+    audit: &mut AuditScope,
+    uat: &UserAuthToken,
+    now: Duration,
+) -> Result<(), OperationError> {
+    if let Some(expiry) = &uat.expiry {
+        let valid = DateTime::parse_from_rfc3339(expiry.as_str())
+            .map(|dt| dt.timestamp() > now.as_secs() as i64)
+            .unwrap_or(false);
+        if !valid {
+            audit_log!(audit, "Session for {} expired at {}", uat.name, expiry);
+            return Err(OperationError::NotAuthenticated);
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct Event {
     // The event's initiator aka origin source.
@@ -88,6 +173,8 @@ impl Event {
         //
         // For now, no.
         let e = try_audit!(audit, qs.internal_search_uuid(audit, user_uuid));
+        audit.set_origin(Some(e.get_uuid().as_str()));
+        check_account_validity(audit, &e, qs.now())?;
 
         Ok(Event {
             origin: EventOrigin::User(e),
@@ -101,8 +188,11 @@ impl Event {
     ) -> Result<Self, OperationError> {
         audit_log!(audit, "from_ro_uat -> {:?}", uat);
         let uat = uat.ok_or(OperationError::NotAuthenticated)?;
+        check_session_expiry(audit, &uat, qs.now())?;
 
         let e = try_audit!(audit, qs.internal_search_uuid(audit, uat.uuid.as_str()));
+        audit.set_origin(Some(e.get_uuid().as_str()));
+        check_account_validity(audit, &e, qs.now())?;
         // TODO #64: Now apply claims from the uat into the Entry
         // to allow filtering.
 
@@ -111,6 +201,24 @@ impl Event {
         })
     }
 
+    pub fn from_rw_uat(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        uat: Option<UserAuthToken>,
+    ) -> Result<Self, OperationError> {
+        audit_log!(audit, "from_rw_uat -> {:?}", uat);
+        let uat = uat.ok_or(OperationError::NotAuthenticated)?;
+        check_session_expiry(audit, &uat, qs.now())?;
+
+        let e = try_audit!(audit, qs.internal_search_uuid(audit, uat.uuid.as_str()));
+        audit.set_origin(Some(e.get_uuid().as_str()));
+        check_account_validity(audit, &e, qs.now())?;
+
+        Ok(Event {
+            origin: EventOrigin::User(e),
+        })
+    }
+
     pub fn from_rw_request(
         audit: &mut AuditScope,
         qs: &QueryServerWriteTransaction,
@@ -121,6 +229,8 @@ impl Event {
         //
         // For now, no.
         let e = try_audit!(audit, qs.internal_search_uuid(audit, user_uuid));
+        audit.set_origin(Some(e.get_uuid().as_str()));
+        check_account_validity(audit, &e, qs.now())?;
 
         Ok(Event {
             origin: EventOrigin::User(e),
@@ -133,7 +243,6 @@ impl Event {
         }
     }
 
-    #[cfg(test)]
     pub fn from_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
         Event {
             origin: EventOrigin::User(e),
@@ -161,6 +270,13 @@ impl Event {
             _ => false,
         }
     }
+
+    pub fn is_anonymous(&self) -> bool {
+        match &self.origin {
+            EventOrigin::User(e) => e.get_uuid() == UUID_ANONYMOUS,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -170,16 +286,105 @@ pub struct SearchEvent {
     pub filter: Filter<FilterValid>,
     // This is the original filter, for the purpose of ACI checking.
     pub filter_orig: Filter<FilterValid>,
+    // Should the evaluation of this search be recorded and returned?
+    pub trace: bool,
+    // When set, filter terms on attributes the caller can't read are treated
+    // as undefined, rather than excluding the whole entry from the results.
+    pub partial: bool,
+    // These are applied to what remains after access controls, so they
+    // can't be used to learn about entries the caller can't see.
+    pub size_limit: Option<usize>,
+    // A normalised attribute name.
+    pub sort: Option<String>,
+    pub page: Option<SearchPage>,
+    // Where the search came from, which anonymous searches are rate limited
+    // by.
+    pub source: Option<String>,
     // TODO #83: Add list of attributes to request
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchPage {
+    // The key of the last entry of the previous page, or None for the first.
+    pub after: Option<PageKey>,
+    pub size: usize,
+}
+
+// Where an entry falls in a sorted or paged search - by the lowest value of
+// the sort attribute, with entries that have none last, then by the entry's
+// id, so that every entry has its own place even when their values tie.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PageKey {
+    missing: bool,
+    value: Option<String>,
+    id: u64,
+}
+
+impl PageKey {
+    pub fn new(e: &Entry<EntryReduced, EntryCommitted>, sort: Option<&String>) -> Self {
+        let value = sort.and_then(|attr| {
+            e.get_ava(attr.as_str())
+                .and_then(|vs| vs.iter().map(|v| v.to_string()).min())
+        });
+        PageKey {
+            missing: sort.is_some() && value.is_none(),
+            value: value,
+            id: e.get_id(),
+        }
+    }
+
+    // The cookie is just the key, so the next page is whatever comes after
+    // it, wherever the entries have moved to since. A made up cookie only
+    // changes where a page starts - the entries are still only those the
+    // caller can see.
+    pub fn to_cookie(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialise page key")
+    }
+}
+
+impl SearchPage {
+    fn from_proto(p: SearchPaging) -> Result<Self, OperationError> {
+        if p.size == 0 {
+            return Err(OperationError::InvalidRequestState);
+        }
+        let after = match p.cookie {
+            Some(c) => Some(
+                serde_json::from_str(c.as_str())
+                    .map_err(|_| OperationError::InvalidPagingCookie)?,
+            ),
+            None => None,
+        };
+        Ok(SearchPage {
+            after: after,
+            size: p.size,
+        })
+    }
+}
+
 impl SearchEvent {
     pub fn from_request(
         audit: &mut AuditScope,
         request: SearchRequest,
+        source: Option<String>,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_ro(audit, &request.filter, qs) {
+        let sort = match request.sort {
+            Some(attr) => {
+                let attr = attr.to_lowercase();
+                if !qs.get_schema().get_attributes().contains_key(&attr) {
+                    return Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttribute(attr),
+                    ));
+                }
+                Some(attr)
+            }
+            None => None,
+        };
+        let page = match request.paging {
+            Some(p) => Some(SearchPage::from_proto(p)?),
+            None => None,
+        };
+        match Filter::from_ro_client(audit, &request.filter, qs) {
             Ok(f) => Ok(SearchEvent {
                 event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
                 // We do need to do this twice to account for the ignore_hidden
@@ -192,6 +397,12 @@ impl SearchEvent {
                 filter_orig: f
                     .validate(qs.get_schema())
                     .map_err(|e| OperationError::SchemaViolation(e))?,
+                trace: request.trace,
+                partial: request.partial,
+                size_limit: request.size_limit,
+                sort: sort,
+                page: page,
+                source: source,
             }),
             Err(e) => Err(e),
         }
@@ -210,6 +421,98 @@ impl SearchEvent {
             filter_orig: filter_all!(f_self())
                 .validate(qs.get_schema())
                 .map_err(|e| OperationError::SchemaViolation(e))?,
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
+        })
+    }
+
+    // The account with this name, for its ssh public keys.
+    pub fn from_sshkeys_request(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        name: &str,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(SearchEvent {
+            event: Event::from_ro_uat(audit, qs, uat)?,
+            filter: filter!(f_and!([f_eq("class", "account"), f_eq("name", name)]))
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: filter_all!(f_and!([f_eq("class", "account"), f_eq("name", name)]))
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
+        })
+    }
+
+    // A search as the holder of a session, rather than the user_uuid of a
+    // request.
+    pub fn from_uat(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        filter: &ProtoFilter,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let f = Filter::from_ro_client(audit, filter, qs)?;
+        Ok(SearchEvent {
+            event: Event::from_ro_uat(audit, qs, uat)?,
+            filter: f
+                .clone()
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
+        })
+    }
+
+    // An ldap search, with its filter as an rfc4515 string. Until a
+    // connection binds, it searches as anonymous.
+    pub fn from_ldap_request(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        filter: &str,
+        size_limit: Option<usize>,
+        source: Option<String>,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let f = Filter::from_ldap_str(filter)?;
+        let event = match uat {
+            Some(_) => Event::from_ro_uat(audit, qs, uat)?,
+            None => Event::from_ro_request(audit, qs, UUID_ANONYMOUS)?,
+        };
+        Ok(SearchEvent {
+            event: event,
+            filter: f
+                .clone()
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            trace: false,
+            partial: false,
+            size_limit: size_limit,
+            sort: None,
+            page: None,
+            source: source,
         })
     }
 
@@ -220,6 +523,12 @@ impl SearchEvent {
             event: Event::from_impersonate_entry_ser(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
@@ -232,6 +541,12 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
@@ -244,17 +559,21 @@ impl SearchEvent {
             event: Event::from_impersonate(event),
             filter: filter,
             filter_orig: filter_orig,
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
-    #[cfg(test)]
-    #[allow(dead_code)]
     pub fn from_rec_request(
         audit: &mut AuditScope,
         request: SearchRecycledRequest,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_ro(audit, &request.filter, qs) {
+        match Filter::from_ro_client(audit, &request.filter, qs) {
             Ok(f) => Ok(SearchEvent {
                 event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
                 filter: f
@@ -265,6 +584,12 @@ impl SearchEvent {
                 filter_orig: f
                     .validate(qs.get_schema())
                     .map_err(|e| OperationError::SchemaViolation(e))?,
+                trace: false,
+                partial: false,
+                size_limit: None,
+                sort: None,
+                page: None,
+                source: None,
             }),
             Err(e) => Err(e),
         }
@@ -280,6 +605,12 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_recycled().to_valid(),
             filter_orig: filter.to_valid(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
@@ -293,6 +624,12 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_ignore_hidden().to_valid(),
             filter_orig: filter.to_valid(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
@@ -302,6 +639,12 @@ impl SearchEvent {
             event: Event::from_internal(),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 
@@ -310,6 +653,12 @@ impl SearchEvent {
             event: Event::from_internal(),
             filter: filter.clone(),
             filter_orig: filter,
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            page: None,
+            source: None,
         }
     }
 }
@@ -333,8 +682,27 @@ impl CreateEvent {
         request: CreateRequest,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        let rentries: Result<Vec<_>, _> = request
-            .entries
+        let event = Event::from_rw_request(audit, qs, request.user_uuid.as_str())?;
+        Self::from_parts(audit, event, &request.entries, qs)
+    }
+
+    pub fn from_uat(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        entries: &Vec<ProtoEntry>,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_uat(audit, qs, uat)?;
+        Self::from_parts(audit, event, entries, qs)
+    }
+
+    fn from_parts(
+        audit: &mut AuditScope,
+        event: Event,
+        entries: &Vec<ProtoEntry>,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let rentries: Result<Vec<_>, _> = entries
             .iter()
             .map(|e| Entry::from_proto_entry(audit, e, qs))
             .collect();
@@ -343,7 +711,7 @@ impl CreateEvent {
                 // From ProtoEntry -> Entry
                 // What is the correct consuming iterator here? Can we
                 // even do that?
-                event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+                event: event,
                 entries: entries,
             }),
             Err(e) => Err(e),
@@ -400,176 +768,1102 @@ impl ExistsEvent {
 }
 
 #[derive(Debug)]
-pub struct DeleteEvent {
+pub struct EffectivePermissionsEvent {
     pub event: Event,
-    // This is the filter, as it will be processed.
-    pub filter: Filter<FilterValid>,
-    // This is the original filter, for the purpose of ACI checking.
-    pub filter_orig: Filter<FilterValid>,
+    pub target_uuid: String,
 }
 
-impl DeleteEvent {
+impl EffectivePermissionsEvent {
     pub fn from_request(
         audit: &mut AuditScope,
-        request: DeleteRequest,
-        qs: &QueryServerWriteTransaction,
+        request: EffectivePermissionsRequest,
+        qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &request.filter, qs) {
-            Ok(f) => Ok(DeleteEvent {
-                event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
-                filter: f
-                    .clone()
-                    .to_ignore_hidden()
-                    .validate(qs.get_schema())
-                    .map_err(|e| OperationError::SchemaViolation(e))?,
-                filter_orig: f
-                    .validate(qs.get_schema())
-                    .map_err(|e| OperationError::SchemaViolation(e))?,
-            }),
-            Err(e) => Err(e),
-        }
+        Ok(EffectivePermissionsEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+        })
     }
 
     #[cfg(test)]
-    pub unsafe fn new_impersonate_entry_ser(e: &str, filter: Filter<FilterInvalid>) -> Self {
-        DeleteEvent {
+    pub unsafe fn new_impersonate_entry_ser(e: &str, target_uuid: &str) -> Self {
+        EffectivePermissionsEvent {
             event: Event::from_impersonate_entry_ser(e),
-            filter: filter.clone().to_valid(),
-            filter_orig: filter.to_valid(),
+            target_uuid: target_uuid.to_string(),
         }
     }
+}
 
-    #[cfg(test)]
-    pub unsafe fn new_internal_invalid(filter: Filter<FilterInvalid>) -> Self {
-        DeleteEvent {
-            event: Event::from_internal(),
-            filter: filter.clone().to_valid(),
-            filter_orig: filter.to_valid(),
-        }
+// A delete that is only looked at, so it comes from a read transaction.
+#[derive(Debug)]
+pub struct DeletePreviewEvent {
+    pub delete: DeleteEvent,
+}
+
+impl DeletePreviewEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: DeletePreviewRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(DeletePreviewEvent {
+            delete: DeleteEvent::from_ro_request(audit, request, qs)?,
+        })
     }
 
-    pub fn new_internal(filter: Filter<FilterValid>) -> Self {
-        DeleteEvent {
-            event: Event::from_internal(),
-            filter: filter.clone(),
-            filter_orig: filter,
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        filter: Filter<FilterInvalid>,
+    ) -> Self {
+        DeletePreviewEvent {
+            delete: DeleteEvent {
+                event: Event::from_impersonate_entry(e),
+                filter: filter.clone().to_ignore_hidden().to_valid(),
+                filter_orig: filter.to_valid(),
+            },
         }
     }
 }
 
 #[derive(Debug)]
-pub struct ModifyEvent {
+pub struct CompareEvent {
     pub event: Event,
-    // This is the filter, as it will be processed.
+    pub target_uuid: String,
+    pub attr: String,
+    // attr=value, normalised by the schema like any other filter.
     pub filter: Filter<FilterValid>,
-    // This is the original filter, for the purpose of ACI checking.
-    pub filter_orig: Filter<FilterValid>,
-    pub modlist: ModifyList<ModifyValid>,
 }
 
-impl ModifyEvent {
+impl CompareEvent {
     pub fn from_request(
         audit: &mut AuditScope,
-        request: ModifyRequest,
-        qs: &QueryServerWriteTransaction,
+        request: CompareRequest,
+        qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &request.filter, qs) {
-            Ok(f) => match ModifyList::from(audit, &request.modlist, qs) {
-                Ok(m) => Ok(ModifyEvent {
-                    event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
-                    filter: f
-                        .clone()
-                        .to_ignore_hidden()
-                        .validate(qs.get_schema())
-                        .map_err(|e| OperationError::SchemaViolation(e))?,
-                    filter_orig: f
-                        .validate(qs.get_schema())
-                        .map_err(|e| OperationError::SchemaViolation(e))?,
-                    modlist: m
-                        .validate(qs.get_schema())
-                        .map_err(|e| OperationError::SchemaViolation(e))?,
-                }),
-                Err(e) => Err(e),
-            },
-
-            Err(e) => Err(e),
-        }
-    }
-
-    pub fn new_internal(filter: Filter<FilterValid>, modlist: ModifyList<ModifyValid>) -> Self {
-        ModifyEvent {
-            event: Event::from_internal(),
-            filter: filter.clone(),
-            filter_orig: filter,
-            modlist: modlist,
-        }
-    }
-
-    #[cfg(test)]
-    pub unsafe fn new_internal_invalid(
-        filter: Filter<FilterInvalid>,
-        modlist: ModifyList<ModifyInvalid>,
-    ) -> Self {
-        ModifyEvent {
-            event: Event::from_internal(),
-            filter: filter.clone().to_valid(),
-            filter_orig: filter.to_valid(),
-            modlist: modlist.to_valid(),
-        }
+        let attr = request.attr.to_lowercase();
+        let filter = filter_all!(f_eq(attr.as_str(), request.value.as_str()))
+            .validate(qs.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        Ok(CompareEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+            attr: attr,
+            filter: filter,
+        })
     }
 
     #[cfg(test)]
     pub unsafe fn new_impersonate_entry_ser(
         e: &str,
-        filter: Filter<FilterInvalid>,
-        modlist: ModifyList<ModifyInvalid>,
+        target_uuid: &str,
+        attr: &str,
+        value: &str,
     ) -> Self {
-        ModifyEvent {
+        CompareEvent {
             event: Event::from_impersonate_entry_ser(e),
-            filter: filter.clone().to_valid(),
-            filter_orig: filter.to_valid(),
-            modlist: modlist.to_valid(),
-        }
-    }
-
-    pub fn new_impersonate(
-        event: &Event,
-        filter: Filter<FilterValid>,
-        filter_orig: Filter<FilterValid>,
-        modlist: ModifyList<ModifyValid>,
-    ) -> Self {
-        ModifyEvent {
-            event: Event::from_impersonate(event),
-            filter: filter,
-            filter_orig: filter_orig,
-            modlist: modlist,
+            target_uuid: target_uuid.to_string(),
+            attr: attr.to_string(),
+            filter: filter_all!(f_eq(attr, value)).to_valid(),
         }
     }
 }
 
 #[derive(Debug)]
-pub struct AuthEventStepInit {
-    pub name: String,
-    pub appid: Option<String>,
+pub struct MemoryReportEvent {
+    pub event: Event,
 }
 
-#[derive(Debug)]
-pub struct AuthEventStepCreds {
-    pub sessionid: Uuid,
-    pub creds: Vec<AuthCredential>,
+impl MemoryReportEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: MemoryReportRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(MemoryReportEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
+        MemoryReportEvent {
+            event: Event::from_impersonate_entry(e),
+        }
+    }
 }
 
 #[derive(Debug)]
-pub enum AuthEventStep {
-    Init(AuthEventStepInit),
-    Creds(AuthEventStepCreds),
+pub struct LogLevelEvent {
+    pub event: Event,
+    pub levels: BTreeMap<String, AuditLevel>,
 }
 
-impl AuthEventStep {
-    fn from_authstep(aus: AuthStep, sid: Option<Uuid>) -> Result<Self, OperationError> {
-        match aus {
-            AuthStep::Init(name, appid) => {
-                if sid.is_some() {
+impl LogLevelEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: LogLevelRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        if request.levels.is_empty() {
+            return Err(OperationError::EmptyRequest);
+        }
+        let mut levels = BTreeMap::new();
+        for l in request.levels.iter() {
+            let v = format!("{}={}", l.subsystem, l.level);
+            match parse_log_level(v.as_str()) {
+                Some((subsystem, level)) => {
+                    levels.insert(subsystem, level);
+                }
+                None => {
+                    audit_log!(audit, "invalid log level {}", v);
+                    return Err(OperationError::InvalidRequestState);
+                }
+            }
+        }
+        Ok(LogLevelEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            levels: levels,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        levels: Vec<(&str, AuditLevel)>,
+    ) -> Self {
+        LogLevelEvent {
+            event: Event::from_impersonate_entry(e),
+            levels: levels
+                .into_iter()
+                .map(|(s, l)| (s.to_string(), l))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AcpCoverageEvent {
+    pub event: Event,
+    pub sample: Option<usize>,
+}
+
+impl AcpCoverageEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: AcpCoverageRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(AcpCoverageEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            sample: request.sample,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        sample: Option<usize>,
+    ) -> Self {
+        AcpCoverageEvent {
+            event: Event::from_impersonate_entry(e),
+            sample: sample,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackupEvent {
+    pub event: Event,
+}
+
+impl BackupEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: BackupRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(BackupEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
+        BackupEvent {
+            event: Event::from_impersonate_entry(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditLogEvent {
+    pub event: Event,
+    pub operation: Option<String>,
+    pub origin: Option<String>,
+    pub request_id: Option<String>,
+    pub since: Option<DateTime<FixedOffset>>,
+}
+
+impl AuditLogEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: AuditLogRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let since = match request.since {
+            Some(s) => Some(try_audit!(
+                audit,
+                DateTime::parse_from_rfc3339(s.as_str()),
+                "Invalid audit log since {:?}",
+                OperationError::InvalidRequestState
+            )),
+            None => None,
+        };
+        Ok(AuditLogEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            operation: request.operation,
+            origin: request.origin,
+            request_id: request.request_id,
+            since: since,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        operation: Option<&str>,
+    ) -> Self {
+        AuditLogEvent {
+            event: Event::from_impersonate_entry(e),
+            operation: operation.map(|s| s.to_string()),
+            origin: None,
+            request_id: None,
+            since: None,
+        }
+    }
+
+    // Does the record match every term of the search? Access is decided
+    // separately.
+    pub fn matches(&self, r: &AuditRecord) -> bool {
+        self.operation
+            .as_ref()
+            .map(|o| o == &r.operation)
+            .unwrap_or(true)
+            && self
+                .origin
+                .as_ref()
+                .map(|o| Some(o) == r.origin.as_ref())
+                .unwrap_or(true)
+            && self
+                .request_id
+                .as_ref()
+                .map(|i| i == &r.request_id)
+                .unwrap_or(true)
+            && self
+                .since
+                .map(|since| {
+                    DateTime::parse_from_rfc3339(r.time.as_str())
+                        .map(|t| t >= since)
+                        .unwrap_or(false)
+                })
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug)]
+pub struct SyncEvent {
+    pub event: Event,
+    // The changelog position the consumer last synced to, or None for an
+    // initial sync.
+    pub token: Option<i64>,
+}
+
+impl SyncEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: SyncRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let token = match request.token {
+            Some(t) => Some(try_audit!(
+                audit,
+                t.parse::<i64>(),
+                "Invalid sync token {:?}",
+                OperationError::InvalidRequestState
+            )),
+            None => None,
+        };
+        Ok(SyncEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            token: token,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        token: Option<i64>,
+    ) -> Self {
+        SyncEvent {
+            event: Event::from_impersonate_entry(e),
+            token: token,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplChangesEvent {
+    pub event: Event,
+    // The position in our changelog the consumer has applied changes up to,
+    // or None to be sent everything.
+    pub since: Option<i64>,
+}
+
+impl ReplChangesEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: ReplChangesRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(ReplChangesEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            since: request.since,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new_internal(since: Option<i64>) -> Self {
+        ReplChangesEvent {
+            event: Event::from_internal(),
+            since: since,
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        since: Option<i64>,
+    ) -> Self {
+        ReplChangesEvent {
+            event: Event::from_impersonate_entry(e),
+            since: since,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GroupJoinCreateEvent {
+    pub event: Event,
+    // The group to join, by name or uuid.
+    pub group: String,
+    pub description: Option<String>,
+}
+
+impl GroupJoinCreateEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: GroupJoinCreateRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(GroupJoinCreateEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            group: request.group,
+            description: request.description,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        group: &str,
+        description: Option<&str>,
+    ) -> Self {
+        GroupJoinCreateEvent {
+            event: Event::from_impersonate_entry(e),
+            group: group.to_string(),
+            description: description.map(|d| d.to_string()),
+        }
+    }
+}
+
+// A search for entries of a class by the start of their name. Being a search,
+// the caller's search access controls decide what it finds.
+#[derive(Debug)]
+pub struct TypeaheadEvent {
+    pub search: SearchEvent,
+    pub limit: usize,
+}
+
+impl TypeaheadEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: TypeaheadRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        if request.prefix.is_empty() {
+            return Err(OperationError::EmptyRequest);
+        }
+        let limit = request
+            .limit
+            .unwrap_or(TYPEAHEAD_DEFAULT_RESULTS)
+            .min(TYPEAHEAD_MAX_RESULTS);
+        let class = request.class.as_str();
+        let prefix = request.prefix.as_str();
+        Ok(TypeaheadEvent {
+            search: SearchEvent {
+                event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+                filter: filter!(f_and!([f_eq("class", class), f_startswith("name", prefix)]))
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+                filter_orig: filter_all!(f_and!([
+                    f_eq("class", class),
+                    f_startswith("name", prefix)
+                ]))
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+                trace: false,
+                partial: false,
+                size_limit: None,
+                sort: None,
+                page: None,
+                source: None,
+            },
+            limit: limit,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        class: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> Self {
+        let f = filter_all!(f_and!([f_eq("class", class), f_startswith("name", prefix)]));
+        TypeaheadEvent {
+            search: SearchEvent::new_impersonate_entry(e, f),
+            limit: limit,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GroupJoinListEvent {
+    pub event: Event,
+}
+
+impl GroupJoinListEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: GroupJoinListRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(GroupJoinListEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
+        GroupJoinListEvent {
+            event: Event::from_impersonate_entry(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GroupJoinDecideEvent {
+    pub event: Event,
+    pub request_uuid: String,
+    pub approve: bool,
+}
+
+impl GroupJoinDecideEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: GroupJoinDecideRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(GroupJoinDecideEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            request_uuid: request.request_uuid,
+            approve: request.approve,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        request_uuid: &str,
+        approve: bool,
+    ) -> Self {
+        GroupJoinDecideEvent {
+            event: Event::from_impersonate_entry(e),
+            request_uuid: request_uuid.to_string(),
+            approve: approve,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteEvent {
+    pub event: Event,
+    // This is the filter, as it will be processed.
+    pub filter: Filter<FilterValid>,
+    // This is the original filter, for the purpose of ACI checking.
+    pub filter_orig: Filter<FilterValid>,
+}
+
+impl DeleteEvent {
+    fn from_ro_request(
+        audit: &mut AuditScope,
+        request: DeletePreviewRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let f = Filter::from_ro_client(audit, &request.filter, qs)?;
+        Ok(DeleteEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            filter: f
+                .clone()
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+        })
+    }
+
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: DeleteRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_request(audit, qs, request.user_uuid.as_str())?;
+        Self::from_parts(audit, event, &request.filter, qs)
+    }
+
+    pub fn from_uat(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        filter: &ProtoFilter,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_uat(audit, qs, uat)?;
+        Self::from_parts(audit, event, filter, qs)
+    }
+
+    fn from_parts(
+        audit: &mut AuditScope,
+        event: Event,
+        filter: &ProtoFilter,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        match Filter::from_rw_client(audit, filter, qs) {
+            Ok(f) => Ok(DeleteEvent {
+                event: event,
+                filter: f
+                    .clone()
+                    .to_ignore_hidden()
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+                filter_orig: f
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(e: &str, filter: Filter<FilterInvalid>) -> Self {
+        DeleteEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            filter: filter.clone().to_valid(),
+            filter_orig: filter.to_valid(),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_internal_invalid(filter: Filter<FilterInvalid>) -> Self {
+        DeleteEvent {
+            event: Event::from_internal(),
+            filter: filter.clone().to_valid(),
+            filter_orig: filter.to_valid(),
+        }
+    }
+
+    pub fn new_internal(filter: Filter<FilterValid>) -> Self {
+        DeleteEvent {
+            event: Event::from_internal(),
+            filter: filter.clone(),
+            filter_orig: filter,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RenameEvent {
+    pub event: Event,
+    pub target_uuid: String,
+    // As given - this is normalised and checked when the rename is applied.
+    pub name: String,
+}
+
+impl RenameEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: RenameRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(RenameEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+            name: request.name,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new_internal(target_uuid: &str, name: &str) -> Self {
+        RenameEvent {
+            event: Event::from_internal(),
+            target_uuid: target_uuid.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(e: &str, target_uuid: &str, name: &str) -> Self {
+        RenameEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            target_uuid: target_uuid.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HostSecretRotateEvent {
+    pub event: Event,
+    pub target_uuid: String,
+}
+
+impl HostSecretRotateEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: HostSecretRotateRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(HostSecretRotateEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new_internal(target_uuid: &str) -> Self {
+        HostSecretRotateEvent {
+            event: Event::from_internal(),
+            target_uuid: target_uuid.to_string(),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(e: &str, target_uuid: &str) -> Self {
+        HostSecretRotateEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            target_uuid: target_uuid.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RadiusSecretRegenerateEvent {
+    pub event: Event,
+    pub target_uuid: String,
+}
+
+impl RadiusSecretRegenerateEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: RadiusSecretRegenerateRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(RadiusSecretRegenerateEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(e: &str, target_uuid: &str) -> Self {
+        RadiusSecretRegenerateEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            target_uuid: target_uuid.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CredentialResetIssueEvent {
+    pub event: Event,
+    pub target_uuid: String,
+    pub lifetime: Option<Duration>,
+}
+
+impl CredentialResetIssueEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: CredentialResetIssueRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(CredentialResetIssueEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            target_uuid: request.target_uuid,
+            lifetime: request.lifetime.map(Duration::from_secs),
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(
+        e: &str,
+        target_uuid: &str,
+        lifetime: Option<Duration>,
+    ) -> Self {
+        CredentialResetIssueEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            target_uuid: target_uuid.to_string(),
+            lifetime: lifetime,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RadiusSecretReadEvent {
+    pub event: Event,
+    pub name: String,
+}
+
+impl RadiusSecretReadEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: RadiusSecretReadRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        Ok(RadiusSecretReadEvent {
+            event: Event::from_ro_request(audit, qs, request.user_uuid.as_str())?,
+            name: request.name,
+        })
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(e: &str, name: &str) -> Self {
+        RadiusSecretReadEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            name: name.to_string(),
+        }
+    }
+}
+
+// The session is only resolved to an account by the idm server, in the same
+// transaction as it looks up the resource server.
+#[derive(Debug)]
+pub struct Oauth2AuthoriseEvent {
+    pub uat: Option<UserAuthToken>,
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+}
+
+impl Oauth2AuthoriseEvent {
+    pub fn from_message(msg: Oauth2AuthoriseMessage) -> Self {
+        let req = msg.req;
+        Oauth2AuthoriseEvent {
+            uat: msg.uat,
+            response_type: req.response_type,
+            client_id: req.client_id,
+            redirect_uri: req.redirect_uri,
+            scopes: req
+                .scope
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+            state: req.state,
+            nonce: req.nonce,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new(uat: UserAuthToken, client_id: &str, redirect_uri: &str, scope: &str) -> Self {
+        Oauth2AuthoriseEvent {
+            uat: Some(uat),
+            response_type: "code".to_string(),
+            client_id: client_id.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            scopes: scope.split_whitespace().map(|s| s.to_string()).collect(),
+            state: Some("teststate".to_string()),
+            nonce: Some("testnonce".to_string()),
+        }
+    }
+}
+
+pub struct Oauth2TokenEvent {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+// Events end up in the audit log, so the secret is left out.
+impl std::fmt::Debug for Oauth2TokenEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Oauth2TokenEvent")
+            .field("grant_type", &self.grant_type)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+impl Oauth2TokenEvent {
+    pub fn from_request(request: Oauth2TokenRequest) -> Self {
+        Oauth2TokenEvent {
+            grant_type: request.grant_type,
+            code: request.code,
+            redirect_uri: request.redirect_uri,
+            client_id: request.client_id,
+            client_secret: request.client_secret,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new(code: &str, redirect_uri: &str, client_id: &str, client_secret: &str) -> Self {
+        Oauth2TokenEvent {
+            grant_type: "authorization_code".to_string(),
+            code: code.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            client_id: Some(client_id.to_string()),
+            client_secret: Some(client_secret.to_string()),
+        }
+    }
+}
+
+// Changes that are made together or not at all.
+#[derive(Debug)]
+pub struct BatchEvent {
+    pub event: Event,
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug)]
+pub enum BatchOperationEvent {
+    Create(CreateEvent),
+    Modify(ModifyEvent),
+    Delete(DeleteEvent),
+}
+
+impl BatchEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: BatchRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        if request.operations.is_empty() {
+            return Err(OperationError::EmptyRequest);
+        }
+        Ok(BatchEvent {
+            event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
+            operations: request.operations,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new_internal(operations: Vec<BatchOperation>) -> Self {
+        BatchEvent {
+            event: Event::from_internal(),
+            operations: operations,
+        }
+    }
+
+    // Each operation only becomes an event when the batch reaches it, so it
+    // sees what the earlier ones changed - such as the name of an entry the
+    // batch created.
+    pub fn operation_event(
+        &self,
+        audit: &mut AuditScope,
+        op: &BatchOperation,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<BatchOperationEvent, OperationError> {
+        let event = self.event.clone();
+        match op {
+            BatchOperation::Create(entries) => {
+                CreateEvent::from_parts(audit, event, entries, qs).map(BatchOperationEvent::Create)
+            }
+            BatchOperation::Modify(filter, modlist) => {
+                ModifyEvent::from_parts(audit, event, filter, modlist, qs)
+                    .map(BatchOperationEvent::Modify)
+            }
+            BatchOperation::ModifyUuid(uuid, modlist) => ModifyEvent::from_parts(
+                audit,
+                event,
+                &ProtoFilter::Eq("uuid".to_string(), uuid.clone()),
+                modlist,
+                qs,
+            )
+            .map(BatchOperationEvent::Modify),
+            BatchOperation::Delete(filter) => {
+                DeleteEvent::from_parts(audit, event, filter, qs).map(BatchOperationEvent::Delete)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ModifyEvent {
+    pub event: Event,
+    // This is the filter, as it will be processed.
+    pub filter: Filter<FilterValid>,
+    // This is the original filter, for the purpose of ACI checking.
+    pub filter_orig: Filter<FilterValid>,
+    pub modlist: ModifyList<ModifyValid>,
+}
+
+impl ModifyEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        request: ModifyRequest,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_request(audit, qs, request.user_uuid.as_str())?;
+        Self::from_parts(audit, event, &request.filter, &request.modlist, qs)
+    }
+
+    pub fn from_uat(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        filter: &ProtoFilter,
+        modlist: &ProtoModifyList,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_uat(audit, qs, uat)?;
+        Self::from_parts(audit, event, filter, modlist, qs)
+    }
+
+    fn from_parts(
+        audit: &mut AuditScope,
+        event: Event,
+        filter: &ProtoFilter,
+        modlist: &ProtoModifyList,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        match Filter::from_rw_client(audit, filter, qs) {
+            Ok(f) => match ModifyList::from(audit, modlist, qs) {
+                Ok(m) => Ok(ModifyEvent {
+                    event: event,
+                    filter: f
+                        .clone()
+                        .to_ignore_hidden()
+                        .validate(qs.get_schema())
+                        .map_err(|e| OperationError::SchemaViolation(e))?,
+                    filter_orig: f
+                        .validate(qs.get_schema())
+                        .map_err(|e| OperationError::SchemaViolation(e))?,
+                    modlist: m
+                        .validate(qs.get_schema())
+                        .map_err(|e| OperationError::SchemaViolation(e))?,
+                }),
+                Err(e) => Err(e),
+            },
+
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn new_internal(filter: Filter<FilterValid>, modlist: ModifyList<ModifyValid>) -> Self {
+        ModifyEvent {
+            event: Event::from_internal(),
+            filter: filter.clone(),
+            filter_orig: filter,
+            modlist: modlist,
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_internal_invalid(
+        filter: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+    ) -> Self {
+        ModifyEvent {
+            event: Event::from_internal(),
+            filter: filter.clone().to_valid(),
+            filter_orig: filter.to_valid(),
+            modlist: modlist.to_valid(),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(
+        e: &str,
+        filter: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+    ) -> Self {
+        ModifyEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            filter: filter.clone().to_valid(),
+            filter_orig: filter.to_valid(),
+            modlist: modlist.to_valid(),
+        }
+    }
+
+    pub fn new_impersonate(
+        event: &Event,
+        filter: Filter<FilterValid>,
+        filter_orig: Filter<FilterValid>,
+        modlist: ModifyList<ModifyValid>,
+    ) -> Self {
+        ModifyEvent {
+            event: Event::from_impersonate(event),
+            filter: filter,
+            filter_orig: filter_orig,
+            modlist: modlist,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthEventStepInit {
+    pub name: String,
+    pub appid: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AuthEventStepCreds {
+    pub sessionid: Uuid,
+    pub creds: Vec<AuthCredential>,
+    // As presented on the connection, not yet verified.
+    pub client_cert: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum AuthEventStep {
+    Init(AuthEventStepInit),
+    Creds(AuthEventStepCreds),
+}
+
+impl AuthEventStep {
+    fn from_authstep(
+        aus: AuthStep,
+        sid: Option<Uuid>,
+        client_cert: Option<Vec<u8>>,
+    ) -> Result<Self, OperationError> {
+        match aus {
+            AuthStep::Init(name, appid) => {
+                if sid.is_some() {
                     Err(OperationError::InvalidAuthState(
                         "session id present in init",
                     ))
@@ -584,6 +1878,7 @@ impl AuthEventStep {
                 Some(ssid) => Ok(AuthEventStep::Creds(AuthEventStepCreds {
                     sessionid: ssid,
                     creds: creds,
+                    client_cert: client_cert,
                 })),
                 None => Err(OperationError::InvalidAuthState(
                     "session id not present in cred",
@@ -605,6 +1900,33 @@ impl AuthEventStep {
         AuthEventStep::Creds(AuthEventStepCreds {
             sessionid: sid,
             creds: vec![AuthCredential::Anonymous],
+            client_cert: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn named_init(name: &str) -> Self {
+        AuthEventStep::Init(AuthEventStepInit {
+            name: name.to_string(),
+            appid: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn password_cred_step(sid: Uuid, pw: &str) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::Password(pw.to_string())],
+            client_cert: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn break_glass_cred_step(sid: Uuid, secret: &str) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::BreakGlass(secret.to_string())],
+            client_cert: None,
         })
     }
 }
@@ -620,7 +1942,7 @@ impl AuthEvent {
     pub fn from_message(msg: AuthMessage) -> Result<Self, OperationError> {
         Ok(AuthEvent {
             event: None,
-            step: AuthEventStep::from_authstep(msg.req.step, msg.sessionid)?,
+            step: AuthEventStep::from_authstep(msg.req.step, msg.sessionid, msg.client_cert)?,
         })
     }
 
@@ -639,6 +1961,30 @@ impl AuthEvent {
             step: AuthEventStep::anonymous_cred_step(sid),
         }
     }
+
+    #[cfg(test)]
+    pub fn named_init(name: &str) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::named_init(name),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn password_cred_step(sid: Uuid, pw: &str) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::password_cred_step(sid, pw),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn break_glass_cred_step(sid: Uuid, secret: &str) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::break_glass_cred_step(sid, secret),
+        }
+    }
 }
 
 // Probably should be a struct with the session id present.
@@ -659,25 +2005,27 @@ impl AuthResult {
 
 pub struct WhoamiResult {
     youare: ProtoEntry,
+    domain: ProtoDomainInfo,
 }
 
 impl WhoamiResult {
-    pub fn new(e: Entry<EntryReduced, EntryCommitted>) -> Self {
+    pub fn new(e: Entry<EntryReduced, EntryCommitted>, domain: &DomainInfo) -> Self {
         WhoamiResult {
             youare: e.into_pe(),
+            domain: domain.to_proto(),
         }
     }
 
     pub fn response(self) -> WhoamiResponse {
-        WhoamiResponse {
-            youare: self.youare,
-        }
+        WhoamiResponse::new(self.youare, self.domain)
     }
 }
 
 #[derive(Debug)]
 pub struct PurgeTombstoneEvent {
     pub event: Event,
+    // Only tombstones older than this are purged.
+    pub window: Duration,
 }
 
 impl Message for PurgeTombstoneEvent {
@@ -685,16 +2033,74 @@ impl Message for PurgeTombstoneEvent {
 }
 
 impl PurgeTombstoneEvent {
-    pub fn new() -> Self {
+    pub fn new(window: Duration) -> Self {
         PurgeTombstoneEvent {
             event: Event::from_internal(),
+            window: window,
         }
     }
 }
 
+// Flag the accounts that have expired since this was last run.
+#[derive(Debug)]
+pub struct AccountExpiryEvent {}
+
+impl Message for AccountExpiryEvent {
+    type Result = ();
+}
+
+impl AccountExpiryEvent {
+    pub fn new() -> Self {
+        AccountExpiryEvent {}
+    }
+}
+
+// End the subscriptions whose sessions have ended.
+#[derive(Debug)]
+pub struct SubscriptionExpiryEvent {}
+
+impl Message for SubscriptionExpiryEvent {
+    type Result = ();
+}
+
+impl SubscriptionExpiryEvent {
+    pub fn new() -> Self {
+        SubscriptionExpiryEvent {}
+    }
+}
+
+// Pull changes from the supplier of every replication agreement.
+#[derive(Debug)]
+pub struct ReplConsumeEvent {}
+
+impl Message for ReplConsumeEvent {
+    type Result = ();
+}
+
+impl ReplConsumeEvent {
+    pub fn new() -> Self {
+        ReplConsumeEvent {}
+    }
+}
+
+#[derive(Debug)]
+pub struct DbCheckpointEvent {}
+
+impl Message for DbCheckpointEvent {
+    type Result = ();
+}
+
+impl DbCheckpointEvent {
+    pub fn new() -> Self {
+        DbCheckpointEvent {}
+    }
+}
+
 #[derive(Debug)]
 pub struct PurgeRecycledEvent {
     pub event: Event,
+    // Only recycled entries older than this are purged.
+    pub window: Duration,
 }
 
 impl Message for PurgeRecycledEvent {
@@ -702,9 +2108,10 @@ impl Message for PurgeRecycledEvent {
 }
 
 impl PurgeRecycledEvent {
-    pub fn new() -> Self {
+    pub fn new(window: Duration) -> Self {
         PurgeRecycledEvent {
             event: Event::from_internal(),
+            window: window,
         }
     }
 }
@@ -730,7 +2137,7 @@ impl ReviveRecycledEvent {
         request: ReviveRecycledRequest,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &request.filter, qs) {
+        match Filter::from_rw_client(audit, &request.filter, qs) {
             Ok(f) => Ok(ReviveRecycledEvent {
                 event: Event::from_rw_request(audit, qs, request.user_uuid.as_str())?,
                 filter: f
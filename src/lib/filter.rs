@@ -3,13 +3,15 @@
 // entry to assert it matches.
 
 use crate::audit::AuditScope;
+use crate::constants::{FILTER_MAX_DEPTH, FILTER_MAX_TERMS};
 use crate::error::{OperationError, SchemaError};
 use crate::event::{Event, EventOrigin};
 use crate::proto::v1::Filter as ProtoFilter;
-use crate::schema::SchemaTransaction;
+use crate::schema::{SchemaTransaction, SyntaxType};
 use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
+use crate::value::Value;
 use std::cmp::{Ordering, PartialOrd};
 use std::collections::BTreeSet;
 
@@ -25,11 +27,37 @@ pub fn f_sub<'a>(a: &'a str, v: &'a str) -> FC<'a> {
     FC::Sub(a, v)
 }
 
+#[allow(dead_code)]
+pub fn f_startswith<'a>(a: &'a str, v: &'a str) -> FC<'a> {
+    FC::StartsWith(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_endswith<'a>(a: &'a str, v: &'a str) -> FC<'a> {
+    FC::EndsWith(a, v)
+}
+
 #[allow(dead_code)]
 pub fn f_pres<'a>(a: &'a str) -> FC<'a> {
     FC::Pres(a)
 }
 
+#[allow(dead_code)]
+pub fn f_ge<'a>(a: &'a str, v: &'a str) -> FC<'a> {
+    FC::Ge(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_le<'a>(a: &'a str, v: &'a str) -> FC<'a> {
+    FC::Le(a, v)
+}
+
+// Both ends are included.
+#[allow(dead_code)]
+pub fn f_between<'a>(a: &'a str, lo: &'a str, hi: &'a str) -> FC<'a> {
+    FC::And(vec![FC::Ge(a, lo), FC::Le(a, hi)])
+}
+
 #[allow(dead_code)]
 pub fn f_or<'a>(vs: Vec<FC<'a>>) -> FC<'a> {
     FC::Or(vs)
@@ -50,31 +78,190 @@ pub fn f_self<'a>() -> FC<'a> {
     FC::SelfUUID
 }
 
+#[allow(dead_code)]
+pub fn f_eq_self<'a>(a: &'a str) -> FC<'a> {
+    FC::EqSelf(a)
+}
+
+#[allow(dead_code)]
+pub fn f_eq_self_or_memberof<'a>(a: &'a str) -> FC<'a> {
+    FC::EqSelfOrMemberOf(a)
+}
+
+// A proto Eq with this value asserts the attribute holds the uuid of whoever
+// initiated the event, rather than the literal string. This lets a single acp
+// target "the entries the receiver manages".
+pub static FILTER_SELF_VALUE: &'static str = "%self%";
+// As above, but the uuid of any group the initiator is a member of matches
+// too, for entries managed by a group rather than a person.
+pub static FILTER_SELF_OR_MEMBEROF_VALUE: &'static str = "%self_or_memberof%";
+
+// How deep and how large a filter from a client may be. Validating and
+// matching a filter walk it recursively, so one nested deeply enough would
+// exhaust the stack. Internal filters are trusted, and not checked.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterLimits {
+    pub max_depth: usize,
+    pub max_terms: usize,
+}
+
+impl FilterLimits {
+    pub fn new() -> Self {
+        FilterLimits {
+            max_depth: FILTER_MAX_DEPTH,
+            max_terms: FILTER_MAX_TERMS,
+        }
+    }
+
+    // This walks the filter with its own stack, so it's safe on any filter.
+    fn check(&self, f: &ProtoFilter) -> Result<(), OperationError> {
+        let mut terms = 0;
+        let mut stack = vec![(f, 1)];
+        while let Some((f, depth)) = stack.pop() {
+            terms += 1;
+            if depth > self.max_depth || terms > self.max_terms {
+                return Err(OperationError::FilterTooComplex);
+            }
+            match f {
+                ProtoFilter::Or(l) | ProtoFilter::And(l) => {
+                    stack.extend(l.iter().map(|f| (f, depth + 1)))
+                }
+                ProtoFilter::AndNot(f) => stack.push((f, depth + 1)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
 // This is the short-form for tests and internal filters that can then
 // be transformed into a filter for the server to use.
 #[derive(Debug, Deserialize)]
 pub enum FC<'a> {
     Eq(&'a str, &'a str),
     Sub(&'a str, &'a str),
+    StartsWith(&'a str, &'a str),
+    EndsWith(&'a str, &'a str),
     Pres(&'a str),
+    Ge(&'a str, &'a str),
+    Le(&'a str, &'a str),
     Or(Vec<FC<'a>>),
     And(Vec<FC<'a>>),
     AndNot(Box<FC<'a>>),
     SelfUUID,
+    EqSelf(&'a str),
+    EqSelfOrMemberOf(&'a str),
     // Not(Box<FC>),
 }
 
+// A substring assertion, with the parts of an ldap one. A value matches
+// when it begins with start, then holds each of any in order without them
+// overlapping, then finishes with end. A plain contains is a single any.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SubMatch {
+    pub start: Option<String>,
+    pub any: Vec<String>,
+    pub end: Option<String>,
+}
+
+impl SubMatch {
+    pub fn contains(v: String) -> Self {
+        SubMatch {
+            start: None,
+            any: vec![v],
+            end: None,
+        }
+    }
+
+    pub fn starts_with(v: String) -> Self {
+        SubMatch {
+            start: Some(v),
+            any: Vec::new(),
+            end: None,
+        }
+    }
+
+    pub fn ends_with(v: String) -> Self {
+        SubMatch {
+            start: None,
+            any: Vec::new(),
+            end: Some(v),
+        }
+    }
+
+    // Every part, in the order they must appear.
+    pub fn parts(&self) -> impl Iterator<Item = &String> {
+        self.start
+            .iter()
+            .chain(self.any.iter())
+            .chain(self.end.iter())
+    }
+
+    fn map_parts<E, F>(&self, mut f: F) -> Result<Self, E>
+    where
+        F: FnMut(&String) -> Result<String, E>,
+    {
+        Ok(SubMatch {
+            start: match &self.start {
+                Some(v) => Some(f(v)?),
+                None => None,
+            },
+            any: self
+                .any
+                .iter()
+                .map(|v| f(v))
+                .collect::<Result<Vec<_>, _>>()?,
+            end: match &self.end {
+                Some(v) => Some(f(v)?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn matches(&self, v: &str) -> bool {
+        let mut rest = v;
+        if let Some(start) = &self.start {
+            if !rest.starts_with(start.as_str()) {
+                return false;
+            }
+            rest = &rest[start.len()..];
+        }
+        // The end is taken off before looking for the middle, so that a
+        // middle part can't claim the characters the end needs.
+        if let Some(end) = &self.end {
+            if !rest.ends_with(end.as_str()) {
+                return false;
+            }
+            rest = &rest[..rest.len() - end.len()];
+        }
+        for a in self.any.iter() {
+            match rest.find(a.as_str()) {
+                Some(i) => rest = &rest[i + a.len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
 // This is the filters internal representation.
 #[derive(Debug, Clone, PartialEq)]
 enum FilterComp {
     // This is attr - value
-    Eq(String, String),
-    Sub(String, String),
+    Eq(String, Value),
+    Sub(String, SubMatch),
     Pres(String),
+    // At or after, and at or before, in the order of the attribute's syntax.
+    Ge(String, Value),
+    Le(String, Value),
     Or(Vec<FilterComp>),
     And(Vec<FilterComp>),
     AndNot(Box<FilterComp>),
     SelfUUID,
+    // The attribute holds the uuid of the event initiator.
+    EqSelf(String),
+    // The attribute holds the uuid of the initiator, or of one of its groups.
+    EqSelfOrMemberOf(String),
     // Does this mean we can add a true not to the type now?
     // Not(Box<FilterComp>),
 }
@@ -86,9 +273,11 @@ enum FilterComp {
 #[derive(Debug, Clone)]
 pub enum FilterResolved {
     // This is attr - value
-    Eq(String, String),
-    Sub(String, String),
+    Eq(String, Value),
+    Sub(String, SubMatch),
     Pres(String),
+    Ge(String, Value),
+    Le(String, Value),
     Or(Vec<FilterResolved>),
     And(Vec<FilterResolved>),
     AndNot(Box<FilterResolved>),
@@ -138,6 +327,19 @@ impl Filter<FilterValidResolved> {
         }
     }
 
+    // As optimise, but using an estimate of how many entries each term can
+    // match. And terms are ordered so the most selective are resolved and
+    // tested first, and clauses that can never match are folded away.
+    pub fn optimise_cost<F>(&self, est: &mut F) -> Result<Self, OperationError>
+    where
+        F: FnMut(&FilterResolved) -> Result<usize, OperationError>,
+    {
+        let (inner, _) = self.state.inner.optimise_cost(est)?;
+        Ok(Filter {
+            state: FilterValidResolved { inner: inner },
+        })
+    }
+
     // It's not possible to invalid a resolved filter, because we don't know
     // what the origin of the Self or Not keywords were.
     //
@@ -178,6 +380,11 @@ impl Filter<FilterValid> {
         self.state.inner.get_attr_set(&mut r_set);
         r_set
     }
+
+    // Does any term of this filter match on a substring?
+    pub fn has_substring(&self) -> bool {
+        self.state.inner.has_substring()
+    }
 }
 
 impl Filter<FilterInvalid> {
@@ -265,7 +472,44 @@ impl Filter<FilterInvalid> {
         }
     }
 
-    pub fn validate(&self, schema: &SchemaTransaction) -> Result<Filter<FilterValid>, SchemaError> {
+    // Parse an RFC 4515 ldap filter string, such as
+    // "(&(class=person)(name=wi*))".
+    pub fn from_ldap_str(s: &str) -> Result<Self, OperationError> {
+        let mut p = LdapFilterParser {
+            s: s.trim().as_bytes(),
+            pos: 0,
+        };
+        let fc = p.parse_filter()?;
+        if p.pos != p.s.len() {
+            return Err(p.error("trailing characters after filter"));
+        }
+        Ok(Filter {
+            state: FilterInvalid { inner: fc },
+        })
+    }
+
+    // Accept a filter the server built itself as valid, without the cost of
+    // validating it. Only for terms made from values already in the db, such
+    // as the uuids of entries a plugin was given - these are normalised and
+    // their attributes in schema. Debug builds still check, so getting this
+    // wrong fails tests rather than subtly missing entries, but release
+    // builds don't, which is why this is unsafe.
+    pub(crate) unsafe fn assume_valid(self, schema: &dyn SchemaTransaction) -> Filter<FilterValid> {
+        debug_assert!(
+            self.state.inner.validate(schema).as_ref() == Ok(&self.state.inner),
+            "assume_valid given a filter that is not valid"
+        );
+        Filter {
+            state: FilterValid {
+                inner: self.state.inner,
+            },
+        }
+    }
+
+    pub fn validate(
+        &self,
+        schema: &dyn SchemaTransaction,
+    ) -> Result<Filter<FilterValid>, SchemaError> {
         Ok(Filter {
             state: FilterValid {
                 inner: self.state.inner.validate(schema)?,
@@ -299,26 +543,215 @@ impl Filter<FilterInvalid> {
             },
         })
     }
+
+    // A filter sent by a client is held to the filter limits first. The
+    // filters the server stored itself, like those of access controls and
+    // dynamic groups, are trusted and must always load.
+    pub fn from_ro_client(
+        audit: &mut AuditScope,
+        f: &ProtoFilter,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        try_audit!(audit, qs.get_filter_limits().check(f));
+        Self::from_ro(audit, f, qs)
+    }
+
+    pub fn from_rw_client(
+        audit: &mut AuditScope,
+        f: &ProtoFilter,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        try_audit!(audit, qs.get_filter_limits().check(f));
+        Self::from_rw(audit, f, qs)
+    }
+}
+
+// A recursive descent parser over the bytes of an ldap filter string. Only
+// the parts of RFC 4515 that map onto our filters are accepted - approx and
+// extensible matches are rejected.
+struct LdapFilterParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LdapFilterParser<'a> {
+    fn error(&self, msg: &str) -> OperationError {
+        OperationError::InvalidFilter(format!("{} at position {}", msg, self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), OperationError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}'", c as char).as_str()))
+        }
+    }
+
+    // filter = "(" filtercomp ")"
+    fn parse_filter(&mut self) -> Result<FilterComp, OperationError> {
+        self.expect(b'(')?;
+        let fc = match self.peek() {
+            Some(b'&') => {
+                self.pos += 1;
+                FilterComp::And(self.parse_list(true)?)
+            }
+            Some(b'|') => {
+                self.pos += 1;
+                FilterComp::Or(self.parse_list(false)?)
+            }
+            Some(b'!') => {
+                self.pos += 1;
+                // We have no bare not, so this is everything except the term.
+                let f = self.parse_filter()?;
+                FilterComp::And(vec![
+                    FilterComp::Pres("class".to_string()),
+                    FilterComp::AndNot(Box::new(f)),
+                ])
+            }
+            _ => self.parse_item()?,
+        };
+        self.expect(b')')?;
+        Ok(fc)
+    }
+
+    // Within an and, a not only has to remove its term from the rest.
+    fn parse_list(&mut self, and: bool) -> Result<Vec<FilterComp>, OperationError> {
+        let mut l = Vec::new();
+        while self.peek() == Some(b'(') {
+            if and && self.s.get(self.pos + 1) == Some(&b'!') {
+                self.pos += 2;
+                let f = self.parse_filter()?;
+                self.expect(b')')?;
+                l.push(FilterComp::AndNot(Box::new(f)));
+            } else {
+                l.push(self.parse_filter()?);
+            }
+        }
+        Ok(l)
+    }
+
+    // item = attr ("=" / ">=" / "<=") value, where an equality value may hold
+    // "*" wildcards.
+    fn parse_item(&mut self) -> Result<FilterComp, OperationError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == b'-' || c == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if self.pos == start {
+            return Err(self.error("expected an attribute name"));
+        }
+        // Only ever ascii, as checked above.
+        let attr = String::from_utf8_lossy(&self.s[start..self.pos]).to_lowercase();
+
+        let ordering = match self.peek() {
+            Some(b'=') => None,
+            Some(c @ b'>') | Some(c @ b'<') => {
+                self.pos += 1;
+                Some(c)
+            }
+            Some(b'~') | Some(b':') => return Err(self.error("unsupported match type")),
+            _ => return Err(self.error("expected '='")),
+        };
+        self.expect(b'=')?;
+
+        // Split the value on the unescaped wildcards.
+        let mut parts: Vec<Vec<u8>> = vec![Vec::new()];
+        loop {
+            match self.peek() {
+                None | Some(b')') => break,
+                Some(b'(') => return Err(self.error("unescaped '(' in value")),
+                Some(b'*') => {
+                    self.pos += 1;
+                    parts.push(Vec::new());
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let hex = self
+                        .s
+                        .get(self.pos..self.pos + 2)
+                        .and_then(|h| std::str::from_utf8(h).ok())
+                        .and_then(|h| u8::from_str_radix(h, 16).ok())
+                        .ok_or_else(|| self.error("invalid escape"))?;
+                    self.pos += 2;
+                    parts.last_mut().expect("parts is never empty").push(hex);
+                }
+                Some(c) => {
+                    self.pos += 1;
+                    parts.last_mut().expect("parts is never empty").push(c);
+                }
+            }
+        }
+
+        let mut parts = parts
+            .into_iter()
+            .map(|p| String::from_utf8(p).map_err(|_| self.error("value is not utf8")))
+            .collect::<Result<Vec<String>, _>>()?;
+
+        match (ordering, parts.len()) {
+            (Some(b'>'), 1) => {
+                return Ok(FilterComp::Ge(attr, Value::from(parts.remove(0).as_str())))
+            }
+            (Some(_), 1) => return Ok(FilterComp::Le(attr, Value::from(parts.remove(0).as_str()))),
+            (Some(_), _) => return Err(self.error("wildcard in ordering match")),
+            (None, 1) => return Ok(FilterComp::Eq(attr, Value::from(parts.remove(0).as_str()))),
+            (None, _) => {}
+        }
+        // An empty first or last part means that end isn't anchored.
+        let end = parts.pop().filter(|p| !p.is_empty());
+        let mut any = parts.into_iter();
+        let start = any.next().filter(|p| !p.is_empty());
+        let any: Vec<String> = any.filter(|p| !p.is_empty()).collect();
+        if start.is_none() && any.is_empty() && end.is_none() {
+            return Ok(FilterComp::Pres(attr));
+        }
+        Ok(FilterComp::Sub(
+            attr,
+            SubMatch {
+                start: start,
+                any: any,
+                end: end,
+            },
+        ))
+    }
 }
 
 impl FilterComp {
     fn new(fc: FC) -> Self {
         match fc {
-            FC::Eq(a, v) => FilterComp::Eq(a.to_string(), v.to_string()),
-            FC::Sub(a, v) => FilterComp::Sub(a.to_string(), v.to_string()),
+            FC::Eq(a, v) => FilterComp::Eq(a.to_string(), Value::from(v)),
+            FC::Sub(a, v) => FilterComp::Sub(a.to_string(), SubMatch::contains(v.to_string())),
+            FC::StartsWith(a, v) => {
+                FilterComp::Sub(a.to_string(), SubMatch::starts_with(v.to_string()))
+            }
+            FC::EndsWith(a, v) => {
+                FilterComp::Sub(a.to_string(), SubMatch::ends_with(v.to_string()))
+            }
             FC::Pres(a) => FilterComp::Pres(a.to_string()),
+            FC::Ge(a, v) => FilterComp::Ge(a.to_string(), Value::from(v)),
+            FC::Le(a, v) => FilterComp::Le(a.to_string(), Value::from(v)),
             FC::Or(v) => FilterComp::Or(v.into_iter().map(|c| FilterComp::new(c)).collect()),
             FC::And(v) => FilterComp::And(v.into_iter().map(|c| FilterComp::new(c)).collect()),
             FC::AndNot(b) => FilterComp::AndNot(Box::new(FilterComp::new(*b))),
             FC::SelfUUID => FilterComp::SelfUUID,
+            FC::EqSelf(a) => FilterComp::EqSelf(a.to_string()),
+            FC::EqSelfOrMemberOf(a) => FilterComp::EqSelfOrMemberOf(a.to_string()),
         }
     }
 
     fn new_ignore_hidden(fc: FilterComp) -> Self {
         FilterComp::And(vec![
             FilterComp::AndNot(Box::new(FilterComp::Or(vec![
-                FilterComp::Eq("class".to_string(), "tombstone".to_string()),
-                FilterComp::Eq("class".to_string(), "recycled".to_string()),
+                FilterComp::Eq("class".to_string(), Value::from("tombstone")),
+                FilterComp::Eq("class".to_string(), Value::from("recycled")),
             ]))),
             fc,
         ])
@@ -326,7 +759,7 @@ impl FilterComp {
 
     fn new_recycled(fc: FilterComp) -> Self {
         FilterComp::And(vec![
-            FilterComp::Eq("class".to_string(), "recycled".to_string()),
+            FilterComp::Eq("class".to_string(), Value::from("recycled")),
             fc,
         ])
     }
@@ -339,7 +772,11 @@ impl FilterComp {
             FilterComp::Sub(attr, _) => {
                 r_set.insert(attr.as_str());
             }
-            FilterComp::Pres(attr) => {
+            FilterComp::Pres(attr)
+            | FilterComp::Ge(attr, _)
+            | FilterComp::Le(attr, _)
+            | FilterComp::EqSelf(attr)
+            | FilterComp::EqSelfOrMemberOf(attr) => {
                 r_set.insert(attr.as_str());
             }
             FilterComp::Or(vs) => vs.iter().for_each(|f| f.get_attr_set(r_set)),
@@ -351,7 +788,22 @@ impl FilterComp {
         }
     }
 
-    pub fn validate(&self, schema: &SchemaTransaction) -> Result<FilterComp, SchemaError> {
+    fn has_substring(&self) -> bool {
+        match self {
+            FilterComp::Sub(_, _) => true,
+            FilterComp::Or(vs) | FilterComp::And(vs) => vs.iter().any(|f| f.has_substring()),
+            FilterComp::AndNot(f) => f.has_substring(),
+            FilterComp::Eq(_, _)
+            | FilterComp::Pres(_)
+            | FilterComp::Ge(_, _)
+            | FilterComp::Le(_, _)
+            | FilterComp::SelfUUID
+            | FilterComp::EqSelf(_)
+            | FilterComp::EqSelfOrMemberOf(_) => false,
+        }
+    }
+
+    pub fn validate(&self, schema: &dyn SchemaTransaction) -> Result<FilterComp, SchemaError> {
         // Optimisation is done at another stage.
 
         // This probably needs some rework
@@ -359,54 +811,65 @@ impl FilterComp {
         // Getting this each recursion could be slow. Maybe
         // we need an inner functon that passes the reference?
         let schema_attributes = schema.get_attributes();
-        let schema_name = schema_attributes
-            .get("name")
-            .expect("Critical: Core schema corrupt or missing.");
 
         match self {
             FilterComp::Eq(attr, value) => {
                 // Validate/normalise the attr name.
-                let attr_norm = schema_name.normalise_value(attr);
+                let attr_norm = schema.normalise_attr_name(attr);
                 // Now check it exists
                 match schema_attributes.get(&attr_norm) {
                     Some(schema_a) => {
-                        let value_norm = schema_a.normalise_value(value);
                         schema_a
-                            .validate_value(&value_norm)
+                            .to_value(value)
                             // Okay, it worked, transform to a filter component
-                            .map(|_| FilterComp::Eq(attr_norm, value_norm))
+                            .map(|value_norm| FilterComp::Eq(attr_norm, value_norm))
                         // On error, pass the error back out.
                     }
-                    None => Err(SchemaError::InvalidAttribute),
+                    None => Err(SchemaError::InvalidAttribute(attr_norm)),
                 }
             }
-            FilterComp::Sub(attr, value) => {
+            FilterComp::Sub(attr, sub) => {
                 // Validate/normalise the attr name.
-                let attr_norm = schema_name.normalise_value(attr);
+                let attr_norm = schema.normalise_attr_name(attr);
                 // Now check it exists
                 match schema_attributes.get(&attr_norm) {
                     Some(schema_a) => {
-                        let value_norm = schema_a.normalise_value(value);
-                        schema_a
-                            .validate_value(&value_norm)
-                            // Okay, it worked, transform to a filter component
-                            .map(|_| FilterComp::Sub(attr_norm, value_norm))
-                        // On error, pass the error back out.
+                        // Each part is normalised and checked on its own.
+                        sub.map_parts(|v| {
+                            let value_norm = schema_a.normalise_value(v);
+                            schema_a.validate_value(&value_norm).map(|_| value_norm)
+                        })
+                        .map(|sub_norm| FilterComp::Sub(attr_norm, sub_norm))
                     }
-                    None => Err(SchemaError::InvalidAttribute),
+                    None => Err(SchemaError::InvalidAttribute(attr_norm)),
                 }
             }
             FilterComp::Pres(attr) => {
-                let attr_norm = schema_name.normalise_value(attr);
+                let attr_norm = schema.normalise_attr_name(attr);
                 // Now check it exists
                 match schema_attributes.get(&attr_norm) {
                     Some(_attr_name) => {
                         // Return our valid data
                         Ok(FilterComp::Pres(attr_norm))
                     }
-                    None => Err(SchemaError::InvalidAttribute),
+                    None => Err(SchemaError::InvalidAttribute(attr_norm)),
                 }
             }
+            FilterComp::Ge(attr, value) | FilterComp::Le(attr, value) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                let schema_a = schema_attributes
+                    .get(&attr_norm)
+                    .ok_or_else(|| SchemaError::InvalidAttribute(attr_norm.clone()))?;
+                // Only some syntaxes have a meaningful order.
+                if !schema_a.syntax.is_ordered() {
+                    return Err(SchemaError::UnorderedSyntax(attr_norm));
+                }
+                let value_norm = schema_a.to_value(value)?;
+                Ok(match self {
+                    FilterComp::Ge(_, _) => FilterComp::Ge(attr_norm, value_norm),
+                    _ => FilterComp::Le(attr_norm, value_norm),
+                })
+            }
             FilterComp::Or(filters) => {
                 // If all filters are okay, return Ok(Filter::Or())
                 // If any is invalid, return the error.
@@ -445,6 +908,20 @@ impl FilterComp {
                 // Pretty hard to mess this one up ;)
                 Ok(FilterComp::SelfUUID)
             }
+            FilterComp::EqSelf(attr) | FilterComp::EqSelfOrMemberOf(attr) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                // Only a uuid can ever be compared to the initiator.
+                match schema_attributes.get(&attr_norm) {
+                    Some(schema_a) => match schema_a.syntax {
+                        SyntaxType::REFERENCE_UUID | SyntaxType::UUID => Ok(match self {
+                            FilterComp::EqSelf(_) => FilterComp::EqSelf(attr_norm),
+                            _ => FilterComp::EqSelfOrMemberOf(attr_norm),
+                        }),
+                        _ => Err(SchemaError::InvalidAttributeSyntax),
+                    },
+                    None => Err(SchemaError::InvalidAttribute(attr_norm)),
+                }
+            }
         }
     }
 
@@ -454,9 +931,30 @@ impl FilterComp {
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match f {
-            ProtoFilter::Eq(a, v) => FilterComp::Eq(a.clone(), qs.clone_value(audit, a, v)?),
-            ProtoFilter::Sub(a, v) => FilterComp::Sub(a.clone(), qs.clone_value(audit, a, v)?),
+            ProtoFilter::Eq(a, v) if v == FILTER_SELF_VALUE => FilterComp::EqSelf(a.clone()),
+            ProtoFilter::Eq(a, v) if v == FILTER_SELF_OR_MEMBEROF_VALUE => {
+                FilterComp::EqSelfOrMemberOf(a.clone())
+            }
+            ProtoFilter::Eq(a, v) => {
+                FilterComp::Eq(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::Sub(a, v) => {
+                FilterComp::Sub(a.clone(), SubMatch::contains(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::StartsWith(a, v) => FilterComp::Sub(
+                a.clone(),
+                SubMatch::starts_with(qs.clone_value(audit, a, v)?),
+            ),
+            ProtoFilter::EndsWith(a, v) => {
+                FilterComp::Sub(a.clone(), SubMatch::ends_with(qs.clone_value(audit, a, v)?))
+            }
             ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
+            ProtoFilter::Ge(a, v) => {
+                FilterComp::Ge(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::Le(a, v) => {
+                FilterComp::Le(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
                     .map(|f| Self::from_ro(audit, f, qs))
@@ -478,9 +976,30 @@ impl FilterComp {
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match f {
-            ProtoFilter::Eq(a, v) => FilterComp::Eq(a.clone(), qs.clone_value(audit, a, v)?),
-            ProtoFilter::Sub(a, v) => FilterComp::Sub(a.clone(), qs.clone_value(audit, a, v)?),
+            ProtoFilter::Eq(a, v) if v == FILTER_SELF_VALUE => FilterComp::EqSelf(a.clone()),
+            ProtoFilter::Eq(a, v) if v == FILTER_SELF_OR_MEMBEROF_VALUE => {
+                FilterComp::EqSelfOrMemberOf(a.clone())
+            }
+            ProtoFilter::Eq(a, v) => {
+                FilterComp::Eq(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::Sub(a, v) => {
+                FilterComp::Sub(a.clone(), SubMatch::contains(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::StartsWith(a, v) => FilterComp::Sub(
+                a.clone(),
+                SubMatch::starts_with(qs.clone_value(audit, a, v)?),
+            ),
+            ProtoFilter::EndsWith(a, v) => {
+                FilterComp::Sub(a.clone(), SubMatch::ends_with(qs.clone_value(audit, a, v)?))
+            }
             ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
+            ProtoFilter::Ge(a, v) => {
+                FilterComp::Ge(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
+            ProtoFilter::Le(a, v) => {
+                FilterComp::Le(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
                     .map(|f| Self::from_rw(audit, f, qs))
@@ -524,6 +1043,8 @@ impl PartialEq for FilterResolved {
             (FilterResolved::Eq(a1, v1), FilterResolved::Eq(a2, v2)) => a1 == a2 && v1 == v2,
             (FilterResolved::Sub(a1, v1), FilterResolved::Sub(a2, v2)) => a1 == a2 && v1 == v2,
             (FilterResolved::Pres(a1), FilterResolved::Pres(a2)) => a1 == a2,
+            (FilterResolved::Ge(a1, v1), FilterResolved::Ge(a2, v2)) => a1 == a2 && v1 == v2,
+            (FilterResolved::Le(a1, v1), FilterResolved::Le(a2, v2)) => a1 == a2 && v1 == v2,
             (FilterResolved::And(vs1), FilterResolved::And(vs2)) => vs1 == vs2,
             (FilterResolved::Or(vs1), FilterResolved::Or(vs2)) => vs1 == vs2,
             (FilterResolved::AndNot(f1), FilterResolved::AndNot(f2)) => f1 == f2,
@@ -569,6 +1090,11 @@ impl Ord for FilterResolved {
                 o => o,
             },
             (FilterResolved::Pres(a1), FilterResolved::Pres(a2)) => a1.cmp(a2),
+            (FilterResolved::Ge(a1, v1), FilterResolved::Ge(a2, v2))
+            | (FilterResolved::Le(a1, v1), FilterResolved::Le(a2, v2)) => match a1.cmp(a2) {
+                Ordering::Equal => v1.cmp(v2),
+                o => o,
+            },
             (FilterResolved::Eq(_, _), _) => {
                 // Always higher prefer Eq over all else, as these will have
                 // the best indexes and return smallest candidates.
@@ -577,6 +1103,12 @@ impl Ord for FilterResolved {
             (_, FilterResolved::Eq(_, _)) => Ordering::Greater,
             (FilterResolved::Pres(_), _) => Ordering::Less,
             (_, FilterResolved::Pres(_)) => Ordering::Greater,
+            // A range is usually narrower than a substring, but we can't
+            // tell without an index for it.
+            (FilterResolved::Ge(_, _), _) => Ordering::Less,
+            (_, FilterResolved::Ge(_, _)) => Ordering::Greater,
+            (FilterResolved::Le(_, _), _) => Ordering::Less,
+            (_, FilterResolved::Le(_, _)) => Ordering::Greater,
             (FilterResolved::Sub(_, _), _) => Ordering::Greater,
             (_, FilterResolved::Sub(_, _)) => Ordering::Less,
             (_, _) => Ordering::Equal,
@@ -591,6 +1123,8 @@ impl FilterResolved {
             FilterComp::Eq(a, v) => FilterResolved::Eq(a, v),
             FilterComp::Sub(a, v) => FilterResolved::Sub(a, v),
             FilterComp::Pres(a) => FilterResolved::Pres(a),
+            FilterComp::Ge(a, v) => FilterResolved::Ge(a, v),
+            FilterComp::Le(a, v) => FilterResolved::Le(a, v),
             FilterComp::Or(vs) => FilterResolved::Or(
                 vs.into_iter()
                     .map(|v| FilterResolved::from_invalid(v))
@@ -610,6 +1144,9 @@ impl FilterResolved {
                 FilterResolved::AndNot(Box::new(FilterResolved::from_invalid((*f).clone())))
             }
             FilterComp::SelfUUID => panic!("Not possible to resolve SelfUUID in from_invalid!"),
+            FilterComp::EqSelf(_) | FilterComp::EqSelfOrMemberOf(_) => {
+                panic!("Not possible to resolve EqSelf in from_invalid!")
+            }
         }
     }
 
@@ -618,6 +1155,8 @@ impl FilterResolved {
             FilterComp::Eq(a, v) => Some(FilterResolved::Eq(a, v)),
             FilterComp::Sub(a, v) => Some(FilterResolved::Sub(a, v)),
             FilterComp::Pres(a) => Some(FilterResolved::Pres(a)),
+            FilterComp::Ge(a, v) => Some(FilterResolved::Ge(a, v)),
+            FilterComp::Le(a, v) => Some(FilterResolved::Le(a, v)),
             FilterComp::Or(vs) => {
                 let fi: Option<Vec<_>> = vs
                     .into_iter()
@@ -644,10 +1183,31 @@ impl FilterResolved {
             FilterComp::SelfUUID => match &ev.origin {
                 EventOrigin::User(e) => Some(FilterResolved::Eq(
                     "uuid".to_string(),
-                    e.get_uuid().to_string(),
+                    Value::from(e.get_uuid().as_str()),
                 )),
                 _ => None,
             },
+            FilterComp::EqSelf(a) => match &ev.origin {
+                EventOrigin::User(e) => {
+                    Some(FilterResolved::Eq(a, Value::from(e.get_uuid().as_str())))
+                }
+                _ => None,
+            },
+            FilterComp::EqSelfOrMemberOf(a) => match &ev.origin {
+                EventOrigin::User(e) => {
+                    let mut uuids = vec![Value::from(e.get_uuid().as_str())];
+                    if let Some(mo) = e.get_ava("memberof") {
+                        uuids.extend(mo.iter().cloned());
+                    }
+                    Some(FilterResolved::Or(
+                        uuids
+                            .into_iter()
+                            .map(|v| FilterResolved::Eq(a.clone(), v))
+                            .collect(),
+                    ))
+                }
+                _ => None,
+            },
         }
     }
 
@@ -710,12 +1270,155 @@ impl FilterResolved {
             f => f.clone(),
         }
     }
+
+    // An empty or can never be true, so it stands in for any clause that no
+    // entry could match.
+    fn impossible() -> Self {
+        FilterResolved::Or(Vec::new())
+    }
+
+    fn is_impossible(&self) -> bool {
+        match self {
+            FilterResolved::Or(l) => l.is_empty(),
+            _ => false,
+        }
+    }
+
+    // Pull the terms of nested and (or or) clauses up to this level.
+    fn flatten_into(f_list: &[FilterResolved], and: bool, r: &mut Vec<FilterResolved>) {
+        f_list.iter().for_each(|f| match (f, and) {
+            (FilterResolved::And(l), true) | (FilterResolved::Or(l), false) => {
+                FilterResolved::flatten_into(l, and, r)
+            }
+            (f, _) => r.push(f.clone()),
+        })
+    }
+
+    // Returns the optimised filter and its estimated candidate count.
+    fn optimise_cost<F>(&self, est: &mut F) -> Result<(Self, usize), OperationError>
+    where
+        F: FnMut(&FilterResolved) -> Result<usize, OperationError>,
+    {
+        match self {
+            FilterResolved::And(f_list) => {
+                let mut flat = Vec::new();
+                FilterResolved::flatten_into(f_list, true, &mut flat);
+
+                let mut terms: Vec<(FilterResolved, usize)> = Vec::with_capacity(flat.len());
+                for f in flat.iter() {
+                    let (f, cost) = f.optimise_cost(est)?;
+                    if f.is_impossible() {
+                        return Ok((FilterResolved::impossible(), 0));
+                    }
+                    match &f {
+                        // Not something impossible is always true.
+                        FilterResolved::AndNot(inner) if inner.is_impossible() => {}
+                        _ => terms.push((f, cost)),
+                    }
+                }
+
+                // (&(a)(!(a))) can't match anything.
+                let contradiction = terms.iter().any(|(f, _)| match f {
+                    FilterResolved::AndNot(inner) => terms.iter().any(|(g, _)| g == inner.as_ref()),
+                    _ => false,
+                });
+                if contradiction {
+                    return Ok((FilterResolved::impossible(), 0));
+                }
+
+                // Nots can only remove from the rest, so they go last, then
+                // smallest candidate set first.
+                terms.sort_unstable_by(|(a, ca), (b, cb)| {
+                    let a_not = match a {
+                        FilterResolved::AndNot(_) => true,
+                        _ => false,
+                    };
+                    let b_not = match b {
+                        FilterResolved::AndNot(_) => true,
+                        _ => false,
+                    };
+                    a_not.cmp(&b_not).then(ca.cmp(cb)).then_with(|| a.cmp(b))
+                });
+                terms.dedup_by(|(a, _), (b, _)| a == b);
+
+                // An and can match no more than its most selective term.
+                let cost = terms
+                    .iter()
+                    .filter(|(f, _)| match f {
+                        FilterResolved::AndNot(_) => false,
+                        _ => true,
+                    })
+                    .map(|(_, c)| *c)
+                    .min()
+                    .unwrap_or(usize::max_value());
+
+                Ok((
+                    FilterResolved::And(terms.into_iter().map(|(f, _)| f).collect()),
+                    cost,
+                ))
+            }
+            FilterResolved::Or(f_list) => {
+                let mut flat = Vec::new();
+                FilterResolved::flatten_into(f_list, false, &mut flat);
+
+                let mut terms: Vec<(FilterResolved, usize)> = Vec::with_capacity(flat.len());
+                for f in flat.iter() {
+                    let (f, cost) = f.optimise_cost(est)?;
+                    if !f.is_impossible() {
+                        terms.push((f, cost));
+                    }
+                }
+
+                // Most likely to match first, so the entry test can stop early.
+                terms.sort_unstable_by(|(a, ca), (b, cb)| cb.cmp(ca).then_with(|| b.cmp(a)));
+                terms.dedup_by(|(a, _), (b, _)| a == b);
+
+                let cost = terms
+                    .iter()
+                    .fold(0, |acc: usize, (_, c)| acc.saturating_add(*c));
+
+                // If every term was impossible, so is this - and an empty or
+                // is already how we say that.
+                Ok((
+                    FilterResolved::Or(terms.into_iter().map(|(f, _)| f).collect()),
+                    cost,
+                ))
+            }
+            FilterResolved::AndNot(f) => {
+                let (inner, _) = f.optimise_cost(est)?;
+                // On its own a not can match anything.
+                Ok((FilterResolved::AndNot(Box::new(inner)), usize::max_value()))
+            }
+            f => Ok((f.clone(), est(f)?)),
+        }
+    }
+
+    // Flatten this filter into (depth, term) pairs in pre-order, so that each
+    // term can be reported on individually when tracing a search.
+    pub fn trace_nodes(&self) -> Vec<(usize, &FilterResolved)> {
+        let mut r = Vec::new();
+        self.trace_nodes_inner(0, &mut r);
+        r
+    }
+
+    fn trace_nodes_inner<'a>(&'a self, depth: usize, r: &mut Vec<(usize, &'a FilterResolved)>) {
+        r.push((depth, self));
+        match self {
+            FilterResolved::Or(l) | FilterResolved::And(l) => {
+                l.iter().for_each(|f| f.trace_nodes_inner(depth + 1, r))
+            }
+            FilterResolved::AndNot(f) => f.trace_nodes_inner(depth + 1, r),
+            _ => {}
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::entry::{Entry, EntryNew, EntryValid};
-    use crate::filter::{Filter, FilterInvalid};
+    use crate::error::OperationError;
+    use crate::filter::{Filter, FilterComp, FilterInvalid, FilterLimits, SubMatch};
+    use crate::proto::v1::Filter as ProtoFilter;
     use serde_json;
     use std::cmp::{Ordering, PartialOrd};
     use std::collections::BTreeSet;
@@ -843,6 +1546,161 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_filter_optimise_cost() {
+        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{FilterResolved, FC};
+
+        // Pretend class is on everything, uid is unique and nothing else is
+        // indexed.
+        fn est(f: &FilterResolved) -> Result<usize, OperationError> {
+            Ok(match f {
+                FilterResolved::Pres(a) if a == "class" => 100,
+                FilterResolved::Eq(a, _) if a == "uid" => 1,
+                _ => usize::max_value(),
+            })
+        }
+
+        fn check(init: FC, expect: FC) {
+            let f_init = unsafe { Filter::new(init).to_valid_resolved() };
+            let f_expect = unsafe { Filter::new(expect).to_valid_resolved() };
+            let f_opt = f_init.optimise_cost(&mut est).expect("optimise failed");
+            println!("opt    --> {:?}", f_opt);
+            println!("expect --> {:?}", f_expect);
+            assert!(f_opt == f_expect);
+        }
+
+        // The selective equality moves ahead of the broad presence, and the
+        // unindexed term goes after both.
+        check(
+            f_and!([
+                f_pres("class"),
+                f_and!([f_sub("name", "te"), f_eq("uid", "test")])
+            ]),
+            f_and!([f_eq("uid", "test"), f_pres("class"), f_sub("name", "te")]),
+        );
+
+        // Nots still go last.
+        check(
+            f_and!([
+                f_andnot(f_eq("uid", "a")),
+                f_sub("name", "te"),
+                f_eq("uid", "test")
+            ]),
+            f_and!([
+                f_eq("uid", "test"),
+                f_sub("name", "te"),
+                f_andnot(f_eq("uid", "a"))
+            ]),
+        );
+
+        // A term and its negation can never match, which makes the enclosing
+        // and impossible, which is then dropped from an or.
+        let impossible = || f_and!([f_eq("uid", "a"), f_andnot(f_eq("uid", "a"))]);
+        check(impossible(), f_or(vec![]));
+        check(
+            f_or!([impossible(), f_pres("class")]),
+            f_or!([f_pres("class")]),
+        );
+        check(
+            f_and!([f_pres("class"), f_or!([impossible()])]),
+            f_or(vec![]),
+        );
+        // And not-ing it is always true, so it goes away.
+        check(
+            f_and!([f_pres("class"), f_andnot(impossible())]),
+            f_and!([f_pres("class")]),
+        );
+    }
+
+    #[test]
+    fn test_filter_limits() {
+        let limits = FilterLimits {
+            max_depth: 3,
+            max_terms: 5,
+        };
+        let eq = || ProtoFilter::Eq("name".to_string(), "a".to_string());
+
+        // Three deep, with five terms, is as large as these allow.
+        let f = ProtoFilter::And(vec![eq(), ProtoFilter::AndNot(Box::new(eq())), eq()]);
+        assert!(limits.check(&f) == Ok(()));
+
+        let f = ProtoFilter::And(vec![ProtoFilter::AndNot(Box::new(ProtoFilter::Or(vec![
+            eq(),
+        ])))]);
+        assert!(limits.check(&f) == Err(OperationError::FilterTooComplex));
+
+        let f = ProtoFilter::Or(vec![eq(), eq(), eq(), eq(), eq()]);
+        assert!(limits.check(&f) == Err(OperationError::FilterTooComplex));
+    }
+
+    #[test]
+    fn test_filter_from_ldap_str() {
+        let ldap = |s: &str| Filter::from_ldap_str(s);
+
+        assert!(
+            ldap("(&(class=person)(name=wi*))")
+                == Ok(filter_all!(f_and!([
+                    f_eq("class", "person"),
+                    f_startswith("name", "wi")
+                ])))
+        );
+        assert!(ldap("(name=*wi)") == Ok(filter_all!(f_endswith("name", "wi"))));
+        assert!(ldap("(name=*wi*)") == Ok(filter_all!(f_sub("name", "wi"))));
+        assert!(
+            ldap(" (|(Name=*)(uid=a*b**c)) ")
+                == Ok(Filter {
+                    state: FilterInvalid {
+                        inner: FilterComp::Or(vec![
+                            FilterComp::Pres("name".to_string()),
+                            FilterComp::Sub(
+                                "uid".to_string(),
+                                SubMatch {
+                                    start: Some("a".to_string()),
+                                    any: vec!["b".to_string()],
+                                    end: Some("c".to_string()),
+                                }
+                            ),
+                        ])
+                    }
+                })
+        );
+        // Escapes, including of the wildcard itself.
+        assert!(ldap("(name=a\\2a\\28b\\29)") == Ok(filter_all!(f_eq("name", "a*(b)"))));
+        assert!(ldap("(name=caf\\c3\\a9)") == Ok(filter_all!(f_eq("name", "caf\u{e9}"))));
+        // A not in an and only removes from it, elsewhere it needs a base.
+        assert!(
+            ldap("(&(class=person)(!(name=a)))")
+                == Ok(filter_all!(f_and!([
+                    f_eq("class", "person"),
+                    f_andnot(f_eq("name", "a"))
+                ])))
+        );
+        assert!(
+            ldap("(!(name=a))")
+                == Ok(filter_all!(f_and!([
+                    f_pres("class"),
+                    f_andnot(f_eq("name", "a"))
+                ])))
+        );
+
+        assert!(ldap("name=a").is_err());
+        assert!(ldap("(name=a").is_err());
+        assert!(ldap("(name=a))").is_err());
+        assert!(
+            ldap("(last_auth>=2019-01-01T00:00:00Z)")
+                == Ok(filter_all!(f_ge("last_auth", "2019-01-01T00:00:00Z")))
+        );
+        assert!(ldap("(last_auth<=2019)") == Ok(filter_all!(f_le("last_auth", "2019"))));
+        assert!(ldap("(name>=a*)").is_err());
+        assert!(ldap("(name>a)").is_err());
+        assert!(ldap("(name~=a)").is_err());
+        assert!(ldap("(=a)").is_err());
+        assert!(ldap("(name=a(b)").is_err());
+        assert!(ldap("(name=\\zz)").is_err());
+        assert!(ldap("(name=\\ff)").is_err());
+    }
+
     #[test]
     fn test_filter_eq() {
         let f_t1a = filter!(f_pres("userid"));
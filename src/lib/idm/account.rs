@@ -1,11 +1,55 @@
+use chrono::DateTime;
+use std::time::Duration;
+
+use crate::constants::BREAK_GLASS_MAX_LIFETIME;
+use crate::domain::DomainInfo;
 use crate::entry::{Entry, EntryCommitted, EntryValid};
 use crate::error::OperationError;
 
 use crate::proto::v1::UserAuthToken;
 
 use crate::idm::claim::Claim;
+use crate::idm::credential::Password;
 use crate::idm::group::Group;
 
+// A DATETIME attribute as time since the epoch. Times before the epoch have
+// passed anyway, so are taken as the epoch.
+fn get_ava_datetime(e: &Entry<EntryValid, EntryCommitted>, attr: &str) -> Option<Duration> {
+    e.get_ava_single(attr)
+        .and_then(|v| DateTime::parse_from_rfc3339(v.to_string().as_str()).ok())
+        .map(|dt| Duration::from_secs(std::cmp::max(dt.timestamp(), 0) as u64))
+}
+
+// When the account may be used, as time since the epoch. Either end may be
+// left open.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidityWindow {
+    pub valid_from: Option<Duration>,
+    pub expire: Option<Duration>,
+}
+
+impl ValidityWindow {
+    pub fn from_entry(e: &Entry<EntryValid, EntryCommitted>) -> Self {
+        ValidityWindow {
+            valid_from: get_ava_datetime(e, "account_valid_from"),
+            expire: get_ava_datetime(e, "account_expire"),
+        }
+    }
+
+    pub fn contains(&self, now: Duration) -> bool {
+        self.valid_from.map(|f| f <= now).unwrap_or(true)
+            && self.expire.map(|x| now < x).unwrap_or(true)
+    }
+}
+
+// An emergency secret for the account, and when it stops working, as time
+// since the epoch.
+#[derive(Debug, Clone)]
+pub(crate) struct BreakGlass {
+    pub secret: Password,
+    pub expiry: Duration,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Account {
     // Later these could be &str if we cache entry here too ...
@@ -17,7 +61,14 @@ pub(crate) struct Account {
     pub name: String,
     pub displayname: String,
     pub uuid: String,
+    pub spn: String,
     pub groups: Vec<Group>,
+    // Rules for the client certificates that may act as this account.
+    pub cert_mappings: Vec<String>,
+    // The password hash, if one has been set.
+    pub primary: Option<Password>,
+    pub break_glass: Option<BreakGlass>,
+    pub validity: ValidityWindow,
     // creds (various types)
     // groups?
     // claims?
@@ -28,6 +79,7 @@ impl Account {
     // TODO #71: We need a second try_from that doesn't do group resolve for test cases I think.
     pub(crate) fn try_from_entry(
         value: Entry<EntryValid, EntryCommitted>,
+        domain: &DomainInfo,
     ) -> Result<Self, OperationError> {
         // Check the classes
         if !value.attribute_value_pres("class", "account") {
@@ -42,25 +94,67 @@ impl Account {
             .ok_or(OperationError::InvalidAccountState(
                 "Missing attribute: name",
             ))?
-            .clone();
+            .to_string();
 
         let displayname = value
             .get_ava_single("displayname")
             .ok_or(OperationError::InvalidAccountState(
                 "Missing attribute: displayname",
             ))?
-            .clone();
+            .to_string();
+
+        // A principal name set on the account is used as it is.
+        let spn = value
+            .get_ava_single("principal_name")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| domain.spn(name.as_str()));
 
         // TODO #71: Resolve groups!!!!
         let groups = Vec::new();
 
+        let cert_mappings = value
+            .get_ava("cert_mapping")
+            .map(|vs| vs.iter().map(|v| v.to_string()).collect())
+            .unwrap_or_else(Vec::new);
+
+        // Values that aren't a hash we can read are never usable, so they are
+        // skipped rather than failing the whole account.
+        let primary = value.get_ava("password").and_then(|vs| {
+            vs.iter()
+                .filter_map(|v| Password::from_stored(v.to_string().as_str()))
+                .next()
+        });
+
+        // Both halves are needed. Whatever the expiry says, the secret stops
+        // working once the longest lifetime the server gives out has passed
+        // since it was set.
+        let break_glass_secret = value
+            .get_ava_single("break_glass_secret")
+            .and_then(|v| Password::from_stored(v.to_string().as_str()));
+        let break_glass_set = value.get_csn("break_glass_secret").map(|c| c.ts());
+        let break_glass_expiry = get_ava_datetime(&value, "break_glass_expiry");
+        let break_glass = match (break_glass_secret, break_glass_set, break_glass_expiry) {
+            (Some(secret), Some(set), Some(expiry)) => Some(BreakGlass {
+                secret: secret,
+                expiry: std::cmp::min(expiry, set + Duration::from_secs(BREAK_GLASS_MAX_LIFETIME)),
+            }),
+            _ => None,
+        };
+
+        let validity = ValidityWindow::from_entry(&value);
+
         let uuid = value.get_uuid().clone();
 
         Ok(Account {
             uuid: uuid,
             name: name,
             displayname: displayname,
+            spn: spn,
             groups: groups,
+            cert_mappings: cert_mappings,
+            primary: primary,
+            break_glass: break_glass,
+            validity: validity,
         })
     }
 
@@ -76,9 +170,11 @@ impl Account {
             name: self.name.clone(),
             displayname: self.name.clone(),
             uuid: self.uuid.clone(),
+            spn: self.spn.clone(),
             application: None,
             groups: self.groups.iter().map(|g| g.into_proto()).collect(),
             claims: claims.iter().map(|c| c.into_proto()).collect(),
+            expiry: None,
         })
     }
 }
@@ -90,8 +186,10 @@ impl Account {
 #[cfg(test)]
 mod tests {
     use crate::constants::JSON_ANONYMOUS_V1;
+    use crate::domain::DomainInfo;
     use crate::entry::{Entry, EntryNew, EntryValid};
     use crate::idm::account::Account;
+    use std::time::Duration;
 
     #[test]
     fn test_idm_account_from_anonymous() {
@@ -99,11 +197,50 @@ mod tests {
             serde_json::from_str(JSON_ANONYMOUS_V1).expect("Json deserialise failure!");
         let anon_e = unsafe { anon_e.to_valid_committed() };
 
-        let anon_account = Account::try_from_entry(anon_e).expect("Must not fail");
+        let anon_account =
+            Account::try_from_entry(anon_e, &DomainInfo::new()).expect("Must not fail");
         println!("{:?}", anon_account);
+        assert!(anon_account.spn == "anonymous@localhost");
         // I think that's it? we may want to check anonymous mech ...
     }
 
+    #[test]
+    fn test_idm_account_validity() {
+        let e: Entry<EntryValid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": {
+                    "uuid": "5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"
+                },
+                "state": null,
+                "attrs": {
+                    "class": ["account", "object"],
+                    "name": ["testperson"],
+                    "uuid": ["5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"],
+                    "displayname": ["Test Person"],
+                    "account_valid_from": ["1970-01-01T01:00:00+00:00"],
+                    "account_expire": ["1970-01-01T02:00:00+00:00"]
+                }
+            }"#,
+        )
+        .expect("Json deserialise failure!");
+        let account =
+            Account::try_from_entry(unsafe { e.to_valid_committed() }, &DomainInfo::new())
+                .expect("Must not fail");
+        // The start is included, and the end isn't.
+        assert!(!account.validity.contains(Duration::from_secs(3599)));
+        assert!(account.validity.contains(Duration::from_secs(3600)));
+        assert!(account.validity.contains(Duration::from_secs(7199)));
+        assert!(!account.validity.contains(Duration::from_secs(7200)));
+
+        // Without either, it's always valid.
+        let anon_e: Entry<EntryValid, EntryNew> =
+            serde_json::from_str(JSON_ANONYMOUS_V1).expect("Json deserialise failure!");
+        let anon_account =
+            Account::try_from_entry(unsafe { anon_e.to_valid_committed() }, &DomainInfo::new())
+                .expect("Must not fail");
+        assert!(anon_account.validity.contains(Duration::from_secs(0)));
+    }
+
     #[test]
     fn test_idm_account_from_real() {
         // For now, nothing, but later, we'll test different types of cred
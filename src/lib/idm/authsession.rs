@@ -1,8 +1,13 @@
+use chrono::{DateTime, Utc};
+use std::time::{Duration, UNIX_EPOCH};
+
 use crate::audit::AuditScope;
 use crate::constants::UUID_ANONYMOUS;
 use crate::error::OperationError;
-use crate::idm::account::Account;
+use crate::idm::account::{Account, BreakGlass};
 use crate::idm::claim::Claim;
+use crate::idm::clientcert::VerifiedCert;
+use crate::idm::credential::Password;
 use crate::proto::v1::{AuthAllowed, AuthCredential, AuthState};
 
 // Each CredHandler takes one or more credentials and determines if the
@@ -19,9 +24,13 @@ enum CredState {
 #[derive(Clone, Debug)]
 enum CredHandler {
     Anonymous,
+    // The cert_mapping rules of the account.
+    ClientCert(Vec<String>),
+    Password(Password),
+    // The account has no credential we can check, so nothing will do.
+    Denied,
     // AppPassword
     // {
-    // Password
     // Webauthn
     // Webauthn + Password
     // TOTP
@@ -32,7 +41,11 @@ enum CredHandler {
 }
 
 impl CredHandler {
-    pub fn validate(&mut self, creds: &Vec<AuthCredential>) -> CredState {
+    pub fn validate(
+        &mut self,
+        creds: &Vec<AuthCredential>,
+        client_cert: Option<&VerifiedCert>,
+    ) -> CredState {
         match self {
             CredHandler::Anonymous => {
                 creds.iter().fold(
@@ -62,12 +75,45 @@ impl CredHandler {
                     },
                 )
             } // end credhandler::anonymous
+            CredHandler::ClientCert(rules) => {
+                // This is a single step - exactly one certificate credential,
+                // and the verified certificate of the connection must map to
+                // this account.
+                match creds.as_slice() {
+                    [AuthCredential::ClientCertificate] => match client_cert {
+                        Some(c) if c.matches_any(rules.as_slice()) => {
+                            CredState::Success(Vec::new())
+                        }
+                        Some(_) => {
+                            CredState::Denied("client certificate does not map to this account")
+                        }
+                        None => CredState::Denied("no trusted client certificate presented"),
+                    },
+                    [] => CredState::Continue(vec![AuthAllowed::ClientCertificate]),
+                    _ => CredState::Denied("non-certificate credential provided"),
+                }
+            }
+            CredHandler::Password(pw) => match creds.as_slice() {
+                [AuthCredential::Password(cleartext)] => {
+                    if pw.verify(cleartext.as_str()) {
+                        CredState::Success(Vec::new())
+                    } else {
+                        CredState::Denied("incorrect password")
+                    }
+                }
+                [] => CredState::Continue(vec![AuthAllowed::Password]),
+                _ => CredState::Denied("non-password credential provided"),
+            },
+            CredHandler::Denied => CredState::Denied("account has no usable credentials"),
         }
     }
 
     pub fn valid_auth_mechs(&self) -> Vec<AuthAllowed> {
         match &self {
             CredHandler::Anonymous => vec![AuthAllowed::Anonymous],
+            CredHandler::ClientCert(_) => vec![AuthAllowed::ClientCertificate],
+            CredHandler::Password(_) => vec![AuthAllowed::Password],
+            CredHandler::Denied => Vec::new(),
         }
     }
 }
@@ -86,6 +132,27 @@ pub(crate) struct AuthSession {
     // Store any related appid we are processing for.
     appid: Option<String>,
     finished: bool,
+    // Set once a break glass secret has been accepted, so it can be spent.
+    break_glass_used: bool,
+}
+
+// A break glass secret is taken instead of whatever the handler wants, so it
+// still works when that is broken. None if these aren't break glass creds.
+fn break_glass_validate(
+    creds: &Vec<AuthCredential>,
+    break_glass: Option<&BreakGlass>,
+    now: Duration,
+) -> Option<CredState> {
+    let secret = match creds.as_slice() {
+        [AuthCredential::BreakGlass(secret)] => secret,
+        _ => return None,
+    };
+    Some(match break_glass {
+        Some(bg) if bg.expiry <= now => CredState::Denied("break glass secret has expired"),
+        Some(bg) if bg.secret.verify(secret.as_str()) => CredState::Success(Vec::new()),
+        Some(_) => CredState::Denied("incorrect break glass secret"),
+        None => CredState::Denied("account has no break glass secret"),
+    })
 }
 
 impl AuthSession {
@@ -103,8 +170,12 @@ impl AuthSession {
                 // and interact with the account more?
                 if account.uuid == UUID_ANONYMOUS {
                     CredHandler::Anonymous
+                } else if !account.cert_mappings.is_empty() {
+                    CredHandler::ClientCert(account.cert_mappings.clone())
+                } else if let Some(pw) = &account.primary {
+                    CredHandler::Password(pw.clone())
                 } else {
-                    unimplemented!();
+                    CredHandler::Denied
                 }
             }
         };
@@ -120,6 +191,7 @@ impl AuthSession {
             handler: handler,
             appid: appid,
             finished: false,
+            break_glass_used: false,
         }
     }
 
@@ -128,6 +200,8 @@ impl AuthSession {
         &mut self,
         au: &mut AuditScope,
         creds: &Vec<AuthCredential>,
+        client_cert: Option<&VerifiedCert>,
+        now: Duration,
     ) -> Result<AuthState, OperationError> {
         if self.finished {
             return Err(OperationError::InvalidAuthState(
@@ -135,14 +209,56 @@ impl AuthSession {
             ));
         }
 
-        match self.handler.validate(creds) {
+        // Whatever the credentials, an account can't be authenticated as
+        // outside of its validity window.
+        if !self.account.validity.contains(now) {
+            self.finished = true;
+            audit_log!(
+                au,
+                "Account {} is not valid at this time",
+                self.account.name
+            );
+            return Ok(AuthState::Denied(
+                "account is not valid at this time".to_string(),
+            ));
+        }
+
+        let break_glass = self.account.break_glass.as_ref();
+        let (state, expiry) = match break_glass_validate(creds, break_glass, now) {
+            Some(state) => {
+                // Every attempt is logged, not just to the audit log, as
+                // these are only for emergencies.
+                let expiry = break_glass
+                    .map(|bg| DateTime::<Utc>::from(UNIX_EPOCH + bg.expiry).to_rfc3339());
+                match &state {
+                    CredState::Success(_) => warn!(
+                        "Break glass session for {} until {:?}",
+                        self.account.name, expiry
+                    ),
+                    CredState::Denied(reason) => {
+                        warn!("Break glass denied for {}: {}", self.account.name, reason)
+                    }
+                    CredState::Continue(_) => {}
+                }
+                audit_log!(au, "Break glass credential for {}", self.account.name);
+                if let CredState::Success(_) = &state {
+                    self.break_glass_used = true;
+                }
+                (state, expiry)
+            }
+            None => (self.handler.validate(creds, client_cert), None),
+        };
+
+        match state {
             CredState::Success(claims) => {
                 audit_log!(au, "Successful cred handling");
                 self.finished = true;
-                let uat = self
+                let mut uat = self
                     .account
                     .to_userauthtoken(claims)
                     .ok_or(OperationError::InvalidState)?;
+                // The session ends when the secret does.
+                uat.expiry = expiry;
                 Ok(AuthState::Success(uat))
             }
             CredState::Continue(allowed) => {
@@ -168,6 +284,14 @@ impl AuthSession {
         //  If success, to authtoken?
     }
 
+    pub fn account_uuid(&self) -> &str {
+        self.account.uuid.as_str()
+    }
+
+    pub fn used_break_glass(&self) -> bool {
+        self.break_glass_used
+    }
+
     pub fn valid_auth_mechs(&self) -> Vec<AuthAllowed> {
         if self.finished {
             Vec::new()
@@ -179,9 +303,83 @@ impl AuthSession {
 
 #[cfg(test)]
 mod tests {
+    use crate::audit::AuditScope;
     use crate::constants::JSON_ANONYMOUS_V1;
     use crate::idm::authsession::AuthSession;
-    use crate::proto::v1::AuthAllowed;
+    use crate::idm::clientcert::VerifiedCert;
+    use crate::proto::v1::{AuthAllowed, AuthCredential, AuthState};
+    use std::time::Duration;
+
+    static JSON_SERVICE_ACCOUNT: &'static str = r#"{
+        "valid": {
+            "uuid": "a9f26e4c-5c9a-4a8d-9f3c-4b0f4a2fd0e6"
+        },
+        "state": null,
+        "attrs": {
+            "class": ["account", "object"],
+            "name": ["backup"],
+            "uuid": ["a9f26e4c-5c9a-4a8d-9f3c-4b0f4a2fd0e6"],
+            "displayname": ["Backup Service"],
+            "cert_mapping": ["san:backup.example.com"]
+        }
+    }"#;
+
+    // The password is "correct horse".
+    static JSON_PASSWORD_ACCOUNT: &'static str = r#"{
+        "valid": {
+            "uuid": "5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"
+        },
+        "state": null,
+        "attrs": {
+            "class": ["account", "object"],
+            "name": ["testperson"],
+            "uuid": ["5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"],
+            "displayname": ["Test Person"],
+            "password": ["pbkdf2_sha256$100$00112233445566778899aabbccddeeff$5638241202bcf456b32d5297f18c38d87b7ec42c0dabe0f049ff5e299af38bd0"]
+        }
+    }"#;
+
+    // The password and the break glass secret are both "correct horse". The
+    // secret was set at the epoch, and expires an hour after.
+    static JSON_BREAK_GLASS_ACCOUNT: &'static str = r#"{
+        "valid": {
+            "uuid": "5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"
+        },
+        "state": null,
+        "attrs": {
+            "class": ["account", "object"],
+            "name": ["testperson"],
+            "uuid": ["5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"],
+            "displayname": ["Test Person"],
+            "password": ["pbkdf2_sha256$100$00112233445566778899aabbccddeeff$5638241202bcf456b32d5297f18c38d87b7ec42c0dabe0f049ff5e299af38bd0"],
+            "break_glass_secret": ["pbkdf2_sha256$100$00112233445566778899aabbccddeeff$5638241202bcf456b32d5297f18c38d87b7ec42c0dabe0f049ff5e299af38bd0"],
+            "break_glass_expiry": ["1970-01-01T01:00:00+00:00"]
+        },
+        "csns": {
+            "break_glass_secret": {
+                "ts": {"secs": 0, "nanos": 0},
+                "rid": "00000000-0000-0000-0000-000000000000"
+            }
+        }
+    }"#;
+
+    // The password is "correct horse", and the account may be used from one
+    // hour after the epoch until two.
+    static JSON_TIMEBOXED_ACCOUNT: &'static str = r#"{
+        "valid": {
+            "uuid": "5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"
+        },
+        "state": null,
+        "attrs": {
+            "class": ["account", "object"],
+            "name": ["testperson"],
+            "uuid": ["5a2a3e70-6c1a-4a5b-9d0e-3c2f0f8a7d51"],
+            "displayname": ["Test Person"],
+            "password": ["pbkdf2_sha256$100$00112233445566778899aabbccddeeff$5638241202bcf456b32d5297f18c38d87b7ec42c0dabe0f049ff5e299af38bd0"],
+            "account_valid_from": ["1970-01-01T01:00:00+00:00"],
+            "account_expire": ["1970-01-01T02:00:00+00:00"]
+        }
+    }"#;
 
     #[test]
     fn test_idm_account_anonymous_auth_mech() {
@@ -198,4 +396,173 @@ mod tests {
             })
         );
     }
+    #[test]
+    fn test_idm_authsession_client_cert() {
+        let mut au = AuditScope::new("test_idm_authsession_client_cert");
+        let good = VerifiedCert {
+            fingerprint: "ab01cd".to_string(),
+            subject: vec![("CN".to_string(), "backup".to_string())],
+            sans: vec!["backup.example.com".to_string()],
+        };
+        let other = VerifiedCert {
+            fingerprint: "ef2345".to_string(),
+            subject: vec![("CN".to_string(), "web".to_string())],
+            sans: vec!["web.example.com".to_string()],
+        };
+        let cert_cred = vec![AuthCredential::ClientCertificate];
+
+        let session = AuthSession::new(entry_str_to_account!(JSON_SERVICE_ACCOUNT), None);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::ClientCertificate]);
+
+        // A certificate that maps to the account succeeds.
+        let mut session = AuthSession::new(entry_str_to_account!(JSON_SERVICE_ACCOUNT), None);
+        match session.validate_creds(&mut au, &cert_cred, Some(&good), Duration::from_secs(0)) {
+            Ok(AuthState::Success(uat)) => assert!(uat.name == "backup"),
+            _ => panic!(),
+        }
+
+        // Another trusted certificate, no certificate, or any other
+        // credential is denied.
+        for (creds, cert) in vec![
+            (vec![AuthCredential::ClientCertificate], Some(&other)),
+            (vec![AuthCredential::ClientCertificate], None),
+            (vec![AuthCredential::Anonymous], Some(&good)),
+            (
+                vec![
+                    AuthCredential::ClientCertificate,
+                    AuthCredential::Password("x".to_string()),
+                ],
+                Some(&good),
+            ),
+        ] {
+            let mut session = AuthSession::new(entry_str_to_account!(JSON_SERVICE_ACCOUNT), None);
+            match session.validate_creds(&mut au, &creds, cert, Duration::from_secs(0)) {
+                Ok(AuthState::Denied(_)) => {}
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_idm_authsession_password() {
+        let mut au = AuditScope::new("test_idm_authsession_password");
+
+        let session = AuthSession::new(entry_str_to_account!(JSON_PASSWORD_ACCOUNT), None);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+
+        let mut session = AuthSession::new(entry_str_to_account!(JSON_PASSWORD_ACCOUNT), None);
+        let creds = vec![AuthCredential::Password("correct horse".to_string())];
+        match session.validate_creds(&mut au, &creds, None, Duration::from_secs(0)) {
+            Ok(AuthState::Success(uat)) => assert!(uat.name == "testperson"),
+            _ => panic!(),
+        }
+        // Once finished, the session can't be used again.
+        assert!(session
+            .validate_creds(&mut au, &creds, None, Duration::from_secs(0))
+            .is_err());
+
+        for creds in vec![
+            vec![AuthCredential::Password("wrong horse".to_string())],
+            vec![AuthCredential::Anonymous],
+            vec![
+                AuthCredential::Password("correct horse".to_string()),
+                AuthCredential::Password("correct horse".to_string()),
+            ],
+        ] {
+            let mut session = AuthSession::new(entry_str_to_account!(JSON_PASSWORD_ACCOUNT), None);
+            match session.validate_creds(&mut au, &creds, None, Duration::from_secs(0)) {
+                Ok(AuthState::Denied(_)) => {}
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_idm_authsession_break_glass() {
+        let mut au = AuditScope::new("test_idm_authsession_break_glass");
+        let creds = vec![AuthCredential::BreakGlass("correct horse".to_string())];
+
+        // Break glass is never offered.
+        let session = AuthSession::new(entry_str_to_account!(JSON_BREAK_GLASS_ACCOUNT), None);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+
+        // Before the expiry it works, and the session carries the expiry.
+        let mut session = AuthSession::new(entry_str_to_account!(JSON_BREAK_GLASS_ACCOUNT), None);
+        match session.validate_creds(&mut au, &creds, None, Duration::from_secs(60)) {
+            Ok(AuthState::Success(uat)) => {
+                assert!(uat.name == "testperson");
+                assert!(uat.expiry == Some("1970-01-01T01:00:00+00:00".to_string()));
+            }
+            _ => panic!(),
+        }
+
+        // Once it expires, or with the wrong secret, or on an account
+        // without one, it's denied.
+        for (json, creds, now) in vec![
+            (
+                JSON_BREAK_GLASS_ACCOUNT,
+                vec![AuthCredential::BreakGlass("correct horse".to_string())],
+                3600,
+            ),
+            (
+                JSON_BREAK_GLASS_ACCOUNT,
+                vec![AuthCredential::BreakGlass("wrong horse".to_string())],
+                60,
+            ),
+            (
+                JSON_PASSWORD_ACCOUNT,
+                vec![AuthCredential::BreakGlass("correct horse".to_string())],
+                60,
+            ),
+        ] {
+            let mut session = AuthSession::new(entry_str_to_account!(json), None);
+            match session.validate_creds(&mut au, &creds, None, Duration::from_secs(now)) {
+                Ok(AuthState::Denied(_)) => {}
+                _ => panic!(),
+            }
+        }
+
+        // An expiry further out than the server gives is cut short.
+        let json = JSON_BREAK_GLASS_ACCOUNT.replace("1970-01-01T01:00:00", "2038-01-19T00:00:00");
+        let mut session = AuthSession::new(entry_str_to_account!(json.as_str()), None);
+        let creds = vec![AuthCredential::BreakGlass("correct horse".to_string())];
+        match session.validate_creds(&mut au, &creds, None, Duration::from_secs(60)) {
+            Ok(AuthState::Success(uat)) => {
+                assert!(uat.expiry == Some("1970-01-01T04:00:00+00:00".to_string()))
+            }
+            _ => panic!(),
+        }
+        let mut session = AuthSession::new(entry_str_to_account!(json.as_str()), None);
+        match session.validate_creds(&mut au, &creds, None, Duration::from_secs(4 * 3600)) {
+            Ok(AuthState::Denied(_)) => {}
+            _ => panic!(),
+        }
+
+        // The password still works as usual, without an expiry.
+        let mut session = AuthSession::new(entry_str_to_account!(JSON_BREAK_GLASS_ACCOUNT), None);
+        let creds = vec![AuthCredential::Password("correct horse".to_string())];
+        match session.validate_creds(&mut au, &creds, None, Duration::from_secs(3600)) {
+            Ok(AuthState::Success(uat)) => assert!(uat.expiry.is_none()),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_idm_authsession_validity() {
+        let mut au = AuditScope::new("test_idm_authsession_validity");
+        let creds = vec![AuthCredential::Password("correct horse".to_string())];
+
+        for (now, ok) in vec![(0, false), (3600, true), (7199, true), (7200, false)] {
+            let mut session = AuthSession::new(entry_str_to_account!(JSON_TIMEBOXED_ACCOUNT), None);
+            match session.validate_creds(&mut au, &creds, None, Duration::from_secs(now)) {
+                Ok(AuthState::Success(_)) => assert!(ok),
+                Ok(AuthState::Denied(_)) => assert!(!ok),
+                _ => panic!(),
+            }
+            // Denied sessions are over.
+            assert!(session
+                .validate_creds(&mut au, &creds, None, Duration::from_secs(now))
+                .is_err());
+        }
+    }
 }
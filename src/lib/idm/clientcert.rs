@@ -0,0 +1,359 @@
+// Client certificate authentication.
+//
+// Services talking to the server shouldn't need a shared secret - they
+// already hold a certificate from our CA. The certificate the client
+// presented is checked against the configured CA here, and what we learn
+// from it (fingerprint, subject and alt names) is matched against the
+// cert_mapping rules of the account it claims to be.
+//
+// Only certificates issued for client authentication are accepted - the
+// extendedKeyUsage must be present and include clientAuth, so a server
+// certificate from the same CA can't be used to log in.
+//
+// The certificate itself is taken from the connection by the frontend, so
+// a client can never just send one in its request.
+
+use openssl::hash::MessageDigest;
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509PurposeId, X509StoreContext, X509};
+
+use crate::error::OperationError;
+
+// The DER encoded object identifier of extendedKeyUsage, 2.5.29.37.
+const OID_EXT_KEY_USAGE: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x25];
+
+pub struct ClientCertVerifier {
+    store: X509Store,
+}
+
+impl ClientCertVerifier {
+    // The trusted CAs, as one or more PEM certificates.
+    pub fn from_pem(ca: &[u8]) -> Result<Self, OperationError> {
+        let certs = X509::stack_from_pem(ca).map_err(|e| {
+            error!("Unable to parse client CA -> {:?}", e);
+            OperationError::InvalidState
+        })?;
+        if certs.is_empty() {
+            error!("No certificates found in client CA");
+            return Err(OperationError::InvalidState);
+        }
+        let mut builder = X509StoreBuilder::new().map_err(|_| OperationError::InvalidState)?;
+        // Refuses a certificate whose extendedKeyUsage doesn't include
+        // clientAuth. One without the extension at all passes this, so
+        // verify checks it is there.
+        builder
+            .set_purpose(X509PurposeId::SSL_CLIENT)
+            .map_err(|_| OperationError::InvalidState)?;
+        for c in certs {
+            builder
+                .add_cert(c)
+                .map_err(|_| OperationError::InvalidState)?;
+        }
+        Ok(ClientCertVerifier {
+            store: builder.build(),
+        })
+    }
+
+    // Check a PEM certificate was issued by one of our CAs and is in date.
+    pub fn verify(&self, pem: &[u8]) -> Result<VerifiedCert, &'static str> {
+        let cert = X509::from_pem(pem).map_err(|_| "invalid client certificate")?;
+        let chain = Stack::new().map_err(|_| "unable to verify client certificate")?;
+        let mut ctx = X509StoreContext::new().map_err(|_| "unable to verify client certificate")?;
+        let valid = ctx
+            .init(&self.store, &cert, &chain, |c| c.verify_cert())
+            .map_err(|_| "unable to verify client certificate")?;
+        if !valid {
+            return Err("client certificate is not trusted");
+        }
+        let der = cert
+            .to_der()
+            .map_err(|_| "unable to verify client certificate")?;
+        if !der
+            .windows(OID_EXT_KEY_USAGE.len())
+            .any(|w| w == OID_EXT_KEY_USAGE)
+        {
+            return Err("client certificate has no extended key usage");
+        }
+        VerifiedCert::from_x509(&cert)
+    }
+}
+
+// What a trusted certificate says about its holder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedCert {
+    // Lower case hex sha256 of the DER certificate.
+    pub fingerprint: String,
+    // The attribute short name and value of each subject RDN, in the order
+    // of the certificate.
+    pub subject: Vec<(String, String)>,
+    // DNS names and email addresses.
+    pub sans: Vec<String>,
+}
+
+impl VerifiedCert {
+    fn from_x509(cert: &X509) -> Result<Self, &'static str> {
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .map_err(|_| "unable to fingerprint client certificate")?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let subject = cert
+            .subject_name()
+            .entries()
+            .map(|e| {
+                let key = e.object().nid().short_name().unwrap_or("UNDEF");
+                let value = e
+                    .data()
+                    .to_string()
+                    .map_err(|_| "unable to read client certificate subject")?;
+                Ok((key.to_string(), value))
+            })
+            .collect::<Result<Vec<_>, &'static str>>()?;
+
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| n.dnsname().or_else(|| n.email()))
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(VerifiedCert {
+            fingerprint: fingerprint,
+            subject: subject,
+            sans: sans,
+        })
+    }
+
+    // Does this certificate satisfy one cert_mapping value? Rules we don't
+    // understand never match.
+    pub fn matches(&self, rule: &str) -> bool {
+        let mut parts = rule.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("sha256"), Some(fp)) => {
+                // Allow the colon separated form most tools print.
+                fp.replace(":", "").to_lowercase() == self.fingerprint
+            }
+            // Compared entry by entry, so a value containing "," or "=" can
+            // only ever match itself.
+            (Some("subject"), Some(dn)) => match parse_dn(dn) {
+                Some(rdns) => {
+                    rdns.len() == self.subject.len()
+                        && rdns
+                            .iter()
+                            .zip(self.subject.iter())
+                            .all(|((rk, rv), (sk, sv))| rk.eq_ignore_ascii_case(sk) && rv == sv)
+                }
+                None => false,
+            },
+            (Some("san"), Some(name)) => self
+                .sans
+                .iter()
+                .any(|s| s.to_lowercase() == name.to_lowercase()),
+            _ => false,
+        }
+    }
+
+    pub fn matches_any(&self, rules: &[String]) -> bool {
+        rules.iter().any(|r| self.matches(r.as_str()))
+    }
+}
+
+// Split an RFC 4514 DN string into its attribute types and values, undoing
+// the escapes in the values. Multi-valued RDNs are flattened, as openssl
+// lists their entries. Spaces around each part are ignored unless escaped.
+fn parse_dn(dn: &str) -> Option<Vec<(String, String)>> {
+    let mut rdns = Vec::new();
+    let mut key: Option<String> = None;
+    // The bytes of the current part, and whether each was escaped.
+    let mut part: Vec<(u8, bool)> = Vec::new();
+    let mut bytes = dn.bytes().peekable();
+
+    let finish = |part: &mut Vec<(u8, bool)>| -> Option<String> {
+        while part.first() == Some(&(b' ', false)) {
+            part.remove(0);
+        }
+        while part.last() == Some(&(b' ', false)) {
+            part.pop();
+        }
+        String::from_utf8(part.drain(..).map(|(b, _)| b).collect()).ok()
+    };
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => {
+                let c = bytes.next()?;
+                let hex = |c: u8| (c as char).to_digit(16);
+                match (hex(c), bytes.peek().and_then(|n| hex(*n))) {
+                    (Some(h), Some(l)) => {
+                        bytes.next();
+                        part.push(((h * 16 + l) as u8, true));
+                    }
+                    _ => part.push((c, true)),
+                }
+            }
+            b'=' if key.is_none() => {
+                key = Some(finish(&mut part)?);
+            }
+            b',' | b'+' => {
+                let k = key.take()?;
+                let v = finish(&mut part)?;
+                if k.is_empty() {
+                    return None;
+                }
+                rdns.push((k, v));
+            }
+            b => part.push((b, false)),
+        }
+    }
+    let k = key.take()?;
+    let v = finish(&mut part)?;
+    if k.is_empty() {
+        return None;
+    }
+    rdns.push((k, v));
+    Some(rdns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientCertVerifier, VerifiedCert};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage};
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+
+    fn subject(rdns: &[(&str, &str)]) -> Vec<(String, String)> {
+        rdns.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    // A certificate for cn, signed by the issuer if given, else by itself.
+    fn make_cert(
+        cn: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        eku: Option<ExtendedKeyUsage>,
+        ca: bool,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().expect("name builder");
+        name.append_entry_by_text("CN", cn).expect("name cn");
+        let name = name.build();
+        let mut serial = BigNum::new().expect("bignum");
+        serial
+            .rand(64, MsbOption::MAYBE_ZERO, false)
+            .expect("serial");
+
+        let mut b = X509Builder::new().expect("x509 builder");
+        b.set_version(2).expect("version");
+        b.set_serial_number(&serial.to_asn1_integer().expect("serial"))
+            .expect("serial");
+        b.set_subject_name(&name).expect("subject");
+        match issuer {
+            Some((i, _)) => b.set_issuer_name(i.subject_name()).expect("issuer"),
+            None => b.set_issuer_name(&name).expect("issuer"),
+        }
+        b.set_pubkey(key).expect("pubkey");
+        b.set_not_before(&Asn1Time::days_from_now(0).expect("time"))
+            .expect("not before");
+        b.set_not_after(&Asn1Time::days_from_now(1).expect("time"))
+            .expect("not after");
+        if ca {
+            b.append_extension(BasicConstraints::new().critical().ca().build().expect("bc"))
+                .expect("bc");
+        }
+        if let Some(eku) = eku {
+            b.append_extension(eku.build().expect("eku")).expect("eku");
+        }
+        let signer = issuer.map(|(_, k)| k).unwrap_or(key);
+        b.sign(signer, MessageDigest::sha256()).expect("sign");
+        b.build()
+    }
+
+    #[test]
+    fn test_clientcert_mapping() {
+        let cert = VerifiedCert {
+            fingerprint: "ab01cd".to_string(),
+            subject: subject(&[("CN", "backup"), ("O", "Example")]),
+            sans: vec!["backup.example.com".to_string()],
+        };
+
+        assert!(cert.matches("sha256:ab01cd"));
+        assert!(cert.matches("sha256:AB:01:CD"));
+        assert!(!cert.matches("sha256:ab01ce"));
+        assert!(cert.matches("subject:CN=backup, O=Example"));
+        assert!(cert.matches("subject:cn=backup,O=Example"));
+        assert!(!cert.matches("subject:CN=backup"));
+        assert!(!cert.matches("subject:CN=backup,O=example"));
+        // Values are compared whole, so a CN holding a comma can't pass for
+        // another subject.
+        let tricky = VerifiedCert {
+            fingerprint: "ef2345".to_string(),
+            subject: subject(&[("CN", "backup,O=Example")]),
+            sans: Vec::new(),
+        };
+        assert!(!tricky.matches("subject:CN=backup,O=Example"));
+        assert!(tricky.matches("subject:CN=backup\\,O\\=Example"));
+        assert!(tricky.matches("subject:CN=backup\\2cO=Example"));
+        assert!(!cert.matches("subject:CN=backup\\,O=Example"));
+        // A DN that doesn't parse matches nothing.
+        assert!(!cert.matches("subject:CN=backup,Example"));
+        assert!(!cert.matches("subject:CN=backup\\"));
+        assert!(cert.matches("san:Backup.example.com"));
+        assert!(!cert.matches("san:other.example.com"));
+        // Unknown or malformed rules never match.
+        assert!(!cert.matches("issuer:CN=backup,O=Example"));
+        assert!(!cert.matches("ab01cd"));
+
+        assert!(cert.matches_any(&["san:x".to_string(), "sha256:ab01cd".to_string()]));
+        assert!(!cert.matches_any(&[]));
+    }
+
+    #[test]
+    fn test_clientcert_verify_client_auth() {
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).expect("rsa")).expect("pkey");
+        let ca = make_cert("test ca", &ca_key, None, None, true);
+        let verifier =
+            ClientCertVerifier::from_pem(ca.to_pem().expect("pem").as_slice()).expect("verifier");
+        let key = PKey::from_rsa(Rsa::generate(2048).expect("rsa")).expect("pkey");
+
+        let mut eku = ExtendedKeyUsage::new();
+        eku.client_auth();
+        let client = make_cert("backup", &key, Some((&ca, &ca_key)), Some(eku), false);
+        let v = verifier
+            .verify(client.to_pem().expect("pem").as_slice())
+            .expect("client cert refused");
+        assert!(v.subject == subject(&[("CN", "backup")]));
+        assert!(v.matches("subject:CN=backup"));
+
+        // Issued for something else, or without saying what for.
+        let mut eku = ExtendedKeyUsage::new();
+        eku.server_auth();
+        let server = make_cert("backup", &key, Some((&ca, &ca_key)), Some(eku), false);
+        assert!(verifier
+            .verify(server.to_pem().expect("pem").as_slice())
+            .is_err());
+        let bare = make_cert("backup", &key, Some((&ca, &ca_key)), None, false);
+        assert!(verifier
+            .verify(bare.to_pem().expect("pem").as_slice())
+            .is_err());
+
+        // Not from our CA at all.
+        let mut eku = ExtendedKeyUsage::new();
+        eku.client_auth();
+        let own = make_cert("backup", &key, None, Some(eku), false);
+        assert!(verifier
+            .verify(own.to_pem().expect("pem").as_slice())
+            .is_err());
+    }
+}
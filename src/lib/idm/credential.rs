@@ -0,0 +1,152 @@
+// Password credentials.
+//
+// A password is only ever stored as a salted pbkdf2-sha256 hash, in the
+// password attribute of the account, as:
+//
+//   pbkdf2_sha256$<iterations>$<hex salt>$<hex hash>
+//
+// The iterations are kept with each hash, so that raising the work factor
+// doesn't lock out every existing password. Any value of the attribute that
+// is not in this form is taken to be a new cleartext password, and is hashed
+// by the password plugin before it is ever written.
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+
+use crate::constants::PASSWORD_PBKDF2_ITERATIONS;
+use crate::error::OperationError;
+
+static PBKDF2_SHA256: &'static str = "pbkdf2_sha256";
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+#[derive(Clone)]
+pub(crate) struct Password {
+    iterations: usize,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+// Never show the hash material, as accounts end up in the audit log.
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Password({}, {} iterations)",
+            PBKDF2_SHA256, self.iterations
+        )
+    }
+}
+
+impl std::fmt::Display for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}${}${}",
+            PBKDF2_SHA256,
+            self.iterations,
+            to_hex(self.salt.as_slice()),
+            to_hex(self.hash.as_slice())
+        )
+    }
+}
+
+fn to_hex(v: &[u8]) -> String {
+    v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn derive(cleartext: &str, salt: &[u8], iterations: usize) -> Result<Vec<u8>, OperationError> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2_hmac(
+        cleartext.as_bytes(),
+        salt,
+        iterations,
+        MessageDigest::sha256(),
+        hash.as_mut_slice(),
+    )
+    .map_err(|_| OperationError::CryptographyError)?;
+    Ok(hash)
+}
+
+impl Password {
+    pub fn new(cleartext: &str) -> Result<Self, OperationError> {
+        let mut salt = vec![0; SALT_LEN];
+        rand_bytes(salt.as_mut_slice()).map_err(|_| OperationError::CryptographyError)?;
+        let hash = derive(cleartext, salt.as_slice(), PASSWORD_PBKDF2_ITERATIONS)?;
+        Ok(Password {
+            iterations: PASSWORD_PBKDF2_ITERATIONS,
+            salt: salt,
+            hash: hash,
+        })
+    }
+
+    // Parse a stored hash. None if the value isn't one.
+    pub fn from_stored(s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split('$').collect();
+        match parts.as_slice() {
+            [scheme, iterations, salt, hash] if *scheme == PBKDF2_SHA256 => {
+                let iterations = iterations.parse::<usize>().ok().filter(|i| *i > 0)?;
+                let salt = from_hex(salt)?;
+                let hash = from_hex(hash).filter(|h| h.len() == HASH_LEN)?;
+                Some(Password {
+                    iterations: iterations,
+                    salt: salt,
+                    hash: hash,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn verify(&self, cleartext: &str) -> bool {
+        match derive(cleartext, self.salt.as_slice(), self.iterations) {
+            Ok(hash) => memcmp::eq(hash.as_slice(), self.hash.as_slice()),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idm::credential::Password;
+
+    #[test]
+    fn test_idm_credential_password() {
+        let p = Password::new("correct horse").expect("hash failed");
+        assert!(p.verify("correct horse"));
+        assert!(!p.verify("correct horse "));
+        assert!(!p.verify(""));
+
+        // The same password salts differently each time.
+        let q = Password::new("correct horse").expect("hash failed");
+        assert!(p.to_string() != q.to_string());
+
+        // And the stored form round trips, without the debug form leaking it.
+        let stored = p.to_string();
+        let r = Password::from_stored(stored.as_str()).expect("stored form not parsed");
+        assert!(r.verify("correct horse"));
+        assert!(r.to_string() == stored);
+        assert!(!format!("{:?}", r).contains(stored.as_str()));
+
+        // A hash made elsewhere, with fewer iterations, still verifies.
+        let r = Password::from_stored("pbkdf2_sha256$100$00112233445566778899aabbccddeeff$5638241202bcf456b32d5297f18c38d87b7ec42c0dabe0f049ff5e299af38bd0").expect("stored form not parsed");
+        assert!(r.verify("correct horse"));
+
+        assert!(Password::from_stored("correct horse").is_none());
+        assert!(Password::from_stored("pbkdf2_sha256$0$00$00").is_none());
+        assert!(Password::from_stored("pbkdf2_sha256$10$zz$00").is_none());
+        assert!(Password::from_stored("md5$10$00$00").is_none());
+    }
+}
@@ -1,6 +1,7 @@
 #[cfg(test)]
 macro_rules! entry_str_to_account {
     ($entry_str:expr) => {{
+        use crate::domain::DomainInfo;
         use crate::entry::{Entry, EntryNew, EntryValid};
         use crate::idm::account::Account;
 
@@ -8,7 +9,7 @@ macro_rules! entry_str_to_account {
             serde_json::from_str($entry_str).expect("Json deserialise failure!");
         let e = unsafe { e.to_valid_committed() };
 
-        Account::try_from_entry(e).expect("Account conversion failure")
+        Account::try_from_entry(e, &DomainInfo::new()).expect("Account conversion failure")
     }};
 }
 
@@ -4,6 +4,9 @@ mod macros;
 pub(crate) mod account;
 pub(crate) mod authsession;
 pub(crate) mod claim;
+pub(crate) mod clientcert;
+pub(crate) mod credential;
 pub(crate) mod group;
+pub(crate) mod oauth2;
 pub(crate) mod server;
 // mod identity;
@@ -0,0 +1,263 @@
+// An oauth2 and openid connect provider. The relying parties - resource
+// servers - are oauth2_resource_server entries, named by their client id.
+// Only the authorisation code flow is offered: the browser of an account with
+// a session is sent back to the resource server with a code, which the
+// resource server exchanges, with its secret, for tokens.
+//
+// Tokens are JWTs signed with HS256 under the client secret, so a resource
+// server checks them with what it already holds. Their claims are what the
+// resource server may read of the account, so access controls decide what it
+// learns.
+
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use url::Url;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryReduced, EntryValid};
+use crate::error::OperationError;
+
+// The scope that asks for an id token, and those that add claims to it.
+pub(crate) static OAUTH2_SCOPE_OPENID: &'static str = "openid";
+static OAUTH2_SCOPE_PROFILE: &'static str = "profile";
+static OAUTH2_SCOPE_EMAIL: &'static str = "email";
+
+// Never Debug, as it holds the secret.
+#[derive(Clone)]
+pub(crate) struct Oauth2ResourceServer {
+    pub name: String,
+    secret: String,
+    redirect_uris: Vec<Url>,
+    // The group uuid, and the scopes its members may be given.
+    scope_maps: Vec<(String, Vec<String>)>,
+}
+
+impl Oauth2ResourceServer {
+    pub fn try_from_entry(
+        au: &mut AuditScope,
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        let name = e
+            .get_ava_single("name")
+            .ok_or(OperationError::InvalidEntryState)?
+            .to_string();
+        let secret = e
+            .get_ava_single("oauth2_rs_basic_secret")
+            .ok_or(OperationError::InvalidEntryState)?
+            .to_string();
+        let redirect_uris = e
+            .get_ava("oauth2_rs_redirect_uri")
+            .map(|vs| {
+                vs.iter()
+                    .filter_map(|v| Url::parse(v.to_string().as_str()).ok())
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        // A map without any scopes gives nothing, so is skipped like one we
+        // can't read.
+        let scope_maps = e
+            .get_ava("oauth2_rs_scope_map")
+            .map(|vs| {
+                vs.iter()
+                    .filter_map(|v| {
+                        let v = v.to_string();
+                        let mut words = v.split_whitespace();
+                        let group = words.next()?.to_lowercase();
+                        let scopes: Vec<String> = words.map(|s| s.to_string()).collect();
+                        if scopes.is_empty() {
+                            audit_log!(au, "Ignoring oauth2 scope map {:?} of {}", v, name);
+                            None
+                        } else {
+                            Some((group, scopes))
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(Oauth2ResourceServer {
+            name: name,
+            secret: secret,
+            redirect_uris: redirect_uris,
+            scope_maps: scope_maps,
+        })
+    }
+
+    // Both are compared parsed, so they are in the same form.
+    pub fn has_redirect_uri(&self, uri: &str) -> bool {
+        Url::parse(uri)
+            .map(|u| self.redirect_uris.contains(&u))
+            .unwrap_or(false)
+    }
+
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        self.secret.len() == secret.len() && memcmp::eq(self.secret.as_bytes(), secret.as_bytes())
+    }
+
+    // Every scope the account may be given, from the groups it's a member of.
+    pub fn scopes_for(&self, e: &Entry<EntryValid, EntryCommitted>) -> BTreeSet<String> {
+        self.scope_maps
+            .iter()
+            .filter(|(g, _)| e.attribute_value_pres("memberof", g.as_str()))
+            .flat_map(|(_, scopes)| scopes.iter().cloned())
+            .collect()
+    }
+
+    pub fn sign(&self, claims: &Map<String, JsonValue>) -> Result<String, OperationError> {
+        jws_hs256(claims, self.secret.as_bytes())
+    }
+}
+
+// A code from the authorise endpoint, waiting for the resource server to
+// exchange it.
+#[derive(Debug, Clone)]
+pub(crate) struct Oauth2Grant {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub account_uuid: String,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+    // As time since the epoch.
+    pub expiry: Duration,
+}
+
+// The registered claims every token has, rfc7519 4.1.
+pub(crate) fn token_claims(
+    iss: &str,
+    grant: &Oauth2Grant,
+    iat: u64,
+    exp: u64,
+) -> Map<String, JsonValue> {
+    let mut claims = Map::new();
+    claims.insert("iss".to_string(), JsonValue::from(iss));
+    claims.insert(
+        "sub".to_string(),
+        JsonValue::from(grant.account_uuid.as_str()),
+    );
+    claims.insert("aud".to_string(), JsonValue::from(grant.client_id.as_str()));
+    claims.insert("iat".to_string(), JsonValue::from(iat));
+    claims.insert("exp".to_string(), JsonValue::from(exp));
+    claims
+}
+
+// Add the openid connect claims the scopes ask for, from the account as the
+// resource server can read it. Claims it can't read are left out.
+pub(crate) fn add_id_claims(
+    claims: &mut Map<String, JsonValue>,
+    grant: &Oauth2Grant,
+    e: &Entry<EntryReduced, EntryCommitted>,
+) {
+    let has_scope = |s: &str| grant.scopes.iter().any(|g| g == s);
+    let mut claim = |c: &str, attr: &str| {
+        if let Some(v) = e.get_ava_single(attr) {
+            claims.insert(c.to_string(), JsonValue::from(v.to_string()));
+        }
+    };
+    if has_scope(OAUTH2_SCOPE_PROFILE) {
+        claim("preferred_username", "name");
+        claim("name", "displayname");
+    }
+    if has_scope(OAUTH2_SCOPE_EMAIL) {
+        claim("email", "mail");
+    }
+    if let Some(nonce) = &grant.nonce {
+        claims.insert("nonce".to_string(), JsonValue::from(nonce.as_str()));
+    }
+}
+
+fn base64url(v: &[u8]) -> String {
+    base64::encode_block(v)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+// The claims as a compact JWS, rfc7515 7.1.
+fn jws_hs256(claims: &Map<String, JsonValue>, key: &[u8]) -> Result<String, OperationError> {
+    let header = base64url(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = serde_json::to_vec(claims).map_err(|_| OperationError::SerdeJsonError)?;
+    let signing_input = format!("{}.{}", header, base64url(payload.as_slice()));
+    let sig = PKey::hmac(key)
+        .and_then(|k| {
+            let mut signer = Signer::new(MessageDigest::sha256(), &k)?;
+            signer.update(signing_input.as_bytes())?;
+            signer.sign_to_vec()
+        })
+        .map_err(|_| OperationError::CryptographyError)?;
+    Ok(format!("{}.{}", signing_input, base64url(sig.as_slice())))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryNew, EntryValid};
+    use crate::idm::oauth2::{base64url, jws_hs256, Oauth2ResourceServer};
+    use openssl::base64;
+    use serde_json::{Map, Value as JsonValue};
+
+    #[test]
+    fn test_oauth2_resource_server() {
+        let mut au = AuditScope::new("test_oauth2_resource_server");
+        let e: Entry<EntryValid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": {"uuid": "cc8e95b4-c24f-4d68-ba54-8bed76f639f1"},
+                "state": null,
+                "attrs": {
+                    "class": ["object", "oauth2_resource_server"],
+                    "name": ["test_rs"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639f1"],
+                    "oauth2_rs_basic_secret": ["hunter2"],
+                    "oauth2_rs_redirect_uri": ["https://rs.example.com"],
+                    "oauth2_rs_scope_map": [
+                        "cc8e95b4-c24f-4d68-ba54-8bed76f639f2 openid profile",
+                        "cc8e95b4-c24f-4d68-ba54-8bed76f639f3"
+                    ]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        let e = unsafe { e.to_valid_committed() };
+        let rs = Oauth2ResourceServer::try_from_entry(&mut au, &e).expect("rs failure");
+        assert!(rs.name == "test_rs");
+        // Parsing gives the same form either way.
+        assert!(rs.has_redirect_uri("https://rs.example.com/"));
+        assert!(!rs.has_redirect_uri("https://evil.example.com/"));
+        assert!(!rs.has_redirect_uri("not a url"));
+        assert!(rs.verify_secret("hunter2"));
+        assert!(!rs.verify_secret("hunter3"));
+        assert!(!rs.verify_secret(""));
+        // The map with no scopes is skipped.
+        assert!(rs.scope_maps.len() == 1);
+    }
+
+    #[test]
+    fn test_oauth2_jws() {
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), JsonValue::from("test"));
+        let jws = jws_hs256(&claims, b"secret").expect("sign failure");
+        let parts: Vec<&str> = jws.split('.').collect();
+        assert!(parts.len() == 3);
+        assert!(parts.iter().all(|p| !p.contains('=') && !p.contains('+')));
+
+        // The payload is the claims, and the signature is the same for the
+        // same key, but not another.
+        let mut payload = parts[1].replace('-', "+").replace('_', "/");
+        while payload.len() % 4 != 0 {
+            payload.push('=');
+        }
+        let payload = base64::decode_block(payload.as_str()).expect("base64 failure");
+        let decoded: Map<String, JsonValue> =
+            serde_json::from_slice(payload.as_slice()).expect("json failure");
+        assert!(decoded == claims);
+        assert!(jws == jws_hs256(&claims, b"secret").expect("sign failure"));
+        assert!(jws != jws_hs256(&claims, b"other").expect("sign failure"));
+        assert!(base64url(&[0xfb, 0xff]) == "-_8");
+    }
+}
@@ -1,13 +1,28 @@
 use crate::audit::AuditScope;
+use crate::constants::{OAUTH2_CODE_LIFETIME, OAUTH2_TOKEN_LIFETIME, UUID_ANONYMOUS};
+use crate::entry::{Entry, EntryCommitted, EntryValid};
 use crate::error::OperationError;
-use crate::event::{AuthEvent, AuthEventStep, AuthResult};
-use crate::idm::account::Account;
+use crate::event::{
+    AuthEvent, AuthEventStep, AuthResult, Event, EventOrigin, Oauth2AuthoriseEvent,
+    Oauth2TokenEvent, SearchEvent,
+};
+use crate::idm::account::{Account, ValidityWindow};
 use crate::idm::authsession::AuthSession;
-use crate::proto::v1::AuthState;
-use crate::server::{QueryServer, QueryServerTransaction};
+use crate::idm::clientcert::ClientCertVerifier;
+use crate::idm::oauth2::{
+    add_id_claims, token_claims, Oauth2Grant, Oauth2ResourceServer, OAUTH2_SCOPE_OPENID,
+};
+use crate::proto::v1::{AuthState, Oauth2TokenResponse};
+use crate::server::{QueryServer, QueryServerReadTransaction, QueryServerTransaction};
 use concread::cowcell::{CowCell, CowCellWriteTxn};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng};
+use url::Url;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 // use lru::LruCache;
 
@@ -19,8 +34,13 @@ pub struct IdmServer {
     //
     // TODO #60: This needs a mark-and-sweep gc to be added.
     sessions: CowCell<BTreeMap<Uuid, AuthSession>>,
+    // Oauth2 authorisation codes that haven't been exchanged yet. Like auth
+    // sessions these are per server, and lost on restart.
+    oauth2_grants: CowCell<BTreeMap<String, Oauth2Grant>>,
     // Need a reference to the query server.
     qs: QueryServer,
+    // Without this, no client certificate is trusted.
+    client_ca: Option<Arc<ClientCertVerifier>>,
 }
 
 pub struct IdmServerWriteTransaction<'a> {
@@ -28,7 +48,9 @@ pub struct IdmServerWriteTransaction<'a> {
     // the idm in memory structures (maybe the query server too). This is
     // things like authentication
     sessions: CowCellWriteTxn<'a, BTreeMap<Uuid, AuthSession>>,
+    oauth2_grants: CowCellWriteTxn<'a, BTreeMap<String, Oauth2Grant>>,
     qs: &'a QueryServer,
+    client_ca: Option<&'a ClientCertVerifier>,
 }
 
 /*
@@ -44,14 +66,22 @@ impl IdmServer {
     pub fn new(qs: QueryServer) -> IdmServer {
         IdmServer {
             sessions: CowCell::new(BTreeMap::new()),
+            oauth2_grants: CowCell::new(BTreeMap::new()),
             qs: qs,
+            client_ca: None,
         }
     }
 
+    pub fn set_client_ca(&mut self, client_ca: ClientCertVerifier) {
+        self.client_ca = Some(Arc::new(client_ca));
+    }
+
     pub fn write(&self) -> IdmServerWriteTransaction {
         IdmServerWriteTransaction {
             sessions: self.sessions.write(),
+            oauth2_grants: self.oauth2_grants.write(),
             qs: &self.qs,
+            client_ca: self.client_ca.as_ref().map(|v| v.as_ref()),
         }
     }
 
@@ -115,13 +145,18 @@ impl<'a> IdmServerWriteTransaction<'a> {
                     }
                 };
 
-                audit_log!(au, "Initiating Authentication Session for ... {:?}", entry);
+                audit_log!(
+                    au,
+                    "Initiating Authentication Session for ... {:?}",
+                    entry.get_uuid()
+                );
+                au.set_origin(Some(entry.get_uuid().as_str()));
 
                 // Now, convert the Entry to an account - this gives us some stronger
                 // typing and functionality so we can assess what auth types can
                 // continue, and helps to keep non-needed entry specific data
                 // out of the LRU.
-                let account = Account::try_from_entry(entry)?;
+                let account = Account::try_from_entry(entry, qs_read.get_domain_info())?;
                 let auth_session = AuthSession::new(account, init.appid.clone());
 
                 // Get the set of mechanisms that can proceed. This is tied
@@ -153,26 +188,240 @@ impl<'a> IdmServerWriteTransaction<'a> {
                         .get_mut(&creds.sessionid)
                         .ok_or(OperationError::InvalidSessionState)
                 );
+                au.set_origin(Some(auth_session.account_uuid()));
+                // Only a certificate that chains to our CA is passed on. If
+                // it doesn't, the handler sees no certificate at all.
+                let client_cert = match (self.client_ca, &creds.client_cert) {
+                    (Some(ca), Some(pem)) => match ca.verify(pem.as_slice()) {
+                        Ok(c) => {
+                            audit_log!(au, "Verified client certificate {:?}", c);
+                            Some(c)
+                        }
+                        Err(e) => {
+                            audit_log!(au, "Rejected client certificate: {}", e);
+                            None
+                        }
+                    },
+                    _ => None,
+                };
                 // Process the credentials here as required.
                 // Basically throw them at the auth_session and see what
                 // falls out.
-                auth_session.validate_creds(au, &creds.creds).map(|aus| {
-                    AuthResult {
-                        // Is this right?
-                        sessionid: creds.sessionid,
-                        state: aus,
+                let state = auth_session.validate_creds(
+                    au,
+                    &creds.creds,
+                    client_cert.as_ref(),
+                    self.qs.now(),
+                )?;
+                // A break glass secret is good for one session, so it's spent
+                // before the session is handed out.
+                if let AuthState::Success(_) = &state {
+                    if auth_session.used_break_glass() {
+                        let mut qs_write = self.qs.write();
+                        qs_write.break_glass_spent(au, auth_session.account_uuid())?;
+                        qs_write.commit(au)?;
                     }
+                }
+                Ok(AuthResult {
+                    // Is this right?
+                    sessionid: creds.sessionid,
+                    state: state,
                 })
             }
         }
     }
 
+    // Check an authorisation request against the resource server and the
+    // account, and give out a code for it. The result is the redirect uri with
+    // the code and state added. Errors are never sent to the redirect uri, so
+    // the browser shows them instead.
+    pub fn oauth2_authorise(
+        &mut self,
+        au: &mut AuditScope,
+        ae: &Oauth2AuthoriseEvent,
+    ) -> Result<String, OperationError> {
+        audit_log!(au, "Received Oauth2AuthoriseEvent -> {:?}", ae);
+        let qs_read = self.qs.read();
+        let account = match Event::from_ro_uat(au, &qs_read, ae.uat.clone())?.origin {
+            EventOrigin::User(e) => e,
+            _ => return Err(OperationError::NotAuthenticated),
+        };
+        let (_, rs) = oauth2_resource_server(au, &qs_read, ae.client_id.as_str())?;
+        if !rs.has_redirect_uri(ae.redirect_uri.as_str()) {
+            audit_log!(
+                au,
+                "{} is not a redirect uri of {}",
+                ae.redirect_uri,
+                rs.name
+            );
+            return Err(OperationError::Oauth2("invalid_request"));
+        }
+        if ae.response_type != "code" {
+            return Err(OperationError::Oauth2("unsupported_response_type"));
+        }
+
+        let granted = rs.scopes_for(&account);
+        if granted.is_empty() || account.get_uuid() == UUID_ANONYMOUS {
+            audit_log!(au, "{} may not use {}", account.get_uuid(), rs.name);
+            return Err(OperationError::Oauth2("access_denied"));
+        }
+        if ae.scopes.is_empty() || !ae.scopes.iter().all(|s| granted.contains(s)) {
+            audit_log!(au, "Scopes {:?} are not all in {:?}", ae.scopes, granted);
+            return Err(OperationError::Oauth2("invalid_scope"));
+        }
+
+        // Codes that were never exchanged are dropped as new ones are given out.
+        let now = self.qs.now();
+        let expired: Vec<String> = self
+            .oauth2_grants
+            .iter()
+            .filter(|(_, g)| g.expiry <= now)
+            .map(|(c, _)| c.clone())
+            .collect();
+        for c in expired {
+            self.oauth2_grants.remove(&c);
+        }
+
+        let mut rng = StdRng::from_entropy();
+        let code: String = rng.sample_iter(&Alphanumeric).take(32).collect();
+        let mut redirect = Url::parse(ae.redirect_uri.as_str())
+            .map_err(|_| OperationError::Oauth2("invalid_request"))?;
+        redirect
+            .query_pairs_mut()
+            .append_pair("code", code.as_str());
+        if let Some(state) = &ae.state {
+            redirect
+                .query_pairs_mut()
+                .append_pair("state", state.as_str());
+        }
+        audit_log!(au, "Oauth2 code for {} to {}", account.get_uuid(), rs.name);
+        self.oauth2_grants.insert(
+            code,
+            Oauth2Grant {
+                client_id: rs.name,
+                redirect_uri: ae.redirect_uri.clone(),
+                account_uuid: account.get_uuid().clone(),
+                scopes: ae.scopes.clone(),
+                nonce: ae.nonce.clone(),
+                expiry: now + Duration::from_secs(OAUTH2_CODE_LIFETIME),
+            },
+        );
+        Ok(redirect.into_string())
+    }
+
+    // Exchange a code for tokens. The resource server authenticates with its
+    // secret, and a code is only ever exchanged once, even if that fails.
+    pub fn oauth2_token(
+        &mut self,
+        au: &mut AuditScope,
+        te: &Oauth2TokenEvent,
+    ) -> Result<Oauth2TokenResponse, OperationError> {
+        audit_log!(au, "Received Oauth2TokenEvent -> {:?}", te);
+        if te.grant_type != "authorization_code" {
+            return Err(OperationError::Oauth2("unsupported_grant_type"));
+        }
+        let (client_id, secret) = match (&te.client_id, &te.client_secret) {
+            (Some(i), Some(s)) => (i, s),
+            _ => return Err(OperationError::Oauth2("invalid_client")),
+        };
+        let qs_read = self.qs.read();
+        let (rs_entry, rs) = oauth2_resource_server(au, &qs_read, client_id.as_str())?;
+        if !rs.verify_secret(secret.as_str()) {
+            audit_log!(au, "Wrong secret for {}", rs.name);
+            return Err(OperationError::Oauth2("invalid_client"));
+        }
+
+        let now = self.qs.now();
+        let grant = self
+            .oauth2_grants
+            .remove(&te.code)
+            .ok_or(OperationError::Oauth2("invalid_grant"))?;
+        if grant.expiry <= now
+            || grant.client_id != rs.name
+            || grant.redirect_uri != te.redirect_uri
+        {
+            audit_log!(au, "Oauth2 code doesn't match the request {:?}", grant);
+            return Err(OperationError::Oauth2("invalid_grant"));
+        }
+
+        // The account may have been deleted or expired since.
+        let account = qs_read
+            .internal_search_uuid(au, grant.account_uuid.as_str())
+            .map_err(|_| OperationError::Oauth2("invalid_grant"))?;
+        if !ValidityWindow::from_entry(&account).contains(now) {
+            return Err(OperationError::Oauth2("invalid_grant"));
+        }
+        // Claims are only what the resource server can read of it.
+        let filt = filter!(f_eq("uuid", grant.account_uuid.as_str()))
+            .validate(qs_read.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let se = SearchEvent::new_impersonate(
+            &Event::from_impersonate_entry(rs_entry),
+            filt.clone(),
+            filt,
+        );
+        let reduced = qs_read
+            .search_ext(au, &se)?
+            .pop()
+            .ok_or(OperationError::Oauth2("invalid_grant"))?;
+
+        let iss = format!("https://{}", qs_read.get_domain_info().name);
+        let iat = now.as_secs();
+        let exp = iat + OAUTH2_TOKEN_LIFETIME;
+        let scope = grant.scopes.join(" ");
+
+        let mut access_claims = token_claims(iss.as_str(), &grant, iat, exp);
+        access_claims.insert("scope".to_string(), scope.clone().into());
+        let access_token = rs.sign(&access_claims)?;
+
+        let id_token = if grant.scopes.iter().any(|s| s == OAUTH2_SCOPE_OPENID) {
+            let mut id_claims = token_claims(iss.as_str(), &grant, iat, exp);
+            add_id_claims(&mut id_claims, &grant, &reduced);
+            Some(rs.sign(&id_claims)?)
+        } else {
+            None
+        };
+
+        audit_log!(
+            au,
+            "Oauth2 tokens for {} to {}",
+            grant.account_uuid,
+            rs.name
+        );
+        Ok(Oauth2TokenResponse {
+            access_token: access_token,
+            token_type: "bearer".to_string(),
+            expires_in: OAUTH2_TOKEN_LIFETIME,
+            scope: scope,
+            id_token: id_token,
+        })
+    }
+
     pub fn commit(self) -> Result<(), OperationError> {
         self.sessions.commit();
+        self.oauth2_grants.commit();
         Ok(())
     }
 }
 
+// The resource server with this client id, and its entry.
+fn oauth2_resource_server(
+    au: &mut AuditScope,
+    qs_read: &QueryServerReadTransaction,
+    client_id: &str,
+) -> Result<(Entry<EntryValid, EntryCommitted>, Oauth2ResourceServer), OperationError> {
+    let filt = filter!(f_and!([
+        f_eq("class", "oauth2_resource_server"),
+        f_eq("name", client_id)
+    ]));
+    let e = qs_read
+        .internal_search(au, filt)?
+        .pop()
+        .ok_or(OperationError::Oauth2("invalid_client"))?;
+    let rs = Oauth2ResourceServer::try_from_entry(au, &e)?;
+    Ok((e, rs))
+}
+
 /*
 impl<'a> IdmServerReadTransaction<'a> {
     pub fn whoami() -> () {}
@@ -183,8 +432,19 @@ impl<'a> IdmServerReadTransaction<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::event::{AuthEvent, AuthResult};
-    use crate::proto::v1::{AuthAllowed, AuthState};
+    use crate::constants::UUID_ADMIN;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::event::{
+        AuthEvent, AuthResult, EventOrigin, Oauth2AuthoriseEvent, Oauth2TokenEvent, SearchEvent,
+    };
+    use crate::idm::server::IdmServerWriteTransaction;
+    use crate::proto::v1::{AuthAllowed, AuthState, UserAuthToken};
+    use crate::server::QueryServerTransaction;
+    use openssl::base64;
+    use serde_json::{Map, Value as JsonValue};
+    use std::time::Duration;
+    use url::Url;
 
     #[test]
     fn test_idm_anonymous_auth() {
@@ -273,4 +533,256 @@ mod tests {
     }
 
     // Test sending anonymous but with no session init.
+
+    #[test]
+    fn test_idm_password_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let pw = {
+                let mut qs_write = qs.write();
+                let pw = qs_write
+                    .recover_account(au, "admin")
+                    .expect("recover failed");
+                qs_write.commit(au).expect("Must not fail");
+                pw
+            };
+
+            let init =
+                |idms_write: &mut IdmServerWriteTransaction, au: &mut AuditScope| match idms_write
+                    .auth(au, &AuthEvent::named_init("admin"))
+                {
+                    Ok(AuthResult {
+                        sessionid,
+                        state: AuthState::Continue(conts),
+                    }) => {
+                        assert!(conts == vec![AuthAllowed::Password]);
+                        sessionid
+                    }
+                    r => panic!("unexpected {:?}", r),
+                };
+
+            let mut idms_write = idms.write();
+            // A wrong password ends the session.
+            let sid = init(&mut idms_write, au);
+            match idms_write.auth(au, &AuthEvent::password_cred_step(sid, "wrong")) {
+                Ok(AuthResult {
+                    state: AuthState::Denied(_),
+                    ..
+                }) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+            assert!(idms_write
+                .auth(au, &AuthEvent::password_cred_step(sid, pw.as_str()))
+                .is_err());
+
+            // The right one gives a token, that acts as the account.
+            let sid = init(&mut idms_write, au);
+            let uat = match idms_write.auth(au, &AuthEvent::password_cred_step(sid, pw.as_str())) {
+                Ok(AuthResult {
+                    state: AuthState::Success(uat),
+                    ..
+                }) => uat,
+                r => panic!("unexpected {:?}", r),
+            };
+            idms_write.commit().expect("Must not fail");
+            assert!(uat.uuid == UUID_ADMIN);
+
+            let qs_read = qs.read();
+            let se = SearchEvent::from_whoami_request(au, Some(uat), &qs_read)
+                .expect("whoami event failed");
+            match &se.event.origin {
+                EventOrigin::User(e) => assert!(e.get_uuid() == UUID_ADMIN),
+                _ => panic!(),
+            }
+            let res = qs_read.search_ext(au, &se).expect("search failed");
+            assert!(res.len() == 1);
+            assert!(res[0].attribute_equality("name", "admin"));
+            assert!(!res[0].attribute_pres("password"));
+        });
+    }
+
+    #[test]
+    fn test_idm_break_glass_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let (secret, expiry) = {
+                let mut qs_write = qs.write();
+                let r = qs_write
+                    .break_glass(au, "admin", Duration::from_secs(600))
+                    .expect("break glass failed");
+                qs_write.commit(au).expect("Must not fail");
+                r
+            };
+
+            let mut idms_write = idms.write();
+            // It's never offered, and admin has no other credential here, but
+            // it's accepted all the same.
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin")) {
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Continue(conts),
+                }) => {
+                    assert!(conts.is_empty());
+                    sessionid
+                }
+                r => panic!("unexpected {:?}", r),
+            };
+            let uat = match idms_write
+                .auth(au, &AuthEvent::break_glass_cred_step(sid, secret.as_str()))
+            {
+                Ok(AuthResult {
+                    state: AuthState::Success(uat),
+                    ..
+                }) => uat,
+                r => panic!("unexpected {:?}", r),
+            };
+            assert!(uat.uuid == UUID_ADMIN);
+            assert!(uat.expiry == Some(expiry));
+
+            // It's spent by that session, so it can't make another.
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin")) {
+                Ok(AuthResult { sessionid, .. }) => sessionid,
+                r => panic!("unexpected {:?}", r),
+            };
+            match idms_write.auth(au, &AuthEvent::break_glass_cred_step(sid, secret.as_str())) {
+                Ok(AuthResult {
+                    state: AuthState::Denied(_),
+                    ..
+                }) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+            idms_write.commit().expect("Must not fail");
+
+            // The token works until it expires.
+            let qs_read = qs.read();
+            assert!(SearchEvent::from_whoami_request(au, Some(uat.clone()), &qs_read).is_ok());
+            let mut expired = uat;
+            expired.expiry = Some("1970-01-01T00:00:00+00:00".to_string());
+            match SearchEvent::from_whoami_request(au, Some(expired), &qs_read) {
+                Err(OperationError::NotAuthenticated) => {}
+                _ => panic!(),
+            }
+        });
+    }
+
+    #[test]
+    fn test_idm_oauth2_flow() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            {
+                let mut qs_write = qs.write();
+                let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                    r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "account"],
+                            "name": ["oauth2_user"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a01"],
+                            "displayname": ["Oauth2 User"]
+                        }
+                    }"#,
+                    r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "group"],
+                            "name": ["oauth2_users"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a02"],
+                            "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a01"]
+                        }
+                    }"#,
+                    r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "oauth2_resource_server"],
+                            "name": ["test_rs"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a03"],
+                            "oauth2_rs_basic_secret": ["hunter2"],
+                            "oauth2_rs_redirect_uri": ["https://rs.example.com/cb"],
+                            "oauth2_rs_scope_map": [
+                                "cc8e95b4-c24f-4d68-ba54-8bed76f63a02 openid profile"
+                            ]
+                        }
+                    }"#,
+                ]
+                .into_iter()
+                .map(|s| serde_json::from_str(s).expect("json failure"))
+                .collect();
+                assert!(qs_write.internal_create(au, entries).is_ok());
+                qs_write.commit(au).expect("Must not fail");
+            }
+            let uat = UserAuthToken {
+                name: "oauth2_user".to_string(),
+                displayname: "Oauth2 User".to_string(),
+                uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63a01".to_string(),
+                spn: String::new(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                expiry: None,
+            };
+            let authorise = |scope: &str, redirect_uri: &str| {
+                Oauth2AuthoriseEvent::new(uat.clone(), "test_rs", redirect_uri, scope)
+            };
+            let mut idms_write = idms.write();
+
+            // Codes only go to the resource server's redirect uris, and only
+            // for the scopes the account is mapped to.
+            assert!(
+                idms_write.oauth2_authorise(au, &authorise("openid", "https://evil.example.com/"))
+                    == Err(OperationError::Oauth2("invalid_request"))
+            );
+            assert!(
+                idms_write
+                    .oauth2_authorise(au, &authorise("openid admin", "https://rs.example.com/cb"))
+                    == Err(OperationError::Oauth2("invalid_scope"))
+            );
+            let redirect = idms_write
+                .oauth2_authorise(
+                    au,
+                    &authorise("openid profile", "https://rs.example.com/cb"),
+                )
+                .expect("authorise failed");
+            let redirect = Url::parse(redirect.as_str()).expect("url failure");
+            let pairs: Map<String, JsonValue> = redirect
+                .query_pairs()
+                .map(|(k, v)| (k.to_string(), JsonValue::from(v.to_string())))
+                .collect();
+            assert!(pairs["state"] == "teststate");
+            let code = pairs["code"].as_str().expect("no code");
+
+            // The resource server has to prove who it is.
+            let token = |secret: &str| {
+                Oauth2TokenEvent::new(code, "https://rs.example.com/cb", "test_rs", secret)
+            };
+            assert!(
+                idms_write.oauth2_token(au, &token("wrong"))
+                    == Err(OperationError::Oauth2("invalid_client"))
+            );
+            let tr = idms_write
+                .oauth2_token(au, &token("hunter2"))
+                .expect("token failed");
+            assert!(tr.scope == "openid profile");
+            // And may only exchange the code once.
+            assert!(
+                idms_write.oauth2_token(au, &token("hunter2"))
+                    == Err(OperationError::Oauth2("invalid_grant"))
+            );
+            idms_write.commit().expect("Must not fail");
+
+            let id_token = tr.id_token.expect("no id token");
+            let payload = id_token.split('.').nth(1).expect("not a jws");
+            let mut payload = payload.replace('-', "+").replace('_', "/");
+            while payload.len() % 4 != 0 {
+                payload.push('=');
+            }
+            let payload = base64::decode_block(payload.as_str()).expect("base64 failure");
+            let claims: Map<String, JsonValue> =
+                serde_json::from_slice(payload.as_slice()).expect("json failure");
+            assert!(claims["sub"] == "cc8e95b4-c24f-4d68-ba54-8bed76f63a01");
+            assert!(claims["aud"] == "test_rs");
+            assert!(claims["preferred_username"] == "oauth2_user");
+            assert!(claims["name"] == "Oauth2 User");
+            assert!(claims["nonce"] == "testnonce");
+        });
+    }
 }
@@ -1,31 +1,65 @@
 use actix::prelude::*;
 use std::time::Duration;
 
-use crate::constants::PURGE_TIMEOUT;
-use crate::event::{PurgeRecycledEvent, PurgeTombstoneEvent};
+use crate::constants::{PURGE_TIMEOUT, REPL_INTERVAL, SUBSCRIPTION_EXPIRY_INTERVAL};
+use crate::event::{
+    AccountExpiryEvent, DbCheckpointEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
+    ReplConsumeEvent, SubscriptionExpiryEvent,
+};
 use crate::proto::v1::actors::QueryServerV1;
 
 pub struct IntervalActor {
     // Store any addresses we require
     server: actix::Addr<QueryServerV1>,
+    // How long entries stay recycled, then tombstoned, before they are purged.
+    recycle_window: Duration,
+    tombstone_window: Duration,
+    // How often to checkpoint the database, if commits aren't each synced.
+    group_commit: Option<Duration>,
 }
 
 impl IntervalActor {
-    pub fn new(server: actix::Addr<QueryServerV1>) -> Self {
-        IntervalActor { server: server }
+    pub fn new(
+        server: actix::Addr<QueryServerV1>,
+        recycle_window: Duration,
+        tombstone_window: Duration,
+        group_commit: Option<Duration>,
+    ) -> Self {
+        IntervalActor {
+            server: server,
+            recycle_window: recycle_window,
+            tombstone_window: tombstone_window,
+            group_commit: group_commit,
+        }
     }
 
     // Define new events here
     fn purge_tombstones(&mut self) {
         // Make a purge request ...
-        let pe = PurgeTombstoneEvent::new();
+        let pe = PurgeTombstoneEvent::new(self.tombstone_window);
         self.server.do_send(pe)
     }
 
     fn purge_recycled(&mut self) {
-        let pe = PurgeRecycledEvent::new();
+        let pe = PurgeRecycledEvent::new(self.recycle_window);
         self.server.do_send(pe)
     }
+
+    fn flag_expired_accounts(&mut self) {
+        self.server.do_send(AccountExpiryEvent::new())
+    }
+
+    fn expire_subscriptions(&mut self) {
+        self.server.do_send(SubscriptionExpiryEvent::new())
+    }
+
+    fn replicate(&mut self) {
+        self.server.do_send(ReplConsumeEvent::new())
+    }
+
+    fn checkpoint(&mut self) {
+        self.server.do_send(DbCheckpointEvent::new())
+    }
 }
 
 impl Actor for IntervalActor {
@@ -39,5 +73,22 @@ impl Actor for IntervalActor {
         ctx.run_interval(Duration::from_secs(PURGE_TIMEOUT), move |act, _ctx| {
             act.purge_tombstones();
         });
+        ctx.run_interval(Duration::from_secs(PURGE_TIMEOUT), move |act, _ctx| {
+            act.flag_expired_accounts();
+        });
+        ctx.run_interval(
+            Duration::from_secs(SUBSCRIPTION_EXPIRY_INTERVAL),
+            move |act, _ctx| {
+                act.expire_subscriptions();
+            },
+        );
+        ctx.run_interval(Duration::from_secs(REPL_INTERVAL), move |act, _ctx| {
+            act.replicate();
+        });
+        if let Some(interval) = self.group_commit {
+            ctx.run_interval(interval, move |act, _ctx| {
+                act.checkpoint();
+            });
+        }
     }
 }
@@ -0,0 +1,850 @@
+// A read only ldap frontend, so that applications that only speak ldap can
+// bind and search. Binds go through the same auth as /v1/auth, and searches
+// become SearchEvents, so access controls reduce what is returned just as
+// they do for any other search.
+//
+// The directory is flat: each entry is name=<name> directly under a base dn
+// made from the domain name. Writes are refused.
+//
+// Connections are served by a fixed pool of threads, as each only ever has
+// one request outstanding. With a certificate chain and key configured, the
+// listener speaks ldaps; without them, only anonymous binds are accepted, so
+// passwords never cross the network in the clear.
+
+pub(crate) mod proto;
+
+use actix::Addr;
+use futures::Future;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::audit::AuditScope;
+use crate::constants::{LDAP_IDLE_TIMEOUT, LDAP_MAX_CONNECTIONS, LDAP_MAX_PENDING};
+use crate::error::OperationError;
+use crate::event::{AuthEvent, AuthResult, SearchEvent};
+use crate::filter::FilterLimits;
+use crate::idm::server::{IdmServer, IdmServerWriteTransaction};
+use crate::ldap::proto::{
+    read_frame, response_tag, LdapMsg, LdapOp, LdapResult, LdapResultCode, LdapSearchEntry,
+    LdapSearchRequest, LdapSearchScope, Tlv,
+};
+use crate::proto::v1::actors::QueryServerV1;
+use crate::proto::v1::messages::{AuthMessage, LdapRequestMessage, RequestMessage};
+use crate::proto::v1::{AuthCredential, AuthRequest, AuthState, AuthStep, UserAuthToken};
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServer, QueryServerTransaction};
+
+// Filter choices, rfc4511 4.5.1.
+const FILTER_AND: u8 = 0xa0;
+const FILTER_OR: u8 = 0xa1;
+const FILTER_NOT: u8 = 0xa2;
+const FILTER_EQUALITY: u8 = 0xa3;
+const FILTER_SUBSTRINGS: u8 = 0xa4;
+const FILTER_GE: u8 = 0xa5;
+const FILTER_LE: u8 = 0xa6;
+const FILTER_PRESENT: u8 = 0x87;
+const FILTER_APPROX: u8 = 0xa8;
+const SUBSTRING_INITIAL: u8 = 0x80;
+const SUBSTRING_ANY: u8 = 0x81;
+const SUBSTRING_FINAL: u8 = 0x82;
+
+// The responses to one request, and who the connection is bound as after it.
+#[derive(Debug)]
+pub struct LdapResponse {
+    pub msgs: Vec<LdapMsg>,
+    pub uat: Option<UserAuthToken>,
+}
+
+// The base dn, as the dc components of the domain name.
+fn basedn(domain: &str) -> String {
+    domain
+        .to_lowercase()
+        .split('.')
+        .map(|dc| format!("dc={}", dc))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// The name from the dn of one of our entries. A bare name is taken too, as
+// that is what most people type into a bind dn.
+fn dn_to_name(dn: &str, basedn: &str) -> Option<String> {
+    let dn = dn.trim().to_lowercase();
+    let rdn = match dn.find(',') {
+        Some(i) if dn[i + 1..] == *basedn => &dn[..i],
+        Some(_) => return None,
+        None => dn.as_str(),
+    };
+    if rdn.starts_with("name=") {
+        Some(rdn["name=".len()..].to_string())
+    } else if !rdn.is_empty() && !rdn.contains('=') {
+        Some(rdn.to_string())
+    } else {
+        None
+    }
+}
+
+// A value as it's written in a filter string, rfc4515 3.
+fn escape(v: &[u8]) -> String {
+    v.iter()
+        .map(|&b| match b {
+            b'*' | b'(' | b')' | b'\\' => format!("\\{:02x}", b),
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\{:02x}", b),
+        })
+        .collect()
+}
+
+// Anything but a plain attribute description could change the meaning of
+// the filter string it's put in.
+fn filter_attr(t: &Tlv) -> Result<String, OperationError> {
+    let a = t.to_utf8()?;
+    if !a.is_empty()
+        && a.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        Ok(a)
+    } else {
+        Err(OperationError::InvalidFilter(format!(
+            "invalid attribute {:?}",
+            a
+        )))
+    }
+}
+
+// Render a search filter as the rfc4515 string Filter::from_ldap_str takes,
+// held to the same limits as any other filter. Approximate matches are taken
+// as equality, as we have no other meaning for them.
+fn filter_to_str(f: &Tlv, limits: &FilterLimits) -> Result<String, OperationError> {
+    let mut out = String::new();
+    let mut terms = 0;
+    filter_to_str_inner(f, 1, &mut terms, limits, &mut out)?;
+    Ok(out)
+}
+
+fn filter_to_str_inner(
+    f: &Tlv,
+    depth: usize,
+    terms: &mut usize,
+    limits: &FilterLimits,
+    out: &mut String,
+) -> Result<(), OperationError> {
+    *terms += 1;
+    if depth > limits.max_depth || *terms > limits.max_terms {
+        return Err(OperationError::FilterTooComplex);
+    }
+    let bad = || OperationError::InvalidLdapMessage("bad filter");
+    match f.tag {
+        FILTER_AND | FILTER_OR => {
+            out.push_str(if f.tag == FILTER_AND { "(&" } else { "(|" });
+            for c in f.children()? {
+                filter_to_str_inner(&c, depth + 1, terms, limits, out)?;
+            }
+            out.push(')');
+        }
+        FILTER_NOT => {
+            let c = f.children()?;
+            if c.len() != 1 {
+                return Err(bad());
+            }
+            out.push_str("(!");
+            filter_to_str_inner(&c[0], depth + 1, terms, limits, out)?;
+            out.push(')');
+        }
+        FILTER_EQUALITY | FILTER_GE | FILTER_LE | FILTER_APPROX => {
+            let c = f.children()?;
+            if c.len() != 2 {
+                return Err(bad());
+            }
+            let op = match f.tag {
+                FILTER_GE => ">=",
+                FILTER_LE => "<=",
+                _ => "=",
+            };
+            out.push_str(
+                format!("({}{}{})", filter_attr(&c[0])?, op, escape(&c[1].value)).as_str(),
+            );
+        }
+        FILTER_SUBSTRINGS => {
+            let c = f.children()?;
+            if c.len() != 2 {
+                return Err(bad());
+            }
+            let subs = c[1].children()?;
+            if subs.is_empty() {
+                return Err(bad());
+            }
+            let mut initial = String::new();
+            let mut any = String::new();
+            let mut fin = String::new();
+            for s in subs.iter() {
+                match s.tag {
+                    SUBSTRING_INITIAL => initial = escape(&s.value),
+                    SUBSTRING_ANY => {
+                        any.push_str(escape(&s.value).as_str());
+                        any.push('*');
+                    }
+                    SUBSTRING_FINAL => fin = escape(&s.value),
+                    _ => return Err(bad()),
+                }
+            }
+            out.push_str(format!("({}={}*{}{})", filter_attr(&c[0])?, initial, any, fin).as_str());
+        }
+        FILTER_PRESENT => {
+            out.push_str(format!("({}=*)", filter_attr(f)?).as_str());
+        }
+        _ => {
+            return Err(OperationError::InvalidFilter(
+                "extensible matches are not supported".to_string(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn ldap_result(e: OperationError) -> LdapResult {
+    let code = match e {
+        OperationError::NotAuthenticated
+        | OperationError::AccessDenied
+        | OperationError::InvalidAccountState(_)
+        | OperationError::InvalidSessionState => LdapResultCode::InsufficientAccessRights,
+        OperationError::SizeLimitExceeded(_) => LdapResultCode::SizeLimitExceeded,
+        OperationError::InvalidFilter(_) | OperationError::InvalidLdapMessage(_) => {
+            LdapResultCode::ProtocolError
+        }
+        OperationError::FilterTooComplex
+        | OperationError::SubstringNotPermitted
+        | OperationError::RateLimited
+        | OperationError::SchemaViolation(_) => LdapResultCode::UnwillingToPerform,
+        _ => LdapResultCode::Other,
+    };
+    LdapResult::new(code, format!("{:?}", e).as_str())
+}
+
+fn auth_step(
+    au: &mut AuditScope,
+    idm_write: &mut IdmServerWriteTransaction,
+    step: AuthStep,
+    sessionid: Option<Uuid>,
+    source: &Option<String>,
+) -> Result<AuthResult, OperationError> {
+    let ae = AuthEvent::from_message(AuthMessage::new(
+        AuthRequest { step: step },
+        sessionid,
+        source.clone(),
+        None,
+    ))?;
+    idm_write.auth(au, &ae)
+}
+
+// A simple bind is both steps of an auth at once. An empty dn and password
+// binds as anonymous.
+fn bind(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    idms: &IdmServer,
+    source: Option<String>,
+    dn: &str,
+    pw: String,
+    basedn: &str,
+) -> (LdapResult, Option<UserAuthToken>) {
+    let (name, cred) = if dn.is_empty() && pw.is_empty() {
+        ("anonymous".to_string(), AuthCredential::Anonymous)
+    } else if pw.is_empty() {
+        // rfc4513 5.1.2
+        return (
+            LdapResult::new(
+                LdapResultCode::UnwillingToPerform,
+                "unauthenticated binds are not allowed",
+            ),
+            None,
+        );
+    } else {
+        match dn_to_name(dn, basedn) {
+            Some(n) => (n, AuthCredential::Password(pw)),
+            None => {
+                return (
+                    LdapResult::new(LdapResultCode::InvalidCredentials, ""),
+                    None,
+                )
+            }
+        }
+    };
+
+    let mut idm_write = idms.write();
+    let r = auth_step(
+        au,
+        &mut idm_write,
+        AuthStep::Init(name, None),
+        None,
+        &source,
+    )
+    .and_then(|r| match r.state {
+        AuthState::Continue(_) => auth_step(
+            au,
+            &mut idm_write,
+            AuthStep::Creds(vec![cred]),
+            Some(r.sessionid),
+            &source,
+        ),
+        _ => Ok(r),
+    })
+    .and_then(|r| idm_write.commit().map(|_| r));
+
+    match r {
+        Ok(AuthResult {
+            state: AuthState::Success(uat),
+            ..
+        }) => (LdapResult::success(), Some(uat)),
+        // An account that needs more than a password can't use a simple
+        // bind.
+        Ok(AuthResult {
+            state: AuthState::Continue(_),
+            ..
+        }) => (
+            LdapResult::new(
+                LdapResultCode::InvalidCredentials,
+                "more credentials are needed",
+            ),
+            None,
+        ),
+        // Whether the account exists isn't given away.
+        r => {
+            audit_log!(au, "ldap bind failed -> {:?}", r);
+            let source = source.as_ref().map(|s| s.as_str()).unwrap_or("unknown");
+            if let Some(a) = qs.record_auth_failure(source) {
+                audit_log!(au, "security alert: {:?}", a);
+            }
+            (
+                LdapResult::new(LdapResultCode::InvalidCredentials, ""),
+                None,
+            )
+        }
+    }
+}
+
+fn search(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    uat: Option<UserAuthToken>,
+    source: Option<String>,
+    sr: &LdapSearchRequest,
+    basedn: &str,
+) -> Result<Vec<LdapOp>, LdapResult> {
+    let base = sr.base.trim().to_lowercase();
+    // The root dse, rfc4512 5.1, which tells clients where to search.
+    if base.is_empty() && sr.scope == LdapSearchScope::Base {
+        return Ok(vec![LdapOp::SearchResultEntry(LdapSearchEntry {
+            dn: String::new(),
+            attrs: vec![
+                ("objectClass".to_string(), vec!["top".to_string()]),
+                ("namingContexts".to_string(), vec![basedn.to_string()]),
+                ("supportedLDAPVersion".to_string(), vec!["3".to_string()]),
+            ],
+        })]);
+    }
+
+    let qs_read = qs.read();
+    let filter = filter_to_str(&sr.filter, qs_read.get_filter_limits()).map_err(ldap_result)?;
+    // The base dn itself isn't an entry, and entries have nothing below them.
+    let filter = if base == basedn {
+        if sr.scope == LdapSearchScope::Base {
+            return Ok(Vec::new());
+        }
+        filter
+    } else {
+        match dn_to_name(base.as_str(), basedn) {
+            Some(_) if sr.scope == LdapSearchScope::OneLevel => return Ok(Vec::new()),
+            Some(name) => format!("(&(name={}){})", escape(name.as_bytes()), filter),
+            None => return Err(LdapResult::new(LdapResultCode::NoSuchObject, "")),
+        }
+    };
+
+    let size_limit = if sr.size_limit > 0 {
+        Some(sr.size_limit as usize)
+    } else {
+        None
+    };
+    let se = SearchEvent::from_ldap_request(au, uat, filter.as_str(), size_limit, source, &qs_read)
+        .map_err(ldap_result)?;
+    let entries = qs_read.search_ext(au, &se).map_err(ldap_result)?;
+
+    // The attributes asked for, by their name in schema, and the name to
+    // return each as. None is all of them, and 1.1 is none.
+    let schema = qs_read.get_schema();
+    let wanted: Option<Vec<(String, String)>> =
+        if sr.attrs.is_empty() || sr.attrs.iter().any(|a| a == "*") {
+            None
+        } else {
+            Some(
+                sr.attrs
+                    .iter()
+                    .filter(|a| *a != "1.1" && *a != "+")
+                    .map(|a| (schema.normalise_attr_name(a), a.clone()))
+                    .collect(),
+            )
+        };
+
+    // An entry is named by what the caller can read of it. One they can
+    // read neither the name nor uuid of can't be named at all, so is left
+    // out.
+    Ok(entries
+        .iter()
+        .filter_map(|e| {
+            let pe = e.into_pe();
+            let rdn = match (pe.attrs.get("name"), pe.attrs.get("uuid")) {
+                (Some(n), _) if !n.is_empty() => format!("name={}", n[0]),
+                (_, Some(u)) if !u.is_empty() => format!("uuid={}", u[0]),
+                _ => return None,
+            };
+            let attrs = match &wanted {
+                None => pe.attrs.into_iter().collect(),
+                Some(w) => w
+                    .iter()
+                    .filter_map(|(attr, as_name)| {
+                        pe.attrs.get(attr).map(|vs| (as_name.clone(), vs.clone()))
+                    })
+                    .collect(),
+            };
+            Some(LdapOp::SearchResultEntry(LdapSearchEntry {
+                dn: format!("{},{}", rdn, basedn),
+                attrs: attrs,
+            }))
+        })
+        .collect())
+}
+
+pub(crate) fn handle(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    idms: &IdmServer,
+    uat: Option<UserAuthToken>,
+    source: Option<String>,
+    secure: bool,
+    msg: LdapMsg,
+) -> LdapResponse {
+    let basedn = basedn(qs.read().get_domain_info().name.as_str());
+    let msgid = msg.msgid;
+    let reply = |op| LdapMsg::new(msgid, op);
+    match msg.op {
+        LdapOp::SimpleBind { version, dn, pw } => {
+            let (result, uat) = if version != 3 {
+                (
+                    LdapResult::new(LdapResultCode::ProtocolError, "only ldap v3 is supported"),
+                    None,
+                )
+            } else if !secure && !pw.is_empty() {
+                // rfc4513 5.1.3 - the password isn't checked, as it has
+                // already been seen by anyone on the path.
+                (
+                    LdapResult::new(
+                        LdapResultCode::ConfidentialityRequired,
+                        "binds with a password need TLS",
+                    ),
+                    None,
+                )
+            } else {
+                bind(au, qs, idms, source, dn.as_str(), pw, basedn.as_str())
+            };
+            LdapResponse {
+                msgs: vec![reply(LdapOp::BindResponse(result))],
+                uat: uat,
+            }
+        }
+        LdapOp::UnsupportedBind => LdapResponse {
+            msgs: vec![reply(LdapOp::BindResponse(LdapResult::new(
+                LdapResultCode::AuthMethodNotSupported,
+                "only simple binds are supported",
+            )))],
+            uat: None,
+        },
+        LdapOp::SearchRequest(sr) => {
+            let ops = match search(au, qs, uat.clone(), source, &sr, basedn.as_str()) {
+                Ok(mut ops) => {
+                    ops.push(LdapOp::SearchResultDone(LdapResult::success()));
+                    ops
+                }
+                Err(r) => vec![LdapOp::SearchResultDone(r)],
+            };
+            LdapResponse {
+                msgs: ops.into_iter().map(reply).collect(),
+                uat: uat,
+            }
+        }
+        LdapOp::Unsupported(tag) => LdapResponse {
+            msgs: vec![reply(LdapOp::Response(
+                response_tag(tag),
+                LdapResult::new(
+                    LdapResultCode::UnwillingToPerform,
+                    "this server is read only",
+                ),
+            ))],
+            uat: uat,
+        },
+        // Neither an unbind nor an abandon has a response, and responses are
+        // never decoded.
+        _ => LdapResponse {
+            msgs: Vec::new(),
+            uat: uat,
+        },
+    }
+}
+
+pub(crate) fn tls_acceptor(chain: &str, key: &str) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(chain)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+pub(crate) fn start(
+    address: &str,
+    qe: Addr<QueryServerV1>,
+    max_bytes: usize,
+    tls: Option<SslAcceptor>,
+) -> Result<(), io::Error> {
+    let listener = TcpListener::bind(address)?;
+    let (tx, rx) = sync_channel::<TcpStream>(LDAP_MAX_PENDING);
+    let rx = Arc::new(Mutex::new(rx));
+    let tls = Arc::new(tls);
+    for i in 0..LDAP_MAX_CONNECTIONS {
+        let rx = rx.clone();
+        let qe = qe.clone();
+        let tls = tls.clone();
+        thread::Builder::new()
+            .name(format!("ldap_{}", i))
+            .spawn(move || serve_connections(rx, qe, max_bytes, tls))?;
+    }
+    thread::Builder::new()
+        .name("ldap".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream.map(|s| tx.try_send(s)) {
+                    Ok(Ok(())) => {}
+                    // Dropping the connection closes it.
+                    Ok(Err(TrySendError::Full(s))) => {
+                        error!(
+                            "ldap connection from {:?} refused, too many are open",
+                            s.peer_addr()
+                        )
+                    }
+                    Ok(Err(TrySendError::Disconnected(_))) => return,
+                    Err(e) => error!("ldap accept failed -> {:?}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn serve_connections(
+    rx: Arc<Mutex<Receiver<TcpStream>>>,
+    qe: Addr<QueryServerV1>,
+    max_bytes: usize,
+    tls: Arc<Option<SslAcceptor>>,
+) {
+    loop {
+        let stream = match rx.lock().map(|rx| rx.recv()) {
+            Ok(Ok(s)) => s,
+            _ => return,
+        };
+        let source = stream.peer_addr().ok().map(|a| a.ip().to_string());
+        // A connection that goes quiet is closed, rather than holding this
+        // thread forever.
+        let timeout = Some(Duration::from_secs(LDAP_IDLE_TIMEOUT));
+        if let Err(e) = stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            debug!("ldap connection from {:?} closed -> {:?}", source, e);
+            continue;
+        }
+        match tls.as_ref() {
+            Some(acceptor) => match acceptor.accept(stream) {
+                Ok(s) => serve_connection(s, source, true, &qe, max_bytes),
+                Err(e) => debug!("ldap TLS handshake from {:?} failed -> {:?}", source, e),
+            },
+            None => serve_connection(stream, source, false, &qe, max_bytes),
+        }
+    }
+}
+
+fn serve_connection<S: Read + Write>(
+    mut stream: S,
+    source: Option<String>,
+    secure: bool,
+    qe: &Addr<QueryServerV1>,
+    max_bytes: usize,
+) {
+    let mut uat = None;
+    loop {
+        let frame = match read_frame(&mut stream, max_bytes) {
+            Ok(Some(f)) => f,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("ldap connection from {:?} closed -> {:?}", source, e);
+                return;
+            }
+        };
+        let msg = match LdapMsg::decode(frame.as_slice()) {
+            Ok(m) => m,
+            // rfc4511 4.1.1 - say why, and hang up.
+            Err(e) => {
+                let notice = LdapMsg::new(0, LdapOp::NoticeOfDisconnection(ldap_result(e)));
+                let _ = stream.write_all(notice.encode().as_slice());
+                return;
+            }
+        };
+        if msg.op == LdapOp::UnbindRequest {
+            return;
+        }
+        let r = qe
+            .send(RequestMessage::new(
+                Uuid::new_v4(),
+                LdapRequestMessage::new(uat.take(), source.clone(), secure, msg),
+            ))
+            .wait();
+        match r {
+            Ok(Ok(r)) => {
+                uat = r.uat;
+                for m in r.msgs.iter() {
+                    if stream.write_all(m.encode().as_slice()).is_err() {
+                        return;
+                    }
+                }
+            }
+            e => {
+                error!("ldap request failed -> {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::OperationError;
+    use crate::filter::FilterLimits;
+    use crate::ldap::proto::{
+        LdapMsg, LdapOp, LdapResultCode, LdapSearchRequest, LdapSearchScope, Tlv, TAG_OCTETSTRING,
+    };
+    use crate::ldap::{basedn, dn_to_name, filter_to_str, handle, FILTER_PRESENT};
+    use crate::server::QueryServerTransaction;
+
+    fn eq(attr: &str, value: &[u8]) -> Tlv {
+        Tlv::seq(
+            0xa3,
+            vec![
+                Tlv::string(TAG_OCTETSTRING, attr),
+                Tlv::new(TAG_OCTETSTRING, value.to_vec()),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_ldap_filter_to_str() {
+        let limits = FilterLimits::new();
+        assert!(filter_to_str(&eq("name", b"admin"), &limits) == Ok("(name=admin)".to_string()));
+        // Values are escaped, so they can't change the filter.
+        assert!(
+            filter_to_str(&eq("name", b"a*)(b\\\xc3\xa9"), &limits)
+                == Ok("(name=a\\2a\\29\\28b\\5c\\c3\\a9)".to_string())
+        );
+        assert!(filter_to_str(&eq("name)(x", b"a"), &limits).is_err());
+
+        let f = Tlv::seq(
+            0xa0,
+            vec![
+                Tlv::seq(0xa2, vec![Tlv::string(FILTER_PRESENT, "class")]),
+                Tlv::seq(
+                    0xa4,
+                    vec![
+                        Tlv::string(TAG_OCTETSTRING, "name"),
+                        Tlv::seq(
+                            0x30,
+                            vec![
+                                Tlv::string(0x80, "a"),
+                                Tlv::string(0x81, "b"),
+                                Tlv::string(0x82, "c"),
+                            ],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        assert!(filter_to_str(&f, &limits) == Ok("(&(!(class=*))(name=a*b*c))".to_string()));
+        let s = filter_to_str(&f, &limits).expect("filter failure");
+        assert!(crate::filter::Filter::from_ldap_str(s.as_str()).is_ok());
+
+        let small = FilterLimits {
+            max_depth: 2,
+            max_terms: 8,
+        };
+        assert!(filter_to_str(&f, &small) == Err(OperationError::FilterTooComplex));
+        // Extensible matches.
+        assert!(filter_to_str(&Tlv::seq(0xa9, Vec::new()), &limits).is_err());
+    }
+
+    #[test]
+    fn test_ldap_dn() {
+        assert!(basedn("Example.com") == "dc=example,dc=com");
+        let base = "dc=example,dc=com";
+        assert!(dn_to_name("name=admin,dc=example,dc=com", base) == Some("admin".to_string()));
+        assert!(dn_to_name("NAME=Admin, dc=example,dc=com", base) == None);
+        assert!(dn_to_name("admin", base) == Some("admin".to_string()));
+        assert!(dn_to_name("name=admin,dc=other", base) == None);
+        assert!(dn_to_name("cn=admin", base) == None);
+        assert!(dn_to_name("", base) == None);
+    }
+
+    #[test]
+    fn test_ldap_handle() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let base = basedn(qs.read().get_domain_info().name.as_str());
+            let search = |base: &str, scope, filter: Tlv, attrs: Vec<&str>| {
+                LdapMsg::new(
+                    2,
+                    LdapOp::SearchRequest(LdapSearchRequest {
+                        base: base.to_string(),
+                        scope: scope,
+                        size_limit: 0,
+                        filter: filter,
+                        attrs: attrs.iter().map(|a| a.to_string()).collect(),
+                    }),
+                )
+            };
+            let result_code = |m: &LdapMsg| match &m.op {
+                LdapOp::BindResponse(r) | LdapOp::SearchResultDone(r) | LdapOp::Response(_, r) => {
+                    r.code
+                }
+                op => panic!("unexpected op {:?}", op),
+            };
+
+            // A wrong password is refused, without a token.
+            let bind = |dn: &str, pw: &str| {
+                LdapMsg::new(
+                    1,
+                    LdapOp::SimpleBind {
+                        version: 3,
+                        dn: dn.to_string(),
+                        pw: pw.to_string(),
+                    },
+                )
+            };
+            let r = handle(au, qs, idms, None, None, true, bind("name=admin", "wrong"));
+            assert!(r.uat.is_none());
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::InvalidCredentials);
+            let r = handle(au, qs, idms, None, None, true, bind("admin", ""));
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::UnwillingToPerform);
+            // Without TLS the password isn't even checked.
+            let r = handle(au, qs, idms, None, None, false, bind("name=admin", "wrong"));
+            assert!(r.uat.is_none());
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::ConfidentialityRequired);
+
+            // Anonymous can, with or without TLS.
+            let r = handle(au, qs, idms, None, None, false, bind("", ""));
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::Success);
+            let uat = r.uat;
+            assert!(uat.is_some());
+
+            // It can read its own name, under whichever name it asks for.
+            let r = handle(
+                au,
+                qs,
+                idms,
+                uat.clone(),
+                None,
+                true,
+                search(
+                    base.as_str(),
+                    LdapSearchScope::Subtree,
+                    eq("name", b"anonymous"),
+                    vec!["NAME"],
+                ),
+            );
+            assert!(r.msgs.len() == 2);
+            match &r.msgs[0].op {
+                LdapOp::SearchResultEntry(e) => {
+                    assert!(e.dn == format!("name=anonymous,{}", base));
+                    assert!(e.attrs == vec![("NAME".to_string(), vec!["anonymous".to_string()])]);
+                }
+                op => panic!("unexpected op {:?}", op),
+            }
+            assert!(result_code(&r.msgs[1]) == LdapResultCode::Success);
+
+            // And by its dn as the base.
+            let r = handle(
+                au,
+                qs,
+                idms,
+                uat.clone(),
+                None,
+                true,
+                search(
+                    format!("name=anonymous,{}", base).as_str(),
+                    LdapSearchScope::Base,
+                    Tlv::string(FILTER_PRESENT, "name"),
+                    vec!["1.1"],
+                ),
+            );
+            assert!(r.msgs.len() == 2);
+
+            // Bases we don't have.
+            let r = handle(
+                au,
+                qs,
+                idms,
+                uat.clone(),
+                None,
+                true,
+                search(
+                    "dc=other",
+                    LdapSearchScope::Subtree,
+                    eq("name", b"anonymous"),
+                    Vec::new(),
+                ),
+            );
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::NoSuchObject);
+
+            // The root dse.
+            let r = handle(
+                au,
+                qs,
+                idms,
+                None,
+                None,
+                true,
+                search(
+                    "",
+                    LdapSearchScope::Base,
+                    Tlv::string(FILTER_PRESENT, "objectclass"),
+                    Vec::new(),
+                ),
+            );
+            assert!(r.msgs.len() == 2);
+
+            // Writes are refused.
+            let r = handle(
+                au,
+                qs,
+                idms,
+                uat.clone(),
+                None,
+                true,
+                LdapMsg::new(3, LdapOp::Unsupported(0x66)),
+            );
+            assert!(r.uat.is_some());
+            match &r.msgs[0].op {
+                LdapOp::Response(0x67, _) => {}
+                op => panic!("unexpected op {:?}", op),
+            }
+            assert!(result_code(&r.msgs[0]) == LdapResultCode::UnwillingToPerform);
+        });
+    }
+}
@@ -0,0 +1,562 @@
+// The LDAPv3 messages of rfc4511 that the ldap frontend understands, and just
+// enough BER (X.690) to read and write them. LDAP only uses single byte tags
+// and definite lengths, so nothing else is accepted.
+
+use std::io::{self, Read};
+
+use crate::error::OperationError;
+
+pub(crate) const TAG_BOOLEAN: u8 = 0x01;
+pub(crate) const TAG_INTEGER: u8 = 0x02;
+pub(crate) const TAG_OCTETSTRING: u8 = 0x04;
+pub(crate) const TAG_ENUMERATED: u8 = 0x0a;
+pub(crate) const TAG_SEQUENCE: u8 = 0x30;
+pub(crate) const TAG_SET: u8 = 0x31;
+
+// The protocol ops, as [APPLICATION n].
+const OP_BIND_REQUEST: u8 = 0x60;
+const OP_BIND_RESPONSE: u8 = 0x61;
+const OP_UNBIND_REQUEST: u8 = 0x42;
+const OP_SEARCH_REQUEST: u8 = 0x63;
+const OP_SEARCH_RESULT_ENTRY: u8 = 0x64;
+const OP_SEARCH_RESULT_DONE: u8 = 0x65;
+const OP_ABANDON_REQUEST: u8 = 0x50;
+const OP_EXTENDED_RESPONSE: u8 = 0x78;
+
+// The simple choice of AuthenticationChoice, [0].
+const AUTH_SIMPLE: u8 = 0x80;
+// responseName of an extended response, [10].
+const EXTENDED_RESPONSE_NAME: u8 = 0x8a;
+// rfc4511 4.4.1
+const OID_NOTICE_OF_DISCONNECTION: &'static str = "1.3.6.1.4.1.1466.20036";
+
+fn malformed(what: &'static str) -> OperationError {
+    OperationError::InvalidLdapMessage(what)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tlv {
+    pub tag: u8,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    pub fn new(tag: u8, value: Vec<u8>) -> Self {
+        Tlv {
+            tag: tag,
+            value: value,
+        }
+    }
+
+    pub fn seq(tag: u8, children: Vec<Tlv>) -> Self {
+        Tlv::new(tag, children.iter().flat_map(|c| c.encode()).collect())
+    }
+
+    pub fn string(tag: u8, s: &str) -> Self {
+        Tlv::new(tag, s.as_bytes().to_vec())
+    }
+
+    // The shortest two's complement form, X.690 8.3.2.
+    pub fn int(tag: u8, i: i64) -> Self {
+        let bytes = i.to_be_bytes();
+        let mut start = 0;
+        while start < bytes.len() - 1 {
+            let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+                || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+            if !redundant {
+                break;
+            }
+            start += 1;
+        }
+        Tlv::new(tag, bytes[start..].to_vec())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.value.len();
+        let mut out = vec![self.tag];
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            let lb: Vec<u8> = len
+                .to_be_bytes()
+                .iter()
+                .cloned()
+                .skip_while(|b| *b == 0)
+                .collect();
+            out.push(0x80 | lb.len() as u8);
+            out.extend(lb);
+        }
+        out.extend(self.value.iter());
+        out
+    }
+
+    // A tlv and how many bytes of buf it took.
+    pub fn decode(buf: &[u8]) -> Result<(Tlv, usize), OperationError> {
+        if buf.len() < 2 {
+            return Err(malformed("truncated element"));
+        }
+        let tag = buf[0];
+        if tag & 0x1f == 0x1f {
+            return Err(malformed("multi byte tag"));
+        }
+        let (len, start) = if buf[1] & 0x80 == 0 {
+            (buf[1] as usize, 2)
+        } else {
+            let n = (buf[1] & 0x7f) as usize;
+            if n == 0 {
+                return Err(malformed("indefinite length"));
+            }
+            if n > 4 || buf.len() < 2 + n {
+                return Err(malformed("bad length"));
+            }
+            let len = buf[2..2 + n]
+                .iter()
+                .fold(0usize, |a, b| (a << 8) | *b as usize);
+            (len, 2 + n)
+        };
+        if buf.len() - start < len {
+            return Err(malformed("truncated element"));
+        }
+        Ok((Tlv::new(tag, buf[start..start + len].to_vec()), start + len))
+    }
+
+    pub fn children(&self) -> Result<Vec<Tlv>, OperationError> {
+        let mut rest = self.value.as_slice();
+        let mut children = Vec::new();
+        while !rest.is_empty() {
+            let (c, used) = Tlv::decode(rest)?;
+            children.push(c);
+            rest = &rest[used..];
+        }
+        Ok(children)
+    }
+
+    pub fn to_int(&self) -> Result<i64, OperationError> {
+        if self.value.is_empty() || self.value.len() > 8 {
+            return Err(malformed("bad integer"));
+        }
+        let init: i64 = if self.value[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(self
+            .value
+            .iter()
+            .fold(init, |a, b| (a << 8) | i64::from(*b)))
+    }
+
+    pub fn to_utf8(&self) -> Result<String, OperationError> {
+        String::from_utf8(self.value.clone()).map_err(|_| malformed("string is not utf8"))
+    }
+}
+
+// Read one message from the connection, without decoding it. None is a clean
+// close between messages.
+pub(crate) fn read_frame<R: Read>(r: &mut R, max_bytes: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut frame = vec![0u8; 2];
+    if r.read(&mut frame[..1])? == 0 {
+        return Ok(None);
+    }
+    r.read_exact(&mut frame[1..])?;
+    let len = if frame[1] & 0x80 == 0 {
+        frame[1] as usize
+    } else {
+        let n = (frame[1] & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad length"));
+        }
+        let mut lb = vec![0u8; n];
+        r.read_exact(&mut lb)?;
+        frame.extend(lb.iter());
+        lb.iter().fold(0usize, |a, b| (a << 8) | *b as usize)
+    };
+    if len > max_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    let start = frame.len();
+    frame.resize(start + len, 0);
+    r.read_exact(&mut frame[start..])?;
+    Ok(Some(frame))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LdapResultCode {
+    Success = 0,
+    ProtocolError = 2,
+    SizeLimitExceeded = 4,
+    AuthMethodNotSupported = 7,
+    ConfidentialityRequired = 13,
+    NoSuchObject = 32,
+    InvalidCredentials = 49,
+    InsufficientAccessRights = 50,
+    UnwillingToPerform = 53,
+    Other = 80,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapResult {
+    pub code: LdapResultCode,
+    pub message: String,
+}
+
+impl LdapResult {
+    pub fn new(code: LdapResultCode, message: &str) -> Self {
+        LdapResult {
+            code: code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn success() -> Self {
+        LdapResult::new(LdapResultCode::Success, "")
+    }
+
+    // The components of LDAPResult, which every response starts with.
+    fn parts(&self) -> Vec<Tlv> {
+        vec![
+            Tlv::int(TAG_ENUMERATED, self.code as i64),
+            Tlv::string(TAG_OCTETSTRING, ""),
+            Tlv::string(TAG_OCTETSTRING, self.message.as_str()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LdapSearchScope {
+    Base,
+    OneLevel,
+    Subtree,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSearchRequest {
+    pub base: String,
+    pub scope: LdapSearchScope,
+    // 0 is no limit.
+    pub size_limit: i64,
+    // Left as BER, as it is only ever turned into a filter string.
+    pub filter: Tlv,
+    pub attrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSearchEntry {
+    pub dn: String,
+    pub attrs: Vec<(String, Vec<String>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapOp {
+    SimpleBind {
+        version: i64,
+        dn: String,
+        pw: String,
+    },
+    // A sasl bind, which we don't offer.
+    UnsupportedBind,
+    UnbindRequest,
+    SearchRequest(LdapSearchRequest),
+    AbandonRequest,
+    // Any other request, by its tag.
+    Unsupported(u8),
+    BindResponse(LdapResult),
+    SearchResultEntry(LdapSearchEntry),
+    SearchResultDone(LdapResult),
+    NoticeOfDisconnection(LdapResult),
+    // The response to an unsupported request, by its tag.
+    Response(u8, LdapResult),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapMsg {
+    pub msgid: i64,
+    pub op: LdapOp,
+}
+
+impl LdapMsg {
+    pub fn new(msgid: i64, op: LdapOp) -> Self {
+        LdapMsg {
+            msgid: msgid,
+            op: op,
+        }
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, OperationError> {
+        let (tlv, used) = Tlv::decode(frame)?;
+        if used != frame.len() || tlv.tag != TAG_SEQUENCE {
+            return Err(malformed("not an LDAPMessage"));
+        }
+        // Controls follow the op, and are ignored.
+        let mut parts = tlv.children()?.into_iter();
+        let msgid = parts
+            .next()
+            .ok_or_else(|| malformed("no message id"))?
+            .to_int()?;
+        let op = parts.next().ok_or_else(|| malformed("no protocol op"))?;
+
+        let op = match op.tag {
+            OP_BIND_REQUEST => {
+                let mut b = op.children()?.into_iter();
+                let version = b.next().ok_or_else(|| malformed("no version"))?.to_int()?;
+                let dn = b.next().ok_or_else(|| malformed("no bind dn"))?.to_utf8()?;
+                let auth = b.next().ok_or_else(|| malformed("no bind credentials"))?;
+                if auth.tag == AUTH_SIMPLE {
+                    LdapOp::SimpleBind {
+                        version: version,
+                        dn: dn,
+                        pw: auth.to_utf8()?,
+                    }
+                } else {
+                    LdapOp::UnsupportedBind
+                }
+            }
+            OP_UNBIND_REQUEST => LdapOp::UnbindRequest,
+            OP_ABANDON_REQUEST => LdapOp::AbandonRequest,
+            OP_SEARCH_REQUEST => {
+                let mut s = op.children()?.into_iter();
+                let mut next = || {
+                    s.next()
+                        .ok_or_else(|| malformed("truncated search request"))
+                };
+                let base = next()?.to_utf8()?;
+                let scope = match next()?.to_int()? {
+                    0 => LdapSearchScope::Base,
+                    1 => LdapSearchScope::OneLevel,
+                    2 => LdapSearchScope::Subtree,
+                    _ => return Err(malformed("bad search scope")),
+                };
+                // derefAliases - we have no aliases to dereference.
+                next()?;
+                let size_limit = next()?.to_int()?;
+                // timeLimit, and typesOnly - values are always sent.
+                next()?;
+                if next()?.tag != TAG_BOOLEAN {
+                    return Err(malformed("bad typesOnly"));
+                }
+                let filter = next()?;
+                let attrs = next()?
+                    .children()?
+                    .iter()
+                    .map(|a| a.to_utf8())
+                    .collect::<Result<Vec<_>, _>>()?;
+                LdapOp::SearchRequest(LdapSearchRequest {
+                    base: base,
+                    scope: scope,
+                    size_limit: size_limit,
+                    filter: filter,
+                    attrs: attrs,
+                })
+            }
+            t => LdapOp::Unsupported(t),
+        };
+        Ok(LdapMsg::new(msgid, op))
+    }
+
+    // Requests are never encoded - we only ever send responses.
+    pub fn encode(&self) -> Vec<u8> {
+        let op = match &self.op {
+            LdapOp::BindResponse(r) => Tlv::seq(OP_BIND_RESPONSE, r.parts()),
+            LdapOp::SearchResultEntry(e) => Tlv::seq(
+                OP_SEARCH_RESULT_ENTRY,
+                vec![
+                    Tlv::string(TAG_OCTETSTRING, e.dn.as_str()),
+                    Tlv::seq(
+                        TAG_SEQUENCE,
+                        e.attrs
+                            .iter()
+                            .map(|(a, vs)| {
+                                Tlv::seq(
+                                    TAG_SEQUENCE,
+                                    vec![
+                                        Tlv::string(TAG_OCTETSTRING, a.as_str()),
+                                        Tlv::seq(
+                                            TAG_SET,
+                                            vs.iter()
+                                                .map(|v| Tlv::string(TAG_OCTETSTRING, v.as_str()))
+                                                .collect(),
+                                        ),
+                                    ],
+                                )
+                            })
+                            .collect(),
+                    ),
+                ],
+            ),
+            LdapOp::SearchResultDone(r) => Tlv::seq(OP_SEARCH_RESULT_DONE, r.parts()),
+            LdapOp::NoticeOfDisconnection(r) => {
+                let mut parts = r.parts();
+                parts.push(Tlv::string(
+                    EXTENDED_RESPONSE_NAME,
+                    OID_NOTICE_OF_DISCONNECTION,
+                ));
+                Tlv::seq(OP_EXTENDED_RESPONSE, parts)
+            }
+            LdapOp::Response(tag, r) => Tlv::seq(*tag, r.parts()),
+            req => {
+                debug_assert!(false, "ldap request {:?} can't be encoded", req);
+                Tlv::seq(TAG_SEQUENCE, Vec::new())
+            }
+        };
+        Tlv::seq(TAG_SEQUENCE, vec![Tlv::int(TAG_INTEGER, self.msgid), op]).encode()
+    }
+}
+
+// The tag of the response to a request, which is always the next
+// application tag, constructed.
+pub(crate) fn response_tag(request_tag: u8) -> u8 {
+    0x60 | ((request_tag & 0x1f) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ldap::proto::{
+        read_frame, response_tag, LdapMsg, LdapOp, LdapResult, LdapResultCode, LdapSearchEntry,
+        LdapSearchScope, Tlv, TAG_BOOLEAN, TAG_ENUMERATED, TAG_INTEGER, TAG_OCTETSTRING,
+        TAG_SEQUENCE,
+    };
+
+    #[test]
+    fn test_ldap_ber_int() {
+        for (i, bytes) in [
+            (0i64, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x00, 0x80]),
+            (256, vec![0x01, 0x00]),
+            (-1, vec![0xff]),
+            (-129, vec![0xff, 0x7f]),
+        ]
+        .iter()
+        {
+            let t = Tlv::int(TAG_INTEGER, *i);
+            assert!(&t.value == bytes);
+            assert!(t.to_int() == Ok(*i));
+        }
+        // Long form lengths.
+        let t = Tlv::new(TAG_OCTETSTRING, vec![0x61; 300]);
+        let enc = t.encode();
+        assert!(enc[1..4] == [0x82, 0x01, 0x2c]);
+        assert!(Tlv::decode(enc.as_slice()) == Ok((t, 304)));
+        // Truncated.
+        assert!(Tlv::decode(&enc[..100]).is_err());
+    }
+
+    #[test]
+    fn test_ldap_decode_requests() {
+        let bind = Tlv::seq(
+            TAG_SEQUENCE,
+            vec![
+                Tlv::int(TAG_INTEGER, 1),
+                Tlv::seq(
+                    0x60,
+                    vec![
+                        Tlv::int(TAG_INTEGER, 3),
+                        Tlv::string(TAG_OCTETSTRING, "name=admin,dc=localhost"),
+                        Tlv::string(0x80, "password"),
+                    ],
+                ),
+            ],
+        )
+        .encode();
+        let mut r = bind.as_slice();
+        let frame = read_frame(&mut r, 1024)
+            .expect("read failure")
+            .expect("no frame");
+        assert!(
+            LdapMsg::decode(frame.as_slice())
+                == Ok(LdapMsg::new(
+                    1,
+                    LdapOp::SimpleBind {
+                        version: 3,
+                        dn: "name=admin,dc=localhost".to_string(),
+                        pw: "password".to_string(),
+                    }
+                ))
+        );
+        // Then the clean close.
+        assert!(read_frame(&mut r, 1024).expect("read failure").is_none());
+        // And over the limit.
+        assert!(read_frame(&mut bind.as_slice(), 8).is_err());
+
+        let filter = Tlv::string(0x87, "class");
+        let search = Tlv::seq(
+            TAG_SEQUENCE,
+            vec![
+                Tlv::int(TAG_INTEGER, 2),
+                Tlv::seq(
+                    0x63,
+                    vec![
+                        Tlv::string(TAG_OCTETSTRING, "dc=localhost"),
+                        Tlv::int(TAG_ENUMERATED, 2),
+                        Tlv::int(TAG_ENUMERATED, 0),
+                        Tlv::int(TAG_INTEGER, 10),
+                        Tlv::int(TAG_INTEGER, 0),
+                        Tlv::int(TAG_BOOLEAN, 0),
+                        filter.clone(),
+                        Tlv::seq(TAG_SEQUENCE, vec![Tlv::string(TAG_OCTETSTRING, "name")]),
+                    ],
+                ),
+            ],
+        )
+        .encode();
+        match LdapMsg::decode(search.as_slice())
+            .expect("decode failure")
+            .op
+        {
+            LdapOp::SearchRequest(sr) => {
+                assert!(sr.base == "dc=localhost");
+                assert!(sr.scope == LdapSearchScope::Subtree);
+                assert!(sr.size_limit == 10);
+                assert!(sr.filter == filter);
+                assert!(sr.attrs == vec!["name".to_string()]);
+            }
+            op => panic!("unexpected op {:?}", op),
+        }
+
+        // A delete, which is an unsupported write.
+        let del = Tlv::seq(
+            TAG_SEQUENCE,
+            vec![Tlv::int(TAG_INTEGER, 3), Tlv::string(0x4a, "dc=localhost")],
+        )
+        .encode();
+        assert!(LdapMsg::decode(del.as_slice()).map(|m| m.op) == Ok(LdapOp::Unsupported(0x4a)));
+        assert!(response_tag(0x4a) == 0x6b);
+        assert!(response_tag(0x66) == 0x67);
+        // Trailing garbage.
+        let mut bad = del.clone();
+        bad.push(0);
+        assert!(LdapMsg::decode(bad.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_ldap_encode_responses() {
+        let done = LdapMsg::new(
+            2,
+            LdapOp::SearchResultDone(LdapResult::new(LdapResultCode::NoSuchObject, "")),
+        );
+        assert!(
+            done.encode()
+                == vec![
+                    0x30, 0x0c, 0x02, 0x01, 0x02, 0x65, 0x07, 0x0a, 0x01, 0x20, 0x04, 0x00, 0x04,
+                    0x00
+                ]
+        );
+
+        let entry = LdapMsg::new(
+            2,
+            LdapOp::SearchResultEntry(LdapSearchEntry {
+                dn: "name=a".to_string(),
+                attrs: vec![("class".to_string(), vec!["x".to_string(), "y".to_string()])],
+            }),
+        )
+        .encode();
+        let (tlv, _) = Tlv::decode(entry.as_slice()).expect("decode failure");
+        let parts = tlv.children().expect("children failure");
+        assert!(parts[1].tag == 0x64);
+        let e = parts[1].children().expect("children failure");
+        assert!(e[0].to_utf8() == Ok("name=a".to_string()));
+        let attr = &e[1].children().expect("children failure")[0];
+        let attr = attr.children().expect("children failure");
+        assert!(attr[0].to_utf8() == Ok("class".to_string()));
+        assert!(attr[1].children().map(|vs| vs.len()) == Ok(2));
+    }
+}
@@ -1,32 +1,62 @@
 #![deny(warnings)]
 
-#[macro_use]
+// The protocol types are always built. Everything else is the server, and is
+// only built with the "server" feature (on by default), so that the proto
+// module alone can be compiled for targets like wasm32.
+#[cfg_attr(feature = "server", macro_use)]
 extern crate log;
 extern crate serde;
-extern crate serde_cbor;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+extern crate uuid;
+
+#[cfg(feature = "server")]
 extern crate actix;
+#[cfg(feature = "server")]
 extern crate actix_web;
+#[cfg(feature = "server")]
 extern crate futures;
+#[cfg(feature = "server")]
 extern crate r2d2;
+#[cfg(feature = "server")]
 extern crate r2d2_sqlite;
+#[cfg(feature = "server")]
 extern crate rand;
+#[cfg(feature = "server")]
 extern crate rusqlite;
+#[cfg(feature = "server")]
+extern crate serde_cbor;
+#[cfg(feature = "server")]
 extern crate time;
-extern crate uuid;
 
+#[cfg(feature = "server")]
 extern crate bytes;
+#[cfg(feature = "server")]
 extern crate chrono;
+#[cfg(feature = "server")]
 extern crate cookie;
+#[cfg(feature = "server")]
 extern crate env_logger;
+#[cfg(feature = "server")]
+extern crate reqwest;
 
+#[cfg(feature = "server")]
 extern crate regex;
+#[cfg(feature = "server")]
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(feature = "server")]
 extern crate concread;
+#[cfg(feature = "server")]
+extern crate lru;
+#[cfg(feature = "server")]
+extern crate rayon;
+#[cfg(feature = "server")]
+extern crate openssl;
+#[cfg(feature = "server")]
+extern crate url;
 
 // use actix::prelude::*;
 // use actix_web::{
@@ -36,27 +66,75 @@ extern crate concread;
 // use futures::Future;
 
 // This has to be before be so the import order works
+#[cfg(feature = "server")]
 #[macro_use]
 mod macros;
+#[cfg(feature = "server")]
 #[macro_use]
 mod async_log;
+#[cfg(feature = "server")]
 #[macro_use]
 mod audit;
+#[cfg(feature = "server")]
+mod anomaly;
+#[cfg(feature = "server")]
 mod be;
+#[cfg(feature = "server")]
+mod changes;
+#[cfg(feature = "server")]
+mod clock;
 pub mod constants;
+#[cfg(feature = "server")]
+mod csn;
+#[cfg(feature = "server")]
+mod domain;
+#[cfg(feature = "server")]
 mod entry;
+#[cfg(feature = "server")]
 mod event;
+#[cfg(feature = "server")]
 mod filter;
+#[cfg(feature = "server")]
 mod interval;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod migrations;
+#[cfg(feature = "server")]
 mod modify;
+#[cfg(feature = "server")]
 #[macro_use]
 mod plugins;
+#[cfg(feature = "server")]
 mod access;
+#[cfg(feature = "server")]
+#[macro_use]
 mod idm;
+// After idm, as its tests use the idm test macros.
+#[cfg(feature = "server")]
+mod ldap;
+#[cfg(feature = "server")]
+mod ratelimit;
+#[cfg(feature = "server")]
+mod repl;
+#[cfg(feature = "server")]
 mod schema;
+#[cfg(feature = "server")]
+mod scim;
+#[cfg(feature = "server")]
 mod server;
+#[cfg(feature = "server")]
+mod subscriptions;
+#[cfg(feature = "server")]
+mod value;
+#[cfg(feature = "server")]
+mod verify;
 
+#[cfg(feature = "server")]
 pub mod config;
+#[cfg(feature = "server")]
 pub mod core;
 pub mod error;
 pub mod proto;
+#[cfg(feature = "server")]
+pub mod testkit;
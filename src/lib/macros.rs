@@ -82,7 +82,10 @@ macro_rules! filter {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_between, f_endswith, f_eq, f_eq_self, f_ge, f_le, f_or, f_pres,
+            f_self, f_startswith, f_sub,
+        };
         Filter::new_ignore_hidden($fc)
     }};
 }
@@ -97,7 +100,10 @@ macro_rules! filter_rec {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_between, f_endswith, f_eq, f_eq_self, f_ge, f_le, f_or, f_pres,
+            f_self, f_startswith, f_sub,
+        };
         Filter::new_recycled($fc)
     }};
 }
@@ -112,7 +118,10 @@ macro_rules! filter_all {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_between, f_endswith, f_eq, f_eq_self, f_ge, f_le, f_or, f_pres,
+            f_self, f_startswith, f_sub,
+        };
         Filter::new($fc)
     }};
 }
@@ -125,7 +134,10 @@ macro_rules! filter_valid {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_between, f_endswith, f_eq, f_eq_self, f_ge, f_le, f_or, f_pres,
+            f_self, f_startswith, f_sub,
+        };
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
@@ -141,7 +153,10 @@ macro_rules! filter_resolved {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_between, f_endswith, f_eq, f_ge, f_le, f_or, f_pres, f_startswith,
+            f_sub,
+        };
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
@@ -0,0 +1,251 @@
+// Counters of where the time of operations goes, so we can tell whether
+// slowness is in the backend, the access checks, or elsewhere. Like the audit
+// log levels they are global, so any part of the server can record to them
+// without the state being passed through. They are only ever added to, for
+// the life of the process.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    // Reading candidate entries from the backend.
+    BackendRead,
+    // Resolving filters, such as self references, before they're applied.
+    FilterResolve,
+    // Deciding what access controls allow.
+    AccessCheck,
+    // Removing the attributes the caller can't read from search results.
+    Reduce,
+}
+
+const STAGES: [Stage; 4] = [
+    Stage::BackendRead,
+    Stage::FilterResolve,
+    Stage::AccessCheck,
+    Stage::Reduce,
+];
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::BackendRead => "backend_read",
+            Stage::FilterResolve => "filter_resolve",
+            Stage::AccessCheck => "access_check",
+            Stage::Reduce => "reduce",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Counter {
+    // Entries read from the backend, before access checks.
+    EntriesRead,
+    // Entries returned to clients by searches.
+    EntriesReturned,
+    // Lookups of the resolved acp filter cache.
+    FilterCacheHit,
+    FilterCacheMiss,
+}
+
+const COUNTERS: [Counter; 4] = [
+    Counter::EntriesRead,
+    Counter::EntriesReturned,
+    Counter::FilterCacheHit,
+    Counter::FilterCacheMiss,
+];
+
+impl Counter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Counter::EntriesRead => "entries_read",
+            Counter::EntriesReturned => "entries_returned",
+            Counter::FilterCacheHit => "filter_cache_hits",
+            Counter::FilterCacheMiss => "filter_cache_misses",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+// Per stage, how many times it ran, and the total and longest time it took,
+// in microseconds.
+static STAGE_COUNT: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static STAGE_TOTAL_US: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static STAGE_MAX_US: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static COUNTER_VALUES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+pub fn record(stage: Stage, d: Duration) {
+    let us = d.as_secs() as usize * 1_000_000 + d.subsec_micros() as usize;
+    let i = stage.index();
+    STAGE_COUNT[i].fetch_add(1, Ordering::Relaxed);
+    STAGE_TOTAL_US[i].fetch_add(us, Ordering::Relaxed);
+    let mut max = STAGE_MAX_US[i].load(Ordering::Relaxed);
+    while us > max {
+        match STAGE_MAX_US[i].compare_exchange(max, us, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(m) => max = m,
+        }
+    }
+}
+
+// Run f, counting the time it takes against the stage.
+pub fn timed<T, F: FnOnce() -> T>(stage: Stage, f: F) -> T {
+    let start = Instant::now();
+    let r = f();
+    record(stage, start.elapsed());
+    r
+}
+
+pub fn incr(counter: Counter, n: usize) {
+    COUNTER_VALUES[counter.index()].fetch_add(n, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageTiming {
+    pub stage: &'static str,
+    pub count: usize,
+    pub total_us: usize,
+    pub max_us: usize,
+}
+
+// The metrics at one point in time. Each value is read separately, so they
+// may be a moment apart from each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub stages: Vec<StageTiming>,
+    pub counters: Vec<(&'static str, usize)>,
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        stages: STAGES
+            .iter()
+            .map(|s| StageTiming {
+                stage: s.as_str(),
+                count: STAGE_COUNT[s.index()].load(Ordering::Relaxed),
+                total_us: STAGE_TOTAL_US[s.index()].load(Ordering::Relaxed),
+                max_us: STAGE_MAX_US[s.index()].load(Ordering::Relaxed),
+            })
+            .collect(),
+        counters: COUNTERS
+            .iter()
+            .map(|c| {
+                (
+                    c.as_str(),
+                    COUNTER_VALUES[c.index()].load(Ordering::Relaxed),
+                )
+            })
+            .collect(),
+    }
+}
+
+impl Snapshot {
+    // The prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, values: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP rsidm_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE rsidm_{} {}", name, kind);
+            for (labels, v) in values {
+                let _ = writeln!(out, "rsidm_{}{} {}", name, labels, v);
+            }
+        };
+        let by_stage = |f: &dyn Fn(&StageTiming) -> String| {
+            self.stages
+                .iter()
+                .map(|s| (format!("{{stage=\"{}\"}}", s.stage), f(s)))
+                .collect::<Vec<_>>()
+        };
+        let secs = |us: usize| format!("{}", us as f64 / 1_000_000.0);
+
+        family(
+            "stage_seconds_total",
+            "counter",
+            "Time spent in each stage of operations.",
+            by_stage(&|s| secs(s.total_us)),
+        );
+        family(
+            "stage_runs_total",
+            "counter",
+            "Times each stage of operations has run.",
+            by_stage(&|s| s.count.to_string()),
+        );
+        family(
+            "stage_max_seconds",
+            "gauge",
+            "The longest a single run of each stage has taken.",
+            by_stage(&|s| secs(s.max_us)),
+        );
+        for (name, v) in self.counters.iter() {
+            family(
+                format!("{}_total", name).as_str(),
+                "counter",
+                "Counted since the server started.",
+                vec![(String::new(), v.to_string())],
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{snapshot, timed, Snapshot, Stage, StageTiming};
+
+    #[test]
+    fn test_metrics_timed() {
+        // Other tests record to the same counters, so only check they grew.
+        let before = snapshot().stages[Stage::Reduce as usize].count;
+        assert!(timed(Stage::Reduce, || 1 + 1) == 2);
+        let after = snapshot().stages[Stage::Reduce as usize].count;
+        assert!(after > before);
+    }
+
+    #[test]
+    fn test_metrics_formats() {
+        let s = Snapshot {
+            stages: vec![StageTiming {
+                stage: "backend_read",
+                count: 4,
+                total_us: 1_500_000,
+                max_us: 500_000,
+            }],
+            counters: vec![("filter_cache_hits", 3), ("filter_cache_misses", 1)],
+        };
+
+        let p = s.to_prometheus();
+        assert!(p.contains("# TYPE rsidm_stage_seconds_total counter\n"));
+        assert!(p.contains("rsidm_stage_seconds_total{stage=\"backend_read\"} 1.5\n"));
+        assert!(p.contains("rsidm_stage_runs_total{stage=\"backend_read\"} 4\n"));
+        assert!(p.contains("rsidm_stage_max_seconds{stage=\"backend_read\"} 0.5\n"));
+        assert!(p.contains("rsidm_filter_cache_misses_total 1\n"));
+    }
+}
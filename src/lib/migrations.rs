@@ -0,0 +1,289 @@
+// Ordered steps that bring the data in a database up to what this release
+// expects. The backend records the version the data is at, and each step
+// moves it on to its own version. They run at startup in the same write
+// transaction as the schema and builtin entries, so a step that fails leaves
+// the database as it was.
+//
+// To add a step, append it to MIGRATIONS with the next version. Never
+// reorder or remove one - a database only records how far down the list it
+// has got. A step that applies the same change to many entries, such as
+// adding a class to every account, should use internal_modify_filter.
+
+use crate::audit::AuditScope;
+use crate::constants::{UUID_IDM_ADMINS_ACP_STATS_V1, UUID_SYSTEM_STATS};
+use crate::error::OperationError;
+use crate::idm::credential::Password;
+use crate::modify::{Modify, ModifyList};
+use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
+
+type MigrationStep =
+    fn(&mut AuditScope, &mut QueryServerWriteTransaction) -> Result<(), OperationError>;
+
+// (version, what it does, step)
+const MIGRATIONS: [(i64, &'static str, MigrationStep); 5] = [
+    (
+        1,
+        "rewrite entries as DbEntryV3, typing their values by schema",
+        retype_entries,
+    ),
+    (
+        2,
+        "tag ssh public keys, and type them with the SSHKEY syntax",
+        retype_entries,
+    ),
+    (3, "hash the service secrets of hosts", hash_service_secrets),
+    (
+        4,
+        "drop the csn cursors of replication agreements",
+        drop_repl_csns,
+    ),
+    (
+        5,
+        "delete the stats entry, as the metrics are kept in memory",
+        delete_stats_entry,
+    ),
+];
+
+// The data version this release brings databases to.
+pub fn data_version() -> i64 {
+    MIGRATIONS.last().map(|(v, _, _)| *v).unwrap_or(0)
+}
+
+// Run the steps the database hasn't had yet, in order. The schema must
+// already be loaded, as steps may depend on it.
+pub fn run(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    let current = qs_write.get_be_txn().get_data_version();
+    let latest = data_version();
+    audit_log!(
+        audit,
+        "data version {}, this release is {}",
+        current,
+        latest
+    );
+    if current > latest {
+        // Steps can't be undone, so a newer database may hold what we can't
+        // make sense of.
+        audit_log!(
+            audit,
+            "data version {} is newer than this release, refusing to start",
+            current
+        );
+        return Err(OperationError::InvalidDBState);
+    }
+
+    for (version, what, step) in MIGRATIONS.iter().filter(|(v, _, _)| *v > current) {
+        audit_log!(audit, "migration {}: {}", version, what);
+        step(audit, qs_write)?;
+        qs_write.get_be_txn().set_data_version(audit, *version)?;
+    }
+    Ok(())
+}
+
+// Write every entry back with its values typed by the current schema.
+// Entries from before V2 hold untyped strings, and values of an attribute
+// whose syntax has changed are typed for the old one - either way they would
+// otherwise only be typed when next modified. An entry that no longer
+// validates is written back as it is.
+fn retype_entries(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    let schema = qs_write.get_schema();
+    let written = qs_write.get_be_txn().rewrite_entries(audit, |e| {
+        e.clone()
+            .invalidate()
+            .validate(schema)
+            .unwrap_or_else(|_| e)
+    })?;
+    audit_log!(audit, "rewrote {} entries", written);
+    Ok(())
+}
+
+// Service secrets were once stored as they were issued. Any still in that
+// form are replaced with their hash, as rotation now writes them.
+fn hash_service_secrets(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    let hosts = qs_write.internal_search(audit, filter_all!(f_pres("service_secret")))?;
+    let mut hashed = 0;
+    for e in hosts {
+        let secret = match e.get_ava_single("service_secret") {
+            Some(v) => v.to_string(),
+            None => continue,
+        };
+        if Password::from_stored(secret.as_str()).is_some() {
+            continue;
+        }
+        let p = Password::new(secret.as_str())?;
+        qs_write.internal_modify(
+            audit,
+            filter_all!(f_eq("uuid", e.get_uuid().as_str())),
+            ModifyList::new_list(vec![
+                Modify::Purged("service_secret".to_string()),
+                Modify::Present("service_secret".to_string(), Value::from(p.to_string())),
+            ]),
+        )?;
+        hashed += 1;
+    }
+    audit_log!(audit, "hashed {} service secrets", hashed);
+    Ok(())
+}
+
+// Agreements once recorded the csn they had reached, which misses changes a
+// supplier applies from elsewhere. The position in the supplier's changelog
+// replaced it, so they pull everything once to find theirs.
+fn drop_repl_csns(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    let agreements = qs_write.internal_search(audit, filter_all!(f_pres("repl_csn")))?;
+    if agreements.is_empty() {
+        return Ok(());
+    }
+    audit_log!(
+        audit,
+        "dropping the csns of {} agreements",
+        agreements.len()
+    );
+    qs_write.internal_modify(
+        audit,
+        filter_all!(f_pres("repl_csn")),
+        ModifyList::new_list(vec![Modify::Purged("repl_csn".to_string())]),
+    )
+}
+
+// The operation metrics were once written to a replicated entry every
+// minute, which gave every master's changelog a change to send forever, and
+// had masters overwrite each other's. They are per server, so are now only
+// kept in memory and exposed on /metrics.
+fn delete_stats_entry(
+    audit: &mut AuditScope,
+    qs_write: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    let filt = || {
+        filter!(f_or!([
+            f_eq("uuid", UUID_SYSTEM_STATS),
+            f_eq("uuid", UUID_IDM_ADMINS_ACP_STATS_V1)
+        ]))
+    };
+    if qs_write.internal_search(audit, filt())?.is_empty() {
+        return Ok(());
+    }
+    qs_write.internal_delete(audit, filt())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::UUID_SYSTEM_STATS;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::idm::credential::Password;
+    use crate::migrations::{data_version, run};
+    use crate::server::QueryServerTransaction;
+    use crate::value::Value;
+
+    #[test]
+    fn test_migrations_run() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            // A new database is brought straight to the latest version.
+            assert!(server_txn.get_be_txn().get_data_version() == data_version());
+            // Nothing more to do.
+            assert!(run(audit, &mut server_txn).is_ok());
+
+            // A database from before migrations were recorded.
+            assert!(server_txn.get_be_txn().set_data_version(audit, 0).is_ok());
+            assert!(run(audit, &mut server_txn).is_ok());
+            assert!(server_txn.get_be_txn().get_data_version() == data_version());
+
+            // One from a later release.
+            assert!(server_txn
+                .get_be_txn()
+                .set_data_version(audit, data_version() + 1)
+                .is_ok());
+            assert!(run(audit, &mut server_txn) == Err(OperationError::InvalidDBState));
+        });
+    }
+
+    #[test]
+    fn test_migrations_hash_service_secrets() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "account", "host"],
+                    "name": ["mg_host"],
+                    "displayname": ["mg_host"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            // Written as an older release would have.
+            let schema = server_txn.get_schema();
+            assert!(server_txn
+                .get_be_txn()
+                .rewrite_entries(audit, |e| {
+                    if !e.attribute_value_pres("name", "mg_host") {
+                        return e;
+                    }
+                    let mut e = e.clone().invalidate();
+                    e.set_avas("service_secret", vec![Value::from("plain secret")]);
+                    e.validate(schema).expect("invalid entry")
+                })
+                .is_ok());
+            assert!(server_txn.get_be_txn().set_data_version(audit, 2).is_ok());
+            assert!(run(audit, &mut server_txn).is_ok());
+
+            let e = server_txn
+                .internal_search(audit, filter!(f_eq("name", "mg_host")))
+                .expect("search failed")
+                .pop()
+                .expect("no host");
+            let stored = e
+                .get_ava_single("service_secret")
+                .expect("no secret")
+                .to_string();
+            assert!(Password::from_stored(stored.as_str())
+                .expect("not hashed")
+                .verify("plain secret"));
+        });
+    }
+
+    #[test]
+    fn test_migrations_delete_stats_entry() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            // As an older release created it.
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "system_stats"],
+                    "uuid": ["00000000-0000-0000-0000-ffffff000010"],
+                    "description": ["Operation metrics of this server."],
+                    "stat": ["backend_read_count=12"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            assert!(server_txn.get_be_txn().set_data_version(audit, 4).is_ok());
+            assert!(run(audit, &mut server_txn).is_ok());
+
+            assert!(server_txn
+                .internal_search(audit, filter!(f_eq("uuid", UUID_SYSTEM_STATS)))
+                .expect("search failed")
+                .is_empty());
+        });
+    }
+}
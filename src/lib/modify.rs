@@ -1,37 +1,58 @@
 use crate::audit::AuditScope;
 use crate::proto::v1::Modify as ProtoModify;
 use crate::proto::v1::ModifyList as ProtoModifyList;
+use crate::proto::v1::{is_credential_attr, Redacted};
 
 use crate::error::{OperationError, SchemaError};
 use crate::schema::SchemaTransaction;
 use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
 
 // Should this be std?
 use std::slice;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModifyValid;
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModifyInvalid;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Modify {
     // This value *should* exist.
-    Present(String, String),
+    Present(String, Value),
     // This value *should not* exist.
-    Removed(String, String),
+    Removed(String, Value),
     // This attr *should not* exist.
     Purged(String),
 }
 
+// Modlists are logged before the plugins have hashed any credential in them.
+impl std::fmt::Debug for Modify {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (name, a, v) = match self {
+            Modify::Present(a, v) => ("Present", a, v),
+            Modify::Removed(a, v) => ("Removed", a, v),
+            Modify::Purged(a) => return f.debug_tuple("Purged").field(a).finish(),
+        };
+        let mut t = f.debug_tuple(name);
+        t.field(a);
+        if is_credential_attr(a) {
+            t.field(&Redacted);
+        } else {
+            t.field(v);
+        }
+        t.finish()
+    }
+}
+
 #[allow(dead_code)]
 pub fn m_pres(a: &str, v: &str) -> Modify {
-    Modify::Present(a.to_string(), v.to_string())
+    Modify::Present(a.to_string(), Value::from(v))
 }
 
 #[allow(dead_code)]
 pub fn m_remove(a: &str, v: &str) -> Modify {
-    Modify::Removed(a.to_string(), v.to_string())
+    Modify::Removed(a.to_string(), Value::from(v))
 }
 
 #[allow(dead_code)]
@@ -46,14 +67,18 @@ impl Modify {
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match m {
-            ProtoModify::Present(a, v) => Modify::Present(a.clone(), qs.clone_value(audit, a, v)?),
-            ProtoModify::Removed(a, v) => Modify::Removed(a.clone(), qs.clone_value(audit, a, v)?),
+            ProtoModify::Present(a, v) => {
+                Modify::Present(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
+            ProtoModify::Removed(a, v) => {
+                Modify::Removed(a.clone(), Value::from(qs.clone_value(audit, a, v)?))
+            }
             ProtoModify::Purged(a) => Modify::Purged(a.clone()),
         })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModifyList<VALID> {
     valid: VALID,
     // The order of this list matters. Each change must be done in order.
@@ -110,45 +135,36 @@ impl ModifyList<ModifyInvalid> {
 
     pub fn validate(
         &self,
-        schema: &SchemaTransaction,
+        schema: &dyn SchemaTransaction,
     ) -> Result<ModifyList<ModifyValid>, SchemaError> {
         let schema_attributes = schema.get_attributes();
-        let schema_name = schema_attributes
-            .get("name")
-            .expect("Critical: Core schema corrupt or missing. To initiate a core transfer, please deposit substitute core in receptacle.");
 
         let res: Result<Vec<Modify>, _> = (&self.mods)
             .into_iter()
             .map(|m| match m {
                 Modify::Present(attr, value) => {
-                    let attr_norm = schema_name.normalise_value(&attr);
+                    let attr_norm = schema.normalise_attr_name(&attr);
                     match schema_attributes.get(&attr_norm) {
-                        Some(schema_a) => {
-                            let value_norm = schema_a.normalise_value(&value);
-                            schema_a
-                                .validate_value(&value_norm)
-                                .map(|_| Modify::Present(attr_norm, value_norm))
-                        }
-                        None => Err(SchemaError::InvalidAttribute),
+                        Some(schema_a) => schema_a
+                            .to_value(value)
+                            .map(|value_norm| Modify::Present(attr_norm, value_norm)),
+                        None => Err(SchemaError::InvalidAttribute(attr_norm)),
                     }
                 }
                 Modify::Removed(attr, value) => {
-                    let attr_norm = schema_name.normalise_value(&attr);
+                    let attr_norm = schema.normalise_attr_name(&attr);
                     match schema_attributes.get(&attr_norm) {
-                        Some(schema_a) => {
-                            let value_norm = schema_a.normalise_value(&value);
-                            schema_a
-                                .validate_value(&value_norm)
-                                .map(|_| Modify::Removed(attr_norm, value_norm))
-                        }
-                        None => Err(SchemaError::InvalidAttribute),
+                        Some(schema_a) => schema_a
+                            .to_value(value)
+                            .map(|value_norm| Modify::Removed(attr_norm, value_norm)),
+                        None => Err(SchemaError::InvalidAttribute(attr_norm)),
                     }
                 }
                 Modify::Purged(attr) => {
-                    let attr_norm = schema_name.normalise_value(&attr);
+                    let attr_norm = schema.normalise_attr_name(&attr);
                     match schema_attributes.get(&attr_norm) {
                         Some(_attr_name) => Ok(Modify::Purged(attr_norm)),
-                        None => Err(SchemaError::InvalidAttribute),
+                        None => Err(SchemaError::InvalidAttribute(attr_norm)),
                     }
                 }
             })
@@ -166,6 +182,23 @@ impl ModifyList<ModifyInvalid> {
         })
     }
 
+    // As Filter::assume_valid - for changes the server made from values that
+    // are already normalised, and attributes it knows are in schema. Unsafe
+    // for the same reason.
+    pub(crate) unsafe fn assume_valid(
+        self,
+        schema: &dyn SchemaTransaction,
+    ) -> ModifyList<ModifyValid> {
+        debug_assert!(
+            self.validate(schema).map(|ml| ml.mods) == Ok(self.mods.clone()),
+            "assume_valid given a modlist that is not valid"
+        );
+        ModifyList {
+            valid: ModifyValid,
+            mods: self.mods,
+        }
+    }
+
     #[cfg(test)]
     pub unsafe fn to_valid(self) -> ModifyList<ModifyValid> {
         ModifyList {
@@ -193,4 +226,10 @@ impl<VALID> ModifyList<VALID> {
     pub fn len(&self) -> usize {
         self.mods.len()
     }
+
+    // Add other's changes after ours. Both lists are in the same state, so
+    // the result is too.
+    pub fn append(&mut self, other: ModifyList<VALID>) {
+        self.mods.extend(other.mods)
+    }
 }
@@ -0,0 +1,174 @@
+// Require human context on access control profiles.
+//
+// When the server is configured to require it, every access control profile
+// that is created or modified must be left with a description explaining what
+// it is for. acp_ticket_ref can additionally record the change request that
+// asked for it. Both are returned with effective permissions, so whoever is
+// looking at why someone has access can see why the profile exists.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::error::OperationError;
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::server::QueryServerWriteTransaction;
+
+pub struct AcpMetadata {}
+
+fn check_description<VALID: std::fmt::Debug, STATE: std::fmt::Debug>(
+    au: &mut AuditScope,
+    cand: &Entry<VALID, STATE>,
+) -> Result<(), OperationError> {
+    if !cand.attribute_value_pres("class", "access_control_profile") {
+        return Ok(());
+    }
+    let described = match cand.get_ava("description") {
+        Some(vs) => vs.iter().any(|v| !v.to_string().trim().is_empty()),
+        None => false,
+    };
+    if described {
+        Ok(())
+    } else {
+        audit_log!(au, "access control profile has no description: {:?}", cand);
+        Err(OperationError::InvalidACPState("Missing description"))
+    }
+}
+
+impl Plugin for AcpMetadata {
+    fn id() -> &'static str {
+        "plugin_acp_metadata"
+    }
+
+    fn pre_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        if !qs.get_acp_require_metadata() {
+            return Ok(());
+        }
+        cand.iter().try_for_each(|e| check_description(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        if !qs.get_acp_require_metadata() {
+            return Ok(());
+        }
+        cand.iter().try_for_each(|e| check_description(au, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::event::{CreateEvent, ModifyEvent};
+    use crate::modify::{Modify, ModifyList};
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+    use crate::value::Value;
+
+    static JSON_ACP_UNDESCRIBED: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "access_control_profile", "access_control_delete"],
+            "name": ["acp_undescribed"],
+            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"name\",\"a\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Eq\":[\"name\",\"a\"]}"
+            ]
+        }
+    }"#;
+
+    fn setup_qs(au: &mut AuditScope, require: bool) -> QueryServer {
+        let be = Backend::new(au, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(au).expect("Failed to init schema");
+        let mut qs = QueryServer::new(be, schema);
+        qs.set_acp_require_metadata(require);
+        qs.initialise_helper(au).expect("init failed!");
+        qs
+    }
+
+    #[test]
+    fn test_acp_metadata_create() {
+        let mut au = AuditScope::new("test_acp_metadata_create");
+        let e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ACP_UNDESCRIBED).expect("json parse failure");
+
+        // Not required, so anything goes.
+        let qs = setup_qs(&mut au, false);
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(vec![e.clone()]);
+        assert!(qs_write.create(&mut au, &ce).is_ok());
+
+        let qs = setup_qs(&mut au, true);
+        let mut qs_write = qs.write();
+        assert!(
+            qs_write.create(&mut au, &ce)
+                == Err(OperationError::InvalidACPState("Missing description"))
+        );
+
+        let mut e_desc = e.clone();
+        e_desc.add_ava("description", "Allow a to delete itself");
+        e_desc.add_ava("acp_ticket_ref", "CHG-1234");
+        let ce = CreateEvent::new_internal(vec![e_desc]);
+        assert!(qs_write.create(&mut au, &ce).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        println!("{}", au);
+    }
+
+    #[test]
+    fn test_acp_metadata_modify() {
+        let mut au = AuditScope::new("test_acp_metadata_modify");
+        let mut e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ACP_UNDESCRIBED).expect("json parse failure");
+        e.add_ava("description", "Allow a to delete itself");
+
+        let qs = setup_qs(&mut au, true);
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(vec![e]);
+        assert!(qs_write.create(&mut au, &ce).is_ok());
+
+        // The description can't be taken away ...
+        let me_purge = unsafe {
+            ModifyEvent::new_internal_invalid(
+                filter!(f_eq("name", "acp_undescribed")),
+                ModifyList::new_list(vec![Modify::Purged(String::from("description"))]),
+            )
+        };
+        assert!(
+            qs_write.modify(&mut au, &me_purge)
+                == Err(OperationError::InvalidACPState("Missing description"))
+        );
+
+        // ... but can be replaced.
+        let me_replace = unsafe {
+            ModifyEvent::new_internal_invalid(
+                filter!(f_eq("name", "acp_undescribed")),
+                ModifyList::new_list(vec![
+                    Modify::Purged(String::from("description")),
+                    Modify::Present(
+                        String::from("description"),
+                        Value::from("Allow a to delete itself, see CHG-1235"),
+                    ),
+                ]),
+            )
+        };
+        assert!(qs_write.modify(&mut au, &me_replace).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        println!("{}", au);
+    }
+}
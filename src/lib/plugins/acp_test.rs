@@ -0,0 +1,202 @@
+// Test cases carried on access control profiles.
+//
+// An access control profile can record examples of what it is meant to allow
+// and to deny, in acp_test_allow and acp_test_deny. Each example is json
+// naming who is asking, what they want to do, and to which entry:
+//
+//     {"identity": "<uuid>", "op": "modify", "attr": "mail", "target": "<uuid>"}
+//
+// op is one of search, modify or delete. For search and modify, attr is the
+// attribute to read or change - without it, any attribute will do. Verify
+// checks every example against all the access controls in force, not only
+// the profile it's on, so the examples keep documenting what the server
+// actually does as other profiles change around them.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::error::{ConsistencyError, OperationError};
+use crate::event::{EffectivePermissionsEvent, Event};
+use crate::server::{QueryServerReadTransaction, QueryServerTransaction};
+
+pub struct AcpTest {}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AcpTestOp {
+    Search,
+    Modify,
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+struct AcpTestCase {
+    identity: String,
+    op: AcpTestOp,
+    #[serde(default)]
+    attr: Option<String>,
+    target: String,
+}
+
+// Would the access controls in force allow this?
+fn allowed(
+    au: &mut AuditScope,
+    qs: &QueryServerReadTransaction,
+    case: &AcpTestCase,
+) -> Result<bool, OperationError> {
+    let epe = EffectivePermissionsEvent {
+        event: Event::from_ro_request(au, qs, case.identity.as_str())?,
+        target_uuid: case.target.clone(),
+    };
+    let ep = qs.effective_permissions(au, &epe)?;
+    let granted = |attrs: &Vec<String>| match &case.attr {
+        Some(a) => attrs.contains(a),
+        None => !attrs.is_empty(),
+    };
+    Ok(match case.op {
+        AcpTestOp::Search => granted(&ep.search),
+        AcpTestOp::Modify => granted(&ep.modify_present),
+        AcpTestOp::Delete => ep.delete,
+    })
+}
+
+impl Plugin for AcpTest {
+    fn id() -> &'static str {
+        "plugin_acp_test"
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let filt = filter!(f_or!([f_pres("acp_test_allow"), f_pres("acp_test_deny")]));
+        let acps = match qs.internal_search(au, filt) {
+            Ok(acps) => acps,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+
+        let mut r = Vec::new();
+        for acp in acps.iter() {
+            for (attr, expect) in &[("acp_test_allow", true), ("acp_test_deny", false)] {
+                let cases = match acp.get_ava(attr) {
+                    Some(cases) => cases,
+                    None => continue,
+                };
+                for case in cases {
+                    let case = case.to_string();
+                    // A case that can't be read or run fails, as it can't be
+                    // showing what was intended.
+                    let res = serde_json::from_str::<AcpTestCase>(case.as_str())
+                        .map_err(|_| OperationError::SerdeJsonError)
+                        .and_then(|c| allowed(au, qs, &c));
+                    match res {
+                        Ok(a) if a == *expect => {}
+                        res => {
+                            audit_log!(
+                                au,
+                                "{:?} {} {} did not hold -> {:?}",
+                                acp.get_uuid(),
+                                attr,
+                                case,
+                                res
+                            );
+                            r.push(Err(ConsistencyError::AcpTestFailed(
+                                acp.get_uuid().clone(),
+                                case,
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::ConsistencyError;
+    use crate::modify::{Modify, ModifyList};
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+    use crate::value::Value;
+
+    static JSON_TESTPERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person"],
+            "name": ["testperson1"],
+            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+            "description": ["testperson"],
+            "displayname": ["testperson1"]
+        }
+    }"#;
+
+    // testperson1 may read its own name. It should not be able to delete
+    // itself, or read its mail.
+    static JSON_ACP_SELF_NAME: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "access_control_profile", "access_control_search"],
+            "name": ["acp_self_name"],
+            "uuid": ["e15b7a24-f2d3-4c5e-8d9a-0b6c3f1a2e47"],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"name\",\"testperson1\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Eq\":[\"name\",\"testperson1\"]}"
+            ],
+            "acp_search_attr": ["name"],
+            "acp_test_allow": [
+                "{\"identity\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\",\"op\":\"search\",\"attr\":\"name\",\"target\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\"}"
+            ],
+            "acp_test_deny": [
+                "{\"identity\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\",\"op\":\"delete\",\"target\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\"}",
+                "{\"identity\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\",\"op\":\"search\",\"attr\":\"mail\",\"target\":\"cc8e95b4-c24f-4d68-ba54-8bed76f63930\"}"
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn test_acp_test_verify() {
+        let mut au = AuditScope::new("test_acp_test_verify");
+        let ep: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON).expect("json parse failure");
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ACP_SELF_NAME).expect("json parse failure");
+        let preload = vec![ep, ea];
+        let qs = setup_test!(&mut au, preload);
+
+        // Everything the profile says holds.
+        assert!(qs.verify(&mut au).len() == 0);
+
+        // A case claiming something the policy doesn't do is reported.
+        let bad_case = r#"{"identity":"cc8e95b4-c24f-4d68-ba54-8bed76f63930","op":"search","attr":"name","target":"cc8e95b4-c24f-4d68-ba54-8bed76f63930"}"#;
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_modify(
+                &mut au,
+                filter!(f_eq("name", "acp_self_name")),
+                ModifyList::new_list(vec![Modify::Present(
+                    "acp_test_deny".to_string(),
+                    Value::from(bad_case),
+                )]),
+            )
+            .is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+
+        let r = qs.verify(&mut au);
+        assert!(
+            r == vec![Err(ConsistencyError::AcpTestFailed(
+                "e15b7a24-f2d3-4c5e-8d9a-0b6c3f1a2e47".to_string(),
+                bad_case.to_string(),
+            ))]
+        );
+        println!("{}", au);
+    }
+}
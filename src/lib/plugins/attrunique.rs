@@ -0,0 +1,298 @@
+// Attribute uniqueness.
+//
+// Attributes marked unique in schema, such as name, may only hold a given
+// value on one entry at a time. Without this two accounts created at the same
+// time could end up with the same name.
+//
+// This runs after the candidates are written to the transaction, so a search
+// for each new value finds every holder of it - including other entries in
+// the same operation. Recycled entries are not counted, but reviving one is a
+// modify, so a conflict is caught then.
+//
+// Attribute and class definitions are named in their own namespaces: schema
+// keeps them apart, so an attribute and a class may share a name, but two
+// attributes may not.
+use crate::plugins::Plugin;
+use std::collections::BTreeMap;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
+use crate::error::{ConsistencyError, OperationError};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::filter::{f_andnot, f_eq, f_or, FC};
+use crate::schema::SchemaTransaction;
+use crate::server::{
+    QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
+};
+use crate::value::Value;
+
+pub struct AttrUnique {}
+
+fn namespace<VALID, STATE>(e: &Entry<VALID, STATE>) -> &'static str {
+    if e.attribute_value_pres("class", "attributetype") {
+        "attributetype"
+    } else if e.attribute_value_pres("class", "classtype") {
+        "classtype"
+    } else {
+        ""
+    }
+}
+
+fn namespace_filter(ns: &'static str) -> FC<'static> {
+    match ns {
+        "" => f_andnot(f_or(vec![
+            f_eq("class", "attributetype"),
+            f_eq("class", "classtype"),
+        ])),
+        ns => f_eq("class", ns),
+    }
+}
+
+fn unique_attrs<T: QueryServerTransaction>(qs: &T) -> Vec<String> {
+    qs.get_schema()
+        .get_unique_types()
+        .iter()
+        .map(|sa| sa.name.clone())
+        .collect()
+}
+
+fn check_value<STATE>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    e: &Entry<EntryValid, STATE>,
+    attr: &str,
+    v: &Value,
+) -> Result<(), OperationError> {
+    let value = v.to_string();
+    let filt = filter!(f_and(vec![
+        f_eq(attr, value.as_str()),
+        f_andnot(f_eq("uuid", e.get_uuid().as_str())),
+        namespace_filter(namespace(e)),
+    ]));
+    let holders = try_audit!(au, qs.internal_search(au, filt));
+    match holders.first() {
+        Some(h) => {
+            audit_log!(
+                au,
+                "{} {} of {:?} is already held by {:?}",
+                attr,
+                value,
+                e.get_uuid(),
+                h.get_uuid()
+            );
+            Err(OperationError::Duplicate(h.get_uuid().clone()))
+        }
+        None => Ok(()),
+    }
+}
+
+impl Plugin for AttrUnique {
+    fn id() -> &'static str {
+        "plugin_attrunique"
+    }
+
+    fn post_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        let attrs = unique_attrs(&*qs);
+        for e in cand.iter() {
+            for attr in attrs.iter() {
+                if let Some(vs) = e.get_ava(attr.as_str()) {
+                    for v in vs.iter() {
+                        check_value(au, qs, e, attr.as_str(), v)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn post_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        let attrs = unique_attrs(&*qs);
+        for (pre, post) in pre_cand.iter().zip(cand.iter()) {
+            // A revived entry's values were not being held while it was
+            // recycled, so they all need checking.
+            let revived = pre.attribute_value_pres("class", "recycled")
+                && !post.attribute_value_pres("class", "recycled");
+            for attr in attrs.iter() {
+                if let Some(vs) = post.get_ava(attr.as_str()) {
+                    for v in vs.iter() {
+                        let unchanged = match pre.get_ava(attr.as_str()) {
+                            Some(pvs) => pvs.contains(v),
+                            None => false,
+                        };
+                        if revived || !unchanged {
+                            check_value(au, qs, post, attr.as_str(), v)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let mut r = Vec::new();
+        for attr in unique_attrs(qs) {
+            let entries = match qs.internal_search(au, filter!(f_pres(attr.as_str()))) {
+                Ok(entries) => entries,
+                Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+            };
+            let mut seen: BTreeMap<(&str, String), &String> = BTreeMap::new();
+            for e in entries.iter() {
+                let vs = match e.get_ava(attr.as_str()) {
+                    Some(vs) => vs,
+                    None => continue,
+                };
+                for v in vs.iter() {
+                    if let Some(other) = seen.insert((namespace(e), v.to_string()), e.get_uuid()) {
+                        audit_log!(
+                            au,
+                            "{} {} is held by {:?} and {:?}",
+                            attr,
+                            v,
+                            other,
+                            e.get_uuid()
+                        );
+                        r.push(Err(ConsistencyError::AttrNotUnique(
+                            attr.clone(),
+                            v.to_string(),
+                        )));
+                    }
+                }
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::modify::{Modify, ModifyList};
+    use crate::value::Value;
+
+    static JSON_TESTPERSON1: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person"],
+            "name": ["testperson1"],
+            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+            "description": ["testperson"],
+            "displayname": ["testperson1"],
+            "mail": ["testperson@example.com"]
+        }
+    }"#;
+
+    static JSON_TESTPERSON2: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person"],
+            "name": ["testperson2"],
+            "uuid": ["538faac7-4d29-473b-a59d-23023ac19955"],
+            "description": ["testperson"],
+            "displayname": ["testperson2"]
+        }
+    }"#;
+
+    #[test]
+    fn test_attrunique_create() {
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json parse failure");
+        let mut eb: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON2).expect("json parse failure");
+        // Names are case insensitive, so this is the same name.
+        eb.set_avas("name", vec![Value::from("TestPerson1")]);
+
+        let preload = vec![ea];
+        let create = vec![eb];
+        run_create_test!(
+            Err(OperationError::Duplicate(
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()
+            )),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_attrunique_create_same_op() {
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json parse failure");
+        let mut eb: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON2).expect("json parse failure");
+        eb.add_ava("mail", "testperson@example.com");
+
+        let preload = Vec::new();
+        let create = vec![ea, eb];
+        run_create_test!(
+            Err(OperationError::Duplicate(
+                "538faac7-4d29-473b-a59d-23023ac19955".to_string()
+            )),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_attrunique_modify() {
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json parse failure");
+        let eb: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON2).expect("json parse failure");
+
+        let preload = vec![ea, eb];
+        run_modify_test!(
+            Err(OperationError::Duplicate(
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()
+            )),
+            preload,
+            filter!(f_eq("name", "testperson2")),
+            ModifyList::new_list(vec![Modify::Present(
+                "mail".to_string(),
+                Value::from("testperson@example.com")
+            )]),
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_attrunique_modify_unchanged() {
+        // Changing something else on an entry doesn't trip over its own name.
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_TESTPERSON1).expect("json parse failure");
+
+        let preload = vec![ea];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", "testperson1")),
+            ModifyList::new_list(vec![Modify::Present(
+                "mail".to_string(),
+                Value::from("testperson1@example.com")
+            )]),
+            None,
+            |_, _| {}
+        );
+    }
+}
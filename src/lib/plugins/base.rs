@@ -1,5 +1,6 @@
 use crate::plugins::Plugin;
 use std::collections::BTreeSet;
+use std::ops::Bound;
 use uuid::Uuid;
 
 use crate::audit::AuditScope;
@@ -11,6 +12,7 @@ use crate::modify::Modify;
 use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
+use crate::value::Value;
 
 // This module has some special properties around it's operation, namely that it
 // has to make a certain number of assertions *early* in the entry lifecycle around
@@ -69,21 +71,23 @@ impl Plugin for Base {
                     // a mistake and your intent is unknown.
                     try_audit!(
                         au,
-                        u.first().ok_or(OperationError::Plugin).map(|v| v.clone())
+                        u.first()
+                            .ok_or(OperationError::Plugin)
+                            .map(|v| v.to_string())
                     )
                 }
                 None => Uuid::new_v4().to_hyphenated().to_string(),
             };
 
             audit_log!(au, "Setting temporary UUID {} to entry", c_uuid);
-            let ava_uuid: Vec<String> = vec![c_uuid];
+            let ava_uuid: Vec<Value> = vec![Value::from(c_uuid)];
 
             entry.set_avas("uuid", ava_uuid);
             audit_log!(au, "Temporary entry state: {:?}", entry);
         }
 
         // Now, every cand has a UUID - create a cand uuid set from it.
-        let mut cand_uuid: BTreeSet<&str> = BTreeSet::new();
+        let mut cand_uuid: BTreeSet<String> = BTreeSet::new();
 
         // As we insert into the set, if a duplicate is found, return an error
         // that a duplicate exists.
@@ -94,7 +98,7 @@ impl Plugin for Base {
                 .first()
                 .ok_or(OperationError::Plugin)?;
             audit_log!(au, "Entry valid UUID: {:?}", entry);
-            match cand_uuid.insert(uuid_ref.as_str()) {
+            match cand_uuid.insert(uuid_ref.to_string()) {
                 false => {
                     audit_log!(au, "uuid duplicate found in create set! {:?}", uuid_ref);
                     return Err(OperationError::Plugin);
@@ -110,7 +114,9 @@ impl Plugin for Base {
             // Sadly we need to allocate these to strings to make references, sigh.
             // let uuid_admin: String = UUID_ADMIN.to_string();
             // let uuid_anon: String = UUID_ANONYMOUS.to_string();
-            let overlap: usize = cand_uuid.range(UUID_ADMIN..UUID_ANONYMOUS).count();
+            let overlap: usize = cand_uuid
+                .range::<str, _>((Bound::Included(UUID_ADMIN), Bound::Excluded(UUID_ANONYMOUS)))
+                .count();
             if overlap != 0 {
                 audit_log!(
                     au,
@@ -252,6 +258,7 @@ mod tests {
     use crate::modify::{Modify, ModifyList};
     use crate::server::QueryServerTransaction;
     use crate::server::QueryServerWriteTransaction;
+    use crate::value::Value;
 
     static JSON_ADMIN_ALLOW_ALL: &'static str = r#"{
         "valid": null,
@@ -558,7 +565,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_a")),
             ModifyList::new_list(vec![Modify::Present(
                 "uuid".to_string(),
-                "f15a7219-1d15-44e3-a7b4-bec899c07788".to_string()
+                Value::from("f15a7219-1d15-44e3-a7b4-bec899c07788")
             )]),
             None,
             |_, _| {}
@@ -590,7 +597,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_a")),
             ModifyList::new_list(vec![Modify::Removed(
                 "uuid".to_string(),
-                "f15a7219-1d15-44e3-a7b4-bec899c07788".to_string()
+                Value::from("f15a7219-1d15-44e3-a7b4-bec899c07788")
             )]),
             None,
             |_, _| {}
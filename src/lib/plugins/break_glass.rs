@@ -0,0 +1,164 @@
+// Keep break glass secrets in the hands of the server.
+//
+// A break glass secret is accepted in place of an account's usual
+// credentials, so only QueryServerWriteTransaction::break_glass may set one,
+// on the server host. Clients can never write the secret or its expiry, even
+// where an access control profile would allow it - otherwise whoever may
+// modify an account could give it a secret of their own, that outlives what
+// the server would issue.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::error::OperationError;
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::modify::Modify;
+use crate::server::QueryServerWriteTransaction;
+
+pub struct BreakGlass {}
+
+static BREAK_GLASS_ATTRS: [&'static str; 2] = ["break_glass_secret", "break_glass_expiry"];
+
+fn refuse(au: &mut AuditScope, attr: &str) -> Result<(), OperationError> {
+    audit_log!(au, "{} may only be set by the server", attr);
+    Err(OperationError::AccessDenied)
+}
+
+impl Plugin for BreakGlass {
+    fn id() -> &'static str {
+        "plugin_break_glass"
+    }
+
+    fn pre_create(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        if ce.event.is_internal() {
+            return Ok(());
+        }
+        match BREAK_GLASS_ATTRS
+            .iter()
+            .find(|a| cand.iter().any(|e| e.attribute_pres(a)))
+        {
+            Some(a) => refuse(au, a),
+            None => Ok(()),
+        }
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        _cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        if me.event.is_internal() {
+            return Ok(());
+        }
+        // Removing one is refused too, as the server relies on purging both
+        // together.
+        match me.modlist.iter().find_map(|m| {
+            let a = match m {
+                Modify::Present(a, _) | Modify::Removed(a, _) | Modify::Purged(a) => a,
+            };
+            BREAK_GLASS_ATTRS.iter().find(|b| *b == a)
+        }) {
+            Some(a) => refuse(au, a),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::JSON_ADMIN_V1;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+
+    // Would allow anything to admin, but for this plugin.
+    static JSON_ADMIN_ALLOW_BREAK_GLASS: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_modify",
+                "access_control_create",
+                "access_control_search"
+            ],
+            "name": ["idm_admins_acp_break_glass_test"],
+            "uuid": ["4f5e3f0a-6a7b-4c1e-9a8d-2b3c4d5e6f70"],
+            "description": ["Builtin IDM Administrators Access Controls."],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Pres\":\"class\"}"
+            ],
+            "acp_search_attr": ["name", "class", "uuid"],
+            "acp_modify_removedattr": ["break_glass_secret", "break_glass_expiry"],
+            "acp_modify_presentattr": ["break_glass_secret", "break_glass_expiry"],
+            "acp_create_class": ["object", "account"],
+            "acp_create_attr": [
+                "name",
+                "class",
+                "displayname",
+                "break_glass_secret",
+                "break_glass_expiry"
+            ]
+        }
+    }"#;
+
+    static JSON_ACCOUNT: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "account"],
+            "name": ["testaccount"],
+            "displayname": ["Test Account"],
+            "break_glass_expiry": ["2038-01-01T00:00:00+00:00"]
+        }
+    }"#;
+
+    #[test]
+    fn test_break_glass_create_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ADMIN_ALLOW_BREAK_GLASS).expect("json parse failure");
+        let e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ACCOUNT).expect("json parse failure");
+
+        let preload = vec![acp];
+        let create = vec![e];
+
+        run_create_test!(
+            Err(OperationError::AccessDenied),
+            preload,
+            create,
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_break_glass_modify_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ADMIN_ALLOW_BREAK_GLASS).expect("json parse failure");
+        let mut e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ACCOUNT).expect("json parse failure");
+        e.purge_ava("break_glass_expiry");
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::AccessDenied),
+            preload,
+            filter!(f_eq("name", "testaccount")),
+            modlist!([m_pres("break_glass_expiry", "2038-01-01T00:00:00+00:00")]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+}
@@ -0,0 +1,432 @@
+// Dynamic Groups
+//
+// A dyngroup is a group whose members are the entries its dyngroup_filter
+// matches, so a group like "every account in engineering" stays current
+// without anything outside having to sync it.
+//
+// The member set of a dyngroup is recomputed whenever the dyngroup itself is
+// written, so a changed filter takes effect at once and members can't be
+// added or removed by hand. When any other entry changes, each dyngroup's
+// filter is checked against it, and the dyngroups it has joined or left are
+// modified - which recomputes them. memberof then follows as for any group.
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::error::{ConsistencyError, OperationError};
+use crate::event::{CreateEvent, Event, ModifyEvent};
+use crate::filter::{Filter, FilterInvalid, FilterValid};
+use crate::modify::{Modify, ModifyList, ModifyValid};
+use crate::plugins::Plugin;
+use crate::proto::v1::Filter as ProtoFilter;
+use crate::schema::SchemaTransaction;
+use crate::server::QueryServerTransaction;
+use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
+
+use std::collections::BTreeSet;
+
+pub struct DynGroup;
+
+fn proto_filter<VALID, STATE>(e: &Entry<VALID, STATE>) -> Result<ProtoFilter, OperationError> {
+    let raw = e
+        .get_ava_single("dyngroup_filter")
+        .ok_or(OperationError::InvalidDynGroupFilter(
+            "Missing dyngroup_filter",
+        ))?;
+    serde_json::from_str(raw.to_string().as_str())
+        .map_err(|_| OperationError::InvalidDynGroupFilter("Invalid dyngroup_filter"))
+}
+
+// Hidden entries are never members, the same as they are never found.
+fn valid_filter(
+    f: Filter<FilterInvalid>,
+    schema: &dyn SchemaTransaction,
+) -> Result<Filter<FilterValid>, OperationError> {
+    f.to_ignore_hidden()
+        .validate(schema)
+        .map_err(|e| OperationError::SchemaViolation(e))
+}
+
+// The uuids a dyngroup's filter matches now, other than its own.
+fn members<QS: QueryServerTransaction>(
+    au: &mut AuditScope,
+    qs: &QS,
+    filter: Filter<FilterValid>,
+    uuid: &str,
+) -> Result<BTreeSet<String>, OperationError> {
+    Ok(qs
+        .internal_search_valid(au, filter)?
+        .iter()
+        .map(|e| e.get_uuid().clone())
+        .filter(|u| u != uuid)
+        .collect())
+}
+
+// Set the members of the dyngroups among the candidates, from their filters.
+fn recompute<STATE: Copy>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    cand: &mut Vec<Entry<EntryInvalid, STATE>>,
+) -> Result<(), OperationError> {
+    for e in cand
+        .iter_mut()
+        .filter(|e| e.attribute_value_pres("class", "dyngroup"))
+    {
+        let uuid = e
+            .get_ava_single("uuid")
+            .map(|v| v.to_string())
+            .ok_or(OperationError::InvalidEntryState)?;
+        let pf = try_audit!(au, proto_filter(e));
+        let f = try_audit!(au, Filter::from_rw(au, &pf, qs));
+        let f = try_audit!(au, valid_filter(f, qs.get_schema()));
+        let members = try_audit!(au, members(au, qs, f, uuid.as_str()));
+        audit_log!(au, "dyngroup {} has {} members", uuid, members.len());
+
+        // A dyngroup is a group, so memberof follows its members.
+        if !e.attribute_value_pres("class", "group") {
+            e.add_ava("class", "group");
+        }
+        e.purge_ava("member");
+        members.iter().for_each(|m| e.add_ava("member", m.as_str()));
+    }
+    Ok(())
+}
+
+// Check the changed entries against every dyngroup, and touch the ones they
+// have joined or left.
+fn apply_dyngroups<STATE>(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    changed: Vec<&Entry<EntryValid, STATE>>,
+) -> Result<(), OperationError> {
+    if changed.is_empty() {
+        return Ok(());
+    }
+    let dyngroups = try_audit!(
+        au,
+        qs.internal_search(au, filter!(f_eq("class", "dyngroup")))
+    );
+    let ev = Event::from_internal();
+
+    let mut batch: Vec<(String, ModifyList<ModifyValid>)> = Vec::new();
+    for dg in dyngroups.iter() {
+        let pf = try_audit!(au, proto_filter(dg));
+        let f = try_audit!(au, Filter::from_rw(au, &pf, qs));
+        let f = try_audit!(au, valid_filter(f, qs.get_schema()));
+        let f = try_audit!(au, f.resolve(&ev));
+
+        let mods: Vec<Modify> = changed
+            .iter()
+            .filter(|e| e.get_uuid() != dg.get_uuid())
+            .filter_map(|e| {
+                let uuid = e.get_uuid().as_str();
+                let is_member = dg.attribute_value_pres("member", uuid);
+                match (e.entry_match_no_index(&f), is_member) {
+                    (true, false) => Some(Modify::Present("member".to_string(), Value::from(uuid))),
+                    (false, true) => Some(Modify::Removed("member".to_string(), Value::from(uuid))),
+                    _ => None,
+                }
+            })
+            .collect();
+        if !mods.is_empty() {
+            audit_log!(au, "dyngroup {} changes {:?}", dg.get_uuid(), mods);
+            batch.push((dg.get_uuid().clone(), unsafe {
+                ModifyList::new_list(mods).assume_valid(qs.get_schema())
+            }));
+        }
+    }
+
+    try_audit!(au, qs.internal_modify_batch_valid(au, batch));
+    Ok(())
+}
+
+impl Plugin for DynGroup {
+    fn id() -> &'static str {
+        "dyngroup"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        // Entries created alongside the dyngroup aren't found yet, they are
+        // added by post_create.
+        recompute(au, qs, cand)
+    }
+
+    fn post_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        apply_dyngroups(au, qs, cand.iter().collect())
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        recompute(au, qs, cand)
+    }
+
+    fn post_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        // Only entries that changed, so the touches of dyngroups come to rest.
+        let changed = pre_cand
+            .iter()
+            .zip(cand.iter())
+            .filter(|(pre, post)| pre != post)
+            .map(|(_, post)| post)
+            .collect();
+        apply_dyngroups(au, qs, changed)
+    }
+
+    // Deleted entries are removed from member by refint, as from any group.
+
+    fn post_repl(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        let changed = try_audit!(
+            au,
+            qs.internal_search(
+                au,
+                filter!(f_or(
+                    uuids.iter().map(|u| f_eq("uuid", u.as_str())).collect()
+                ))
+            )
+        );
+        apply_dyngroups(au, qs, changed.iter().collect())
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let dyngroups = match qs.internal_search(au, filter!(f_eq("class", "dyngroup"))) {
+            Ok(dgs) => dgs,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+
+        dyngroups
+            .iter()
+            .map(|dg| {
+                let expect = proto_filter(dg)
+                    .and_then(|pf| Filter::from_ro(au, &pf, qs))
+                    .and_then(|f| valid_filter(f, qs.get_schema()))
+                    .and_then(|f| members(au, qs, f, dg.get_uuid().as_str()))
+                    .map_err(|_| ConsistencyError::DynGroupMemberInvalid(dg.get_id()))?;
+                let have: BTreeSet<String> = dg
+                    .get_ava("member")
+                    .map(|vs| vs.iter().map(|v| v.to_string()).collect())
+                    .unwrap_or_else(BTreeSet::new);
+                if have == expect {
+                    Ok(())
+                } else {
+                    audit_log!(
+                        au,
+                        "dyngroup {} has members {:?}, expected {:?}",
+                        dg.get_uuid(),
+                        have,
+                        expect
+                    );
+                    Err(ConsistencyError::DynGroupMemberInvalid(dg.get_id()))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::modify::{Modify, ModifyList};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::Value;
+
+    static UUID_DG: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63a01";
+    static UUID_A: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63a02";
+    static UUID_B: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63a03";
+
+    fn account(name: &str, uuid: &str, tag: &str) -> Entry<EntryInvalid, EntryNew> {
+        serde_json::from_str(
+            format!(
+                r#"{{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "account"],
+                        "name": ["{}"],
+                        "uuid": ["{}"],
+                        "displayname": ["{}"],
+                        "tag": ["{}"]
+                    }}
+                }}"#,
+                name, uuid, name, tag
+            )
+            .as_str(),
+        )
+        .expect("json failure")
+    }
+
+    fn dyngroup() -> Entry<EntryInvalid, EntryNew> {
+        serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "dyngroup"],
+                    "name": ["dg_engineering"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a01"],
+                    "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63a03"],
+                    "dyngroup_filter": ["{\"And\":[{\"Eq\":[\"class\",\"account\"]},{\"Eq\":[\"tag\",\"engineering\"]}]}"]
+                }
+            }"#,
+        )
+        .expect("json failure")
+    }
+
+    fn assert_member(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        member: &str,
+        expect: bool,
+    ) {
+        let dg = qs.internal_search_uuid(au, UUID_DG).expect("search failed");
+        assert!(dg.attribute_value_pres("member", member) == expect);
+        assert!(
+            qs.internal_search_uuid(au, member)
+                .expect("search failed")
+                .attribute_value_pres("memberof", UUID_DG)
+                == expect
+        );
+    }
+
+    #[test]
+    fn test_dyngroup_create() {
+        // Members given on create are replaced by those the filter matches,
+        // including entries created with the dyngroup.
+        let preload = vec![account("dg_a", UUID_A, "engineering")];
+        let create = vec![dyngroup(), account("dg_b", UUID_B, "sales")];
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let dg = qs.internal_search_uuid(au, UUID_DG).expect("search failed");
+                assert!(dg.attribute_value_pres("class", "group"));
+                assert_member(au, qs, UUID_A, true);
+                assert_member(au, qs, UUID_B, false);
+            }
+        );
+
+        let preload = vec![dyngroup()];
+        let create = vec![account("dg_a", UUID_A, "engineering")];
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_member(au, qs, UUID_A, true);
+            }
+        );
+    }
+
+    #[test]
+    fn test_dyngroup_modify() {
+        // An entry joins and leaves as it comes to match the filter or not.
+        let preload = vec![
+            dyngroup(),
+            account("dg_a", UUID_A, "engineering"),
+            account("dg_b", UUID_B, "sales"),
+        ];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("class", "account")),
+            ModifyList::new_list(vec![
+                Modify::Purged("tag".to_string()),
+                Modify::Present("tag".to_string(), Value::from("engineering")),
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_member(au, qs, UUID_A, true);
+                assert_member(au, qs, UUID_B, true);
+            }
+        );
+
+        let preload = vec![
+            dyngroup(),
+            account("dg_a", UUID_A, "engineering"),
+            account("dg_b", UUID_B, "sales"),
+        ];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_A)),
+            ModifyList::new_list(vec![Modify::Removed(
+                "tag".to_string(),
+                Value::from("engineering")
+            )]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_member(au, qs, UUID_A, false);
+            }
+        );
+
+        // Changing the filter recomputes the members, and they can't be
+        // changed by hand.
+        let preload = vec![
+            dyngroup(),
+            account("dg_a", UUID_A, "engineering"),
+            account("dg_b", UUID_B, "sales"),
+        ];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_DG)),
+            ModifyList::new_list(vec![
+                Modify::Purged("dyngroup_filter".to_string()),
+                Modify::Present(
+                    "dyngroup_filter".to_string(),
+                    Value::from("{\"Eq\":[\"tag\",\"sales\"]}")
+                ),
+                Modify::Present("member".to_string(), Value::from(UUID_A)),
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_member(au, qs, UUID_A, false);
+                assert_member(au, qs, UUID_B, true);
+            }
+        );
+    }
+
+    #[test]
+    fn test_dyngroup_delete() {
+        let preload = vec![dyngroup(), account("dg_a", UUID_A, "engineering")];
+        run_delete_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_A)),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let dg = qs.internal_search_uuid(au, UUID_DG).expect("search failed");
+                assert!(!dg.attribute_value_pres("member", UUID_A));
+            }
+        );
+    }
+}
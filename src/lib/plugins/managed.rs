@@ -0,0 +1,678 @@
+// Managed Entries
+//
+// An entry_template describes companion entries that are kept for every
+// entry matching its template_filter - for example, a private group for each
+// account. The companion has the template_class classes, is named for its
+// origin and the template ("alice_private"), and holds copies of the origin's
+// template_link attributes.
+//
+// Companions are created when their origin is created or comes to match, and
+// their name and linked attributes follow the origin whenever it is written.
+// They are deleted with their origin, when it stops matching, or with their
+// template. A companion can't be edited away from its origin - writing to it
+// sets them back - nor deleted on its own by anyone but the server.
+//
+// A new or changed template is applied to every entry it matches at once.
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::error::{ConsistencyError, OperationError};
+use crate::event::{CreateEvent, DeleteEvent, Event, ModifyEvent};
+use crate::filter::{Filter, FilterInvalid, FilterValid, FilterValidResolved};
+use crate::modify::{Modify, ModifyList, ModifyValid};
+use crate::plugins::Plugin;
+use crate::proto::v1::Filter as ProtoFilter;
+use crate::server::QueryServerTransaction;
+use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct ManagedEntries;
+
+struct Template {
+    uuid: String,
+    name: String,
+    // To find the origins, and to test a single entry.
+    search: Filter<FilterValid>,
+    filter: Filter<FilterValidResolved>,
+    classes: Vec<String>,
+    links: Vec<String>,
+}
+
+fn strings<VALID, STATE>(e: &Entry<VALID, STATE>, attr: &str) -> Vec<String> {
+    e.get_ava(attr)
+        .map(|vs| vs.iter().map(|v| v.to_string()).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+fn load_templates<QS: QueryServerTransaction>(
+    au: &mut AuditScope,
+    qs: &QS,
+    from_proto: &dyn Fn(
+        &mut AuditScope,
+        &ProtoFilter,
+    ) -> Result<Filter<FilterInvalid>, OperationError>,
+) -> Result<Vec<Template>, OperationError> {
+    let ev = Event::from_internal();
+    qs.internal_search(au, filter!(f_eq("class", "entry_template")))?
+        .iter()
+        .map(|t| {
+            let raw =
+                t.get_ava_single("template_filter")
+                    .ok_or(OperationError::InvalidEntryTemplate(
+                        "Missing template_filter",
+                    ))?;
+            let pf: ProtoFilter = serde_json::from_str(raw.to_string().as_str())
+                .map_err(|_| OperationError::InvalidEntryTemplate("Invalid template_filter"))?;
+            // Hidden entries never have companions.
+            let search = from_proto(au, &pf)?
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?;
+            let filter = search.resolve(&ev)?;
+            let name = t
+                .get_ava_single("name")
+                .map(|v| v.to_string())
+                .ok_or(OperationError::InvalidEntryState)?;
+            Ok(Template {
+                uuid: t.get_uuid().clone(),
+                name: name,
+                search: search,
+                filter: filter,
+                classes: strings(t, "template_class"),
+                links: strings(t, "template_link"),
+            })
+        })
+        .collect()
+}
+
+fn origin_name<STATE>(e: &Entry<EntryValid, STATE>) -> Option<String> {
+    e.get_ava_single("name").map(|v| v.to_string())
+}
+
+// Companions and templates never have companions of their own, and without
+// a name there is nothing to name the companion for.
+fn is_origin<STATE>(e: &Entry<EntryValid, STATE>) -> bool {
+    !e.attribute_value_pres("class", "managed_entry")
+        && !e.attribute_value_pres("class", "entry_template")
+        && origin_name(e).is_some()
+}
+
+// The values a companion of this origin holds that follow the origin.
+fn expected<STATE>(t: &Template, origin: &Entry<EntryValid, STATE>) -> Vec<(String, Vec<Value>)> {
+    let name = format!(
+        "{}_{}",
+        origin_name(origin).unwrap_or_else(String::new),
+        t.name
+    );
+    let mut avas = vec![("name".to_string(), vec![Value::from(name)])];
+    avas.extend(t.links.iter().map(|attr| {
+        (
+            attr.clone(),
+            origin.get_ava(attr).cloned().unwrap_or_else(Vec::new),
+        )
+    }));
+    avas
+}
+
+fn companion<STATE>(
+    t: &Template,
+    origin: &Entry<EntryValid, STATE>,
+) -> Entry<EntryInvalid, EntryNew> {
+    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+    e.add_ava("class", "object");
+    e.add_ava("class", "managed_entry");
+    t.classes
+        .iter()
+        .for_each(|c| e.add_ava("class", c.as_str()));
+    e.add_ava("managed_origin", origin.get_uuid().as_str());
+    e.add_ava("managed_template", t.uuid.as_str());
+    for (attr, values) in expected(t, origin) {
+        if !values.is_empty() {
+            e.set_avas(attr.as_str(), values);
+        }
+    }
+    e
+}
+
+// The changes that bring a companion back in line with its origin.
+fn sync_mods<STATE>(
+    t: &Template,
+    origin: &Entry<EntryValid, STATE>,
+    comp: &Entry<EntryValid, EntryCommitted>,
+) -> Vec<Modify> {
+    let mut mods = Vec::new();
+    for (attr, values) in expected(t, origin) {
+        if comp
+            .get_ava(attr.as_str())
+            .cloned()
+            .unwrap_or_else(Vec::new)
+            != values
+        {
+            mods.push(Modify::Purged(attr.clone()));
+            mods.extend(values.into_iter().map(|v| Modify::Present(attr.clone(), v)));
+        }
+    }
+    mods
+}
+
+// The existing companions of a template, by their origin.
+fn companions<QS: QueryServerTransaction>(
+    au: &mut AuditScope,
+    qs: &QS,
+    t: &Template,
+) -> Result<BTreeMap<String, Entry<EntryValid, EntryCommitted>>, OperationError> {
+    Ok(qs
+        .internal_search(
+            au,
+            filter!(f_and!([
+                f_eq("class", "managed_entry"),
+                f_eq("managed_template", t.uuid.as_str())
+            ])),
+        )?
+        .into_iter()
+        .filter_map(|c| {
+            c.get_ava_single("managed_origin")
+                .map(|o| o.to_string())
+                .map(|o| (o, c))
+        })
+        .collect())
+}
+
+fn delete_uuids(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    uuids: Vec<String>,
+) -> Result<(), OperationError> {
+    if uuids.is_empty() {
+        return Ok(());
+    }
+    audit_log!(au, "managed entries deleting {:?}", uuids);
+    qs.internal_delete(
+        au,
+        filter!(f_or(
+            uuids.iter().map(|u| f_eq("uuid", u.as_str())).collect()
+        )),
+    )
+}
+
+// Bring the companions of these entries for a template up to date - create
+// those missing, sync those that exist, and delete those of entries that no
+// longer match.
+fn apply_template<STATE>(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    t: &Template,
+    entries: &Vec<&Entry<EntryValid, STATE>>,
+) -> Result<(), OperationError> {
+    let mut existing = try_audit!(au, companions(au, &*qs, t));
+
+    let mut create = Vec::new();
+    let mut batch: Vec<(String, ModifyList<ModifyValid>)> = Vec::new();
+    let mut delete = Vec::new();
+    for e in entries.iter().filter(|e| is_origin(**e)) {
+        let matches = e.entry_match_no_index(&t.filter);
+        match (matches, existing.remove(e.get_uuid())) {
+            (true, None) => create.push(companion(t, *e)),
+            (true, Some(comp)) => {
+                let mods = sync_mods(t, *e, &comp);
+                if !mods.is_empty() {
+                    batch.push((comp.get_uuid().clone(), unsafe {
+                        ModifyList::new_list(mods).assume_valid(qs.get_schema())
+                    }));
+                }
+            }
+            (false, Some(comp)) => delete.push(comp.get_uuid().clone()),
+            (false, None) => {}
+        }
+    }
+
+    if !create.is_empty() {
+        audit_log!(
+            au,
+            "managed entries creating {} for {}",
+            create.len(),
+            t.name
+        );
+        try_audit!(au, qs.internal_create(au, create));
+    }
+    try_audit!(au, qs.internal_modify_batch_valid(au, batch));
+    try_audit!(au, delete_uuids(au, qs, delete));
+    Ok(())
+}
+
+// A new or changed template is applied to everything it now matches, and
+// companions of entries it no longer matches are removed.
+fn reconcile_template(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    t: &Template,
+) -> Result<(), OperationError> {
+    let origins = try_audit!(au, qs.internal_search_valid(au, t.search.clone()));
+    let matched: BTreeSet<&String> = origins.iter().map(|e| e.get_uuid()).collect();
+    let stale: Vec<String> = try_audit!(au, companions(au, &*qs, t))
+        .into_iter()
+        .filter(|(o, _)| !matched.contains(o))
+        .map(|(_, c)| c.get_uuid().clone())
+        .collect();
+    try_audit!(au, delete_uuids(au, qs, stale));
+    let entries: Vec<&Entry<EntryValid, EntryCommitted>> = origins.iter().collect();
+    apply_template(au, qs, t, &entries)
+}
+
+fn apply_all<STATE>(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    changed: Vec<&Entry<EntryValid, STATE>>,
+) -> Result<(), OperationError> {
+    // Most writes, including those made here to companions, concern no
+    // template at all.
+    let changed: Vec<&Entry<EntryValid, STATE>> = changed
+        .into_iter()
+        .filter(|e| is_origin(*e) || e.attribute_value_pres("class", "entry_template"))
+        .collect();
+    if changed.is_empty() {
+        return Ok(());
+    }
+    let templates = {
+        let qs_ref = &*qs;
+        try_audit!(
+            au,
+            load_templates(au, qs_ref, &|au, pf| Filter::from_rw(au, pf, qs_ref))
+        )
+    };
+    let changed_templates: BTreeSet<&String> = changed
+        .iter()
+        .filter(|e| e.attribute_value_pres("class", "entry_template"))
+        .map(|e| e.get_uuid())
+        .collect();
+
+    for t in templates.iter() {
+        if changed_templates.contains(&t.uuid) {
+            try_audit!(au, reconcile_template(au, qs, t));
+        } else {
+            try_audit!(au, apply_template(au, qs, t, &changed));
+        }
+    }
+    Ok(())
+}
+
+// Set the values a companion takes from its origin, so they can't drift.
+fn resync<STATE: Copy>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    cand: &mut Vec<Entry<EntryInvalid, STATE>>,
+) -> Result<(), OperationError> {
+    let mut templates: Option<Vec<Template>> = None;
+    for e in cand
+        .iter_mut()
+        .filter(|e| e.attribute_value_pres("class", "managed_entry"))
+    {
+        if templates.is_none() {
+            templates = Some(try_audit!(
+                au,
+                load_templates(au, qs, &|au, pf| Filter::from_rw(au, pf, qs))
+            ));
+        }
+        let t_uuid = e.get_ava_single("managed_template").map(|v| v.to_string());
+        let o_uuid = e.get_ava_single("managed_origin").map(|v| v.to_string());
+        let t = templates
+            .as_ref()
+            .and_then(|ts| ts.iter().find(|t| Some(&t.uuid) == t_uuid.as_ref()));
+        // A companion being torn down with its origin or template is left be.
+        let origin = match (t, o_uuid) {
+            (Some(t), Some(o)) => qs.internal_search_uuid(au, o.as_str()).ok().map(|o| (t, o)),
+            _ => None,
+        };
+        if let Some((t, origin)) = origin {
+            for (attr, values) in expected(t, &origin) {
+                if values.is_empty() {
+                    e.purge_ava(attr.as_str());
+                } else {
+                    e.set_avas(attr.as_str(), values);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Plugin for ManagedEntries {
+    fn id() -> &'static str {
+        "managed_entries"
+    }
+
+    fn pre_create(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        // Only the server makes companions.
+        if !ce.event.is_internal()
+            && cand
+                .iter()
+                .any(|e| e.attribute_value_pres("class", "managed_entry"))
+        {
+            audit_log!(au, "refusing to create a managed entry");
+            return Err(OperationError::SystemProtectedObject);
+        }
+        Ok(())
+    }
+
+    fn post_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        apply_all(au, qs, cand.iter().collect())
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        resync(au, qs, cand)
+    }
+
+    fn post_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        let changed = pre_cand
+            .iter()
+            .zip(cand.iter())
+            .filter(|(pre, post)| pre != post)
+            .map(|(_, post)| post)
+            .collect();
+        apply_all(au, qs, changed)
+    }
+
+    fn pre_delete(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        de: &DeleteEvent,
+    ) -> Result<(), OperationError> {
+        if de.event.is_internal() {
+            return Ok(());
+        }
+        // A companion goes with its origin, not on its own.
+        let deleting: BTreeSet<String> = cand
+            .iter()
+            .filter_map(|e| e.get_ava_single("uuid").map(|v| v.to_string()))
+            .collect();
+        let orphaned = cand.iter().any(|e| {
+            e.attribute_value_pres("class", "managed_entry")
+                && !e
+                    .get_ava_single("managed_origin")
+                    .map(|o| deleting.contains(&o.to_string()))
+                    .unwrap_or(false)
+        });
+        if orphaned {
+            audit_log!(au, "refusing to delete a managed entry without its origin");
+            return Err(OperationError::SystemProtectedObject);
+        }
+        Ok(())
+    }
+
+    fn post_delete(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        _de: &DeleteEvent,
+    ) -> Result<(), OperationError> {
+        // This runs before refint, which would otherwise strip the references
+        // the companions are found by.
+        let filt = filter!(f_and!([
+            f_eq("class", "managed_entry"),
+            f_or(
+                cand.iter()
+                    .flat_map(|e| {
+                        vec![
+                            f_eq("managed_origin", e.get_uuid().as_str()),
+                            f_eq("managed_template", e.get_uuid().as_str()),
+                        ]
+                    })
+                    .collect()
+            )
+        ]));
+        let uuids: Vec<String> = try_audit!(au, qs.internal_search(au, filt))
+            .iter()
+            .map(|c| c.get_uuid().clone())
+            .collect();
+        delete_uuids(au, qs, uuids)
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let templates = match load_templates(au, qs, &|au, pf| Filter::from_ro(au, pf, qs)) {
+            Ok(ts) => ts,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+
+        let mut res = Vec::new();
+        for t in templates.iter() {
+            let (origins, mut existing) = match (
+                qs.internal_search_valid(au, t.search.clone()),
+                companions(au, qs, t),
+            ) {
+                (Ok(o), Ok(c)) => (o, c),
+                _ => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+            };
+            for o in origins.iter().filter(|o| is_origin(*o)) {
+                res.push(match existing.remove(o.get_uuid()) {
+                    Some(comp) => {
+                        if sync_mods(t, o, &comp).is_empty() {
+                            Ok(())
+                        } else {
+                            audit_log!(au, "managed entry {} is out of sync", comp.get_uuid());
+                            Err(ConsistencyError::ManagedEntryInvalid(comp.get_id()))
+                        }
+                    }
+                    None => {
+                        audit_log!(au, "{} has no {} companion", o.get_uuid(), t.name);
+                        Err(ConsistencyError::ManagedEntryInvalid(o.get_id()))
+                    }
+                });
+            }
+            // Whatever is left no longer has a matching origin.
+            res.extend(existing.values().map(|comp| {
+                audit_log!(au, "managed entry {} has no origin", comp.get_uuid());
+                Err(ConsistencyError::ManagedEntryInvalid(comp.get_id()))
+            }));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+    use crate::modify::{Modify, ModifyList};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::Value;
+
+    static UUID_T: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63b01";
+    static UUID_A: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63b02";
+
+    fn template() -> Entry<EntryInvalid, EntryNew> {
+        serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "entry_template"],
+                    "name": ["private"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63b01"],
+                    "template_filter": ["{\"Eq\":[\"class\",\"account\"]}"],
+                    "template_class": ["group"],
+                    "template_link": ["description"]
+                }
+            }"#,
+        )
+        .expect("json failure")
+    }
+
+    fn account() -> Entry<EntryInvalid, EntryNew> {
+        serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "account"],
+                    "name": ["mg_a"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63b02"],
+                    "displayname": ["mg_a"],
+                    "description": ["first"]
+                }
+            }"#,
+        )
+        .expect("json failure")
+    }
+
+    fn companions(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+    ) -> Vec<Entry<EntryValid, EntryCommitted>> {
+        qs.internal_search(au, filter!(f_eq("managed_origin", UUID_A)))
+            .expect("search failed")
+    }
+
+    fn assert_companion(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        name: &str,
+        description: &str,
+    ) {
+        let comps = companions(au, qs);
+        assert!(comps.len() == 1);
+        assert!(comps[0].attribute_value_pres("class", "group"));
+        assert!(comps[0].attribute_value_pres("managed_template", UUID_T));
+        assert!(comps[0].attribute_value_pres("name", name));
+        assert!(comps[0].attribute_value_pres("description", description));
+    }
+
+    #[test]
+    fn test_managed_create() {
+        // An origin created after its template.
+        let preload = vec![template()];
+        let create = vec![account()];
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_companion(au, qs, "mg_a_private", "first");
+            }
+        );
+
+        // And a template created after the entries it matches.
+        let preload = vec![account()];
+        let create = vec![template()];
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_companion(au, qs, "mg_a_private", "first");
+            }
+        );
+    }
+
+    #[test]
+    fn test_managed_modify() {
+        // The companion follows a rename and its linked attributes.
+        let preload = vec![template(), account()];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_A)),
+            ModifyList::new_list(vec![
+                Modify::Purged("name".to_string()),
+                Modify::Present("name".to_string(), Value::from("mg_b")),
+                Modify::Purged("description".to_string()),
+                Modify::Present("description".to_string(), Value::from("second")),
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_companion(au, qs, "mg_b_private", "second");
+            }
+        );
+
+        // It can't be changed away from its origin.
+        let preload = vec![template(), account()];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", "mg_a_private")),
+            ModifyList::new_list(vec![
+                Modify::Purged("description".to_string()),
+                Modify::Present("description".to_string(), Value::from("other")),
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_companion(au, qs, "mg_a_private", "first");
+            }
+        );
+
+        // When the template stops matching the origin, its companion goes.
+        let preload = vec![template(), account()];
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_T)),
+            ModifyList::new_list(vec![
+                Modify::Purged("template_filter".to_string()),
+                Modify::Present(
+                    "template_filter".to_string(),
+                    Value::from("{\"Eq\":[\"class\",\"person\"]}")
+                ),
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert!(companions(au, qs).len() == 0);
+            }
+        );
+    }
+
+    #[test]
+    fn test_managed_delete() {
+        // Deleting the origin deletes the companion.
+        let preload = vec![template(), account()];
+        run_delete_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_A)),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert!(companions(au, qs).len() == 0);
+            }
+        );
+
+        // So does deleting the template.
+        let preload = vec![template(), account()];
+        run_delete_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", UUID_T)),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert!(companions(au, qs).len() == 0);
+                assert!(qs.internal_search_uuid(au, UUID_A).is_ok());
+            }
+        );
+    }
+}
@@ -9,6 +9,11 @@
 //
 // As a result, we first need to run refint to clean up all dangling references, then memberof
 // fixes the graph of memberships
+//
+// memberof holds every group an entry is in, following nested groups through
+// to the top, so an access control can name a group and match all of its
+// members however deeply they are nested. Because access decisions rely on
+// it, only this plugin may set it - any value given on create is discarded.
 
 use crate::audit::AuditScope;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
@@ -18,15 +23,16 @@ use crate::modify::{Modify, ModifyList};
 use crate::plugins::Plugin;
 use crate::server::QueryServerTransaction;
 use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 pub struct MemberOf;
 
 fn affected_uuids<'a, STATE>(
     au: &mut AuditScope,
     changed: Vec<&'a Entry<EntryValid, STATE>>,
-) -> Vec<&'a String>
+) -> Vec<&'a Value>
 where
     STATE: std::fmt::Debug,
 {
@@ -40,7 +46,7 @@ where
         .collect();
 
     // Now, build a map of all UUID's that will require updates as a result of this change
-    let mut affected_uuids: Vec<&String> = changed_groups
+    let mut affected_uuids: Vec<&Value> = changed_groups
         .iter()
         .filter_map(|e| {
             // Only groups with member get collected up here.
@@ -65,7 +71,7 @@ where
 fn apply_memberof(
     au: &mut AuditScope,
     qs: &mut QueryServerWriteTransaction,
-    affected_uuids: Vec<&String>,
+    affected_uuids: Vec<&Value>,
 ) -> Result<(), OperationError> {
     audit_log!(au, " => entering apply_memberof");
     audit_log!(au, "affected uuids -> {:?}", affected_uuids);
@@ -84,20 +90,31 @@ fn apply_memberof(
     // callback, as regardless of initial entry point, all subsequent MO internal operations
     // are modifies - it is up to post_modify to break cycles!
 
-    // Now work on the affected set.
+    // Now work on the affected set. The changes are collected and written as
+    // one batch, so each affected entry is stored once for this step, however
+    // many groups it's in. Groups changed by the batch are revisited when its
+    // post_modify runs.
+    let mut batch = Vec::with_capacity(affected_uuids.len());
 
     // For each affected uuid
     for a_uuid in affected_uuids {
+        let a_uuid = a_uuid.to_string();
         // search where group + Eq("member": "uuid")
         let groups = try_audit!(
             au,
-            qs.internal_search(
-                au,
-                filter!(f_and!([f_eq("class", "group"), f_eq("member", a_uuid)]))
-            )
+            qs.internal_search_valid(au, unsafe {
+                filter!(f_and!([
+                    f_eq("class", "group"),
+                    f_eq("member", a_uuid.as_str())
+                ]))
+                .assume_valid(qs.get_schema())
+            })
         );
         // get UUID of all groups + all memberof values
-        let mut dir_mo_set: Vec<_> = groups.iter().map(|g| g.get_uuid().clone()).collect();
+        let mut dir_mo_set: Vec<_> = groups
+            .iter()
+            .map(|g| Value::from(g.get_uuid().as_str()))
+            .collect();
 
         // No need to dedup this. Sorting could be of questionable
         // value too though ...
@@ -107,7 +124,7 @@ fn apply_memberof(
             .iter()
             .map(|g| {
                 // TODO #61: This could be more effecient
-                let mut v = vec![g.get_uuid().clone()];
+                let mut v = vec![Value::from(g.get_uuid().as_str())];
                 match g.get_ava("memberof") {
                     Some(mos) => {
                         for mo in mos {
@@ -133,7 +150,7 @@ fn apply_memberof(
         // TODO #68: Could this affect replication? Or should the CL work out the
         // true diff of the operation?
         let mo_purge = vec![
-            Modify::Present("class".to_string(), "memberof".to_string()),
+            Modify::Present("class".to_string(), Value::from("memberof")),
             Modify::Purged("memberof".to_string()),
             Modify::Purged("directmemberof".to_string()),
         ];
@@ -154,14 +171,13 @@ fn apply_memberof(
             .collect();
 
         // apply to affected uuid
-        let modlist = ModifyList::new_list(mod_set);
-
-        try_audit!(
-            au,
-            qs.internal_modify(au, filter!(f_eq("uuid", a_uuid)), modlist,)
-        );
+        batch.push((a_uuid, unsafe {
+            ModifyList::new_list(mod_set).assume_valid(qs.get_schema())
+        }));
     }
 
+    try_audit!(au, qs.internal_modify_batch_valid(au, batch));
+
     Ok(())
 }
 
@@ -173,6 +189,21 @@ impl Plugin for MemberOf {
     // TODO #61: We could make this more effecient by limiting change detection to ONLY member/memberof
     // attrs rather than any attrs.
 
+    fn pre_create_transform(
+        _au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        // A new entry can't be in any group yet. If a group being created
+        // with it names it as a member, post_create adds the memberships.
+        cand.iter_mut().for_each(|e| {
+            e.purge_ava("memberof");
+            e.purge_ava("directmemberof");
+        });
+        Ok(())
+    }
+
     fn post_create(
         au: &mut AuditScope,
         qs: &mut QueryServerWriteTransaction,
@@ -194,7 +225,7 @@ impl Plugin for MemberOf {
         _me: &ModifyEvent,
     ) -> Result<(), OperationError> {
         // The condition here is critical - ONLY trigger on entries where changes occur!
-        let mut changed: Vec<&String> = pre_cand
+        let mut changed: Vec<&Value> = pre_cand
             .iter()
             .zip(cand.iter())
             .filter(|(pre, post)| {
@@ -269,6 +300,55 @@ impl Plugin for MemberOf {
         apply_memberof(au, qs, uuids)
     }
 
+    // The memberships sent with the entries are the supplier's, and may be
+    // missing changes made here first, so they're worked out again.
+    fn post_repl(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        Self::recover(au, qs, uuids)
+    }
+
+    fn recover(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        // The changed entries, and anything that was in one of them. That
+        // finds members a changed group no longer lists, and the members of
+        // deleted groups.
+        let changed = try_audit!(
+            au,
+            qs.internal_search_valid(au, unsafe {
+                filter!(f_or(
+                    uuids
+                        .iter()
+                        .flat_map(|u| vec![f_eq("uuid", u.as_str()), f_eq("memberof", u.as_str())])
+                        .collect()
+                ))
+                .assume_valid(qs.get_schema())
+            })
+        );
+        // Of those, the ones that have a memberof, and the members of any
+        // that are groups.
+        let mut affected: Vec<&Value> = changed
+            .iter()
+            .filter(|e| e.attribute_value_pres("class", "memberof"))
+            .filter_map(|e| e.get_ava("uuid"))
+            .flatten()
+            .collect();
+        affected.extend(affected_uuids(au, changed.iter().collect()));
+        affected.sort();
+        affected.dedup();
+        audit_log!(
+            au,
+            "recovery: refreshing memberof of {} entries",
+            affected.len()
+        );
+        apply_memberof(au, qs, affected)
+    }
+
     fn verify(
         au: &mut AuditScope,
         qs: &QueryServerReadTransaction,
@@ -301,8 +381,10 @@ impl Plugin for MemberOf {
             };
             // for all direct -> add uuid to map
 
-            let d_groups_set: BTreeMap<&String, ()> =
-                direct_memberof.iter().map(|e| (e.get_uuid(), ())).collect();
+            let d_groups_set: BTreeMap<String, ()> = direct_memberof
+                .iter()
+                .map(|e| (e.get_uuid().clone(), ()))
+                .collect();
 
             audit_log!(au, "Direct groups {:?} -> {:?}", e.get_uuid(), d_groups_set);
 
@@ -329,7 +411,7 @@ impl Plugin for MemberOf {
             };
 
             for mo_uuid in dmos {
-                if !d_groups_set.contains_key(&mo_uuid) {
+                if !d_groups_set.contains_key(&mo_uuid.to_string()) {
                     audit_log!(
                         au,
                         "Entry {:?}, MO {:?} not in direct groups",
@@ -342,18 +424,39 @@ impl Plugin for MemberOf {
                 }
             }
 
-            // Could check all dmos in mos?
+            // Now expand the direct groups to every group this entry is in,
+            // directly or through nesting. A group already in the set is not
+            // followed again, which is what stops us going around a cycle.
+            let mut mo_set: BTreeSet<String> = BTreeSet::new();
+            let mut stack: Vec<String> = d_groups_set.keys().cloned().collect();
+            while let Some(g_uuid) = stack.pop() {
+                if !mo_set.insert(g_uuid.clone()) {
+                    continue;
+                }
+                let filt_in = filter!(f_eq("member", g_uuid.as_str()));
+                match qs.internal_search(au, filt_in) {
+                    Ok(parents) => stack.extend(parents.iter().map(|p| p.get_uuid().clone())),
+                    Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+                }
+            }
 
-            /* To check nested! */
-            // add all direct to a stack
-            // for all in stack
-            // check their direct memberships
-            // if not in map
-            // add to map
-            // push to stack
+            let mos: BTreeSet<String> = match e.get_ava("memberof") {
+                Some(mos) => mos.iter().map(|v| v.to_string()).collect(),
+                None => BTreeSet::new(),
+            };
+
+            audit_log!(au, "MO groups {:?} -> {:?}", e.get_uuid(), mos);
 
-            // check mo == map set
-            // if not, consistency error!
+            if mos != mo_set {
+                audit_log!(
+                    au,
+                    "Entry {:?}, MO {:?} is not the expanded set {:?}",
+                    e.get_uuid(),
+                    mos,
+                    mo_set
+                );
+                r.push(Err(ConsistencyError::MemberOfInvalid(e.get_id())));
+            }
         }
 
         r
@@ -368,6 +471,7 @@ mod tests {
     // use crate::error::OperationError;
     use crate::modify::{Modify, ModifyList};
     use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::Value;
 
     static EA: &'static str = r#"{
             "valid": null,
@@ -509,6 +613,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_create_mo_claimed() {
+        // B claims to be in A, but A doesn't have B as a member.
+        let ea: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(EA).expect("Json parse failure");
+
+        let mut eb: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(EB).expect("Json parse failure");
+
+        eb.add_ava("memberof", UUID_A);
+        eb.add_ava("directmemberof", UUID_A);
+
+        let preload = vec![ea];
+        let create = vec![eb];
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                assert_not_memberof!(au, qs, UUID_B, UUID_A);
+                assert_not_dirmemberof!(au, qs, UUID_B, UUID_A);
+            }
+        );
+    }
+
     #[test]
     fn test_create_mo_nested() {
         // A -> B -> C
@@ -712,7 +842,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_A)),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                UUID_B.to_string()
+                Value::from(UUID_B)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -750,7 +880,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_A)),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                UUID_B.to_string()
+                Value::from(UUID_B)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -806,7 +936,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_B)),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                UUID_C.to_string()
+                Value::from(UUID_C)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -865,7 +995,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_C)),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                UUID_A.to_string()
+                Value::from(UUID_A)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -932,7 +1062,7 @@ mod tests {
             filter!(f_or!([f_eq("uuid", UUID_C), f_eq("uuid", UUID_D),])),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                UUID_A.to_string()
+                Value::from(UUID_A)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -1002,7 +1132,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_A)),
             ModifyList::new_list(vec![Modify::Removed(
                 "member".to_string(),
-                UUID_B.to_string()
+                Value::from(UUID_B)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -1043,7 +1173,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_A)),
             ModifyList::new_list(vec![Modify::Removed(
                 "member".to_string(),
-                UUID_B.to_string()
+                Value::from(UUID_B)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -1103,7 +1233,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_B)),
             ModifyList::new_list(vec![Modify::Removed(
                 "member".to_string(),
-                UUID_C.to_string()
+                Value::from(UUID_C)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -1173,7 +1303,7 @@ mod tests {
             filter!(f_eq("uuid", UUID_C)),
             ModifyList::new_list(vec![Modify::Removed(
                 "member".to_string(),
-                UUID_A.to_string()
+                Value::from(UUID_A)
             )]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
@@ -1261,8 +1391,8 @@ mod tests {
             preload,
             filter!(f_eq("uuid", UUID_C)),
             ModifyList::new_list(vec![
-                Modify::Removed("member".to_string(), UUID_A.to_string()),
-                Modify::Removed("member".to_string(), UUID_D.to_string()),
+                Modify::Removed("member".to_string(), Value::from(UUID_A)),
+                Modify::Removed("member".to_string(), Value::from(UUID_D)),
             ]),
             None,
             |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
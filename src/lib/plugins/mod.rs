@@ -1,15 +1,51 @@
+// The plugin pipeline.
+//
+// Features that must hold across every write - memberof, refint, attribute
+// uniqueness, dyngroups and so on - are plugins, rather than code in the
+// server's operations. The server calls each stage of an operation on
+// Plugins, which runs the plugins registered for that stage in the order
+// listed in its run_ function:
+//
+//   create: pre_create_transform, (schema), pre_create, (write), post_create
+//   modify: pre_modify, (schema), (write), post_modify
+//   delete: pre_delete, (write), post_delete
+//   replication: (write), post_repl
+//
+// Pre hooks may change or refuse the candidates. Post hooks see what was
+// written, and may make further internal operations - which pass through
+// the pipeline in turn. The first plugin to fail stops the stage, later
+// plugins are not run, and the operation fails with its error. As nothing
+// is committed until the transaction is, the caller drops the transaction
+// and every plugin's changes go with it.
+//
+// Each plugin runs in its own audit scope named by its id, so the audit log
+// shows what each did. verify checks the plugins' invariants over the whole
+// database, in the order of verify_ids.
+//
+// A plugin implements only the hooks of the stages it is registered in - the
+// defaults fail, so a registration without an implementation is caught by
+// the first test to reach it.
+
 use crate::audit::AuditScope;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
 use crate::error::{ConsistencyError, OperationError};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
 use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
+use std::collections::BTreeSet;
 
 #[macro_use]
 mod macros;
 
+mod acp_metadata;
+mod acp_test;
+mod attrunique;
 mod base;
+mod break_glass;
+mod dyngroup;
 mod failure;
+mod managed;
 mod memberof;
+mod password;
 mod protected;
 mod recycle;
 mod refint;
@@ -95,6 +131,31 @@ trait Plugin {
         Err(OperationError::Plugin)
     }
 
+    // Called with the uuids of the entries a replication pull changed, once
+    // they are all written. They were made as their supplier had them, and
+    // can't be refused, so this brings what derives from them here back in
+    // line instead.
+    fn post_repl(
+        _au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        _uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        debug!("plugin {} has an unimplemented post_repl!", Self::id());
+        Err(OperationError::Plugin)
+    }
+
+    // Called at startup with the entries changed since the recovery point,
+    // whose derived values may not have been brought up to date before the
+    // server stopped. Only plugins that derive values need this.
+    fn recover(
+        _au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        _uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        debug!("plugin {} has an unimplemented recover!", Self::id());
+        Err(OperationError::Plugin)
+    }
+
     fn verify(
         _au: &mut AuditScope,
         _qs: &QueryServerReadTransaction,
@@ -252,6 +313,42 @@ macro_rules! run_post_delete_plugin {
     }};
 }
 
+macro_rules! run_post_repl_plugin {
+    (
+        $au:ident,
+        $qs:ident,
+        $uuids:ident,
+        $target_plugin:ty
+    ) => {{
+        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let r = audit_segment!(audit_scope, || <($target_plugin)>::post_repl(
+            &mut audit_scope,
+            $qs,
+            $uuids,
+        ));
+        $au.append_scope(audit_scope);
+        r
+    }};
+}
+
+macro_rules! run_recover_plugin {
+    (
+        $au:ident,
+        $qs:ident,
+        $uuids:ident,
+        $target_plugin:ty
+    ) => {{
+        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let r = audit_segment!(audit_scope, || <($target_plugin)>::recover(
+            &mut audit_scope,
+            $qs,
+            $uuids,
+        ));
+        $au.append_scope(audit_scope);
+        r
+    }};
+}
+
 macro_rules! run_verify_plugin {
     (
         $au:ident,
@@ -260,11 +357,11 @@ macro_rules! run_verify_plugin {
         $target_plugin:ty
     ) => {{
         let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
-        let mut r = audit_segment!(audit_scope, || <($target_plugin)>::verify(
+        let r = audit_segment!(audit_scope, || <($target_plugin)>::verify(
             &mut audit_scope,
             $qs,
         ));
-        $results.append(&mut r);
+        $results.push((<($target_plugin)>::id(), r));
         $au.append_scope(audit_scope);
     }};
 }
@@ -277,7 +374,16 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base);
+            let res = run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base)
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, memberof::MemberOf)
+                })
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, dyngroup::DynGroup)
+                })
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, password::PasswordHash)
+                });
 
             res
         })
@@ -290,7 +396,10 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_pre_create_plugin!(au, qs, cand, ce, protected::Protected);
+            let res = run_pre_create_plugin!(au, qs, cand, ce, protected::Protected)
+                .and_then(|_| run_pre_create_plugin!(au, qs, cand, ce, break_glass::BreakGlass))
+                .and_then(|_| run_pre_create_plugin!(au, qs, cand, ce, managed::ManagedEntries))
+                .and_then(|_| run_pre_create_plugin!(au, qs, cand, ce, acp_metadata::AcpMetadata));
 
             res
         })
@@ -303,8 +412,13 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_post_create_plugin!(au, qs, cand, ce, refint::ReferentialIntegrity)
-                .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, memberof::MemberOf));
+            let res = run_post_create_plugin!(au, qs, cand, ce, attrunique::AttrUnique)
+                .and_then(|_| {
+                    run_post_create_plugin!(au, qs, cand, ce, refint::ReferentialIntegrity)
+                })
+                .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, dyngroup::DynGroup))
+                .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, memberof::MemberOf))
+                .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, managed::ManagedEntries));
 
             res
         })
@@ -318,7 +432,12 @@ impl Plugins {
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
             let res = run_pre_modify_plugin!(au, qs, cand, me, protected::Protected)
-                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, base::Base));
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, break_glass::BreakGlass))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, base::Base))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, dyngroup::DynGroup))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, managed::ManagedEntries))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, password::PasswordHash))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, acp_metadata::AcpMetadata));
 
             res
         })
@@ -332,11 +451,26 @@ impl Plugins {
         me: &ModifyEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res =
-                run_post_modify_plugin!(au, qs, pre_cand, cand, me, refint::ReferentialIntegrity)
-                    .and_then(|_| {
-                        run_post_modify_plugin!(au, qs, pre_cand, cand, me, memberof::MemberOf)
-                    });
+            let res = run_post_modify_plugin!(au, qs, pre_cand, cand, me, attrunique::AttrUnique)
+                .and_then(|_| {
+                    run_post_modify_plugin!(
+                        au,
+                        qs,
+                        pre_cand,
+                        cand,
+                        me,
+                        refint::ReferentialIntegrity
+                    )
+                })
+                .and_then(|_| {
+                    run_post_modify_plugin!(au, qs, pre_cand, cand, me, dyngroup::DynGroup)
+                })
+                .and_then(|_| {
+                    run_post_modify_plugin!(au, qs, pre_cand, cand, me, memberof::MemberOf)
+                })
+                .and_then(|_| {
+                    run_post_modify_plugin!(au, qs, pre_cand, cand, me, managed::ManagedEntries)
+                });
 
             res
         })
@@ -349,7 +483,8 @@ impl Plugins {
         de: &DeleteEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_pre_delete_plugin!(au, qs, cand, de, protected::Protected);
+            let res = run_pre_delete_plugin!(au, qs, cand, de, protected::Protected)
+                .and_then(|_| run_pre_delete_plugin!(au, qs, cand, de, managed::ManagedEntries));
             res
         })
     }
@@ -361,21 +496,129 @@ impl Plugins {
         de: &DeleteEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_post_delete_plugin!(au, qs, cand, de, refint::ReferentialIntegrity)
+            // Managed entries must find companions by their origin before refint
+            // removes the reference.
+            let res = run_post_delete_plugin!(au, qs, cand, de, managed::ManagedEntries)
+                .and_then(|_| {
+                    run_post_delete_plugin!(au, qs, cand, de, refint::ReferentialIntegrity)
+                })
                 .and_then(|_| run_post_delete_plugin!(au, qs, cand, de, memberof::MemberOf));
 
             res
         })
     }
 
+    pub fn run_post_repl(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        audit_segment!(au, || {
+            let res = run_post_repl_plugin!(au, qs, uuids, refint::ReferentialIntegrity)
+                .and_then(|_| run_post_repl_plugin!(au, qs, uuids, dyngroup::DynGroup))
+                .and_then(|_| run_post_repl_plugin!(au, qs, uuids, memberof::MemberOf));
+            res
+        })
+    }
+
+    pub fn run_recover(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        audit_segment!(au, || {
+            let res = run_recover_plugin!(au, qs, uuids, memberof::MemberOf);
+            res
+        })
+    }
+
+    // The plugins run_verify checks with, in order.
+    pub fn verify_ids() -> Vec<&'static str> {
+        vec![
+            base::Base::id(),
+            attrunique::AttrUnique::id(),
+            refint::ReferentialIntegrity::id(),
+            memberof::MemberOf::id(),
+            dyngroup::DynGroup::id(),
+            managed::ManagedEntries::id(),
+            acp_test::AcpTest::id(),
+        ]
+    }
+
+    // The results of each plugin's verify, by the plugin.
     pub fn run_verify(
         au: &mut AuditScope,
         qs: &QueryServerReadTransaction,
-    ) -> Vec<Result<(), ConsistencyError>> {
+    ) -> Vec<(&'static str, Vec<Result<(), ConsistencyError>>)> {
         let mut results = Vec::new();
         run_verify_plugin!(au, qs, &mut results, base::Base);
+        run_verify_plugin!(au, qs, &mut results, attrunique::AttrUnique);
         run_verify_plugin!(au, qs, &mut results, refint::ReferentialIntegrity);
         run_verify_plugin!(au, qs, &mut results, memberof::MemberOf);
+        run_verify_plugin!(au, qs, &mut results, dyngroup::DynGroup);
+        run_verify_plugin!(au, qs, &mut results, managed::ManagedEntries);
+        run_verify_plugin!(au, qs, &mut results, acp_test::AcpTest);
         results
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+
+    #[test]
+    fn test_plugins_short_circuit() {
+        // refint refuses the dangling reference, so the plugins after it in
+        // post_create - here managed entries - never run.
+        let template: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "entry_template"],
+                    "name": ["private"],
+                    "template_filter": ["{\"Eq\":[\"name\",\"pl_a\"]}"],
+                    "template_class": ["group"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        let account: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "account"],
+                    "name": ["pl_a"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63c01"],
+                    "displayname": ["pl_a"],
+                    "managed_by": ["cc8e95b4-c24f-4d68-ba54-8bed76f63c02"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+
+        let preload = vec![template];
+        let create = vec![account];
+        run_create_test!(
+            Err(OperationError::Plugin),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                let comps = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq(
+                            "managed_origin",
+                            "cc8e95b4-c24f-4d68-ba54-8bed76f63c01"
+                        )),
+                    )
+                    .expect("search failed");
+                assert!(comps.len() == 0);
+            }
+        );
+    }
+}
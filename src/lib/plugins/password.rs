@@ -0,0 +1,242 @@
+// Hash passwords and host service secrets before they are written.
+//
+// Clients set a password by giving the cleartext as a value of the password
+// attribute, in a create or modify like any other. Whatever isn't already a
+// stored hash is replaced with one here, so the cleartext never reaches the
+// backend, the changelog or a replica. Service secrets are only compared,
+// never read back, so they are kept the same way.
+//
+// Only the server itself may write a value that is already a stored hash.
+// Otherwise a client could set a hash it made offline, and skip the policy
+// applied to cleartext, or copy one account's hash onto another.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew};
+use crate::error::{OperationError, SchemaError};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::idm::credential::Password;
+use crate::modify::Modify;
+use crate::server::QueryServerWriteTransaction;
+use crate::value::Value;
+
+pub struct PasswordHash {}
+
+static HASHED_ATTRS: [&'static str; 2] = ["password", "service_secret"];
+
+fn hash_passwords<STATE>(
+    au: &mut AuditScope,
+    cand: &mut Entry<EntryInvalid, STATE>,
+) -> Result<(), OperationError>
+where
+    STATE: Copy,
+{
+    HASHED_ATTRS
+        .iter()
+        .try_for_each(|attr| hash_values(au, cand, attr))
+}
+
+fn hash_values<STATE>(
+    au: &mut AuditScope,
+    cand: &mut Entry<EntryInvalid, STATE>,
+    attr: &str,
+) -> Result<(), OperationError>
+where
+    STATE: Copy,
+{
+    let values = match cand.get_ava(attr) {
+        Some(vs) => vs,
+        None => return Ok(()),
+    };
+    if values.iter().all(is_stored) {
+        return Ok(());
+    }
+    let mut hashed = values
+        .iter()
+        .map(|v| {
+            let s = v.to_string();
+            match Password::from_stored(s.as_str()) {
+                Some(_) => Ok(v.clone()),
+                None => Password::new(s.as_str()).map(|p| Value::from(p.to_string())),
+            }
+        })
+        .collect::<Result<Vec<Value>, OperationError>>()?;
+    hashed.sort();
+    audit_log!(au, "hashed {} new {} values", hashed.len(), attr);
+    cand.set_avas(attr, hashed);
+    Ok(())
+}
+
+fn is_stored(v: &Value) -> bool {
+    Password::from_stored(v.to_string().as_str()).is_some()
+}
+
+fn refuse_stored(au: &mut AuditScope, attr: &str) -> Result<(), OperationError> {
+    audit_log!(
+        au,
+        "refusing a stored {} value from an external event",
+        attr
+    );
+    Err(OperationError::SchemaViolation(
+        SchemaError::InvalidAttributeSyntax,
+    ))
+}
+
+impl Plugin for PasswordHash {
+    fn id() -> &'static str {
+        "plugin_password_hash"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        if !ce.event.is_internal() {
+            for attr in HASHED_ATTRS.iter() {
+                let stored = cand
+                    .iter()
+                    .filter_map(|e| e.get_ava(attr))
+                    .any(|vs| vs.iter().any(is_stored));
+                if stored {
+                    return refuse_stored(au, attr);
+                }
+            }
+        }
+        cand.iter_mut().try_for_each(|e| hash_passwords(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        if !me.event.is_internal() {
+            for m in me.modlist.iter() {
+                match m {
+                    Modify::Present(a, v) if HASHED_ATTRS.contains(&a.as_str()) && is_stored(v) => {
+                        return refuse_stored(au, a);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        cand.iter_mut().try_for_each(|e| hash_passwords(au, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::constants::JSON_ADMIN_V1;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::{OperationError, SchemaError};
+    use crate::idm::credential::Password;
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+
+    static JSON_PERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["account", "object"],
+            "name": ["testperson"],
+            "displayname": ["Test Person"],
+            "password": ["first password"]
+        }
+    }"#;
+
+    fn check_password(au: &mut AuditScope, qs: &QueryServerWriteTransaction, cleartext: &str) {
+        let cands = qs
+            .internal_search(au, filter!(f_eq("name", "testperson")))
+            .expect("Internal search failure");
+        let e = cands.first().expect("No cand");
+        let stored = e.get_ava("password").expect("No password");
+        assert!(stored.len() == 1);
+        let p = Password::from_stored(stored[0].to_string().as_str()).expect("Not hashed");
+        assert!(p.verify(cleartext));
+    }
+
+    #[test]
+    fn test_password_hash_create() {
+        let e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_PERSON).expect("json parse failure");
+        let preload: Vec<Entry<EntryInvalid, EntryNew>> = Vec::new();
+        let create = vec![e];
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                check_password(au, qs, "first password")
+            }
+        );
+    }
+
+    #[test]
+    fn test_password_hash_modify() {
+        let e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_PERSON).expect("json parse failure");
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", "testperson")),
+            modlist!([m_purge("password"), m_pres("password", "second password")]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                check_password(au, qs, "second password")
+            }
+        );
+    }
+
+    #[test]
+    fn test_password_stored_external_refused() {
+        let acp: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "access_control_profile", "access_control_search", "access_control_modify"],
+                "name": ["idm_admins_acp_password_test"],
+                "uuid": ["0c3a9b5e-58e5-4d1a-a6e4-6f0ea0d2c3b1"],
+                "description": ["Builtin IDM Administrators Access Controls."],
+                "acp_enable": ["true"],
+                "acp_receiver": [
+                    "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+                ],
+                "acp_targetscope": [
+                    "{\"Eq\":[\"name\",\"testperson\"]}"
+                ],
+                "acp_search_attr": ["name", "class"],
+                "acp_modify_removedattr": ["password"],
+                "acp_modify_presentattr": ["password"]
+            }
+        }"#,
+        )
+        .expect("json parse failure");
+        let e: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_PERSON).expect("json parse failure");
+        let preload = vec![acp, e];
+        let stored = Password::new("second password")
+            .expect("hash failure")
+            .to_string();
+
+        run_modify_test!(
+            Err(OperationError::SchemaViolation(
+                SchemaError::InvalidAttributeSyntax
+            )),
+            preload,
+            filter!(f_eq("name", "testperson")),
+            modlist!([m_purge("password"), m_pres("password", stored.as_str())]),
+            Some(JSON_ADMIN_V1),
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                check_password(au, qs, "first password")
+            }
+        );
+    }
+}
@@ -15,6 +15,8 @@ pub struct Protected {}
 // Here is the declaration of all the attrs that can be altered by
 // a call on a system object. We trust they are allowed because
 // schema will have checked this, and we don't allow class changes!
+// A must can only be removed though - adding one to a system class would
+// leave the existing entries of that class invalid.
 
 lazy_static! {
     static ref ALLOWED_ATTRS: HashSet<&'static str> = {
@@ -78,7 +80,7 @@ impl Plugin for Protected {
             } else {
                 match m {
                     Modify::Present(a, v) => {
-                        if a == "class" && v == "system" {
+                        if a == "class" && v.to_str() == Some("system") {
                             Err(OperationError::SystemProtectedObject)
                         } else {
                             Ok(())
@@ -111,6 +113,9 @@ impl Plugin for Protected {
                 acc
             } else {
                 let a = match m {
+                    Modify::Present(a, _) if a == "must" => {
+                        return Err(OperationError::SystemProtectedObject)
+                    }
                     Modify::Present(a, _) => a,
                     Modify::Removed(a, _) => a,
                     Modify::Purged(a) => a,
@@ -314,6 +319,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pre_modify_system_must_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ADMIN_ALLOW_ALL).expect("json parse failure");
+        let preload = vec![acp];
+
+        // Existing persons would be missing it.
+        run_modify_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            filter!(f_eq("name", "person")),
+            modlist!([m_pres("must", "tag")]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_system_may_allow() {
+        let acp: Entry<EntryInvalid, EntryNew> =
+            serde_json::from_str(JSON_ADMIN_ALLOW_ALL).expect("json parse failure");
+        let preload = vec![acp];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", "person")),
+            modlist!([m_pres("may", "tag")]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
     #[test]
     fn test_pre_delete_deny() {
         let acp: Entry<EntryInvalid, EntryNew> =
@@ -9,7 +9,7 @@
 // when that is written, as they *both* manipulate and alter entry reference
 // data, so we should be careful not to step on each other.
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use crate::audit::AuditScope;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
@@ -20,24 +20,35 @@ use crate::plugins::Plugin;
 use crate::schema::SchemaTransaction;
 use crate::server::QueryServerTransaction;
 use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
+use crate::value::Value;
 
 // NOTE: This *must* be after base.rs!!!
 
 pub struct ReferentialIntegrity;
 
 impl ReferentialIntegrity {
-    fn check_uuid_exists(
+    fn uuid_exists(
         au: &mut AuditScope,
         qs: &QueryServerWriteTransaction,
-        rtype: &String,
-        uuid: &String,
-    ) -> Result<(), OperationError> {
+        uuid: &Value,
+    ) -> Result<bool, OperationError> {
         let mut au_qs = AuditScope::new("qs_exist");
-        let filt_in = filter!(f_eq("uuid", uuid));
-        let r = qs.internal_exists(&mut au_qs, filt_in);
+        let uuid_s = uuid.to_string();
+        // The reference has passed schema, so is a normalised uuid.
+        let filt_in =
+            unsafe { filter!(f_eq("uuid", uuid_s.as_str())).assume_valid(qs.get_schema()) };
+        let r = qs.internal_exists_valid(&mut au_qs, filt_in);
         au.append_scope(au_qs);
+        r
+    }
 
-        let b = try_audit!(au, r);
+    fn check_uuid_exists(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        rtype: &String,
+        uuid: &Value,
+    ) -> Result<(), OperationError> {
+        let b = try_audit!(au, Self::uuid_exists(au, qs, uuid));
         // Is the reference in the qs?
         if b {
             Ok(())
@@ -51,6 +62,56 @@ impl ReferentialIntegrity {
             Err(OperationError::Plugin)
         }
     }
+
+    // Remove every reference to these entries, from everything - including
+    // recycled entries.
+    fn remove_references(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: Vec<&String>,
+    ) -> Result<(), OperationError> {
+        let schema = qs.get_schema();
+        let ref_types = schema.get_reference_types();
+
+        // Generate a filter which is the set of all schema reference types
+        // as EQ to all uuid of all entries in delete. - this INCLUDES recycled
+        // types too!
+        let filt = filter!(FC::Or(
+            uuids
+                .iter()
+                .map(|u| ref_types
+                    .values()
+                    .map(move |r_type| f_eq(r_type.name.as_str(), u)))
+                .flatten()
+                .collect(),
+        ));
+
+        audit_log!(au, "refint remove references filter {:?}", filt);
+
+        // Create a modlist:
+        //    In each, create a "removed" for each attr:uuid pair
+        let modlist: ModifyList<ModifyInvalid> = ModifyList::new_list(
+            uuids
+                .iter()
+                .map(|u| {
+                    ref_types.values().map(move |r_type| {
+                        Modify::Removed(r_type.name.clone(), Value::from(u.as_str()))
+                    })
+                })
+                .flatten()
+                .collect(),
+        );
+
+        audit_log!(au, "refint remove references modlist {:?}", modlist);
+
+        // Do an internal modify to apply the modlist and filter. Both are made
+        // from schema reference types and the uuids of existing entries, so
+        // there is nothing to normalise.
+        let schema = qs.get_schema();
+        let (f_valid, m_valid) =
+            unsafe { (filt.assume_valid(schema), modlist.assume_valid(schema)) };
+        qs.internal_modify_valid(au, f_valid, m_valid)
+    }
 }
 
 impl Plugin for ReferentialIntegrity {
@@ -138,47 +199,60 @@ impl Plugin for ReferentialIntegrity {
         // Delete is pretty different to the other pre checks. This is
         // actually the bulk of the work we'll do to clean up references
         // when they are deleted.
+        Self::remove_references(au, qs, cand.iter().map(|e| e.get_uuid()).collect())
+    }
 
-        // Find all reference types in the schema
-        let schema = qs.get_schema();
-        let ref_types = schema.get_reference_types();
-        // Get the UUID of all entries we are deleting
-        let uuids: Vec<&String> = cand.iter().map(|e| e.get_uuid()).collect();
-
-        // Generate a filter which is the set of all schema reference types
-        // as EQ to all uuid of all entries in delete. - this INCLUDES recycled
-        // types too!
-        let filt = filter!(FC::Or(
-            uuids
-                .iter()
-                .map(|u| ref_types
-                    .values()
-                    .map(move |r_type| f_eq(r_type.name.as_str(), u)))
-                .flatten()
-                .collect(),
-        ));
-
-        audit_log!(au, "refint post_delete filter {:?}", filt);
-
-        // Create a modlist:
-        //    In each, create a "removed" for each attr:uuid pair
-        let modlist: ModifyList<ModifyInvalid> = ModifyList::new_list(
-            uuids
-                .iter()
-                .map(|u| {
-                    ref_types
-                        .values()
-                        .map(move |r_type| Modify::Removed(r_type.name.clone(), u.to_string()))
-                })
-                .flatten()
-                .collect(),
+    // A replicated change can't be refused for a dangling reference, as its
+    // supplier has already accepted it. Instead the references are dropped,
+    // as if the entry referred to had just been deleted here: references to
+    // the changed entries that are now deleted, and references the changed
+    // entries hold to entries deleted here first.
+    fn post_repl(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        uuids: &BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        let live = try_audit!(
+            au,
+            qs.internal_search(
+                au,
+                filter!(f_or(
+                    uuids.iter().map(|u| f_eq("uuid", u.as_str())).collect()
+                ))
+            )
         );
+        let gone: Vec<&String> = uuids
+            .iter()
+            .filter(|u| live.iter().all(|e| e.get_uuid() != *u))
+            .collect();
+        if !gone.is_empty() {
+            Self::remove_references(au, qs, gone)?;
+        }
 
-        audit_log!(au, "refint post_delete modlist {:?}", modlist);
-
-        // Do an internal modify to apply the modlist and filter.
-
-        qs.internal_modify(au, filt, modlist)
+        let ref_types: Vec<String> = qs
+            .get_schema()
+            .get_reference_types()
+            .keys()
+            .map(|k| (*k).clone())
+            .collect();
+        let mut batch = Vec::new();
+        for e in live.iter() {
+            let mut mods = Vec::new();
+            for rtype in ref_types.iter() {
+                for v in e.get_ava(rtype).into_iter().flatten() {
+                    if !try_audit!(au, Self::uuid_exists(au, qs, v)) {
+                        audit_log!(au, "{} drops dangling {}:{}", e.get_uuid(), rtype, v);
+                        mods.push(Modify::Removed(rtype.clone(), v.clone()));
+                    }
+                }
+            }
+            if !mods.is_empty() {
+                batch.push((e.get_uuid().clone(), unsafe {
+                    ModifyList::new_list(mods).assume_valid(qs.get_schema())
+                }));
+            }
+        }
+        qs.internal_modify_batch_valid(au, batch)
     }
 
     fn verify(
@@ -197,9 +271,10 @@ impl Plugin for ReferentialIntegrity {
             Err(e) => return vec![e],
         };
 
-        let acu: Vec<&String> = all_cand.iter().map(|e| e.get_uuid()).collect();
-
-        let acu_map: HashMap<&String, ()> = acu.into_iter().map(|v| (v, ())).collect();
+        let acu_map: HashMap<String, ()> = all_cand
+            .iter()
+            .map(|e| (e.get_uuid().clone(), ()))
+            .collect();
 
         let schema = qs.get_schema();
         let ref_types = schema.get_reference_types();
@@ -214,7 +289,7 @@ impl Plugin for ReferentialIntegrity {
                     Some(vs) => {
                         // For each value in the set.
                         for v in vs {
-                            if acu_map.get(v).is_none() {
+                            if acu_map.get(&v.to_string()).is_none() {
                                 res.push(Err(ConsistencyError::RefintNotUpheld(c.get_id())))
                             }
                         }
@@ -236,6 +311,7 @@ mod tests {
     use crate::error::OperationError;
     use crate::modify::{Modify, ModifyList};
     use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::Value;
 
     // The create references a uuid that doesn't exist - reject
     #[test]
@@ -387,7 +463,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_b")),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                "d2b496bd-8493-47b7-8142-f568b5cf47ee".to_string()
+                Value::from("d2b496bd-8493-47b7-8142-f568b5cf47ee")
             )]),
             None,
             |_, _| {}
@@ -418,7 +494,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_b")),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                "d2b496bd-8493-47b7-8142-f568b5cf47ee".to_string()
+                Value::from("d2b496bd-8493-47b7-8142-f568b5cf47ee")
             )]),
             None,
             |_, _| {}
@@ -493,7 +569,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_a")),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                "d2b496bd-8493-47b7-8142-f568b5cf47ee".to_string()
+                Value::from("d2b496bd-8493-47b7-8142-f568b5cf47ee")
             )]),
             None,
             |_, _| {}
@@ -538,7 +614,7 @@ mod tests {
             filter!(f_eq("name", "testgroup_b")),
             ModifyList::new_list(vec![Modify::Present(
                 "member".to_string(),
-                "d2b496bd-8493-47b7-8142-f568b5cf47ee".to_string()
+                Value::from("d2b496bd-8493-47b7-8142-f568b5cf47ee")
             )]),
             None,
             |_, _| {}
@@ -1,31 +1,71 @@
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
+use futures::sync::mpsc::Receiver;
+use serde_json::Value as JsonValue;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use crate::audit::AuditScope;
 use crate::be::Backend;
 
+use crate::anomaly::{AlertLogger, AlertWebhook, AnomalyThresholds};
 use crate::async_log::EventLog;
+use crate::changes::{ChangeBus, ChangeLogger, ChangeSubscriber};
 use crate::error::OperationError;
 use crate::event::{
-    AuthEvent, CreateEvent, DeleteEvent, ModifyEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
-    SearchEvent, SearchResult, WhoamiResult,
+    AccountExpiryEvent, AcpCoverageEvent, AuditLogEvent, AuthEvent, BackupEvent, BatchEvent,
+    CompareEvent, CreateEvent, CredentialResetIssueEvent, DbCheckpointEvent, DeleteEvent,
+    DeletePreviewEvent, EffectivePermissionsEvent, GroupJoinCreateEvent, GroupJoinDecideEvent,
+    GroupJoinListEvent, HostSecretRotateEvent, LogLevelEvent, MemoryReportEvent, ModifyEvent,
+    Oauth2AuthoriseEvent, Oauth2TokenEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
+    RadiusSecretReadEvent, RadiusSecretRegenerateEvent, RenameEvent, ReplChangesEvent,
+    ReplConsumeEvent, ReviveRecycledEvent, SearchEvent, SearchResult, SubscriptionExpiryEvent,
+    SyncEvent, TypeaheadEvent, WhoamiResult,
 };
+use crate::filter::FilterLimits;
 use crate::schema::Schema;
 
+use crate::idm::clientcert::ClientCertVerifier;
 use crate::idm::server::IdmServer;
+use crate::ldap::proto::LdapOp;
+use crate::ldap::{self, LdapResponse};
+use crate::repl::ReplConsumer;
+use crate::scim;
 use crate::server::{QueryServer, QueryServerTransaction};
+use crate::subscriptions::Subscriptions;
 
 use crate::proto::v1::{
-    AuthResponse, CreateRequest, DeleteRequest, ModifyRequest, OperationResponse, SearchRequest,
-    SearchResponse, WhoamiResponse,
+    AcpCoverageRequest, AcpCoverageResponse, AuditLogRequest, AuditLogResponse, AuthResponse,
+    AuthState, BackupRequest, BackupResponse, BatchRequest, CompareRequest, CompareResponse,
+    CreateRequest, CredentialResetIssueRequest, CredentialResetRedeemRequest,
+    CredentialResetTokenResponse, DeletePreviewRequest, DeletePreviewResponse, DeleteRequest,
+    EffectivePermissionsRequest, EffectivePermissionsResponse, GroupJoinCreateRequest,
+    GroupJoinDecideRequest, GroupJoinListRequest, GroupJoinListResponse, HostSecretRotateRequest,
+    HostSecretRotateResponse, LogLevelRequest, LogLevelResponse, MemoryReportRequest,
+    MemoryReportResponse, ModifyRequest, Oauth2TokenRequest, Oauth2TokenResponse,
+    OperationResponse, RadiusSecretReadRequest, RadiusSecretRegenerateRequest,
+    RadiusSecretResponse, RenameRequest, ReplChangesRequest, ReplChangesResponse,
+    ReviveRecycledRequest, SearchRecycledRequest, SearchRequest, SearchResponse,
+    SubscriptionNotification, SyncRequest, SyncResponse, TypeaheadRequest, TypeaheadResponse,
+    WhoamiResponse,
 };
 
-use crate::proto::v1::messages::{AuthMessage, WhoamiMessage};
+use crate::proto::v1::messages::{
+    AuthMessage, LdapRequestMessage, Oauth2AuthoriseMessage, RequestMessage, ScimMessage,
+    SshKeysMessage, SubscribeMessage, WhoamiMessage,
+};
 
 pub struct QueryServerV1 {
     log: actix::Addr<EventLog>,
     qs: QueryServer,
     idms: Arc<IdmServer>,
+    subscriptions: Arc<Subscriptions>,
+    repl: actix::Addr<ReplConsumer>,
+    // Where online backups are written, if they're allowed.
+    backup_path: Option<String>,
+    // The file security relevant operations are kept in, if they are.
+    audit_log_path: Option<String>,
 }
 
 impl Actor for QueryServerV1 {
@@ -37,12 +77,24 @@ impl Actor for QueryServerV1 {
 }
 
 impl QueryServerV1 {
-    pub fn new(log: actix::Addr<EventLog>, qs: QueryServer, idms: Arc<IdmServer>) -> Self {
+    pub fn new(
+        log: actix::Addr<EventLog>,
+        qs: QueryServer,
+        idms: Arc<IdmServer>,
+        subscriptions: Arc<Subscriptions>,
+        repl: actix::Addr<ReplConsumer>,
+        backup_path: Option<String>,
+        audit_log_path: Option<String>,
+    ) -> Self {
         log_event!(log, "Starting query server v1 worker ...");
         QueryServerV1 {
             log: log,
             qs: qs,
             idms: idms,
+            subscriptions: subscriptions,
+            repl: repl,
+            backup_path: backup_path,
+            audit_log_path: audit_log_path,
         }
     }
 
@@ -53,7 +105,17 @@ impl QueryServerV1 {
     pub fn start(
         log: actix::Addr<EventLog>,
         be: Backend,
+        domain_name: &str,
         threads: usize,
+        acp_require_metadata: bool,
+        filter_limits: FilterLimits,
+        search_max_results: usize,
+        log_changes: bool,
+        anomaly_thresholds: AnomalyThresholds,
+        anomaly_webhook: Option<String>,
+        client_ca: Option<ClientCertVerifier>,
+        backup_path: Option<String>,
+        audit_log_path: Option<String>,
     ) -> Result<actix::Addr<QueryServerV1>, OperationError> {
         let mut audit = AuditScope::new("server_start");
         let log_inner = log.clone();
@@ -68,7 +130,28 @@ impl QueryServerV1 {
             };
 
             // Create a query_server implementation
-            let query_server = QueryServer::new(be, schema);
+            let mut query_server = QueryServer::new(be, schema);
+            query_server.set_domain_name(domain_name);
+            query_server.set_acp_require_metadata(acp_require_metadata);
+            query_server.set_filter_limits(filter_limits);
+            query_server.set_search_max_results(search_max_results);
+            query_server.set_anomaly_thresholds(anomaly_thresholds);
+
+            // Persistent searches are always offered, so there is always a
+            // bus.
+            let subscriptions = Arc::new(Subscriptions::new(query_server.clone()));
+            let mut subscribers: Vec<Arc<dyn ChangeSubscriber>> = Vec::new();
+            subscribers.push(subscriptions.clone());
+            if log_changes {
+                subscribers.push(Arc::new(ChangeLogger::new(log_inner.clone())));
+            }
+            if anomaly_thresholds.enabled() {
+                subscribers.push(Arc::new(AlertLogger::new(log_inner.clone())));
+                if let Some(url) = &anomaly_webhook {
+                    subscribers.push(Arc::new(AlertWebhook::new(url.as_str())?));
+                }
+            }
+            query_server.set_change_bus(ChangeBus::new(subscribers));
 
             let mut audit_qsc = AuditScope::new("query_server_init");
             // TODO #62: Should the IDM parts be broken out to the IdmServer?
@@ -79,16 +162,34 @@ impl QueryServerV1 {
             // Now search for the schema itself, and validate that the system
             // in memory matches the BE on disk, and that it's syntactically correct.
             // Write it out if changes are needed.
-            query_server.initialise_helper(&mut audit_qsc)?;
+            // The scope is kept even if this fails, as it says what recovery
+            // found and repaired.
+            let r = query_server.initialise_helper(&mut audit_qsc);
+            audit.append_scope(audit_qsc);
+            r?;
 
             // We generate a SINGLE idms only!
 
-            let idms = Arc::new(IdmServer::new(query_server.clone()));
+            let mut idms = IdmServer::new(query_server.clone());
+            if let Some(ca) = client_ca {
+                idms.set_client_ca(ca);
+            }
+            let idms = Arc::new(idms);
 
-            audit.append_scope(audit_qsc);
+            // One consumer, so pulls never overlap each other.
+            let consumer = ReplConsumer::new(log_inner.clone(), query_server.clone())?;
+            let repl_addr = SyncArbiter::start(1, move || consumer.clone());
 
             let x = SyncArbiter::start(threads, move || {
-                QueryServerV1::new(log_inner.clone(), query_server.clone(), idms.clone())
+                QueryServerV1::new(
+                    log_inner.clone(),
+                    query_server.clone(),
+                    idms.clone(),
+                    subscriptions.clone(),
+                    repl_addr.clone(),
+                    backup_path.clone(),
+                    audit_log_path.clone(),
+                )
             });
             Ok(x)
         });
@@ -102,17 +203,26 @@ impl QueryServerV1 {
 // required complete. We still need to do certain validation steps, but
 // at this point our just is just to route to do_<action>
 
-impl Handler<SearchRequest> for QueryServerV1 {
+impl Handler<RequestMessage<SearchRequest>> for QueryServerV1 {
     type Result = Result<SearchResponse, OperationError>;
 
-    fn handle(&mut self, msg: SearchRequest, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("search");
+    fn handle(
+        &mut self,
+        req: RequestMessage<SearchRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id,
+            source,
+            msg,
+        } = req;
+        let mut audit = AuditScope::new_request("search", Some(request_id));
         let res = audit_segment!(&mut audit, || {
             // Begin a read
             let qs_read = self.qs.read();
 
             // Make an event from the request
-            let srch = match SearchEvent::from_request(&mut audit, msg, &qs_read) {
+            let srch = match SearchEvent::from_request(&mut audit, msg, source, &qs_read) {
                 Ok(s) => s,
                 Err(e) => {
                     audit_log!(audit, "Failed to begin search: {:?}", e);
@@ -121,10 +231,25 @@ impl Handler<SearchRequest> for QueryServerV1 {
             };
 
             audit_log!(audit, "Begin event {:?}", srch);
+            audit.set_filter(&srch.filter_orig);
 
-            match qs_read.search_ext(&mut audit, &srch) {
-                Ok(entries) => {
-                    let sr = SearchResult::new(entries);
+            if srch.trace {
+                return qs_read
+                    .search_ext_trace(&mut audit, &srch)
+                    .map(|(entries, trace)| SearchResult::new_trace(entries, trace).response());
+            }
+
+            if srch.sort.is_some() || srch.page.is_some() {
+                return qs_read
+                    .search_ext_page(&mut audit, &srch)
+                    .map(|(entries, cookie)| SearchResult::new_page(entries, cookie).response());
+            }
+
+            match qs_read
+                .search_ext_iter(&mut audit, &srch)
+                .and_then(SearchResult::new_iter)
+            {
+                Ok(sr) => {
                     // Now convert to a response, and return
                     Ok(sr.response())
                 }
@@ -132,16 +257,540 @@ impl Handler<SearchRequest> for QueryServerV1 {
             }
         });
         // At the end of the event we send it for logging.
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<EffectivePermissionsRequest>> for QueryServerV1 {
+    type Result = Result<EffectivePermissionsResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<EffectivePermissionsRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("effective_permissions", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let epe = match EffectivePermissionsEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin effective permissions: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .effective_permissions(&mut audit, &epe)
+                .map(|ep| EffectivePermissionsResponse::new(ep))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<CompareRequest>> for QueryServerV1 {
+    type Result = Result<CompareResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<CompareRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("compare", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let ce = match CompareEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin compare: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .compare(&mut audit, &ce)
+                .map(|m| CompareResponse::new(m))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<DeletePreviewRequest>> for QueryServerV1 {
+    type Result = Result<DeletePreviewResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<DeletePreviewRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("delete_preview", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let dpe = match DeletePreviewEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin delete preview: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .delete_preview(&mut audit, &dpe)
+                .map(|p| DeletePreviewResponse::new(p))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<MemoryReportRequest>> for QueryServerV1 {
+    type Result = Result<MemoryReportResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<MemoryReportRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("memory_report", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let mre = match MemoryReportEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin memory report: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .memory_report(&mut audit, &mre)
+                .map(|r| MemoryReportResponse::new(r))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<ReplChangesRequest>> for QueryServerV1 {
+    type Result = Result<ReplChangesResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<ReplChangesRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("replication_changes", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let rce = match ReplChangesEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin replication changes: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read.repl_changes(&mut audit, &rce)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<AcpCoverageRequest>> for QueryServerV1 {
+    type Result = Result<AcpCoverageResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<AcpCoverageRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("acp_coverage", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let ace = match AcpCoverageEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin acp coverage: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .acp_coverage(&mut audit, &ace)
+                .map(|c| AcpCoverageResponse { coverage: c })
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<BackupRequest>> for QueryServerV1 {
+    type Result = Result<BackupResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<BackupRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("backup", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let bue = match BackupEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin backup: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            let dir = match &self.backup_path {
+                Some(d) => d,
+                None => return Err(OperationError::BackupNotConfigured),
+            };
+            // Named by the time, so earlier backups are never overwritten.
+            let now = DateTime::<Utc>::from(UNIX_EPOCH + qs_read.now());
+            let name = format!("backup-{}.json", now.format("%Y%m%dT%H%M%S%.3fZ"));
+            let path = PathBuf::from(dir).join(&name);
+            let path = path.to_str().ok_or(OperationError::FsError)?;
+
+            qs_read
+                .backup(&mut audit, &bue, path)
+                .map(|_| BackupResponse { name: name })
+        });
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
 }
 
-impl Handler<CreateRequest> for QueryServerV1 {
+impl Handler<RequestMessage<AuditLogRequest>> for QueryServerV1 {
+    type Result = Result<AuditLogResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<AuditLogRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("audit_log", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let ale = match AuditLogEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin audit log search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            let path = match &self.audit_log_path {
+                Some(p) => p,
+                None => return Err(OperationError::AuditLogNotConfigured),
+            };
+
+            qs_read
+                .audit_log_search(&mut audit, &ale, path.as_str())
+                .map(|records| AuditLogResponse { records: records })
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<SyncRequest>> for QueryServerV1 {
+    type Result = Result<SyncResponse, OperationError>;
+
+    fn handle(&mut self, req: RequestMessage<SyncRequest>, _: &mut Self::Context) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("sync", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let se = match SyncEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin sync: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .sync(&mut audit, &se)
+                .map(|(entries, deleted, token)| {
+                    SyncResponse::new(
+                        token.to_string(),
+                        entries.iter().map(|e| e.into_pe()).collect(),
+                        deleted,
+                    )
+                })
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<TypeaheadRequest>> for QueryServerV1 {
+    type Result = Result<TypeaheadResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<TypeaheadRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("typeahead", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let te = match TypeaheadEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin typeahead: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .typeahead(&mut audit, &te)
+                .map(TypeaheadResponse::new)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<GroupJoinListRequest>> for QueryServerV1 {
+    type Result = Result<GroupJoinListResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<GroupJoinListRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("group_join_list", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let gle = match GroupJoinListEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin group join list: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read
+                .group_join_list(&mut audit, &gle)
+                .map(GroupJoinListResponse::new)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<GroupJoinCreateRequest>> for QueryServerV1 {
+    type Result = Result<OperationResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<GroupJoinCreateRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("group_join_create", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let gce = match GroupJoinCreateEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin group join create: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .group_join_create(&mut audit, &gce)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<GroupJoinDecideRequest>> for QueryServerV1 {
+    type Result = Result<OperationResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<GroupJoinDecideRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("group_join_decide", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let gde = match GroupJoinDecideEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin group join decide: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .group_join_decide(&mut audit, &gde)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<SearchRecycledRequest>> for QueryServerV1 {
+    type Result = Result<SearchResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<SearchRecycledRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("search_recycled", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let srch = match SearchEvent::from_rec_request(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin recycled search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+            audit.set_filter(&srch.filter_orig);
+
+            qs_read
+                .search_ext_iter(&mut audit, &srch)
+                .and_then(SearchResult::new_iter)
+                .map(|sr| sr.response())
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<ReviveRecycledRequest>> for QueryServerV1 {
     type Result = Result<OperationResponse, OperationError>;
 
-    fn handle(&mut self, msg: CreateRequest, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("create");
+    fn handle(
+        &mut self,
+        req: RequestMessage<ReviveRecycledRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("revive_recycled", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let rre = match ReviveRecycledEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(r) => r,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin revive: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin revive event {:?}", rre);
+
+            qs_write
+                .revive_recycled(&mut audit, &rre)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<CreateRequest>> for QueryServerV1 {
+    type Result = Result<OperationResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<CreateRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("create", Some(request_id));
         let res = audit_segment!(&mut audit, || {
             let mut qs_write = self.qs.write();
 
@@ -160,16 +809,24 @@ impl Handler<CreateRequest> for QueryServerV1 {
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
         // At the end of the event we send it for logging.
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
 }
 
-impl Handler<ModifyRequest> for QueryServerV1 {
+impl Handler<RequestMessage<ModifyRequest>> for QueryServerV1 {
     type Result = Result<OperationResponse, OperationError>;
 
-    fn handle(&mut self, msg: ModifyRequest, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("modify");
+    fn handle(
+        &mut self,
+        req: RequestMessage<ModifyRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("modify", Some(request_id));
         let res = audit_segment!(&mut audit, || {
             let mut qs_write = self.qs.write();
             let mdf = match ModifyEvent::from_request(&mut audit, msg, &qs_write) {
@@ -181,21 +838,62 @@ impl Handler<ModifyRequest> for QueryServerV1 {
             };
 
             audit_log!(audit, "Begin modify event {:?}", mdf);
+            audit.set_filter(&mdf.filter_orig);
+            audit.set_modlist(&mdf.modlist);
 
             qs_write
                 .modify(&mut audit, &mdf)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
 }
 
-impl Handler<DeleteRequest> for QueryServerV1 {
+impl Handler<RequestMessage<BatchRequest>> for QueryServerV1 {
     type Result = Result<OperationResponse, OperationError>;
 
-    fn handle(&mut self, msg: DeleteRequest, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("delete");
+    fn handle(&mut self, req: RequestMessage<BatchRequest>, _: &mut Self::Context) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("batch", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+            let be = match BatchEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(b) => b,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin batch: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin batch event {:?}", be);
+
+            // On any failure qs_write is dropped, so nothing is committed.
+            qs_write
+                .batch(&mut audit, &be)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<DeleteRequest>> for QueryServerV1 {
+    type Result = Result<OperationResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<DeleteRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("delete", Some(request_id));
         let res = audit_segment!(&mut audit, || {
             let mut qs_write = self.qs.write();
 
@@ -208,11 +906,240 @@ impl Handler<DeleteRequest> for QueryServerV1 {
             };
 
             audit_log!(audit, "Begin delete event {:?}", del);
+            audit.set_filter(&del.filter_orig);
 
             qs_write
                 .delete(&mut audit, &del)
                 .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
         });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<RenameRequest>> for QueryServerV1 {
+    type Result = Result<OperationResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<RenameRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("rename", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let re = match RenameEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(r) => r,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin rename: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .rename(&mut audit, &re)
+                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<HostSecretRotateRequest>> for QueryServerV1 {
+    type Result = Result<HostSecretRotateResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<HostSecretRotateRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("host_secret_rotate", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let hre = match HostSecretRotateEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin host secret rotate: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .rotate_host_secret(&mut audit, &hre)
+                .and_then(|r| qs_write.commit(&mut audit).map(|_| r))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<RadiusSecretRegenerateRequest>> for QueryServerV1 {
+    type Result = Result<RadiusSecretResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<RadiusSecretRegenerateRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("radius_secret_regenerate", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let rre = match RadiusSecretRegenerateEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin radius secret regenerate: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .regenerate_radius_secret(&mut audit, &rre)
+                .and_then(|r| qs_write.commit(&mut audit).map(|_| r))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<CredentialResetIssueRequest>> for QueryServerV1 {
+    type Result = Result<CredentialResetTokenResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<CredentialResetIssueRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("credential_reset_issue", Some(request_id));
+        audit.set_security_relevant();
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let cre = match CredentialResetIssueEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin credential reset issue: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .issue_credential_reset(&mut audit, &cre)
+                .and_then(|r| qs_write.commit(&mut audit).map(|_| r))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// The token and password are never logged.
+impl Handler<RequestMessage<CredentialResetRedeemRequest>> for QueryServerV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<CredentialResetRedeemRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("credential_reset_redeem", Some(request_id));
+        audit.set_security_relevant();
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+            qs_write
+                .redeem_credential_reset(&mut audit, msg.token.as_str(), msg.password.as_str())
+                .and_then(|_| qs_write.commit(&mut audit))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<RadiusSecretReadRequest>> for QueryServerV1 {
+    type Result = Result<RadiusSecretResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<RadiusSecretReadRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("radius_secret_read", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let rre = match RadiusSecretReadEvent::from_request(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin radius secret read: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_read.radius_secret_read(&mut audit, &rre)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<LogLevelRequest>> for QueryServerV1 {
+    type Result = Result<LogLevelResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<LogLevelRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("log_level", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let lle = match LogLevelEvent::from_request(&mut audit, msg, &qs_write) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin log level change: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            qs_write
+                .set_log_levels(&mut audit, &lle)
+                .and_then(|levels| {
+                    qs_write
+                        .commit(&mut audit)
+                        .map(|_| LogLevelResponse { levels: levels })
+                })
+        });
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
@@ -222,15 +1149,19 @@ impl Handler<DeleteRequest> for QueryServerV1 {
 // requires a lock ...
 // needs session id, entry, etc.
 
-impl Handler<AuthMessage> for QueryServerV1 {
+impl Handler<RequestMessage<AuthMessage>> for QueryServerV1 {
     type Result = Result<AuthResponse, OperationError>;
 
-    fn handle(&mut self, msg: AuthMessage, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, req: RequestMessage<AuthMessage>, _: &mut Self::Context) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
         // This is probably the first function that really implements logic
         // "on top" of the db server concept. In this case we check if
         // the credentials provided is sufficient to say if someone is
         // "authenticated" or not.
-        let mut audit = AuditScope::new("auth");
+        let mut audit = AuditScope::new_request("auth", Some(request_id));
+        audit.set_security_relevant();
         let res = audit_segment!(&mut audit, || {
             audit_log!(audit, "Begin auth event {:?}", msg);
 
@@ -240,6 +1171,7 @@ impl Handler<AuthMessage> for QueryServerV1 {
 
             let mut idm_write = self.idms.write();
 
+            let source = msg.source.clone();
             let ae = try_audit!(audit, AuthEvent::from_message(msg));
 
             // Generally things like auth denied are in Ok() msgs
@@ -250,19 +1182,158 @@ impl Handler<AuthMessage> for QueryServerV1 {
 
             audit_log!(audit, "Sending result -> {:?}", r);
             // Build the result.
-            r.map(|r| r.response())
+            r.map(|r| r.response()).map(|r| {
+                if let AuthState::Denied(_) = r.state {
+                    let source = source.as_ref().map(|s| s.as_str()).unwrap_or("unknown");
+                    if let Some(a) = self.qs.record_auth_failure(source) {
+                        audit_log!(audit, "security alert: {:?}", a);
+                    }
+                }
+                r
+            })
         });
         // At the end of the event we send it for logging.
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<Oauth2AuthoriseMessage>> for QueryServerV1 {
+    type Result = Result<String, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<Oauth2AuthoriseMessage>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("oauth2_authorise", Some(request_id));
+        audit.set_security_relevant();
+        let res = audit_segment!(&mut audit, || {
+            let mut idm_write = self.idms.write();
+            let ae = Oauth2AuthoriseEvent::from_message(msg);
+            idm_write
+                .oauth2_authorise(&mut audit, &ae)
+                .and_then(|r| idm_write.commit().map(|_| r))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<LdapRequestMessage>> for QueryServerV1 {
+    type Result = Result<LdapResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<LdapRequestMessage>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("ldap", Some(request_id));
+        // Binds are auths. The message isn't logged, as a bind holds the
+        // password.
+        if let LdapOp::SimpleBind { .. } = msg.msg.op {
+            audit.set_security_relevant();
+        }
+        let res = audit_segment!(&mut audit, || {
+            Ok(ldap::handle(
+                &mut audit, &self.qs, &self.idms, msg.uat, msg.source, msg.secure, msg.msg,
+            ))
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<ScimMessage>> for QueryServerV1 {
+    type Result = Result<Option<JsonValue>, OperationError>;
+
+    fn handle(&mut self, req: RequestMessage<ScimMessage>, _: &mut Self::Context) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("scim", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            // Not the resource, as a provisioning source may send a user's
+            // password with it.
+            audit_log!(audit, "Begin scim event on {:?}", msg.rt);
+            scim::handle(&mut audit, &self.qs, msg.uat, msg.rt, msg.op)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<SubscribeMessage>> for QueryServerV1 {
+    type Result = Result<Receiver<SubscriptionNotification>, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<SubscribeMessage>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("subscribe", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin subscribe event {:?}", msg.req);
+            self.subscriptions.subscribe(&mut audit, msg.uat, msg.req)
+        });
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<Oauth2TokenRequest>> for QueryServerV1 {
+    type Result = Result<Oauth2TokenResponse, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<Oauth2TokenRequest>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("oauth2_token", Some(request_id));
+        audit.set_security_relevant();
+        let res = audit_segment!(&mut audit, || {
+            let mut idm_write = self.idms.write();
+            let te = Oauth2TokenEvent::from_request(msg);
+            // A code is used up even when the exchange fails, so this always
+            // commits.
+            let r = idm_write.oauth2_token(&mut audit, &te);
+            idm_write.commit().and_then(|_| r)
+        });
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
 }
 
-impl Handler<WhoamiMessage> for QueryServerV1 {
+impl Handler<RequestMessage<WhoamiMessage>> for QueryServerV1 {
     type Result = Result<WhoamiResponse, OperationError>;
 
-    fn handle(&mut self, msg: WhoamiMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("whoami");
+    fn handle(
+        &mut self,
+        req: RequestMessage<WhoamiMessage>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("whoami", Some(request_id));
         let res = audit_segment!(&mut audit, || {
             // TODO #62: Move this to IdmServer!!!
             // Begin a read
@@ -284,6 +1355,7 @@ impl Handler<WhoamiMessage> for QueryServerV1 {
             };
 
             audit_log!(audit, "Begin event {:?}", srch);
+            audit.set_filter(&srch.filter_orig);
 
             match qs_read.search_ext(&mut audit, &srch) {
                 Ok(mut entries) => {
@@ -293,7 +1365,7 @@ impl Handler<WhoamiMessage> for QueryServerV1 {
                         1 => {
                             let e = entries.pop().expect("Entry length mismatch!!!");
                             // Now convert to a response, and return
-                            let wr = WhoamiResult::new(e);
+                            let wr = WhoamiResult::new(e, qs_read.get_domain_info());
                             Ok(wr.response())
                         }
                         // Somehow we matched multiple, which should be impossible.
@@ -306,6 +1378,62 @@ impl Handler<WhoamiMessage> for QueryServerV1 {
         });
         // Should we log the final result?
         // At the end of the event we send it for logging.
+        audit.set_decision(&res);
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RequestMessage<SshKeysMessage>> for QueryServerV1 {
+    type Result = Result<Vec<String>, OperationError>;
+
+    fn handle(
+        &mut self,
+        req: RequestMessage<SshKeysMessage>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let RequestMessage {
+            request_id, msg, ..
+        } = req;
+        let mut audit = AuditScope::new_request("ssh_keys", Some(request_id));
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+            let srch = match SearchEvent::from_sshkeys_request(
+                &mut audit,
+                msg.uat,
+                msg.name.as_str(),
+                &qs_read,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin ssh_keys: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+            audit.set_filter(&srch.filter_orig);
+
+            // Keys the caller can't read are reduced away, so an account
+            // they can see without its keys has none.
+            let mut entries = qs_read.search_ext(&mut audit, &srch)?;
+            match entries.len() {
+                0 => Err(OperationError::NoMatchingEntries),
+                1 => {
+                    let e = entries.pop().expect("Entry length mismatch!!!");
+                    Ok(e.get_ava("ssh_publickey")
+                        .map(|vs| {
+                            vs.iter()
+                                .filter_map(|v| v.to_sshkey())
+                                .map(|k| k.to_string())
+                                .collect()
+                        })
+                        .unwrap_or_else(Vec::new))
+                }
+                _ => Err(OperationError::InvalidState),
+            }
+        });
+        audit.set_decision(&res);
         self.log.do_send(audit);
         res
     }
@@ -323,7 +1451,7 @@ impl Handler<PurgeTombstoneEvent> for QueryServerV1 {
             let qs_write = self.qs.write();
 
             let res = qs_write
-                .purge_tombstones(&mut audit)
+                .purge_tombstones(&mut audit, msg.window)
                 .and_then(|_| qs_write.commit(&mut audit));
             audit_log!(audit, "Purge tombstones result: {:?}", res);
             res.expect("Invalid Server State");
@@ -334,6 +1462,73 @@ impl Handler<PurgeTombstoneEvent> for QueryServerV1 {
     }
 }
 
+// Pulls wait on the supplier, so they are handed to the replication actor
+// instead of holding up a worker here.
+impl Handler<ReplConsumeEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReplConsumeEvent, _: &mut Self::Context) -> Self::Result {
+        self.repl.do_send(msg)
+    }
+}
+
+impl Handler<DbCheckpointEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: DbCheckpointEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("db checkpoint");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin db checkpoint event {:?}", msg);
+            let res = self.qs.checkpoint(&mut audit);
+            audit_log!(audit, "Db checkpoint result: {:?}", res);
+            if let Err(e) = res {
+                error!("Db checkpoint failed -> {:?}", e);
+            }
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AccountExpiryEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: AccountExpiryEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("account expiry");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin account expiry event {:?}", msg);
+            let mut qs_write = self.qs.write();
+
+            let res = qs_write
+                .flag_expired_accounts(&mut audit)
+                .and_then(|n| qs_write.commit(&mut audit).map(|_| n));
+            audit_log!(audit, "Account expiry result: {:?}", res);
+            match res {
+                Ok(n) if n > 0 => info!("Account expiry flags changed on {} accounts", n),
+                Ok(_) => {}
+                Err(e) => error!("Account expiry failed -> {:?}", e),
+            }
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SubscriptionExpiryEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscriptionExpiryEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("subscription expiry");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin subscription expiry event {:?}", msg);
+            let n = self.subscriptions.expire(&mut audit);
+            audit_log!(audit, "Ended {} subscriptions", n);
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
 impl Handler<PurgeRecycledEvent> for QueryServerV1 {
     type Result = ();
 
@@ -344,7 +1539,7 @@ impl Handler<PurgeRecycledEvent> for QueryServerV1 {
             let qs_write = self.qs.write();
 
             let res = qs_write
-                .purge_recycled(&mut audit)
+                .purge_recycled(&mut audit, msg.window)
                 .and_then(|_| qs_write.commit(&mut audit));
             audit_log!(audit, "Purge recycled result: {:?}", res);
             res.expect("Invalid Server State");
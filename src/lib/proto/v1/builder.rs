@@ -0,0 +1,190 @@
+// A builder for protocol filters.
+//
+// Clients send filters as the Filter enum, usually as json. Rather than
+// writing that out by hand, this lets a client put together a filter in code:
+//
+//     let f: Filter = FilterBuilder::eq("class", "person")
+//         .and(FilterBuilder::sub("name", "will").or(FilterBuilder::pres("mail")))
+//         .and(FilterBuilder::eq("name", "admin").not())
+//         .into();
+//
+// The builder only arranges the terms. Attribute names and values are checked
+// by the server against schema when the filter is used.
+
+use crate::proto::v1::Filter;
+
+#[derive(Debug, Clone)]
+pub struct FilterBuilder {
+    inner: Filter,
+}
+
+impl FilterBuilder {
+    /// Match entries where attr has exactly this value.
+    pub fn eq(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::Eq(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr contains this value.
+    pub fn sub(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::Sub(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr begins with this value.
+    pub fn starts_with(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::StartsWith(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr finishes with this value.
+    pub fn ends_with(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::EndsWith(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries that have any value for attr.
+    pub fn pres(attr: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::Pres(attr.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr is at or after this value.
+    pub fn ge(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::Ge(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr is at or before this value.
+    pub fn le(attr: &str, value: &str) -> Self {
+        FilterBuilder {
+            inner: Filter::Le(attr.to_string(), value.to_string()),
+        }
+    }
+
+    /// Match entries where some value of attr is within lo and hi, inclusive.
+    pub fn between(attr: &str, lo: &str, hi: &str) -> Self {
+        FilterBuilder::all(vec![
+            FilterBuilder::ge(attr, lo),
+            FilterBuilder::le(attr, hi),
+        ])
+    }
+
+    /// Match the entry of whoever is making the request.
+    pub fn self_uuid() -> Self {
+        FilterBuilder {
+            inner: Filter::SelfUUID,
+        }
+    }
+
+    /// Match entries that match every one of these filters.
+    pub fn all<I>(filters: I) -> Self
+    where
+        I: IntoIterator<Item = FilterBuilder>,
+    {
+        FilterBuilder {
+            inner: Filter::And(filters.into_iter().map(|f| f.inner).collect()),
+        }
+    }
+
+    /// Match entries that match any of these filters.
+    pub fn any<I>(filters: I) -> Self
+    where
+        I: IntoIterator<Item = FilterBuilder>,
+    {
+        FilterBuilder {
+            inner: Filter::Or(filters.into_iter().map(|f| f.inner).collect()),
+        }
+    }
+
+    /// Both this filter and other must match. Chained calls are collected
+    /// into a single and term rather than nesting.
+    pub fn and(self, other: FilterBuilder) -> Self {
+        let inner = match self.inner {
+            Filter::And(mut terms) => {
+                terms.push(other.inner);
+                Filter::And(terms)
+            }
+            f => Filter::And(vec![f, other.inner]),
+        };
+        FilterBuilder { inner }
+    }
+
+    /// Either this filter or other must match. Chained calls are collected
+    /// into a single or term rather than nesting.
+    pub fn or(self, other: FilterBuilder) -> Self {
+        let inner = match self.inner {
+            Filter::Or(mut terms) => {
+                terms.push(other.inner);
+                Filter::Or(terms)
+            }
+            f => Filter::Or(vec![f, other.inner]),
+        };
+        FilterBuilder { inner }
+    }
+
+    /// Match entries that this filter does not.
+    pub fn not(self) -> Self {
+        FilterBuilder {
+            inner: Filter::AndNot(Box::new(self.inner)),
+        }
+    }
+
+    pub fn build(self) -> Filter {
+        self.inner
+    }
+}
+
+impl From<FilterBuilder> for Filter {
+    fn from(fb: FilterBuilder) -> Self {
+        fb.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::v1::builder::FilterBuilder;
+    use crate::proto::v1::Filter;
+
+    #[test]
+    fn test_filter_builder() {
+        let f: Filter = FilterBuilder::eq("class", "person")
+            .and(FilterBuilder::sub("name", "will").or(FilterBuilder::pres("mail")))
+            .and(FilterBuilder::self_uuid().not())
+            .into();
+
+        // The ands are flattened, but the or stays its own term.
+        assert!(
+            serde_json::to_string(&f).expect("JSON failure")
+                == r#"{"And":[{"Eq":["class","person"]},{"Or":[{"Sub":["name","will"]},{"Pres":"mail"}]},{"AndNot":"Self"}]}"#
+        );
+
+        let f = FilterBuilder::any(vec![
+            FilterBuilder::eq("name", "a"),
+            FilterBuilder::eq("name", "b"),
+        ])
+        .build();
+        assert!(
+            serde_json::to_string(&f).expect("JSON failure")
+                == r#"{"Or":[{"Eq":["name","a"]},{"Eq":["name","b"]}]}"#
+        );
+
+        let f = FilterBuilder::starts_with("name", "wi").build();
+        assert!(
+            serde_json::to_string(&f).expect("JSON failure") == r#"{"StartsWith":["name","wi"]}"#
+        );
+
+        let f = FilterBuilder::between("last_auth", "2019-01-01T00:00:00Z", "2019-02-01T00:00:00Z")
+            .build();
+        assert!(
+            serde_json::to_string(&f).expect("JSON failure")
+                == r#"{"And":[{"Ge":["last_auth","2019-01-01T00:00:00Z"]},{"Le":["last_auth","2019-02-01T00:00:00Z"]}]}"#
+        );
+    }
+}
@@ -1,8 +1,16 @@
 use crate::error::OperationError;
+use crate::ldap::proto::LdapMsg;
+use crate::ldap::LdapResponse;
+use crate::scim::{ScimOp, ScimResourceType};
 use actix::prelude::*;
+use futures::sync::mpsc::Receiver;
+use serde_json::Value as JsonValue;
 use uuid::Uuid;
 
-use crate::proto::v1::{AuthRequest, AuthResponse, UserAuthToken, WhoamiResponse};
+use crate::proto::v1::{
+    AuthRequest, AuthResponse, Oauth2AuthoriseRequest, SubscribeRequest, SubscriptionNotification,
+    UserAuthToken, WhoamiResponse,
+};
 
 // These are used when the request (IE Get) has no intrising request
 // type. Additionally, they are used in some requests where we need
@@ -25,17 +33,137 @@ impl Message for WhoamiMessage {
     type Result = Result<WhoamiResponse, OperationError>;
 }
 
+// The ssh public keys of the account with this name, as authorized_keys
+// lines.
+pub struct SshKeysMessage {
+    pub uat: Option<UserAuthToken>,
+    pub name: String,
+}
+
+impl SshKeysMessage {
+    pub fn new(uat: Option<UserAuthToken>, name: String) -> Self {
+        SshKeysMessage {
+            uat: uat,
+            name: name,
+        }
+    }
+}
+
+impl Message for SshKeysMessage {
+    type Result = Result<Vec<String>, OperationError>;
+}
+
+// An oauth2 authorisation request from the browser of an account with a
+// session. The result is where to redirect it to.
+#[derive(Debug)]
+pub struct Oauth2AuthoriseMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: Oauth2AuthoriseRequest,
+}
+
+impl Oauth2AuthoriseMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: Oauth2AuthoriseRequest) -> Self {
+        Oauth2AuthoriseMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for Oauth2AuthoriseMessage {
+    type Result = Result<String, OperationError>;
+}
+
+// One message from an ldap connection, with who the connection is bound as
+// and where it's from.
+pub struct LdapRequestMessage {
+    pub uat: Option<UserAuthToken>,
+    pub source: Option<String>,
+    // The connection is over TLS.
+    pub secure: bool,
+    pub msg: LdapMsg,
+}
+
+impl LdapRequestMessage {
+    pub fn new(
+        uat: Option<UserAuthToken>,
+        source: Option<String>,
+        secure: bool,
+        msg: LdapMsg,
+    ) -> Self {
+        LdapRequestMessage {
+            uat: uat,
+            source: source,
+            secure: secure,
+            msg: msg,
+        }
+    }
+}
+
+impl Message for LdapRequestMessage {
+    type Result = Result<LdapResponse, OperationError>;
+}
+
+// A SCIM request on the Users or Groups endpoint. The result is the resource
+// to return, if there is one.
+#[derive(Debug)]
+pub struct ScimMessage {
+    pub uat: Option<UserAuthToken>,
+    pub rt: ScimResourceType,
+    pub op: ScimOp,
+}
+
+impl ScimMessage {
+    pub fn new(uat: Option<UserAuthToken>, rt: ScimResourceType, op: ScimOp) -> Self {
+        ScimMessage {
+            uat: uat,
+            rt: rt,
+            op: op,
+        }
+    }
+}
+
+impl Message for ScimMessage {
+    type Result = Result<Option<JsonValue>, OperationError>;
+}
+
+// A persistent search for the holder of the session. The result is where its
+// notifications arrive.
+#[derive(Debug)]
+pub struct SubscribeMessage {
+    pub uat: Option<UserAuthToken>,
+    pub req: SubscribeRequest,
+}
+
+impl SubscribeMessage {
+    pub fn new(uat: Option<UserAuthToken>, req: SubscribeRequest) -> Self {
+        SubscribeMessage { uat: uat, req: req }
+    }
+}
+
+impl Message for SubscribeMessage {
+    type Result = Result<Receiver<SubscriptionNotification>, OperationError>;
+}
+
 #[derive(Debug)]
 pub struct AuthMessage {
     pub sessionid: Option<Uuid>,
     pub req: AuthRequest,
+    // Where the request came from, so failures can be counted against it.
+    pub source: Option<String>,
+    // The PEM client certificate of the connection, if one was presented.
+    pub client_cert: Option<Vec<u8>>,
 }
 
 impl AuthMessage {
-    pub fn new(req: AuthRequest, sessionid: Option<Uuid>) -> Self {
+    pub fn new(
+        req: AuthRequest,
+        sessionid: Option<Uuid>,
+        source: Option<String>,
+        client_cert: Option<Vec<u8>>,
+    ) -> Self {
         AuthMessage {
             sessionid: sessionid,
             req: req,
+            source: source,
+            client_cert: client_cert,
         }
     }
 }
@@ -43,3 +171,31 @@ impl AuthMessage {
 impl Message for AuthMessage {
     type Result = Result<AuthResponse, OperationError>;
 }
+
+// Wraps any request that came from a client, tagging it with the request id
+// that is returned to them, so the audit log can be correlated.
+pub struct RequestMessage<M> {
+    pub request_id: Uuid,
+    // Where the request came from, for the limits that are kept per source.
+    pub source: Option<String>,
+    pub msg: M,
+}
+
+impl<M> RequestMessage<M> {
+    pub fn new(request_id: Uuid, msg: M) -> Self {
+        RequestMessage {
+            request_id: request_id,
+            source: None,
+            msg: msg,
+        }
+    }
+
+    pub fn with_source(mut self, source: Option<String>) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl<M: Message> Message for RequestMessage<M> {
+    type Result = M::Result;
+}
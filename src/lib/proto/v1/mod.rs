@@ -1,14 +1,22 @@
 // use super::entry::Entry;
 // use super::filter::Filter;
+use crate::constants::CREDENTIAL_ATTRS;
+#[cfg(feature = "server")]
 use crate::error::OperationError;
+#[cfg(feature = "server")]
 use actix::prelude::*;
 use std::collections::BTreeMap;
 use uuid::Uuid;
 
+#[cfg(feature = "server")]
 pub(crate) mod actors;
+pub mod builder;
 pub mod client;
+#[cfg(feature = "server")]
 pub(crate) mod messages;
 
+pub use crate::proto::v1::builder::FilterBuilder;
+
 // These proto implementations are here because they have public definitions
 
 /* ===== higher level types ===== */
@@ -38,6 +46,16 @@ pub struct Application {
     pub uuid: String,
 }
 
+// The domain a server belongs to. Every server replicating the domain shares
+// its uuid, and principal names end with its name.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DomainInfo {
+    pub name: String,
+    pub uuid: String,
+    pub version: String,
+    pub features: Vec<String>,
+}
+
 // The currently authenticated user, and any required metadata for them
 // to properly authorise them. This is similar in nature to oauth and the krb
 // PAC/PAD structures. Currently we only use this internally, but we should
@@ -57,9 +75,16 @@ pub struct UserAuthToken {
     pub name: String,
     pub displayname: String,
     pub uuid: String,
+    // The principal name, as name@domain unless one was set on the account.
+    #[serde(default)]
+    pub spn: String,
     pub application: Option<Application>,
     pub groups: Vec<Group>,
     pub claims: Vec<Claim>,
+    // After this time (rfc3339) the token is refused. Only set for sessions
+    // from a break glass secret.
+    #[serde(default)]
+    pub expiry: Option<String>,
     // Should we allow supplemental ava's to be added on request?
 }
 
@@ -73,17 +98,64 @@ pub struct UserAuthToken {
 // the in memory server core entry type, without affecting the protoEntry type
 //
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Entry {
     pub attrs: BTreeMap<String, Vec<String>>,
 }
 
+// Requests and entries end up in the audit log, and a credential in one may
+// not have been hashed yet. The values of credential attributes are never
+// shown.
+pub(crate) fn is_credential_attr(attr: &str) -> bool {
+    CREDENTIAL_ATTRS
+        .iter()
+        .any(|c| c.eq_ignore_ascii_case(attr))
+}
+
+pub(crate) struct Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+pub(crate) struct RedactedAttrs<'a, V>(pub &'a BTreeMap<String, V>);
+
+impl<'a, V: std::fmt::Debug> std::fmt::Debug for RedactedAttrs<'a, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut m = f.debug_map();
+        for (k, v) in self.0.iter() {
+            if is_credential_attr(k) {
+                m.entry(k, &Redacted);
+            } else {
+                m.entry(k, v);
+            }
+        }
+        m.finish()
+    }
+}
+
+impl std::fmt::Debug for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Entry")
+            .field("attrs", &RedactedAttrs(&self.attrs))
+            .finish()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Filter {
     // This is attr - value
     Eq(String, String),
     Sub(String, String),
+    StartsWith(String, String),
+    EndsWith(String, String),
     Pres(String),
+    // At or after, and at or before. Only for attributes with an ordered
+    // syntax, such as DATETIME.
+    Ge(String, String),
+    Le(String, String),
     Or(Vec<Filter>),
     And(Vec<Filter>),
     AndNot(Box<Filter>),
@@ -91,13 +163,31 @@ pub enum Filter {
     SelfUUID,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Modify {
     Present(String, String),
     Removed(String, String),
     Purged(String),
 }
 
+impl std::fmt::Debug for Modify {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (name, a, v) = match self {
+            Modify::Present(a, v) => ("Present", a, v),
+            Modify::Removed(a, v) => ("Removed", a, v),
+            Modify::Purged(a) => return f.debug_tuple("Purged").field(a).finish(),
+        };
+        let mut t = f.debug_tuple(name);
+        t.field(a);
+        if is_credential_attr(a) {
+            t.field(&Redacted);
+        } else {
+            t.field(v);
+        }
+        t.finish()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModifyList {
     pub mods: Vec<Modify>,
@@ -122,6 +212,32 @@ impl OperationResponse {
 pub struct SearchRequest {
     pub filter: Filter,
     pub user_uuid: String,
+    // Request that the server explain how this search was evaluated. This is
+    // only honoured for administrators.
+    #[serde(default)]
+    pub trace: bool,
+    // Return entries that match on the attributes you can read, rather than
+    // dropping any entry where the filter touched something you can't.
+    #[serde(default)]
+    pub partial: bool,
+    // Fail rather than return more than this many entries.
+    #[serde(default)]
+    pub size_limit: Option<usize>,
+    // Order the entries by the lowest value of this attribute. Entries
+    // without it, or where you can't read it, come last.
+    #[serde(default)]
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub paging: Option<SearchPaging>,
+}
+
+// Return the results a page at a time. The first request has no cookie, and
+// each response has the cookie for the next page, until there are no more.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchPaging {
+    pub size: usize,
+    #[serde(default)]
+    pub cookie: Option<String>,
 }
 
 impl SearchRequest {
@@ -129,22 +245,142 @@ impl SearchRequest {
         SearchRequest {
             filter: filter,
             user_uuid: user_uuid.to_string(),
+            trace: false,
+            partial: false,
+            size_limit: None,
+            sort: None,
+            paging: None,
+        }
+    }
+
+    pub fn new_trace(filter: Filter, user_uuid: &str) -> Self {
+        SearchRequest {
+            trace: true,
+            ..SearchRequest::new(filter, user_uuid)
+        }
+    }
+
+    pub fn new_partial(filter: Filter, user_uuid: &str) -> Self {
+        SearchRequest {
+            partial: true,
+            ..SearchRequest::new(filter, user_uuid)
+        }
+    }
+
+    pub fn new_paged(filter: Filter, user_uuid: &str, size: usize, cookie: Option<String>) -> Self {
+        SearchRequest {
+            paging: Some(SearchPaging {
+                size: size,
+                cookie: cookie,
+            }),
+            ..SearchRequest::new(filter, user_uuid)
         }
     }
 }
 
+#[cfg(feature = "server")]
 impl Message for SearchRequest {
     type Result = Result<SearchResponse, OperationError>;
 }
 
+// The diagnostic result of a traced search. This describes the candidate set
+// per filter term, why entries were excluded by the filter, and which entries
+// were then removed by access controls.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchTraceNode {
+    // How deep in the filter this term is, where 0 is the root.
+    pub depth: usize,
+    pub filter: String,
+    pub candidates: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchTraceFailure {
+    pub uuid: String,
+    // The filter term that this entry did not satisfy.
+    pub term: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchTraceAccess {
+    pub uuid: String,
+    // The names of the acps that applied to this entry for the requester.
+    pub acps: Vec<String>,
+    // The requested attributes that no acp granted.
+    pub denied_attrs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchTrace {
+    pub candidates: usize,
+    pub nodes: Vec<SearchTraceNode>,
+    pub failures: Vec<SearchTraceFailure>,
+    pub access: Vec<SearchTraceAccess>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SearchResponse {
     pub entries: Vec<Entry>,
+    #[serde(default)]
+    pub trace: Option<SearchTrace>,
+    // For a paged search, where the next page starts. None on the last page.
+    #[serde(default)]
+    pub cookie: Option<String>,
 }
 
 impl SearchResponse {
     pub fn new(entries: Vec<Entry>) -> Self {
-        SearchResponse { entries: entries }
+        SearchResponse {
+            entries: entries,
+            trace: None,
+            cookie: None,
+        }
+    }
+}
+
+// Complete a name as it's typed, for example when picking members to add to
+// a group. Only entries the caller can search by class and name are given.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypeaheadRequest {
+    pub prefix: String,
+    pub class: String,
+    // How many to give back. The server caps this.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    pub user_uuid: String,
+}
+
+impl TypeaheadRequest {
+    pub fn new(prefix: &str, class: &str, limit: Option<usize>, user_uuid: &str) -> Self {
+        TypeaheadRequest {
+            prefix: prefix.to_string(),
+            class: class.to_string(),
+            limit: limit,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for TypeaheadRequest {
+    type Result = Result<TypeaheadResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TypeaheadEntry {
+    pub name: String,
+    pub uuid: String,
+}
+
+// Sorted by name.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypeaheadResponse {
+    pub entries: Vec<TypeaheadEntry>,
+}
+
+impl TypeaheadResponse {
+    pub fn new(entries: Vec<TypeaheadEntry>) -> Self {
+        TypeaheadResponse { entries: entries }
     }
 }
 
@@ -163,6 +399,7 @@ impl CreateRequest {
     }
 }
 
+#[cfg(feature = "server")]
 impl Message for CreateRequest {
     type Result = Result<OperationResponse, OperationError>;
 }
@@ -182,10 +419,110 @@ impl DeleteRequest {
     }
 }
 
+#[cfg(feature = "server")]
 impl Message for DeleteRequest {
     type Result = Result<OperationResponse, OperationError>;
 }
 
+// What a delete would do, without doing it. Limited to members of idm_admins,
+// as it reports on entries beyond those being deleted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletePreviewRequest {
+    pub filter: Filter,
+    pub user_uuid: String,
+}
+
+impl DeletePreviewRequest {
+    pub fn new(filter: Filter, user_uuid: &str) -> Self {
+        DeletePreviewRequest {
+            filter: filter,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for DeletePreviewRequest {
+    type Result = Result<DeletePreviewResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeletePreviewGroup {
+    pub uuid: String,
+    // The members that would be deleted.
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeletePreviewReference {
+    pub uuid: String,
+    // The attributes that would lose a reference.
+    pub attrs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeletePreview {
+    // The uuids of the entries that would be deleted.
+    pub entries: Vec<String>,
+    // Groups that would lose members.
+    pub groups: Vec<DeletePreviewGroup>,
+    // Every remaining entry whose references to the deleted entries would be
+    // removed, including those groups.
+    pub references: Vec<DeletePreviewReference>,
+    // Access control profiles that apply to someone now, but would apply to
+    // no one after the delete.
+    pub access_controls: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletePreviewResponse {
+    pub preview: DeletePreview,
+}
+
+impl DeletePreviewResponse {
+    pub fn new(preview: DeletePreview) -> Self {
+        DeletePreviewResponse { preview: preview }
+    }
+}
+
+// Does the entry hold attr=value? This is answered when the attribute may be
+// read, or a compare access control allows it, so a value the client already
+// knows can be checked without the attribute being readable.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompareRequest {
+    pub target_uuid: String,
+    pub attr: String,
+    pub value: String,
+    pub user_uuid: String,
+}
+
+impl CompareRequest {
+    pub fn new(target_uuid: &str, attr: &str, value: &str, user_uuid: &str) -> Self {
+        CompareRequest {
+            target_uuid: target_uuid.to_string(),
+            attr: attr.to_string(),
+            value: value.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for CompareRequest {
+    type Result = Result<CompareResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompareResponse {
+    pub matches: bool,
+}
+
+impl CompareResponse {
+    pub fn new(matches: bool) -> Self {
+        CompareResponse { matches: matches }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ModifyRequest {
     // Probably needs a modlist?
@@ -204,10 +541,66 @@ impl ModifyRequest {
     }
 }
 
+#[cfg(feature = "server")]
 impl Message for ModifyRequest {
     type Result = Result<OperationResponse, OperationError>;
 }
 
+// Change the name of an entry, along with anything derived from it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameRequest {
+    pub target_uuid: String,
+    pub name: String,
+    pub user_uuid: String,
+}
+
+impl RenameRequest {
+    pub fn new(target_uuid: &str, name: &str, user_uuid: &str) -> Self {
+        RenameRequest {
+            target_uuid: target_uuid.to_string(),
+            name: name.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for RenameRequest {
+    type Result = Result<OperationResponse, OperationError>;
+}
+
+// One change in a batch. A modify may name its target by uuid, rather than
+// with a filter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum BatchOperation {
+    Create(Vec<Entry>),
+    Modify(Filter, ModifyList),
+    ModifyUuid(String, ModifyList),
+    Delete(Filter),
+}
+
+// Operations that are applied in order, in one transaction. If any of them
+// fails, none of them are applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+    pub user_uuid: String,
+}
+
+impl BatchRequest {
+    pub fn new(operations: Vec<BatchOperation>, user_uuid: &str) -> Self {
+        BatchRequest {
+            operations: operations,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for BatchRequest {
+    type Result = Result<OperationResponse, OperationError>;
+}
+
 // Login is a multi-step process potentially. First the client says who they
 // want to request
 //
@@ -220,13 +613,29 @@ impl Message for ModifyRequest {
 //
 // On loginSuccess, we send a cookie, and that allows the token to be
 // generated. The cookie can be shared between servers.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub enum AuthCredential {
     Anonymous,
     Password(String),
+    // The certificate comes from the connection, not the request.
+    ClientCertificate,
+    // A secret minted on the server host, for when the usual credentials
+    // can't be used. It's accepted in place of them, but isn't offered.
+    BreakGlass(String),
     // TOTP(String),
 }
 
+impl std::fmt::Debug for AuthCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthCredential::Anonymous => write!(f, "Anonymous"),
+            AuthCredential::Password(_) => f.debug_tuple("Password").field(&Redacted).finish(),
+            AuthCredential::ClientCertificate => write!(f, "ClientCertificate"),
+            AuthCredential::BreakGlass(_) => f.debug_tuple("BreakGlass").field(&Redacted).finish(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum AuthStep {
     // name, application id?
@@ -253,6 +662,7 @@ pub struct AuthRequest {
 pub enum AuthAllowed {
     Anonymous,
     Password,
+    ClientCertificate,
     // TOTP,
     // Webauthn(String),
 }
@@ -274,10 +684,726 @@ pub struct AuthResponse {
     pub state: AuthState,
 }
 
+// Ask what the requesting user may do to a single entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EffectivePermissionsRequest {
+    pub target_uuid: String,
+    pub user_uuid: String,
+}
+
+impl EffectivePermissionsRequest {
+    pub fn new(target_uuid: &str, user_uuid: &str) -> Self {
+        EffectivePermissionsRequest {
+            target_uuid: target_uuid.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for EffectivePermissionsRequest {
+    type Result = Result<EffectivePermissionsResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EffectivePermissions {
+    pub uuid: String,
+    // Attributes that may be read.
+    pub search: Vec<String>,
+    // Attributes that may have values added or removed.
+    pub modify_present: Vec<String>,
+    pub modify_remove: Vec<String>,
+    // Class values that may be added or removed.
+    pub modify_class_add: Vec<String>,
+    pub modify_class_remove: Vec<String>,
+    // Where only some values of an attribute above may be added or removed,
+    // those values. Attributes not listed here may take any value.
+    #[serde(default)]
+    pub modify_present_values: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub modify_remove_values: BTreeMap<String, Vec<String>>,
+    // Could an entry with these classes and attributes be created?
+    pub create: bool,
+    pub delete: bool,
+    // The profiles that granted or denied any of the above.
+    #[serde(default)]
+    pub profiles: Vec<AccessControlProfileInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessControlProfileInfo {
+    pub name: String,
+    pub uuid: String,
+    pub description: Option<String>,
+    pub ticket_refs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EffectivePermissionsResponse {
+    pub permissions: EffectivePermissions,
+}
+
+impl EffectivePermissionsResponse {
+    pub fn new(permissions: EffectivePermissions) -> Self {
+        EffectivePermissionsResponse {
+            permissions: permissions,
+        }
+    }
+}
+
+// Incremental sync for external consumers. A request without a token is an
+// initial sync, and returns every entry the caller can read. The response
+// token is then sent with the next request to receive only what changed.
+// Entries that were deleted, or that the caller can no longer read, are
+// listed in deleted and should be dropped from the consumer's copy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub token: Option<String>,
+    pub user_uuid: String,
+}
+
+impl SyncRequest {
+    pub fn new(token: Option<&str>, user_uuid: &str) -> Self {
+        SyncRequest {
+            token: token.map(|t| t.to_string()),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for SyncRequest {
+    type Result = Result<SyncResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncResponse {
+    // Opaque to the client - only ever send it back as is.
+    pub token: String,
+    pub entries: Vec<Entry>,
+    pub deleted: Vec<String>,
+}
+
+impl SyncResponse {
+    pub fn new(token: String, entries: Vec<Entry>, deleted: Vec<String>) -> Self {
+        SyncResponse {
+            token: token,
+            entries: entries,
+            deleted: deleted,
+        }
+    }
+}
+
+// A persistent search, for the holder of the session. After subscribing, the
+// response streams a notification, one json object per line, for each
+// committed change to an entry that matches the filter as the caller can see
+// it. Entries that stop matching, or that the caller can no longer read, are
+// given as deleted. attrs limits the attributes sent with each entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    pub filter: Filter,
+    #[serde(default)]
+    pub attrs: Option<Vec<String>>,
+}
+
+impl SubscribeRequest {
+    pub fn new(filter: Filter, attrs: Option<Vec<&str>>) -> Self {
+        SubscribeRequest {
+            filter: filter,
+            attrs: attrs.map(|a| a.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SubscriptionOp {
+    // Newly matched, either created or changed to match.
+    Added,
+    Modified,
+    Deleted,
+}
+
+// Deleted notifications have no entry.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubscriptionNotification {
+    pub op: SubscriptionOp,
+    pub uuid: String,
+    pub entry: Option<Entry>,
+}
+
+/* Replication */
+
+// The changes a replication consumer hasn't seen yet. since is the position
+// in the supplier's changelog from the last response it applied, or None to
+// be sent every entry - needed the first time, and when the changes since
+// have been trimmed. Limited to members of idm_admins, as it gives out whole
+// entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplChangesRequest {
+    pub since: Option<i64>,
+    pub user_uuid: String,
+}
+
+impl ReplChangesRequest {
+    pub fn new(since: Option<i64>, user_uuid: &str) -> Self {
+        ReplChangesRequest {
+            since: since,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for ReplChangesRequest {
+    type Result = Result<ReplChangesResponse, OperationError>;
+}
+
+// An entry as the supplier holds it now, with the csn (see Csn::to_repl_string)
+// of the last change to each attribute, including attributes since removed. attrs is
+// None once its tombstone has been purged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReplEntry {
+    pub uuid: String,
+    pub attrs: Option<BTreeMap<String, Vec<String>>>,
+    pub csns: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplChangesResponse {
+    pub entries: Vec<ReplEntry>,
+    // Send this as since next time.
+    pub seq: i64,
+    // The supplier's domain. Changes are only taken from a supplier of the
+    // same domain, at the same version.
+    pub domain: DomainInfo,
+}
+
+impl ReplChangesResponse {
+    pub fn new(entries: Vec<ReplEntry>, seq: i64, domain: DomainInfo) -> Self {
+        ReplChangesResponse {
+            entries: entries,
+            seq: seq,
+            domain: domain,
+        }
+    }
+}
+
+/* Diagnostics */
+
+// How much memory the server's data is taking, for capacity planning. Limited
+// to members of idm_admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoryReportRequest {
+    pub user_uuid: String,
+}
+
+impl MemoryReportRequest {
+    pub fn new(user_uuid: &str) -> Self {
+        MemoryReportRequest {
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for MemoryReportRequest {
+    type Result = Result<MemoryReportResponse, OperationError>;
+}
+
+// Sizes are estimates from the lengths of the data held, not counts of what
+// the allocator has handed out, so treat them as a lower bound.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MemoryUse {
+    pub name: String,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MemoryReport {
+    // The values held under each attribute, over every entry.
+    pub entry_attrs: Vec<MemoryUse>,
+    // The entries of each class, and their whole size.
+    pub entry_classes: Vec<MemoryUse>,
+    // The keys of each attribute's indexes, and their id lists. Indexes are
+    // read from the database as they're needed rather than held, so these
+    // are the bytes they take on disk.
+    pub indexes_on_disk: Vec<MemoryUse>,
+    // The parsed access control profiles, by the kind of access.
+    pub access_controls: Vec<MemoryUse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoryReportResponse {
+    pub report: MemoryReport,
+}
+
+impl MemoryReportResponse {
+    pub fn new(report: MemoryReport) -> Self {
+        MemoryReportResponse { report: report }
+    }
+}
+
+// Turn the audit logging of a subsystem - access, be, schema or idm - up or
+// down while the server runs. Subsystems that aren't named keep their level.
+// Limited to members of idm_admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevelRequest {
+    pub levels: Vec<LogLevel>,
+    pub user_uuid: String,
+}
+
+impl LogLevelRequest {
+    pub fn new(levels: Vec<LogLevel>, user_uuid: &str) -> Self {
+        LogLevelRequest {
+            levels: levels,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for LogLevelRequest {
+    type Result = Result<LogLevelResponse, OperationError>;
+}
+
+// The level is one of off, info or debug. Info is the default, and debug also
+// writes each audit event to the server log as it happens.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LogLevel {
+    pub subsystem: String,
+    pub level: String,
+}
+
+// The levels of every subsystem, once the request is applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevelResponse {
+    pub levels: Vec<LogLevel>,
+}
+
+// Snapshot the live database to a new file in the server's backup_path.
+// Limited to members of idm_admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupRequest {
+    pub user_uuid: String,
+}
+
+impl BackupRequest {
+    pub fn new(user_uuid: &str) -> Self {
+        BackupRequest {
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for BackupRequest {
+    type Result = Result<BackupResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupResponse {
+    // The name of the file written, within backup_path.
+    pub name: String,
+}
+
+// What is kept of a security relevant operation in the audit log: who did
+// it, to what, what it changed, and how it ended. Credential values are
+// never kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: String,
+    pub operation: String,
+    pub request_id: String,
+    // The uuid of the entry that initiated the operation, once it's known.
+    pub origin: Option<String>,
+    pub filter: Option<String>,
+    pub decision: Option<String>,
+    // Records from before this was kept don't have it.
+    #[serde(default)]
+    pub modlist: Option<String>,
+}
+
+// Search the persisted audit log. Every term given must match, and since is
+// an rfc3339 time. Only records an audit read profile grants are returned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogRequest {
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub origin: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub since: Option<String>,
+    pub user_uuid: String,
+}
+
+impl AuditLogRequest {
+    pub fn new(
+        operation: Option<String>,
+        origin: Option<String>,
+        request_id: Option<String>,
+        since: Option<String>,
+        user_uuid: &str,
+    ) -> Self {
+        AuditLogRequest {
+            operation: operation,
+            origin: origin,
+            request_id: request_id,
+            since: since,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for AuditLogRequest {
+    type Result = Result<AuditLogResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogResponse {
+    pub records: Vec<AuditRecord>,
+}
+
+// Map who can read what under the current search profiles. Every live
+// entry is checked unless sample is given, in which case that many are, spread
+// evenly over the database. Limited to members of idm_admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcpCoverageRequest {
+    #[serde(default)]
+    pub sample: Option<usize>,
+    pub user_uuid: String,
+}
+
+impl AcpCoverageRequest {
+    pub fn new(sample: Option<usize>, user_uuid: &str) -> Self {
+        AcpCoverageRequest {
+            sample: sample,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for AcpCoverageRequest {
+    type Result = Result<AcpCoverageResponse, OperationError>;
+}
+
+// An entry is counted once for each of its classes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AcpCoverageClass {
+    pub class: String,
+    pub entries: usize,
+    // Not readable by any account outside idm_admins.
+    pub unreadable: usize,
+    // Readable by anonymous, and so by anyone.
+    pub world_readable: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AcpCoverage {
+    // Live entries, and how many of them were checked.
+    pub total: usize,
+    pub checked: usize,
+    // The accounts outside idm_admins that each entry was checked against.
+    pub receivers: usize,
+    pub classes: Vec<AcpCoverageClass>,
+    // The uuids of the checked entries in each group, up to a limit.
+    pub unreadable: Vec<String>,
+    pub world_readable: Vec<String>,
+    // Set when either list was cut short.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcpCoverageResponse {
+    pub coverage: AcpCoverage,
+}
+
+/* Host service secrets */
+
+// Replace the service secret of a host with a new random one. A host may do
+// this for itself, so it can renew before the old secret expires.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HostSecretRotateRequest {
+    pub target_uuid: String,
+    pub user_uuid: String,
+}
+
+impl HostSecretRotateRequest {
+    pub fn new(target_uuid: &str, user_uuid: &str) -> Self {
+        HostSecretRotateRequest {
+            target_uuid: target_uuid.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for HostSecretRotateRequest {
+    type Result = Result<HostSecretRotateResponse, OperationError>;
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct HostSecretRotateResponse {
+    pub secret: String,
+    // RFC3339.
+    pub expiry: String,
+}
+
+/* Radius secrets */
+
+// Replace the radius secret of an account with a new random one. An account
+// may do this for itself, such as when a device holding it is lost.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RadiusSecretRegenerateRequest {
+    pub target_uuid: String,
+    pub user_uuid: String,
+}
+
+impl RadiusSecretRegenerateRequest {
+    pub fn new(target_uuid: &str, user_uuid: &str) -> Self {
+        RadiusSecretRegenerateRequest {
+            target_uuid: target_uuid.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for RadiusSecretRegenerateRequest {
+    type Result = Result<RadiusSecretResponse, OperationError>;
+}
+
+// The radius secret of the account with this name, as a radius server asks
+// for it when the account authenticates.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RadiusSecretReadRequest {
+    pub name: String,
+    pub user_uuid: String,
+}
+
+impl RadiusSecretReadRequest {
+    pub fn new(name: &str, user_uuid: &str) -> Self {
+        RadiusSecretReadRequest {
+            name: name.to_string(),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for RadiusSecretReadRequest {
+    type Result = Result<RadiusSecretResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RadiusSecretResponse {
+    pub secret: String,
+}
+
+/* Credential reset */
+
+// Issue a one time token that sets the password of the target account. The
+// lifetime is in seconds, and is capped by the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialResetIssueRequest {
+    pub target_uuid: String,
+    pub lifetime: Option<u64>,
+    pub user_uuid: String,
+}
+
+impl CredentialResetIssueRequest {
+    pub fn new(target_uuid: &str, lifetime: Option<u64>, user_uuid: &str) -> Self {
+        CredentialResetIssueRequest {
+            target_uuid: target_uuid.to_string(),
+            lifetime: lifetime,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for CredentialResetIssueRequest {
+    type Result = Result<CredentialResetTokenResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CredentialResetTokenResponse {
+    pub token: String,
+    pub expiry: String,
+}
+
+// Spend a token on a new password. This needs no session, as the token is
+// what shows the caller may. Never Debug, as it holds both.
+#[derive(Serialize, Deserialize)]
+pub struct CredentialResetRedeemRequest {
+    pub token: String,
+    pub password: String,
+}
+
+impl CredentialResetRedeemRequest {
+    pub fn new(token: &str, password: &str) -> Self {
+        CredentialResetRedeemRequest {
+            token: token.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for CredentialResetRedeemRequest {
+    type Result = Result<(), OperationError>;
+}
+
+/* Oauth2 */
+
+// The query of an authorisation request, as rfc6749 4.1.1. The scopes are
+// space separated, and the nonce is from openid connect.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Oauth2AuthoriseRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+// The form of a token request, as rfc6749 4.1.3. The client id and secret
+// are given here, or with http basic authentication.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Oauth2TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+#[cfg(feature = "server")]
+impl Message for Oauth2TokenRequest {
+    type Result = Result<Oauth2TokenResponse, OperationError>;
+}
+
+// As rfc6749 5.1. The id token is only given when the openid scope was.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Oauth2TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// As rfc6749 5.2, with the code from the Oauth2 operation error.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Oauth2ErrorResponse {
+    pub error: String,
+}
+
+/* Group join requests */
+
+// Ask to be added to a group, by name or uuid. The group's managers decide
+// whether to let you in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupJoinCreateRequest {
+    pub group: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub user_uuid: String,
+}
+
+impl GroupJoinCreateRequest {
+    pub fn new(group: &str, description: Option<&str>, user_uuid: &str) -> Self {
+        GroupJoinCreateRequest {
+            group: group.to_string(),
+            description: description.map(|d| d.to_string()),
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for GroupJoinCreateRequest {
+    type Result = Result<OperationResponse, OperationError>;
+}
+
+// List the pending requests to join the groups you manage.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupJoinListRequest {
+    pub user_uuid: String,
+}
+
+impl GroupJoinListRequest {
+    pub fn new(user_uuid: &str) -> Self {
+        GroupJoinListRequest {
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for GroupJoinListRequest {
+    type Result = Result<GroupJoinListResponse, OperationError>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GroupJoinInfo {
+    pub uuid: String,
+    pub group: String,
+    pub requester: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupJoinListResponse {
+    pub requests: Vec<GroupJoinInfo>,
+}
+
+impl GroupJoinListResponse {
+    pub fn new(requests: Vec<GroupJoinInfo>) -> Self {
+        GroupJoinListResponse { requests: requests }
+    }
+}
+
+// Approve or deny a pending request. Either way the request is removed, and
+// if approved the requester is added to the group.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupJoinDecideRequest {
+    pub request_uuid: String,
+    pub approve: bool,
+    pub user_uuid: String,
+}
+
+impl GroupJoinDecideRequest {
+    pub fn new(request_uuid: &str, approve: bool, user_uuid: &str) -> Self {
+        GroupJoinDecideRequest {
+            request_uuid: request_uuid.to_string(),
+            approve: approve,
+            user_uuid: user_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Message for GroupJoinDecideRequest {
+    type Result = Result<OperationResponse, OperationError>;
+}
+
 /* Recycle Requests area */
 
 // Only two actions on recycled is possible. Search and Revive.
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SearchRecycledRequest {
     pub filter: Filter,
     pub user_uuid: String,
@@ -292,8 +1418,14 @@ impl SearchRecycledRequest {
     }
 }
 
-// Need a search response here later.
+#[cfg(feature = "server")]
+impl Message for SearchRecycledRequest {
+    type Result = Result<SearchResponse, OperationError>;
+}
 
+// Revive the recycled entries matching the filter. This needs the right to
+// remove class=recycled from them.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ReviveRecycledRequest {
     pub filter: Filter,
     pub user_uuid: String,
@@ -308,6 +1440,11 @@ impl ReviveRecycledRequest {
     }
 }
 
+#[cfg(feature = "server")]
+impl Message for ReviveRecycledRequest {
+    type Result = Result<OperationResponse, OperationError>;
+}
+
 // This doesn't need seralise because it's only accessed via a "get".
 #[derive(Debug)]
 pub struct WhoamiRequest {}
@@ -322,17 +1459,24 @@ impl WhoamiRequest {
 pub struct WhoamiResponse {
     // Should we just embed the entry? Or destructure it?
     pub youare: Entry,
+    // The domain of the server that answered.
+    #[serde(default)]
+    pub domain: DomainInfo,
 }
 
 impl WhoamiResponse {
-    pub fn new(e: Entry) -> Self {
-        WhoamiResponse { youare: e }
+    pub fn new(e: Entry, domain: DomainInfo) -> Self {
+        WhoamiResponse {
+            youare: e,
+            domain: domain,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::proto::v1::Filter as ProtoFilter;
+
     #[test]
     fn test_protofilter_simple() {
         let pf: ProtoFilter = ProtoFilter::Pres("class".to_string());
@@ -0,0 +1,83 @@
+// How often something may happen.
+//
+// A rate limit is shared by every transaction of the server, and counts the
+// attempts made within a sliding window of time, separately for each key -
+// usually where the attempts came from - so one client using up its share
+// doesn't shut out everyone else. The times come from the server's clock, so
+// tests can step through a window without sleeping.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::constants::RATELIMIT_MAX_KEYS;
+
+pub struct RateLimit {
+    clock: Arc<dyn Clock>,
+    // The times of the allowed attempts still within the last window, by key.
+    recent: Mutex<BTreeMap<String, VecDeque<Duration>>>,
+}
+
+impl RateLimit {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        RateLimit {
+            clock: clock,
+            recent: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Record an attempt against key, if fewer than max have been allowed for
+    // it within the window. Refused attempts aren't recorded, so a flood
+    // doesn't keep the limit shut once it has passed.
+    pub fn check(&self, key: &str, window: Duration, max: usize) -> bool {
+        let now = self.clock.now();
+        let mut recent = self.recent.lock().expect("Rate limit lock poisoned");
+        // Keys that have gone quiet are only swept out when there are many of
+        // them, so a flood of new keys can't grow this forever.
+        if recent.len() > RATELIMIT_MAX_KEYS {
+            recent.retain(|_, times| times.back().map(|t| *t + window > now).unwrap_or(false));
+        }
+        let times = recent.entry(key.to_string()).or_insert_with(VecDeque::new);
+        while times.front().map(|t| *t + window <= now).unwrap_or(false) {
+            times.pop_front();
+        }
+        if times.len() < max {
+            times.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::ratelimit::RateLimit;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ratelimit_window() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let rl = RateLimit::new(clock.clone());
+        let minute = Duration::from_secs(60);
+
+        assert!(rl.check("a", minute, 2));
+        clock.advance(Duration::from_secs(30));
+        assert!(rl.check("a", minute, 2));
+        assert!(!rl.check("a", minute, 2));
+
+        // Other keys have their own share.
+        assert!(rl.check("b", minute, 2));
+
+        // The first attempt leaves the window, making room for one more.
+        clock.advance(Duration::from_secs(30));
+        assert!(rl.check("a", minute, 2));
+        assert!(!rl.check("a", minute, 2));
+
+        // Nothing is ever allowed with no limit.
+        assert!(!rl.check("c", minute, 0));
+    }
+}
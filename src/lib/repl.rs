@@ -0,0 +1,363 @@
+// Multi-master replication.
+//
+// Every server keeps a changelog of the entries it changed, and each entry
+// keeps the csn of the last change to every attribute. A consumer asks its
+// supplier for the entries changed since the last position in the supplier's
+// changelog it applied, and merges each into its own copy:
+//
+// - a tombstone always wins, whatever the csns say, so a delete can't be
+//   undone by an older change made elsewhere before the delete replicated.
+// - otherwise each attribute is taken from whichever side changed it last.
+//
+// Servers replicating both ways converge, as an entry a consumer took as it
+// was has nothing newer to give back to its supplier.
+//
+// What to pull from is set by entries of class replication_agreement, naming
+// the supplier, the account to act as there, and the position reached so
+// far. They are managed through the normal entry api, and are never
+// replicated themselves - they only make sense on the server that holds them.
+//
+// Pulls block on the supplier, so they run on their own actor rather than on
+// the query server workers.
+
+use actix::prelude::*;
+use std::time::Duration;
+
+use crate::async_log::EventLog;
+use crate::audit::AuditScope;
+use crate::constants::REPL_FETCH_TIMEOUT;
+use crate::csn::Csn;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::error::OperationError;
+use crate::event::ReplConsumeEvent;
+use crate::proto::v1::{ReplChangesRequest, ReplChangesResponse, ReplEntry};
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServer, QueryServerTransaction};
+
+// What a consumer does with one entry from its supplier.
+#[derive(Debug)]
+pub enum ReplAction {
+    Skip,
+    Create(Entry<EntryInvalid, EntryNew>),
+    Modify(Entry<EntryInvalid, EntryCommitted>),
+    // The supplier purged the entry's tombstone.
+    Purge(Entry<EntryValid, EntryCommitted>),
+}
+
+pub fn resolve(
+    schema: &dyn SchemaTransaction,
+    local: Option<&Entry<EntryValid, EntryCommitted>>,
+    re: &ReplEntry,
+) -> Result<ReplAction, OperationError> {
+    let local = match local {
+        Some(l) => l,
+        None => {
+            return match re.attrs {
+                Some(_) => Entry::from_repl_entry(re, schema).map(ReplAction::Create),
+                None => Ok(ReplAction::Skip),
+            }
+        }
+    };
+    let r_attrs = match &re.attrs {
+        Some(a) => a,
+        None => return Ok(ReplAction::Purge(local.clone())),
+    };
+
+    if local.attribute_value_pres("class", "tombstone") {
+        Ok(ReplAction::Skip)
+    } else if r_attrs
+        .get("class")
+        .map(|cs| cs.iter().any(|c| c == "tombstone"))
+        .unwrap_or(false)
+    {
+        let csn = re
+            .csns
+            .get("class")
+            .and_then(|c| Csn::from_repl_str(c.as_str()))
+            .ok_or_else(|| {
+                OperationError::ReplicationFailed(format!("tombstone {} has no csn", re.uuid))
+            })?;
+        Ok(ReplAction::Modify(local.to_tombstone(&csn).invalidate()))
+    } else {
+        Ok(match local.repl_merge(re, schema)? {
+            Some(e) => ReplAction::Modify(e),
+            None => ReplAction::Skip,
+        })
+    }
+}
+
+// Ask a supplier for its changes. A failure the supplier reports, like the
+// changes having been trimmed, is returned as it is.
+fn fetch_changes(
+    client: &reqwest::Client,
+    supplier: &str,
+    req: &ReplChangesRequest,
+) -> Result<ReplChangesResponse, OperationError> {
+    let url = format!("{}/v1/replication/changes", supplier.trim_end_matches('/'));
+    let failed = |e: reqwest::Error| OperationError::ReplicationFailed(format!("{} -> {}", url, e));
+    let mut response = client
+        .post(url.as_str())
+        .json(req)
+        .send()
+        .map_err(&failed)?;
+    if response.status().is_success() {
+        return response.json().map_err(&failed);
+    }
+    // Some operation errors borrow static strings, so can't be decoded from
+    // the response. The one the consumer acts on is matched here, the rest
+    // are reported as the supplier sent them.
+    let body = response.text().map_err(&failed)?;
+    if serde_json::from_str::<serde_json::Value>(body.as_str()).ok()
+        == serde_json::to_value(OperationError::ChangelogTrimmed).ok()
+    {
+        Err(OperationError::ChangelogTrimmed)
+    } else {
+        Err(OperationError::ReplicationFailed(format!(
+            "{} -> {}",
+            url, body
+        )))
+    }
+}
+
+// One pull from an agreement's supplier, applied and committed along with the
+// position it reached. If the changes since our position have been trimmed
+// there, every entry is pulled again instead. Returns how many entries
+// changed here.
+pub fn consume(
+    au: &mut AuditScope,
+    client: &reqwest::Client,
+    qs: &QueryServer,
+    agreement: &Entry<EntryValid, EntryCommitted>,
+) -> Result<usize, OperationError> {
+    let attr = |a: &str| {
+        agreement
+            .get_ava_single(a)
+            .map(|v| v.to_string())
+            .ok_or_else(|| OperationError::ReplicationFailed(format!("{} has no {}", agreement, a)))
+    };
+    let supplier = attr("repl_supplier")?;
+    let user = attr("repl_user")?;
+    let since = agreement
+        .get_ava_single("repl_seq")
+        .and_then(|v| v.to_integer());
+
+    let req = ReplChangesRequest::new(since, user.as_str());
+    let changes = match fetch_changes(client, supplier.as_str(), &req) {
+        Err(OperationError::ChangelogTrimmed) => {
+            audit_log!(
+                au,
+                "{} trimmed past {:?}, pulling everything",
+                supplier,
+                since
+            );
+            fetch_changes(
+                client,
+                supplier.as_str(),
+                &ReplChangesRequest::new(None, user.as_str()),
+            )
+        }
+        r => r,
+    };
+    let changes = try_audit!(au, changes);
+    audit_log!(au, "{} sent {} entries", supplier, changes.entries.len());
+
+    let mut qs_write = qs.write();
+    // Servers of different domains, or at different versions of one, can't
+    // merge each other's entries.
+    let domain = qs_write.get_domain_info();
+    if changes.domain.name != domain.name || changes.domain.version != domain.version {
+        audit_log!(
+            au,
+            "{} is in domain {} at version {}, not {} at {}",
+            supplier,
+            changes.domain.name,
+            changes.domain.version,
+            domain.name,
+            domain.version
+        );
+        return Err(OperationError::ReplicationFailed(format!(
+            "{} is not in domain {}",
+            supplier, domain.name
+        )));
+    }
+    let applied = qs_write.repl_apply(au, &changes.entries)?;
+    if since != Some(changes.seq) {
+        qs_write.repl_agreement_update(au, agreement.get_uuid(), changes.seq)?;
+    }
+    qs_write.commit(au)?;
+    Ok(applied)
+}
+
+#[derive(Clone)]
+pub struct ReplConsumer {
+    log: actix::Addr<EventLog>,
+    qs: QueryServer,
+    client: reqwest::Client,
+}
+
+impl Actor for ReplConsumer {
+    type Context = SyncContext<Self>;
+}
+
+impl ReplConsumer {
+    pub fn new(log: actix::Addr<EventLog>, qs: QueryServer) -> Result<Self, OperationError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(REPL_FETCH_TIMEOUT))
+            .build()
+            .map_err(|e| {
+                error!("Unable to build the replication client -> {:?}", e);
+                OperationError::InvalidState
+            })?;
+        Ok(ReplConsumer {
+            log: log,
+            qs: qs,
+            client: client,
+        })
+    }
+}
+
+// Each agreement is pulled and committed on its own, so one supplier being
+// down doesn't hold back the others.
+impl Handler<ReplConsumeEvent> for ReplConsumer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReplConsumeEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("replication consume");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin replication consume event {:?}", msg);
+            let agreements = self
+                .qs
+                .read()
+                .internal_search(&mut audit, filter!(f_eq("class", "replication_agreement")));
+            match agreements {
+                Ok(agreements) => {
+                    for a in agreements {
+                        let res = consume(&mut audit, &self.client, &self.qs, &a);
+                        audit_log!(audit, "Replication from {} result: {:?}", a, res);
+                        if let Err(e) = res {
+                            error!("Replication for agreement {} failed -> {:?}", a, e);
+                        }
+                    }
+                }
+                Err(e) => audit_log!(audit, "Unable to read replication agreements: {:?}", e),
+            }
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::proto::v1::ReplEntry;
+    use crate::repl::{resolve, ReplAction};
+    use crate::schema::Schema;
+    use std::collections::BTreeMap;
+
+    fn repl_entry(attrs: Option<&[(&str, &str, &str)]>) -> ReplEntry {
+        let mut re = ReplEntry {
+            uuid: "1e69a1ff-9ad4-4f7c-8d74-8c4d4dd94d93".to_string(),
+            attrs: None,
+            csns: BTreeMap::new(),
+        };
+        if let Some(attrs) = attrs {
+            let mut a = BTreeMap::new();
+            for (k, v, c) in attrs {
+                a.insert(k.to_string(), vec![v.to_string()]);
+                re.csns.insert(k.to_string(), c.to_string());
+            }
+            re.attrs = Some(a);
+        }
+        re
+    }
+
+    #[test]
+    fn test_repl_resolve() {
+        let mut audit = AuditScope::new("test_repl_resolve");
+        let schema_outer = Schema::new(&mut audit).expect("Failed to init schema");
+        let schema = schema_outer.read();
+
+        let t1 = "1970-01-01T00:00:10+00:00";
+        let t2 = "1970-01-01T00:00:20+00:00";
+        let t3 = "1970-01-01T00:00:30+00:00";
+        let uuid = "1e69a1ff-9ad4-4f7c-8d74-8c4d4dd94d93";
+
+        // The local entry, last changed at t2.
+        let remote = repl_entry(Some(&[
+            ("class", "object", t2),
+            ("uuid", uuid, t2),
+            ("description", "local", t2),
+        ]));
+        let e: Entry<EntryInvalid, EntryNew> =
+            Entry::from_repl_entry(&remote, &schema).expect("from_repl_entry failed");
+        let local = unsafe { e.to_valid_committed() };
+
+        // New here, and already gone here.
+        match resolve(&schema, None, &remote) {
+            Ok(ReplAction::Create(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match resolve(&schema, None, &repl_entry(None)) {
+            Ok(ReplAction::Skip) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // The same change again has nothing new.
+        match resolve(&schema, Some(&local), &local.to_repl_entry()) {
+            Ok(ReplAction::Skip) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // Only the attributes changed after ours are taken.
+        let newer = repl_entry(Some(&[
+            ("class", "object", t1),
+            ("uuid", uuid, t1),
+            ("description", "remote", t3),
+        ]));
+        match resolve(&schema, Some(&local), &newer) {
+            Ok(ReplAction::Modify(e)) => {
+                assert!(e.attribute_value_pres("description", "remote"));
+                assert!(e.attribute_value_pres("class", "object"));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        let older = repl_entry(Some(&[
+            ("class", "object", t1),
+            ("uuid", uuid, t1),
+            ("description", "remote", t1),
+        ]));
+        match resolve(&schema, Some(&local), &older) {
+            Ok(ReplAction::Skip) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // A tombstone wins, even against a later change.
+        let tombstone = repl_entry(Some(&[("class", "tombstone", t1), ("uuid", uuid, t1)]));
+        match resolve(&schema, Some(&local), &tombstone) {
+            Ok(ReplAction::Modify(e)) => {
+                assert!(e.attribute_value_pres("class", "tombstone"));
+                assert!(!e.attribute_pres("description"));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        let local_ts = unsafe {
+            Entry::from_repl_entry(&tombstone, &schema)
+                .expect("from_repl_entry failed")
+                .to_valid_committed()
+        };
+        match resolve(&schema, Some(&local_ts), &newer) {
+            Ok(ReplAction::Skip) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match resolve(&schema, Some(&local), &repl_entry(None)) {
+            Ok(ReplAction::Purge(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // Bad csns are refused.
+        let bad = repl_entry(Some(&[("class", "object", "later"), ("uuid", uuid, t1)]));
+        assert!(resolve(&schema, None, &bad).is_err());
+    }
+}
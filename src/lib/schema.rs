@@ -3,11 +3,14 @@ use crate::constants::*;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
 use crate::error::{ConsistencyError, OperationError, SchemaError};
 use crate::proto::v1::Filter as ProtoFilter;
+use crate::value::{self, Value};
 
+use chrono::{DateTime, Utc};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryFrom;
 use std::str::FromStr;
+use url::Url;
 use uuid::Uuid;
 
 use concread::cowcell::{CowCell, CowCellReadTxn, CowCellWriteTxn};
@@ -21,7 +24,7 @@ use concread::cowcell::{CowCell, CowCellReadTxn, CowCellWriteTxn};
 // TODO #72: prefix on all schema types that are system?
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum IndexType {
     EQUALITY,
     PRESENCE,
@@ -68,6 +71,10 @@ pub enum SyntaxType {
     INDEX_ID,
     REFERENCE_UUID,
     JSON_FILTER,
+    DATETIME,
+    URL,
+    INTEGER,
+    SSHKEY,
 }
 
 impl TryFrom<&str> for SyntaxType {
@@ -92,6 +99,14 @@ impl TryFrom<&str> for SyntaxType {
             Ok(SyntaxType::REFERENCE_UUID)
         } else if value == "JSON_FILTER" {
             Ok(SyntaxType::JSON_FILTER)
+        } else if value == "DATETIME" {
+            Ok(SyntaxType::DATETIME)
+        } else if value == "URL" {
+            Ok(SyntaxType::URL)
+        } else if value == "INTEGER" {
+            Ok(SyntaxType::INTEGER)
+        } else if value == "SSHKEY" {
+            Ok(SyntaxType::SSHKEY)
         } else {
             Err(())
         }
@@ -110,8 +125,20 @@ impl SyntaxType {
             SyntaxType::INDEX_ID => "INDEX_ID",
             SyntaxType::REFERENCE_UUID => "REFERENCE_UUID",
             SyntaxType::JSON_FILTER => "JSON_FILTER",
+            SyntaxType::DATETIME => "DATETIME",
+            SyntaxType::URL => "URL",
+            SyntaxType::INTEGER => "INTEGER",
+            SyntaxType::SSHKEY => "SSHKEY",
         })
     }
+
+    // Can values of this syntax be compared by greater or less than?
+    pub fn is_ordered(&self) -> bool {
+        match self {
+            SyntaxType::DATETIME | SyntaxType::INTEGER => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -125,6 +152,11 @@ pub struct SchemaAttribute {
     pub multivalue: bool,
     pub index: Vec<IndexType>,
     pub syntax: SyntaxType,
+    // No two entries may share a value. The attrunique plugin enforces this.
+    pub unique: bool,
+    // Other names the attribute may be given by, such as its LDAP name.
+    // Values are always stored under the attribute's own name.
+    pub alias: Vec<String>,
 }
 
 impl SchemaAttribute {
@@ -184,14 +216,19 @@ impl SchemaAttribute {
                 .get_ava_single_syntax("syntax")
                 .ok_or(OperationError::InvalidSchemaState("missing syntax"))
         );
+        // unique - attribute types defined before this existed don't have it.
+        let unique = value.get_ava_single_bool("unique").unwrap_or(false);
+        let alias = value.get_ava_opt("alias");
 
         Ok(SchemaAttribute {
-            name: name.clone(),
+            name: name.to_string(),
             uuid: uuid.clone(),
-            description: description.clone(),
+            description: description.to_string(),
             multivalue: multivalue,
             index: index,
             syntax: syntax,
+            unique: unique,
+            alias: alias,
         })
     }
 
@@ -258,6 +295,34 @@ impl SchemaAttribute {
             .map(|_: ProtoFilter| ())
     }
 
+    fn validate_datetime(&self, v: &String) -> Result<(), SchemaError> {
+        DateTime::parse_from_rfc3339(v.as_str())
+            .map(|_| ())
+            .map_err(|_| SchemaError::InvalidAttributeSyntax)
+    }
+
+    // A signed 64 bit integer, in decimal.
+    fn validate_integer(&self, v: &String) -> Result<(), SchemaError> {
+        i64::from_str(v.as_str())
+            .map(|_| ())
+            .map_err(|_| SchemaError::InvalidAttributeSyntax)
+    }
+
+    // A tagged OpenSSH public key, "tag: type base64 [comment]".
+    fn validate_sshkey(&self, v: &String) -> Result<(), SchemaError> {
+        value::parse_sshkey(v.as_str())
+            .map(|_| ())
+            .map_err(|e| SchemaError::InvalidSshKey(v.clone(), e))
+    }
+
+    // Any absolute url. Where only some schemes make sense, such as http for
+    // a redirect, that is for whatever uses the value to check.
+    fn validate_url(&self, v: &String) -> Result<(), SchemaError> {
+        Url::parse(v.as_str())
+            .map(|_| ())
+            .map_err(|e| SchemaError::InvalidUrl(v.clone(), e.to_string()))
+    }
+
     fn validate_utf8string_insensitive(&self, v: &String) -> Result<(), SchemaError> {
         let t = v.to_lowercase();
         if &t == v {
@@ -279,78 +344,39 @@ impl SchemaAttribute {
             SyntaxType::UTF8STRING_INSENSITIVE => self.validate_utf8string_insensitive(v),
             SyntaxType::UTF8STRING_PRINCIPAL => self.validate_principal(v),
             SyntaxType::JSON_FILTER => self.validate_json_filter(v),
+            SyntaxType::DATETIME => self.validate_datetime(v),
+            SyntaxType::URL => self.validate_url(v),
+            SyntaxType::INTEGER => self.validate_integer(v),
+            SyntaxType::SSHKEY => self.validate_sshkey(v),
             _ => Ok(()),
         }
     }
 
-    pub fn validate_ava(&self, ava: &Vec<String>) -> Result<(), SchemaError> {
+    pub fn validate_ava(&self, ava: &Vec<Value>) -> Result<(), SchemaError> {
         debug!("Checking for ... {:?}", self);
         debug!("Checking ava -> {:?}", ava);
         // Check multivalue
         if self.multivalue == false && ava.len() > 1 {
             debug!("Ava len > 1 on single value attribute!");
-            return Err(SchemaError::InvalidAttributeSyntax);
+            return Err(SchemaError::MultipleValues(self.name.clone(), ava.len()));
         };
         // If syntax, check the type is correct
         match self.syntax {
-            SyntaxType::BOOLEAN => {
-                ava.iter().fold(Ok(()), |acc, v| {
-                    // If acc is err, fold will skip it.
-                    if acc.is_ok() {
-                        self.validate_bool(v)
-                    } else {
-                        acc
-                    }
-                })
-            }
-            SyntaxType::SYNTAX_ID => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    self.validate_syntax(v)
-                } else {
-                    acc
-                }
-            }),
-            SyntaxType::UUID => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    self.validate_uuid(v)
-                } else {
-                    acc
-                }
-            }),
-            // This is the same as a UUID, refint is a plugin
-            SyntaxType::REFERENCE_UUID => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    self.validate_uuid(v)
-                } else {
-                    acc
-                }
-            }),
-            SyntaxType::INDEX_ID => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    debug!("Checking index ... {}", v);
-                    self.validate_index(v)
-                } else {
-                    acc
-                }
-            }),
-            SyntaxType::UTF8STRING_INSENSITIVE => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    self.validate_utf8string_insensitive(v)
-                } else {
-                    acc
-                }
-            }),
-            SyntaxType::UTF8STRING_PRINCIPAL => ava.iter().fold(Ok(()), |acc, v| {
-                if acc.is_ok() {
-                    self.validate_principal(v)
-                } else {
-                    acc
-                }
-            }),
-            _ => Ok(()),
+            // Filters are only checked when they are used.
+            SyntaxType::JSON_FILTER => Ok(()),
+            _ => ava
+                .iter()
+                .try_for_each(|v| self.validate_value(&v.to_string())),
         }
     }
 
+    // Normalise a value and type it for this attribute's syntax.
+    pub fn to_value(&self, v: &Value) -> Result<Value, SchemaError> {
+        let v_norm = self.normalise_value(&v.to_string());
+        self.validate_value(&v_norm)?;
+        Value::new(&self.syntax, v_norm.as_str())
+    }
+
     pub fn normalise_syntax(&self, v: &String) -> String {
         v.to_uppercase()
     }
@@ -376,6 +402,33 @@ impl SchemaAttribute {
         }
     }
 
+    // Times are stored in UTC, whatever offset they were given with.
+    pub fn normalise_datetime(&self, v: &String) -> String {
+        match DateTime::parse_from_rfc3339(v.as_str()) {
+            Ok(dt) => dt.with_timezone(&Utc).to_rfc3339(),
+            Err(_) => v.clone(),
+        }
+    }
+
+    // Without a sign or leading zeros, so each number has one form.
+    pub fn normalise_integer(&self, v: &String) -> String {
+        match i64::from_str(v.trim()) {
+            Ok(i) => i.to_string(),
+            Err(_) => v.clone(),
+        }
+    }
+
+    // Parsing lowercases the scheme and host, converts an international
+    // domain name to its ascii (punycode) form, drops a default port, and
+    // gives an empty path as "/". So two ways of writing one url are stored
+    // the same, and compare equal.
+    pub fn normalise_url(&self, v: &String) -> String {
+        match Url::parse(v.as_str()) {
+            Ok(u) => u.into_string(),
+            Err(_) => v.clone(),
+        }
+    }
+
     // NOTE: This clones values, but it's hard to see a way around it.
     pub fn normalise_value(&self, v: &String) -> String {
         match self.syntax {
@@ -385,6 +438,10 @@ impl SchemaAttribute {
             SyntaxType::REFERENCE_UUID => self.normalise_uuid(v),
             SyntaxType::UTF8STRING_INSENSITIVE => self.normalise_utf8string_insensitive(v),
             SyntaxType::UTF8STRING_PRINCIPAL => self.normalise_principal(v),
+            SyntaxType::DATETIME => self.normalise_datetime(v),
+            SyntaxType::URL => self.normalise_url(v),
+            SyntaxType::INTEGER => self.normalise_integer(v),
+            SyntaxType::SSHKEY => value::normalise_sshkey(v.as_str()),
             _ => v.clone(),
         }
     }
@@ -445,9 +502,9 @@ impl SchemaClass {
         let must = value.get_ava_opt("must");
 
         Ok(SchemaClass {
-            name: name.clone(),
+            name: name.to_string(),
             uuid: uuid,
-            description: description.clone(),
+            description: description.to_string(),
             systemmay: systemmay,
             systemmust: systemmust,
             may: may,
@@ -461,6 +518,8 @@ pub struct SchemaInner {
     // We contain sets of classes and attributes.
     classes: HashMap<String, SchemaClass>,
     attributes: HashMap<String, SchemaAttribute>,
+    // The attribute each alias stands for.
+    aliases: HashMap<String, String>,
 }
 
 pub trait SchemaTransaction {
@@ -474,6 +533,17 @@ pub trait SchemaTransaction {
         self.get_inner().is_multivalue(attr)
     }
 
+    // Attribute names are case insensitive, and an alias is replaced by the
+    // name of the attribute it stands for. Every name from outside the server
+    // goes through this before it's looked up.
+    fn normalise_attr_name(&self, attr: &str) -> String {
+        let attr = attr.to_lowercase();
+        match self.get_inner().aliases.get(&attr) {
+            Some(name) => name.clone(),
+            None => attr,
+        }
+    }
+
     // Probably need something like get_classes or similar
     // so that externals can call and use this data.
 
@@ -485,6 +555,21 @@ pub trait SchemaTransaction {
         &self.get_inner().attributes
     }
 
+    fn get_unique_types(&self) -> Vec<&SchemaAttribute> {
+        self.get_attributes()
+            .values()
+            .filter(|sa| sa.unique)
+            .collect()
+    }
+
+    // Every (attribute, index type) the backend should maintain.
+    fn get_idxmeta(&self) -> BTreeSet<(String, IndexType)> {
+        self.get_attributes()
+            .values()
+            .flat_map(|sa| sa.index.iter().map(move |i| (sa.name.clone(), i.clone())))
+            .collect()
+    }
+
     fn get_reference_types(&self) -> HashMap<&String, &SchemaAttribute> {
         self.get_attributes()
             .iter()
@@ -504,6 +589,7 @@ impl SchemaInner {
             let mut s = SchemaInner {
                 classes: HashMap::new(),
                 attributes: HashMap::new(),
+                aliases: HashMap::new(),
             };
             // Bootstrap in definitions of our own schema types
             // First, add all the needed core attributes for schema parsing
@@ -517,6 +603,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![String::from("objectclass")],
                 },
             );
             s.attributes.insert(
@@ -529,6 +617,8 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UUID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -539,8 +629,11 @@ impl SchemaInner {
                         .expect("unable to parse static uuid"),
                     description: String::from("The shortform name of an object"),
                     multivalue: false,
-                    index: vec![IndexType::EQUALITY],
+                    // Substrings are indexed for typeahead.
+                    index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: true,
+                    alias: vec![String::from("uid")],
                 },
             );
             s.attributes.insert(
@@ -552,6 +645,8 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_PRINCIPAL,
+                    unique: true,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -564,6 +659,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(String::from("multivalue"), SchemaAttribute {
@@ -573,7 +670,41 @@ impl SchemaInner {
                 multivalue: false,
                 index: vec![],
                 syntax: SyntaxType::BOOLEAN,
+                unique: false,
+                alias: vec![],
             });
+            s.attributes.insert(
+                String::from("unique"),
+                SchemaAttribute {
+                    name: String::from("unique"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_UNIQUE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "If true, no two entries may hold the same value of this attribute.",
+                    ),
+                    multivalue: false,
+                    index: vec![],
+                    syntax: SyntaxType::BOOLEAN,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("alias"),
+                SchemaAttribute {
+                    name: String::from("alias"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ALIAS)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "Another name of an attribute, which is stored under its own name.",
+                    ),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: true,
+                    alias: vec![],
+                },
+            );
             s.attributes.insert(
                 String::from("index"),
                 SchemaAttribute {
@@ -586,6 +717,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::INDEX_ID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -600,6 +733,8 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::SYNTAX_ID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -614,6 +749,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -628,6 +765,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -642,6 +781,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -656,6 +797,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             // SYSINFO attrs
@@ -666,10 +809,74 @@ impl SchemaInner {
                     name: String::from("acp_enable"),
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_ENABLE)
                         .expect("unable to parse static uuid"),
-                    description: String::from("A flag to determine if this ACP is active for application. True is enabled, and enforce. False is checked but not enforced."),
+                    description: String::from("A flag to determine if this ACP is active for application. True is enabled, and enforce. False is disabled, unless acp_log_only is set."),
+                    multivalue: false,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::BOOLEAN,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_log_only"),
+                SchemaAttribute {
+                    name: String::from("acp_log_only"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_LOG_ONLY)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("A flag to check a disabled ACP without enforcing it. The decisions it would change are written to the audit log."),
                     multivalue: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::BOOLEAN,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_ticket_ref"),
+                SchemaAttribute {
+                    name: String::from("acp_ticket_ref"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_TICKET_REF)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "References to the change requests that led to this ACP.",
+                    ),
+                    multivalue: true,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_test_allow"),
+                SchemaAttribute {
+                    name: String::from("acp_test_allow"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_TEST_ALLOW)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "Operations, as json, that the access controls in force must allow. Checked by verify.",
+                    ),
+                    multivalue: true,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_test_deny"),
+                SchemaAttribute {
+                    name: String::from("acp_test_deny"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_TEST_DENY)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "Operations, as json, that the access controls in force must deny. Checked by verify.",
+                    ),
+                    multivalue: true,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
                 },
             );
 
@@ -685,6 +892,8 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -699,6 +908,8 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -711,6 +922,36 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_compare_attr"),
+                SchemaAttribute {
+                    name: String::from("acp_compare_attr"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_COMPARE_ATTR)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The attributes whose values may be compared by the reciever on targetscope, without being able to read them."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_operation"),
+                SchemaAttribute {
+                    name: String::from("acp_operation"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_OPERATION)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The server operations the reciever may run."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -725,6 +966,24 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_delete_class"),
+                SchemaAttribute {
+                    name: String::from("acp_delete_class"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_DELETE_CLASS)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "If set, only entries with one of these classes may be deleted.",
+                    ),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -739,6 +998,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
 
@@ -752,6 +1013,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -764,6 +1027,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -772,10 +1037,68 @@ impl SchemaInner {
                     name: String::from("acp_modify_class"),
                     uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS)
                         .expect("unable to parse static uuid"),
-                    description: String::from("The set of class values that could be asserted or added to an entry. Only applies to modify::present operations on class."),
+                    description: String::from("The set of class values that could be added to or removed from an entry. This is equivalent to setting both acp_modify_class_add and acp_modify_class_remove."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_modify_class_add"),
+                SchemaAttribute {
+                    name: String::from("acp_modify_class_add"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS_ADD)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The set of class values that could be added to an entry. Only applies to modify::present operations on class."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_modify_class_remove"),
+                SchemaAttribute {
+                    name: String::from("acp_modify_class_remove"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS_REMOVE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The set of class values that could be removed from an entry. Only applies to modify::removed operations on class."),
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_modify_presentvalue"),
+                SchemaAttribute {
+                    name: String::from("acp_modify_presentvalue"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTVALUE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("Constrains the values that may be added to an attribute, as attr=value. The value self is the uuid of the modifying entry."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_modify_removedvalue"),
+                SchemaAttribute {
+                    name: String::from("acp_modify_removedvalue"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDVALUE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("Constrains the values that may be removed from an attribute, as attr=value. The value self is the uuid of the modifying entry."),
+                    multivalue: true,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             // MO/Member
@@ -789,6 +1112,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -801,6 +1126,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             s.attributes.insert(
@@ -813,6 +1140,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             // Migration related
@@ -828,6 +1157,55 @@ impl SchemaInner {
                     multivalue: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            // The domain_info entry.
+            s.attributes.insert(
+                String::from("domain_name"),
+                SchemaAttribute {
+                    name: String::from("domain_name"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_NAME)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The DNS name of the domain, which principal names end with.",
+                    ),
+                    multivalue: false,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("domain_uuid"),
+                SchemaAttribute {
+                    name: String::from("domain_uuid"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_UUID)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The uuid of the domain, shared by every server replicating it.",
+                    ),
+                    multivalue: false,
+                    index: vec![],
+                    syntax: SyntaxType::UUID,
+                    unique: false,
+                    alias: vec![],
+                },
+            );
+            s.attributes.insert(
+                String::from("domain_feature"),
+                SchemaAttribute {
+                    name: String::from("domain_feature"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_FEATURE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("An optional feature enabled for the domain."),
+                    multivalue: true,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
             // Domain for sysinfo
@@ -841,6 +1219,8 @@ impl SchemaInner {
                     multivalue: true,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                    unique: false,
+                    alias: vec![],
                 },
             );
 
@@ -851,7 +1231,11 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ATTRIBUTETYPE)
                         .expect("unable to parse static uuid"),
                     description: String::from("Definition of a schema attribute"),
-                    systemmay: vec![String::from("index")],
+                    systemmay: vec![
+                        String::from("index"),
+                        String::from("unique"),
+                        String::from("alias"),
+                    ],
                     may: vec![],
                     systemmust: vec![
                         String::from("class"),
@@ -982,6 +1366,23 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("domain_info"),
+                SchemaClass {
+                    name: String::from("domain_info"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_DOMAIN_INFO)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The domain this server belongs to"),
+                    systemmay: vec![String::from("description"), String::from("domain_feature")],
+                    may: vec![],
+                    systemmust: vec![
+                        String::from("domain_name"),
+                        String::from("domain_uuid"),
+                        String::from("version"),
+                    ],
+                    must: vec![],
+                },
+            );
             // ACP
             s.classes.insert(
                 String::from("access_control_profile"),
@@ -990,7 +1391,13 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE)
                         .expect("unable to parse static uuid"),
                     description: String::from("System Access Control Profile Class"),
-                    systemmay: vec!["description".to_string()],
+                    systemmay: vec![
+                        "description".to_string(),
+                        "acp_log_only".to_string(),
+                        "acp_ticket_ref".to_string(),
+                        "acp_test_allow".to_string(),
+                        "acp_test_deny".to_string(),
+                    ],
                     may: vec![],
                     systemmust: vec![
                         "acp_enable".to_string(),
@@ -1013,6 +1420,45 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("access_control_compare"),
+                SchemaClass {
+                    name: String::from("access_control_compare"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_COMPARE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System Access Control Compare Class"),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec!["acp_compare_attr".to_string()],
+                    must: vec![],
+                },
+            );
+            s.classes.insert(
+                String::from("access_control_audit_read"),
+                SchemaClass {
+                    name: String::from("access_control_audit_read"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_AUDIT_READ)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System Access Control Audit Read Class"),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec![],
+                    must: vec![],
+                },
+            );
+            s.classes.insert(
+                String::from("access_control_operation"),
+                SchemaClass {
+                    name: String::from("access_control_operation"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_OPERATION)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System Access Control Operation Class"),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec!["acp_operation".to_string()],
+                    must: vec![],
+                },
+            );
             s.classes.insert(
                 String::from("access_control_delete"),
                 SchemaClass {
@@ -1020,7 +1466,7 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE)
                         .expect("unable to parse static uuid"),
                     description: String::from("System Access Control DELETE Class"),
-                    systemmay: vec![],
+                    systemmay: vec!["acp_delete_class".to_string()],
                     may: vec![],
                     systemmust: vec![],
                     must: vec![],
@@ -1037,6 +1483,10 @@ impl SchemaInner {
                         "acp_modify_removedattr".to_string(),
                         "acp_modify_presentattr".to_string(),
                         "acp_modify_class".to_string(),
+                        "acp_modify_class_add".to_string(),
+                        "acp_modify_class_remove".to_string(),
+                        "acp_modify_presentvalue".to_string(),
+                        "acp_modify_removedvalue".to_string(),
                     ],
                     may: vec![],
                     systemmust: vec![],
@@ -1059,6 +1509,19 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("access_control_deny"),
+                SchemaClass {
+                    name: String::from("access_control_deny"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_DENY)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System Access Control Deny Class. Marks a profile as removing, rather than granting, the rights it describes."),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec![],
+                    must: vec![],
+                },
+            );
             s.classes.insert(
                 String::from("system"),
                 SchemaClass {
@@ -1073,6 +1536,7 @@ impl SchemaInner {
                 },
             );
 
+            s.update_aliases();
             let r = s.validate(&mut au);
             if r.len() == 0 {
                 Ok(s)
@@ -1085,8 +1549,29 @@ impl SchemaInner {
         r
     }
 
+    fn update_aliases(&mut self) {
+        self.aliases = self
+            .attributes
+            .values()
+            .flat_map(|a| a.alias.iter().map(move |al| (al.clone(), a.name.clone())))
+            .collect();
+    }
+
     pub fn validate(&self, _audit: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
         let mut res = Vec::new();
+        // An alias can't also be the name of an attribute, or we couldn't
+        // tell which was meant. Two attributes can't share one either, which
+        // the unique alias attribute already prevents for stored schema.
+        for a in self.attributes.values() {
+            for al in &a.alias {
+                if self.attributes.contains_key(al) || self.aliases.get(al) != Some(&a.name) {
+                    res.push(Err(ConsistencyError::SchemaAliasConflict(
+                        a.name.clone(),
+                        al.clone(),
+                    )))
+                }
+            }
+        }
         // Does this need to validate anything further at all? The UUID
         // will be checked as part of the schema migration on startup, so I think
         // just that all the content is sane is fine.
@@ -1167,7 +1652,7 @@ impl SchemaInner {
             Some(a_schema) => Ok(a_schema.multivalue),
             None => {
                 debug!("Attribute does not exist?!");
-                return Err(SchemaError::InvalidAttribute);
+                return Err(SchemaError::InvalidAttribute(attr_name.to_string()));
             }
         }
     }
@@ -1204,6 +1689,7 @@ impl<'a> SchemaWriteTransaction<'a> {
         attributetypes.into_iter().for_each(|a| {
             self.inner.attributes.insert(a.name.clone(), a);
         });
+        self.inner.update_aliases();
         Ok(())
     }
 
@@ -1286,6 +1772,7 @@ mod tests {
     // use crate::filter::{Filter, FilterValid};
     use crate::schema::SchemaTransaction;
     use crate::schema::{IndexType, Schema, SchemaAttribute, SchemaClass, SyntaxType};
+    use crate::value::Value;
     use serde_json;
     use std::convert::TryFrom;
     use uuid::Uuid;
@@ -1619,6 +2106,8 @@ mod tests {
                 multivalue: false,
                 index: vec![IndexType::EQUALITY],
                 syntax: SyntaxType::UTF8STRING_PRINCIPAL,
+                unique: false,
+                alias: vec![],
             };
 
         let r1 = sa.validate_principal(&String::from("a@a"));
@@ -1649,6 +2138,8 @@ mod tests {
             multivalue: false,
             index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
             syntax: SyntaxType::JSON_FILTER,
+            unique: false,
+            alias: vec![],
         };
 
         // Outright wrong
@@ -1673,6 +2164,93 @@ mod tests {
         assert!(r5.is_ok());
     }
 
+    #[test]
+    fn test_schema_syntax_url() {
+        let sa = SchemaAttribute {
+            name: String::from("website"),
+            uuid: Uuid::new_v4(),
+            description: String::from(""),
+            multivalue: true,
+            index: vec![],
+            syntax: SyntaxType::URL,
+            unique: false,
+            alias: vec![],
+        };
+
+        assert_eq!(
+            sa.normalise_value(&String::from("HTTPS://Example.COM:443")),
+            "https://example.com/"
+        );
+        assert_eq!(
+            sa.normalise_value(&String::from("https://bücher.example/")),
+            "https://xn--bcher-kva.example/"
+        );
+        assert!(sa
+            .validate_url(&String::from("mailto:admin@example.com"))
+            .is_ok());
+
+        // The error says which value was wrong.
+        match sa.validate_url(&String::from("/relative/path")) {
+            Err(SchemaError::InvalidUrl(v, _)) => assert_eq!(v, "/relative/path"),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(sa.to_value(&Value::from("http://[::1")).is_err());
+    }
+
+    #[test]
+    fn test_schema_syntax_integer() {
+        let sa = SchemaAttribute {
+            name: String::from("uidnumber"),
+            uuid: Uuid::new_v4(),
+            description: String::from(""),
+            multivalue: false,
+            index: vec![],
+            syntax: SyntaxType::INTEGER,
+            unique: false,
+            alias: vec![],
+        };
+
+        assert_eq!(sa.normalise_value(&String::from(" +0042")), "42");
+        assert_eq!(sa.normalise_value(&String::from("-7")), "-7");
+        assert!(sa.validate_integer(&String::from("12")).is_ok());
+        assert!(sa.validate_integer(&String::from("1.5")).is_err());
+        assert!(sa.validate_integer(&String::from("twelve")).is_err());
+        // Out of range for 64 bits.
+        assert!(sa
+            .validate_integer(&String::from("9223372036854775808"))
+            .is_err());
+        assert!(sa.to_value(&Value::from("007")) == Ok(Value::Integer(7)));
+    }
+
+    #[test]
+    fn test_schema_syntax_sshkey() {
+        let sa = SchemaAttribute {
+            name: String::from("ssh_publickey"),
+            uuid: Uuid::new_v4(),
+            description: String::from(""),
+            multivalue: true,
+            index: vec![],
+            syntax: SyntaxType::SSHKEY,
+            unique: false,
+            alias: vec![],
+        };
+
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+        assert!(sa.validate_sshkey(&format!("laptop: {}", key)).is_ok());
+        assert!(sa.validate_sshkey(&String::from(key)).is_err());
+        match sa.validate_sshkey(&String::from("laptop: ssh-ed25519 AAAA")) {
+            Err(SchemaError::InvalidSshKey(_, _)) => {}
+            _ => panic!("Invalid key accepted"),
+        }
+        // A key from before tags is given one as it's typed.
+        assert!(
+            sa.to_value(&Value::from(format!("{} william@laptop", key).as_str()))
+                .map(|v| v.to_string())
+                == Ok(format!("william@laptop: {} william@laptop", key))
+        );
+    }
+
     #[test]
     fn test_schema_normalise_uuid() {
         let sa = SchemaAttribute {
@@ -1682,6 +2260,8 @@ mod tests {
             multivalue: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UUID,
+            unique: false,
+            alias: vec![],
         };
         let u1 = String::from("936DA01F9ABD4d9d80C702AF85C822A8");
 
@@ -1702,14 +2282,22 @@ mod tests {
             multivalue: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+            unique: false,
+            alias: vec![],
         };
 
-        let r1 = single_value_string.validate_ava(&vec![String::from("test")]);
+        let r1 = single_value_string.validate_ava(&vec![Value::from("test")]);
         assert_eq!(r1, Ok(()));
 
         let r2 =
-            single_value_string.validate_ava(&vec![String::from("test1"), String::from("test2")]);
-        assert_eq!(r2, Err(SchemaError::InvalidAttributeSyntax));
+            single_value_string.validate_ava(&vec![Value::from("test1"), Value::from("test2")]);
+        assert_eq!(
+            r2,
+            Err(SchemaError::MultipleValues(
+                single_value_string.name.clone(),
+                2
+            ))
+        );
 
         // test multivalue string, boolean
 
@@ -1721,10 +2309,11 @@ mod tests {
             multivalue: true,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING,
+            unique: false,
+            alias: vec![],
         };
 
-        let r5 =
-            multi_value_string.validate_ava(&vec![String::from("test1"), String::from("test2")]);
+        let r5 = multi_value_string.validate_ava(&vec![Value::from("test1"), Value::from("test2")]);
         assert_eq!(r5, Ok(()));
 
         let multi_value_boolean = SchemaAttribute {
@@ -1735,14 +2324,15 @@ mod tests {
             multivalue: true,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::BOOLEAN,
+            unique: false,
+            alias: vec![],
         };
 
         let r3 =
-            multi_value_boolean.validate_ava(&vec![String::from("test1"), String::from("test2")]);
+            multi_value_boolean.validate_ava(&vec![Value::from("test1"), Value::from("test2")]);
         assert_eq!(r3, Err(SchemaError::InvalidAttributeSyntax));
 
-        let r4 =
-            multi_value_boolean.validate_ava(&vec![String::from("true"), String::from("false")]);
+        let r4 = multi_value_boolean.validate_ava(&vec![Value::from("true"), Value::from("false")]);
         assert_eq!(r4, Ok(()));
 
         // syntax_id and index_type values
@@ -1754,12 +2344,14 @@ mod tests {
             multivalue: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::SYNTAX_ID,
+            unique: false,
+            alias: vec![],
         };
 
-        let r6 = single_value_syntax.validate_ava(&vec![String::from("UTF8STRING")]);
+        let r6 = single_value_syntax.validate_ava(&vec![Value::from("UTF8STRING")]);
         assert_eq!(r6, Ok(()));
 
-        let r7 = single_value_syntax.validate_ava(&vec![String::from("thaeountaheu")]);
+        let r7 = single_value_syntax.validate_ava(&vec![Value::from("thaeountaheu")]);
         assert_eq!(r7, Err(SchemaError::InvalidAttributeSyntax));
 
         let single_value_index = SchemaAttribute {
@@ -1770,19 +2362,20 @@ mod tests {
             multivalue: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::INDEX_ID,
+            unique: false,
+            alias: vec![],
         };
         //
-        let r8 = single_value_index.validate_ava(&vec![String::from("EQUALITY")]);
+        let r8 = single_value_index.validate_ava(&vec![Value::from("EQUALITY")]);
         assert_eq!(r8, Ok(()));
 
-        let r9 = single_value_index.validate_ava(&vec![String::from("thaeountaheu")]);
+        let r9 = single_value_index.validate_ava(&vec![Value::from("thaeountaheu")]);
         assert_eq!(r9, Err(SchemaError::InvalidAttributeSyntax));
     }
 
     #[test]
     fn test_schema_classes_simple() {
         // Test basic functions of simple attributes
-
     }
 
     #[test]
@@ -1818,7 +2411,7 @@ mod tests {
 
         assert_eq!(
             e_no_uuid.validate(&schema),
-            Err(SchemaError::MissingMustAttribute("uuid".to_string()))
+            Err(vec![SchemaError::MissingMustAttribute("uuid".to_string())])
         );
 
         let e_no_class: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
@@ -1832,7 +2425,10 @@ mod tests {
         )
         .expect("json parse failure");
 
-        assert_eq!(e_no_class.validate(&schema), Err(SchemaError::InvalidClass));
+        assert_eq!(
+            e_no_class.validate(&schema),
+            Err(vec![SchemaError::InvalidClass])
+        );
 
         let e_bad_class: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
             r#"{
@@ -1847,7 +2443,7 @@ mod tests {
         .expect("json parse failure");
         assert_eq!(
             e_bad_class.validate(&schema),
-            Err(SchemaError::InvalidClass)
+            Err(vec![SchemaError::InvalidClass])
         );
 
         let e_attr_invalid: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
@@ -1864,7 +2460,10 @@ mod tests {
 
         let res = e_attr_invalid.validate(&schema);
         assert!(match res {
-            Err(SchemaError::MissingMustAttribute(_)) => true,
+            Err(errs) => errs.iter().all(|e| match e {
+                SchemaError::MissingMustAttribute(_) => true,
+                _ => false,
+            }),
             _ => false,
         });
 
@@ -1887,7 +2486,7 @@ mod tests {
 
         assert_eq!(
             e_attr_invalid_may.validate(&schema),
-            Err(SchemaError::InvalidAttribute)
+            Err(vec![SchemaError::InvalidAttribute("zzzzz".to_string())])
         );
 
         let e_attr_invalid_syn: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
@@ -1908,7 +2507,7 @@ mod tests {
 
         assert_eq!(
             e_attr_invalid_syn.validate(&schema),
-            Err(SchemaError::InvalidAttributeSyntax)
+            Err(vec![SchemaError::InvalidAttributeSyntax])
         );
 
         let e_ok: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
@@ -2026,6 +2625,41 @@ mod tests {
         println!("{}", audit);
     }
 
+    #[test]
+    fn test_schema_entry_all_errors() {
+        // Every problem with an entry is reported, not just the first.
+        let mut audit = AuditScope::new("test_schema_entry_all_errors");
+        let schema_outer = Schema::new(&mut audit).expect("failed to create schema");
+        let schema = schema_outer.read();
+
+        let e_many: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "attributetype"],
+                "name": ["testattr"],
+                "multivalue": ["zzzzz"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"],
+                "zzzzz": ["zzzz"]
+            }
+        }"#,
+        )
+        .expect("json parse failure");
+
+        let errs = e_many
+            .validate(&schema)
+            .expect_err("entry should not be valid");
+        assert!(errs.len() == 4);
+        assert!(errs.contains(&SchemaError::MissingMustAttribute(
+            "description".to_string()
+        )));
+        assert!(errs.contains(&SchemaError::MissingMustAttribute("syntax".to_string())));
+        assert!(errs.contains(&SchemaError::InvalidAttribute("zzzzz".to_string())));
+        assert!(errs.contains(&SchemaError::InvalidAttributeSyntax));
+        println!("{}", audit);
+    }
+
     #[test]
     fn test_schema_extensible() {
         let mut audit = AuditScope::new("test_schema_extensible");
@@ -2048,7 +2682,7 @@ mod tests {
 
         assert_eq!(
             e_extensible_bad.validate(&schema),
-            Err(SchemaError::InvalidAttributeSyntax)
+            Err(vec![SchemaError::InvalidAttributeSyntax])
         );
 
         let e_extensible: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
@@ -2078,7 +2712,7 @@ mod tests {
         let f_mixed = filter_all!(f_eq("nonClAsS", "attributetype"));
         assert_eq!(
             f_mixed.validate(&schema),
-            Err(SchemaError::InvalidAttribute)
+            Err(SchemaError::InvalidAttribute("nonclass".to_string()))
         );
 
         // test syntax of bool
@@ -2093,6 +2727,12 @@ mod tests {
             f_insense.validate(&schema),
             Ok(unsafe { filter_valid!(f_eq("class", "attributetype")) })
         );
+        // Only ordered syntaxes can be ranged over.
+        let f_ge = filter_all!(f_ge("name", "a"));
+        assert_eq!(
+            f_ge.validate(&schema),
+            Err(SchemaError::UnorderedSyntax("name".to_string()))
+        );
         // Test the recursive structures validate
         let f_or_empty = filter_all!(f_or!([]));
         assert_eq!(f_or_empty.validate(&schema), Err(SchemaError::EmptyFilter));
@@ -2128,6 +2768,60 @@ mod tests {
         println!("{}", audit);
     }
 
+    #[test]
+    fn test_schema_attr_alias() {
+        let mut audit = AuditScope::new("test_schema_attr_alias");
+        let schema_outer = Schema::new(&mut audit).expect("failed to create schema");
+        let mut schema = schema_outer.write();
+
+        assert_eq!(schema.normalise_attr_name("UID"), "name");
+        assert_eq!(schema.normalise_attr_name("objectClass"), "class");
+        assert_eq!(schema.normalise_attr_name("Description"), "description");
+
+        // Values given under an alias and the real name are merged.
+        let e_test: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "objectClass": ["Object"],
+                "class": ["extensibleobject"],
+                "uid": ["TestPerson"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"]
+            }
+        }"#,
+        )
+        .expect("json parse failure");
+        let e_normal = e_test.normalise(&schema).expect("normalise failure");
+        assert!(e_normal.attribute_value_pres("class", "object"));
+        assert!(e_normal.attribute_value_pres("class", "extensibleobject"));
+        assert!(e_normal.attribute_value_pres("name", "testperson"));
+        assert!(!e_normal.attribute_pres("uid"));
+
+        let f_alias = filter_all!(f_and!([f_eq("uid", "TestPerson"), f_pres("objectclass")]));
+        assert_eq!(
+            f_alias.validate(&schema),
+            Ok(unsafe { filter_valid!(f_and!([f_eq("name", "testperson"), f_pres("class")])) })
+        );
+
+        // An alias that is already an attribute's name is rejected.
+        let mut attrs: Vec<SchemaAttribute> = schema.get_attributes().values().cloned().collect();
+        attrs
+            .iter_mut()
+            .find(|a| a.name == "description")
+            .expect("description missing")
+            .alias = vec!["name".to_string()];
+        schema.update_attributes(attrs).expect("update failure");
+        assert_eq!(
+            schema.validate(&mut audit),
+            vec![Err(ConsistencyError::SchemaAliasConflict(
+                "description".to_string(),
+                "name".to_string()
+            ))]
+        );
+        println!("{}", audit);
+    }
+
     #[test]
     fn test_schema_filter_normalisation() {
         // Test mixed case attr name
@@ -0,0 +1,722 @@
+// SCIM 2.0 provisioning (rfc7643, rfc7644), so that sources such as Okta and
+// Azure AD can drive the accounts and groups here. Users and Groups are views
+// over account and group entries, and each request becomes the same events as
+// the v1 api, made as the holder of the session, so access controls apply as
+// they do to any other change.
+//
+// Attributes of the resources that we have nothing for are ignored, as are
+// those the server maintains itself, such as a user's groups.
+
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+
+use crate::audit::AuditScope;
+use crate::error::OperationError;
+use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, SearchEvent};
+use crate::proto::v1::{
+    Entry as ProtoEntry, Filter as ProtoFilter, Modify as ProtoModify,
+    ModifyList as ProtoModifyList, UserAuthToken,
+};
+use crate::server::{QueryServer, QueryServerTransaction};
+
+static SCIM_SCHEMA_USER: &'static str = "urn:ietf:params:scim:schemas:core:2.0:User";
+static SCIM_SCHEMA_GROUP: &'static str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+static SCIM_SCHEMA_LIST_RESPONSE: &'static str =
+    "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+static SCIM_SCHEMA_ERROR: &'static str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+// (SCIM attribute, our attribute, multivalued, writable). Multivalued SCIM
+// attributes are lists of {"value": ...}.
+type ScimAttr = (&'static str, &'static str, bool, bool);
+
+static USER_ATTRS: &'static [ScimAttr] = &[
+    ("id", "uuid", false, false),
+    ("userName", "name", false, true),
+    ("displayName", "displayname", false, true),
+    ("emails", "mail", true, true),
+    ("groups", "memberof", true, false),
+];
+
+static GROUP_ATTRS: &'static [ScimAttr] = &[
+    ("id", "uuid", false, false),
+    ("displayName", "name", false, true),
+    ("members", "member", true, true),
+];
+
+fn invalid(what: &'static str) -> OperationError {
+    OperationError::InvalidScimRequest(what)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScimResourceType {
+    User,
+    Group,
+}
+
+impl ScimResourceType {
+    // From the endpoint, as /scim/v2/Users.
+    pub fn from_path(p: &str) -> Option<Self> {
+        match p {
+            "Users" => Some(ScimResourceType::User),
+            "Groups" => Some(ScimResourceType::Group),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScimResourceType::User => "User",
+            ScimResourceType::Group => "Group",
+        }
+    }
+
+    fn schema(self) -> &'static str {
+        match self {
+            ScimResourceType::User => SCIM_SCHEMA_USER,
+            ScimResourceType::Group => SCIM_SCHEMA_GROUP,
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            ScimResourceType::User => "account",
+            ScimResourceType::Group => "group",
+        }
+    }
+
+    // Users are people, so they may have mail.
+    fn classes(self) -> Vec<String> {
+        let classes: &[&str] = match self {
+            ScimResourceType::User => &["object", "account", "person"],
+            ScimResourceType::Group => &["object", "group"],
+        };
+        classes.iter().map(|c| c.to_string()).collect()
+    }
+
+    fn attrs(self) -> &'static [ScimAttr] {
+        match self {
+            ScimResourceType::User => USER_ATTRS,
+            ScimResourceType::Group => GROUP_ATTRS,
+        }
+    }
+
+    // SCIM attribute names are case insensitive, and may be given with
+    // their schema. The value of a multivalued attribute stands for the
+    // attribute.
+    fn lookup(self, path: &str) -> Option<&'static ScimAttr> {
+        let prefix = format!("{}:", self.schema());
+        let path = match path.get(..prefix.len()) {
+            Some(p) if p.eq_ignore_ascii_case(&prefix) => &path[prefix.len()..],
+            _ => path,
+        };
+        let path = match path.find('.') {
+            Some(i) if path[i + 1..].eq_ignore_ascii_case("value") => &path[..i],
+            _ => path,
+        };
+        self.attrs()
+            .iter()
+            .find(|(scim, _, _, _)| scim.eq_ignore_ascii_case(path))
+    }
+}
+
+#[derive(Debug)]
+pub enum ScimOp {
+    // With the filter, if there is one.
+    List(Option<String>),
+    Get(String),
+    Create(JsonValue),
+    Patch(String, JsonValue),
+    Delete(String),
+}
+
+// The values of an attribute in a resource. Either form is taken for any
+// attribute, so a single value may be given for a multivalued one.
+fn values(v: &JsonValue) -> Result<Vec<String>, OperationError> {
+    let value = |v: &JsonValue| match v {
+        JsonValue::String(s) => Ok(s.clone()),
+        JsonValue::Object(o) => o
+            .get("value")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| invalid("multivalued attribute without a value")),
+        _ => Err(invalid("attribute values must be strings")),
+    };
+    match v {
+        JsonValue::Null => Ok(Vec::new()),
+        JsonValue::Array(vs) => vs.iter().map(value).collect(),
+        v => value(v).map(|v| vec![v]),
+    }
+}
+
+pub fn to_resource(rt: ScimResourceType, pe: &ProtoEntry) -> JsonValue {
+    let mut r = Map::new();
+    r.insert("schemas".to_string(), JsonValue::from(vec![rt.schema()]));
+    for (scim, attr, multi, _) in rt.attrs() {
+        let vs = match pe.attrs.get(*attr) {
+            Some(vs) if !vs.is_empty() => vs,
+            _ => continue,
+        };
+        let v = if *multi {
+            JsonValue::Array(
+                vs.iter()
+                    .map(|v| {
+                        let mut o = Map::new();
+                        o.insert("value".to_string(), JsonValue::from(v.as_str()));
+                        JsonValue::Object(o)
+                    })
+                    .collect(),
+            )
+        } else {
+            JsonValue::from(vs[0].as_str())
+        };
+        r.insert(scim.to_string(), v);
+    }
+    let mut meta = Map::new();
+    meta.insert("resourceType".to_string(), JsonValue::from(rt.name()));
+    if let Some(lm) = pe.attrs.get("last_modified").and_then(|vs| vs.first()) {
+        meta.insert("lastModified".to_string(), JsonValue::from(lm.as_str()));
+    }
+    r.insert("meta".to_string(), JsonValue::Object(meta));
+    JsonValue::Object(r)
+}
+
+pub fn from_resource(rt: ScimResourceType, r: &JsonValue) -> Result<ProtoEntry, OperationError> {
+    let r = r
+        .as_object()
+        .ok_or_else(|| invalid("resource is not an object"))?;
+    let mut attrs = BTreeMap::new();
+    attrs.insert("class".to_string(), rt.classes());
+    for (k, v) in r.iter() {
+        if let Some((_, attr, _, true)) = rt.lookup(k) {
+            let vs = values(v)?;
+            if !vs.is_empty() {
+                attrs.insert(attr.to_string(), vs);
+            }
+        }
+    }
+    if !attrs.contains_key("name") {
+        return Err(invalid(match rt {
+            ScimResourceType::User => "userName is required",
+            ScimResourceType::Group => "displayName is required",
+        }));
+    }
+    // An account must have a displayname, which a SCIM user needn't.
+    if rt == ScimResourceType::User && !attrs.contains_key("displayname") {
+        let name = attrs.get("name").cloned().unwrap_or_else(Vec::new);
+        attrs.insert("displayname".to_string(), name);
+    }
+    Ok(ProtoEntry { attrs: attrs })
+}
+
+// One `attrPath op "value"` comparison, and what is left after it. Only
+// string values are taken, as every attribute we map is a string.
+fn parse_comparison(s: &str) -> Result<(&str, String, String, &str), OperationError> {
+    let bad = |what: &str| OperationError::InvalidFilter(format!("{} in {:?}", what, s));
+    let rest = s.trim_start();
+    let end = rest.find(' ').ok_or_else(|| bad("no operator"))?;
+    let path = &rest[..end];
+    let rest = rest[end..].trim_start();
+    let end = rest.find(' ').ok_or_else(|| bad("no value"))?;
+    let op = rest[..end].to_lowercase();
+    let rest = rest[end..].trim_start();
+    if !rest.starts_with('"') {
+        return Err(bad("value is not a string"));
+    }
+    let mut escaped = false;
+    let mut close = None;
+    for (i, c) in rest.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            close = Some(i);
+            break;
+        }
+    }
+    let close = close.ok_or_else(|| bad("unterminated string"))?;
+    let value: String = serde_json::from_str(&rest[..=close]).map_err(|_| bad("invalid string"))?;
+    Ok((path, op, value, &rest[close + 1..]))
+}
+
+// A SCIM filter, rfc7644 3.4.2.2, as comparisons joined by and. Each is eq,
+// or co, sw or ew for a substring.
+pub fn to_filter(rt: ScimResourceType, s: Option<&str>) -> Result<ProtoFilter, OperationError> {
+    let mut terms = vec![ProtoFilter::Eq("class".to_string(), rt.class().to_string())];
+    let mut rest = s.unwrap_or("").trim();
+    while !rest.is_empty() {
+        let (path, op, value, r) = parse_comparison(rest)?;
+        let attr = rt
+            .lookup(path)
+            .map(|(_, attr, _, _)| attr.to_string())
+            .ok_or_else(|| OperationError::InvalidFilter(format!("unknown attribute {}", path)))?;
+        terms.push(match op.as_str() {
+            "eq" => ProtoFilter::Eq(attr, value),
+            "co" => ProtoFilter::Sub(attr, value),
+            "sw" => ProtoFilter::StartsWith(attr, value),
+            "ew" => ProtoFilter::EndsWith(attr, value),
+            _ => {
+                return Err(OperationError::InvalidFilter(format!(
+                    "unsupported operator {}",
+                    op
+                )))
+            }
+        });
+        rest = r.trim_start();
+        if !rest.is_empty() {
+            let end = rest.find(' ').unwrap_or_else(|| rest.len());
+            if !rest[..end].eq_ignore_ascii_case("and") {
+                return Err(OperationError::InvalidFilter(format!(
+                    "unsupported filter {:?}",
+                    rest
+                )));
+            }
+            rest = rest[end..].trim_start();
+            if rest.is_empty() {
+                return Err(OperationError::InvalidFilter(
+                    "nothing after and".to_string(),
+                ));
+            }
+        }
+    }
+    Ok(ProtoFilter::And(terms))
+}
+
+fn by_id(rt: ScimResourceType, id: &str) -> ProtoFilter {
+    ProtoFilter::And(vec![
+        ProtoFilter::Eq("class".to_string(), rt.class().to_string()),
+        ProtoFilter::Eq("uuid".to_string(), id.to_string()),
+    ])
+}
+
+// One operation of a PatchOp, on one attribute. A path may pick a single
+// value to remove, as members[value eq "..."].
+fn patch_attr(
+    rt: ScimResourceType,
+    op: &str,
+    path: &str,
+    value: &JsonValue,
+    mods: &mut Vec<ProtoModify>,
+) -> Result<(), OperationError> {
+    let (path, selected) = match path.find('[') {
+        Some(i) if path.ends_with(']') => {
+            let (vpath, vop, v, rest) = parse_comparison(&path[i + 1..path.len() - 1])?;
+            if !vpath.eq_ignore_ascii_case("value") || vop != "eq" || !rest.trim().is_empty() {
+                return Err(invalid("only [value eq \"...\"] may select a value"));
+            }
+            (&path[..i], Some(v))
+        }
+        _ => (path, None),
+    };
+    let attr = match rt.lookup(path) {
+        Some((_, attr, _, true)) => attr.to_string(),
+        _ => return Ok(()),
+    };
+    let vs = match selected {
+        Some(v) => vec![v],
+        None => values(value)?,
+    };
+    match op {
+        "add" => {}
+        "replace" => mods.push(ProtoModify::Purged(attr.clone())),
+        "remove" if vs.is_empty() => mods.push(ProtoModify::Purged(attr.clone())),
+        "remove" => {
+            mods.extend(
+                vs.into_iter()
+                    .map(|v| ProtoModify::Removed(attr.clone(), v)),
+            );
+            return Ok(());
+        }
+        _ => return Err(invalid("op must be add, replace or remove")),
+    }
+    mods.extend(
+        vs.into_iter()
+            .map(|v| ProtoModify::Present(attr.clone(), v)),
+    );
+    Ok(())
+}
+
+// A PatchOp, rfc7644 3.5.2. Without a path, the value of an operation holds
+// the attributes it applies to.
+pub fn to_modlist(
+    rt: ScimResourceType,
+    patch: &JsonValue,
+) -> Result<ProtoModifyList, OperationError> {
+    let ops = patch
+        .get("Operations")
+        .and_then(|o| o.as_array())
+        .ok_or_else(|| invalid("no Operations"))?;
+    let mut mods = Vec::new();
+    for o in ops.iter() {
+        let op = o
+            .get("op")
+            .and_then(|op| op.as_str())
+            .map(|op| op.to_lowercase())
+            .ok_or_else(|| invalid("operation without an op"))?;
+        let value = o.get("value").unwrap_or(&JsonValue::Null);
+        match o.get("path").and_then(|p| p.as_str()) {
+            Some(path) => patch_attr(rt, op.as_str(), path, value, &mut mods)?,
+            None => {
+                let attrs = value
+                    .as_object()
+                    .ok_or_else(|| invalid("operation without a path or attributes"))?;
+                for (path, v) in attrs.iter() {
+                    patch_attr(rt, op.as_str(), path, v, &mut mods)?;
+                }
+            }
+        }
+    }
+    Ok(ProtoModifyList::new_list(mods))
+}
+
+// The error response, rfc7644 3.12, and its status.
+pub fn error_response(e: &OperationError) -> (u16, JsonValue) {
+    let (status, scim_type) = match e {
+        OperationError::NotAuthenticated => (401, None),
+        OperationError::AccessDenied => (403, None),
+        OperationError::NoMatchingEntries => (404, None),
+        OperationError::Duplicate(_) | OperationError::NameInUse(_) => (409, Some("uniqueness")),
+        OperationError::InvalidFilter(_) | OperationError::FilterTooComplex => {
+            (400, Some("invalidFilter"))
+        }
+        OperationError::InvalidScimRequest(_) => (400, Some("invalidSyntax")),
+        OperationError::SchemaViolation(_) | OperationError::EntrySchemaViolation(_) => {
+            (400, Some("invalidValue"))
+        }
+        _ => (500, None),
+    };
+    let mut r = Map::new();
+    r.insert(
+        "schemas".to_string(),
+        JsonValue::from(vec![SCIM_SCHEMA_ERROR]),
+    );
+    r.insert("status".to_string(), JsonValue::from(status.to_string()));
+    if let Some(t) = scim_type {
+        r.insert("scimType".to_string(), JsonValue::from(t));
+    }
+    r.insert("detail".to_string(), JsonValue::from(format!("{:?}", e)));
+    (status, JsonValue::Object(r))
+}
+
+fn search(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    uat: Option<UserAuthToken>,
+    rt: ScimResourceType,
+    filter: &ProtoFilter,
+) -> Result<Vec<JsonValue>, OperationError> {
+    let qs_read = qs.read();
+    let se = SearchEvent::from_uat(au, uat, filter, &qs_read)?;
+    qs_read.search_ext(au, &se).map(|entries| {
+        entries
+            .iter()
+            .map(|e| to_resource(rt, &e.into_pe()))
+            .collect()
+    })
+}
+
+fn get(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    uat: Option<UserAuthToken>,
+    rt: ScimResourceType,
+    filter: &ProtoFilter,
+) -> Result<JsonValue, OperationError> {
+    search(au, qs, uat, rt, filter)?
+        .into_iter()
+        .next()
+        .ok_or(OperationError::NoMatchingEntries)
+}
+
+// The resource a request leaves, if any. A created or changed resource is
+// read back as the caller can see it.
+pub(crate) fn handle(
+    au: &mut AuditScope,
+    qs: &QueryServer,
+    uat: Option<UserAuthToken>,
+    rt: ScimResourceType,
+    op: ScimOp,
+) -> Result<Option<JsonValue>, OperationError> {
+    match op {
+        ScimOp::List(filter) => {
+            let filter = to_filter(rt, filter.as_ref().map(|f| f.as_str()))?;
+            let resources = search(au, qs, uat, rt, &filter)?;
+            let mut r = Map::new();
+            r.insert(
+                "schemas".to_string(),
+                JsonValue::from(vec![SCIM_SCHEMA_LIST_RESPONSE]),
+            );
+            r.insert("totalResults".to_string(), JsonValue::from(resources.len()));
+            r.insert("itemsPerPage".to_string(), JsonValue::from(resources.len()));
+            r.insert("startIndex".to_string(), JsonValue::from(1));
+            r.insert("Resources".to_string(), JsonValue::Array(resources));
+            Ok(Some(JsonValue::Object(r)))
+        }
+        ScimOp::Get(id) => get(au, qs, uat, rt, &by_id(rt, id.as_str())).map(Some),
+        ScimOp::Create(resource) => {
+            let pe = from_resource(rt, &resource)?;
+            let name = pe.attrs["name"][0].clone();
+            let mut qs_write = qs.write();
+            let ce = CreateEvent::from_uat(au, uat.clone(), &vec![pe], &qs_write)?;
+            qs_write.create(au, &ce)?;
+            qs_write.commit(au)?;
+            let filter = ProtoFilter::And(vec![
+                ProtoFilter::Eq("class".to_string(), rt.class().to_string()),
+                ProtoFilter::Eq("name".to_string(), name),
+            ]);
+            get(au, qs, uat, rt, &filter).map(Some)
+        }
+        ScimOp::Patch(id, patch) => {
+            let modlist = to_modlist(rt, &patch)?;
+            let filter = by_id(rt, id.as_str());
+            let mut qs_write = qs.write();
+            let me = ModifyEvent::from_uat(au, uat.clone(), &filter, &modlist, &qs_write)?;
+            qs_write.modify(au, &me)?;
+            qs_write.commit(au)?;
+            get(au, qs, uat, rt, &filter).map(Some)
+        }
+        ScimOp::Delete(id) => {
+            let mut qs_write = qs.write();
+            let de = DeleteEvent::from_uat(au, uat, &by_id(rt, id.as_str()), &qs_write)?;
+            qs_write.delete(au, &de)?;
+            qs_write.commit(au).map(|_| None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::proto::v1::{
+        Entry as ProtoEntry, Filter as ProtoFilter, Modify as ProtoModify, UserAuthToken,
+    };
+    use crate::scim::{
+        error_response, from_resource, handle, to_filter, to_modlist, to_resource, ScimOp,
+        ScimResourceType,
+    };
+    use serde_json::Value as JsonValue;
+    use std::collections::BTreeMap;
+
+    fn json(s: &str) -> JsonValue {
+        serde_json::from_str(s).expect("json failure")
+    }
+
+    #[test]
+    fn test_scim_filter() {
+        let user = ScimResourceType::User;
+        let class = ProtoFilter::Eq("class".to_string(), "account".to_string());
+        match to_filter(user, None) {
+            Ok(ProtoFilter::And(terms)) => assert!(terms.len() == 1),
+            f => panic!("unexpected filter {:?}", f),
+        }
+        match to_filter(
+            user,
+            Some(r#"userName eq "bob" AND emails.value co "ex\"ample""#),
+        ) {
+            Ok(ProtoFilter::And(terms)) => {
+                assert!(format!("{:?}", terms[0]) == format!("{:?}", class));
+                match (&terms[1], &terms[2]) {
+                    (ProtoFilter::Eq(a, v), ProtoFilter::Sub(b, w)) => {
+                        assert!(a == "name" && v == "bob");
+                        assert!(b == "mail" && w == "ex\"ample");
+                    }
+                    f => panic!("unexpected filter {:?}", f),
+                }
+            }
+            f => panic!("unexpected filter {:?}", f),
+        }
+        // Schema qualified names.
+        assert!(to_filter(
+            user,
+            Some("urn:ietf:params:scim:schemas:core:2.0:User:userName eq \"bob\"")
+        )
+        .is_ok());
+        for bad in [
+            "userName eq bob",
+            "userName gt \"bob\"",
+            "title eq \"bob\"",
+            "userName eq \"bob\" or displayName eq \"x\"",
+            "userName eq \"bob\" and",
+            "userName eq \"bob",
+        ]
+        .iter()
+        {
+            match to_filter(user, Some(*bad)) {
+                Err(OperationError::InvalidFilter(_)) => {}
+                f => panic!("{} gave {:?}", bad, f),
+            }
+        }
+    }
+
+    #[test]
+    fn test_scim_resource() {
+        let user = ScimResourceType::User;
+        let pe = from_resource(
+            user,
+            &json(
+                r#"{
+                    "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+                    "userName": "bob",
+                    "emails": [{"value": "bob@example.com", "primary": true}],
+                    "groups": [{"value": "ignored"}],
+                    "title": "ignored"
+                }"#,
+            ),
+        )
+        .expect("resource failure");
+        assert!(pe.attrs["name"] == vec!["bob".to_string()]);
+        // The displayname is taken from the name.
+        assert!(pe.attrs["displayname"] == vec!["bob".to_string()]);
+        assert!(pe.attrs["mail"] == vec!["bob@example.com".to_string()]);
+        assert!(!pe.attrs.contains_key("memberof"));
+        assert!(from_resource(user, &json(r#"{"displayName": "bob"}"#)).is_err());
+
+        let mut attrs = BTreeMap::new();
+        attrs.insert("uuid".to_string(), vec!["u".to_string()]);
+        attrs.insert("name".to_string(), vec!["bob".to_string()]);
+        attrs.insert("memberof".to_string(), vec!["g".to_string()]);
+        let r = to_resource(user, &ProtoEntry { attrs: attrs });
+        assert!(r["id"] == "u");
+        assert!(r["userName"] == "bob");
+        assert!(r["groups"][0]["value"] == "g");
+        assert!(r["meta"]["resourceType"] == "User");
+        assert!(r.get("emails").is_none());
+    }
+
+    #[test]
+    fn test_scim_patch() {
+        let group = ScimResourceType::Group;
+        let ml = to_modlist(
+            group,
+            &json(
+                r#"{
+                    "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+                    "Operations": [
+                        {"op": "Add", "path": "members", "value": [{"value": "a"}]},
+                        {"op": "remove", "path": "members[value eq \"b\"]"},
+                        {"op": "replace", "value": {"displayName": "g", "id": "x"}}
+                    ]
+                }"#,
+            ),
+        )
+        .expect("patch failure");
+        let mods: Vec<String> = ml.mods.iter().map(|m| format!("{:?}", m)).collect();
+        let expect: Vec<String> = vec![
+            ProtoModify::Present("member".to_string(), "a".to_string()),
+            ProtoModify::Removed("member".to_string(), "b".to_string()),
+            ProtoModify::Purged("name".to_string()),
+            ProtoModify::Present("name".to_string(), "g".to_string()),
+        ]
+        .iter()
+        .map(|m| format!("{:?}", m))
+        .collect();
+        assert!(mods == expect);
+        assert!(to_modlist(group, &json(r#"{"Operations": [{"op": "move"}]}"#)).is_err());
+        assert!(to_modlist(group, &json(r#"{}"#)).is_err());
+    }
+
+    #[test]
+    fn test_scim_handle() {
+        run_test!(|qs: &QueryServer, au: &mut AuditScope| {
+            {
+                // A provisioning account, and what it may do to groups.
+                let mut qs_write = qs.write();
+                let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                    r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "account"],
+                            "name": ["scim_user"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63b01"],
+                            "displayname": ["Scim User"]
+                        }
+                    }"#,
+                    r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": [
+                                "object",
+                                "access_control_profile",
+                                "access_control_search",
+                                "access_control_create",
+                                "access_control_modify",
+                                "access_control_delete"
+                            ],
+                            "name": ["scim_acp"],
+                            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63b02"],
+                            "acp_enable": ["true"],
+                            "acp_receiver": [
+                                "{\"Eq\":[\"name\",\"scim_user\"]}"
+                            ],
+                            "acp_targetscope": [
+                                "{\"Eq\":[\"class\",\"group\"]}"
+                            ],
+                            "acp_search_attr": ["class", "name", "uuid", "member"],
+                            "acp_create_class": ["object", "group"],
+                            "acp_create_attr": ["class", "name", "member"],
+                            "acp_modify_removedattr": ["name", "member"],
+                            "acp_modify_presentattr": ["name", "member"]
+                        }
+                    }"#,
+                ]
+                .into_iter()
+                .map(|s| serde_json::from_str(s).expect("json failure"))
+                .collect();
+                assert!(qs_write.internal_create(au, entries).is_ok());
+                qs_write.commit(au).expect("Must not fail");
+            }
+            let uat = UserAuthToken {
+                name: "scim_user".to_string(),
+                displayname: "Scim User".to_string(),
+                uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63b01".to_string(),
+                spn: String::new(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                expiry: None,
+            };
+            let group = ScimResourceType::Group;
+            let mut scim = |op| handle(au, qs, Some(uat.clone()), group, op);
+
+            let created = scim(ScimOp::Create(json(r#"{"displayName": "scim_group"}"#)))
+                .expect("create failure")
+                .expect("no resource");
+            assert!(created["displayName"] == "scim_group");
+            let id = created["id"].as_str().expect("no id").to_string();
+
+            let found = scim(ScimOp::List(Some(
+                "displayName eq \"scim_group\"".to_string(),
+            )))
+            .expect("list failure")
+            .expect("no resource");
+            assert!(found["totalResults"] == 1);
+            assert!(found["Resources"][0]["id"] == id.as_str());
+
+            let patched = scim(ScimOp::Patch(
+                id.clone(),
+                json(
+                    r#"{"Operations": [
+                        {"op": "add", "path": "members", "value": [{"value": "cc8e95b4-c24f-4d68-ba54-8bed76f63b01"}]}
+                    ]}"#,
+                ),
+            ))
+            .expect("patch failure")
+            .expect("no resource");
+            assert!(patched["members"][0]["value"] == "cc8e95b4-c24f-4d68-ba54-8bed76f63b01");
+
+            assert!(scim(ScimOp::Delete(id.clone())) == Ok(None));
+            let r = scim(ScimOp::Get(id));
+            assert!(r == Err(OperationError::NoMatchingEntries));
+            assert!(error_response(&r.unwrap_err()).0 == 404);
+        });
+        run_test!(|qs: &QueryServer, au: &mut AuditScope| {
+            // Without a session.
+            let r = handle(au, qs, None, ScimResourceType::User, ScimOp::List(None));
+            assert!(r == Err(OperationError::NotAuthenticated));
+            assert!(error_response(&r.unwrap_err()).0 == 401);
+        });
+    }
+}
@@ -1,38 +1,247 @@
 // This is really only used for long lived, high level types that need clone
 // that otherwise can't be cloned. Think Mutex.
 // use actix::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 use std::sync::Arc;
-
-use crate::audit::AuditScope;
-use crate::be::{Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction};
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use concread::cowcell::{CowCell, CowCellReadTxn, CowCellWriteTxn};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng};
+use uuid::Uuid;
+
+use crate::anomaly::{Alert, AnomalyDetector, AnomalyThresholds, SecurityEventKind};
+use crate::audit::{self, read_audit_records, AuditLevel, AuditScope, LogLevels, LOG_SUBSYSTEMS};
+use crate::be::{
+    Backend, BackendReadTransaction, BackendSearchIter, BackendTransaction,
+    BackendWriteTransaction, ChangeOrigin,
+};
+use crate::changes::{ChangeBus, ChangeOp, ChangeSummary};
+use crate::clock::{Clock, SystemClock};
+use crate::csn::Csn;
+use crate::domain::DomainInfo;
+use crate::metrics::{self, Counter, Stage};
+use crate::migrations;
+use crate::ratelimit::RateLimit;
 
 use crate::access::{
-    AccessControlCreate, AccessControlDelete, AccessControlModify, AccessControlSearch,
+    AccessControlAuditRead, AccessControlCompare, AccessControlCreate, AccessControlDelete,
+    AccessControlKind, AccessControlModify, AccessControlOperation, AccessControlSearch,
     AccessControls, AccessControlsReadTransaction, AccessControlsTransaction,
-    AccessControlsWriteTransaction,
+    AccessControlsWriteTransaction, SearchAccess,
 };
 use crate::constants::{
-    JSON_ADMIN_V1, JSON_ANONYMOUS_V1, JSON_IDM_ADMINS_ACP_REVIVE_V1, JSON_IDM_ADMINS_ACP_SEARCH_V1,
-    JSON_IDM_ADMINS_V1, JSON_IDM_SELF_ACP_READ_V1, JSON_SCHEMA_ATTR_DISPLAYNAME,
-    JSON_SCHEMA_ATTR_MAIL, JSON_SCHEMA_ATTR_PASSWORD, JSON_SCHEMA_ATTR_SSH_PUBLICKEY,
-    JSON_SCHEMA_CLASS_ACCOUNT, JSON_SCHEMA_CLASS_GROUP, JSON_SCHEMA_CLASS_PERSON,
-    JSON_SYSTEM_INFO_V1, UUID_DOES_NOT_EXIST,
+    ACP_COVERAGE_BATCH_SIZE, ACP_COVERAGE_MAX_LISTED, ANON_SEARCH_MAX_OPS, ANON_SEARCH_MAX_RESULTS,
+    BREAK_GLASS_MAX_LIFETIME, CREDENTIAL_ATTRS, CREDENTIAL_RESET_DEFAULT_LIFETIME,
+    CREDENTIAL_RESET_MAX_ATTEMPTS, CREDENTIAL_RESET_MAX_LIFETIME, CREDENTIAL_RESET_WINDOW,
+    DOMAIN_VERSION, HOST_SECRET_LIFETIME, INTERNAL_MODIFY_BATCH_SIZE, JSON_ADMIN_V1,
+    JSON_ANONYMOUS_V1, JSON_IDM_ACP_PASSWORD_DENY_V1, JSON_IDM_ADMINS_ACP_ACCOUNT_VALIDITY_V1,
+    JSON_IDM_ADMINS_ACP_AUDIT_READ_V1, JSON_IDM_ADMINS_ACP_CREDENTIAL_RESET_V1,
+    JSON_IDM_ADMINS_ACP_HOST_SECRET_V1, JSON_IDM_ADMINS_ACP_LOG_LEVEL_V1,
+    JSON_IDM_ADMINS_ACP_OAUTH2_V1, JSON_IDM_ADMINS_ACP_OPERATION_V1,
+    JSON_IDM_ADMINS_ACP_PASSWORD_V1, JSON_IDM_ADMINS_ACP_REPLICATION_V1,
+    JSON_IDM_ADMINS_ACP_REVIVE_V1, JSON_IDM_ADMINS_ACP_SCHEMA_V1, JSON_IDM_ADMINS_ACP_SEARCH_V1,
+    JSON_IDM_ADMINS_V1, JSON_IDM_GROUP_MANAGERS_ACP_MEMBER_V1, JSON_IDM_HOST_ACP_SECRET_READ_V1,
+    JSON_IDM_HOST_ACP_SECRET_ROTATE_V1, JSON_IDM_HOST_ACP_SSH_PUBLICKEY_READ_V1,
+    JSON_IDM_OAUTH2_RS_ACP_READ_V1, JSON_IDM_RADIUS_SERVERS_ACP_READ_V1,
+    JSON_IDM_RADIUS_SERVERS_V1, JSON_IDM_SELF_ACP_PASSWORD_V1, JSON_IDM_SELF_ACP_RADIUS_SECRET_V1,
+    JSON_IDM_SELF_ACP_READ_V1, JSON_IDM_SELF_ACP_SSH_PUBLICKEY_V1, JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE,
+    JSON_SCHEMA_ATTR_ACCOUNT_EXPIRED, JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM,
+    JSON_SCHEMA_ATTR_ANON_SEARCH_ALLOW_SUBSTRING, JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_OPS,
+    JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_RESULTS, JSON_SCHEMA_ATTR_BREAK_GLASS_EXPIRY,
+    JSON_SCHEMA_ATTR_BREAK_GLASS_SECRET, JSON_SCHEMA_ATTR_CERT_MAPPING,
+    JSON_SCHEMA_ATTR_CREDENTIAL_RESET_EXPIRY, JSON_SCHEMA_ATTR_CREDENTIAL_RESET_TOKEN,
+    JSON_SCHEMA_ATTR_DISPLAYNAME, JSON_SCHEMA_ATTR_DYNGROUP_FILTER, JSON_SCHEMA_ATTR_GROUP_MANAGER,
+    JSON_SCHEMA_ATTR_JOIN_GROUP, JSON_SCHEMA_ATTR_JOIN_REQUESTER, JSON_SCHEMA_ATTR_LOG_LEVEL,
+    JSON_SCHEMA_ATTR_MAIL, JSON_SCHEMA_ATTR_MANAGED_BY, JSON_SCHEMA_ATTR_MANAGED_ORIGIN,
+    JSON_SCHEMA_ATTR_MANAGED_TEMPLATE, JSON_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET,
+    JSON_SCHEMA_ATTR_OAUTH2_RS_REDIRECT_URI, JSON_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP,
+    JSON_SCHEMA_ATTR_PASSWORD, JSON_SCHEMA_ATTR_RADIUS_SECRET, JSON_SCHEMA_ATTR_REPL_CSN,
+    JSON_SCHEMA_ATTR_REPL_SEQ, JSON_SCHEMA_ATTR_REPL_SUPPLIER, JSON_SCHEMA_ATTR_REPL_USER,
+    JSON_SCHEMA_ATTR_SERVICE_SECRET, JSON_SCHEMA_ATTR_SERVICE_SECRET_EXPIRY,
+    JSON_SCHEMA_ATTR_SSH_PUBLICKEY, JSON_SCHEMA_ATTR_STAT, JSON_SCHEMA_ATTR_TAG,
+    JSON_SCHEMA_ATTR_TEMPLATE_CLASS, JSON_SCHEMA_ATTR_TEMPLATE_FILTER,
+    JSON_SCHEMA_ATTR_TEMPLATE_LINK, JSON_SCHEMA_CLASS_ACCOUNT, JSON_SCHEMA_CLASS_DYNGROUP,
+    JSON_SCHEMA_CLASS_ENTRY_TEMPLATE, JSON_SCHEMA_CLASS_GROUP,
+    JSON_SCHEMA_CLASS_GROUP_JOIN_REQUEST, JSON_SCHEMA_CLASS_HOST, JSON_SCHEMA_CLASS_MANAGED_ENTRY,
+    JSON_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER, JSON_SCHEMA_CLASS_PERSON,
+    JSON_SCHEMA_CLASS_REPLICATION_AGREEMENT, JSON_SCHEMA_CLASS_SYSTEM_CONFIG,
+    JSON_SCHEMA_CLASS_SYSTEM_STATS, JSON_SYSTEM_CONFIG_V1, JSON_SYSTEM_INFO_V1, RESERVED_NAMES,
+    SEARCH_MAX_RESULTS, UUID_ANONYMOUS, UUID_DOES_NOT_EXIST, UUID_DOMAIN_INFO, UUID_IDM_ADMINS,
+    UUID_SYSTEM_CONFIG,
 };
 use crate::entry::{
     Entry, EntryCommitted, EntryInvalid, EntryNew, EntryNormalised, EntryReduced, EntryValid,
 };
 use crate::error::{ConsistencyError, OperationError, SchemaError};
 use crate::event::{
-    CreateEvent, DeleteEvent, Event, EventOrigin, ExistsEvent, ModifyEvent, ReviveRecycledEvent,
-    SearchEvent,
+    AcpCoverageEvent, AuditLogEvent, BackupEvent, BatchEvent, BatchOperationEvent, CompareEvent,
+    CreateEvent, CredentialResetIssueEvent, DeleteEvent, DeletePreviewEvent,
+    EffectivePermissionsEvent, Event, EventOrigin, ExistsEvent, GroupJoinCreateEvent,
+    GroupJoinDecideEvent, GroupJoinListEvent, HostSecretRotateEvent, LogLevelEvent,
+    MemoryReportEvent, ModifyEvent, PageKey, RadiusSecretReadEvent, RadiusSecretRegenerateEvent,
+    RenameEvent, ReplChangesEvent, ReviveRecycledEvent, SearchEvent, SyncEvent, TypeaheadEvent,
 };
-use crate::filter::{Filter, FilterInvalid, FilterValid};
+use crate::filter::{Filter, FilterInvalid, FilterLimits, FilterValid};
+use crate::idm::credential::Password;
 use crate::modify::{Modify, ModifyInvalid, ModifyList, ModifyValid};
 use crate::plugins::Plugins;
+use crate::proto::v1::{
+    AcpCoverage, AcpCoverageClass, AuditRecord, CredentialResetTokenResponse, DeletePreview,
+    DeletePreviewGroup, DeletePreviewReference, EffectivePermissions, GroupJoinInfo,
+    HostSecretRotateResponse, LogLevel, MemoryReport, MemoryUse, RadiusSecretResponse,
+    ReplChangesResponse, ReplEntry, SearchTrace, SearchTraceFailure, SearchTraceNode,
+    TypeaheadEntry,
+};
+use crate::repl::{self, ReplAction};
 use crate::schema::{
     Schema, SchemaAttribute, SchemaClass, SchemaReadTransaction, SchemaTransaction,
     SchemaWriteTransaction, SyntaxType,
 };
+use crate::value::Value;
+use crate::verify::{run_verify, VerifyReport};
+
+lazy_static! {
+    // The idm schema and default entries never change, so they are parsed
+    // once rather than each time a server is started - which tests do a lot.
+    static ref IDM_SCHEMA: Vec<Entry<EntryValid, EntryNew>> = parse_builtin(&[
+        JSON_SCHEMA_ATTR_DISPLAYNAME,
+        JSON_SCHEMA_ATTR_MAIL,
+        JSON_SCHEMA_ATTR_SSH_PUBLICKEY,
+        JSON_SCHEMA_ATTR_PASSWORD,
+        JSON_SCHEMA_ATTR_GROUP_MANAGER,
+        JSON_SCHEMA_ATTR_JOIN_GROUP,
+        JSON_SCHEMA_ATTR_JOIN_REQUESTER,
+        JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_RESULTS,
+        JSON_SCHEMA_ATTR_ANON_SEARCH_MAX_OPS,
+        JSON_SCHEMA_ATTR_ANON_SEARCH_ALLOW_SUBSTRING,
+        JSON_SCHEMA_ATTR_TAG,
+        JSON_SCHEMA_ATTR_CERT_MAPPING,
+        JSON_SCHEMA_ATTR_SERVICE_SECRET,
+        JSON_SCHEMA_ATTR_SERVICE_SECRET_EXPIRY,
+        JSON_SCHEMA_ATTR_REPL_SUPPLIER,
+        JSON_SCHEMA_ATTR_REPL_USER,
+        JSON_SCHEMA_ATTR_REPL_CSN,
+        JSON_SCHEMA_ATTR_REPL_SEQ,
+        JSON_SCHEMA_ATTR_BREAK_GLASS_SECRET,
+        JSON_SCHEMA_ATTR_BREAK_GLASS_EXPIRY,
+        JSON_SCHEMA_ATTR_LOG_LEVEL,
+        JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM,
+        JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE,
+        JSON_SCHEMA_ATTR_ACCOUNT_EXPIRED,
+        JSON_SCHEMA_ATTR_STAT,
+        JSON_SCHEMA_ATTR_RADIUS_SECRET,
+        JSON_SCHEMA_ATTR_OAUTH2_RS_REDIRECT_URI,
+        JSON_SCHEMA_ATTR_OAUTH2_RS_SCOPE_MAP,
+        JSON_SCHEMA_ATTR_OAUTH2_RS_BASIC_SECRET,
+        JSON_SCHEMA_ATTR_CREDENTIAL_RESET_TOKEN,
+        JSON_SCHEMA_ATTR_CREDENTIAL_RESET_EXPIRY,
+        JSON_SCHEMA_ATTR_DYNGROUP_FILTER,
+        JSON_SCHEMA_ATTR_MANAGED_BY,
+        JSON_SCHEMA_ATTR_TEMPLATE_FILTER,
+        JSON_SCHEMA_ATTR_TEMPLATE_CLASS,
+        JSON_SCHEMA_ATTR_TEMPLATE_LINK,
+        JSON_SCHEMA_ATTR_MANAGED_ORIGIN,
+        JSON_SCHEMA_ATTR_MANAGED_TEMPLATE,
+        JSON_SCHEMA_CLASS_PERSON,
+        JSON_SCHEMA_CLASS_GROUP,
+        JSON_SCHEMA_CLASS_ACCOUNT,
+        JSON_SCHEMA_CLASS_GROUP_JOIN_REQUEST,
+        JSON_SCHEMA_CLASS_SYSTEM_CONFIG,
+        JSON_SCHEMA_CLASS_HOST,
+        JSON_SCHEMA_CLASS_REPLICATION_AGREEMENT,
+        JSON_SCHEMA_CLASS_SYSTEM_STATS,
+        JSON_SCHEMA_CLASS_OAUTH2_RESOURCE_SERVER,
+        JSON_SCHEMA_CLASS_DYNGROUP,
+        JSON_SCHEMA_CLASS_ENTRY_TEMPLATE,
+        JSON_SCHEMA_CLASS_MANAGED_ENTRY,
+    ]);
+    static ref IDM_ENTRIES: Vec<Entry<EntryValid, EntryNew>> = parse_builtin(&[
+        JSON_ANONYMOUS_V1,
+        JSON_ADMIN_V1,
+        JSON_IDM_ADMINS_V1,
+        JSON_IDM_ADMINS_ACP_SEARCH_V1,
+        JSON_IDM_ADMINS_ACP_REVIVE_V1,
+        JSON_IDM_SELF_ACP_READ_V1,
+        JSON_SYSTEM_CONFIG_V1,
+        JSON_IDM_HOST_ACP_SECRET_READ_V1,
+        JSON_IDM_HOST_ACP_SECRET_ROTATE_V1,
+        JSON_IDM_ADMINS_ACP_HOST_SECRET_V1,
+        JSON_IDM_ADMINS_ACP_REPLICATION_V1,
+        JSON_IDM_ACP_PASSWORD_DENY_V1,
+        JSON_IDM_SELF_ACP_PASSWORD_V1,
+        JSON_IDM_ADMINS_ACP_PASSWORD_V1,
+        JSON_IDM_ADMINS_ACP_LOG_LEVEL_V1,
+        JSON_IDM_ADMINS_ACP_ACCOUNT_VALIDITY_V1,
+        JSON_IDM_ADMINS_ACP_AUDIT_READ_V1,
+        JSON_IDM_ADMINS_ACP_OPERATION_V1,
+        JSON_IDM_GROUP_MANAGERS_ACP_MEMBER_V1,
+        JSON_IDM_ADMINS_ACP_SCHEMA_V1,
+        JSON_IDM_SELF_ACP_SSH_PUBLICKEY_V1,
+        JSON_IDM_HOST_ACP_SSH_PUBLICKEY_READ_V1,
+        JSON_IDM_RADIUS_SERVERS_V1,
+        JSON_IDM_SELF_ACP_RADIUS_SECRET_V1,
+        JSON_IDM_RADIUS_SERVERS_ACP_READ_V1,
+        JSON_IDM_ADMINS_ACP_OAUTH2_V1,
+        JSON_IDM_OAUTH2_RS_ACP_READ_V1,
+        JSON_IDM_ADMINS_ACP_CREDENTIAL_RESET_V1,
+    ]);
+}
+
+fn parse_builtin(e_strs: &[&str]) -> Vec<Entry<EntryValid, EntryNew>> {
+    e_strs
+        .iter()
+        .map(|e_str| serde_json::from_str(e_str).expect("Builtin entry is invalid json"))
+        .collect()
+}
+
+// The entries of an external search, read from the backend, access checked and
+// reduced one at a time. See search_ext_iter.
+pub struct SearchExtIter<'a> {
+    au: &'a mut AuditScope,
+    candidates: BackendSearchIter<'a>,
+    access: SearchAccess<'a>,
+    // The most entries this search may return, and how many it has.
+    limit: Option<usize>,
+    count: usize,
+}
+
+impl<'a> Iterator for SearchExtIter<'a> {
+    type Item = Result<Entry<EntryReduced, EntryCommitted>, OperationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.limit.map(|max| self.count > max).unwrap_or(false) {
+            return None;
+        }
+        loop {
+            let (au, candidates) = (&mut *self.au, &mut self.candidates);
+            let e = match metrics::timed(Stage::BackendRead, || candidates.next_entry(au))? {
+                Ok(e) => e,
+                Err(e) => return Some(Err(e)),
+            };
+            metrics::incr(Counter::EntriesRead, 1);
+            let (au, access) = (&mut *self.au, &self.access);
+            let allowed = match metrics::timed(Stage::AccessCheck, || access.check_entry(au, e)) {
+                Some(a) => a,
+                None => continue,
+            };
+            if let Some(e) = metrics::timed(Stage::Reduce, || access.reduce_entry(au, allowed)) {
+                metrics::incr(Counter::EntriesReturned, 1);
+                self.count += 1;
+                return match self.limit {
+                    Some(max) if self.count > max => {
+                        audit_log!(self.au, "search went over the limit of {} entries", max);
+                        Some(Err(OperationError::SizeLimitExceeded(max)))
+                    }
+                    _ => Some(Ok(e)),
+                };
+            }
+        }
+    }
+}
 
 // This is the core of the server. It implements all
 // the search and modify actions, applies access controls
@@ -47,6 +256,144 @@ pub trait QueryServerTransaction {
     type AccessControlsTransactionType: AccessControlsTransaction;
     fn get_accesscontrols(&self) -> &Self::AccessControlsTransactionType;
 
+    fn get_anon_search_rate(&self) -> &RateLimit;
+
+    fn get_anomalies(&self) -> &AnomalyDetector;
+
+    fn get_filter_limits(&self) -> &FilterLimits;
+
+    fn get_search_max_results(&self) -> usize;
+
+    fn get_domain_info(&self) -> &DomainInfo;
+
+    // Count an operation refused by access controls against its initiator.
+    fn record_access_denied(&self, au: &mut AuditScope, ev: &Event) {
+        if let EventOrigin::User(e) = &ev.origin {
+            if let Some(a) =
+                self.get_anomalies()
+                    .record(SecurityEventKind::AccessDenied, e.get_uuid(), 1)
+            {
+                audit_log!(au, "security alert: {:?}", a);
+            }
+        }
+    }
+
+    // Server operations that aren't about particular entries are granted by
+    // access_control_operation profiles, by name.
+    fn require_operation(
+        &self,
+        au: &mut AuditScope,
+        ev: &Event,
+        operation: &str,
+    ) -> Result<(), OperationError> {
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let allow = self
+            .get_accesscontrols()
+            .operation_allow(&mut audit_acp, ev, operation);
+        au.append_scope(audit_acp);
+        if allow {
+            Ok(())
+        } else {
+            audit_log!(au, "{} denied to {:?}", operation, ev.origin);
+            self.record_access_denied(au, ev);
+            Err(OperationError::AccessDenied)
+        }
+    }
+
+    // Anonymous searches are the easiest way to enumerate or load the
+    // server, so they are held to the limits in the system_config entry.
+    // Returns how many entries the search may return, if it's limited.
+    fn anon_search_limits(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<Option<usize>, OperationError> {
+        if !se.event.is_anonymous() {
+            return Ok(None);
+        }
+
+        let config = self.internal_search_uuid(au, UUID_SYSTEM_CONFIG).ok();
+        // The limits are only ever stricter than the defaults if they can't
+        // be read.
+        let limit = |attr: &str, default: usize| {
+            config
+                .as_ref()
+                .and_then(|e| e.get_ava_single(attr))
+                .and_then(|v| v.to_string().parse::<usize>().ok())
+                .unwrap_or(default)
+        };
+        let max_results = limit("anon_search_max_results", ANON_SEARCH_MAX_RESULTS);
+        let max_ops = limit("anon_search_max_ops", ANON_SEARCH_MAX_OPS);
+        let allow_substring = config
+            .as_ref()
+            .and_then(|e| e.get_ava_single_bool("anon_search_allow_substring"))
+            .unwrap_or(false);
+
+        if !allow_substring && se.filter_orig.has_substring() {
+            audit_log!(au, "anonymous substring search denied");
+            return Err(OperationError::SubstringNotPermitted);
+        }
+        let source = se.source.as_ref().map(|s| s.as_str()).unwrap_or("unknown");
+        if !self
+            .get_anon_search_rate()
+            .check(source, Duration::from_secs(60), max_ops)
+        {
+            audit_log!(
+                au,
+                "anonymous search from {} over {} a minute",
+                source,
+                max_ops
+            );
+            return Err(OperationError::RateLimited);
+        }
+        Ok(Some(max_results))
+    }
+
+    // The audit log levels set in the system_config entry. Values that can't
+    // be parsed are skipped, so the subsystem stays at the default.
+    fn log_levels(&self, au: &mut AuditScope) -> BTreeMap<String, AuditLevel> {
+        let config = match self.internal_search_uuid(au, UUID_SYSTEM_CONFIG) {
+            Ok(e) => e,
+            Err(_) => return BTreeMap::new(),
+        };
+        config
+            .get_ava("log_level")
+            .map(|vs| {
+                vs.iter()
+                    .filter_map(|v| {
+                        let r = audit::parse_log_level(v.to_string().as_str());
+                        if r.is_none() {
+                            audit_log!(au, "ignoring invalid log level {}", v.to_string());
+                        }
+                        r
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(BTreeMap::new)
+    }
+
+    // The most entries an external search may return, if it's limited. The
+    // server-wide limit bounds each page of a paged search instead, so that
+    // large sets can still be walked.
+    fn search_size_limit(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<Option<usize>, OperationError> {
+        let server_limit = match se.page {
+            Some(_) => None,
+            None => Some(self.get_search_max_results()),
+        };
+        Ok(vec![
+            self.anon_search_limits(au, se)?,
+            se.size_limit,
+            server_limit,
+        ]
+        .into_iter()
+        .filter_map(|l| l)
+        .min())
+    }
+
     fn search_ext(
         &self,
         au: &mut AuditScope,
@@ -57,949 +404,1864 @@ pub trait QueryServerTransaction {
          * so as a result it also reduces the entry set's attributes at
          * the end.
          */
-        let entries = self.search(au, se)?;
+        self.search_ext_iter(au, se)?.collect()
+    }
 
-        let mut audit_acp = AuditScope::new("access_control_profiles");
-        let access = self.get_accesscontrols();
-        let acp_res = access.search_filter_entry_attributes(&mut audit_acp, se, entries);
-        au.append_scope(audit_acp);
-        // Log and fail if something went wrong.
-        let entries_filtered = try_audit!(au, acp_res);
+    fn search_ext_iter<'a>(
+        &'a self,
+        au: &'a mut AuditScope,
+        se: &'a SearchEvent,
+    ) -> Result<SearchExtIter<'a>, OperationError> {
+        /*
+         * The external search as a pipeline: the backend reads candidates a
+         * batch at a time, and each is access checked and reduced as it
+         * comes through. Only the entries that haven't been taken from the
+         * iterator yet are in memory, rather than the whole result set.
+         */
+        audit_log!(au, "search: filter -> {:?}", se.filter);
 
-        // This is the final entry set that was reduced.
-        Ok(entries_filtered)
+        let limit = self.search_size_limit(au, se)?;
+        let vfr = try_audit!(
+            au,
+            metrics::timed(Stage::FilterResolve, || se.filter.resolve(&se.event))
+        );
+        let access = metrics::timed(Stage::AccessCheck, || {
+            self.get_accesscontrols().search_access(au, se)
+        })?;
+        let candidates = self.get_be_txn().search_iter(au, vfr)?;
+
+        Ok(SearchExtIter {
+            au: au,
+            candidates: candidates,
+            access: access,
+            limit: limit,
+            count: 0,
+        })
     }
 
-    fn search(
+    // An external search that is sorted, paged, or both, with the cookie for
+    // the next page. Both are done on the reduced entries, so only what the
+    // caller can see - including which attribute values they can read - has
+    // any effect on the order or the page boundaries.
+    fn search_ext_page(
         &self,
         au: &mut AuditScope,
         se: &SearchEvent,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        audit_log!(au, "search: filter -> {:?}", se.filter);
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, Option<String>), OperationError> {
+        let entries = self.search_ext(au, se)?;
+        let sort = se.sort.as_ref();
+
+        match se.page.as_ref() {
+            Some(page) => {
+                // Only the entries after the last page are kept, and of those
+                // only the page is sorted, so each page costs the search and
+                // not a sort of everything it found.
+                let size = std::cmp::min(page.size, self.get_search_max_results());
+                let mut keyed: Vec<_> = entries
+                    .into_iter()
+                    .map(|e| (PageKey::new(&e, sort), e))
+                    .filter(|(k, _)| page.after.as_ref().map(|a| k > a).unwrap_or(true))
+                    .collect();
+                let more = keyed.len() > size;
+                if more {
+                    keyed.select_nth_unstable_by(size, |a, b| a.0.cmp(&b.0));
+                    keyed.truncate(size);
+                }
+                keyed.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                let cookie = if more {
+                    keyed.last().map(|(k, _)| k.to_cookie())
+                } else {
+                    None
+                };
+                Ok((keyed.into_iter().map(|(_, e)| e).collect(), cookie))
+            }
+            None => {
+                let mut entries = entries;
+                if sort.is_some() {
+                    entries.sort_by_cached_key(|e| PageKey::new(e, sort));
+                }
+                Ok((entries, None))
+            }
+        }
+    }
 
-        // This is an important security step because it prevents us from
-        // performing un-indexed searches on attr's that don't exist in the
-        // server. This is why ExtensibleObject can only take schema that
-        // exists in the server, not arbitrary attr names.
-        //
-        // This normalises and validates in a single step.
-        //
-        // NOTE: Filters are validated in event conversion.
+    fn search_ext_trace(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, SearchTrace), OperationError> {
+        /*
+         * Perform the external search, but also record how the filter and
+         * access controls were evaluated, so that "why didn't my search return
+         * X?" can be answered without access to the server logs. Because this
+         * discloses entries the caller may not be able to read, it needs the
+         * search_trace operation, which only idm_admins are granted.
+         */
+        self.require_operation(au, &se.event, "search_trace")?;
 
-        // Now resolve all references.
         let vfr = try_audit!(au, se.filter.resolve(&se.event));
 
-        // NOTE: We currently can't build search plugins due to the inability to hand
-        // the QS wr/ro to the plugin trait. However, there shouldn't be a need for search
-        // plugis, because all data transforms should be in the write path.
+        // Evaluate against every entry in the backend, so we can report what each
+        // term of the filter did to the candidate set.
+        let f_all = filter_all!(f_pres("class"))
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let fr_all = try_audit!(au, f_all.resolve(&se.event));
 
-        let mut audit_be = AuditScope::new("backend_search");
+        let mut audit_be = AuditScope::new("backend_search_trace");
         let res = self
             .get_be_txn()
-            .search(&mut audit_be, &vfr)
-            .map(|r| r)
+            .search(&mut audit_be, &fr_all)
             .map_err(|_| OperationError::Backend);
         au.append_scope(audit_be);
+        let candidates = try_audit!(au, res);
 
-        let res = try_audit!(au, res);
+        let nodes: Vec<SearchTraceNode> = vfr
+            .to_inner()
+            .trace_nodes()
+            .into_iter()
+            .map(|(depth, f)| SearchTraceNode {
+                depth: depth,
+                filter: format!("{:?}", f),
+                candidates: candidates
+                    .iter()
+                    .filter(|e| e.entry_match_trace(f).is_none())
+                    .count(),
+            })
+            .collect();
 
-        // Apply ACP before we let the plugins "have at it".
-        // WARNING; for external searches this is NOT the only
-        // ACP application. There is a second application to reduce the
-        // attribute set on the entries!
-        //
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let (matched, failures): (Vec<_>, Vec<_>) = candidates
+            .iter()
+            .map(|e| (e, e.entry_match_trace(vfr.to_inner())))
+            .partition(|(_, term)| term.is_none());
+
+        let failures: Vec<SearchTraceFailure> = failures
+            .into_iter()
+            .filter_map(|(e, term)| {
+                term.map(|t| SearchTraceFailure {
+                    uuid: e.get_uuid().clone(),
+                    term: format!("{:?}", t),
+                })
+            })
+            .collect();
+
+        let matched: Vec<Entry<EntryValid, EntryCommitted>> =
+            matched.into_iter().map(|(e, _)| e.clone()).collect();
+
+        let mut audit_acp = AuditScope::new("access_control_profiles_trace");
         let access = self.get_accesscontrols();
-        let acp_res = access.search_filter_entries(&mut audit_acp, se, res);
+        let acp_res = access.search_trace_entries(&mut audit_acp, se, &matched);
+        au.append_scope(audit_acp);
+        let access_trace = try_audit!(au, acp_res);
+
+        // Finally, the real search, so the result is exactly what the caller
+        // would have seen without the trace.
+        let entries = self.search_ext(au, se)?;
+
+        Ok((
+            entries,
+            SearchTrace {
+                candidates: candidates.len(),
+                nodes: nodes,
+                failures: failures,
+                access: access_trace,
+            },
+        ))
+    }
 
+    // The entry an operation names by uuid, provided the initiator can read
+    // some part of it. One they can't is missing, exactly as one that doesn't
+    // exist, so these operations can't be used to probe for entries.
+    fn visible_target(
+        &self,
+        au: &mut AuditScope,
+        ev: &Event,
+        uuid: &str,
+    ) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+        let target = self.internal_search_uuid(au, uuid)?;
+        let f_valid = filter!(f_eq("uuid", uuid))
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let f_orig = filter_all!(f_eq("uuid", uuid))
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let se = SearchEvent::new_impersonate(ev, f_valid, f_orig);
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let visible = self.get_accesscontrols().search_filter_entry_attributes(
+            &mut audit_acp,
+            &se,
+            vec![target.clone()],
+        );
         au.append_scope(audit_acp);
-        let acp_res = try_audit!(au, acp_res);
+        if visible?.is_empty() {
+            audit_log!(au, "{} is not visible to {:?}", uuid, ev.origin);
+            Err(OperationError::NoMatchingEntries)
+        } else {
+            Ok(target)
+        }
+    }
+
+    fn effective_permissions(
+        &self,
+        au: &mut AuditScope,
+        epe: &EffectivePermissionsEvent,
+    ) -> Result<EffectivePermissions, OperationError> {
+        audit_log!(au, "Begin effective permissions event {:?}", epe);
+        let target = try_audit!(
+            au,
+            self.visible_target(au, &epe.event, epe.target_uuid.as_str())
+        );
 
-        Ok(acp_res)
+        let mut audit_acp = AuditScope::new("access_effective_permissions");
+        let res = self.get_accesscontrols().access_effective_permissions(
+            &mut audit_acp,
+            &epe.event,
+            &target,
+        );
+        au.append_scope(audit_acp);
+        res
     }
 
-    fn exists(&self, au: &mut AuditScope, ee: &ExistsEvent) -> Result<bool, OperationError> {
-        let mut audit_be = AuditScope::new("backend_exists");
+    // Does the target entry hold the value? The entry must be visible, but a
+    // compare acp can be honoured even where the attribute can't be read.
+    fn compare(&self, au: &mut AuditScope, ce: &CompareEvent) -> Result<bool, OperationError> {
+        audit_log!(au, "Begin compare event {:?}", ce);
+        let target = try_audit!(
+            au,
+            self.visible_target(au, &ce.event, ce.target_uuid.as_str())
+        );
 
-        let vfr = try_audit!(au, ee.filter.resolve(&ee.event));
+        let mut audit_acp = AuditScope::new("compare_allow_operation");
+        let access = self
+            .get_accesscontrols()
+            .compare_allow_operation(&mut audit_acp, ce, &target);
+        au.append_scope(audit_acp);
+        if !try_audit!(au, access) {
+            audit_log!(au, "compare of {} denied", ce.attr);
+            self.record_access_denied(au, &ce.event);
+            return Err(OperationError::AccessDenied);
+        }
 
-        let res = self
-            .get_be_txn()
-            .exists(&mut audit_be, &vfr)
-            .map(|r| r)
-            .map_err(|_| OperationError::Backend);
-        au.append_scope(audit_be);
-        res
+        let f_res = try_audit!(au, ce.filter.resolve(&ce.event));
+        Ok(target.entry_match_no_index(&f_res))
     }
 
-    // Should this actually be names_to_uuids and we do batches?
-    //  In the initial design "no", we can always write a batched
-    //  interface later.
-    //
-    // The main question is if we need association between the name and
-    // the request uuid - if we do, we need singular. If we don't, we can
-    // just do the batching.
-    //
-    // Filter conversion likely needs 1:1, due to and/or conversions
-    // but create/mod likely doesn't due to the nature of the attributes.
-    //
-    // In the end, singular is the simple and correct option, so lets do
-    // that first, and we can add batched (and cache!) later.
-    //
-    // Remember, we don't care if the name is invalid, because search
-    // will validate/normalise the filter we construct for us. COOL!
-    fn name_to_uuid(
+    // Which entries the search profiles leave readable by no account outside
+    // idm_admins, and which they leave readable by anonymous - and so by
+    // anyone - for reviewing the policy as a whole. Each entry is checked
+    // against every account outside idm_admins, so on a large database ask
+    // for a sample. Limited to members of idm_admins.
+    fn acp_coverage(
         &self,
-        audit: &mut AuditScope,
-        name: &String,
-    ) -> Result<String, OperationError> {
-        // For now this just constructs a filter and searches, but later
-        // we could actually improve this to contact the backend and do
-        // index searches, completely bypassing id2entry.
-
-        // construct the filter
-        let filt = filter!(f_eq("name", name));
-        audit_log!(audit, "name_to_uuid: name -> {:?}", name);
+        au: &mut AuditScope,
+        ace: &AcpCoverageEvent,
+    ) -> Result<AcpCoverage, OperationError> {
+        self.require_operation(au, &ace.event, "acp_coverage")?;
 
-        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
-        let res = match self.internal_search(audit, filt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
+        let filt = try_audit!(
+            au,
+            filter!(f_pres("class"))
+                .validate(self.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(au, filt.resolve(&Event::from_internal()));
+
+        // The entries are read and checked a batch at a time, so only one
+        // batch is ever held. A sample is spread over the whole database,
+        // rather than being the first n, so that needs the total first.
+        let total = match ace.sample {
+            Some(_) => {
+                let mut iter = try_audit!(au, self.get_be_txn().search_iter(au, filt.clone()));
+                let mut total = 0;
+                while let Some(r) = iter.next_entry(au) {
+                    try_audit!(au, r);
+                    total += 1;
+                }
+                total
+            }
+            None => 0,
         };
+        let sample = ace.sample.filter(|n| *n < total);
 
-        audit_log!(audit, "name_to_uuid: results -- {:?}", res);
-
-        if res.len() == 0 {
-            // If result len == 0, error no such result
-            return Err(OperationError::NoMatchingEntries);
-        } else if res.len() >= 2 {
-            // if result len >= 2, error, invaid entry state.
-            return Err(OperationError::InvalidDBState);
-        }
+        let receivers = try_audit!(
+            au,
+            self.internal_search(
+                au,
+                filter!(f_and!([
+                    f_eq("class", "account"),
+                    f_andnot(f_eq("memberof", UUID_IDM_ADMINS))
+                ]))
+            )
+        );
+        let anon = receivers
+            .iter()
+            .position(|e| e.get_uuid() == UUID_ANONYMOUS);
+        let n_receivers = receivers.len();
+        let events: Vec<Event> = receivers
+            .into_iter()
+            .map(Event::from_impersonate_entry)
+            .collect();
 
-        // error should never be triggered due to the len checks above.
-        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
-        // Get the uuid from the entry. Again, check it exists, and only one.
-        let uuid_res: String = e.get_uuid().to_string();
+        // Every entry and receiver pair is evaluated, which would swamp the
+        // audit log - only the outcome is kept.
+        let mut audit_scan = AuditScope::new("acp_coverage_scan");
+        let mut classes: BTreeMap<String, AcpCoverageClass> = BTreeMap::new();
+        let mut unreadable = Vec::new();
+        let mut world_readable = Vec::new();
+        let mut truncated = false;
+        let mut seen = 0;
+        let mut checked = 0;
+        // With a sample of n, the k'th pick is entry k * total / n.
+        let mut picked = 0;
+
+        let mut iter = try_audit!(au, self.get_be_txn().search_iter(au, filt));
+        loop {
+            let mut batch = Vec::with_capacity(ACP_COVERAGE_BATCH_SIZE);
+            while batch.len() < ACP_COVERAGE_BATCH_SIZE {
+                let e = match iter.next_entry(au) {
+                    Some(r) => try_audit!(au, r),
+                    None => break,
+                };
+                let keep = match sample {
+                    Some(n) => {
+                        if picked < n && seen == picked * total / n {
+                            picked += 1;
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    None => true,
+                };
+                seen += 1;
+                if keep {
+                    batch.push(e);
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            checked += batch.len();
 
-        audit_log!(audit, "name_to_uuid: uuid <- {:?}", uuid_res);
+            let readers = self.get_accesscontrols().search_coverage(
+                &mut audit_scan,
+                &events,
+                batch.as_slice(),
+            );
+            for (e, r) in batch.iter().zip(readers.iter()) {
+                let is_unreadable = r.is_empty();
+                let is_world = anon.map(|a| r.contains(&a)).unwrap_or(false);
+                for c in e.get_ava_set("class").unwrap_or_default() {
+                    let cc = classes
+                        .entry(c.to_string())
+                        .or_insert_with(|| AcpCoverageClass {
+                            class: c.to_string(),
+                            entries: 0,
+                            unreadable: 0,
+                            world_readable: 0,
+                        });
+                    cc.entries += 1;
+                    if is_unreadable {
+                        cc.unreadable += 1;
+                    }
+                    if is_world {
+                        cc.world_readable += 1;
+                    }
+                }
+                let list = if is_unreadable {
+                    &mut unreadable
+                } else if is_world {
+                    &mut world_readable
+                } else {
+                    continue;
+                };
+                if list.len() < ACP_COVERAGE_MAX_LISTED {
+                    list.push(e.get_uuid().clone());
+                } else {
+                    truncated = true;
+                }
+            }
+        }
 
-        Ok(uuid_res)
+        let coverage = AcpCoverage {
+            total: seen,
+            checked: checked,
+            receivers: n_receivers,
+            classes: classes.into_iter().map(|(_, c)| c).collect(),
+            unreadable: unreadable,
+            world_readable: world_readable,
+            truncated: truncated,
+        };
+        audit_log!(
+            au,
+            "acp coverage of {} entries -> {} unreadable, {} world readable",
+            coverage.checked,
+            coverage.unreadable.len(),
+            coverage.world_readable.len()
+        );
+        Ok(coverage)
     }
 
-    fn uuid_to_name(
+    // Roughly how much memory the directory's data takes, for planning how
+    // much a server needs. This shows the shape of every entry, so is
+    // limited to members of idm_admins.
+    fn memory_report(
         &self,
-        audit: &mut AuditScope,
-        uuid: &String,
-    ) -> Result<String, OperationError> {
-        // construct the filter
-        let filt = filter!(f_eq("uuid", uuid));
-        audit_log!(audit, "uuid_to_name: uuid -> {:?}", uuid);
-
-        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
-        let res = match self.internal_search(audit, filt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
+        au: &mut AuditScope,
+        mre: &MemoryReportEvent,
+    ) -> Result<MemoryReport, OperationError> {
+        self.require_operation(au, &mre.event, "memory_report")?;
+
+        let to_uses = |m: BTreeMap<String, (usize, usize)>| -> Vec<MemoryUse> {
+            m.into_iter()
+                .map(|(name, (count, bytes))| MemoryUse {
+                    name: name,
+                    count: count,
+                    bytes: bytes,
+                })
+                .collect()
         };
 
-        audit_log!(audit, "uuid_to_name: results -- {:?}", res);
+        let mut audit_be = AuditScope::new("backend_memory_report");
+        let sizes = self
+            .get_be_txn()
+            .entry_sizes(&mut audit_be)
+            .and_then(|e| self.get_be_txn().idx_sizes(&mut audit_be).map(|i| (e, i)));
+        au.append_scope(audit_be);
+        let ((attrs, classes), indexes) = try_audit!(au, sizes);
 
-        if res.len() == 0 {
-            // If result len == 0, error no such result
-            return Err(OperationError::NoMatchingEntries);
-        } else if res.len() >= 2 {
-            // if result len >= 2, error, invaid entry state.
-            return Err(OperationError::InvalidDBState);
-        }
-
-        // fine for 0/1 case, but check len for >= 2 to eliminate that case.
-        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
-        // Get the uuid from the entry. Again, check it exists, and only one.
-        let name_res = match e.get_ava(&String::from("name")) {
-            Some(vas) => match vas.first() {
-                Some(u) => u.clone(),
-                None => return Err(OperationError::InvalidEntryState),
-            },
-            None => return Err(OperationError::InvalidEntryState),
-        };
-
-        audit_log!(audit, "uuid_to_name: name <- {:?}", name_res);
-
-        Ok(name_res)
+        Ok(MemoryReport {
+            entry_attrs: to_uses(attrs),
+            entry_classes: to_uses(classes),
+            indexes_on_disk: to_uses(indexes),
+            access_controls: self.get_accesscontrols().memory_use(),
+        })
     }
 
-    // From internal, generate an exists event and dispatch
-    fn internal_exists(
+    // Snapshot the database to a file. This is a read, so the server carries
+    // on while it's written. Only members of idm_admins may, as a backup
+    // holds everything.
+    fn backup(
         &self,
         au: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
-    ) -> Result<bool, OperationError> {
-        // Check the filter
-        let f_valid = filter
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        // Build an exists event
-        let ee = ExistsEvent::new_internal(f_valid);
-        // Submit it
-        let mut audit_int = AuditScope::new("internal_exists");
-        let res = self.exists(&mut audit_int, &ee);
-        au.append_scope(audit_int);
-        // return result
+        bue: &BackupEvent,
+        dst_path: &str,
+    ) -> Result<(), OperationError> {
+        self.require_operation(au, &bue.event, "backup")?;
+
+        let mut audit_be = AuditScope::new("backend_backup");
+        let res = self.get_be_txn().backup(&mut audit_be, dst_path);
+        au.append_scope(audit_be);
+        audit_log!(au, "backup to {} -> {:?}", dst_path, res);
         res
     }
 
-    fn internal_search(
+    // The radius secret of the named account, as the initiator may read it.
+    // An account that can't be seen, or whose secret can't be, has none.
+    fn radius_secret_read(
         &self,
-        audit: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        let f_valid = filter
+        au: &mut AuditScope,
+        rre: &RadiusSecretReadEvent,
+    ) -> Result<RadiusSecretResponse, OperationError> {
+        audit_log!(au, "Begin radius secret read event {:?}", rre);
+        let filt = try_audit!(
+            au,
+            filter!(f_and!([
+                f_eq("class", "account"),
+                f_eq("name", rre.name.as_str())
+            ]))
             .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let se = SearchEvent::new_internal(f_valid);
-        let mut audit_int = AuditScope::new("internal_search");
-        let res = self.search(&mut audit_int, &se);
-        audit.append_scope(audit_int);
-        res
+            .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let se = SearchEvent::new_impersonate(&rre.event, filt.clone(), filt);
+        let res = self.search_ext(au, &se)?;
+        res.iter()
+            .filter_map(|e| e.get_ava_single("radius_secret"))
+            .next()
+            .map(|s| RadiusSecretResponse {
+                secret: s.to_string(),
+            })
+            .ok_or(OperationError::NoMatchingEntries)
     }
 
-    fn impersonate_search_valid(
+    // The persisted audit records matching the event's terms. A record is
+    // returned only if an audit read profile that applies to the caller has
+    // a targetscope matching the entry that initiated the operation. Records
+    // with no initiator, such as an auth for a name that doesn't exist, are
+    // returned to anyone an audit read profile applies to.
+    fn audit_log_search(
         &self,
-        audit: &mut AuditScope,
-        f_valid: Filter<FilterValid>,
-        f_intent_valid: Filter<FilterValid>,
-        event: &Event,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        let se = SearchEvent::new_impersonate(event, f_valid, f_intent_valid);
-        let mut audit_int = AuditScope::new("impersonate_search");
-        let res = self.search(&mut audit_int, &se);
-        audit.append_scope(audit_int);
-        res
+        au: &mut AuditScope,
+        ale: &AuditLogEvent,
+        path: &str,
+    ) -> Result<Vec<AuditRecord>, OperationError> {
+        let records: Vec<AuditRecord> = try_audit!(au, read_audit_records(path, ale.since))
+            .into_iter()
+            .filter(|r| ale.matches(r))
+            .collect();
+
+        // Initiators that have since been deleted are still found, as
+        // internal searches include recycled entries and tombstones.
+        let origins: BTreeSet<&str> = records
+            .iter()
+            .filter_map(|r| r.origin.as_ref())
+            .filter(|o| Uuid::parse_str(o.as_str()).is_ok())
+            .map(|o| o.as_str())
+            .collect();
+        let origin_entries = if origins.is_empty() {
+            Vec::new()
+        } else {
+            try_audit!(
+                au,
+                self.internal_search(
+                    au,
+                    filter!(f_or(origins.iter().map(|u| f_eq("uuid", u)).collect()))
+                )
+            )
+        };
+
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let allowed =
+            access.audit_read_allow_operation(&mut audit_acp, &ale.event, &origin_entries);
+        au.append_scope(audit_acp);
+        let allowed = match try_audit!(au, allowed) {
+            Some(a) => a,
+            None => {
+                audit_log!(
+                    au,
+                    "audit log requested without an audit read profile, denying"
+                );
+                self.record_access_denied(au, &ale.event);
+                return Err(OperationError::AccessDenied);
+            }
+        };
+
+        Ok(records
+            .into_iter()
+            .filter(|r| match &r.origin {
+                Some(o) => allowed.contains(o),
+                None => true,
+            })
+            .collect())
     }
 
-    // Who they are will go here
-    fn impersonate_search(
+    // What deleting the entries matching a filter would do, without doing it:
+    // the entries themselves, who loses a reference to them, and which access
+    // profiles would then apply to no one. Only members of idm_admins may
+    // ask, as the impact reaches entries they may not be deleting, and they
+    // must be allowed to delete the entries themselves.
+    fn delete_preview(
         &self,
-        audit: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
-        filter_intent: Filter<FilterInvalid>,
-        event: &Event,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        let f_valid = filter
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let f_intent_valid = filter_intent
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        self.impersonate_search_valid(audit, f_valid, f_intent_valid, event)
+        au: &mut AuditScope,
+        dpe: &DeletePreviewEvent,
+    ) -> Result<DeletePreview, OperationError> {
+        let de = &dpe.delete;
+        self.require_operation(au, &de.event, "delete_preview")?;
+
+        let pre_candidates = self.impersonate_search_valid(
+            au,
+            de.filter.clone(),
+            de.filter_orig.clone(),
+            &de.event,
+        )?;
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let acp_res = metrics::timed(Stage::AccessCheck, || {
+            self.get_accesscontrols()
+                .delete_allow_operation(&mut audit_acp, de, &pre_candidates)
+        });
+        au.append_scope(audit_acp);
+        if try_audit!(au, acp_res) != true {
+            self.record_access_denied(au, &de.event);
+            return Err(OperationError::AccessDenied);
+        }
+
+        let deleted: BTreeSet<String> = pre_candidates
+            .iter()
+            .map(|e| e.get_uuid().clone())
+            .collect();
+        let mut preview = DeletePreview {
+            entries: deleted.iter().cloned().collect(),
+            groups: Vec::new(),
+            references: Vec::new(),
+            access_controls: Vec::new(),
+        };
+        if deleted.is_empty() {
+            return Ok(preview);
+        }
+
+        // The same references referential integrity would remove.
+        let ref_types: Vec<String> = self
+            .get_schema()
+            .get_reference_types()
+            .keys()
+            .map(|a| (*a).clone())
+            .collect();
+        let f_ref = filter!(f_or(
+            deleted
+                .iter()
+                .flat_map(|u| ref_types.iter().map(move |a| f_eq(a.as_str(), u.as_str())))
+                .collect()
+        ));
+        let referring = self.internal_search(au, f_ref)?;
+        for e in referring.iter().filter(|e| !deleted.contains(e.get_uuid())) {
+            let lost = |attr: &str| -> Vec<String> {
+                e.get_ava(attr)
+                    .map(|vs| {
+                        vs.iter()
+                            .map(|v| v.to_string())
+                            .filter(|v| deleted.contains(v))
+                            .collect()
+                    })
+                    .unwrap_or_else(Vec::new)
+            };
+            if e.attribute_value_pres("class", "group") {
+                let members = lost("member");
+                if !members.is_empty() {
+                    preview.groups.push(DeletePreviewGroup {
+                        uuid: e.get_uuid().clone(),
+                        members: members,
+                    });
+                }
+            }
+            preview.references.push(DeletePreviewReference {
+                uuid: e.get_uuid().clone(),
+                attrs: ref_types
+                    .iter()
+                    .filter(|a| !lost(a.as_str()).is_empty())
+                    .cloned()
+                    .collect(),
+            });
+        }
+
+        // A receiver that only matched by referring to what is deleted stops
+        // matching too. Receivers that depend on who is asking can't be
+        // worked out here, so are left alone.
+        let ev_internal = Event::from_internal();
+        for (uuid, receiver) in self.get_accesscontrols().receivers() {
+            if deleted.contains(&uuid) {
+                continue;
+            }
+            let r_resolved = match receiver.resolve(&ev_internal) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            let matched = self.internal_search_valid(au, (*receiver).clone())?;
+            if !matched.is_empty()
+                && !matched.iter().any(|e| {
+                    !deleted.contains(e.get_uuid())
+                        && e.entry_match_no_index_without(&r_resolved, &deleted)
+                })
+            {
+                preview.access_controls.push(uuid);
+            }
+        }
+
+        audit_log!(au, "delete preview -> {:?}", preview);
+        Ok(preview)
     }
 
-    // Get a single entry by it's UUID. This is heavily relied on for internal
-    // server operations, especially in login and acp checks for acp.
-    fn internal_search_uuid(
+    // Everything that changed since the sync token, as the caller is allowed
+    // to see it. Returns the current state of changed entries that the caller
+    // can still read, the uuids of changed entries they can't (deleted, or
+    // no longer visible to them), and the token for the next sync.
+    fn sync(
         &self,
-        audit: &mut AuditScope,
-        uuid: &str,
-    ) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
-        let filter = filter!(f_eq("uuid", uuid));
+        au: &mut AuditScope,
+        se: &SyncEvent,
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, Vec<String>, i64), OperationError> {
+        audit_log!(au, "Begin sync event {:?}", se);
+        let mut audit_be = AuditScope::new("backend_changelog");
+        let changes = match se.token {
+            Some(seq) => self
+                .get_be_txn()
+                .changelog_since(&mut audit_be, seq)
+                .map(|(uuids, seq)| (Some(uuids), seq)),
+            None => self
+                .get_be_txn()
+                .changelog_max_seq(&mut audit_be)
+                .map(|seq| (None, seq)),
+        };
+        au.append_scope(audit_be);
+        let (changed, token) = try_audit!(au, changes);
+
+        let filter = match &changed {
+            // Initial sync, so send everything.
+            None => filter!(f_pres("uuid")),
+            Some(uuids) => {
+                if uuids.is_empty() {
+                    return Ok((Vec::new(), Vec::new(), token));
+                }
+                filter!(f_or(
+                    uuids.iter().map(|u| f_eq("uuid", u.as_str())).collect()
+                ))
+            }
+        };
         let f_valid = filter
             .validate(self.get_schema())
             .map_err(|e| OperationError::SchemaViolation(e))?;
-        let se = SearchEvent::new_internal(f_valid);
-        let mut audit_int = AuditScope::new("internal_search_uuid");
-        let res = self.search(&mut audit_int, &se);
-        audit.append_scope(audit_int);
-        match res {
-            Ok(vs) => {
-                if vs.len() > 1 {
-                    return Err(OperationError::NoMatchingEntries);
-                }
-                vs.into_iter()
-                    .next()
-                    .ok_or(OperationError::NoMatchingEntries)
+        let sre = SearchEvent::new_impersonate(&se.event, f_valid.clone(), f_valid);
+        let entries = self.search_ext(au, &sre)?;
+
+        let deleted = match changed {
+            None => Vec::new(),
+            // If the caller can't read uuid on an entry, they have no way to
+            // key it in their copy, so it's reported as deleted too.
+            Some(mut uuids) => {
+                entries.iter().for_each(|e| {
+                    if let Some(u) = e.get_ava_single("uuid") {
+                        uuids.remove(&u.to_string());
+                    }
+                });
+                uuids.into_iter().collect()
             }
-            Err(e) => Err(e),
-        }
+        };
+
+        Ok((entries, deleted, token))
     }
 
-    // Do a schema aware clone, that fixes values that need some kind of alteration
-    // or lookup from the front end.
-    //
-    // For example, reference types.
-    //
-    // For passwords, hashing and changes will take place later.
-    //
-    // TODO #66: It could be argued that we should have a proper "Value" type, so that we can
-    // take care of this a bit cleaner, and do the checks in that, but I think for
-    // now this is good enough.
-    fn clone_value(
+    // The entries changed since the consumer's position in our changelog, as
+    // they are now, and the position to ask from next time. Without one every
+    // entry is sent, with the position of the latest change. Only members of
+    // idm_admins may replicate, as this bypasses access controls.
+    fn repl_changes(
         &self,
-        audit: &mut AuditScope,
-        attr: &String,
-        value: &String,
-    ) -> Result<String, OperationError> {
-        let schema = self.get_schema();
-        let schema_name = schema
-            .get_attributes()
-            .get("name")
-            .expect("Schema corrupted");
-
-        // Should we return the normalise attr?
-        // no, I think that it's not up to us to normalise this all the time.
-        let temp_a = schema_name.normalise_value(attr);
+        au: &mut AuditScope,
+        rce: &ReplChangesEvent,
+    ) -> Result<ReplChangesResponse, OperationError> {
+        self.require_operation(au, &rce.event, "replication")?;
+
+        let mut audit_be = AuditScope::new("backend_changelog");
+        let changes = match rce.since {
+            Some(since) => self
+                .get_be_txn()
+                .changelog_entries_since(&mut audit_be, since)
+                .map(|cs| {
+                    let next = cs.last().map(|c| c.seq).unwrap_or(since);
+                    let uuids: BTreeSet<String> = cs.into_iter().map(|c| c.uuid).collect();
+                    (Some(uuids), next)
+                }),
+            None => self
+                .get_be_txn()
+                .changelog_max_seq(&mut audit_be)
+                .map(|seq| (None, seq)),
+        };
+        au.append_scope(audit_be);
+        let (changed, next) = try_audit!(au, changes);
 
-        // Lookup the attr
-        match schema.get_attributes().get(&temp_a) {
-            Some(schema_a) => {
-                // Now check the type of the attribute ...
-                match schema_a.syntax {
-                    SyntaxType::REFERENCE_UUID => {
-                        match schema_a.validate_value(value) {
-                            // So, if possible, resolve the value
-                            // to a concrete uuid.
-                            Ok(_) => {
-                                // It's a uuid - we do NOT check for existance, because that
-                                // could be revealing or disclosing - it is up to acp to assert
-                                // if we can see the value or not, and it's not up to us to
-                                // assert the filter value exists.
-                                Ok(value.clone())
-                            }
-                            Err(_) => {
-                                // it's not a uuid, try to resolve it.
-                                // if the value is NOT found, we map to "does not exist" to allow
-                                // the value to continue being evaluated, which of course, will fail
-                                // all subsequent filter tests because it ... well, doesn't exist.
-                                self.name_to_uuid(audit, value)
-                                    .or_else(|_| Ok(UUID_DOES_NOT_EXIST.to_string()))
-                            }
-                        }
-                    }
-                    _ => {
-                        // Probs okay.
-                        Ok(value.clone())
+        let is_agreement = |e: &Entry<EntryValid, EntryCommitted>| {
+            e.attribute_value_pres("class", "replication_agreement")
+        };
+        let entries: Vec<ReplEntry> = match changed {
+            None => self
+                .internal_search(au, filter_all!(f_pres("class")))?
+                .iter()
+                .filter(|e| !is_agreement(*e))
+                .map(|e| e.to_repl_entry())
+                .collect(),
+            Some(uuids) => {
+                let mut entries = Vec::new();
+                for u in uuids {
+                    match self
+                        .internal_search(au, filter_all!(f_eq("uuid", u.as_str())))?
+                        .first()
+                    {
+                        Some(e) if is_agreement(e) => {}
+                        Some(e) => entries.push(e.to_repl_entry()),
+                        // Its tombstone has been purged.
+                        None => entries.push(ReplEntry {
+                            uuid: u,
+                            attrs: None,
+                            csns: BTreeMap::new(),
+                        }),
                     }
                 }
+                entries
             }
-            None => {
-                // No attribute of this name exists - clone the value anyway, because
-                // the schema check will fail soon, and it's beyond this functions
-                // purpose to validate your content anyway.
-                Ok(value.clone())
-            }
-        }
+        };
+        audit_log!(
+            au,
+            "replication since {:?} -> {} entries",
+            rce.since,
+            entries.len()
+        );
+        Ok(ReplChangesResponse::new(
+            entries,
+            next,
+            self.get_domain_info().to_proto(),
+        ))
     }
 
-    // In the opposite direction, we can resolve values for presentation
-    fn resolve_value(&self, _attr: &String, value: &String) -> Result<String, OperationError> {
-        Ok(value.clone())
+    // The uuids of the groups whose join requests this event can see and
+    // decide on. A group is managed by whoever its group_manager names, either
+    // directly or through a group they are a member of. Internal events can
+    // act on any group, so get None.
+    fn group_join_managed(
+        &self,
+        au: &mut AuditScope,
+        event: &Event,
+    ) -> Result<Option<BTreeSet<String>>, OperationError> {
+        let e = match &event.origin {
+            EventOrigin::Internal => return Ok(None),
+            EventOrigin::User(e) => e,
+        };
+        let mut managers: Vec<String> = vec![e.get_uuid().clone()];
+        if let Some(mo) = e.get_ava("memberof") {
+            managers.extend(mo.iter().map(|v| v.to_string()));
+        }
+        let filt = filter!(f_and!([
+            f_eq("class", "group"),
+            f_or(
+                managers
+                    .iter()
+                    .map(|m| f_eq("group_manager", m.as_str()))
+                    .collect()
+            )
+        ]));
+        let groups = try_audit!(au, self.internal_search(au, filt));
+        Ok(Some(groups.iter().map(|g| g.get_uuid().clone()).collect()))
     }
-}
-
-pub struct QueryServerReadTransaction {
-    be_txn: BackendReadTransaction,
-    // Anything else? In the future, we'll need to have a schema transaction
-    // type, maybe others?
-    schema: SchemaReadTransaction,
-    accesscontrols: AccessControlsReadTransaction,
-}
 
-// Actually conduct a search request
-// This is the core of the server, as it processes the entire event
-// applies all parts required in order and more.
-impl QueryServerTransaction for QueryServerReadTransaction {
-    type BackendTransactionType = BackendReadTransaction;
+    // Pending join requests for the groups the caller manages.
+    fn group_join_list(
+        &self,
+        au: &mut AuditScope,
+        gle: &GroupJoinListEvent,
+    ) -> Result<Vec<GroupJoinInfo>, OperationError> {
+        audit_log!(au, "Begin group join list event {:?}", gle);
+        let managed = self.group_join_managed(au, &gle.event)?;
+        let requests = try_audit!(
+            au,
+            self.internal_search(au, filter!(f_eq("class", "group_join_request")))
+        );
 
-    fn get_be_txn(&self) -> &BackendReadTransaction {
-        &self.be_txn
+        // A request whose group or requester has been deleted has lost that
+        // reference to refint, and there is nothing left to decide.
+        Ok(requests
+            .iter()
+            .filter_map(|r| {
+                let group = r.get_ava_single("join_group")?.to_string();
+                let requester = r.get_ava_single("join_requester")?.to_string();
+                match &managed {
+                    Some(groups) if !groups.contains(&group) => None,
+                    _ => Some(GroupJoinInfo {
+                        uuid: r.get_uuid().clone(),
+                        group: group,
+                        requester: requester,
+                        description: r.get_ava_single("description").map(|d| d.to_string()),
+                    }),
+                }
+            })
+            .collect())
     }
 
-    type SchemaTransactionType = SchemaReadTransaction;
-
-    fn get_schema(&self) -> &SchemaReadTransaction {
-        &self.schema
+    // Names of the entries the caller can see, that start with the prefix,
+    // first by name. Only the first few by name are kept as the matches are
+    // read, so a short prefix doesn't hold every entry of the class.
+    fn typeahead(
+        &self,
+        au: &mut AuditScope,
+        te: &TypeaheadEvent,
+    ) -> Result<Vec<TypeaheadEntry>, OperationError> {
+        audit_log!(au, "Begin typeahead event {:?}", te);
+        // Names are unique, so they key the suggestions.
+        let mut first: BTreeMap<String, String> = BTreeMap::new();
+        for r in self.search_ext_iter(au, &te.search)? {
+            let e = r?;
+            // Both have to be readable to be any use to the caller.
+            if let (Some(name), Some(uuid)) = (e.get_ava_single("name"), e.get_ava_single("uuid")) {
+                first.insert(name.to_string(), uuid.to_string());
+                if first.len() > te.limit {
+                    first.pop_last();
+                }
+            }
+        }
+        Ok(first
+            .into_iter()
+            .map(|(name, uuid)| TypeaheadEntry {
+                name: name,
+                uuid: uuid,
+            })
+            .collect())
     }
 
-    type AccessControlsTransactionType = AccessControlsReadTransaction;
+    fn search(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        audit_log!(au, "search: filter -> {:?}", se.filter);
 
-    fn get_accesscontrols(&self) -> &AccessControlsReadTransaction {
-        &self.accesscontrols
-    }
-}
+        // This is an important security step because it prevents us from
+        // performing un-indexed searches on attr's that don't exist in the
+        // server. This is why ExtensibleObject can only take schema that
+        // exists in the server, not arbitrary attr names.
+        //
+        // This normalises and validates in a single step.
+        //
+        // NOTE: Filters are validated in event conversion.
 
-impl QueryServerReadTransaction {
-    // Verify the data content of the server is as expected. This will probably
-    // call various functions for validation, including possibly plugin
-    // verifications.
-    fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
-        let mut audit = AuditScope::new("verify");
+        let limit = self.anon_search_limits(au, se)?;
 
-        // If we fail after backend, we need to return NOW because we can't
-        // assert any other faith in the DB states.
-        //  * backend
-        let be_errs = self.get_be_txn().verify();
+        // Now resolve all references.
+        let vfr = try_audit!(
+            au,
+            metrics::timed(Stage::FilterResolve, || se.filter.resolve(&se.event))
+        );
 
-        if be_errs.len() != 0 {
-            au.append_scope(audit);
-            return be_errs;
-        }
+        // NOTE: We currently can't build search plugins due to the inability to hand
+        // the QS wr/ro to the plugin trait. However, there shouldn't be a need for search
+        // plugis, because all data transforms should be in the write path.
 
-        //  * in memory schema consistency.
-        let sc_errs = self.get_schema().validate(&mut audit);
+        let mut audit_be = AuditScope::new("backend_search");
+        let res = metrics::timed(Stage::BackendRead, || {
+            self.get_be_txn().search(&mut audit_be, &vfr)
+        })
+        .map_err(|_| OperationError::Backend);
+        au.append_scope(audit_be);
 
-        if sc_errs.len() != 0 {
-            au.append_scope(audit);
-            return sc_errs;
-        }
+        let res = try_audit!(au, res);
+        metrics::incr(Counter::EntriesRead, res.len());
 
-        //  * Indexing (req be + sch )
-        /*
-        idx_errs = self.get_be_txn()
-            .verify_indexes();
+        // Apply ACP before we let the plugins "have at it".
+        // WARNING; for external searches this is NOT the only
+        // ACP application. There is a second application to reduce the
+        // attribute set on the entries!
+        //
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = metrics::timed(Stage::AccessCheck, || {
+            access.search_filter_entries(
+                &mut audit_acp,
+                se,
+                res,
+                self.get_be_txn().get_parallel_min(),
+            )
+        });
+
+        au.append_scope(audit_acp);
+        let acp_res = try_audit!(au, acp_res);
 
-        if idx_errs.len() != 0 {
-            au.append_scope(audit);
-            return idx_errs;
+        match limit {
+            Some(max) if acp_res.len() > max => {
+                audit_log!(au, "search went over the limit of {} entries", max);
+                Err(OperationError::SizeLimitExceeded(max))
+            }
+            _ => Ok(acp_res),
         }
-         */
+    }
 
-        // Ok BE passed, lets move on to the content.
-        // Most of our checks are in the plugins, so we let them
-        // do their job.
+    fn exists(&self, au: &mut AuditScope, ee: &ExistsEvent) -> Result<bool, OperationError> {
+        let mut audit_be = AuditScope::new("backend_exists");
 
-        // Now, call the plugins verification system.
-        let pl_errs = Plugins::run_verify(&mut audit, self);
+        let vfr = try_audit!(au, ee.filter.resolve(&ee.event));
 
-        // Finish up ...
-        au.append_scope(audit);
-        pl_errs
+        let res = self
+            .get_be_txn()
+            .exists(&mut audit_be, &vfr)
+            .map(|r| r)
+            .map_err(|_| OperationError::Backend);
+        au.append_scope(audit_be);
+        res
     }
-}
 
-pub struct QueryServerWriteTransaction<'a> {
-    committed: bool,
-    be_txn: BackendWriteTransaction,
-    schema: SchemaWriteTransaction<'a>,
-    accesscontrols: AccessControlsWriteTransaction<'a>,
-    // We store a set of flags that indicate we need a reload of
-    // schema or acp, which is tested by checking the classes of the
-    // changing content.
-    changed_schema: bool,
-    changed_acp: bool,
-}
+    // Should this actually be names_to_uuids and we do batches?
+    //  In the initial design "no", we can always write a batched
+    //  interface later.
+    //
+    // The main question is if we need association between the name and
+    // the request uuid - if we do, we need singular. If we don't, we can
+    // just do the batching.
+    //
+    // Filter conversion likely needs 1:1, due to and/or conversions
+    // but create/mod likely doesn't due to the nature of the attributes.
+    //
+    // In the end, singular is the simple and correct option, so lets do
+    // that first, and we can add batched (and cache!) later.
+    //
+    // Remember, we don't care if the name is invalid, because search
+    // will validate/normalise the filter we construct for us. COOL!
+    fn name_to_uuid(
+        &self,
+        audit: &mut AuditScope,
+        name: &String,
+    ) -> Result<String, OperationError> {
+        // For now this just constructs a filter and searches, but later
+        // we could actually improve this to contact the backend and do
+        // index searches, completely bypassing id2entry.
 
-impl<'a> QueryServerTransaction for QueryServerWriteTransaction<'a> {
-    type BackendTransactionType = BackendWriteTransaction;
+        // construct the filter
+        let filt = filter!(f_eq("name", name));
+        audit_log!(audit, "name_to_uuid: name -> {:?}", name);
 
-    fn get_be_txn(&self) -> &BackendWriteTransaction {
-        &self.be_txn
-    }
+        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
+        let res = match self.internal_search(audit, filt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
 
-    type SchemaTransactionType = SchemaWriteTransaction<'a>;
+        audit_log!(audit, "name_to_uuid: results -- {:?}", res);
 
-    fn get_schema(&self) -> &SchemaWriteTransaction<'a> {
-        &self.schema
-    }
+        if res.len() == 0 {
+            // If result len == 0, error no such result
+            return Err(OperationError::NoMatchingEntries);
+        } else if res.len() >= 2 {
+            // if result len >= 2, error, invaid entry state.
+            return Err(OperationError::InvalidDBState);
+        }
 
-    type AccessControlsTransactionType = AccessControlsWriteTransaction<'a>;
+        // error should never be triggered due to the len checks above.
+        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
+        // Get the uuid from the entry. Again, check it exists, and only one.
+        let uuid_res: String = e.get_uuid().to_string();
 
-    fn get_accesscontrols(&self) -> &AccessControlsWriteTransaction<'a> {
-        &self.accesscontrols
+        audit_log!(audit, "name_to_uuid: uuid <- {:?}", uuid_res);
+
+        Ok(uuid_res)
     }
-}
 
-#[derive(Clone)]
-pub struct QueryServer {
-    // log: actix::Addr<EventLog>,
-    be: Backend,
-    schema: Arc<Schema>,
-    accesscontrols: Arc<AccessControls>,
-}
+    fn uuid_to_name(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &String,
+    ) -> Result<String, OperationError> {
+        // construct the filter
+        let filt = filter!(f_eq("uuid", uuid));
+        audit_log!(audit, "uuid_to_name: uuid -> {:?}", uuid);
 
-impl QueryServer {
-    pub fn new(be: Backend, schema: Schema) -> Self {
-        // log_event!(log, "Starting query worker ...");
-        QueryServer {
-            be: be,
-            schema: Arc::new(schema),
-            accesscontrols: Arc::new(AccessControls::new()),
-        }
-    }
+        // Internal search - DO NOT SEARCH TOMBSTONES AND RECYCLE
+        let res = match self.internal_search(audit, filt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
 
-    pub fn read(&self) -> QueryServerReadTransaction {
-        QueryServerReadTransaction {
-            be_txn: self.be.read(),
-            schema: self.schema.read(),
-            accesscontrols: self.accesscontrols.read(),
-        }
-    }
+        audit_log!(audit, "uuid_to_name: results -- {:?}", res);
 
-    pub fn write(&self) -> QueryServerWriteTransaction {
-        QueryServerWriteTransaction {
-            // I think this is *not* needed, because commit is mut self which should
-            // take ownership of the value, and cause the commit to "only be run
-            // once".
-            //
-            // The commited flag is however used for abort-specific code in drop
-            // which today I don't think we have ... yet.
-            committed: false,
-            be_txn: self.be.write(),
-            schema: self.schema.write(),
-            accesscontrols: self.accesscontrols.write(),
-            changed_schema: false,
-            changed_acp: false,
+        if res.len() == 0 {
+            // If result len == 0, error no such result
+            return Err(OperationError::NoMatchingEntries);
+        } else if res.len() >= 2 {
+            // if result len >= 2, error, invaid entry state.
+            return Err(OperationError::InvalidDBState);
         }
-    }
 
-    pub(crate) fn initialise_helper(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        let mut ts_write_1 = self.write();
-        ts_write_1
-            .initialise_schema_core(audit)
-            .and_then(|_| ts_write_1.commit(audit))?;
+        // fine for 0/1 case, but check len for >= 2 to eliminate that case.
+        let e = res.first().ok_or(OperationError::NoMatchingEntries)?;
+        // Get the uuid from the entry. Again, check it exists, and only one.
+        let name_res = match e.get_ava(&String::from("name")) {
+            Some(vas) => match vas.first() {
+                Some(u) => u.to_string(),
+                None => return Err(OperationError::InvalidEntryState),
+            },
+            None => return Err(OperationError::InvalidEntryState),
+        };
 
-        let mut ts_write_2 = self.write();
-        ts_write_2
-            .initialise_schema_idm(audit)
-            .and_then(|_| ts_write_2.commit(audit))?;
+        audit_log!(audit, "uuid_to_name: name <- {:?}", name_res);
 
-        let mut ts_write_3 = self.write();
-        ts_write_3
-            .initialise_idm(audit)
-            .and_then(|_| ts_write_3.commit(audit))
+        Ok(name_res)
     }
 
-    pub fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
-        let r_txn = self.read();
-        r_txn.verify(au)
+    // From internal, generate an exists event and dispatch
+    fn internal_exists(
+        &self,
+        au: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+    ) -> Result<bool, OperationError> {
+        // Check the filter
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        self.internal_exists_valid(au, f_valid)
     }
-}
-
-impl<'a> QueryServerWriteTransaction<'a> {
-    pub fn create(&mut self, au: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
-        // The create event is a raw, read only representation of the request
-        // that was made to us, including information about the identity
-        // performing the request.
 
-        // Log the request
+    // As internal_exists, for a filter that is already valid. Plugins on hot
+    // paths use this with Filter::assume_valid to skip revalidating.
+    fn internal_exists_valid(
+        &self,
+        au: &mut AuditScope,
+        f_valid: Filter<FilterValid>,
+    ) -> Result<bool, OperationError> {
+        // Build an exists event
+        let ee = ExistsEvent::new_internal(f_valid);
+        // Submit it
+        let mut audit_int = AuditScope::new("internal_exists");
+        let res = self.exists(&mut audit_int, &ee);
+        au.append_scope(audit_int);
+        // return result
+        res
+    }
 
-        // TODO #67: Do we need limits on number of creates, or do we constraint
-        // based on request size in the frontend?
+    fn internal_search(
+        &self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        self.internal_search_valid(audit, f_valid)
+    }
 
-        // Copy the entries to a writeable form.
-        let candidates: Vec<Entry<EntryInvalid, EntryNew>> =
-            ce.entries.iter().map(|er| er.clone()).collect();
-
-        // Normalise but DO NOT validate the entries.
-        let norm_cand: Result<Vec<Entry<EntryNormalised, EntryNew>>, _> = candidates
-            .into_iter()
-            .map(|e| {
-                e.normalise(&self.schema)
-                    .map_err(|er| OperationError::SchemaViolation(er))
-            })
-            .collect();
+    fn internal_search_valid(
+        &self,
+        audit: &mut AuditScope,
+        f_valid: Filter<FilterValid>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let se = SearchEvent::new_internal(f_valid);
+        let mut audit_int = AuditScope::new("internal_search");
+        let res = self.search(&mut audit_int, &se);
+        audit.append_scope(audit_int);
+        res
+    }
 
-        let norm_cand = try_audit!(au, norm_cand);
+    fn impersonate_search_valid(
+        &self,
+        audit: &mut AuditScope,
+        f_valid: Filter<FilterValid>,
+        f_intent_valid: Filter<FilterValid>,
+        event: &Event,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let se = SearchEvent::new_impersonate(event, f_valid, f_intent_valid);
+        let mut audit_int = AuditScope::new("impersonate_search");
+        let res = self.search(&mut audit_int, &se);
+        audit.append_scope(audit_int);
+        res
+    }
 
-        // Handle the error.
+    // Who they are will go here
+    fn impersonate_search(
+        &self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        filter_intent: Filter<FilterInvalid>,
+        event: &Event,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let f_intent_valid = filter_intent
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        self.impersonate_search_valid(audit, f_valid, f_intent_valid, event)
+    }
 
-        // Do we have rights to perform these creates?
-        // create_allow_operation
-        let mut audit_acp = AuditScope::new("access_control_profiles");
-        let access = self.get_accesscontrols();
-        let acp_res = access.create_allow_operation(&mut audit_acp, ce, &norm_cand);
-        au.append_scope(audit_acp);
-        if try_audit!(au, acp_res) != true {
-            return Err(OperationError::AccessDenied);
+    // Get a single entry by it's UUID. This is heavily relied on for internal
+    // server operations, especially in login and acp checks for acp.
+    fn internal_search_uuid(
+        &self,
+        audit: &mut AuditScope,
+        uuid: &str,
+    ) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+        let filter = filter!(f_eq("uuid", uuid));
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let se = SearchEvent::new_internal(f_valid);
+        let mut audit_int = AuditScope::new("internal_search_uuid");
+        let res = self.search(&mut audit_int, &se);
+        audit.append_scope(audit_int);
+        match res {
+            Ok(vs) => {
+                if vs.len() > 1 {
+                    return Err(OperationError::NoMatchingEntries);
+                }
+                vs.into_iter()
+                    .next()
+                    .ok_or(OperationError::NoMatchingEntries)
+            }
+            Err(e) => Err(e),
         }
+    }
 
-        // Invalidate them all again ...
-        let mut candidates: Vec<Entry<EntryInvalid, EntryNew>> =
-            norm_cand.into_iter().map(|e| e.invalidate()).collect();
-
-        // run any pre plugins, giving them the list of mutable candidates.
-        // pre-plugins are defined here in their correct order of calling!
-        // I have no intent to make these dynamic or configurable.
+    // Do a schema aware clone, that fixes values that need some kind of alteration
+    // or lookup from the front end.
+    //
+    // For example, reference types.
+    //
+    // For passwords, hashing and changes will take place later.
+    //
+    // Values of attributes in schema are normalised and must be valid for the
+    // attribute's syntax, so bad input is refused here with the attribute and
+    // value that were wrong. The result is still a string - it becomes a typed
+    // Value when the filter or modlist it's part of is validated with schema.
+    fn clone_value(
+        &self,
+        audit: &mut AuditScope,
+        attr: &String,
+        value: &String,
+    ) -> Result<String, OperationError> {
+        let schema = self.get_schema();
 
-        let mut audit_plugin_pre_transform = AuditScope::new("plugin_pre_create_transform");
-        let plug_pre_transform_res = Plugins::run_pre_create_transform(
-            &mut audit_plugin_pre_transform,
-            self,
-            &mut candidates,
-            ce,
-        );
-        au.append_scope(audit_plugin_pre_transform);
+        // Should we return the normalise attr?
+        // no, I think that it's not up to us to normalise this all the time.
+        let temp_a = schema.normalise_attr_name(attr);
 
-        let _ = try_audit!(
-            au,
-            plug_pre_transform_res,
-            "Create operation failed (plugin), {:?}"
-        );
+        // Lookup the attr
+        match schema.get_attributes().get(&temp_a) {
+            Some(schema_a) => {
+                // Now check the type of the attribute ...
+                let value_norm = schema_a.normalise_value(value);
+                match schema_a.syntax {
+                    SyntaxType::REFERENCE_UUID => {
+                        match schema_a.validate_value(&value_norm) {
+                            // So, if possible, resolve the value
+                            // to a concrete uuid.
+                            Ok(_) => {
+                                // It's a uuid - we do NOT check for existance, because that
+                                // could be revealing or disclosing - it is up to acp to assert
+                                // if we can see the value or not, and it's not up to us to
+                                // assert the filter value exists.
+                                Ok(value_norm)
+                            }
+                            Err(_) => {
+                                // it's not a uuid, try to resolve it.
+                                // if the value is NOT found, we map to "does not exist" to allow
+                                // the value to continue being evaluated, which of course, will fail
+                                // all subsequent filter tests because it ... well, doesn't exist.
+                                self.name_to_uuid(audit, value)
+                                    .or_else(|_| Ok(UUID_DOES_NOT_EXIST.to_string()))
+                            }
+                        }
+                    }
+                    _ => match schema_a.validate_value(&value_norm) {
+                        Ok(_) => Ok(value_norm),
+                        Err(e) => {
+                            audit_log!(audit, "clone_value: invalid value for {}", temp_a);
+                            // Errors that already say what was wrong are kept.
+                            Err(OperationError::SchemaViolation(match e {
+                                SchemaError::InvalidAttributeSyntax => {
+                                    SchemaError::InvalidValue(temp_a.clone(), value.clone())
+                                }
+                                e => e,
+                            }))
+                        }
+                    },
+                }
+            }
+            None => {
+                // No attribute of this name exists - clone the value anyway, because
+                // the schema check will fail soon, and it's beyond this functions
+                // purpose to validate your content anyway.
+                Ok(value.clone())
+            }
+        }
+    }
 
-        // NOTE: This is how you map from Vec<Result<T>> to Result<Vec<T>>
-        // remember, that you only get the first error and the iter terminates.
+    // In the opposite direction, we can resolve values for presentation
+    fn resolve_value(&self, _attr: &String, value: &String) -> Result<String, OperationError> {
+        Ok(value.clone())
+    }
+}
 
-        // Now, normalise AND validate!
+pub struct QueryServerReadTransaction {
+    be_txn: BackendReadTransaction,
+    // Anything else? In the future, we'll need to have a schema transaction
+    // type, maybe others?
+    schema: SchemaReadTransaction,
+    accesscontrols: AccessControlsReadTransaction,
+    anon_search_rate: Arc<RateLimit>,
+    anomalies: Arc<AnomalyDetector>,
+    filter_limits: FilterLimits,
+    search_max_results: usize,
+    domain_info: CowCellReadTxn<DomainInfo>,
+    // When the transaction began, from the server's clock.
+    now: Duration,
+}
 
-        let res: Result<Vec<Entry<EntryValid, EntryNew>>, OperationError> = candidates
-            .into_iter()
-            .map(|e| {
-                e.validate(&self.schema)
-                    .map_err(|er| OperationError::SchemaViolation(er))
-            })
-            .collect();
+// Actually conduct a search request
+// This is the core of the server, as it processes the entire event
+// applies all parts required in order and more.
+impl QueryServerTransaction for QueryServerReadTransaction {
+    type BackendTransactionType = BackendReadTransaction;
 
-        let norm_cand: Vec<Entry<_, _>> = try_audit!(au, res);
+    fn get_be_txn(&self) -> &BackendReadTransaction {
+        &self.be_txn
+    }
 
-        // Run any pre-create plugins now with schema validated entries.
-        // This is important for normalisation of certain types IE class
-        // or attributes for these checks.
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_create");
-        let plug_pre_res = Plugins::run_pre_create(&mut audit_plugin_pre, self, &norm_cand, ce);
-        au.append_scope(audit_plugin_pre);
+    type SchemaTransactionType = SchemaReadTransaction;
 
-        let _ = try_audit!(au, plug_pre_res, "Create operation failed (plugin), {:?}");
+    fn get_schema(&self) -> &SchemaReadTransaction {
+        &self.schema
+    }
 
-        let mut audit_be = AuditScope::new("backend_create");
-        // We may change from ce.entries later to something else?
-        let res = self
-            .be_txn
-            .create(&mut audit_be, &norm_cand)
-            .map(|_| ())
-            .map_err(|e| e);
+    type AccessControlsTransactionType = AccessControlsReadTransaction;
 
-        au.append_scope(audit_be);
+    fn get_accesscontrols(&self) -> &AccessControlsReadTransaction {
+        &self.accesscontrols
+    }
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Create operation failed (backend), {:?}", res);
-            return res;
-        }
-        // Run any post plugins
+    fn get_anon_search_rate(&self) -> &RateLimit {
+        &self.anon_search_rate
+    }
 
-        let mut audit_plugin_post = AuditScope::new("plugin_post_create");
-        let plug_post_res = Plugins::run_post_create(&mut audit_plugin_post, self, &norm_cand, ce);
-        au.append_scope(audit_plugin_post);
+    fn get_anomalies(&self) -> &AnomalyDetector {
+        &self.anomalies
+    }
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Create operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
-        }
+    fn get_filter_limits(&self) -> &FilterLimits {
+        &self.filter_limits
+    }
 
-        // We have finished all plugs and now have a successful operation - flag if
-        // schema or acp requires reload.
-        self.changed_schema = norm_cand.iter().fold(false, |acc, e| {
-            if acc {
-                acc
-            } else {
-                e.attribute_value_pres("class", "classtype")
-                    || e.attribute_value_pres("class", "attributetype")
-            }
-        });
-        self.changed_acp = norm_cand.iter().fold(false, |acc, e| {
-            if acc {
-                acc
-            } else {
-                e.attribute_value_pres("class", "access_control_profile")
-            }
-        });
-        audit_log!(
-            au,
-            "Schema reload: {:?}, ACP reload: {:?}",
-            self.changed_schema,
-            self.changed_acp
-        );
+    fn get_search_max_results(&self) -> usize {
+        self.search_max_results
+    }
 
-        // We are complete, finalise logging and return
+    fn get_domain_info(&self) -> &DomainInfo {
+        &self.domain_info
+    }
+}
 
-        audit_log!(au, "Create operation success");
-        res
+impl QueryServerReadTransaction {
+    pub fn now(&self) -> Duration {
+        self.now
     }
 
-    pub fn delete(&mut self, au: &mut AuditScope, de: &DeleteEvent) -> Result<(), OperationError> {
-        // Do you have access to view all the set members? Reduce based on your
-        // read permissions and attrs
-        // THIS IS PRETTY COMPLEX SEE THE DESIGN DOC
-        // In this case we need a search, but not INTERNAL to keep the same
-        // associated credentials.
-        // We only need to retrieve uuid though ...
+    // Verify the data content of the server is as expected. This will probably
+    // call various functions for validation, including possibly plugin
+    // verifications.
+    fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        self.verify_report(au).into_results()
+    }
 
-        // Now, delete only what you can see
-        let pre_candidates = match self.impersonate_search_valid(
+    // What each of the consistency checks found. See verify.rs.
+    pub fn verify_report(&self, au: &mut AuditScope) -> VerifyReport {
+        let mut audit = AuditScope::new("verify");
+        let report = run_verify(&mut audit, self);
+        au.append_scope(audit);
+        report
+    }
+
+    // Write every live entry to a file in its external json form, one entry
+    // per line. Unlike a backup this can be read and edited by hand, and
+    // loaded with import. Returns how many entries were written.
+    pub fn export(&self, au: &mut AuditScope, dst_path: &str) -> Result<usize, OperationError> {
+        let entries = self.internal_search(au, filter!(f_pres("class")))?;
+        let lines: Result<Vec<String>, _> = entries.iter().map(|e| e.to_external_json()).collect();
+        let mut data = try_audit!(au, lines).join("\n");
+        data.push('\n');
+        try_audit!(
             au,
-            de.filter.clone(),
-            de.filter_orig.clone(),
-            &de.event,
-        ) {
-            Ok(results) => results,
-            Err(e) => {
-                audit_log!(au, "delete: error in pre-candidate selection {:?}", e);
-                return Err(e);
-            }
-        };
+            fs::write(dst_path, data),
+            "fs::write error {:?}",
+            OperationError::FsError
+        );
+        audit_log!(au, "exported {} entries", entries.len());
+        Ok(entries.len())
+    }
+}
 
-        // Apply access controls to reduce the set if required.
-        // delete_allow_operation
-        let mut audit_acp = AuditScope::new("access_control_profiles");
-        let access = self.get_accesscontrols();
-        let acp_res = access.delete_allow_operation(&mut audit_acp, de, &pre_candidates);
-        au.append_scope(audit_acp);
-        if try_audit!(au, acp_res) != true {
-            return Err(OperationError::AccessDenied);
-        }
+pub struct QueryServerWriteTransaction<'a> {
+    committed: bool,
+    be_txn: BackendWriteTransaction,
+    schema: SchemaWriteTransaction<'a>,
+    accesscontrols: AccessControlsWriteTransaction<'a>,
+    // We store a set of flags that indicate we need a reload of
+    // schema or acp, which is tested by checking the classes of the
+    // changing content. For acps we keep the uuids that changed, so
+    // that only those profiles need to be reparsed.
+    changed_schema: bool,
+    changed_acp: BTreeSet<String>,
+    // The system_config entry was changed, so the log levels are reapplied.
+    changed_log_levels: bool,
+    // The domain_info entry was changed, so it's read again.
+    changed_domain_info: bool,
+    acp_require_metadata: bool,
+    // Every change made in this transaction is recorded against this csn.
+    csn: Csn,
+    // What this transaction has changed, for the change bus once it commits.
+    // Only collected when there is a bus to send it to.
+    change_bus: Option<ChangeBus>,
+    changes: Vec<ChangeSummary>,
+    anon_search_rate: Arc<RateLimit>,
+    credential_reset_rate: Arc<RateLimit>,
+    anomalies: Arc<AnomalyDetector>,
+    // Entries deleted at a user's request, counted once this commits.
+    user_deletes: usize,
+    filter_limits: FilterLimits,
+    search_max_results: usize,
+    domain_info: CowCellWriteTxn<'a, DomainInfo>,
+    log_levels: Arc<LogLevels>,
+}
 
-        // Is the candidate set empty?
-        if pre_candidates.len() == 0 {
-            audit_log!(au, "delete: no candidates match filter {:?}", de.filter);
-            return Err(OperationError::NoMatchingEntries);
-        };
+impl<'a> QueryServerTransaction for QueryServerWriteTransaction<'a> {
+    type BackendTransactionType = BackendWriteTransaction;
 
-        let modlist_inv = ModifyList::new_list(vec![Modify::Present(
-            String::from("class"),
-            String::from("recycled"),
-        )]);
+    fn get_be_txn(&self) -> &BackendWriteTransaction {
+        &self.be_txn
+    }
 
-        let modlist = match modlist_inv.validate(&self.schema) {
-            Ok(ml) => ml,
-            Err(e) => return Err(OperationError::SchemaViolation(e)),
-        };
+    type SchemaTransactionType = SchemaWriteTransaction<'a>;
 
-        let mut candidates: Vec<Entry<EntryInvalid, EntryCommitted>> = pre_candidates
-            .iter()
-            .map(|er| er.clone().invalidate())
-            .collect();
+    fn get_schema(&self) -> &SchemaWriteTransaction<'a> {
+        &self.schema
+    }
 
-        candidates
-            .iter_mut()
-            .for_each(|er| er.apply_modlist(&modlist));
+    type AccessControlsTransactionType = AccessControlsWriteTransaction<'a>;
 
-        audit_log!(au, "delete: candidates -> {:?}", candidates);
+    fn get_accesscontrols(&self) -> &AccessControlsWriteTransaction<'a> {
+        &self.accesscontrols
+    }
 
-        // Pre delete plugs
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_delete");
-        let plug_pre_res =
-            Plugins::run_pre_delete(&mut audit_plugin_pre, self, &mut candidates, de);
-        au.append_scope(audit_plugin_pre);
+    fn get_anon_search_rate(&self) -> &RateLimit {
+        &self.anon_search_rate
+    }
 
-        if plug_pre_res.is_err() {
-            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_pre_res);
-            return plug_pre_res;
-        }
+    fn get_anomalies(&self) -> &AnomalyDetector {
+        &self.anomalies
+    }
 
-        let res: Result<Vec<Entry<EntryValid, EntryCommitted>>, SchemaError> = candidates
-            .into_iter()
-            .map(|e| e.validate(&self.schema))
-            .collect();
+    fn get_filter_limits(&self) -> &FilterLimits {
+        &self.filter_limits
+    }
 
-        let del_cand: Vec<Entry<_, _>> = match res {
-            Ok(v) => v,
-            Err(e) => return Err(OperationError::SchemaViolation(e)),
-        };
+    fn get_search_max_results(&self) -> usize {
+        self.search_max_results
+    }
 
-        let mut audit_be = AuditScope::new("backend_modify");
+    fn get_domain_info(&self) -> &DomainInfo {
+        &self.domain_info
+    }
+}
 
-        let res = self.be_txn.modify(&mut audit_be, &del_cand);
-        au.append_scope(audit_be);
+#[derive(Clone)]
+pub struct QueryServer {
+    // log: actix::Addr<EventLog>,
+    be: Backend,
+    schema: Arc<Schema>,
+    accesscontrols: Arc<AccessControls>,
+    acp_require_metadata: bool,
+    clock: Arc<dyn Clock>,
+    change_bus: Option<ChangeBus>,
+    // Shared by every transaction, so the rate is over the whole server.
+    anon_search_rate: Arc<RateLimit>,
+    credential_reset_rate: Arc<RateLimit>,
+    anomalies: Arc<AnomalyDetector>,
+    filter_limits: FilterLimits,
+    search_max_results: usize,
+    domain_info: Arc<CowCell<DomainInfo>>,
+    // The name a new domain is given. An existing domain keeps its own.
+    domain_name: String,
+    // This server's log levels, set on each thread that opens a transaction.
+    log_levels: Arc<LogLevels>,
+}
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Delete operation failed (backend), {:?}", res);
-            return res;
+impl QueryServer {
+    pub fn new(be: Backend, schema: Schema) -> Self {
+        // log_event!(log, "Starting query worker ...");
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        QueryServer {
+            be: be,
+            schema: Arc::new(schema),
+            accesscontrols: Arc::new(AccessControls::new()),
+            acp_require_metadata: false,
+            clock: clock.clone(),
+            change_bus: None,
+            anon_search_rate: Arc::new(RateLimit::new(clock.clone())),
+            credential_reset_rate: Arc::new(RateLimit::new(clock.clone())),
+            anomalies: Arc::new(AnomalyDetector::new(clock, AnomalyThresholds::new())),
+            filter_limits: FilterLimits::new(),
+            search_max_results: SEARCH_MAX_RESULTS,
+            domain_info: Arc::new(CowCell::new(DomainInfo::new())),
+            domain_name: String::from("localhost"),
+            log_levels: Arc::new(LogLevels::new()),
         }
+    }
 
-        // Post delete plugs
-        let mut audit_plugin_post = AuditScope::new("plugin_post_delete");
-        let plug_post_res = Plugins::run_post_delete(&mut audit_plugin_post, self, &del_cand, de);
-        au.append_scope(audit_plugin_post);
+    // Take the time from this clock rather than the system.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.anon_search_rate = Arc::new(RateLimit::new(clock.clone()));
+        self.credential_reset_rate = Arc::new(RateLimit::new(clock.clone()));
+        self.clock = clock;
+        let thresholds = self.anomalies.thresholds();
+        self.set_anomaly_thresholds(thresholds);
+    }
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
-        }
+    // When set, every create and modify of an access control profile, internal
+    // or external, must leave it with a description.
+    pub fn set_acp_require_metadata(&mut self, require: bool) {
+        self.acp_require_metadata = require;
+    }
 
-        // We have finished all plugs and now have a successful operation - flag if
-        // schema or acp requires reload.
-        self.changed_schema = del_cand.iter().fold(false, |acc, e| {
-            if acc {
-                acc
-            } else {
-                e.attribute_value_pres("class", "classtype")
-                    || e.attribute_value_pres("class", "attributetype")
-            }
-        });
-        self.changed_acp = del_cand.iter().fold(false, |acc, e| {
-            if acc {
-                acc
-            } else {
-                e.attribute_value_pres("class", "access_control_profile")
-            }
-        });
-        audit_log!(
-            au,
-            "Schema reload: {:?}, ACP reload: {:?}",
-            self.changed_schema,
-            self.changed_acp
-        );
+    // Filters from clients that go over these are refused.
+    pub fn set_filter_limits(&mut self, limits: FilterLimits) {
+        self.filter_limits = limits;
+    }
 
-        // Send result
-        audit_log!(au, "Delete operation success");
-        res
+    // No external search returns more than this, or pages of more than this.
+    pub fn set_search_max_results(&mut self, max: usize) {
+        self.search_max_results = max;
     }
 
-    pub fn purge_tombstones(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // delete everything that is a tombstone.
+    // Only used when the domain is first set up, by initialise_helper.
+    pub fn set_domain_name(&mut self, name: &str) {
+        self.domain_name = name.to_lowercase();
+    }
 
-        // Search for tombstones
-        let ts = match self.internal_search(au, filter_all!(f_eq("class", "tombstone"))) {
-            Ok(r) => r,
-            Err(e) => return Err(e),
-        };
+    // Committed changes, and security alerts, are sent to the subscribers of
+    // this bus.
+    pub fn set_change_bus(&mut self, bus: ChangeBus) {
+        self.anomalies.set_bus(bus.clone());
+        self.change_bus = Some(bus);
+    }
 
-        // TODO #68: Has an appropriate amount of time/condition past (ie replication events?)
+    // This starts the counts again, so is only for setting up the server.
+    pub fn set_anomaly_thresholds(&mut self, thresholds: AnomalyThresholds) {
+        let anomalies = AnomalyDetector::new(self.clock.clone(), thresholds);
+        if let Some(bus) = &self.change_bus {
+            anomalies.set_bus(bus.clone());
+        }
+        self.anomalies = Arc::new(anomalies);
+    }
 
-        // Delete them
-        let mut audit_be = AuditScope::new("backend_delete");
+    // Count a failed authentication against where it came from.
+    pub fn record_auth_failure(&self, source: &str) -> Option<Alert> {
+        self.anomalies
+            .record(SecurityEventKind::AuthFailure, source, 1)
+    }
 
-        let res = self
-            .be_txn
-            // Change this to an update, not delete.
-            .delete(&mut audit_be, &ts);
-        au.append_scope(audit_be);
+    // Sync what has been committed so far, when commits aren't each synced.
+    pub fn checkpoint(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.be.checkpoint(audit, false)
+    }
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Tombstone purge operation failed (backend), {:?}", res);
-            return res;
+    pub fn read(&self) -> QueryServerReadTransaction {
+        audit::use_log_levels(&self.log_levels);
+        QueryServerReadTransaction {
+            be_txn: self.be.read(),
+            schema: self.schema.read(),
+            accesscontrols: self.accesscontrols.read(),
+            anon_search_rate: self.anon_search_rate.clone(),
+            anomalies: self.anomalies.clone(),
+            filter_limits: self.filter_limits,
+            search_max_results: self.search_max_results,
+            domain_info: self.domain_info.read(),
+            now: self.clock.now(),
         }
-
-        // Send result
-        audit_log!(au, "Tombstone purge operation success");
-        res
     }
 
-    pub fn purge_recycled(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // Send everything that is recycled to tombstone
-        // Search all recycled
-        let rc = match self.internal_search(au, filter_all!(f_eq("class", "recycled"))) {
-            Ok(r) => r,
-            Err(e) => return Err(e),
-        };
-
-        // Modify them to strip all avas except uuid
-        let tombstone_cand = rc.iter().map(|e| e.to_tombstone()).collect();
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
 
-        // Backend Modify
-        let mut audit_be = AuditScope::new("backend_modify");
+    pub fn write(&self) -> QueryServerWriteTransaction {
+        audit::use_log_levels(&self.log_levels);
+        QueryServerWriteTransaction {
+            // I think this is *not* needed, because commit is mut self which should
+            // take ownership of the value, and cause the commit to "only be run
+            // once".
+            //
+            // The commited flag is however used for abort-specific code in drop
+            // which today I don't think we have ... yet.
+            committed: false,
+            be_txn: self.be.write(),
+            schema: self.schema.write(),
+            accesscontrols: self.accesscontrols.write(),
+            changed_schema: false,
+            changed_acp: BTreeSet::new(),
+            changed_log_levels: false,
+            changed_domain_info: false,
+            acp_require_metadata: self.acp_require_metadata,
+            csn: Csn::new(self.clock.now(), self.be.replica_id()),
+            change_bus: self.change_bus.clone(),
+            changes: Vec::new(),
+            anon_search_rate: self.anon_search_rate.clone(),
+            credential_reset_rate: self.credential_reset_rate.clone(),
+            anomalies: self.anomalies.clone(),
+            user_deletes: 0,
+            filter_limits: self.filter_limits,
+            search_max_results: self.search_max_results,
+            domain_info: self.domain_info.write(),
+            log_levels: self.log_levels.clone(),
+        }
+    }
 
-        let res = self.be_txn.modify(&mut audit_be, &tombstone_cand);
-        au.append_scope(audit_be);
+    pub(crate) fn initialise_helper(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // Committing the initialisation moves the recovery point on, so
+        // what needs checking has to be found first.
+        let pending = self.recovery_pending(audit)?;
+
+        // This is all one transaction. The idm entries need the idm schema,
+        // so rather than committing between them we reload the schema once
+        // it's in place. Data migrations run against that schema, before the
+        // builtin entries are brought up to date.
+        let mut ts_write = self.write();
+        ts_write
+            .initialise_schema_core(audit)
+            .and_then(|_| ts_write.initialise_schema_idm(audit))
+            .and_then(|_| ts_write.reload_schema(audit))
+            .and_then(|_| migrations::run(audit, &mut ts_write))
+            .and_then(|_| ts_write.initialise_idm(audit))
+            .and_then(|_| ts_write.initialise_domain_info(audit, self.domain_name.as_str()))
+            .and_then(|_| ts_write.reload_idxmeta(audit))
+            .and_then(|_| ts_write.commit(audit))?;
+
+        let levels = self.read().log_levels(audit);
+        self.log_levels.set(&levels);
+
+        self.recover(audit, &pending)
+    }
 
-        if res.is_err() {
-            // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Purge recycled operation failed (backend), {:?}", res);
-            return res;
+    // The entries changed since the recovery point. If the server stopped
+    // between committing them and finishing what derives from them, this
+    // is where that would have been left undone.
+    fn recovery_pending(&self, audit: &mut AuditScope) -> Result<BTreeSet<String>, OperationError> {
+        let r_txn = self.read();
+        let be_txn = r_txn.get_be_txn();
+        match be_txn.recovery_seq(audit)? {
+            Some(seq) => be_txn.changelog_since(audit, seq).map(|(uuids, _)| uuids),
+            // A new database, or one from before recovery was recorded.
+            None => Ok(BTreeSet::new()),
         }
-
-        // return
-        audit_log!(au, "Purge recycled operation success");
-        res
     }
 
-    // Should this take a revive event?
-    pub fn revive_recycled(
-        &mut self,
-        au: &mut AuditScope,
-        re: &ReviveRecycledEvent,
+    // Bring what derives from these entries back in line with them: their
+    // index keys, memberof, and the schema and access controls, which are
+    // rebuilt in full.
+    pub(crate) fn recover(
+        &self,
+        audit: &mut AuditScope,
+        uuids: &BTreeSet<String>,
     ) -> Result<(), OperationError> {
-        // Revive an entry to live. This is a specialised (limited)
-        // modify proxy.
-        //
-        // impersonate modify will require ability to search the class=recycled
-        // and the ability to remove that from the object.
+        if uuids.is_empty() {
+            audit_log!(audit, "recovery: nothing to check");
+            return Ok(());
+        }
+        audit_log!(audit, "recovery: checking {} changed entries", uuids.len());
+        let mut ts_write = self.write();
+        let repaired = ts_write.get_be_txn().reindex_uuids(audit, uuids)?;
+        audit_log!(audit, "recovery: repaired {} index keys", repaired);
+        Plugins::run_recover(audit, &mut ts_write, uuids)?;
+        ts_write.changed_schema = true;
+        ts_write.commit(audit)
+    }
 
-        // create the modify
-        // tl;dr, remove the class=recycled
-        let modlist = ModifyList::new_list(vec![Modify::Removed(
-            "class".to_string(),
-            "recycled".to_string(),
-        )]);
+    pub fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        let r_txn = self.read();
+        r_txn.verify(au)
+    }
 
-        let m_valid = try_audit!(
-            au,
-            modlist
-                .validate(self.get_schema())
-                .map_err(|e| OperationError::SchemaViolation(e))
-        );
+    pub fn verify_report(&self, au: &mut AuditScope) -> VerifyReport {
+        let r_txn = self.read();
+        r_txn.verify_report(au)
+    }
+}
 
-        // Now impersonate the modify
-        self.impersonate_modify_valid(au, re.filter.clone(), re.filter.clone(), m_valid, &re.event)
+impl<'a> QueryServerWriteTransaction<'a> {
+    // When the transaction began, which every change in it is recorded at.
+    pub fn now(&self) -> Duration {
+        self.csn.ts()
     }
 
-    pub fn modify(&mut self, au: &mut AuditScope, me: &ModifyEvent) -> Result<(), OperationError> {
-        // Get the candidates.
-        // Modify applies a modlist to a filter, so we need to internal search
-        // then apply.
+    pub fn create(&mut self, au: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
+        // The create event is a raw, read only representation of the request
+        // that was made to us, including information about the identity
+        // performing the request.
 
-        // Validate input.
+        // Log the request
 
-        // Is the modlist non zero?
-        if me.modlist.len() == 0 {
-            audit_log!(au, "modify: empty modify request");
-            return Err(OperationError::EmptyRequest);
+        // TODO #67: Do we need limits on number of creates, or do we constraint
+        // based on request size in the frontend?
+
+        if ce
+            .entries
+            .iter()
+            .any(|e| e.attribute_value_pres("class", "access_control_profile"))
+        {
+            au.set_security_relevant();
         }
 
-        // Is the modlist valid?
-        // This is now done in the event transform
+        // Copy the entries to a writeable form.
+        let candidates: Vec<Entry<EntryInvalid, EntryNew>> =
+            ce.entries.iter().map(|er| er.clone()).collect();
 
-        // Is the filter invalid to schema?
-        // This is now done in the event transform
+        // Normalise but DO NOT validate the entries.
+        let norm_cand: Result<Vec<Entry<EntryNormalised, EntryNew>>, _> = candidates
+            .into_iter()
+            .map(|e| {
+                e.normalise(&self.schema)
+                    .map_err(|er| OperationError::SchemaViolation(er))
+            })
+            .collect();
 
-        // This also checks access controls due to use of the impersonation.
+        let norm_cand = try_audit!(au, norm_cand);
+
+        // Handle the error.
+
+        // Do we have rights to perform these creates?
+        // create_allow_operation
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = metrics::timed(Stage::AccessCheck, || {
+            access.create_allow_operation(&mut audit_acp, ce, &norm_cand)
+        });
+        au.append_scope(audit_acp);
+        if try_audit!(au, acp_res) != true {
+            self.record_access_denied(au, &ce.event);
+            return Err(OperationError::AccessDenied);
+        }
+
+        // Invalidate them all again ...
+        let mut candidates: Vec<Entry<EntryInvalid, EntryNew>> =
+            norm_cand.into_iter().map(|e| e.invalidate()).collect();
+
+        // run any pre plugins, giving them the list of mutable candidates.
+        // pre-plugins are defined here in their correct order of calling!
+        // I have no intent to make these dynamic or configurable.
+
+        let mut audit_plugin_pre_transform = AuditScope::new("plugin_pre_create_transform");
+        let plug_pre_transform_res = Plugins::run_pre_create_transform(
+            &mut audit_plugin_pre_transform,
+            self,
+            &mut candidates,
+            ce,
+        );
+        au.append_scope(audit_plugin_pre_transform);
+
+        let _ = try_audit!(
+            au,
+            plug_pre_transform_res,
+            "Create operation failed (plugin), {:?}"
+        );
+
+        candidates.iter_mut().for_each(|e| e.set_csn_all(&self.csn));
+
+        // NOTE: This is how you map from Vec<Result<T>> to Result<Vec<T>>
+        // remember, that you only get the first error and the iter terminates.
+
+        // Now, normalise AND validate!
+
+        let res: Result<Vec<Entry<EntryValid, EntryNew>>, OperationError> = candidates
+            .into_iter()
+            .map(|e| {
+                e.validate(&self.schema)
+                    .map_err(|er| OperationError::EntrySchemaViolation(er))
+            })
+            .collect();
+
+        let norm_cand: Vec<Entry<_, _>> = try_audit!(au, res);
+
+        // Run any pre-create plugins now with schema validated entries.
+        // This is important for normalisation of certain types IE class
+        // or attributes for these checks.
+        let mut audit_plugin_pre = AuditScope::new("plugin_pre_create");
+        let plug_pre_res = Plugins::run_pre_create(&mut audit_plugin_pre, self, &norm_cand, ce);
+        au.append_scope(audit_plugin_pre);
+
+        let _ = try_audit!(au, plug_pre_res, "Create operation failed (plugin), {:?}");
+
+        let mut audit_be = AuditScope::new("backend_create");
+        // We may change from ce.entries later to something else?
+        let res = self
+            .be_txn
+            .create(&mut audit_be, &self.change_origin(&ce.event), &norm_cand)
+            .map(|_| ())
+            .map_err(|e| e);
+
+        au.append_scope(audit_be);
+
+        if res.is_err() {
+            // be_txn is dropped, ie aborted here.
+            audit_log!(au, "Create operation failed (backend), {:?}", res);
+            return res;
+        }
+        // Run any post plugins
+
+        let mut audit_plugin_post = AuditScope::new("plugin_post_create");
+        let plug_post_res = Plugins::run_post_create(&mut audit_plugin_post, self, &norm_cand, ce);
+        au.append_scope(audit_plugin_post);
+
+        if plug_post_res.is_err() {
+            audit_log!(au, "Create operation failed (plugin), {:?}", plug_post_res);
+            return plug_post_res;
+        }
+
+        // We have finished all plugs and now have a successful operation - flag if
+        // schema or acp requires reload.
+        let changed_schema = norm_cand.iter().fold(false, |acc, e| {
+            if acc {
+                acc
+            } else {
+                e.attribute_value_pres("class", "classtype")
+                    || e.attribute_value_pres("class", "attributetype")
+            }
+        });
+        try_audit!(au, self.reload_changed_schema(au, changed_schema));
+        self.changed_acp.extend(
+            norm_cand
+                .iter()
+                .filter(|e| e.attribute_value_pres("class", "access_control_profile"))
+                .map(|e| e.get_uuid().clone()),
+        );
+        if norm_cand.iter().any(|e| e.get_uuid() == UUID_DOMAIN_INFO) {
+            self.changed_domain_info = true;
+        }
+        self.record_changes(&norm_cand, ChangeOp::Create);
+        audit_log!(
+            au,
+            "Schema reload: {:?}, ACP reload: {:?}",
+            self.changed_schema,
+            self.changed_acp
+        );
+
+        // We are complete, finalise logging and return
+
+        audit_log!(au, "Create operation success");
+        res
+    }
+
+    pub fn delete(&mut self, au: &mut AuditScope, de: &DeleteEvent) -> Result<(), OperationError> {
+        // Do you have access to view all the set members? Reduce based on your
+        // read permissions and attrs
+        // THIS IS PRETTY COMPLEX SEE THE DESIGN DOC
+        // In this case we need a search, but not INTERNAL to keep the same
+        // associated credentials.
+        // We only need to retrieve uuid though ...
+        au.set_security_relevant();
+
+        // Now, delete only what you can see
         let pre_candidates = match self.impersonate_search_valid(
             au,
-            me.filter.clone(),
-            me.filter_orig.clone(),
-            &me.event,
+            de.filter.clone(),
+            de.filter_orig.clone(),
+            &de.event,
         ) {
             Ok(results) => results,
             Err(e) => {
-                audit_log!(au, "modify: error in pre-candidate selection {:?}", e);
+                audit_log!(au, "delete: error in pre-candidate selection {:?}", e);
                 return Err(e);
             }
         };
 
-        if pre_candidates.len() == 0 {
-            match me.event.origin {
-                EventOrigin::Internal => {
-                    audit_log!(
-                        au,
-                        "modify: no candidates match filter ... continuing {:?}",
-                        me.filter
-                    );
-                    return Ok(());
-                }
-                _ => {
-                    audit_log!(
-                        au,
-                        "modify: no candidates match filter, failure {:?}",
-                        me.filter
-                    );
-                    return Err(OperationError::NoMatchingEntries);
-                }
-            }
-        };
-
-        // Are we allowed to make the changes we want to?
-        // modify_allow_operation
+        // Apply access controls to reduce the set if required.
+        // delete_allow_operation
         let mut audit_acp = AuditScope::new("access_control_profiles");
         let access = self.get_accesscontrols();
-        let acp_res = access.modify_allow_operation(&mut audit_acp, me, &pre_candidates);
+        let acp_res = metrics::timed(Stage::AccessCheck, || {
+            access.delete_allow_operation(&mut audit_acp, de, &pre_candidates)
+        });
         au.append_scope(audit_acp);
         if try_audit!(au, acp_res) != true {
+            self.record_access_denied(au, &de.event);
             return Err(OperationError::AccessDenied);
         }
 
-        // Clone a set of writeables.
-        // Apply the modlist -> Remember, we have a set of origs
-        // and the new modified ents.
+        // Is the candidate set empty?
+        if pre_candidates.len() == 0 {
+            audit_log!(au, "delete: no candidates match filter {:?}", de.filter);
+            return Err(OperationError::NoMatchingEntries);
+        };
+
+        let modlist_inv = ModifyList::new_list(vec![Modify::Present(
+            String::from("class"),
+            Value::from("recycled"),
+        )]);
+
+        let modlist = match modlist_inv.validate(&self.schema) {
+            Ok(ml) => ml,
+            Err(e) => return Err(OperationError::SchemaViolation(e)),
+        };
+
         let mut candidates: Vec<Entry<EntryInvalid, EntryCommitted>> = pre_candidates
             .iter()
             .map(|er| er.clone().invalidate())
@@ -1007,96 +2269,78 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         candidates
             .iter_mut()
-            .for_each(|er| er.apply_modlist(&me.modlist));
-
-        // let mut candidates = try_audit!(au, candidates);
+            .for_each(|er| er.apply_modlist(&modlist, &self.csn));
 
-        audit_log!(au, "modify: candidates -> {:?}", candidates);
+        audit_log!(au, "delete: candidates -> {:?}", candidates);
 
-        // Pre mod plugins
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_modify");
+        // Pre delete plugs
+        let mut audit_plugin_pre = AuditScope::new("plugin_pre_delete");
         let plug_pre_res =
-            Plugins::run_pre_modify(&mut audit_plugin_pre, self, &mut candidates, me);
+            Plugins::run_pre_delete(&mut audit_plugin_pre, self, &mut candidates, de);
         au.append_scope(audit_plugin_pre);
 
         if plug_pre_res.is_err() {
-            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_pre_res);
+            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_pre_res);
             return plug_pre_res;
         }
 
-        // NOTE: There is a potential optimisation here, where if
-        // candidates == pre-candidates, then we don't need to store anything
-        // because we effectively just did an assert. However, like all
-        // optimisations, this could be premature - so we for now, just
-        // do the CORRECT thing and recommit as we may find later we always
-        // want to add CSN's or other.
-        //
-        // memberOf actually wants the pre cand list and the norm_cand list to see what
-        // changed. Could be optimised, but this is correct still ...
-
-        let res: Result<Vec<Entry<EntryValid, EntryCommitted>>, SchemaError> = candidates
+        let res: Result<Vec<Entry<EntryValid, EntryCommitted>>, Vec<SchemaError>> = candidates
             .into_iter()
             .map(|e| e.validate(&self.schema))
             .collect();
 
-        let norm_cand: Vec<Entry<_, _>> = match res {
+        let del_cand: Vec<Entry<_, _>> = match res {
             Ok(v) => v,
-            Err(e) => return Err(OperationError::SchemaViolation(e)),
+            Err(e) => return Err(OperationError::EntrySchemaViolation(e)),
         };
 
-        // Backend Modify
         let mut audit_be = AuditScope::new("backend_modify");
 
-        let res = self.be_txn.modify(&mut audit_be, &norm_cand);
+        let res = self
+            .be_txn
+            .modify(&mut audit_be, &self.change_origin(&de.event), &del_cand);
         au.append_scope(audit_be);
 
         if res.is_err() {
             // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Modify operation failed (backend), {:?}", res);
+            audit_log!(au, "Delete operation failed (backend), {:?}", res);
             return res;
         }
 
-        // Post Plugins
-        let mut audit_plugin_post = AuditScope::new("plugin_post_modify");
-        let plug_post_res = Plugins::run_post_modify(
-            &mut audit_plugin_post,
-            self,
-            &pre_candidates,
-            &norm_cand,
-            me,
-        );
+        // Post delete plugs
+        let mut audit_plugin_post = AuditScope::new("plugin_post_delete");
+        let plug_post_res = Plugins::run_post_delete(&mut audit_plugin_post, self, &del_cand, de);
         au.append_scope(audit_plugin_post);
 
         if plug_post_res.is_err() {
-            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_post_res);
+            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_post_res);
             return plug_post_res;
         }
 
         // We have finished all plugs and now have a successful operation - flag if
-        // schema or acp requires reload. Remember, this is a modify, so we need to check
-        // pre and post cands.
-        self.changed_schema =
-            norm_cand
-                .iter()
-                .chain(pre_candidates.iter())
-                .fold(false, |acc, e| {
-                    if acc {
-                        acc
-                    } else {
-                        e.attribute_value_pres("class", "classtype")
-                            || e.attribute_value_pres("class", "attributetype")
-                    }
-                });
-        self.changed_acp = norm_cand
-            .iter()
-            .chain(pre_candidates.iter())
-            .fold(false, |acc, e| {
-                if acc {
-                    acc
-                } else {
-                    e.attribute_value_pres("class", "access_control_profile")
-                }
-            });
+        // schema or acp requires reload.
+        let changed_schema = del_cand.iter().fold(false, |acc, e| {
+            if acc {
+                acc
+            } else {
+                e.attribute_value_pres("class", "classtype")
+                    || e.attribute_value_pres("class", "attributetype")
+            }
+        });
+        try_audit!(au, self.reload_changed_schema(au, changed_schema));
+        self.changed_acp.extend(
+            del_cand
+                .iter()
+                .filter(|e| e.attribute_value_pres("class", "access_control_profile"))
+                .map(|e| e.get_uuid().clone()),
+        );
+        self.record_changes(&del_cand, ChangeOp::Delete);
+        // Only deletes users asked for - the server's own cleanup of the
+        // recycle bin isn't a mass delete. They're counted on commit, as a
+        // delete that is rolled back didn't happen.
+        if let EventOrigin::User(_) = &de.event.origin {
+            self.user_deletes += del_cand.len();
+        }
         audit_log!(
             au,
             "Schema reload: {:?}, ACP reload: {:?}",
@@ -1104,789 +2348,4204 @@ impl<'a> QueryServerWriteTransaction<'a> {
             self.changed_acp
         );
 
-        // return
-        audit_log!(au, "Modify operation success");
+        // Send result
+        audit_log!(au, "Delete operation success");
         res
     }
 
-    // These are where searches and other actions are actually implemented. This
-    // is the "internal" version, where we define the event as being internal
-    // only, allowing certain plugin by passes etc.
+    fn record_changes<STATE>(&mut self, cand: &[Entry<EntryValid, STATE>], op: ChangeOp) {
+        if self.change_bus.is_some() {
+            self.changes
+                .extend(cand.iter().map(|e| ChangeSummary::new(e, op)));
+        }
+    }
 
-    pub fn internal_create(
-        &mut self,
-        audit: &mut AuditScope,
-        entries: Vec<Entry<EntryInvalid, EntryNew>>,
+    // Has this entry's class been unchanged for at least window? For a
+    // recycled entry or tombstone, that is how long it has been one. Entries
+    // stored before csns were recorded have no age, and are treated as old.
+    fn purge_window_passed(&self, e: &Entry<EntryValid, EntryCommitted>, window: Duration) -> bool {
+        match e.get_csn("class") {
+            Some(c) => c.ts() + window <= self.csn.ts(),
+            None => true,
+        }
+    }
+
+    // Who made a change, and when, for the changelog.
+    fn change_origin(&self, event: &Event) -> ChangeOrigin {
+        ChangeOrigin {
+            csn: self.csn,
+            initiator: match &event.origin {
+                EventOrigin::User(e) => Some(e.get_uuid().clone()),
+                EventOrigin::Internal => None,
+            },
+        }
+    }
+
+    pub fn purge_tombstones(
+        &self,
+        au: &mut AuditScope,
+        window: Duration,
     ) -> Result<(), OperationError> {
-        // Start the audit scope
-        let mut audit_int = AuditScope::new("internal_create");
-        // Create the CreateEvent
-        let ce = CreateEvent::new_internal(entries);
-        let res = self.create(&mut audit_int, &ce);
-        audit.append_scope(audit_int);
+        // delete everything that has been a tombstone for longer than window.
+
+        // Search for tombstones
+        let ts: Vec<_> = match self.internal_search(au, filter_all!(f_eq("class", "tombstone"))) {
+            Ok(r) => r
+                .into_iter()
+                .filter(|e| self.purge_window_passed(e, window))
+                .collect(),
+            Err(e) => return Err(e),
+        };
+
+        // TODO #68: Has an appropriate amount of time/condition past (ie replication events?)
+        if ts.len() == 0 {
+            audit_log!(au, "No tombstones to purge");
+            return Ok(());
+        }
+
+        // Delete them
+        let mut audit_be = AuditScope::new("backend_delete");
+
+        let res = self
+            .be_txn
+            // Change this to an update, not delete.
+            .delete(&mut audit_be, &ChangeOrigin::internal(self.csn), &ts);
+        au.append_scope(audit_be);
+
+        if res.is_err() {
+            // be_txn is dropped, ie aborted here.
+            audit_log!(au, "Tombstone purge operation failed (backend), {:?}", res);
+            return res;
+        }
+
+        // A consumer of the changelog further behind than this has missed
+        // these tombstones too, and must sync in full anyway.
+        let mut audit_be = AuditScope::new("backend_changelog_trim");
+        let res = match self.csn.ts().checked_sub(window) {
+            Some(before) => self
+                .be_txn
+                .changelog_trim(&mut audit_be, &Csn::new(before, Uuid::nil()))
+                .map(|_| ()),
+            None => Ok(()),
+        };
+        au.append_scope(audit_be);
+
+        if res.is_err() {
+            audit_log!(au, "Changelog trim failed (backend), {:?}", res);
+            return res;
+        }
+
+        // Send result
+        audit_log!(au, "Tombstone purge operation success");
         res
     }
 
-    pub fn internal_delete(
-        &mut self,
-        audit: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
+    pub fn purge_recycled(
+        &self,
+        au: &mut AuditScope,
+        window: Duration,
     ) -> Result<(), OperationError> {
-        let f_valid = filter
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let mut audit_int = AuditScope::new("internal_delete");
-        let de = DeleteEvent::new_internal(f_valid);
-        let res = self.delete(&mut audit_int, &de);
-        audit.append_scope(audit_int);
+        // Send everything that has been recycled for longer than window to tombstone
+        // Search all recycled
+        let rc: Vec<_> = match self.internal_search(au, filter_all!(f_eq("class", "recycled"))) {
+            Ok(r) => r
+                .into_iter()
+                .filter(|e| self.purge_window_passed(e, window))
+                .collect(),
+            Err(e) => return Err(e),
+        };
+
+        if rc.len() == 0 {
+            audit_log!(au, "No recycled entries to purge");
+            return Ok(());
+        }
+
+        // Modify them to strip all avas except uuid
+        let tombstone_cand = rc.iter().map(|e| e.to_tombstone(&self.csn)).collect();
+
+        // Backend Modify
+        let mut audit_be = AuditScope::new("backend_modify");
+
+        let res = self.be_txn.modify(
+            &mut audit_be,
+            &ChangeOrigin::internal(self.csn),
+            &tombstone_cand,
+        );
+        au.append_scope(audit_be);
+
+        if res.is_err() {
+            // be_txn is dropped, ie aborted here.
+            audit_log!(au, "Purge recycled operation failed (backend), {:?}", res);
+            return res;
+        }
+
+        // return
+        audit_log!(au, "Purge recycled operation success");
         res
     }
 
-    pub fn internal_modify(
+    // Mark the accounts that are past their account_expire, and unmark any
+    // whose expiry has since been moved or removed. Only the dates decide if
+    // an account can be used - this is so expired accounts can be found.
+    pub fn flag_expired_accounts(&mut self, au: &mut AuditScope) -> Result<usize, OperationError> {
+        let now = DateTime::<Utc>::from(UNIX_EPOCH + self.csn.ts()).to_rfc3339();
+        let expired = filter!(f_and!([
+            f_eq("class", "account"),
+            f_le("account_expire", now.as_str()),
+            f_andnot(f_eq("account_expired", "true"))
+        ]));
+        let unexpired = filter!(f_and!([
+            f_eq("account_expired", "true"),
+            f_andnot(f_le("account_expire", now.as_str()))
+        ]));
+
+        let mut changed = 0;
+        for (filt, modlist) in vec![
+            (
+                expired,
+                ModifyList::new_list(vec![Modify::Present(
+                    "account_expired".to_string(),
+                    Value::from("true"),
+                )]),
+            ),
+            (
+                unexpired,
+                ModifyList::new_list(vec![Modify::Purged("account_expired".to_string())]),
+            ),
+        ] {
+            changed += self.internal_modify_filter(au, filt, modlist)?;
+        }
+        audit_log!(au, "Account expiry flags changed on {} accounts", changed);
+        Ok(changed)
+    }
+
+    // Should this take a revive event?
+    pub fn revive_recycled(
         &mut self,
-        audit: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
-        modlist: ModifyList<ModifyInvalid>,
+        au: &mut AuditScope,
+        re: &ReviveRecycledEvent,
     ) -> Result<(), OperationError> {
-        let f_valid = filter
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let m_valid = modlist
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let mut audit_int = AuditScope::new("internal_modify");
-        let me = ModifyEvent::new_internal(f_valid, m_valid);
-        let res = self.modify(&mut audit_int, &me);
-        audit.append_scope(audit_int);
-        res
+        // Revive an entry to live. This is a specialised (limited)
+        // modify proxy.
+        //
+        // impersonate modify will require ability to search the class=recycled
+        // and the ability to remove that from the object.
+
+        // create the modify
+        // tl;dr, remove the class=recycled
+        let modlist = ModifyList::new_list(vec![Modify::Removed(
+            "class".to_string(),
+            Value::from("recycled"),
+        )]);
+
+        let m_valid = try_audit!(
+            au,
+            modlist
+                .validate(self.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+
+        // Now impersonate the modify
+        self.impersonate_modify_valid(au, re.filter.clone(), re.filter.clone(), m_valid, &re.event)
     }
 
-    pub fn impersonate_modify_valid(
+    // File a request for the caller to join a group. Asking again while a
+    // request is pending, or asking to join a group you're already in, is
+    // not an error.
+    pub fn group_join_create(
         &mut self,
-        audit: &mut AuditScope,
-        f_valid: Filter<FilterValid>,
-        f_intent_valid: Filter<FilterValid>,
-        m_valid: ModifyList<ModifyValid>,
-        event: &Event,
+        au: &mut AuditScope,
+        gce: &GroupJoinCreateEvent,
     ) -> Result<(), OperationError> {
-        let mut audit_int = AuditScope::new("impersonate_modify");
-        let me = ModifyEvent::new_impersonate(event, f_valid, f_intent_valid, m_valid);
-        let res = self.modify(&mut audit_int, &me);
-        audit.append_scope(audit_int);
-        res
+        audit_log!(au, "Begin group join create event {:?}", gce);
+        let requester = match &gce.event.origin {
+            EventOrigin::User(e) => e.get_uuid().clone(),
+            EventOrigin::Internal => {
+                audit_log!(au, "Group join requests must be made by a user");
+                return Err(OperationError::InvalidRequestState);
+            }
+        };
+
+        let group_uuid = try_audit!(
+            au,
+            self.clone_value(au, &"join_group".to_string(), &gce.group)
+        );
+        let groups = try_audit!(
+            au,
+            self.internal_search(
+                au,
+                filter!(f_and!([
+                    f_eq("class", "group"),
+                    f_eq("uuid", group_uuid.as_str())
+                ]))
+            )
+        );
+        let group = match groups.first() {
+            Some(g) => g,
+            None => {
+                audit_log!(au, "No group {:?} to join", gce.group);
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+
+        if group.attribute_value_pres("member", requester.as_str()) {
+            audit_log!(au, "{} is already a member of {}", requester, group_uuid);
+            return Ok(());
+        }
+
+        let pending = try_audit!(
+            au,
+            self.internal_exists(
+                au,
+                filter!(f_and!([
+                    f_eq("class", "group_join_request"),
+                    f_eq("join_group", group_uuid.as_str()),
+                    f_eq("join_requester", requester.as_str())
+                ]))
+            )
+        );
+        if pending {
+            audit_log!(
+                au,
+                "{} already has a request to join {}",
+                requester,
+                group_uuid
+            );
+            return Ok(());
+        }
+
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e.add_ava("class", "object");
+        e.add_ava("class", "group_join_request");
+        e.add_ava("join_group", group_uuid.as_str());
+        e.add_ava("join_requester", requester.as_str());
+        if let Some(d) = &gce.description {
+            e.add_ava("description", d.as_str());
+        }
+        self.internal_create(au, vec![e])
     }
 
-    pub fn impersonate_modify(
+    // Approve or deny a join request. Only a manager of the group may decide,
+    // and either way the request is removed. Approval adds the requester to
+    // the group internally, as the manager needn't otherwise be able to
+    // modify the group's members.
+    pub fn group_join_decide(
         &mut self,
-        audit: &mut AuditScope,
-        filter: Filter<FilterInvalid>,
-        filter_intent: Filter<FilterInvalid>,
-        modlist: ModifyList<ModifyInvalid>,
-        event: &Event,
+        au: &mut AuditScope,
+        gde: &GroupJoinDecideEvent,
     ) -> Result<(), OperationError> {
-        let f_valid = filter
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let f_intent_valid = filter_intent
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        let m_valid = modlist
-            .validate(self.get_schema())
-            .map_err(|e| OperationError::SchemaViolation(e))?;
-        self.impersonate_modify_valid(audit, f_valid, f_intent_valid, m_valid, event)
+        audit_log!(au, "Begin group join decide event {:?}", gde);
+        let filt_request = filter!(f_and!([
+            f_eq("class", "group_join_request"),
+            f_eq("uuid", gde.request_uuid.as_str())
+        ]));
+        let requests = try_audit!(au, self.internal_search(au, filt_request.clone()));
+        let request = match requests.first() {
+            Some(r) => r,
+            None => {
+                audit_log!(au, "No group join request {}", gde.request_uuid);
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+
+        let group = request.get_ava_single("join_group").map(|v| v.to_string());
+        let requester = request
+            .get_ava_single("join_requester")
+            .map(|v| v.to_string());
+
+        let managed = self.group_join_managed(au, &gde.event)?;
+        if let Some(groups) = managed {
+            let allowed = match &group {
+                Some(g) => groups.contains(g),
+                None => false,
+            };
+            if !allowed {
+                audit_log!(au, "Not a manager of the group for {}", gde.request_uuid);
+                return Err(OperationError::AccessDenied);
+            }
+        }
+
+        if gde.approve {
+            let (group, requester) = match (group, requester) {
+                (Some(g), Some(r)) => (g, r),
+                _ => {
+                    audit_log!(
+                        au,
+                        "Group join request {} lost its group or requester",
+                        gde.request_uuid
+                    );
+                    return Err(OperationError::InvalidEntryState);
+                }
+            };
+            // As the manager, so the membership change is held to the acps
+            // like any other.
+            let modlist = ModifyList::new_list(vec![Modify::Present(
+                "member".to_string(),
+                Value::from(requester),
+            )]);
+            try_audit!(
+                au,
+                self.impersonate_modify(
+                    au,
+                    filter!(f_eq("uuid", group.as_str())),
+                    filter_all!(f_eq("uuid", group.as_str())),
+                    modlist,
+                    &gde.event,
+                )
+            );
+        }
+
+        self.internal_delete(au, filt_request)
     }
 
-    // internal server operation types.
-    // These just wrap the fn create/search etc, but they allow
-    // creating the needed create event with the correct internal flags
-    // and markers. They act as though they have the highest level privilege
-    // IE there are no access control checks.
+    // Change the name of an entry. Everything that refers to an entry does so
+    // by uuid, so memberships follow a rename untouched - but a principal_name
+    // is derived from the name, and is changed with it. This is a modify as
+    // the caller, so needs the same rights as changing the attributes directly.
+    pub fn rename(&mut self, au: &mut AuditScope, re: &RenameEvent) -> Result<(), OperationError> {
+        audit_log!(au, "Begin rename event {:?}", re);
+        let name = {
+            let schema_name = match self.schema.get_attributes().get("name") {
+                Some(a) => a,
+                None => return Err(OperationError::InvalidSchemaState("Missing name attribute")),
+            };
+            let name = schema_name.normalise_value(&re.name);
+            try_audit!(
+                au,
+                schema_name
+                    .validate_value(&name)
+                    .map_err(|e| OperationError::SchemaViolation(e))
+            );
+            name
+        };
+
+        let target = try_audit!(au, self.internal_search_uuid(au, re.target_uuid.as_str()));
+        let old = match target.get_ava_single("name") {
+            Some(v) => v.to_string(),
+            None => {
+                audit_log!(au, "rename target {} has no name", re.target_uuid);
+                return Err(OperationError::InvalidEntryState);
+            }
+        };
+        if old == name {
+            return Ok(());
+        }
+        let mut mods = vec![
+            Modify::Purged("name".to_string()),
+            Modify::Present("name".to_string(), Value::from(name.as_str())),
+        ];
+        // A principal name of the form name@domain follows the name. Any other
+        // form was set by hand, and is left alone.
+        if let Some(spn) = target.get_ava_single("principal_name") {
+            let spn = spn.to_string();
+            let prefix = format!("{}@", old);
+            if spn.starts_with(prefix.as_str()) {
+                let spn_new = format!("{}@{}", name, &spn[prefix.len()..]);
+                mods.push(Modify::Purged("principal_name".to_string()));
+                mods.push(Modify::Present(
+                    "principal_name".to_string(),
+                    Value::from(spn_new.as_str()),
+                ));
+            }
+        }
+        let modlist = try_audit!(
+            au,
+            ModifyList::new_list(mods)
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(
+            au,
+            filter!(f_eq("uuid", re.target_uuid.as_str()))
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+
+        let me = ModifyEvent::new_impersonate(&re.event, filt.clone(), filt, modlist);
+
+        // Whether the name is free is only answered to someone who may change
+        // it, and never with the holder, which they may not be able to see.
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let acp_res =
+            self.get_accesscontrols()
+                .modify_allow_operation(&mut audit_acp, &me, &vec![target]);
+        au.append_scope(audit_acp);
+        if try_audit!(au, acp_res) != true {
+            self.record_access_denied(au, &re.event);
+            return Err(OperationError::AccessDenied);
+        }
+
+        if let Some(r) = RESERVED_NAMES
+            .iter()
+            .find(|r| **r == old.as_str() || **r == name.as_str())
+        {
+            audit_log!(au, "rename of {} to {} touches a reserved name", old, name);
+            return Err(OperationError::ReservedName(r.to_string()));
+        }
+
+        let holders = try_audit!(
+            au,
+            self.internal_search(au, filter_all!(f_eq("name", name.as_str())))
+        );
+        if let Some(h) = holders.first() {
+            audit_log!(au, "name {} is held by {}", name, h.get_uuid());
+            return Err(OperationError::NameInUse(name));
+        }
+
+        self.modify(au, &me).map_err(|e| match e {
+            OperationError::Duplicate(_) => OperationError::NameInUse(name),
+            e => e,
+        })
+    }
+
+    // Give a host a new random service secret, valid for HOST_SECRET_LIFETIME
+    // from the start of this transaction. The change is made as the
+    // initiator, so the access profiles decide who may - by default the host
+    // itself and idm_admins. The cleartext is written, and the password plugin
+    // hashes it, so only the hash is kept - the secret is handed back once,
+    // here.
+    pub fn rotate_host_secret(
+        &mut self,
+        au: &mut AuditScope,
+        hre: &HostSecretRotateEvent,
+    ) -> Result<HostSecretRotateResponse, OperationError> {
+        audit_log!(au, "Begin host secret rotate event {:?}", hre);
+        let target = try_audit!(au, self.internal_search_uuid(au, hre.target_uuid.as_str()));
+        if !target.attribute_value_pres("class", "host") {
+            audit_log!(au, "secret rotate target {} is not a host", hre.target_uuid);
+            return Err(OperationError::InvalidEntryState);
+        }
+
+        let mut rng = StdRng::from_entropy();
+        let bytes: [u8; 32] = rng.gen();
+        let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let expiry = DateTime::<Utc>::from(
+            UNIX_EPOCH + self.csn.ts() + Duration::from_secs(HOST_SECRET_LIFETIME),
+        )
+        .to_rfc3339();
+
+        let modlist = try_audit!(
+            au,
+            ModifyList::new_list(vec![
+                Modify::Purged("service_secret".to_string()),
+                Modify::Present(
+                    "service_secret".to_string(),
+                    Value::from(secret.as_str())
+                ),
+                Modify::Purged("service_secret_expiry".to_string()),
+                Modify::Present(
+                    "service_secret_expiry".to_string(),
+                    Value::from(expiry.as_str())
+                ),
+            ])
+            .validate(&self.schema)
+            .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(
+            au,
+            filter!(f_eq("uuid", hre.target_uuid.as_str()))
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+
+        // The target exists, so if the initiator's modify finds nothing it's
+        // because they may not see it - which is as much a refusal as being
+        // unable to change it.
+        let me = ModifyEvent::new_impersonate(&hre.event, filt.clone(), filt, modlist);
+        match self.modify(au, &me) {
+            Ok(()) => Ok(HostSecretRotateResponse { secret, expiry }),
+            Err(OperationError::NoMatchingEntries) => {
+                audit_log!(au, "secret rotate target {} not visible", hre.target_uuid);
+                self.record_access_denied(au, &hre.event);
+                Err(OperationError::AccessDenied)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Give an account a new random radius secret. As with host secrets the
+    // change is made as the initiator - by default only the account itself
+    // may.
+    pub fn regenerate_radius_secret(
+        &mut self,
+        au: &mut AuditScope,
+        rre: &RadiusSecretRegenerateEvent,
+    ) -> Result<RadiusSecretResponse, OperationError> {
+        audit_log!(au, "Begin radius secret regenerate event {:?}", rre);
+        let target = try_audit!(au, self.internal_search_uuid(au, rre.target_uuid.as_str()));
+        if !target.attribute_value_pres("class", "account") || target.get_uuid() == UUID_ANONYMOUS {
+            audit_log!(
+                au,
+                "radius secret target {} is not an account",
+                rre.target_uuid
+            );
+            return Err(OperationError::InvalidEntryState);
+        }
+
+        let mut rng = StdRng::from_entropy();
+        let secret: String = rng.sample_iter(&Alphanumeric).take(24).collect();
+        let modlist = try_audit!(
+            au,
+            ModifyList::new_list(vec![
+                Modify::Purged("radius_secret".to_string()),
+                Modify::Present("radius_secret".to_string(), Value::from(secret.as_str())),
+            ])
+            .validate(&self.schema)
+            .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(
+            au,
+            filter!(f_eq("uuid", rre.target_uuid.as_str()))
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+
+        // The target exists, so if the initiator's modify finds nothing it's
+        // because they may not see it - which is as much a refusal as being
+        // unable to change it.
+        let me = ModifyEvent::new_impersonate(&rre.event, filt.clone(), filt, modlist);
+        match self.modify(au, &me) {
+            Ok(()) => Ok(RadiusSecretResponse { secret: secret }),
+            Err(OperationError::NoMatchingEntries) => {
+                audit_log!(au, "radius secret target {} not visible", rre.target_uuid);
+                self.record_access_denied(au, &rre.event);
+                Err(OperationError::AccessDenied)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Change the audit log levels of the named subsystems, keeping the rest.
+    // This is stored in system_config as the initiator, so the access profiles
+    // decide who may, and the levels are applied when the change commits.
+    pub fn set_log_levels(
+        &mut self,
+        au: &mut AuditScope,
+        lle: &LogLevelEvent,
+    ) -> Result<Vec<LogLevel>, OperationError> {
+        audit_log!(au, "Begin log level event {:?}", lle);
+        let mut levels = self.log_levels(au);
+        levels.extend(lle.levels.iter().map(|(s, l)| (s.clone(), *l)));
+        // Info is the default, so it isn't stored.
+        let mut mods = vec![Modify::Purged("log_level".to_string())];
+        mods.extend(
+            levels
+                .iter()
+                .filter(|(_, l)| **l != AuditLevel::Info)
+                .map(|(s, l)| {
+                    Modify::Present(
+                        "log_level".to_string(),
+                        Value::from(format!("{}={}", s, l.as_str()).as_str()),
+                    )
+                }),
+        );
+
+        let modlist = try_audit!(
+            au,
+            ModifyList::new_list(mods)
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(
+            au,
+            filter!(f_eq("uuid", UUID_SYSTEM_CONFIG))
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let me = ModifyEvent::new_impersonate(&lle.event, filt.clone(), filt, modlist);
+        // system_config always exists, so not finding it means it can't be
+        // seen by who asked.
+        self.modify(au, &me).map_err(|e| match e {
+            OperationError::NoMatchingEntries => OperationError::AccessDenied,
+            e => e,
+        })?;
+        info!("Audit log levels changed to {:?}", levels);
+
+        Ok(LOG_SUBSYSTEMS
+            .iter()
+            .map(|s| LogLevel {
+                subsystem: s.to_string(),
+                level: levels
+                    .get(*s)
+                    .cloned()
+                    .unwrap_or(AuditLevel::Info)
+                    .as_str()
+                    .to_string(),
+            })
+            .collect())
+    }
+
+    // Give an account a new random password, for when no one who could set
+    // one is able to log in - such as admin on a new server. This is only
+    // done from the server's own command line, so it's internal.
+    pub fn recover_account(
+        &mut self,
+        au: &mut AuditScope,
+        name: &str,
+    ) -> Result<String, OperationError> {
+        let account = try_audit!(
+            au,
+            self.internal_search(
+                au,
+                filter!(f_and!([f_eq("class", "account"), f_eq("name", name)]))
+            )
+        );
+        let account = match account.as_slice() {
+            [a] if a.get_uuid() == UUID_ANONYMOUS => {
+                return Err(OperationError::InvalidAccountState(
+                    "anonymous has no password",
+                ))
+            }
+            [a] => a.get_uuid().clone(),
+            _ => return Err(OperationError::NoMatchingEntries),
+        };
+
+        let mut rng = StdRng::from_entropy();
+        let cleartext: String = rng.sample_iter(&Alphanumeric).take(24).collect();
+        // The password plugin hashes this before it's stored.
+        self.internal_modify(
+            au,
+            filter!(f_eq("uuid", account.as_str())),
+            ModifyList::new_list(vec![
+                Modify::Purged("password".to_string()),
+                Modify::Present("password".to_string(), Value::from(cleartext.as_str())),
+            ]),
+        )?;
+        audit_log!(au, "Reset the password of {}", account);
+        Ok(cleartext)
+    }
+
+    // Give an account a break glass secret, for when its usual credentials
+    // can't be used. Authenticating with it acts as the account, so access
+    // controls apply as ever, but only until the expiry. Returns the secret
+    // and the expiry.
+    pub fn break_glass(
+        &mut self,
+        au: &mut AuditScope,
+        name: &str,
+        lifetime: Duration,
+    ) -> Result<(String, String), OperationError> {
+        let account = try_audit!(
+            au,
+            self.internal_search(
+                au,
+                filter!(f_and!([f_eq("class", "account"), f_eq("name", name)]))
+            )
+        );
+        let account = match account.as_slice() {
+            [a] if a.get_uuid() == UUID_ANONYMOUS => {
+                return Err(OperationError::InvalidAccountState(
+                    "anonymous can't break glass",
+                ))
+            }
+            [a] => a.get_uuid().clone(),
+            _ => return Err(OperationError::NoMatchingEntries),
+        };
+
+        let lifetime = std::cmp::min(lifetime, Duration::from_secs(BREAK_GLASS_MAX_LIFETIME));
+        let mut rng = StdRng::from_entropy();
+        let secret: String = rng.sample_iter(&Alphanumeric).take(32).collect();
+        let hash = Password::new(secret.as_str())?;
+        // Whole seconds, as that's what the expiry is read back as.
+        let expiry = Duration::from_secs((self.csn.ts() + lifetime).as_secs());
+        let expiry = DateTime::<Utc>::from(UNIX_EPOCH + expiry).to_rfc3339();
+        self.internal_modify(
+            au,
+            filter!(f_eq("uuid", account.as_str())),
+            ModifyList::new_list(vec![
+                Modify::Purged("break_glass_secret".to_string()),
+                Modify::Present(
+                    "break_glass_secret".to_string(),
+                    Value::from(hash.to_string()),
+                ),
+                Modify::Purged("break_glass_expiry".to_string()),
+                Modify::Present(
+                    "break_glass_expiry".to_string(),
+                    Value::from(expiry.as_str()),
+                ),
+            ]),
+        )?;
+        audit_log!(
+            au,
+            "Break glass secret set for {} until {}",
+            account,
+            expiry
+        );
+        Ok((secret, expiry))
+    }
+
+    // Remove an account's break glass secret once a session has been made
+    // with it.
+    pub fn break_glass_spent(
+        &mut self,
+        au: &mut AuditScope,
+        uuid: &str,
+    ) -> Result<(), OperationError> {
+        self.internal_modify(
+            au,
+            filter!(f_eq("uuid", uuid)),
+            ModifyList::new_list(vec![
+                Modify::Purged("break_glass_secret".to_string()),
+                Modify::Purged("break_glass_expiry".to_string()),
+            ]),
+        )?;
+        audit_log!(au, "Break glass secret of {} spent", uuid);
+        Ok(())
+    }
+
+    // Give an account a one time token that lets whoever holds it set the
+    // account's password without knowing the old one, until it expires. The
+    // change is made as the initiator, so by default only idm_admins may. Only
+    // the token's hash is kept, and issuing again replaces any earlier token.
+    pub fn issue_credential_reset(
+        &mut self,
+        au: &mut AuditScope,
+        cre: &CredentialResetIssueEvent,
+    ) -> Result<CredentialResetTokenResponse, OperationError> {
+        audit_log!(au, "Begin credential reset issue event {:?}", cre);
+        let target = try_audit!(au, self.internal_search_uuid(au, cre.target_uuid.as_str()));
+        if !target.attribute_value_pres("class", "account") || target.get_uuid() == UUID_ANONYMOUS {
+            audit_log!(
+                au,
+                "credential reset target {} is not an account",
+                cre.target_uuid
+            );
+            return Err(OperationError::InvalidEntryState);
+        }
+
+        let lifetime = std::cmp::min(
+            cre.lifetime
+                .unwrap_or(Duration::from_secs(CREDENTIAL_RESET_DEFAULT_LIFETIME)),
+            Duration::from_secs(CREDENTIAL_RESET_MAX_LIFETIME),
+        );
+        let mut rng = StdRng::from_entropy();
+        let secret: String = rng.sample_iter(&Alphanumeric).take(32).collect();
+        let hash = Password::new(secret.as_str())?;
+        let expiry = DateTime::<Utc>::from(UNIX_EPOCH + self.csn.ts() + lifetime).to_rfc3339();
+
+        let modlist = try_audit!(
+            au,
+            ModifyList::new_list(vec![
+                Modify::Purged("credential_reset_token".to_string()),
+                Modify::Present(
+                    "credential_reset_token".to_string(),
+                    Value::from(hash.to_string())
+                ),
+                Modify::Purged("credential_reset_expiry".to_string()),
+                Modify::Present(
+                    "credential_reset_expiry".to_string(),
+                    Value::from(expiry.as_str())
+                ),
+            ])
+            .validate(&self.schema)
+            .map_err(|e| OperationError::SchemaViolation(e))
+        );
+        let filt = try_audit!(
+            au,
+            filter!(f_eq("uuid", cre.target_uuid.as_str()))
+                .validate(&self.schema)
+                .map_err(|e| OperationError::SchemaViolation(e))
+        );
+
+        let me = ModifyEvent::new_impersonate(&cre.event, filt.clone(), filt, modlist);
+        self.modify(au, &me)?;
+        audit_log!(
+            au,
+            "Credential reset token issued for {} until {}",
+            cre.target_uuid,
+            expiry
+        );
+        // The account is named in the token, so redeeming it needs no search.
+        Ok(CredentialResetTokenResponse {
+            token: format!("{}.{}", target.get_uuid(), secret),
+            expiry: expiry,
+        })
+    }
+
+    // Set the password of the account a reset token was issued for, and spend
+    // the token. Whoever holds the token may, without authenticating, so the
+    // attempts are rate limited across the server and every failure looks the
+    // same to the caller.
+    pub fn redeem_credential_reset(
+        &mut self,
+        au: &mut AuditScope,
+        token: &str,
+        password: &str,
+    ) -> Result<(), OperationError> {
+        if !self.credential_reset_rate.check(
+            "",
+            Duration::from_secs(CREDENTIAL_RESET_WINDOW),
+            CREDENTIAL_RESET_MAX_ATTEMPTS,
+        ) {
+            audit_log!(
+                au,
+                "credential reset redeemed over {} times in {}s",
+                CREDENTIAL_RESET_MAX_ATTEMPTS,
+                CREDENTIAL_RESET_WINDOW
+            );
+            return Err(OperationError::RateLimited);
+        }
+
+        let mut parts = token.splitn(2, '.');
+        let (uuid, secret) = match (parts.next(), parts.next()) {
+            (Some(uuid), Some(secret)) if Uuid::parse_str(uuid).is_ok() => (uuid, secret),
+            _ => {
+                audit_log!(au, "credential reset token is malformed");
+                return Err(OperationError::InvalidCredentialResetToken);
+            }
+        };
+        au.set_origin(Some(uuid));
+        let account = self
+            .internal_search_uuid(au, uuid)
+            .map_err(|_| OperationError::InvalidCredentialResetToken)?;
+
+        let hash = account
+            .get_ava_single("credential_reset_token")
+            .and_then(|v| Password::from_stored(v.to_string().as_str()));
+        let expiry = account
+            .get_ava_single("credential_reset_expiry")
+            .and_then(|v| DateTime::parse_from_rfc3339(v.to_string().as_str()).ok())
+            .map(|dt| Duration::from_secs(std::cmp::max(dt.timestamp(), 0) as u64));
+        match (hash, expiry) {
+            (Some(hash), Some(expiry)) if hash.verify(secret) => {
+                if expiry <= self.csn.ts() {
+                    audit_log!(au, "credential reset token for {} has expired", uuid);
+                    return Err(OperationError::InvalidCredentialResetToken);
+                }
+            }
+            _ => {
+                audit_log!(au, "credential reset token for {} doesn't match", uuid);
+                return Err(OperationError::InvalidCredentialResetToken);
+            }
+        }
+
+        // The password plugin hashes this before it's stored.
+        self.internal_modify(
+            au,
+            filter!(f_eq("uuid", uuid)),
+            ModifyList::new_list(vec![
+                Modify::Purged("credential_reset_token".to_string()),
+                Modify::Purged("credential_reset_expiry".to_string()),
+                Modify::Purged("password".to_string()),
+                Modify::Present("password".to_string(), Value::from(password)),
+            ]),
+        )?;
+        audit_log!(au, "Credential reset token redeemed for {}", uuid);
+        Ok(())
+    }
+
+    pub fn modify(&mut self, au: &mut AuditScope, me: &ModifyEvent) -> Result<(), OperationError> {
+        // Get the candidates.
+        // Modify applies a modlist to a filter, so we need to internal search
+        // then apply.
+
+        // Validate input.
+
+        // Is the modlist non zero?
+        if me.modlist.len() == 0 {
+            audit_log!(au, "modify: empty modify request");
+            return Err(OperationError::EmptyRequest);
+        }
+
+        let changes_credentials = me.modlist.iter().any(|m| {
+            let attr = match m {
+                Modify::Present(a, _) | Modify::Removed(a, _) | Modify::Purged(a) => a,
+            };
+            CREDENTIAL_ATTRS.contains(&attr.as_str())
+        });
+        if changes_credentials {
+            au.set_security_relevant();
+        }
+
+        // Is the modlist valid?
+        // This is now done in the event transform
+
+        // Is the filter invalid to schema?
+        // This is now done in the event transform
+
+        // This also checks access controls due to use of the impersonation.
+        let pre_candidates = match self.impersonate_search_valid(
+            au,
+            me.filter.clone(),
+            me.filter_orig.clone(),
+            &me.event,
+        ) {
+            Ok(results) => results,
+            Err(e) => {
+                audit_log!(au, "modify: error in pre-candidate selection {:?}", e);
+                return Err(e);
+            }
+        };
+
+        if pre_candidates
+            .iter()
+            .any(|e| e.attribute_value_pres("class", "access_control_profile"))
+        {
+            au.set_security_relevant();
+        }
+
+        if pre_candidates.len() == 0 {
+            match me.event.origin {
+                EventOrigin::Internal => {
+                    audit_log!(
+                        au,
+                        "modify: no candidates match filter ... continuing {:?}",
+                        me.filter
+                    );
+                    return Ok(());
+                }
+                _ => {
+                    audit_log!(
+                        au,
+                        "modify: no candidates match filter, failure {:?}",
+                        me.filter
+                    );
+                    return Err(OperationError::NoMatchingEntries);
+                }
+            }
+        };
+
+        // Are we allowed to make the changes we want to?
+        // modify_allow_operation
+        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = metrics::timed(Stage::AccessCheck, || {
+            access.modify_allow_operation(&mut audit_acp, me, &pre_candidates)
+        });
+        au.append_scope(audit_acp);
+        if try_audit!(au, acp_res) != true {
+            self.record_access_denied(au, &me.event);
+            return Err(OperationError::AccessDenied);
+        }
+
+        // Clone a set of writeables.
+        // Apply the modlist -> Remember, we have a set of origs
+        // and the new modified ents.
+        let mut candidates: Vec<Entry<EntryInvalid, EntryCommitted>> = pre_candidates
+            .iter()
+            .map(|er| er.clone().invalidate())
+            .collect();
+
+        candidates
+            .iter_mut()
+            .for_each(|er| er.apply_modlist(&me.modlist, &self.csn));
+
+        // let mut candidates = try_audit!(au, candidates);
+
+        audit_log!(au, "modify: candidates -> {:?}", candidates);
+
+        self.modify_candidates(au, pre_candidates, candidates, me)
+    }
+
+    // The rest of a modify, once the changes have been applied to the
+    // candidates: plugins, schema and the backend write. me is what the
+    // plugins are told the modify was.
+    fn modify_candidates(
+        &mut self,
+        au: &mut AuditScope,
+        pre_candidates: Vec<Entry<EntryValid, EntryCommitted>>,
+        mut candidates: Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        // Pre mod plugins
+        let mut audit_plugin_pre = AuditScope::new("plugin_pre_modify");
+        let plug_pre_res =
+            Plugins::run_pre_modify(&mut audit_plugin_pre, self, &mut candidates, me);
+        au.append_scope(audit_plugin_pre);
+
+        if plug_pre_res.is_err() {
+            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_pre_res);
+            return plug_pre_res;
+        }
+
+        // NOTE: There is a potential optimisation here, where if
+        // candidates == pre-candidates, then we don't need to store anything
+        // because we effectively just did an assert. However, like all
+        // optimisations, this could be premature - so we for now, just
+        // do the CORRECT thing and recommit as we may find later we always
+        // want to add CSN's or other.
+        //
+        // memberOf actually wants the pre cand list and the norm_cand list to see what
+        // changed. Could be optimised, but this is correct still ...
+
+        let res: Result<Vec<Entry<EntryValid, EntryCommitted>>, Vec<SchemaError>> = candidates
+            .into_iter()
+            .map(|e| e.validate(&self.schema))
+            .collect();
+
+        let norm_cand: Vec<Entry<_, _>> = match res {
+            Ok(v) => v,
+            Err(e) => return Err(OperationError::EntrySchemaViolation(e)),
+        };
+
+        // Backend Modify
+        let mut audit_be = AuditScope::new("backend_modify");
+
+        let res = self
+            .be_txn
+            .modify(&mut audit_be, &self.change_origin(&me.event), &norm_cand);
+        au.append_scope(audit_be);
+
+        if res.is_err() {
+            // be_txn is dropped, ie aborted here.
+            audit_log!(au, "Modify operation failed (backend), {:?}", res);
+            return res;
+        }
+
+        // Post Plugins
+        let mut audit_plugin_post = AuditScope::new("plugin_post_modify");
+        let plug_post_res = Plugins::run_post_modify(
+            &mut audit_plugin_post,
+            self,
+            &pre_candidates,
+            &norm_cand,
+            me,
+        );
+        au.append_scope(audit_plugin_post);
+
+        if plug_post_res.is_err() {
+            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_post_res);
+            return plug_post_res;
+        }
+
+        // We have finished all plugs and now have a successful operation - flag if
+        // schema or acp requires reload. Remember, this is a modify, so we need to check
+        // pre and post cands.
+        let changed_schema = norm_cand
+            .iter()
+            .chain(pre_candidates.iter())
+            .fold(false, |acc, e| {
+                if acc {
+                    acc
+                } else {
+                    e.attribute_value_pres("class", "classtype")
+                        || e.attribute_value_pres("class", "attributetype")
+                }
+            });
+        try_audit!(au, self.reload_changed_schema(au, changed_schema));
+        self.changed_acp.extend(
+            norm_cand
+                .iter()
+                .chain(pre_candidates.iter())
+                .filter(|e| e.attribute_value_pres("class", "access_control_profile"))
+                .map(|e| e.get_uuid().clone()),
+        );
+        if norm_cand.iter().any(|e| e.get_uuid() == UUID_SYSTEM_CONFIG) {
+            self.changed_log_levels = true;
+        }
+        if norm_cand.iter().any(|e| e.get_uuid() == UUID_DOMAIN_INFO) {
+            self.changed_domain_info = true;
+        }
+        self.record_changes(&norm_cand, ChangeOp::Modify);
+        audit_log!(
+            au,
+            "Schema reload: {:?}, ACP reload: {:?}",
+            self.changed_schema,
+            self.changed_acp
+        );
+
+        // return
+        audit_log!(au, "Modify operation success");
+        res
+    }
+
+    // These are where searches and other actions are actually implemented. This
+    // is the "internal" version, where we define the event as being internal
+    // only, allowing certain plugin by passes etc.
+
+    // Apply each operation of a batch in turn. This stops at the first that
+    // fails, and the caller must then drop the transaction rather than commit
+    // it, so that none of the batch is kept.
+    pub fn batch(&mut self, au: &mut AuditScope, be: &BatchEvent) -> Result<(), OperationError> {
+        for (i, op) in be.operations.iter().enumerate() {
+            let res = be.operation_event(au, op, self).and_then(|ev| match ev {
+                BatchOperationEvent::Create(ce) => self.create(au, &ce),
+                BatchOperationEvent::Modify(me) => self.modify(au, &me),
+                BatchOperationEvent::Delete(de) => self.delete(au, &de),
+            });
+            if let Err(e) = res {
+                audit_log!(au, "batch: operation {} failed, {:?}", i, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn internal_create(
+        &mut self,
+        audit: &mut AuditScope,
+        entries: Vec<Entry<EntryInvalid, EntryNew>>,
+    ) -> Result<(), OperationError> {
+        // Start the audit scope
+        let mut audit_int = AuditScope::new("internal_create");
+        // Create the CreateEvent
+        let ce = CreateEvent::new_internal(entries);
+        let res = self.create(&mut audit_int, &ce);
+        audit.append_scope(audit_int);
+        res
+    }
+
+    pub fn internal_delete(
+        &mut self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+    ) -> Result<(), OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let mut audit_int = AuditScope::new("internal_delete");
+        let de = DeleteEvent::new_internal(f_valid);
+        let res = self.delete(&mut audit_int, &de);
+        audit.append_scope(audit_int);
+        res
+    }
+
+    pub fn internal_modify(
+        &mut self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+    ) -> Result<(), OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let m_valid = modlist
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        self.internal_modify_valid(audit, f_valid, m_valid)
+    }
+
+    pub fn internal_modify_valid(
+        &mut self,
+        audit: &mut AuditScope,
+        f_valid: Filter<FilterValid>,
+        m_valid: ModifyList<ModifyValid>,
+    ) -> Result<(), OperationError> {
+        let mut audit_int = AuditScope::new("internal_modify");
+        let me = ModifyEvent::new_internal(f_valid, m_valid);
+        let res = self.modify(&mut audit_int, &me);
+        audit.append_scope(audit_int);
+        res
+    }
+
+    // Apply one modlist to every entry matching a filter, such as adding a
+    // class to every account during a migration. The matches are found once
+    // up front, so each is modified exactly once even if the change alters
+    // what the filter matches. They are then modified in batches, so a large
+    // database isn't loaded and passed through the plugins all at once.
+    // Returns how many entries were modified.
+    pub fn internal_modify_filter(
+        &mut self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+    ) -> Result<usize, OperationError> {
+        self.internal_modify_filter_batched(audit, filter, modlist, INTERNAL_MODIFY_BATCH_SIZE)
+    }
+
+    fn internal_modify_filter_batched(
+        &mut self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+        batch_size: usize,
+    ) -> Result<usize, OperationError> {
+        let m_valid = modlist
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let uuids: Vec<String> = self
+            .internal_search(audit, filter)?
+            .into_iter()
+            .map(|e| e.get_uuid().clone())
+            .collect();
+        let total = uuids.len();
+
+        let mut au = AuditScope::new("internal_modify_filter");
+        let mut done = 0;
+        let mut res = Ok(total);
+        for chunk in uuids.chunks(batch_size) {
+            // The uuids are already normalised, and the search above decided
+            // which hidden entries are included.
+            let f_chunk = unsafe {
+                filter_all!(f_or(
+                    chunk
+                        .iter()
+                        .map(|uuid| f_eq("uuid", uuid.as_str()))
+                        .collect()
+                ))
+                .assume_valid(self.get_schema())
+            };
+            if let Err(e) = self.internal_modify_valid(&mut au, f_chunk, m_valid.clone()) {
+                res = Err(e);
+                break;
+            }
+            done += chunk.len();
+            audit_log!(au, "internal_modify_filter: {} of {} modified", done, total);
+        }
+        audit.append_scope(au);
+        res
+    }
+
+    // Apply a different modlist to each of a set of entries, as a single
+    // modify. Plugins such as memberof work out a change per entry - rather
+    // than a full modify of each, the targets are loaded once, every change
+    // for an entry is applied to it in order, and they are stored and passed
+    // through the plugins together. Plugins see one modify of all the
+    // targets, with all of the changes.
+    pub fn internal_modify_batch(
+        &mut self,
+        audit: &mut AuditScope,
+        batch: Vec<(String, ModifyList<ModifyInvalid>)>,
+    ) -> Result<(), OperationError> {
+        let batch: Vec<(String, ModifyList<ModifyValid>)> = try_audit!(
+            audit,
+            batch
+                .into_iter()
+                .map(|(uuid, modlist)| {
+                    modlist
+                        .validate(self.get_schema())
+                        .map(|ml| (uuid, ml))
+                        .map_err(|e| OperationError::SchemaViolation(e))
+                })
+                .collect()
+        );
+        self.internal_modify_batch_valid(audit, batch)
+    }
+
+    // As internal_modify_batch, with modlists that are already valid.
+    pub fn internal_modify_batch_valid(
+        &mut self,
+        audit: &mut AuditScope,
+        batch: Vec<(String, ModifyList<ModifyValid>)>,
+    ) -> Result<(), OperationError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut au = AuditScope::new("internal_modify_batch");
+        let res = self.modify_batch(&mut au, batch);
+        audit.append_scope(au);
+        res
+    }
+
+    fn modify_batch(
+        &mut self,
+        au: &mut AuditScope,
+        batch: Vec<(String, ModifyList<ModifyValid>)>,
+    ) -> Result<(), OperationError> {
+        // Collect the changes for each target together.
+        let mut targets: BTreeMap<String, ModifyList<ModifyValid>> = BTreeMap::new();
+        for (uuid, modlist) in batch {
+            match targets.get_mut(&uuid) {
+                Some(ml) => ml.append(modlist),
+                None => {
+                    targets.insert(uuid, modlist);
+                }
+            }
+        }
+
+        // The targets are uuids of entries, so are already normalised.
+        let f_valid = unsafe {
+            filter!(f_or(
+                targets
+                    .keys()
+                    .map(|uuid| f_eq("uuid", uuid.as_str()))
+                    .collect()
+            ))
+            .assume_valid(self.get_schema())
+        };
+
+        let pre_candidates = try_audit!(
+            au,
+            self.impersonate_search_valid(
+                au,
+                f_valid.clone(),
+                f_valid.clone(),
+                &Event::from_internal()
+            )
+        );
+        if pre_candidates.len() == 0 {
+            audit_log!(au, "modify_batch: no candidates match ... continuing");
+            return Ok(());
+        }
+
+        let candidates: Vec<Entry<EntryInvalid, EntryCommitted>> = pre_candidates
+            .iter()
+            .map(|er| {
+                let mut e = er.clone().invalidate();
+                if let Some(modlist) = targets.get(er.get_uuid()) {
+                    e.apply_modlist(modlist, &self.csn);
+                }
+                e
+            })
+            .collect();
+
+        audit_log!(au, "modify_batch: candidates -> {:?}", candidates);
+
+        let mut modlist_all: Option<ModifyList<ModifyValid>> = None;
+        for ml in targets.values() {
+            match modlist_all.as_mut() {
+                Some(all) => all.append(ml.clone()),
+                None => modlist_all = Some(ml.clone()),
+            }
+        }
+        let me =
+            ModifyEvent::new_internal(f_valid, modlist_all.ok_or(OperationError::EmptyRequest)?);
+
+        self.modify_candidates(au, pre_candidates, candidates, &me)
+    }
+
+    pub fn impersonate_modify_valid(
+        &mut self,
+        audit: &mut AuditScope,
+        f_valid: Filter<FilterValid>,
+        f_intent_valid: Filter<FilterValid>,
+        m_valid: ModifyList<ModifyValid>,
+        event: &Event,
+    ) -> Result<(), OperationError> {
+        let mut audit_int = AuditScope::new("impersonate_modify");
+        let me = ModifyEvent::new_impersonate(event, f_valid, f_intent_valid, m_valid);
+        let res = self.modify(&mut audit_int, &me);
+        audit.append_scope(audit_int);
+        res
+    }
+
+    pub fn impersonate_modify(
+        &mut self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        filter_intent: Filter<FilterInvalid>,
+        modlist: ModifyList<ModifyInvalid>,
+        event: &Event,
+    ) -> Result<(), OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let f_intent_valid = filter_intent
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        let m_valid = modlist
+            .validate(self.get_schema())
+            .map_err(|e| OperationError::SchemaViolation(e))?;
+        self.impersonate_modify_valid(audit, f_valid, f_intent_valid, m_valid, event)
+    }
+
+    // internal server operation types.
+    // These just wrap the fn create/search etc, but they allow
+    // creating the needed create event with the correct internal flags
+    // and markers. They act as though they have the highest level privilege
+    // IE there are no access control checks.
+
+    pub fn internal_exists_or_create(
+        &self,
+        _e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        // If the thing exists, stop.
+        // if not, create from Entry.
+        unimplemented!()
+    }
+
+    pub fn internal_migrate_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        // if the thing exists, ensure the set of attributes on
+        // Entry A match and are present (but don't delete multivalue, or extended
+        // attributes in the situation.
+        // If not exist, create from Entry B
+        //
+        // This will extra classes an attributes alone!
+        //
+        // NOTE: gen modlist IS schema aware and will handle multivalue
+        // correctly!
+        let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
+            Some(f) => f,
+            None => return Err(OperationError::FilterGeneration),
+        };
+
+        match self.internal_search(audit, filt.clone()) {
+            Ok(results) => {
+                if results.len() == 0 {
+                    // It does not exist. Create it.
+                    self.internal_create(audit, vec![e.invalidate()])
+                } else if results.len() == 1 {
+                    // If the thing is subset, pass
+                    match e.gen_modlist_assert(&self.schema) {
+                        Ok(modlist) => {
+                            // Apply to &results[0]
+                            audit_log!(audit, "Generated modlist -> {:?}", modlist);
+                            self.internal_modify(audit, filt, modlist)
+                        }
+                        Err(_e) => {
+                            unimplemented!()
+                            // No action required.
+                        }
+                    }
+                } else {
+                    Err(OperationError::InvalidDBState)
+                }
+            }
+            Err(e) => {
+                // An error occured. pass it back up.
+                Err(e)
+            }
+        }
+    }
+
+    // The same as internal_migrate_or_create, for many entries at once. The
+    // ones that already exist are found with a single search, and everything
+    // missing is created together - on a new database, that's all of them.
+    pub fn internal_migrate_or_create_batch(
+        &mut self,
+        audit: &mut AuditScope,
+        entries: Vec<Entry<EntryValid, EntryNew>>,
+    ) -> Result<(), OperationError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let filt = filter_all!(f_or(
+            entries
+                .iter()
+                .map(|e| f_eq("uuid", e.get_uuid().as_str()))
+                .collect()
+        ));
+        let existing: BTreeSet<String> = try_audit!(audit, self.internal_search(audit, filt))
+            .iter()
+            .map(|e| e.get_uuid().clone())
+            .collect();
+
+        let (present, missing): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|e| existing.contains(e.get_uuid()));
+        audit_log!(
+            audit,
+            "migrate_or_create_batch -> {} to create, {} to migrate",
+            missing.len(),
+            present.len()
+        );
+
+        if !missing.is_empty() {
+            try_audit!(
+                audit,
+                self.internal_create(audit, missing.into_iter().map(|e| e.invalidate()).collect())
+            );
+        }
+
+        present
+            .into_iter()
+            .try_for_each(|e| self.internal_migrate_or_create(audit, e))
+    }
+
+    // Should this take a be_txn?
+    pub fn internal_assert_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        // If exists, ensure the object is exactly as provided
+        // else, if not exists, create it. IE no extra or excess
+        // attributes and classes.
+
+        // Create a filter from the entry for assertion.
+        let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
+            Some(f) => f,
+            None => return Err(OperationError::FilterGeneration),
+        };
+
+        // Does it exist? we use search here, not exists, so that if the entry does exist
+        // we can compare it is identical, which avoids a delete/create cycle that would
+        // trigger csn/repl each time we start up.
+        match self.internal_search(audit, filt.clone()) {
+            Ok(results) => {
+                if results.len() == 0 {
+                    // It does not exist. Create it.
+                    self.internal_create(audit, vec![e.invalidate()])
+                } else if results.len() == 1 {
+                    // it exists. To guarantee content exactly as is, we compare if it's identical.
+                    if !e.compare(&results[0]) {
+                        self.internal_delete(audit, filt)
+                            .and_then(|_| self.internal_create(audit, vec![e.invalidate()]))
+                    } else {
+                        // No action required
+                        Ok(())
+                    }
+                } else {
+                    Err(OperationError::InvalidDBState)
+                }
+            }
+            Err(er) => {
+                // An error occured. pass it back up.
+                Err(er)
+            }
+        }
+    }
+
+    pub fn initialise_schema_core(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // Load in all the "core" schema, that we already have in "memory".
+        let entries = self.schema.to_entries();
+        audit_log!(audit, "init schema -> {} core entries", entries.len());
+
+        let r = self.internal_migrate_or_create_batch(audit, entries);
+        assert!(r.is_ok());
+        r
+    }
+
+    pub fn initialise_schema_idm(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let mut audit_si = AuditScope::new("start_initialise_schema_idm");
+        let r = self.internal_migrate_or_create_batch(&mut audit_si, IDM_SCHEMA.clone());
+        audit_log!(audit_si, "start_initialise_schema_idm -> result {:?}", r);
+        audit.append_scope(audit_si);
+        assert!(r.is_ok());
+        r
+    }
+
+    // This function is idempotent
+    pub fn initialise_idm(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // First, check the system_info object. This stores some server information
+        // and details. It's a pretty static thing.
+        let mut audit_si = AuditScope::new("start_system_info");
+        let res = audit_segment!(audit_si, || serde_json::from_str(JSON_SYSTEM_INFO_V1)
+            .map_err(|_| OperationError::SerdeJsonError)
+            .and_then(
+                |e: Entry<EntryValid, EntryNew>| self.internal_assert_or_create(audit, e)
+            ));
+        audit_log!(audit_si, "start_system_info -> result {:?}", res);
+        audit.append_scope(audit_si);
+        assert!(res.is_ok());
+        if res.is_err() {
+            return res;
+        }
+
+        // Check anonymous, admin, the default idm_admins group and the
+        // default access profiles exist (migrations).
+        let mut audit_an = AuditScope::new("start_idm_migrations_internal");
+        let res = self.internal_migrate_or_create_batch(&mut audit_an, IDM_ENTRIES.clone());
+        audit_log!(
+            audit_an,
+            "start_idm_migrations_internal -> result {:?}",
+            res
+        );
+        audit.append_scope(audit_an);
+        assert!(res.is_ok());
+        res
+    }
+
+    // The domain_info entry is made once, with a new domain uuid and the
+    // configured name. A domain is never renamed by changing the
+    // configuration, as that would leave every principal name behind.
+    fn initialise_domain_info(
+        &mut self,
+        audit: &mut AuditScope,
+        name: &str,
+    ) -> Result<(), OperationError> {
+        let mut audit_di = AuditScope::new("start_domain_info");
+        let res = match self.internal_search_uuid(&mut audit_di, UUID_DOMAIN_INFO) {
+            Ok(e) => {
+                if !e.attribute_equality("domain_name", name) {
+                    audit_log!(
+                        audit_di,
+                        "domain_info: keeping the existing domain name, not {}",
+                        name
+                    );
+                }
+                Ok(())
+            }
+            Err(OperationError::NoMatchingEntries) => {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", "object");
+                e.add_ava("class", "system");
+                e.add_ava("class", "domain_info");
+                e.add_ava("uuid", UUID_DOMAIN_INFO);
+                e.add_ava("description", "The domain this server belongs to.");
+                e.add_ava("domain_name", name);
+                e.add_ava(
+                    "domain_uuid",
+                    Uuid::new_v4().to_hyphenated().to_string().as_str(),
+                );
+                e.add_ava("version", DOMAIN_VERSION);
+                self.internal_create(&mut audit_di, vec![e])
+            }
+            Err(e) => Err(e),
+        };
+        audit_log!(audit_di, "start_domain_info -> result {:?}", res);
+        audit.append_scope(audit_di);
+        // Read it, whether it's new or not.
+        self.changed_domain_info = true;
+        res
+    }
+
+    fn reload_domain_info(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let e = try_audit!(audit, self.internal_search_uuid(audit, UUID_DOMAIN_INFO));
+        let di = try_audit!(audit, DomainInfo::try_from(audit, &e));
+        *self.domain_info = di;
+        Ok(())
+    }
+
+    fn reload_schema(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // supply entries to the writable schema to reload from.
+        // find all attributes.
+        let filt = filter!(f_eq("class", "attributetype"));
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+        // load them.
+        let attributetypes: Result<Vec<_>, _> = res
+            .iter()
+            .map(|e| SchemaAttribute::try_from(audit, e))
+            .collect();
+        let attributetypes = try_audit!(audit, attributetypes);
+
+        try_audit!(audit, self.schema.update_attributes(attributetypes));
+
+        // find all classes
+        let filt = filter!(f_eq("class", "classtype"));
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+        // load them.
+        let classtypes: Result<Vec<_>, _> = res
+            .iter()
+            .map(|e| SchemaClass::try_from(audit, e))
+            .collect();
+        let classtypes = try_audit!(audit, classtypes);
+
+        try_audit!(audit, self.schema.update_classes(classtypes));
+
+        // validate.
+        let valid_r = self.schema.validate(audit);
+
+        // Translate the result.
+        if valid_r.len() == 0 {
+            Ok(())
+        } else {
+            audit_log!(audit, "schema is inconsistent -> {:?}", valid_r);
+            Err(OperationError::ConsistencyError(valid_r))
+        }
+    }
+
+    // Changes to schema entries take effect for the rest of the transaction,
+    // so a definition that doesn't parse, or names attributes that don't
+    // exist, fails the operation that made it.
+    fn reload_changed_schema(
+        &mut self,
+        audit: &mut AuditScope,
+        changed: bool,
+    ) -> Result<(), OperationError> {
+        if !changed {
+            return Ok(());
+        }
+        self.changed_schema = true;
+        let mut audit_schema = AuditScope::new("reload_schema");
+        let r = self.reload_schema(&mut audit_schema);
+        audit.append_scope(audit_schema);
+        r
+    }
+
+    // Bring what the backend indexes into line with schema, rebuilding the
+    // indexes if that changed.
+    fn reload_idxmeta(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        if self
+            .be_txn
+            .update_idxmeta(audit, self.schema.get_idxmeta())?
+        {
+            self.be_txn.reindex(audit)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Rebuild every index from the stored entries. Schema is read from the
+    // database first, so this is correct without initialising the server.
+    pub fn reindex(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        self.reload_schema(audit)?;
+        self.be_txn
+            .update_idxmeta(audit, self.schema.get_idxmeta())?;
+        self.be_txn.reindex(audit)
+    }
+
+    // Create the entries in a file written by export. Each must be valid
+    // under the schema in the database. An entry whose uuid already exists is
+    // left as it is, so an export can be loaded into a server that already
+    // holds the builtin entries. Returns how many entries were created.
+    pub fn import(
+        &mut self,
+        audit: &mut AuditScope,
+        src_path: &str,
+    ) -> Result<usize, OperationError> {
+        self.reload_schema(audit)?;
+        let data = try_audit!(
+            audit,
+            fs::read_to_string(src_path),
+            "fs::read_to_string error {:?}",
+            OperationError::FsError
+        );
+        let mut entries = Vec::new();
+        for line in data.lines().filter(|l| !l.trim().is_empty()) {
+            let e = try_audit!(audit, Entry::from_external_json(line, &self.schema));
+            let uuid = e
+                .get_ava("uuid")
+                .and_then(|vs| vs.first())
+                .map(|v| v.to_string());
+            let exists = match &uuid {
+                Some(u) => self.internal_exists(audit, filter_all!(f_eq("uuid", u.as_str())))?,
+                None => false,
+            };
+            if exists {
+                audit_log!(audit, "import: skipping existing entry {:?}", uuid);
+            } else {
+                entries.push(e);
+            }
+        }
+        let n = entries.len();
+        if n > 0 {
+            self.internal_create(audit, entries)?;
+        }
+        audit_log!(audit, "imported {} entries", n);
+        Ok(n)
+    }
+
+    // Apply the entries sent by a replication supplier, resolving each against
+    // ours - see repl.rs. Schema entries go first, and the schema is reloaded
+    // before the rest, so entries using new attributes or classes can be
+    // checked. The pre plugins aren't run, as a change the supplier accepted
+    // can't be refused here. Once everything is written, post_repl brings
+    // what the plugins derive - references, dyngroups and memberof - in line
+    // with the result here, which may differ from the supplier's if it
+    // hasn't yet seen changes made here. Returns how many entries changed
+    // here.
+    pub fn repl_apply(
+        &mut self,
+        au: &mut AuditScope,
+        entries: &[ReplEntry],
+    ) -> Result<usize, OperationError> {
+        let (schema_entries, rest): (Vec<&ReplEntry>, Vec<&ReplEntry>) =
+            entries.iter().partition(|re| {
+                re.attrs
+                    .as_ref()
+                    .and_then(|a| a.get("class"))
+                    .map(|cs| cs.iter().any(|c| c == "classtype" || c == "attributetype"))
+                    .unwrap_or(false)
+            });
+        let mut applied = self.repl_apply_entries(au, &schema_entries)?;
+        if !applied.is_empty() {
+            self.reload_schema(au)?;
+        }
+        applied.extend(self.repl_apply_entries(au, &rest)?);
+        if !applied.is_empty() {
+            Plugins::run_post_repl(au, self, &applied)?;
+        }
+        Ok(applied.len())
+    }
+
+    // The uuids of the entries changed here.
+    fn repl_apply_entries(
+        &mut self,
+        au: &mut AuditScope,
+        entries: &[&ReplEntry],
+    ) -> Result<BTreeSet<String>, OperationError> {
+        let mut creates = Vec::new();
+        let mut modifies = Vec::new();
+        let mut purges = Vec::new();
+        let mut pre_candidates = Vec::new();
+        for re in entries {
+            let local = self
+                .internal_search(au, filter_all!(f_eq("uuid", re.uuid.as_str())))?
+                .pop();
+            match try_audit!(au, repl::resolve(&self.schema, local.as_ref(), re)) {
+                ReplAction::Skip => {}
+                ReplAction::Create(e) => creates.push(e),
+                ReplAction::Modify(e) => {
+                    modifies.push(e);
+                    pre_candidates.extend(local);
+                }
+                ReplAction::Purge(e) => purges.push(e),
+            }
+        }
+        audit_log!(
+            au,
+            "replication: {} creates, {} modifies, {} purges",
+            creates.len(),
+            modifies.len(),
+            purges.len()
+        );
+
+        let creates: Vec<Entry<EntryValid, EntryNew>> = try_audit!(
+            au,
+            creates
+                .into_iter()
+                .map(|e| e.validate(&self.schema))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(OperationError::EntrySchemaViolation)
+        );
+        let modifies: Vec<Entry<EntryValid, EntryCommitted>> = try_audit!(
+            au,
+            modifies
+                .into_iter()
+                .map(|e| e.validate(&self.schema))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(OperationError::EntrySchemaViolation)
+        );
+
+        // Each change is logged with the csn it was made at, not ours, so it
+        // keeps its place when passed on to anything replicating from us.
+        // Consumers follow our changelog by position, so an older csn logged
+        // now is still sent to them.
+        fn origin<STATE>(e: &Entry<EntryValid, STATE>, csn: Csn) -> ChangeOrigin {
+            ChangeOrigin::internal(e.last_modified().cloned().unwrap_or(csn))
+        }
+        let mut audit_be = AuditScope::new("backend_replication");
+        let csn = self.csn;
+        let be_txn = &self.be_txn;
+        let res = creates
+            .iter()
+            .try_for_each(|e| be_txn.create(&mut audit_be, &origin(e, csn), &vec![e.clone()]))
+            .and_then(|_| {
+                modifies.iter().try_for_each(|e| {
+                    be_txn.modify(&mut audit_be, &origin(e, csn), &vec![e.clone()])
+                })
+            })
+            .and_then(|_| {
+                purges.iter().try_for_each(|e| {
+                    be_txn.delete(&mut audit_be, &origin(e, csn), &vec![e.clone()])
+                })
+            });
+        au.append_scope(audit_be);
+        try_audit!(au, res);
+
+        fn is_schema<STATE>(e: &Entry<EntryValid, STATE>) -> bool {
+            e.attribute_value_pres("class", "classtype")
+                || e.attribute_value_pres("class", "attributetype")
+        }
+        fn acp_uuid<STATE>(e: &Entry<EntryValid, STATE>) -> Option<String> {
+            if e.attribute_value_pres("class", "access_control_profile") {
+                Some(e.get_uuid().clone())
+            } else {
+                None
+            }
+        }
+        let committed = || {
+            modifies
+                .iter()
+                .chain(purges.iter())
+                .chain(pre_candidates.iter())
+        };
+        if creates.iter().any(|e| is_schema(e)) || committed().any(|e| is_schema(e)) {
+            self.changed_schema = true;
+        }
+        if creates.iter().any(|e| e.get_uuid() == UUID_DOMAIN_INFO)
+            || modifies.iter().any(|e| e.get_uuid() == UUID_DOMAIN_INFO)
+        {
+            self.changed_domain_info = true;
+        }
+        self.changed_acp
+            .extend(creates.iter().filter_map(|e| acp_uuid(e)));
+        self.changed_acp
+            .extend(committed().filter_map(|e| acp_uuid(e)));
+        self.record_changes(&creates, ChangeOp::Create);
+        self.record_changes(&modifies, ChangeOp::Modify);
+        self.record_changes(&purges, ChangeOp::Delete);
+        Ok(creates
+            .iter()
+            .map(|e| e.get_uuid())
+            .chain(modifies.iter().map(|e| e.get_uuid()))
+            .chain(purges.iter().map(|e| e.get_uuid()))
+            .cloned()
+            .collect())
+    }
+
+    // Record how far through its supplier's changelog a replication
+    // agreement has got.
+    pub fn repl_agreement_update(
+        &mut self,
+        au: &mut AuditScope,
+        agreement_uuid: &str,
+        seq: i64,
+    ) -> Result<(), OperationError> {
+        self.internal_modify(
+            au,
+            filter!(f_eq("uuid", agreement_uuid)),
+            ModifyList::new_list(vec![
+                Modify::Purged("repl_seq".to_string()),
+                Modify::Present("repl_seq".to_string(), Value::Integer(seq)),
+            ]),
+        )
+    }
+
+    // Replace everything with a backup. The schema in the backup is loaded,
+    // and every entry must be valid under it, or nothing is committed.
+    pub fn restore(
+        &mut self,
+        audit: &mut AuditScope,
+        src_path: &str,
+    ) -> Result<(), OperationError> {
+        let mut audit_be = AuditScope::new("backend_restore");
+        let res = self.be_txn.restore(&mut audit_be, src_path);
+        audit.append_scope(audit_be);
+        try_audit!(audit, res);
+
+        self.reload_schema(audit)?;
+        let entries = self.internal_search(audit, filter_all!(f_pres("class")))?;
+        let errs: Vec<SchemaError> = entries
+            .into_iter()
+            .filter_map(|e| e.invalidate().validate(&self.schema).err())
+            .flatten()
+            .collect();
+        if !errs.is_empty() {
+            audit_log!(audit, "restore: entries invalid under schema {:?}", errs);
+            return Err(OperationError::EntrySchemaViolation(errs));
+        }
+
+        // Rebuild the indexes and access controls from the restored schema.
+        self.changed_schema = true;
+        Ok(())
+    }
+
+    pub(crate) fn get_acp_require_metadata(&self) -> bool {
+        self.acp_require_metadata
+    }
+
+    // Parse the acps of one kind from the profiles given, and replace that
+    // set with them. For a partial reload, only the acps changed in this
+    // transaction are replaced.
+    fn reload_acp_kind<T: AccessControlKind>(
+        &mut self,
+        audit: &mut AuditScope,
+        entries: &[Entry<EntryValid, EntryCommitted>],
+        partial: bool,
+    ) -> Result<(), OperationError> {
+        let acps: Result<Vec<T>, _> = entries
+            .iter()
+            .filter(|e| e.attribute_value_pres("class", T::CLASS))
+            .map(|e| T::try_from(audit, self, e))
+            .collect();
+        let acps = try_audit!(audit, acps);
+        if partial {
+            try_audit!(
+                audit,
+                self.accesscontrols.update_partial(&self.changed_acp, acps)
+            );
+        } else {
+            try_audit!(audit, self.accesscontrols.update(acps));
+        }
+        Ok(())
+    }
+
+    // The profiles of every kind of acp, refreshed the same way.
+    fn reload_acp_kinds(
+        &mut self,
+        audit: &mut AuditScope,
+        entries: &[Entry<EntryValid, EntryCommitted>],
+        partial: bool,
+    ) -> Result<(), OperationError> {
+        self.reload_acp_kind::<AccessControlSearch>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlCreate>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlModify>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlDelete>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlCompare>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlAuditRead>(audit, entries, partial)?;
+        self.reload_acp_kind::<AccessControlOperation>(audit, entries, partial)
+    }
+
+    fn reload_accesscontrols(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // supply entries to the writable access controls to reload from.
+        //
+        // Disabled acps are not loaded at all. Log-only acps are, as they
+        // are still evaluated, just not enforced.
+        //
+        // Note, we have to do the search, parse, then submit here, because of the
+        // requirement to have the write query server reference in the parse stage - this
+        // would cause a rust double-borrow if we had AccessControls to try to handle
+        // the entry lists themself.
+        let filt = filter!(f_and!([
+            f_eq("class", "access_control_profile"),
+            f_or(vec![
+                f_eq("acp_enable", "true"),
+                f_eq("acp_log_only", "true")
+            ]),
+        ]));
+
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+
+        self.reload_acp_kinds(audit, &res, false)
+    }
+
+    // Reparse only the acps that were touched in this transaction. Anything that
+    // was changed but is no longer an enabled or log-only acp of a given type
+    // (deleted, disabled or had the class removed) is dropped from that set.
+    fn reload_accesscontrols_partial(
+        &mut self,
+        audit: &mut AuditScope,
+    ) -> Result<(), OperationError> {
+        audit_log!(audit, "Partial acp reload of {:?}", self.changed_acp);
+        let filt = filter!(f_and!([
+            f_eq("class", "access_control_profile"),
+            f_or(vec![
+                f_eq("acp_enable", "true"),
+                f_eq("acp_log_only", "true")
+            ]),
+            f_or(
+                self.changed_acp
+                    .iter()
+                    .map(|u| f_eq("uuid", u.as_str()))
+                    .collect()
+            ),
+        ]));
+
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+
+        self.reload_acp_kinds(audit, &res, true)
+    }
+
+    pub fn commit(mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // This could be faster if we cache the set of classes changed
+        // in an operation so we can check if we need to do the reload or not
+        //
+        // Reload the schema from qs.
+        if self.changed_schema {
+            self.reload_schema(audit)?;
+            self.reload_idxmeta(audit)?;
+        }
+        // Determine if we need to update access control profiles
+        // based on any modifications that have occured.
+        // IF SCHEMA CHANGED WE MUST ALSO RELOAD!!! IE if schema had an attr removed
+        // that we rely on we MUST fail this here!!
+        if self.changed_schema {
+            self.reload_accesscontrols(audit)?;
+        } else if !self.changed_acp.is_empty() {
+            self.reload_accesscontrols_partial(audit)?;
+        }
+        let log_levels = if self.changed_log_levels {
+            Some(self.log_levels(audit))
+        } else {
+            None
+        };
+        if self.changed_domain_info {
+            self.reload_domain_info(audit)?;
+        }
+
+        // Now destructure the transaction ready to reset it.
+        let QueryServerWriteTransaction {
+            committed,
+            be_txn,
+            schema,
+            accesscontrols,
+            changed_schema: _,
+            changed_acp: _,
+            changed_log_levels: _,
+            changed_domain_info: _,
+            acp_require_metadata: _,
+            csn: _,
+            change_bus,
+            changes,
+            anon_search_rate: _,
+            credential_reset_rate: _,
+            anomalies,
+            user_deletes,
+            filter_limits: _,
+            search_max_results: _,
+            domain_info,
+            log_levels: levels_handle,
+        } = self;
+        assert!(!committed);
+        // Write out the held back index changes while we can still fail.
+        be_txn.flush_idls(audit)?;
+        // What derives from this transaction's changes is committed along
+        // with them, so the recovery point moves past them too. Only changes
+        // that were never followed by this, at the last start, are checked.
+        let seq = be_txn.changelog_max_seq(audit)?;
+        be_txn.set_recovery_seq(audit, seq)?;
+        // Begin an audit.
+        // Validate the schema as we just loaded it.
+        let r = schema.validate(audit);
+
+        if r.len() == 0 {
+            // Schema has been validated, so we can go ahead and commit it with the be
+            // because both are consistent.
+            let res = schema
+                .commit()
+                .and_then(|_| accesscontrols.commit().and_then(|_| be_txn.commit()));
+            if res.is_ok() {
+                domain_info.commit();
+            }
+            if res.is_ok() && user_deletes > 0 {
+                if let Some(a) = anomalies.record(SecurityEventKind::Delete, "all", user_deletes) {
+                    audit_log!(audit, "security alert: {:?}", a);
+                }
+            }
+            if let (Ok(_), Some(levels)) = (&res, log_levels) {
+                levels_handle.set(&levels);
+            }
+            // Subscribers only hear about changes that are now visible.
+            if let (Ok(_), Some(bus)) = (&res, change_bus) {
+                if !changes.is_empty() {
+                    bus.publish(changes);
+                }
+            }
+            res
+        } else {
+            Err(OperationError::ConsistencyError(r))
+        }
+        // Audit done
+    }
+}
+
+// Auth requests? How do we structure these ...
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{append_audit_record, AuditLevel, AuditScope};
+    use crate::be::{test_file_path, Backend, BackendTransaction};
+    use crate::clock::MockClock;
+    use crate::constants::{
+        BREAK_GLASS_MAX_LIFETIME, CREDENTIAL_RESET_MAX_ATTEMPTS, DOMAIN_VERSION, JSON_ADMIN_V1,
+        JSON_ANONYMOUS_V1, UUID_ADMIN, UUID_ANONYMOUS, UUID_DOMAIN_INFO, UUID_IDM_ADMINS,
+        UUID_IDM_RADIUS_SERVERS, UUID_SYSTEM_CONFIG,
+    };
+    use crate::csn::Csn;
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced};
+    use crate::error::{OperationError, SchemaError};
+    use crate::event::{
+        AcpCoverageEvent, AuditLogEvent, BackupEvent, BatchEvent, CompareEvent, CreateEvent,
+        CredentialResetIssueEvent, DeleteEvent, DeletePreviewEvent, EffectivePermissionsEvent,
+        Event, GroupJoinCreateEvent, GroupJoinDecideEvent, GroupJoinListEvent,
+        HostSecretRotateEvent, LogLevelEvent, MemoryReportEvent, ModifyEvent,
+        RadiusSecretReadEvent, RadiusSecretRegenerateEvent, RenameEvent, ReplChangesEvent,
+        ReviveRecycledEvent, SearchEvent, SearchPage, SyncEvent, TypeaheadEvent,
+    };
+    use crate::idm::credential::Password;
+    use crate::modify::{Modify, ModifyList};
+    use crate::proto::v1::Entry as ProtoEntry;
+    use crate::proto::v1::Filter as ProtoFilter;
+    use crate::proto::v1::Modify as ProtoModify;
+    use crate::proto::v1::ModifyList as ProtoModifyList;
+    use crate::proto::v1::{
+        AuditRecord, BatchOperation, DeletePreviewGroup, DeleteRequest, LogLevel, MemoryUse,
+        ModifyRequest, ReviveRecycledRequest,
+    };
+    use crate::schema::{IndexType, Schema};
+    use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::Value;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_qs_recovery_pending() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63931"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // A committed change leaves nothing for the next start to check.
+            let pending = server.recovery_pending(audit).expect("recovery failed");
+            assert!(pending.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_qs_create_user() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let filt = filter!(f_eq("name", "testperson"));
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+
+            let se1 = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), filt.clone()) };
+            let se2 = unsafe { SearchEvent::new_impersonate_entry(admin, filt) };
+
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e.clone()]);
+
+            let r1 = server_txn.search(audit, &se1).expect("search failure");
+            assert!(r1.len() == 0);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            let r2 = server_txn.search(audit, &se2).expect("search failure");
+            println!("--> {:?}", r2);
+            assert!(r2.len() == 1);
+
+            let expected = unsafe { vec![e.to_valid_committed()] };
+
+            assert_eq!(r2, expected);
+
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_qs_init_idempotent_schema_core() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            {
+                // Setup and abort.
+                let mut server_txn = server.write();
+                assert!(server_txn.initialise_schema_core(audit).is_ok());
+            }
+            {
+                let mut server_txn = server.write();
+                assert!(server_txn.initialise_schema_core(audit).is_ok());
+                assert!(server_txn.initialise_schema_core(audit).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
+            {
+                // Now do it again in a new txn, but abort
+                let mut server_txn = server.write();
+                assert!(server_txn.initialise_schema_core(audit).is_ok());
+            }
+            {
+                // Now do it again in a new txn.
+                let mut server_txn = server.write();
+                assert!(server_txn.initialise_schema_core(audit).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
+        });
+    }
+
+    #[test]
+    fn test_qs_init_idempotent_helper() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // The server is already initialised, so this only migrates what
+            // exists and creates nothing new.
+            assert!(server.initialise_helper(audit).is_ok());
+            let server_txn = server.read();
+            let admins = server_txn
+                .internal_search(audit, filter!(f_eq("name", "admin")))
+                .expect("search failure");
+            assert!(admins.len() == 1);
+            assert!(server_txn
+                .internal_exists(audit, filter!(f_eq("name", "group_join_request")))
+                .expect("exists failure"));
+        });
+    }
+
+    #[test]
+    fn test_qs_modify() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Create an object
+            let mut server_txn = server.write();
+
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
+                    "description": ["testperson2"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e1.clone(), e2.clone()]);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Empty Modlist (filter is valid)
+            let me_emp = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_pres("class")),
+                    ModifyList::new_list(vec![]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_emp) == Err(OperationError::EmptyRequest));
+
+            // Mod changes no objects
+            let me_nochg = unsafe {
+                ModifyEvent::new_impersonate_entry_ser(
+                    JSON_ADMIN_V1,
+                    filter!(f_eq("name", "flarbalgarble")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("description"),
+                        Value::from("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_nochg) == Err(OperationError::NoMatchingEntries));
+
+            // Filter is invalid to schema - to check this due to changes in the way events are
+            // handled, we put this via the internal modify function to get the modlist
+            // checked for us. Normal server operation doesn't allow weird bypasses like
+            // this.
+            let r_inv_1 = server_txn.internal_modify(
+                audit,
+                filter!(f_eq("tnanuanou", "Flarbalgarble")),
+                ModifyList::new_list(vec![Modify::Present(
+                    String::from("description"),
+                    Value::from("anusaosu"),
+                )]),
+            );
+            assert!(
+                r_inv_1
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttribute("tnanuanou".to_string())
+                    ))
+            );
+
+            // Mod is invalid to schema
+            let me_inv_m = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_pres("class")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("htnaonu"),
+                        Value::from("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(
+                server_txn.modify(audit, &me_inv_m)
+                    == Err(OperationError::EntrySchemaViolation(vec![
+                        SchemaError::InvalidAttribute("htnaonu".to_string())
+                    ]))
+            );
+
+            // Mod single object
+            let me_sin = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson2")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("description"),
+                        Value::from("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_sin).is_ok());
+
+            // Mod multiple object
+            let me_mult = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_or!([
+                        f_eq("name", "testperson1"),
+                        f_eq("name", "testperson2"),
+                    ])),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("description"),
+                        Value::from("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_mult).is_ok());
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_modify_invalid_class() {
+        // Test modifying an entry and adding an extra class, that would cause the entry
+        // to no longer conform to schema.
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e1.clone()]);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Add class but no values
+            let me_sin = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson1")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("class"),
+                        Value::from("system_info"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_sin).is_err());
+
+            // Add multivalue where not valid
+            let me_sin = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson1")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("name"),
+                        Value::from("testpersonx"),
+                    )]),
+                )
+            };
+            assert!(
+                server_txn.modify(audit, &me_sin)
+                    == Err(OperationError::EntrySchemaViolation(vec![
+                        SchemaError::MultipleValues("name".to_string(), 2)
+                    ]))
+            );
+
+            // add class and valid values?
+            let me_sin = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson1")),
+                    ModifyList::new_list(vec![
+                        Modify::Present(String::from("class"), Value::from("system_info")),
+                        Modify::Present(String::from("domain"), Value::from("domain.name")),
+                        Modify::Present(String::from("version"), Value::from("1")),
+                    ]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_sin).is_ok());
+
+            // Replace a value
+            let me_sin = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson1")),
+                    ModifyList::new_list(vec![
+                        Modify::Purged("name".to_string()),
+                        Modify::Present(String::from("name"), Value::from("testpersonx")),
+                    ]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_sin).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_delete() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Create
+            let mut server_txn = server.write();
+
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let e3: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson3"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63933"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson3"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e1.clone(), e2.clone(), e3.clone()]);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Delete filter is syntax invalid
+            let de_inv =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_pres("nhtoaunaoehtnu"))) };
+            assert!(server_txn.delete(audit, &de_inv).is_err());
+
+            // Delete deletes nothing
+            let de_empty = unsafe {
+                DeleteEvent::new_internal_invalid(filter!(f_eq(
+                    "uuid",
+                    "cc8e95b4-c24f-4d68-ba54-000000000000"
+                )))
+            };
+            assert!(server_txn.delete(audit, &de_empty).is_err());
+
+            // Delete matches one
+            let de_sin =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson3"))) };
+            assert!(server_txn.delete(audit, &de_sin).is_ok());
+
+            // Delete matches many
+            let de_mult = unsafe {
+                DeleteEvent::new_internal_invalid(filter!(f_eq("description", "testperson")))
+            };
+            assert!(server_txn.delete(audit, &de_mult).is_ok());
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_tombstone() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+
+            let filt_ts = ProtoFilter::Eq(String::from("class"), String::from("tombstone"));
+
+            let filt_i_ts = filter_all!(f_eq("class", "tombstone"));
+
+            // Create fake external requests. Probably from admin later
+            // Should we do this with impersonate instead of using the external
+            let me_ts = ModifyEvent::from_request(
+                audit,
+                ModifyRequest::new(
+                    filt_ts.clone(),
+                    ProtoModifyList::new_list(vec![ProtoModify::Present(
+                        String::from("class"),
+                        String::from("tombstone"),
+                    )]),
+                    UUID_ADMIN,
+                ),
+                &server_txn,
+            )
+            .expect("modify event create failed");
+            let de_ts = DeleteEvent::from_request(
+                audit,
+                DeleteRequest::new(filt_ts.clone(), UUID_ADMIN),
+                &server_txn,
+            )
+            .expect("delete event create failed");
+            let se_ts = unsafe { SearchEvent::new_ext_impersonate_entry(admin, filt_i_ts.clone()) };
+
+            // First, create a tombstone
+            let e_ts: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["tombstone", "object"],
+                    "uuid": ["9557f49c-97a5-4277-a9a5-097d17eb8317"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e_ts]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Can it be seen (external search)
+            let r1 = server_txn.search(audit, &se_ts).expect("search failed");
+            assert!(r1.len() == 0);
+
+            // Can it be deleted (external delete)
+            // Should be err-no candidates.
+            assert!(server_txn.delete(audit, &de_ts).is_err());
+
+            // Can it be modified? (external modify)
+            // Should be err-no candidates
+            assert!(server_txn.modify(audit, &me_ts).is_err());
+
+            // Can it be seen (internal search)
+            // Internal search should see it.
+            let r2 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r2.len() == 1);
+
+            // Now purge
+            assert!(server_txn
+                .purge_tombstones(audit, Duration::from_secs(0))
+                .is_ok());
+
+            // Assert it's gone
+            // Internal search should not see it.
+            let r3 = server_txn
+                .internal_search(audit, filt_i_ts)
+                .expect("internal search failed");
+            assert!(r3.len() == 0);
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_recycle_simple() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+
+            let filt_rc = ProtoFilter::Eq(String::from("class"), String::from("recycled"));
+
+            let filt_i_rc = filter_all!(f_eq("class", "recycled"));
+
+            let filt_i_ts = filter_all!(f_eq("class", "tombstone"));
+
+            let filt_i_per = filter_all!(f_eq("class", "person"));
+
+            // Create fake external requests. Probably from admin later
+            let me_rc = ModifyEvent::from_request(
+                audit,
+                ModifyRequest::new(
+                    filt_rc.clone(),
+                    ProtoModifyList::new_list(vec![ProtoModify::Present(
+                        String::from("class"),
+                        String::from("recycled"),
+                    )]),
+                    UUID_ADMIN,
+                ),
+                &server_txn,
+            )
+            .expect("modify event create failed");
+            let de_rc = DeleteEvent::from_request(
+                audit,
+                DeleteRequest::new(filt_rc.clone(), UUID_ADMIN),
+                &server_txn,
+            )
+            .expect("delete event create failed");
+            let se_rc =
+                unsafe { SearchEvent::new_ext_impersonate_entry(admin.clone(), filt_i_rc.clone()) };
+
+            let sre_rc =
+                unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_i_rc.clone()) };
+
+            let rre_rc = ReviveRecycledEvent::from_request(
+                audit,
+                ReviveRecycledRequest::new(
+                    ProtoFilter::Eq("name".to_string(), "testperson1".to_string()),
+                    UUID_ADMIN,
+                ),
+                &server_txn,
+            )
+            .expect("revive recycled create failed");
+
+            // Create some recycled objects
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "recycled"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "recycled"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e1, e2]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Can it be seen (external search)
+            let r1 = server_txn.search(audit, &se_rc).expect("search failed");
+            assert!(r1.len() == 0);
+
+            // Can it be deleted (external delete)
+            // Should be err-no candidates.
+            assert!(server_txn.delete(audit, &de_rc).is_err());
+
+            // Can it be modified? (external modify)
+            // Should be err-no candidates
+            assert!(server_txn.modify(audit, &me_rc).is_err());
+
+            // Can in be seen by special search? (external recycle search)
+            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
+            assert!(r2.len() == 2);
+
+            // Can it be seen (internal search)
+            // Internal search should see it.
+            let r2 = server_txn
+                .internal_search(audit, filt_i_rc.clone())
+                .expect("internal search failed");
+            assert!(r2.len() == 2);
+
+            // There are now two options
+            //  revival - which anonymous has no right to do.
+            let rre_anon = ReviveRecycledEvent::from_request(
+                audit,
+                ReviveRecycledRequest::new(
+                    ProtoFilter::Eq("name".to_string(), "testperson1".to_string()),
+                    UUID_ANONYMOUS,
+                ),
+                &server_txn,
+            )
+            .expect("revive recycled create failed");
+            assert!(server_txn.revive_recycled(audit, &rre_anon).is_err());
+            assert!(server_txn.revive_recycled(audit, &rre_rc).is_ok());
+
+            //  purge to tombstone
+            assert!(server_txn
+                .purge_recycled(audit, Duration::from_secs(0))
+                .is_ok());
+
+            // Should be no recycled objects.
+            let r3 = server_txn
+                .internal_search(audit, filt_i_rc.clone())
+                .expect("internal search failed");
+            assert!(r3.len() == 0);
+
+            // There should be one tombstone
+            let r4 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r4.len() == 1);
+
+            // There should be one entry
+            let r5 = server_txn
+                .internal_search(audit, filt_i_per.clone())
+                .expect("internal search failed");
+            assert!(r5.len() == 1);
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_purge_window() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e1]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            let de =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1"))) };
+            assert!(server_txn.delete(audit, &de).is_ok());
+
+            let filt_i_rc = filter_all!(f_eq("class", "recycled"));
+            let filt_i_ts = filter_all!(f_eq("class", "tombstone"));
+            let hour = Duration::from_secs(3600);
+
+            // Only just recycled, so it's kept.
+            assert!(server_txn.purge_recycled(audit, hour).is_ok());
+            let r1 = server_txn
+                .internal_search(audit, filt_i_rc.clone())
+                .expect("internal search failed");
+            assert!(r1.len() == 1);
+
+            assert!(server_txn
+                .purge_recycled(audit, Duration::from_secs(0))
+                .is_ok());
+            let r2 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r2.len() == 1);
+
+            // The tombstone's age starts from when it became one.
+            assert!(server_txn.purge_tombstones(audit, hour).is_ok());
+            let r3 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r3.len() == 1);
+
+            assert!(server_txn
+                .purge_tombstones(audit, Duration::from_secs(0))
+                .is_ok());
+            let r4 = server_txn
+                .internal_search(audit, filt_i_ts)
+                .expect("internal search failed");
+            assert!(r4.len() == 0);
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_modify_batch() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
+                    "description": ["testperson2"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
+            let ce = CreateEvent::new_internal(vec![e1, e2]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // Two changes to one entry, and a different one to another.
+            let batch = vec![
+                (
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+                    ModifyList::new_list(vec![Modify::Purged("description".to_string())]),
+                ),
+                (
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f63932".to_string(),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::from("second"),
+                    )]),
+                ),
+                (
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::from("batched"),
+                    )]),
+                ),
+            ];
+            assert!(server_txn.internal_modify_batch(audit, batch).is_ok());
+
+            let r1 = server_txn
+                .internal_search(audit, filter!(f_eq("description", "batched")))
+                .expect("internal search failed");
+            assert!(r1.len() == 1);
+            assert!(r1[0].get_uuid() == "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+            assert!(!r1[0].attribute_value_pres("description", "testperson1"));
+
+            let r2 = server_txn
+                .internal_search(audit, filter!(f_eq("description", "second")))
+                .expect("internal search failed");
+            assert!(r2.len() == 1);
+            assert!(r2[0].attribute_value_pres("description", "testperson2"));
+
+            // A batch that matches nothing does nothing.
+            let batch = vec![(
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63999".to_string(),
+                ModifyList::new_list(vec![Modify::Purged("description".to_string())]),
+            )];
+            assert!(server_txn.internal_modify_batch(audit, batch).is_ok());
+
+            // Changes the server knows are valid can skip validation.
+            let batch = vec![
+                ("cc8e95b4-c24f-4d68-ba54-8bed76f63932".to_string(), unsafe {
+                    ModifyList::new_list(vec![Modify::Purged("description".to_string())])
+                        .assume_valid(server_txn.get_schema())
+                }),
+            ];
+            assert!(server_txn.internal_modify_batch_valid(audit, batch).is_ok());
+            let f_valid = unsafe {
+                filter!(f_eq("description", "second")).assume_valid(server_txn.get_schema())
+            };
+            let r3 = server_txn
+                .internal_search_valid(audit, f_valid)
+                .expect("internal search failed");
+            assert!(r3.len() == 0);
 
-    pub fn internal_exists_or_create(
-        &self,
-        _e: Entry<EntryValid, EntryNew>,
-    ) -> Result<(), OperationError> {
-        // If the thing exists, stop.
-        // if not, create from Entry.
-        unimplemented!()
+            assert!(server_txn.commit(audit).is_ok());
+        })
     }
 
-    pub fn internal_migrate_or_create_str(
-        &mut self,
-        audit: &mut AuditScope,
-        e_str: &str,
-    ) -> Result<(), OperationError> {
-        let res = audit_segment!(audit, || serde_json::from_str(e_str)
-            .map_err(|_| OperationError::SerdeJsonError)
-            .and_then(
-                |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
-            ));
-        audit_log!(audit, "internal_migrate_or_create_str -> result {:?}", res);
-        assert!(res.is_ok());
-        res
+    #[test]
+    fn test_qs_modify_filter() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                ("testperson1", "cc8e95b4-c24f-4d68-ba54-8bed76f63930"),
+                ("testperson2", "cc8e95b4-c24f-4d68-ba54-8bed76f63932"),
+                ("testperson3", "cc8e95b4-c24f-4d68-ba54-8bed76f63934"),
+            ]
+            .into_iter()
+            .map(|(name, uuid)| {
+                serde_json::from_str(&format!(
+                    r#"{{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "person"],
+                        "name": ["{}"],
+                        "uuid": ["{}"],
+                        "displayname": ["{}"]
+                    }}
+                }}"#,
+                    name, uuid, name
+                ))
+                .expect("json failure")
+            })
+            .collect();
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // The modlist stops the filter matching, but each entry is still
+            // changed once, over several batches.
+            let n = server_txn
+                .internal_modify_filter_batched(
+                    audit,
+                    filter!(f_and!([
+                        f_eq("class", "person"),
+                        f_andnot(f_pres("description"))
+                    ])),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::from("bulk"),
+                    )]),
+                    2,
+                )
+                .expect("modify failed");
+            assert!(n == 3);
+            let r1 = server_txn
+                .internal_search(audit, filter!(f_eq("description", "bulk")))
+                .expect("internal search failed");
+            assert!(r1.len() == 3);
+
+            // Nothing matching is not an error.
+            let n = server_txn
+                .internal_modify_filter(
+                    audit,
+                    filter!(f_eq("description", "none")),
+                    ModifyList::new_list(vec![Modify::Purged("description".to_string())]),
+                )
+                .expect("modify failed");
+            assert!(n == 0);
+
+            // An invalid modlist is refused before anything is changed.
+            assert!(server_txn
+                .internal_modify_filter(
+                    audit,
+                    filter!(f_eq("class", "person")),
+                    ModifyList::new_list(vec![Modify::Purged("not_an_attr".to_string())]),
+                )
+                .is_err());
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
     }
 
-    pub fn internal_migrate_or_create(
-        &mut self,
-        audit: &mut AuditScope,
-        e: Entry<EntryValid, EntryNew>,
-    ) -> Result<(), OperationError> {
-        // if the thing exists, ensure the set of attributes on
-        // Entry A match and are present (but don't delete multivalue, or extended
-        // attributes in the situation.
-        // If not exist, create from Entry B
-        //
-        // This will extra classes an attributes alone!
-        //
-        // NOTE: gen modlist IS schema aware and will handle multivalue
-        // correctly!
-        let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
-            Some(f) => f,
-            None => return Err(OperationError::FilterGeneration),
-        };
+    // Only debug builds check, so there is nothing to catch this otherwise.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_qs_assume_valid_unnormalised() {
+        run_test!(|server: &QueryServer, _audit: &mut AuditScope| {
+            let server_txn = server.write();
+            // Attribute names are normalised to lowercase, so this isn't valid
+            // as it stands - debug builds must catch that.
+            let _ =
+                unsafe { filter!(f_eq("Description", "x")).assume_valid(server_txn.get_schema()) };
+        })
+    }
 
-        match self.internal_search(audit, filt.clone()) {
-            Ok(results) => {
-                if results.len() == 0 {
-                    // It does not exist. Create it.
-                    self.internal_create(audit, vec![e.invalidate()])
-                } else if results.len() == 1 {
-                    // If the thing is subset, pass
-                    match e.gen_modlist_assert(&self.schema) {
-                        Ok(modlist) => {
-                            // Apply to &results[0]
-                            audit_log!(audit, "Generated modlist -> {:?}", modlist);
-                            self.internal_modify(audit, filt, modlist)
-                        }
-                        Err(_e) => {
-                            unimplemented!()
-                            // No action required.
-                        }
-                    }
-                } else {
-                    Err(OperationError::InvalidDBState)
-                }
-            }
-            Err(e) => {
-                // An error occured. pass it back up.
-                Err(e)
+    #[test]
+    fn test_qs_clock() {
+        let mut audit = AuditScope::new("test_qs_clock");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_000_000)));
+        let mut server = QueryServer::new(be, schema);
+        server.set_clock(clock.clone());
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let mut server_txn = server.write();
+        let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "person"],
+                "name": ["testperson1"],
+                "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                "description": ["testperson"],
+                "displayname": ["testperson1"]
             }
-        }
+        }"#,
+        )
+        .expect("json failure");
+        let ce = CreateEvent::new_internal(vec![e1]);
+        assert!(server_txn.create(&mut audit, &ce).is_ok());
+        let de = unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1"))) };
+        assert!(server_txn.delete(&mut audit, &de).is_ok());
+
+        // The change is stamped with the mock time, not the system's.
+        let r1 = server_txn
+            .internal_search(&mut audit, filter_all!(f_eq("class", "recycled")))
+            .expect("internal search failed");
+        assert!(r1.len() == 1);
+        let csn = Csn::new(Duration::from_secs(1_000_000), server.be.replica_id());
+        assert!(r1[0].last_modified() == Some(&csn));
+        assert!(server_txn.commit(&mut audit).is_ok());
+
+        // Not yet old enough to purge.
+        let hour = Duration::from_secs(3600);
+        let server_txn = server.write();
+        assert!(server_txn.purge_recycled(&mut audit, hour).is_ok());
+        let r2 = server_txn
+            .internal_search(&mut audit, filter_all!(f_eq("class", "recycled")))
+            .expect("internal search failed");
+        assert!(r2.len() == 1);
+        assert!(server_txn.commit(&mut audit).is_ok());
+
+        // Once the window has passed, the next transaction purges it.
+        clock.advance(Duration::from_secs(3601));
+        let server_txn = server.write();
+        assert!(server_txn.purge_recycled(&mut audit, hour).is_ok());
+        let r3 = server_txn
+            .internal_search(&mut audit, filter_all!(f_eq("class", "recycled")))
+            .expect("internal search failed");
+        assert!(r3.len() == 0);
+        assert!(server_txn.commit(&mut audit).is_ok());
+        println!("{}", audit);
     }
 
-    // Should this take a be_txn?
-    pub fn internal_assert_or_create(
-        &mut self,
-        audit: &mut AuditScope,
-        e: Entry<EntryValid, EntryNew>,
-    ) -> Result<(), OperationError> {
-        // If exists, ensure the object is exactly as provided
-        // else, if not exists, create it. IE no extra or excess
-        // attributes and classes.
+    #[test]
+    fn test_qs_filter_limits_client_only() {
+        use crate::filter::{Filter, FilterInvalid, FilterLimits};
+
+        let mut audit = AuditScope::new("test_qs_filter_limits_client_only");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let mut server = QueryServer::new(be, schema);
+        server.set_filter_limits(FilterLimits {
+            max_depth: 1,
+            max_terms: 1,
+        });
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        // The filters of an access control aren't a client's, so one past
+        // the limits is still loaded, and reloaded after each change.
+        let mut server_txn = server.write();
+        let acp: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "access_control_profile", "access_control_search"],
+                "name": ["deep_acp_search"],
+                "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639f2"],
+                "description": ["Filters past the client limits."],
+                "acp_enable": ["true"],
+                "acp_receiver": ["{\"And\":[{\"Pres\":\"class\"},{\"Pres\":\"name\"}]}"],
+                "acp_targetscope": ["{\"And\":[{\"Pres\":\"class\"},{\"Pres\":\"name\"}]}"],
+                "acp_search_attr": ["name"]
+            }
+        }"#,
+        )
+        .expect("json failure");
+        assert!(server_txn.internal_create(&mut audit, vec![acp]).is_ok());
+        assert!(server_txn.commit(&mut audit).is_ok());
+
+        let server_txn = server.write();
+        let pf = ProtoFilter::And(vec![
+            ProtoFilter::Pres("class".to_string()),
+            ProtoFilter::Pres("name".to_string()),
+        ]);
+        let f: Result<Filter<FilterInvalid>, _> = Filter::from_rw(&mut audit, &pf, &server_txn);
+        assert!(f.is_ok());
+        let f: Result<Filter<FilterInvalid>, _> =
+            Filter::from_rw_client(&mut audit, &pf, &server_txn);
+        assert!(f.err() == Some(OperationError::FilterTooComplex));
+        println!("{}", audit);
+    }
 
-        // Create a filter from the entry for assertion.
-        let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
-            Some(f) => f,
-            None => return Err(OperationError::FilterGeneration),
+    #[test]
+    fn test_qs_account_validity() {
+        let mut audit = AuditScope::new("test_qs_account_validity");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_000_000)));
+        let mut server = QueryServer::new(be, schema);
+        server.set_clock(clock.clone());
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let at = |secs: u64| {
+            chrono::DateTime::<chrono::Utc>::from(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+                .to_rfc3339()
+        };
+        let account = |name: &str, uuid: &str, attr: &str, when: &str| {
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                format!(
+                    r#"{{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "account"],
+                            "name": ["{}"],
+                            "uuid": ["{}"],
+                            "displayname": ["{}"],
+                            "{}": ["{}"]
+                        }}
+                    }}"#,
+                    name, uuid, name, attr, when
+                )
+                .as_str(),
+            )
+            .expect("json failure");
+            e
         };
+        let expiring = "cc8e95b4-c24f-4d68-ba54-8bed76f639f1";
+        let pending = "cc8e95b4-c24f-4d68-ba54-8bed76f639f2";
+
+        let mut server_txn = server.write();
+        assert!(server_txn
+            .internal_create(
+                &mut audit,
+                vec![
+                    account("ev_expiring", expiring, "account_expire", &at(1_003_600)),
+                    account("ev_pending", pending, "account_valid_from", &at(1_003_600)),
+                ]
+            )
+            .is_ok());
+        // Until it expires, the account may act.
+        assert!(Event::from_rw_request(&mut audit, &server_txn, expiring).is_ok());
+        assert!(
+            Event::from_rw_request(&mut audit, &server_txn, pending).err()
+                == Some(OperationError::NotAuthenticated)
+        );
+        assert!(server_txn.flag_expired_accounts(&mut audit) == Ok(0));
+        assert!(server_txn.commit(&mut audit).is_ok());
 
-        // Does it exist? we use search here, not exists, so that if the entry does exist
-        // we can compare it is identical, which avoids a delete/create cycle that would
-        // trigger csn/repl each time we start up.
-        match self.internal_search(audit, filt.clone()) {
-            Ok(results) => {
-                if results.len() == 0 {
-                    // It does not exist. Create it.
-                    self.internal_create(audit, vec![e.invalidate()])
-                } else if results.len() == 1 {
-                    // it exists. To guarantee content exactly as is, we compare if it's identical.
-                    if !e.compare(&results[0]) {
-                        self.internal_delete(audit, filt)
-                            .and_then(|_| self.internal_create(audit, vec![e.invalidate()]))
-                    } else {
-                        // No action required
-                        Ok(())
-                    }
-                } else {
-                    Err(OperationError::InvalidDBState)
-                }
-            }
-            Err(er) => {
-                // An error occured. pass it back up.
-                Err(er)
-            }
+        // Once it has, it can't, and it's flagged - only once.
+        clock.advance(Duration::from_secs(3600));
+        {
+            let server_txn = server.read();
+            assert!(
+                Event::from_ro_request(&mut audit, &server_txn, expiring).err()
+                    == Some(OperationError::NotAuthenticated)
+            );
+            assert!(Event::from_ro_request(&mut audit, &server_txn, pending).is_ok());
         }
+        let mut server_txn = server.write();
+        assert!(server_txn.flag_expired_accounts(&mut audit) == Ok(1));
+        assert!(server_txn.flag_expired_accounts(&mut audit) == Ok(0));
+        let e = server_txn
+            .internal_search_uuid(&mut audit, expiring)
+            .expect("search failed");
+        assert!(e.get_ava_single_bool("account_expired") == Some(true));
+
+        // Extending the account clears the flag.
+        let ml = ModifyList::new_list(vec![
+            Modify::Purged("account_expire".to_string()),
+            Modify::Present(
+                "account_expire".to_string(),
+                Value::from(at(1_010_000).as_str()),
+            ),
+        ]);
+        assert!(server_txn
+            .internal_modify(&mut audit, filter!(f_eq("uuid", expiring)), ml)
+            .is_ok());
+        assert!(server_txn.flag_expired_accounts(&mut audit) == Ok(1));
+        let e = server_txn
+            .internal_search_uuid(&mut audit, expiring)
+            .expect("search failed");
+        assert!(!e.attribute_pres("account_expired"));
+        assert!(Event::from_rw_request(&mut audit, &server_txn, expiring).is_ok());
+        assert!(server_txn.commit(&mut audit).is_ok());
+        println!("{}", audit);
     }
 
-    pub fn initialise_schema_core(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // Load in all the "core" schema, that we already have in "memory".
-        let entries = self.schema.to_entries();
+    #[test]
+    fn test_qs_anon_search_limits() {
+        use crate::filter::{Filter, FilterInvalid};
+
+        let mut audit = AuditScope::new("test_qs_anon_search_limits");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init BE");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_000_000)));
+        let mut server = QueryServer::new(be, schema);
+        server.set_clock(clock.clone());
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let mut server_txn = server.write();
+        let anon = server_txn
+            .internal_search_uuid(&mut audit, UUID_ANONYMOUS)
+            .expect("failed");
+        let admin = server_txn
+            .internal_search_uuid(&mut audit, UUID_ADMIN)
+            .expect("failed");
+        let se_anon = |f: Filter<FilterInvalid>| unsafe {
+            SearchEvent::new_ext_impersonate_entry(anon.clone(), f)
+        };
 
-        // internal_migrate_or_create.
-        let r: Result<_, _> = entries
-            .into_iter()
-            .map(|e| {
-                audit_log!(
-                    audit,
-                    "init schema -> {}",
-                    serde_json::to_string_pretty(&e).unwrap()
-                );
-                self.internal_migrate_or_create(audit, e)
-            })
-            .collect();
-        assert!(r.is_ok());
-        r
-    }
+        // By default substrings are refused before they cost anything.
+        assert!(
+            server_txn
+                .search_ext(&mut audit, &se_anon(filter_all!(f_sub("name", "anon"))))
+                .err()
+                == Some(OperationError::SubstringNotPermitted)
+        );
 
-    pub fn initialise_schema_idm(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // List of IDM schemas to init.
-        let idm_schema: Vec<&str> = vec![
-            JSON_SCHEMA_ATTR_DISPLAYNAME,
-            JSON_SCHEMA_ATTR_MAIL,
-            JSON_SCHEMA_ATTR_SSH_PUBLICKEY,
-            JSON_SCHEMA_ATTR_PASSWORD,
-            JSON_SCHEMA_CLASS_PERSON,
-            JSON_SCHEMA_CLASS_GROUP,
-            JSON_SCHEMA_CLASS_ACCOUNT,
-        ];
+        let config = |txn: &mut QueryServerWriteTransaction, audit: &mut AuditScope, attr, v| {
+            let ml = ModifyList::new_list(vec![
+                Modify::Purged(String::from(attr)),
+                Modify::Present(String::from(attr), Value::from(v)),
+            ]);
+            assert!(txn
+                .internal_modify(audit, filter_all!(f_eq("uuid", UUID_SYSTEM_CONFIG)), ml)
+                .is_ok());
+        };
+        config(
+            &mut server_txn,
+            &mut audit,
+            "anon_search_allow_substring",
+            "true",
+        );
+        config(&mut server_txn, &mut audit, "anon_search_max_ops", "2");
+
+        let r1 = server_txn
+            .search_ext(&mut audit, &se_anon(filter_all!(f_sub("name", "anon"))))
+            .expect("search failed");
+        assert!(r1.len() == 1);
+        let r2 = server_txn
+            .search_ext(&mut audit, &se_anon(filter_all!(f_eq("name", "anonymous"))))
+            .expect("search failed");
+        assert!(r2.len() == 1);
+        assert!(
+            server_txn
+                .search_ext(&mut audit, &se_anon(filter_all!(f_eq("name", "anonymous"))))
+                .err()
+                == Some(OperationError::RateLimited)
+        );
 
-        let mut audit_si = AuditScope::new("start_initialise_schema_idm");
-        let r: Result<Vec<()>, _> = idm_schema
-            .iter()
-            // Each item individually logs it's result
-            .map(|e_str| self.internal_migrate_or_create_str(&mut audit_si, e_str))
-            .collect();
-        audit.append_scope(audit_si);
-        assert!(r.is_ok());
+        // Each source has its own share.
+        let mut se_other = se_anon(filter_all!(f_eq("name", "anonymous")));
+        se_other.source = Some("192.0.2.1".to_string());
+        assert!(server_txn.search_ext(&mut audit, &se_other).is_ok());
 
-        r.map(|_| ())
+        // Others aren't held to any of this.
+        let se_admin = unsafe {
+            SearchEvent::new_ext_impersonate_entry(admin, filter_all!(f_sub("name", "anon")))
+        };
+        assert!(server_txn.search_ext(&mut audit, &se_admin).is_ok());
+
+        // A minute later there is room again, but nothing may be returned.
+        clock.advance(Duration::from_secs(60));
+        config(&mut server_txn, &mut audit, "anon_search_max_results", "0");
+        assert!(
+            server_txn
+                .search_ext(&mut audit, &se_anon(filter_all!(f_eq("name", "anonymous"))))
+                .err()
+                == Some(OperationError::SizeLimitExceeded(0))
+        );
+        assert!(server_txn.commit(&mut audit).is_ok());
     }
 
-    // This function is idempotent
-    pub fn initialise_idm(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // First, check the system_info object. This stores some server information
-        // and details. It's a pretty static thing.
-        let mut audit_si = AuditScope::new("start_system_info");
-        let res = audit_segment!(audit_si, || serde_json::from_str(JSON_SYSTEM_INFO_V1)
-            .map_err(|_| OperationError::SerdeJsonError)
-            .and_then(
-                |e: Entry<EntryValid, EntryNew>| self.internal_assert_or_create(audit, e)
-            ));
-        audit_log!(audit_si, "start_system_info -> result {:?}", res);
-        audit.append_scope(audit_si);
-        assert!(res.is_ok());
-        if res.is_err() {
-            return res;
-        }
-
-        // Check the anonymous object exists (migrations).
-        let mut audit_an = AuditScope::new("start_anonymous");
-        let res = audit_segment!(audit_an, || serde_json::from_str(JSON_ANONYMOUS_V1)
-            .map_err(|_| OperationError::SerdeJsonError)
-            .and_then(
-                |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
-            ));
-        audit_log!(audit_an, "start_anonymous -> result {:?}", res);
-        audit.append_scope(audit_an);
-        assert!(res.is_ok());
-        if res.is_err() {
-            return res;
-        }
+    #[test]
+    fn test_qs_search_paging() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
 
-        // Check the admin object exists (migrations).
-        // Create the default idm_admin group.
-        let mut audit_an = AuditScope::new("start_idm_admin_migrations");
-        let res = self
-            .internal_migrate_or_create_str(&mut audit_an, JSON_ADMIN_V1)
-            .and_then(|_| self.internal_migrate_or_create_str(&mut audit_an, JSON_IDM_ADMINS_V1));
-        audit.append_scope(audit_an);
-        if res.is_err() {
-            return res;
-        }
+            // Descriptions out of name order, and one without.
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                ("pg_a", Some("d")),
+                ("pg_b", Some("b")),
+                ("pg_c", None),
+                ("pg_d", Some("a")),
+                ("pg_e", Some("c")),
+            ]
+            .into_iter()
+            .map(|(name, desc)| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", "object");
+                e.add_ava("class", "person");
+                e.add_ava("name", name);
+                e.add_ava("displayname", name);
+                if let Some(d) = desc {
+                    e.add_ava("description", d);
+                }
+                e
+            })
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            // Only what can be read has a say in the order.
+            let acp: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["paging_acp_search"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639f1"],
+                    "description": ["Everyone can read the names and descriptions."],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Pres\":\"class\"}"],
+                    "acp_targetscope": ["{\"Pres\":\"class\"}"],
+                    "acp_search_attr": ["name", "description"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            assert!(server_txn.internal_create(audit, vec![acp]).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            let mut server_txn = server.write();
 
-        // Create any system default schema entries.
+            let names = |es: &Vec<Entry<EntryReduced, EntryCommitted>>| -> Vec<String> {
+                es.iter()
+                    .map(|e| e.get_ava_single("name").expect("no name").to_string())
+                    .collect()
+            };
+            let se_with = |page: Option<SearchPage>| {
+                let mut se = unsafe {
+                    SearchEvent::new_ext_impersonate_entry(
+                        admin.clone(),
+                        filter_all!(f_sub("name", "pg_")),
+                    )
+                };
+                se.sort = Some(String::from("description"));
+                se.page = page;
+                se
+            };
 
-        // Create any system default access profile entries.
-        let mut audit_an = AuditScope::new("start_idm_migrations_internal");
-        let res = self
-            .internal_migrate_or_create_str(&mut audit_an, JSON_IDM_ADMINS_ACP_SEARCH_V1)
-            .and_then(|_| {
-                self.internal_migrate_or_create_str(&mut audit_an, JSON_IDM_ADMINS_ACP_REVIVE_V1)
-            })
-            .and_then(|_| {
-                self.internal_migrate_or_create_str(&mut audit_an, JSON_IDM_SELF_ACP_READ_V1)
+            let (all, cookie) = server_txn
+                .search_ext_page(audit, &se_with(None))
+                .expect("search failed");
+            assert!(names(&all) == vec!["pg_d", "pg_b", "pg_e", "pg_a", "pg_c"]);
+            assert!(cookie.is_none());
+
+            // Walk it two at a time, following the cookies.
+            let mut seen = Vec::new();
+            let mut page = Some(SearchPage {
+                after: None,
+                size: 2,
             });
-        audit.append_scope(audit_an);
-        if res.is_err() {
-            return res;
-        }
+            while let Some(p) = page {
+                let (es, cookie) = server_txn
+                    .search_ext_page(audit, &se_with(Some(p)))
+                    .expect("search failed");
+                assert!(es.len() <= 2);
+                seen.extend(names(&es));
+                page = cookie.map(|c| SearchPage {
+                    after: Some(serde_json::from_str(c.as_str()).expect("bad cookie")),
+                    size: 2,
+                });
+            }
+            assert!(seen == names(&all));
 
-        Ok(())
+            // A limit on the event is a limit on the whole result.
+            let mut se = se_with(None);
+            se.size_limit = Some(4);
+            assert!(
+                server_txn.search_ext_page(audit, &se).err()
+                    == Some(OperationError::SizeLimitExceeded(4))
+            );
+
+            // An entry added behind the walk doesn't move the rest along, as
+            // the cookie is where the last page ended, not a count.
+            let (first, cookie) = server_txn
+                .search_ext_page(
+                    audit,
+                    &se_with(Some(SearchPage {
+                        after: None,
+                        size: 2,
+                    })),
+                )
+                .expect("search failed");
+            assert!(names(&first) == vec!["pg_d", "pg_b"]);
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("class", "object");
+            e.add_ava("class", "person");
+            e.add_ava("name", "pg_f");
+            e.add_ava("displayname", "pg_f");
+            e.add_ava("description", "0");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            let se_with = |page: Option<SearchPage>| {
+                let mut se = unsafe {
+                    SearchEvent::new_ext_impersonate_entry(
+                        admin.clone(),
+                        filter_all!(f_sub("name", "pg_")),
+                    )
+                };
+                se.sort = Some(String::from("description"));
+                se.page = page;
+                se
+            };
+            let (next, _) = server_txn
+                .search_ext_page(
+                    audit,
+                    &se_with(Some(SearchPage {
+                        after: Some(
+                            serde_json::from_str(cookie.expect("no cookie").as_str())
+                                .expect("bad cookie"),
+                        ),
+                        size: 2,
+                    })),
+                )
+                .expect("search failed");
+            assert!(names(&next) == vec!["pg_e", "pg_a"]);
+        })
     }
 
-    fn reload_schema(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // supply entries to the writable schema to reload from.
-        // find all attributes.
-        let filt = filter!(f_eq("class", "attributetype"));
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        // load them.
-        let attributetypes: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| SchemaAttribute::try_from(audit, e))
-            .collect();
-        let attributetypes = try_audit!(audit, attributetypes);
-
-        try_audit!(audit, self.schema.update_attributes(attributetypes));
+    // The delete test above should be unaffected by recycle anyway
+    #[test]
+    fn test_qs_recycle_advanced() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Create items
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
 
-        // find all classes
-        let filt = filter!(f_eq("class", "classtype"));
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        // load them.
-        let classtypes: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| SchemaClass::try_from(audit, e))
-            .collect();
-        let classtypes = try_audit!(audit, classtypes);
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e1]);
 
-        try_audit!(audit, self.schema.update_classes(classtypes));
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+            // Delete and ensure they became recycled.
+            let de_sin =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1"))) };
+            assert!(server_txn.delete(audit, &de_sin).is_ok());
+            // Can in be seen by special search? (external recycle search)
+            let filt_rc = filter_all!(f_eq("class", "recycled"));
+            let sre_rc = unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_rc.clone()) };
+            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
+            assert!(r2.len() == 1);
 
-        // validate.
-        let valid_r = self.schema.validate(audit);
+            // Create dup uuid (rej)
+            // After a delete -> recycle, create duplicate name etc.
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_err());
 
-        // Translate the result.
-        if valid_r.len() == 0 {
-            Ok(())
-        } else {
-            // Log the failures?
-            unimplemented!();
-        }
+            assert!(server_txn.commit(audit).is_ok());
+        })
     }
 
-    fn reload_accesscontrols(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // supply entries to the writable access controls to reload from.
-        // This has to be done in FOUR passes - one for each type!
-        //
-        // Note, we have to do the search, parse, then submit here, because of the
-        // requirement to have the write query server reference in the parse stage - this
-        // would cause a rust double-borrow if we had AccessControls to try to handle
-        // the entry lists themself.
+    #[test]
+    fn test_qs_name_to_uuid() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
 
-        // Update search
-        let filt = filter!(f_and!([
-            f_eq("class", "access_control_profile"),
-            f_eq("class", "access_control_search"),
-            f_eq("acp_enable", "true"),
-        ]));
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+                }"#,
+            )
+            .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e1]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
 
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        let search_acps: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| AccessControlSearch::try_from(audit, self, e))
-            .collect();
+            // Name doesn't exist
+            let r1 = server_txn.name_to_uuid(audit, &String::from("testpers"));
+            assert!(r1.is_err());
+            // Name doesn't exist (not syntax normalised)
+            let r2 = server_txn.name_to_uuid(audit, &String::from("tEsTpErS"));
+            assert!(r2.is_err());
+            // Name does exist
+            let r3 = server_txn.name_to_uuid(audit, &String::from("testperson1"));
+            assert!(r3.is_ok());
+            // Name is not syntax normalised (but exists)
+            let r4 = server_txn.name_to_uuid(audit, &String::from("tEsTpErSoN1"));
+            assert!(r4.is_ok());
+        })
+    }
 
-        let search_acps = try_audit!(audit, search_acps);
+    #[test]
+    fn test_qs_uuid_to_name() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
 
-        try_audit!(audit, self.accesscontrols.update_search(search_acps));
-        // Update create
-        let filt = filter!(f_and!([
-            f_eq("class", "access_control_profile"),
-            f_eq("class", "access_control_create"),
-            f_eq("acp_enable", "true"),
-        ]));
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e1]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
 
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        let create_acps: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| AccessControlCreate::try_from(audit, self, e))
-            .collect();
+            // Name doesn't exist
+            let r1 = server_txn
+                .uuid_to_name(audit, &String::from("bae3f507-e6c3-44ba-ad01-f8ff1083534a"));
+            assert!(r1.is_err());
+            // Name doesn't exist (not syntax normalised)
+            let r2 = server_txn.uuid_to_name(audit, &String::from("bae3f507-e6c3-44ba-ad01"));
+            assert!(r2.is_err());
+            // Name does exist
+            let r3 = server_txn
+                .uuid_to_name(audit, &String::from("cc8e95b4-c24f-4d68-ba54-8bed76f63930"));
+            assert!(r3.is_ok());
+            // Name is not syntax normalised (but exists)
+            let r4 = server_txn
+                .uuid_to_name(audit, &String::from("CC8E95B4-C24F-4D68-BA54-8BED76F63930"));
+            assert!(r4.is_ok());
+        })
+    }
 
-        let create_acps = try_audit!(audit, create_acps);
+    #[test]
+    fn test_qs_clone_value() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e1]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
 
-        try_audit!(audit, self.accesscontrols.update_create(create_acps));
-        // Update modify
-        let filt = filter!(f_and!([
-            f_eq("class", "access_control_profile"),
-            f_eq("class", "access_control_modify"),
-            f_eq("acp_enable", "true"),
-        ]));
+            // test attr not exist
+            let r1 =
+                server_txn.clone_value(audit, &"tausau".to_string(), &"naoeutnhaou".to_string());
 
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        let modify_acps: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| AccessControlModify::try_from(audit, self, e))
-            .collect();
+            assert!(r1 == Ok("naoeutnhaou".to_string()));
 
-        let modify_acps = try_audit!(audit, modify_acps);
+            // test attr not-normalised
+            // test attr not-reference
+            let r2 = server_txn.clone_value(audit, &"NaMe".to_string(), &"NaMe".to_string());
 
-        try_audit!(audit, self.accesscontrols.update_modify(modify_acps));
-        // Update delete
-        let filt = filter!(f_and!([
-            f_eq("class", "access_control_profile"),
-            f_eq("class", "access_control_delete"),
-            f_eq("acp_enable", "true"),
-        ]));
+            assert!(r2 == Ok("name".to_string()));
 
-        let res = try_audit!(audit, self.internal_search(audit, filt));
-        let delete_acps: Result<Vec<_>, _> = res
-            .iter()
-            .map(|e| AccessControlDelete::try_from(audit, self, e))
-            .collect();
+            // test value invalid for the syntax
+            let r2b = server_txn.clone_value(audit, &"uuid".to_string(), &"zzzz".to_string());
 
-        let delete_acps = try_audit!(audit, delete_acps);
+            assert!(
+                r2b == Err(OperationError::SchemaViolation(SchemaError::InvalidValue(
+                    "uuid".to_string(),
+                    "zzzz".to_string()
+                )))
+            );
 
-        try_audit!(audit, self.accesscontrols.update_delete(delete_acps));
-        // Alternately, we just get ACP class, and just let acctrl work it out ...
-        Ok(())
-    }
+            // test attr reference
+            let r3 =
+                server_txn.clone_value(audit, &"member".to_string(), &"testperson1".to_string());
 
-    pub fn commit(mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
-        // This could be faster if we cache the set of classes changed
-        // in an operation so we can check if we need to do the reload or not
-        //
-        // Reload the schema from qs.
-        if self.changed_schema {
-            self.reload_schema(audit)?;
-        }
-        // Determine if we need to update access control profiles
-        // based on any modifications that have occured.
-        // IF SCHEMA CHANGED WE MUST ALSO RELOAD!!! IE if schema had an attr removed
-        // that we rely on we MUST fail this here!!
-        if self.changed_schema || self.changed_acp {
-            self.reload_accesscontrols(audit)?;
-        }
+            assert!(r3 == Ok("cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()));
 
-        // Now destructure the transaction ready to reset it.
-        let QueryServerWriteTransaction {
-            committed,
-            be_txn,
-            schema,
-            accesscontrols,
-            changed_schema: _,
-            changed_acp: _,
-        } = self;
-        assert!(!committed);
-        // Begin an audit.
-        // Validate the schema as we just loaded it.
-        let r = schema.validate(audit);
+            // test attr reference already resolved.
+            let r4 = server_txn.clone_value(
+                audit,
+                &"member".to_string(),
+                &"cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+            );
 
-        if r.len() == 0 {
-            // Schema has been validated, so we can go ahead and commit it with the be
-            // because both are consistent.
-            schema
-                .commit()
-                .and_then(|_| accesscontrols.commit().and_then(|_| be_txn.commit()))
-        } else {
-            Err(OperationError::ConsistencyError(r))
-        }
-        // Audit done
+            println!("{:?}", r4);
+            assert!(r4 == Ok("cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()));
+        })
     }
-}
 
-// Auth requests? How do we structure these ...
+    #[test]
+    fn test_qs_domain_info() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let di = server.read().get_domain_info().clone();
+            assert!(di.name == "localhost");
+            assert!(di.version == DOMAIN_VERSION);
+            assert!(Uuid::parse_str(di.uuid.as_str()).is_ok());
 
-#[cfg(test)]
-mod tests {
-    use crate::constants::{JSON_ADMIN_V1, UUID_ADMIN};
-    use crate::entry::{Entry, EntryInvalid, EntryNew};
-    use crate::error::{OperationError, SchemaError};
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, ReviveRecycledEvent, SearchEvent};
-    use crate::modify::{Modify, ModifyList};
-    use crate::proto::v1::Filter as ProtoFilter;
-    use crate::proto::v1::Modify as ProtoModify;
-    use crate::proto::v1::ModifyList as ProtoModifyList;
-    use crate::proto::v1::{DeleteRequest, ModifyRequest, ReviveRecycledRequest};
-    use crate::server::QueryServerTransaction;
+            // Changes to the entry are seen once they're committed.
+            let mut server_txn = server.write();
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter_all!(f_eq("uuid", UUID_DOMAIN_INFO)),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("domain_feature"),
+                        Value::from("Example"),
+                    )]),
+                )
+                .is_ok());
+            assert!(!server_txn.get_domain_info().features.contains("example"));
+            assert!(server_txn.commit(audit).is_ok());
+            assert!(server.read().get_domain_info().features.contains("example"));
+
+            // Initialising again keeps the domain as it is.
+            assert!(server.initialise_helper(audit).is_ok());
+            assert!(server.read().get_domain_info().uuid == di.uuid);
+        })
+    }
 
     #[test]
-    fn test_qs_create_user() {
+    fn test_qs_proto_normalisation() {
+        use crate::filter::{Filter, FilterInvalid};
+
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
-            let filt = filter!(f_eq("name", "testperson"));
-            let admin = server_txn
-                .internal_search_uuid(audit, UUID_ADMIN)
-                .expect("failed");
+            let server_txn = server.write();
 
-            let se1 = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), filt.clone()) };
-            let se2 = unsafe { SearchEvent::new_impersonate_entry(admin, filt) };
+            // Names and values are normalised as they come in.
+            let pe: ProtoEntry = serde_json::from_str(
+                r#"{
+                "attrs": {
+                    "objectClass": ["Object", "Person"],
+                    "Name": ["TestPerson1"],
+                    "uuid": ["CC8E95B4-C24F-4D68-BA54-8BED76F63930"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let e = Entry::from_proto_entry(audit, &pe, &server_txn).expect("invalid entry");
+            assert!(e.attribute_value_pres("objectClass", "person"));
+            assert!(e.attribute_value_pres("Name", "testperson1"));
+            assert!(e.attribute_value_pres("uuid", "cc8e95b4-c24f-4d68-ba54-8bed76f63930"));
 
-            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            // Every bad attribute and value is reported at once.
+            let pe_bad: ProtoEntry = serde_json::from_str(
                 r#"{
-                "valid": null,
-                "state": null,
                 "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson"]
+                    "class": ["object"],
+                    "nonexist": ["a"],
+                    "multivalue": ["maybe"],
+                    "uuid": ["zzzz"]
                 }
             }"#,
             )
             .expect("json failure");
+            assert!(
+                Entry::from_proto_entry(audit, &pe_bad, &server_txn)
+                    == Err(OperationError::EntrySchemaViolation(vec![
+                        SchemaError::InvalidValue("multivalue".to_string(), "maybe".to_string()),
+                        SchemaError::InvalidAttribute("nonexist".to_string()),
+                        SchemaError::InvalidValue("uuid".to_string(), "zzzz".to_string()),
+                    ]))
+            );
 
-            let ce = CreateEvent::new_internal(vec![e.clone()]);
+            // Filters are refused before they are used.
+            let pf = ProtoFilter::Eq("uuid".to_string(), "zzzz".to_string());
+            let f: Result<Filter<FilterInvalid>, _> = Filter::from_rw(audit, &pf, &server_txn);
+            assert!(
+                f.err()
+                    == Some(OperationError::SchemaViolation(SchemaError::InvalidValue(
+                        "uuid".to_string(),
+                        "zzzz".to_string()
+                    )))
+            );
+        })
+    }
 
-            let r1 = server_txn.search(audit, &se1).expect("search failure");
-            assert!(r1.len() == 0);
+    #[test]
+    fn test_qs_dynamic_schema_class() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "testclass"],
+                    "name": ["testobj1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                }
+            }"#,
+            )
+            .expect("json failure");
 
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            // Class definition
+            let e_cd: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "classtype"],
+                    "name": ["testclass"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
+                    "description": ["Test Class"]
+                }
+            }"#,
+            )
+            .expect("json failure");
 
-            let r2 = server_txn.search(audit, &se2).expect("search failure");
-            println!("--> {:?}", r2);
-            assert!(r2.len() == 1);
+            let mut server_txn = server.write();
+            // Trying to add it now should fail.
+            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_fail).is_err());
+            // Add a new class.
+            let ce_class = CreateEvent::new_internal(vec![e_cd.clone()]);
+            assert!(server_txn.create(audit, &ce_class).is_ok());
+            // Add the class to an object, in the same transaction
+            // should work
+            let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_work).is_ok());
 
-            let expected = unsafe { vec![e.to_valid_committed()] };
+            // Commit
+            server_txn.commit(audit).expect("should not fail");
 
-            assert_eq!(r2, expected);
+            // Start a new write
+            let mut server_txn = server.write();
+            // delete the class
+            let de_class =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testclass"))) };
+            assert!(server_txn.delete(audit, &de_class).is_ok());
+            // Commit
+            server_txn.commit(audit).expect("should not fail");
 
-            assert!(server_txn.commit(audit).is_ok());
-        });
-    }
+            // Start a new write
+            let mut server_txn = server.write();
+            // Trying to add now should fail
+            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_fail).is_err());
+            // Search our entry
+            let testobj1 = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+                .expect("failed");
+            assert!(testobj1.attribute_value_pres("class", "testclass"));
 
-    #[test]
-    fn test_qs_init_idempotent_schema_core() {
-        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            {
-                // Setup and abort.
-                let mut server_txn = server.write();
-                assert!(server_txn.initialise_schema_core(audit).is_ok());
-            }
-            {
-                let mut server_txn = server.write();
-                assert!(server_txn.initialise_schema_core(audit).is_ok());
-                assert!(server_txn.initialise_schema_core(audit).is_ok());
-                assert!(server_txn.commit(audit).is_ok());
-            }
-            {
-                // Now do it again in a new txn, but abort
-                let mut server_txn = server.write();
-                assert!(server_txn.initialise_schema_core(audit).is_ok());
-            }
-            {
-                // Now do it again in a new txn.
-                let mut server_txn = server.write();
-                assert!(server_txn.initialise_schema_core(audit).is_ok());
-                assert!(server_txn.commit(audit).is_ok());
-            }
-        });
+            // Should still be good
+            server_txn.commit(audit).expect("should not fail");
+            // Commit.
+        })
     }
 
     #[test]
-    fn test_qs_modify() {
+    fn test_qs_dynamic_schema_attr() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            // Create an object
-            let mut server_txn = server.write();
-
             let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson1"],
+                    "class": ["object", "extensibleobject"],
+                    "name": ["testobj1"],
                     "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson1"],
-                    "displayname": ["testperson1"]
+                    "testattr": ["test"]
                 }
             }"#,
             )
             .expect("json failure");
 
-            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            // Attribute definition
+            let e_ad: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson2"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
-                    "description": ["testperson2"],
-                    "displayname": ["testperson2"]
+                    "class": ["object", "attributetype"],
+                    "name": ["testattr"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
+                    "description": ["Test Attribute"],
+                    "multivalue": ["false"],
+                    "syntax": ["UTF8STRING"]
                 }
             }"#,
             )
             .expect("json failure");
 
-            let ce = CreateEvent::new_internal(vec![e1.clone(), e2.clone()]);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-
-            // Empty Modlist (filter is valid)
-            let me_emp = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_pres("class")),
-                    ModifyList::new_list(vec![]),
-                )
-            };
-            assert!(server_txn.modify(audit, &me_emp) == Err(OperationError::EmptyRequest));
-
-            // Mod changes no objects
-            let me_nochg = unsafe {
-                ModifyEvent::new_impersonate_entry_ser(
-                    JSON_ADMIN_V1,
-                    filter!(f_eq("name", "flarbalgarble")),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("description"),
-                        String::from("anusaosu"),
-                    )]),
-                )
-            };
-            assert!(server_txn.modify(audit, &me_nochg) == Err(OperationError::NoMatchingEntries));
-
-            // Filter is invalid to schema - to check this due to changes in the way events are
-            // handled, we put this via the internal modify function to get the modlist
-            // checked for us. Normal server operation doesn't allow weird bypasses like
-            // this.
-            let r_inv_1 = server_txn.internal_modify(
-                audit,
-                filter!(f_eq("tnanuanou", "Flarbalgarble")),
-                ModifyList::new_list(vec![Modify::Present(
-                    String::from("description"),
-                    String::from("anusaosu"),
-                )]),
-            );
-            assert!(
-                r_inv_1
-                    == Err(OperationError::SchemaViolation(
-                        SchemaError::InvalidAttribute
-                    ))
-            );
+            let mut server_txn = server.write();
+            // Trying to add it now should fail. (use extensible object)
+            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_fail).is_err());
+            // Add a new attribute.
+            let ce_attr = CreateEvent::new_internal(vec![e_ad.clone()]);
+            assert!(server_txn.create(audit, &ce_attr).is_ok());
+            // Add the attr to an object, in the same transaction
+            // should work
+            let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_work).is_ok());
 
-            // Mod is invalid to schema
-            let me_inv_m = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_pres("class")),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("htnaonu"),
-                        String::from("anusaosu"),
-                    )]),
-                )
-            };
-            assert!(
-                server_txn.modify(audit, &me_inv_m)
-                    == Err(OperationError::SchemaViolation(
-                        SchemaError::InvalidAttribute
-                    ))
-            );
+            // Commit
+            server_txn.commit(audit).expect("should not fail");
 
-            // Mod single object
-            let me_sin = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", "testperson2")),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("description"),
-                        String::from("anusaosu"),
-                    )]),
-                )
-            };
-            assert!(server_txn.modify(audit, &me_sin).is_ok());
+            // Start a new write
+            let mut server_txn = server.write();
+            // delete the attr
+            let de_attr =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testattr"))) };
+            assert!(server_txn.delete(audit, &de_attr).is_ok());
+            // Commit
+            server_txn.commit(audit).expect("should not fail");
 
-            // Mod multiple object
-            let me_mult = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_or!([
-                        f_eq("name", "testperson1"),
-                        f_eq("name", "testperson2"),
-                    ])),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("description"),
-                        String::from("anusaosu"),
-                    )]),
-                )
-            };
-            assert!(server_txn.modify(audit, &me_mult).is_ok());
+            // Start a new write
+            let mut server_txn = server.write();
+            // Trying to add now should fail
+            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
+            assert!(server_txn.create(audit, &ce_fail).is_err());
+            // Search our attribute - should FAIL
+            let filt = filter!(f_eq("testattr", "test"));
+            assert!(server_txn.internal_search(audit, filt).is_err());
+            // Search the entry - the attribute will still be present
+            // even if we can't search on it.
+            let testobj1 = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+                .expect("failed");
+            assert!(testobj1.attribute_value_pres("testattr", "test"));
 
-            assert!(server_txn.commit(audit).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+            // Commit.
         })
     }
 
     #[test]
-    fn test_modify_invalid_class() {
-        // Test modifying an entry and adding an extra class, that would cause the entry
-        // to no longer conform to schema.
+    fn test_qs_dynamic_schema_invalid() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // A class that needs an attribute nothing defines.
+            let e_cd: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "classtype"],
+                    "name": ["testclass"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
+                    "description": ["Test Class"],
+                    "must": ["testattr"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+
             let mut server_txn = server.write();
+            let ce_class = CreateEvent::new_internal(vec![e_cd.clone()]);
+            match server_txn.create(audit, &ce_class) {
+                Err(OperationError::ConsistencyError(errs)) => assert!(!errs.is_empty()),
+                r => panic!("unexpected result {:?}", r),
+            }
+        })
+    }
 
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+    #[test]
+    fn test_qs_search_trace() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
                     "class": ["object", "person"],
-                    "name": ["testperson1"],
+                    "name": ["testperson"],
                     "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson1"],
-                    "displayname": ["testperson1"]
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
                 }
             }"#,
             )
             .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(server_txn.create(audit, &ce).is_ok());
 
-            let ce = CreateEvent::new_internal(vec![e1.clone()]);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
 
-            // Add class but no values
-            let me_sin = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", "testperson1")),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("class"),
-                        String::from("system_info"),
-                    )]),
+            // Anonymous may not trace.
+            let se_anon = unsafe {
+                SearchEvent::new_ext_impersonate_entry(
+                    anon,
+                    filter_all!(f_eq("name", "testperson")),
                 )
             };
-            assert!(server_txn.modify(audit, &me_sin).is_err());
+            assert!(
+                server_txn.search_ext_trace(audit, &se_anon).err()
+                    == Some(OperationError::AccessDenied)
+            );
 
-            // Add multivalue where not valid
-            let me_sin = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", "testperson1")),
-                    ModifyList::new_list(vec![Modify::Present(
-                        String::from("name"),
-                        String::from("testpersonx"),
-                    )]),
+            // Admin can, and the result is the same as without the trace.
+            let se_admin = unsafe {
+                SearchEvent::new_ext_impersonate_entry(
+                    admin.clone(),
+                    filter_all!(f_eq("name", "testperson")),
                 )
             };
-            assert!(server_txn.modify(audit, &me_sin).is_err());
-
-            // add class and valid values?
-            let me_sin = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", "testperson1")),
-                    ModifyList::new_list(vec![
-                        Modify::Present(String::from("class"), String::from("system_info")),
-                        Modify::Present(String::from("domain"), String::from("domain.name")),
-                        Modify::Present(String::from("version"), String::from("1")),
-                    ]),
+            let (entries, trace) = server_txn
+                .search_ext_trace(audit, &se_admin)
+                .expect("trace failed");
+            assert!(entries.len() == 1);
+            assert!(trace.nodes[0].depth == 0);
+            assert!(trace.nodes[0].candidates == 1);
+            assert!(trace.failures.len() == trace.candidates - 1);
+            assert!(trace.access.len() == 0);
+
+            // Description is not covered by the admin search acp, so the entry
+            // matches the filter but is pruned by access controls.
+            let se_desc = unsafe {
+                SearchEvent::new_ext_impersonate_entry(
+                    admin,
+                    filter_all!(f_eq("description", "testperson")),
                 )
             };
-            assert!(server_txn.modify(audit, &me_sin).is_ok());
+            let (entries, trace) = server_txn
+                .search_ext_trace(audit, &se_desc)
+                .expect("trace failed");
+            assert!(entries.len() == 0);
+            assert!(trace.access.len() == 1);
+            assert!(trace.access[0].denied_attrs == vec!["description".to_string()]);
 
-            // Replace a value
-            let me_sin = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", "testperson1")),
-                    ModifyList::new_list(vec![
-                        Modify::Purged("name".to_string()),
-                        Modify::Present(String::from("name"), String::from("testpersonx")),
-                    ]),
-                )
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_memory_report() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
+
+            let mre_anon = unsafe { MemoryReportEvent::new_impersonate_entry(anon) };
+            assert!(
+                server_txn.memory_report(audit, &mre_anon).err()
+                    == Some(OperationError::AccessDenied)
+            );
+
+            let mre_admin = unsafe { MemoryReportEvent::new_impersonate_entry(admin) };
+            let report = server_txn
+                .memory_report(audit, &mre_admin)
+                .expect("report failed");
+            let find = |uses: &Vec<MemoryUse>, name: &str| {
+                uses.iter()
+                    .find(|u| u.name == name)
+                    .cloned()
+                    .expect("missing use")
             };
-            assert!(server_txn.modify(audit, &me_sin).is_ok());
+            // Every entry has a uuid, and the class is counted with it.
+            let uuids = find(&report.entry_attrs, "uuid");
+            let objects = find(&report.entry_classes, "object");
+            assert!(uuids.count >= objects.count);
+            assert!(uuids.bytes > 0);
+            assert!(objects.bytes > uuids.bytes / uuids.count);
+            // Each kind counts the profiles of that kind.
+            for (kind, class) in vec![
+                ("search", "access_control_search"),
+                ("create", "access_control_create"),
+                ("modify", "access_control_modify"),
+                ("delete", "access_control_delete"),
+                ("compare", "access_control_compare"),
+                ("audit_read", "access_control_audit_read"),
+                ("operation", "access_control_operation"),
+            ] {
+                let acps = server_txn
+                    .internal_search(audit, filter!(f_eq("class", class)))
+                    .expect("search failed");
+                assert!(find(&report.access_controls, kind).count == acps.len());
+            }
         })
     }
 
     #[test]
-    fn test_qs_delete() {
+    fn test_qs_acp_coverage() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            // Create
             let mut server_txn = server.write();
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["cov_public"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639e1"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["cov_private"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639e2"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_search"],
+                        "name": ["cov_acp_public"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639e3"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": ["{\"Pres\":\"class\"}"],
+                        "acp_targetscope": ["{\"Eq\":[\"name\",\"cov_public\"]}"],
+                        "acp_search_attr": ["name"]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
 
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let server_txn = server.read();
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
+
+            let ace = unsafe { AcpCoverageEvent::new_impersonate_entry(anon, None) };
+            assert!(
+                server_txn.acp_coverage(audit, &ace).err() == Some(OperationError::AccessDenied)
+            );
+
+            let ace = unsafe { AcpCoverageEvent::new_impersonate_entry(admin.clone(), None) };
+            let cov = server_txn
+                .acp_coverage(audit, &ace)
+                .expect("coverage failed");
+            assert!(cov.checked == cov.total);
+            assert!(cov.receivers >= 1);
+            let public = "cc8e95b4-c24f-4d68-ba54-8bed76f639e1".to_string();
+            let private = "cc8e95b4-c24f-4d68-ba54-8bed76f639e2".to_string();
+            assert!(cov.world_readable.contains(&public));
+            assert!(!cov.unreadable.contains(&public));
+            assert!(cov.unreadable.contains(&private));
+            // Anonymous can read itself, so anyone can.
+            assert!(cov.world_readable.contains(&UUID_ANONYMOUS.to_string()));
+            let groups = cov
+                .classes
+                .iter()
+                .find(|c| c.class == "group")
+                .expect("no group class");
+            assert!(groups.world_readable >= 1 && groups.unreadable >= 1);
+
+            // A sample only checks so many.
+            let ace = unsafe { AcpCoverageEvent::new_impersonate_entry(admin, Some(3)) };
+            let cov = server_txn
+                .acp_coverage(audit, &ace)
+                .expect("coverage failed");
+            assert!(cov.checked == 3 && cov.total > 3);
+        })
+    }
+
+    #[test]
+    fn test_qs_audit_log() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let path = "./.qs_audit_log_test.json";
+            let _ = fs::remove_file(path);
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
@@ -1901,384 +6560,1027 @@ mod tests {
             )
             .expect("json failure");
 
-            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson2"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson2"]
-                }
-            }"#,
-            )
-            .expect("json failure");
-
-            let e3: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson3"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63933"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson3"]
-                }
-            }"#,
-            )
-            .expect("json failure");
-
-            let ce = CreateEvent::new_internal(vec![e1.clone(), e2.clone(), e3.clone()]);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-
-            // Delete filter is syntax invalid
-            let de_inv =
-                unsafe { DeleteEvent::new_internal_invalid(filter!(f_pres("nhtoaunaoehtnu"))) };
-            assert!(server_txn.delete(audit, &de_inv).is_err());
-
-            // Delete deletes nothing
-            let de_empty = unsafe {
-                DeleteEvent::new_internal_invalid(filter!(f_eq(
-                    "uuid",
-                    "cc8e95b4-c24f-4d68-ba54-000000000000"
-                )))
-            };
-            assert!(server_txn.delete(audit, &de_empty).is_err());
-
-            // Delete matches one
-            let de_sin =
-                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson3"))) };
-            assert!(server_txn.delete(audit, &de_sin).is_ok());
+            // Deletes and credential changes are marked to be kept, other
+            // changes aren't.
+            {
+                let mut server_txn = server.write();
+                let ce = CreateEvent::new_internal(vec![e]);
+                let mut au_create = AuditScope::new_request("create", Some(Uuid::new_v4()));
+                assert!(server_txn.create(&mut au_create, &ce).is_ok());
+                assert!(au_create.to_record().is_none());
+
+                let filt = filter!(f_eq("name", "testperson1"));
+                let me = unsafe {
+                    ModifyEvent::new_internal_invalid(
+                        filt.clone(),
+                        ModifyList::new_list(vec![Modify::Purged("password".to_string())]),
+                    )
+                };
+                let mut au_modify = AuditScope::new_request("modify", Some(Uuid::new_v4()));
+                assert!(server_txn.modify(&mut au_modify, &me).is_ok());
+                assert!(au_modify.to_record().is_some());
+
+                let de = unsafe { DeleteEvent::new_internal_invalid(filt) };
+                let mut au_delete = AuditScope::new_request("delete", Some(Uuid::new_v4()));
+                assert!(server_txn.delete(&mut au_delete, &de).is_ok());
+                assert!(au_delete.to_record().is_some());
+                // Without a request id, it wasn't asked for by a client.
+                let mut au_internal = AuditScope::new("delete");
+                au_internal.set_security_relevant();
+                assert!(au_internal.to_record().is_none());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            // Delete matches many
-            let de_mult = unsafe {
-                DeleteEvent::new_internal_invalid(filter!(f_eq("description", "testperson")))
+            let record = |operation: &str, origin: Option<&str>| AuditRecord {
+                time: "2019-07-01T10:00:00+00:00".to_string(),
+                operation: operation.to_string(),
+                request_id: Uuid::new_v4().to_hyphenated().to_string(),
+                origin: origin.map(|s| s.to_string()),
+                filter: None,
+                decision: Some("success".to_string()),
+                modlist: None,
             };
-            assert!(server_txn.delete(audit, &de_mult).is_ok());
-
-            assert!(server_txn.commit(audit).is_ok());
-        })
-    }
+            assert!(append_audit_record(path, &record("delete", Some(UUID_ADMIN))).is_ok());
+            assert!(append_audit_record(path, &record("auth", Some(UUID_ANONYMOUS))).is_ok());
+            assert!(append_audit_record(path, &record("auth", None)).is_ok());
 
-    #[test]
-    fn test_qs_tombstone() {
-        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            let server_txn = server.read();
             let admin = server_txn
                 .internal_search_uuid(audit, UUID_ADMIN)
                 .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
 
-            let filt_ts = ProtoFilter::Eq(String::from("class"), String::from("tombstone"));
+            // Only the audit read profile grants access.
+            let ale_anon = unsafe { AuditLogEvent::new_impersonate_entry(anon, None) };
+            assert!(
+                server_txn.audit_log_search(audit, &ale_anon, path).err()
+                    == Some(OperationError::AccessDenied)
+            );
 
-            let filt_i_ts = filter_all!(f_eq("class", "tombstone"));
+            let ale_admin = unsafe { AuditLogEvent::new_impersonate_entry(admin.clone(), None) };
+            let r = server_txn
+                .audit_log_search(audit, &ale_admin, path)
+                .expect("search failed");
+            assert!(r.len() == 3);
 
-            // Create fake external requests. Probably from admin later
-            // Should we do this with impersonate instead of using the external
-            let me_ts = ModifyEvent::from_request(
-                audit,
-                ModifyRequest::new(
-                    filt_ts.clone(),
-                    ProtoModifyList::new_list(vec![ProtoModify::Present(
-                        String::from("class"),
-                        String::from("tombstone"),
-                    )]),
-                    UUID_ADMIN,
-                ),
-                &server_txn,
-            )
-            .expect("modify event create failed");
-            let de_ts = DeleteEvent::from_request(
-                audit,
-                DeleteRequest::new(filt_ts.clone(), UUID_ADMIN),
-                &server_txn,
-            )
-            .expect("delete event create failed");
-            let se_ts = unsafe { SearchEvent::new_ext_impersonate_entry(admin, filt_i_ts.clone()) };
+            let ale_auth = unsafe { AuditLogEvent::new_impersonate_entry(admin, Some("auth")) };
+            let r = server_txn
+                .audit_log_search(audit, &ale_auth, path)
+                .expect("search failed");
+            assert!(r.len() == 2);
+            assert!(r.iter().all(|r| r.operation == "auth"));
 
-            // First, create a tombstone
-            let e_ts: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let _ = fs::remove_file(path);
+        })
+    }
+
+    #[test]
+    fn test_qs_backup_restore() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let path = test_file_path("qs_backup_test.json");
+            let path = path.as_str();
+            let uuid = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["tombstone", "object"],
-                    "uuid": ["9557f49c-97a5-4277-a9a5-097d17eb8317"]
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
                 }
             }"#,
             )
             .expect("json failure");
+            {
+                let mut server_txn = server.write();
+                let ce = CreateEvent::new_internal(vec![e]);
+                assert!(server_txn.create(audit, &ce).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            let ce = CreateEvent::new_internal(vec![e_ts]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            // Only admins may take a backup.
+            {
+                let server_txn = server.read();
+                let admin = server_txn
+                    .internal_search_uuid(audit, UUID_ADMIN)
+                    .expect("failed");
+                let anon = server_txn
+                    .internal_search_uuid(audit, UUID_ANONYMOUS)
+                    .expect("failed");
+                let bue_anon = unsafe { BackupEvent::new_impersonate_entry(anon) };
+                assert!(
+                    server_txn.backup(audit, &bue_anon, path).err()
+                        == Some(OperationError::AccessDenied)
+                );
+                let bue_admin = unsafe { BackupEvent::new_impersonate_entry(admin) };
+                assert!(server_txn.backup(audit, &bue_admin, path).is_ok());
+            }
 
-            // Can it be seen (external search)
-            let r1 = server_txn.search(audit, &se_ts).expect("search failed");
-            assert!(r1.len() == 0);
+            // A restore undoes what changed since the backup.
+            {
+                let mut server_txn = server.write();
+                let de = unsafe {
+                    DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1")))
+                };
+                assert!(server_txn.delete(audit, &de).is_ok());
+                assert!(server_txn.restore(audit, path).is_ok());
+                assert!(server_txn.internal_search_uuid(audit, uuid).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            // Can it be deleted (external delete)
-            // Should be err-no candidates.
-            assert!(server_txn.delete(audit, &de_ts).is_err());
+            // A backup holding an entry that breaks schema isn't restored.
+            let mut backup: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(path).expect("read failed"))
+                    .expect("json failure");
+            backup["V1"]["entries"]
+                .as_array_mut()
+                .expect("no entries")
+                .iter_mut()
+                .filter(|e| e.to_string().contains(uuid))
+                .for_each(|e| {
+                    e["ent"]["V3"]["attrs"]
+                        .as_object_mut()
+                        .expect("no attrs")
+                        .remove("name");
+                });
+            fs::write(path, backup.to_string()).expect("write failed");
+            let mut server_txn = server.write();
+            match server_txn.restore(audit, path) {
+                Err(OperationError::EntrySchemaViolation(_)) => {}
+                _ => panic!(),
+            }
+            let _ = fs::remove_file(path);
+        })
+    }
 
-            // Can it be modified? (external modify)
-            // Should be err-no candidates
-            assert!(server_txn.modify(audit, &me_ts).is_err());
+    #[test]
+    fn test_qs_export_import() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let path = test_file_path("qs_export_test.json");
+            let path = path.as_str();
+            let uuid = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+            {
+                let mut server_txn = server.write();
+                let e = Entry::from_external_json(
+                    r#"{
+                    "attrs": {
+                        "class": ["object", "person"],
+                        "name": ["testperson1"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "description": ["testperson"],
+                        "displayname": ["testperson1"]
+                    }
+                }"#,
+                    server_txn.get_schema(),
+                )
+                .expect("import failed");
+                let ce = CreateEvent::new_internal(vec![e]);
+                assert!(server_txn.create(audit, &ce).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            // Can it be seen (internal search)
-            // Internal search should see it.
-            let r2 = server_txn
-                .internal_search(audit, filt_i_ts.clone())
-                .expect("internal search failed");
-            assert!(r2.len() == 1);
+            {
+                let server_txn = server.read();
+                let n = server_txn.export(audit, path).expect("export failed");
+                let data = fs::read_to_string(path).expect("read failed");
+                assert!(data.lines().count() == n);
+                assert!(data.contains(uuid));
+                assert!(!data.contains("\"valid\""));
+            }
 
-            // Now purge
-            assert!(server_txn.purge_tombstones(audit).is_ok());
+            // Once the entry is gone for good, importing brings it back, and
+            // everything still there is left alone.
+            {
+                let mut server_txn = server.write();
+                let de = unsafe {
+                    DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1")))
+                };
+                assert!(server_txn.delete(audit, &de).is_ok());
+                assert!(server_txn
+                    .purge_recycled(audit, Duration::from_secs(0))
+                    .is_ok());
+                assert!(server_txn
+                    .purge_tombstones(audit, Duration::from_secs(0))
+                    .is_ok());
+                assert!(server_txn.internal_search_uuid(audit, uuid).is_err());
+                assert!(server_txn.import(audit, path) == Ok(1));
+                assert!(server_txn.internal_search_uuid(audit, uuid).is_ok());
+                assert!(server_txn.commit(audit).is_ok());
+            }
 
-            // Assert it's gone
-            // Internal search should not see it.
-            let r3 = server_txn
-                .internal_search(audit, filt_i_ts)
-                .expect("internal search failed");
-            assert!(r3.len() == 0);
+            // The internal form isn't accepted.
+            fs::write(path, r#"{"valid": null, "state": null, "attrs": {}}"#)
+                .expect("write failed");
+            let mut server_txn = server.write();
+            assert!(server_txn.import(audit, path) == Err(OperationError::SerdeJsonError));
+            let _ = fs::remove_file(path);
+        })
+    }
+
+    #[test]
+    fn test_qs_batch() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let uuid = "cc8e95b4-c24f-4d68-ba54-8bed76f639b1";
+            let pe: ProtoEntry = serde_json::from_str(
+                r#"{
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["bt_person"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b1"],
+                    "description": ["one"],
+                    "displayname": ["bt_person"]
+                }
+            }"#,
+            )
+            .expect("json failure");
+            let add_desc = |v: &str| {
+                ProtoModifyList::new_list(vec![ProtoModify::Present(
+                    "description".to_string(),
+                    v.to_string(),
+                )])
+            };
 
+            // Later operations see the entries made by earlier ones.
+            let mut server_txn = server.write();
+            let be = BatchEvent::new_internal(vec![
+                BatchOperation::Create(vec![pe]),
+                BatchOperation::ModifyUuid(uuid.to_string(), add_desc("two")),
+            ]);
+            assert!(server_txn.batch(audit, &be).is_ok());
             assert!(server_txn.commit(audit).is_ok());
+
+            // The delete matches nothing, so the modify before it is lost too.
+            let mut server_txn = server.write();
+            let be = BatchEvent::new_internal(vec![
+                BatchOperation::ModifyUuid(uuid.to_string(), add_desc("three")),
+                BatchOperation::Delete(ProtoFilter::Eq(
+                    "name".to_string(),
+                    "bt_missing".to_string(),
+                )),
+            ]);
+            assert!(server_txn.batch(audit, &be).err() == Some(OperationError::NoMatchingEntries));
+            drop(server_txn);
+
+            let server_txn = server.read();
+            let e = server_txn
+                .internal_search_uuid(audit, uuid)
+                .expect("failed");
+            assert!(e.attribute_value_pres("description", "one"));
+            assert!(e.attribute_value_pres("description", "two"));
+            assert!(!e.attribute_value_pres("description", "three"));
         })
     }
 
     #[test]
-    fn test_qs_recycle_simple() {
+    fn test_qs_delete_preview() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person"],
+                        "name": ["dp_person"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639a1"],
+                        "description": ["dp_target"],
+                        "displayname": ["dp_person"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["dp_group"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639a2"],
+                        "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f639a1"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_delete"],
+                        "name": ["dp_acp_delete"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639a3"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"description\",\"dp_target\"]}"
+                        ]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_delete"],
+                        "name": ["dp_acp_group"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639a4"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"memberof\",\"cc8e95b4-c24f-4d68-ba54-8bed76f639a2\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"dp_nothing\"]}"
+                        ]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
             let admin = server_txn
                 .internal_search_uuid(audit, UUID_ADMIN)
                 .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
 
-            let filt_rc = ProtoFilter::Eq(String::from("class"), String::from("recycled"));
+            let dpe_anon = unsafe {
+                DeletePreviewEvent::new_impersonate_entry(anon, filter!(f_eq("name", "dp_person")))
+            };
+            assert!(
+                server_txn.delete_preview(audit, &dpe_anon).err()
+                    == Some(OperationError::AccessDenied)
+            );
 
-            let filt_i_rc = filter_all!(f_eq("class", "recycled"));
+            let dpe = unsafe {
+                DeletePreviewEvent::new_impersonate_entry(admin, filter!(f_eq("name", "dp_person")))
+            };
+            let preview = server_txn
+                .delete_preview(audit, &dpe)
+                .expect("preview failed");
+            assert!(preview.entries == vec!["cc8e95b4-c24f-4d68-ba54-8bed76f639a1".to_string()]);
+            assert!(
+                preview.groups
+                    == vec![DeletePreviewGroup {
+                        uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f639a2".to_string(),
+                        members: vec!["cc8e95b4-c24f-4d68-ba54-8bed76f639a1".to_string()],
+                    }]
+            );
+            assert!(preview.references.len() == 1);
+            assert!(preview.references[0].attrs == vec!["member".to_string()]);
+            // The group's only member goes, so its profile applies to no one.
+            assert!(
+                preview.access_controls == vec!["cc8e95b4-c24f-4d68-ba54-8bed76f639a4".to_string()]
+            );
 
-            let filt_i_ts = filter_all!(f_eq("class", "tombstone"));
+            // Nothing was actually deleted.
+            assert!(server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f639a1")
+                .is_ok());
+        })
+    }
 
-            let filt_i_per = filter_all!(f_eq("class", "person"));
+    #[test]
+    fn test_qs_rename() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person", "extensibleobject"],
+                        "name": ["rn_person"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639c1"],
+                        "description": ["rn_person"],
+                        "displayname": ["rn_person"],
+                        "principal_name": ["rn_person@example.com"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["rn_group"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639c2"],
+                        "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f639c1"]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+
+            // The name is normalised by the schema, and the spn follows it.
+            let re =
+                RenameEvent::new_internal("cc8e95b4-c24f-4d68-ba54-8bed76f639c1", "RN_Renamed");
+            assert!(server_txn.rename(audit, &re).is_ok());
+            let e = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f639c1")
+                .expect("failed");
+            assert!(e.attribute_equality("name", "rn_renamed"));
+            assert!(e.attribute_equality("principal_name", "rn_renamed@example.com"));
+            // Membership is by uuid, so is untouched.
+            assert!(e.attribute_value_pres("memberof", "cc8e95b4-c24f-4d68-ba54-8bed76f639c2"));
 
-            // Create fake external requests. Probably from admin later
-            let me_rc = ModifyEvent::from_request(
-                audit,
-                ModifyRequest::new(
-                    filt_rc.clone(),
-                    ProtoModifyList::new_list(vec![ProtoModify::Present(
-                        String::from("class"),
-                        String::from("recycled"),
-                    )]),
-                    UUID_ADMIN,
-                ),
-                &server_txn,
-            )
-            .expect("modify event create failed");
-            let de_rc = DeleteEvent::from_request(
-                audit,
-                DeleteRequest::new(filt_rc.clone(), UUID_ADMIN),
-                &server_txn,
-            )
-            .expect("delete event create failed");
-            let se_rc =
-                unsafe { SearchEvent::new_ext_impersonate_entry(admin.clone(), filt_i_rc.clone()) };
+            // Names already held, and reserved names, are refused.
+            let re = RenameEvent::new_internal("cc8e95b4-c24f-4d68-ba54-8bed76f639c1", "rn_group");
+            assert!(
+                server_txn.rename(audit, &re)
+                    == Err(OperationError::NameInUse("rn_group".to_string()))
+            );
+            let re =
+                RenameEvent::new_internal("cc8e95b4-c24f-4d68-ba54-8bed76f639c1", "idm_admins");
+            assert!(
+                server_txn.rename(audit, &re)
+                    == Err(OperationError::ReservedName("idm_admins".to_string()))
+            );
+            let re = RenameEvent::new_internal(UUID_ADMIN, "rn_admin");
+            assert!(
+                server_txn.rename(audit, &re)
+                    == Err(OperationError::ReservedName("admin".to_string()))
+            );
 
-            let sre_rc =
-                unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_i_rc.clone()) };
+            // A rename needs the same rights as changing the name directly.
+            let re = unsafe {
+                RenameEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639c1",
+                    "rn_anon",
+                )
+            };
+            assert!(server_txn.rename(audit, &re) == Err(OperationError::AccessDenied));
+            // Even for a name that is taken, without saying by whom.
+            let re = unsafe {
+                RenameEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639c1",
+                    "rn_group",
+                )
+            };
+            assert!(server_txn.rename(audit, &re) == Err(OperationError::AccessDenied));
+            let e = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f639c1")
+                .expect("failed");
+            assert!(e.attribute_equality("name", "rn_renamed"));
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 
-            let rre_rc = ReviveRecycledEvent::from_request(
-                audit,
-                ReviveRecycledRequest::new(
-                    ProtoFilter::Eq("name".to_string(), "testperson1".to_string()),
-                    UUID_ADMIN,
+    #[test]
+    fn test_qs_ssh_publickey() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let key =
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+            let account = |name: &str, uuid: &str, class: &str, valid: bool| {
+                format!(
+                    r#"{{
+                        "valid": {},
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "account", "{}"],
+                            "name": ["{}"],
+                            "uuid": ["{}"],
+                            "displayname": ["{}"]
+                        }}
+                    }}"#,
+                    if valid {
+                        format!("{{\"uuid\": \"{}\"}}", uuid)
+                    } else {
+                        "null".to_string()
+                    },
+                    class,
+                    name,
+                    uuid,
+                    name
+                )
+            };
+            let user = account(
+                "sk_user",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639f1",
+                "person",
+                true,
+            );
+            let host = account(
+                "sk_host",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639f2",
+                "host",
+                true,
+            );
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                account(
+                    "sk_user",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639f1",
+                    "person",
+                    false,
                 ),
-                &server_txn,
-            )
-            .expect("revive recycled create failed");
-
-            // Create some recycled objects
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person", "recycled"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson1"]
-                }
-            }"#,
-            )
-            .expect("json failure");
-
-            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person", "recycled"],
-                    "name": ["testperson2"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson2"]
-                }
-            }"#,
-            )
-            .expect("json failure");
+                account(
+                    "sk_host",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639f2",
+                    "host",
+                    false,
+                ),
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s.as_str()).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
 
-            let ce = CreateEvent::new_internal(vec![e1, e2]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            // An account manages its own keys.
+            let add_key = |v: String| unsafe {
+                ModifyEvent::new_impersonate_entry_ser(
+                    user.as_str(),
+                    filter!(f_eq("name", "sk_user")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("ssh_publickey"),
+                        Value::from(v.as_str()),
+                    )]),
+                )
+            };
+            assert!(server_txn
+                .modify(audit, &add_key(format!("laptop: {}", key)))
+                .is_ok());
+            assert!(server_txn
+                .modify(audit, &add_key("laptop: ssh-ed25519 AAAA".to_string()))
+                .is_err());
+
+            // Hosts read them, to let the account log in.
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    host.as_str(),
+                    filter!(f_eq("name", "sk_user")),
+                )
+            };
+            let res = server_txn.search_ext(audit, &se).expect("search failed");
+            assert!(res.len() == 1);
+            let keys: Vec<&str> = res[0]
+                .get_ava("ssh_publickey")
+                .expect("no keys")
+                .iter()
+                .filter_map(|v| v.to_sshkey())
+                .collect();
+            assert!(keys == vec![key]);
+
+            // Anonymous can't.
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    filter!(f_eq("name", "sk_user")),
+                )
+            };
+            let res = server_txn.search_ext(audit, &se).expect("search failed");
+            assert!(!res.iter().any(|e| e.attribute_pres("ssh_publickey")));
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 
-            // Can it be seen (external search)
-            let r1 = server_txn.search(audit, &se_rc).expect("search failed");
-            assert!(r1.len() == 0);
+    #[test]
+    fn test_qs_host_secret_rotate() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let host = |name: &str, uuid: &str, valid: bool| {
+                format!(
+                    r#"{{
+                        "valid": {},
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "account", "host"],
+                            "name": ["{}"],
+                            "uuid": ["{}"],
+                            "displayname": ["{}"]
+                        }}
+                    }}"#,
+                    if valid {
+                        format!("{{\"uuid\": \"{}\"}}", uuid)
+                    } else {
+                        "null".to_string()
+                    },
+                    name,
+                    uuid,
+                    name
+                )
+            };
+            let host_a = host("hs_host_a", "cc8e95b4-c24f-4d68-ba54-8bed76f639d1", true);
+            let host_b = host("hs_host_b", "cc8e95b4-c24f-4d68-ba54-8bed76f639d2", true);
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                host("hs_host_a", "cc8e95b4-c24f-4d68-ba54-8bed76f639d1", false),
+                host("hs_host_b", "cc8e95b4-c24f-4d68-ba54-8bed76f639d2", false),
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s.as_str()).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
 
-            // Can it be deleted (external delete)
-            // Should be err-no candidates.
-            assert!(server_txn.delete(audit, &de_rc).is_err());
+            // A host may rotate its own secret, and read it back.
+            let hre = unsafe {
+                HostSecretRotateEvent::new_impersonate_entry_ser(
+                    host_a.as_str(),
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639d1",
+                )
+            };
+            let r = server_txn
+                .rotate_host_secret(audit, &hre)
+                .expect("rotate failed");
+            assert!(r.secret.len() == 64);
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    host_a.as_str(),
+                    filter!(f_eq("name", "hs_host_a")),
+                )
+            };
+            let res = server_txn.search_ext(audit, &se).expect("search failed");
+            assert!(res.len() == 1);
+            assert!(res[0].attribute_pres("service_secret_expiry"));
+            // What is kept is a hash of the secret, not the secret.
+            let stored = res[0]
+                .get_ava_single("service_secret")
+                .expect("no service_secret")
+                .to_string();
+            assert!(stored != r.secret);
+            assert!(Password::from_stored(stored.as_str())
+                .expect("not hashed")
+                .verify(r.secret.as_str()));
+
+            // Rotating again replaces it.
+            let r2 = server_txn
+                .rotate_host_secret(audit, &hre)
+                .expect("rotate failed");
+            assert!(r2.secret != r.secret);
+
+            // But not the secret of another host, and it can't read theirs.
+            let hre = unsafe {
+                HostSecretRotateEvent::new_impersonate_entry_ser(
+                    host_b.as_str(),
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639d1",
+                )
+            };
+            assert!(
+                server_txn.rotate_host_secret(audit, &hre) == Err(OperationError::AccessDenied)
+            );
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    host_b.as_str(),
+                    filter!(f_eq("name", "hs_host_a")),
+                )
+            };
+            let res = server_txn.search_ext(audit, &se).expect("search failed");
+            assert!(res.iter().all(|e| !e.attribute_pres("service_secret")));
 
-            // Can it be modified? (external modify)
-            // Should be err-no candidates
-            assert!(server_txn.modify(audit, &me_rc).is_err());
+            // Only hosts have secrets.
+            let hre = HostSecretRotateEvent::new_internal(UUID_ADMIN);
+            assert!(
+                server_txn.rotate_host_secret(audit, &hre)
+                    == Err(OperationError::InvalidEntryState)
+            );
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 
-            // Can in be seen by special search? (external recycle search)
-            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
-            assert!(r2.len() == 2);
+    #[test]
+    fn test_qs_radius_secret() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let account = |name: &str, uuid: &str, valid: bool, memberof: &str| {
+                format!(
+                    r#"{{
+                        "valid": {},
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "account"],
+                            "name": ["{}"],
+                            "uuid": ["{}"],
+                            "displayname": ["{}"]{}
+                        }}
+                    }}"#,
+                    if valid {
+                        format!("{{\"uuid\": \"{}\"}}", uuid)
+                    } else {
+                        "null".to_string()
+                    },
+                    name,
+                    uuid,
+                    name,
+                    memberof
+                )
+            };
+            let rs_memberof = format!(",\n\"memberof\": [\"{}\"]", UUID_IDM_RADIUS_SERVERS);
+            let user_a = account(
+                "rs_user_a",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639e1",
+                true,
+                "",
+            );
+            let user_b = account(
+                "rs_user_b",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639e2",
+                true,
+                "",
+            );
+            let radius = account(
+                "rs_radius",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639e3",
+                true,
+                rs_memberof.as_str(),
+            );
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                account(
+                    "rs_user_a",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639e1",
+                    false,
+                    "",
+                ),
+                account(
+                    "rs_user_b",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639e2",
+                    false,
+                    "",
+                ),
+                account(
+                    "rs_radius",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639e3",
+                    false,
+                    "",
+                ),
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s.as_str()).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            assert!(server_txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq("uuid", UUID_IDM_RADIUS_SERVERS)),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "member".to_string(),
+                        Value::from("cc8e95b4-c24f-4d68-ba54-8bed76f639e3"),
+                    )]),
+                )
+                .is_ok());
 
-            // Can it be seen (internal search)
-            // Internal search should see it.
+            // An account may regenerate its own secret.
+            let rre = unsafe {
+                RadiusSecretRegenerateEvent::new_impersonate_entry_ser(
+                    user_a.as_str(),
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639e1",
+                )
+            };
+            let r = server_txn
+                .regenerate_radius_secret(audit, &rre)
+                .expect("regenerate failed");
+            assert!(r.secret.len() == 24);
             let r2 = server_txn
-                .internal_search(audit, filt_i_rc.clone())
-                .expect("internal search failed");
-            assert!(r2.len() == 2);
-
-            // There are now two options
-            //  revival
-            assert!(server_txn.revive_recycled(audit, &rre_rc).is_ok());
-
-            //  purge to tombstone
-            assert!(server_txn.purge_recycled(audit).is_ok());
+                .regenerate_radius_secret(audit, &rre)
+                .expect("regenerate failed");
+            assert!(r2.secret != r.secret);
+
+            // But not that of another account.
+            let rre = unsafe {
+                RadiusSecretRegenerateEvent::new_impersonate_entry_ser(
+                    user_b.as_str(),
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639e1",
+                )
+            };
+            assert!(
+                server_txn.regenerate_radius_secret(audit, &rre)
+                    == Err(OperationError::AccessDenied)
+            );
 
-            // Should be no recycled objects.
-            let r3 = server_txn
-                .internal_search(audit, filt_i_rc.clone())
-                .expect("internal search failed");
-            assert!(r3.len() == 0);
+            // Radius servers can read it, other accounts and anonymous can't.
+            let rre = unsafe {
+                RadiusSecretReadEvent::new_impersonate_entry_ser(radius.as_str(), "rs_user_a")
+            };
+            assert!(server_txn.radius_secret_read(audit, &rre) == Ok(r2));
+            let rre = unsafe {
+                RadiusSecretReadEvent::new_impersonate_entry_ser(user_b.as_str(), "rs_user_a")
+            };
+            assert!(server_txn.radius_secret_read(audit, &rre).is_err());
+            let rre = unsafe {
+                RadiusSecretReadEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, "rs_user_a")
+            };
+            assert!(server_txn.radius_secret_read(audit, &rre).is_err());
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 
-            // There should be one tombstone
-            let r4 = server_txn
-                .internal_search(audit, filt_i_ts.clone())
-                .expect("internal search failed");
-            assert!(r4.len() == 1);
+    #[test]
+    fn test_qs_compare() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["cmp_group"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b1"],
+                        "description": ["cmp_group"],
+                        "member": ["00000000-0000-0000-0000-000000000000"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_compare"],
+                        "name": ["cmp_acp"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b2"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"anonymous\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"cmp_group\"]}"
+                        ],
+                        "acp_compare_attr": ["member"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_search"],
+                        "name": ["cmp_acp_search"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b3"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"anonymous\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"cmp_group\"]}"
+                        ],
+                        "acp_search_attr": ["name", "uuid"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["cmp_hidden"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b4"],
+                        "member": ["00000000-0000-0000-0000-000000000000"]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
 
-            // There should be one entry
-            let r5 = server_txn
-                .internal_search(audit, filt_i_per.clone())
-                .expect("internal search failed");
-            assert!(r5.len() == 1);
+            let server_txn = server.read();
+            let compare_in = |target: &str, attr: &str, value: &str| {
+                let ce = unsafe {
+                    CompareEvent::new_impersonate_entry_ser(JSON_ANONYMOUS_V1, target, attr, value)
+                };
+                let mut audit_cmp = AuditScope::new("test_qs_compare");
+                server_txn.compare(&mut audit_cmp, &ce)
+            };
+            let compare = |attr: &str, value: &str| {
+                compare_in("cc8e95b4-c24f-4d68-ba54-8bed76f639b1", attr, value)
+            };
 
-            assert!(server_txn.commit(audit).is_ok());
+            // Membership can be checked, but still not read.
+            assert!(compare("member", "00000000-0000-0000-0000-000000000000") == Ok(true));
+            assert!(compare("member", "00000000-0000-0000-0000-000000000001") == Ok(false));
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    filter!(f_eq("name", "cmp_group")),
+                )
+            };
+            let r = server_txn.search_ext(audit, &se).expect("search failed");
+            assert!(r.iter().all(|e| !e.attribute_pres("member")));
+            // No right was given to compare anything else.
+            assert!(compare("description", "cmp_group") == Err(OperationError::AccessDenied));
+            // An entry that can't be seen looks the same as one that doesn't
+            // exist, whatever the value.
+            let hidden = "cc8e95b4-c24f-4d68-ba54-8bed76f639b4";
+            let missing = "cc8e95b4-c24f-4d68-ba54-8bed76f639b5";
+            let member = "00000000-0000-0000-0000-000000000000";
+            assert!(compare_in(hidden, "member", member) == Err(OperationError::NoMatchingEntries));
+            assert!(
+                compare_in(missing, "member", member) == Err(OperationError::NoMatchingEntries)
+            );
         })
     }
 
-    // The delete test above should be unaffected by recycle anyway
     #[test]
-    fn test_qs_recycle_advanced() {
+    fn test_qs_effective_permissions_hidden() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            // Create items
             let mut server_txn = server.write();
-            let admin = server_txn
-                .internal_search_uuid(audit, UUID_ADMIN)
-                .expect("failed");
-
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson1"]
+                    "class": ["object", "group"],
+                    "name": ["ep_hidden"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639b6"]
                 }
             }"#,
             )
             .expect("json failure");
-            let ce = CreateEvent::new_internal(vec![e1]);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-            // Delete and ensure they became recycled.
-            let de_sin =
-                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson1"))) };
-            assert!(server_txn.delete(audit, &de_sin).is_ok());
-            // Can in be seen by special search? (external recycle search)
-            let filt_rc = filter_all!(f_eq("class", "recycled"));
-            let sre_rc = unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_rc.clone()) };
-            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
-            assert!(r2.len() == 1);
-
-            // Create dup uuid (rej)
-            // After a delete -> recycle, create duplicate name etc.
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_err());
-
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
             assert!(server_txn.commit(audit).is_ok());
+
+            // An entry that can't be seen looks the same as one that doesn't
+            // exist, so what could be done to it isn't reported either.
+            let server_txn = server.read();
+            let hidden = unsafe {
+                EffectivePermissionsEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639b6",
+                )
+            };
+            let missing = unsafe {
+                EffectivePermissionsEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f639b7",
+                )
+            };
+            assert!(
+                server_txn.effective_permissions(audit, &hidden).err()
+                    == Some(OperationError::NoMatchingEntries)
+            );
+            assert!(
+                server_txn.effective_permissions(audit, &missing).err()
+                    == Some(OperationError::NoMatchingEntries)
+            );
+            // Where it can be seen, it's reported as before.
+            let own = unsafe {
+                EffectivePermissionsEvent::new_impersonate_entry_ser(
+                    JSON_ANONYMOUS_V1,
+                    UUID_ANONYMOUS,
+                )
+            };
+            assert!(server_txn.effective_permissions(audit, &own).is_ok());
         })
     }
 
     #[test]
-    fn test_qs_name_to_uuid() {
+    fn test_qs_search_ext_iter() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
+            // More than the backend reads in one batch.
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = (0..300)
+                .map(|i| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                    e.add_ava("class", "object");
+                    e.add_ava("class", "person");
+                    e.add_ava("name", format!("testperson{}", i).as_str());
+                    e.add_ava("description", "testperson");
+                    e.add_ava("displayname", "testperson");
+                    e
+                })
+                .collect();
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
 
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson1"]
-                }
-                }"#,
-            )
-            .expect("json failure");
-            let ce = CreateEvent::new_internal(vec![e1]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+            let se = unsafe {
+                SearchEvent::new_ext_impersonate_entry(admin, filter!(f_eq("class", "person")))
+            };
 
-            // Name doesn't exist
-            let r1 = server_txn.name_to_uuid(audit, &String::from("testpers"));
-            assert!(r1.is_err());
-            // Name doesn't exist (not syntax normalised)
-            let r2 = server_txn.name_to_uuid(audit, &String::from("tEsTpErS"));
-            assert!(r2.is_err());
-            // Name does exist
-            let r3 = server_txn.name_to_uuid(audit, &String::from("testperson1"));
-            assert!(r3.is_ok());
-            // Name is not syntax normalised (but exists)
-            let r4 = server_txn.name_to_uuid(audit, &String::from("tEsTpErSoN1"));
-            assert!(r4.is_ok());
+            let mut count = 0;
+            for r in server_txn
+                .search_ext_iter(audit, &se)
+                .expect("search failed")
+            {
+                let e = r.expect("search failed");
+                // Reduced to what the admin search acp allows.
+                assert!(e.attribute_pres("name"));
+                assert!(!e.attribute_pres("description"));
+                count += 1;
+            }
+            assert!(count == 300);
+            assert!(
+                server_txn
+                    .search_ext(audit, &se)
+                    .expect("search failed")
+                    .len()
+                    == 300
+            );
+
+            assert!(server_txn.commit(audit).is_ok());
         })
     }
 
     #[test]
-    fn test_qs_uuid_to_name() {
+    fn test_qs_sync() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
-
             let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
@@ -2293,239 +7595,970 @@ mod tests {
             }"#,
             )
             .expect("json failure");
-            let ce = CreateEvent::new_internal(vec![e1]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-
-            // Name doesn't exist
-            let r1 = server_txn
-                .uuid_to_name(audit, &String::from("bae3f507-e6c3-44ba-ad01-f8ff1083534a"));
-            assert!(r1.is_err());
-            // Name doesn't exist (not syntax normalised)
-            let r2 = server_txn.uuid_to_name(audit, &String::from("bae3f507-e6c3-44ba-ad01"));
-            assert!(r2.is_err());
-            // Name does exist
-            let r3 = server_txn
-                .uuid_to_name(audit, &String::from("cc8e95b4-c24f-4d68-ba54-8bed76f63930"));
-            assert!(r3.is_ok());
-            // Name is not syntax normalised (but exists)
-            let r4 = server_txn
-                .uuid_to_name(audit, &String::from("CC8E95B4-C24F-4D68-BA54-8BED76F63930"));
-            assert!(r4.is_ok());
-        })
-    }
-
-    #[test]
-    fn test_qs_clone_value() {
-        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let e2: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
                     "class": ["object", "person"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
                     "description": ["testperson"],
-                    "displayname": ["testperson1"]
+                    "displayname": ["testperson2"]
                 }
             }"#,
             )
             .expect("json failure");
-            let ce = CreateEvent::new_internal(vec![e1]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-
-            // test attr not exist
-            let r1 =
-                server_txn.clone_value(audit, &"tausau".to_string(), &"naoeutnhaou".to_string());
-
-            assert!(r1 == Ok("naoeutnhaou".to_string()));
-
-            // test attr not-normalised
-            // test attr not-reference
-            let r2 = server_txn.clone_value(audit, &"NaMe".to_string(), &"NaMe".to_string());
-
-            assert!(r2 == Ok("NaMe".to_string()));
-
-            // test attr reference
-            let r3 =
-                server_txn.clone_value(audit, &"member".to_string(), &"testperson1".to_string());
+            let ce = CreateEvent::new_internal(vec![e1, e2]);
+            assert!(server_txn.create(audit, &ce).is_ok());
 
-            assert!(r3 == Ok("cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()));
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
 
-            // test attr reference already resolved.
-            let r4 = server_txn.clone_value(
-                audit,
-                &"member".to_string(),
-                &"cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
-            );
+            // The initial sync returns everything admin can read, and no deletes.
+            let sy_init = unsafe { SyncEvent::new_impersonate_entry(admin.clone(), None) };
+            let (entries, deleted, token) = server_txn.sync(audit, &sy_init).expect("sync failed");
+            assert!(entries
+                .iter()
+                .any(|e| e.attribute_value_pres("name", "testperson1")));
+            assert!(deleted.len() == 0);
+
+            // Nothing has changed since.
+            let sy_none = unsafe { SyncEvent::new_impersonate_entry(admin.clone(), Some(token)) };
+            let (entries, deleted, token_b) =
+                server_txn.sync(audit, &sy_none).expect("sync failed");
+            assert!(entries.len() == 0);
+            assert!(deleted.len() == 0);
+            assert!(token_b == token);
+
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", "testperson1")),
+                    ModifyList::new_list(vec![Modify::Present(
+                        String::from("description"),
+                        Value::from("changed"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
+            let de =
+                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testperson2"))) };
+            assert!(server_txn.delete(audit, &de).is_ok());
+
+            // Only the modified entry comes back, and the deleted one (now
+            // recycled, so no longer visible) is listed as deleted.
+            let sy_delta = unsafe { SyncEvent::new_impersonate_entry(admin, Some(token)) };
+            let (entries, deleted, token_c) =
+                server_txn.sync(audit, &sy_delta).expect("sync failed");
+            assert!(entries.len() == 1);
+            assert!(entries[0].attribute_value_pres("name", "testperson1"));
+            assert!(deleted == vec!["cc8e95b4-c24f-4d68-ba54-8bed76f63932".to_string()]);
+            assert!(token_c > token);
 
-            println!("{:?}", r4);
-            assert!(r4 == Ok("cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string()));
+            assert!(server_txn.commit(audit).is_ok());
         })
     }
 
     #[test]
-    fn test_qs_dynamic_schema_class() {
+    fn test_qs_group_join() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let mut server_txn = server.write();
+            let person = |name: &str, uuid: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+                e.add_ava("class", "object");
+                e.add_ava("class", "person");
+                e.add_ava("name", name);
+                e.add_ava("uuid", uuid);
+                e.add_ava("description", name);
+                e.add_ava("displayname", name);
+                e
+            };
+            let ce = CreateEvent::new_internal(vec![
+                person("testperson1", "cc8e95b4-c24f-4d68-ba54-8bed76f63930"),
+                person("testperson2", "cc8e95b4-c24f-4d68-ba54-8bed76f63931"),
+                person("testperson3", "cc8e95b4-c24f-4d68-ba54-8bed76f63932"),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // testperson2 manages testgroup through their membership of
+            // testmanagers.
+            let e_managers: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "testclass"],
-                    "name": ["testobj1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                    "class": ["object", "group"],
+                    "name": ["testmanagers"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63940"],
+                    "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63931"]
                 }
             }"#,
             )
             .expect("json failure");
-
-            // Class definition
-            let e_cd: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            let e_group: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "classtype"],
-                    "name": ["testclass"],
-                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
-                    "description": ["Test Class"]
+                    "class": ["object", "group"],
+                    "name": ["testgroup"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63941"],
+                    "group_manager": ["cc8e95b4-c24f-4d68-ba54-8bed76f63940"]
                 }
             }"#,
             )
             .expect("json failure");
+            let ce = CreateEvent::new_internal(vec![e_managers, e_group]);
+            assert!(server_txn.create(audit, &ce).is_ok());
 
-            let mut server_txn = server.write();
-            // Add a new class.
-            let ce_class = CreateEvent::new_internal(vec![e_cd.clone()]);
-            assert!(server_txn.create(audit, &ce_class).is_ok());
-            // Trying to add it now should fail.
-            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_fail).is_err());
+            let requester = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+                .expect("failed");
+            let manager = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63931")
+                .expect("failed");
+            let other = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63932")
+                .expect("failed");
 
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            // Asking twice only files one request.
+            let gce = unsafe {
+                GroupJoinCreateEvent::new_impersonate_entry(
+                    requester.clone(),
+                    "testgroup",
+                    Some("I'm on the test team"),
+                )
+            };
+            assert!(server_txn.group_join_create(audit, &gce).is_ok());
+            assert!(server_txn.group_join_create(audit, &gce).is_ok());
+
+            let gle = unsafe { GroupJoinListEvent::new_impersonate_entry(manager.clone()) };
+            let requests = server_txn
+                .group_join_list(audit, &gle)
+                .expect("list failed");
+            assert!(requests.len() == 1);
+            assert!(requests[0].group == "cc8e95b4-c24f-4d68-ba54-8bed76f63941");
+            assert!(requests[0].requester == "cc8e95b4-c24f-4d68-ba54-8bed76f63930");
+            assert!(requests[0].description == Some("I'm on the test team".to_string()));
+            let request_uuid = requests[0].uuid.clone();
+
+            // Someone who doesn't manage the group can't see or decide it.
+            let gle = unsafe { GroupJoinListEvent::new_impersonate_entry(other.clone()) };
+            assert!(server_txn.group_join_list(audit, &gle) == Ok(Vec::new()));
+            let gde = unsafe {
+                GroupJoinDecideEvent::new_impersonate_entry(other, request_uuid.as_str(), true)
+            };
+            assert!(server_txn.group_join_decide(audit, &gde) == Err(OperationError::AccessDenied));
 
-            // Start a new write
-            let mut server_txn = server.write();
-            // Add the class to an object
-            // should work
-            let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_work).is_ok());
+            let gde = unsafe {
+                GroupJoinDecideEvent::new_impersonate_entry(
+                    manager.clone(),
+                    request_uuid.as_str(),
+                    true,
+                )
+            };
+            assert!(server_txn.group_join_decide(audit, &gde).is_ok());
 
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            let group = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63941")
+                .expect("failed");
+            assert!(group.attribute_value_pres("member", "cc8e95b4-c24f-4d68-ba54-8bed76f63930"));
 
-            // Start a new write
-            let mut server_txn = server.write();
-            // delete the class
-            let de_class =
-                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testclass"))) };
-            assert!(server_txn.delete(audit, &de_class).is_ok());
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            // The request is gone, and asking again now you're a member
+            // doesn't file a new one.
+            assert!(server_txn.group_join_create(audit, &gce).is_ok());
+            let gle = unsafe { GroupJoinListEvent::new_impersonate_entry(manager) };
+            assert!(server_txn.group_join_list(audit, &gle) == Ok(Vec::new()));
 
-            // Start a new write
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_typeahead() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
-            // Trying to add now should fail
-            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_fail).is_err());
-            // Search our entry
-            let testobj1 = server_txn
-                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+            // testperson1 may search groups, but testperson2 may not.
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person"],
+                        "name": ["testperson1"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63950"],
+                        "description": ["testperson1"],
+                        "displayname": ["testperson1"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person"],
+                        "name": ["testperson2"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63951"],
+                        "description": ["testperson2"],
+                        "displayname": ["testperson2"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["testgroup_b"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63952"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["testgroup_a"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63953"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "name": ["othergroup"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63954"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_search"],
+                        "name": ["test_acp_typeahead"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63955"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": ["{\"Eq\":[\"uuid\",\"cc8e95b4-c24f-4d68-ba54-8bed76f63950\"]}"],
+                        "acp_targetscope": ["{\"Eq\":[\"class\",\"group\"]}"],
+                        "acp_search_attr": ["class", "name", "uuid"]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            // Access controls are loaded as the transaction commits.
+            assert!(server_txn.commit(audit).is_ok());
+            let server_txn = server.write();
+
+            let allowed = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63950")
+                .expect("failed");
+            let denied = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63951")
                 .expect("failed");
-            assert!(testobj1.attribute_value_pres("class", "testclass"));
 
-            // Should still be good
-            server_txn.commit(audit).expect("should not fail");
-            // Commit.
+            let te = unsafe {
+                TypeaheadEvent::new_impersonate_entry(allowed.clone(), "group", "testg", 10)
+            };
+            let r = server_txn.typeahead(audit, &te).expect("typeahead failed");
+            let names: Vec<&str> = r.iter().map(|t| t.name.as_str()).collect();
+            assert!(names == vec!["testgroup_a", "testgroup_b"]);
+            assert!(r[0].uuid == "cc8e95b4-c24f-4d68-ba54-8bed76f63953");
+
+            let te = unsafe {
+                TypeaheadEvent::new_impersonate_entry(allowed.clone(), "group", "testg", 1)
+            };
+            // The limit keeps the first by name, not the first found.
+            let r = server_txn.typeahead(audit, &te).expect("typeahead failed");
+            assert!(r.len() == 1);
+            assert!(r[0].name == "testgroup_a");
+
+            // People aren't covered by the acp, so they aren't suggested.
+            let te =
+                unsafe { TypeaheadEvent::new_impersonate_entry(allowed, "person", "testp", 10) };
+            assert!(server_txn.typeahead(audit, &te) == Ok(Vec::new()));
+
+            let te = unsafe { TypeaheadEvent::new_impersonate_entry(denied, "group", "testg", 10) };
+            assert!(server_txn.typeahead(audit, &te) == Ok(Vec::new()));
+            assert!(server_txn.commit(audit).is_ok());
         })
     }
 
     #[test]
-    fn test_qs_dynamic_schema_attr() {
+    fn test_qs_typeahead_reindex() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
-                r#"{
+            // A database from before names had a substring index.
+            let server_txn = server.write();
+            let mut idxmeta = server_txn
+                .get_be_txn()
+                .get_idxmeta(audit)
+                .expect("idxmeta failed");
+            assert!(idxmeta.remove(&("name".to_string(), IndexType::SUBSTRING)));
+            assert!(server_txn.get_be_txn().update_idxmeta(audit, idxmeta) == Ok(true));
+            assert!(server_txn.get_be_txn().reindex(audit).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Starting up indexes the substrings of the names already there,
+            // so a prefix still finds them.
+            assert!(server.initialise_helper(audit).is_ok());
+            let server_txn = server.read();
+            assert!(server_txn
+                .get_be_txn()
+                .get_idxmeta(audit)
+                .expect("idxmeta failed")
+                .contains(&("name".to_string(), IndexType::SUBSTRING)));
+            let r = server_txn
+                .internal_search(audit, filter!(f_startswith("name", "admi")))
+                .expect("search failed");
+            assert!(r.iter().any(|e| e.attribute_equality("name", "admin")));
+        })
+    }
+
+    #[test]
+    fn test_qs_replication() {
+        let mut audit = AuditScope::new("test_qs_replication");
+        // Both start at the same time, so the builtin entries they each
+        // create differ only by replica. The consumer's win, so only what
+        // is made on the supplier afterwards is new to it.
+        let new_server = |audit: &mut AuditScope, rid: &str| {
+            let mut be = Backend::new(audit, "", 1).expect("Failed to init BE");
+            be.set_replica_id(Uuid::parse_str(rid).expect("invalid uuid"));
+            let schema = Schema::new(audit).expect("Failed to init schema");
+            let clock = Arc::new(MockClock::new(Duration::from_secs(1_000_000)));
+            let mut server = QueryServer::new(be, schema);
+            server.set_clock(clock.clone());
+            server.initialise_helper(audit).expect("init failed!");
+            (server, clock)
+        };
+        let s_rid = "00000000-0000-0000-0000-00000000000a";
+        let (supplier, s_clock) = new_server(&mut audit, s_rid);
+        let (consumer, c_clock) = new_server(&mut audit, "00000000-0000-0000-0000-00000000000b");
+        let t1 = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+        let pull = |audit: &mut AuditScope, since: Option<i64>| {
+            let changes = supplier
+                .read()
+                .repl_changes(audit, &ReplChangesEvent::new_internal(since))
+                .expect("repl_changes failed");
+            let mut c_txn = consumer.write();
+            let n = c_txn
+                .repl_apply(audit, &changes.entries)
+                .expect("repl_apply failed");
+            assert!(c_txn.commit(audit).is_ok());
+            (n, Some(changes.seq))
+        };
+        let modify = |audit: &mut AuditScope, server: &QueryServer, attr: &str, value: &str| {
+            let mut txn = server.write();
+            assert!(txn
+                .internal_modify(
+                    audit,
+                    filter!(f_eq("uuid", t1)),
+                    ModifyList::new_list(vec![
+                        Modify::Purged(attr.to_string()),
+                        Modify::Present(attr.to_string(), Value::from(value)),
+                    ]),
+                )
+                .is_ok());
+            assert!(txn.commit(audit).is_ok());
+        };
+        let consumer_t1 = |audit: &mut AuditScope| {
+            consumer
+                .read()
+                .internal_search(audit, filter_all!(f_eq("uuid", t1)))
+                .expect("search failed")
+                .pop()
+                .expect("not replicated")
+        };
+
+        // Only the entry made on the supplier is new to the consumer.
+        s_clock.advance(Duration::from_secs(100));
+        let mut s_txn = supplier.write();
+        let e1: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "person"],
+                "name": ["testperson1"],
+                "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                "description": ["testperson"],
+                "displayname": ["testperson1"]
+            }
+        }"#,
+        )
+        .expect("json failure");
+        assert!(s_txn.internal_create(&mut audit, vec![e1]).is_ok());
+        assert!(s_txn.commit(&mut audit).is_ok());
+        let (n, since) = pull(&mut audit, None);
+        assert!(n == 1);
+        assert!(consumer_t1(&mut audit).attribute_value_pres("description", "testperson"));
+        // Logged with the csn it was made at on the supplier.
+        let c_txn = consumer.read();
+        let changes = c_txn
+            .get_be_txn()
+            .changelog_entries_since(&mut audit, 0)
+            .expect("changelog failed");
+        let logged = changes
+            .iter()
+            .rev()
+            .find(|c| c.uuid == t1)
+            .expect("not logged");
+        let s_rid = Uuid::parse_str(s_rid).expect("invalid uuid");
+        assert!(logged.origin.csn == Csn::new(Duration::from_secs(1_000_100), s_rid));
+        drop(c_txn);
+
+        // Each attribute is taken from where it last changed.
+        s_clock.advance(Duration::from_secs(100));
+        modify(&mut audit, &supplier, "description", "from supplier");
+        modify(&mut audit, &supplier, "displayname", "from supplier");
+        c_clock.advance(Duration::from_secs(300));
+        modify(&mut audit, &consumer, "description", "from consumer");
+        let (n, since) = pull(&mut audit, since);
+        assert!(n == 1);
+        let e = consumer_t1(&mut audit);
+        assert!(e.attribute_value_pres("description", "from consumer"));
+        assert!(e.attribute_value_pres("displayname", "from supplier"));
+        // Nothing more until something changes.
+        assert!(pull(&mut audit, since) == (0, since));
+
+        // Changes made at the same instant are settled by replica, so the
+        // consumer keeps its own.
+        s_clock.advance(Duration::from_secs(100));
+        modify(&mut audit, &supplier, "description", "tie supplier");
+        modify(&mut audit, &consumer, "description", "tie consumer");
+        let (n, since) = pull(&mut audit, since);
+        assert!(n == 0);
+        assert!(consumer_t1(&mut audit).attribute_value_pres("description", "tie consumer"));
+
+        // A delete wins over a later change, once it is a tombstone.
+        s_clock.advance(Duration::from_secs(200));
+        let mut s_txn = supplier.write();
+        assert!(s_txn
+            .internal_delete(&mut audit, filter!(f_eq("uuid", t1)))
+            .is_ok());
+        assert!(s_txn.commit(&mut audit).is_ok());
+        let s_txn = supplier.write();
+        assert!(s_txn
+            .purge_recycled(&mut audit, Duration::from_secs(0))
+            .is_ok());
+        assert!(s_txn.commit(&mut audit).is_ok());
+        c_clock.advance(Duration::from_secs(1000));
+        modify(&mut audit, &consumer, "description", "too late");
+        let (n, _) = pull(&mut audit, since);
+        assert!(n == 1);
+        let e = consumer_t1(&mut audit);
+        assert!(e.attribute_value_pres("class", "tombstone"));
+        assert!(!e.attribute_pres("description"));
+
+        // Replication bypasses access controls, so is only for admins.
+        let c_txn = consumer.read();
+        let anon = c_txn
+            .internal_search_uuid(&mut audit, UUID_ANONYMOUS)
+            .expect("failed");
+        let rce = unsafe { ReplChangesEvent::new_impersonate_entry(anon, None) };
+        assert!(c_txn.repl_changes(&mut audit, &rce).err() == Some(OperationError::AccessDenied));
+        drop(c_txn);
+
+        // The agreement records how far it got, and stays on the consumer.
+        let mut c_txn = consumer.write();
+        let agreement: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "replication_agreement"],
+                "name": ["from_supplier"],
+                "uuid": ["2a4f9b7e-2c7e-4b6f-8d8e-5b6a1c3d9e01"],
+                "repl_supplier": ["https://supplier.example.com"],
+                "repl_user": ["00000000-0000-0000-0000-000000000000"]
+            }
+        }"#,
+        )
+        .expect("json failure");
+        assert!(c_txn.internal_create(&mut audit, vec![agreement]).is_ok());
+        let seq = since.expect("no seq");
+        assert!(c_txn
+            .repl_agreement_update(&mut audit, "2a4f9b7e-2c7e-4b6f-8d8e-5b6a1c3d9e01", seq)
+            .is_ok());
+        let a = c_txn
+            .internal_search_uuid(&mut audit, "2a4f9b7e-2c7e-4b6f-8d8e-5b6a1c3d9e01")
+            .expect("failed");
+        assert!(a.get_ava_single("repl_seq").and_then(|v| v.to_integer()) == Some(seq));
+        assert!(c_txn.commit(&mut audit).is_ok());
+        let changes = consumer
+            .read()
+            .repl_changes(&mut audit, &ReplChangesEvent::new_internal(None))
+            .expect("repl_changes failed");
+        assert!(changes
+            .entries
+            .iter()
+            .all(|re| re.uuid != "2a4f9b7e-2c7e-4b6f-8d8e-5b6a1c3d9e01"));
+        println!("{}", audit);
+    }
+
+    #[test]
+    fn test_qs_replication_plugins() {
+        let mut audit = AuditScope::new("test_qs_replication_plugins");
+        let new_server = |audit: &mut AuditScope, rid: &str| {
+            let mut be = Backend::new(audit, "", 1).expect("Failed to init BE");
+            be.set_replica_id(Uuid::parse_str(rid).expect("invalid uuid"));
+            let schema = Schema::new(audit).expect("Failed to init schema");
+            let clock = Arc::new(MockClock::new(Duration::from_secs(1_000_000)));
+            let mut server = QueryServer::new(be, schema);
+            server.set_clock(clock.clone());
+            server.initialise_helper(audit).expect("init failed!");
+            (server, clock)
+        };
+        let (supplier, s_clock) = new_server(&mut audit, "00000000-0000-0000-0000-00000000000a");
+        let (consumer, c_clock) = new_server(&mut audit, "00000000-0000-0000-0000-00000000000b");
+        let pull = |audit: &mut AuditScope, since: Option<i64>| {
+            let changes = supplier
+                .read()
+                .repl_changes(audit, &ReplChangesEvent::new_internal(since))
+                .expect("repl_changes failed");
+            let mut c_txn = consumer.write();
+            assert!(c_txn.repl_apply(audit, &changes.entries).is_ok());
+            assert!(c_txn.commit(audit).is_ok());
+            Some(changes.seq)
+        };
+        let p1 = "cc8e95b4-c24f-4d68-ba54-8bed76f63a01";
+        let p2 = "cc8e95b4-c24f-4d68-ba54-8bed76f63a02";
+        let g1 = "cc8e95b4-c24f-4d68-ba54-8bed76f63a03";
+
+        s_clock.advance(Duration::from_secs(100));
+        let mut s_txn = supplier.write();
+        let entries = [
+            ("rp_p1", p1, "person"),
+            ("rp_p2", p2, "person"),
+            ("rp_g1", g1, "group"),
+        ]
+        .iter()
+        .map(|(name, uuid, class)| {
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e.add_ava("class", "object");
+            e.add_ava("class", class);
+            e.add_ava("name", name);
+            e.add_ava("uuid", uuid);
+            if *class == "person" {
+                e.add_ava("displayname", name);
+            }
+            e
+        })
+        .collect();
+        assert!(s_txn.internal_create(&mut audit, entries).is_ok());
+        assert!(s_txn.commit(&mut audit).is_ok());
+        let since = pull(&mut audit, None);
+
+        // p2 is deleted here, and is a tombstone by the time the supplier,
+        // which hasn't seen that, adds both to the group.
+        c_clock.advance(Duration::from_secs(200));
+        let mut c_txn = consumer.write();
+        assert!(c_txn
+            .internal_delete(&mut audit, filter!(f_eq("uuid", p2)))
+            .is_ok());
+        assert!(c_txn.commit(&mut audit).is_ok());
+        let c_txn = consumer.write();
+        assert!(c_txn
+            .purge_recycled(&mut audit, Duration::from_secs(0))
+            .is_ok());
+        assert!(c_txn.commit(&mut audit).is_ok());
+        s_clock.advance(Duration::from_secs(200));
+        let mut s_txn = supplier.write();
+        assert!(s_txn
+            .internal_modify(
+                &mut audit,
+                filter!(f_eq("uuid", g1)),
+                ModifyList::new_list(vec![
+                    Modify::Present("member".to_string(), Value::from(p1)),
+                    Modify::Present("member".to_string(), Value::from(p2)),
+                ]),
+            )
+            .is_ok());
+        assert!(s_txn.commit(&mut audit).is_ok());
+        pull(&mut audit, since);
+
+        // The reference to the entry deleted here is dropped, and memberof
+        // follows what remains.
+        let c_txn = consumer.read();
+        let g = c_txn
+            .internal_search_uuid(&mut audit, g1)
+            .expect("group not replicated");
+        assert!(g.attribute_value_pres("member", p1));
+        assert!(!g.attribute_value_pres("member", p2));
+        let p = c_txn
+            .internal_search_uuid(&mut audit, p1)
+            .expect("person not replicated");
+        assert!(p.attribute_value_pres("memberof", g1));
+        drop(c_txn);
+        assert!(consumer.verify(&mut audit).len() == 0);
+        println!("{}", audit);
+    }
+
+    #[test]
+    fn test_qs_password() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let person = |name: &str, uuid: &str, valid: bool| {
+                format!(
+                    r#"{{
+                        "valid": {},
+                        "state": null,
+                        "attrs": {{
+                            "class": ["object", "account"],
+                            "name": ["{}"],
+                            "uuid": ["{}"],
+                            "displayname": ["{}"]
+                        }}
+                    }}"#,
+                    if valid {
+                        format!("{{\"uuid\": \"{}\"}}", uuid)
+                    } else {
+                        "null".to_string()
+                    },
+                    name,
+                    uuid,
+                    name
+                )
+            };
+            let user_a = person("pw_user_a", "cc8e95b4-c24f-4d68-ba54-8bed76f639e1", true);
+            let user_b = person("pw_user_b", "cc8e95b4-c24f-4d68-ba54-8bed76f639e2", true);
+            // Even a profile that grants it can't expose the hashes.
+            let acp = r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "extensibleobject"],
-                    "name": ["testobj1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "testattr": ["test"]
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["pw_acp_search"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639e3"],
+                    "description": ["Everyone can read everything."],
+                    "acp_enable": ["true"],
+                    "acp_receiver": [
+                        "{\"Pres\":\"class\"}"
+                    ],
+                    "acp_targetscope": [
+                        "{\"Pres\":\"class\"}"
+                    ],
+                    "acp_search_attr": ["name", "class", "password"]
                 }
-            }"#,
-            )
-            .expect("json failure");
+            }"#;
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                person("pw_user_a", "cc8e95b4-c24f-4d68-ba54-8bed76f639e1", false),
+                person("pw_user_b", "cc8e95b4-c24f-4d68-ba54-8bed76f639e2", false),
+                acp.to_string(),
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s.as_str()).expect("json failure"))
+            .collect();
+            assert!(server_txn.internal_create(audit, entries).is_ok());
+            // Access controls are loaded as the transaction commits.
+            assert!(server_txn.commit(audit).is_ok());
+            let mut server_txn = server.write();
 
-            // Attribute definition
-            let e_ad: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            // Recovery sets a random password, which is only stored hashed.
+            let pw = server_txn
+                .recover_account(audit, "pw_user_a")
+                .expect("recover failed");
+            let stored = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f639e1")
+                .expect("search failed")
+                .get_ava_single("password")
+                .expect("no password")
+                .to_string();
+            assert!(stored != pw);
+            let hash = Password::from_stored(stored.as_str()).expect("not a hash");
+            assert!(hash.verify(pw.as_str()));
+            assert!(
+                server_txn.recover_account(audit, "anonymous")
+                    == Err(OperationError::InvalidAccountState(
+                        "anonymous has no password"
+                    ))
+            );
+            assert!(
+                server_txn.recover_account(audit, "pw_user_c")
+                    == Err(OperationError::NoMatchingEntries)
+            );
+
+            // The hash is never returned, or matched by a filter, even to the owner.
+            for e in vec![user_a.as_str(), user_b.as_str()] {
+                let se = unsafe {
+                    SearchEvent::new_impersonate_entry_ser(e, filter!(f_eq("name", "pw_user_a")))
+                };
+                let res = server_txn.search_ext(audit, &se).expect("search failed");
+                assert!(res.len() == 1);
+                assert!(res[0].attribute_pres("name"));
+                assert!(!res[0].attribute_pres("password"));
+                let se = unsafe {
+                    SearchEvent::new_impersonate_entry_ser(e, filter!(f_pres("password")))
+                };
+                let res = server_txn.search_ext(audit, &se).expect("search failed");
+                assert!(res.is_empty());
+            }
+
+            // An account may set its own password, but not that of another.
+            let set_pw = |e: &str| unsafe {
+                ModifyEvent::new_impersonate_entry_ser(
+                    e,
+                    filter!(f_eq("name", "pw_user_a")),
+                    ModifyList::new_list(vec![
+                        Modify::Purged("password".to_string()),
+                        Modify::Present("password".to_string(), Value::from("new password")),
+                    ]),
+                )
+            };
+            assert!(
+                server_txn.modify(audit, &set_pw(user_b.as_str()))
+                    == Err(OperationError::AccessDenied)
+            );
+            assert!(server_txn.modify(audit, &set_pw(user_a.as_str())).is_ok());
+            let stored = server_txn
+                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f639e1")
+                .expect("search failed")
+                .get_ava_single("password")
+                .expect("no password")
+                .to_string();
+            let hash = Password::from_stored(stored.as_str()).expect("not a hash");
+            assert!(hash.verify("new password"));
+            assert!(!hash.verify(pw.as_str()));
+        })
+    }
+
+    #[test]
+    fn test_qs_break_glass() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let expiry_of = |e: &str| {
+                chrono::DateTime::parse_from_rfc3339(e)
+                    .expect("bad expiry")
+                    .timestamp() as u64
+            };
+
+            // The secret is only stored hashed, with its expiry.
+            let (secret, expiry) = server_txn
+                .break_glass(audit, "admin", Duration::from_secs(600))
+                .expect("break glass failed");
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("search failed");
+            let stored = admin
+                .get_ava_single("break_glass_secret")
+                .expect("no secret")
+                .to_string();
+            assert!(stored != secret);
+            let hash = Password::from_stored(stored.as_str()).expect("not a hash");
+            assert!(hash.verify(secret.as_str()));
+            assert!(
+                admin
+                    .get_ava_single("break_glass_expiry")
+                    .map(|v| v.to_string())
+                    == Some(expiry.clone())
+            );
+            assert!(expiry_of(expiry.as_str()) >= server.now().as_secs() + 590);
+
+            // A new secret replaces the last.
+            let (secret2, _) = server_txn
+                .break_glass(audit, "admin", Duration::from_secs(600))
+                .expect("break glass failed");
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("search failed");
+            let stored = admin
+                .get_ava_single("break_glass_secret")
+                .expect("no secret");
+            let hash = Password::from_stored(stored.to_string().as_str()).expect("not a hash");
+            assert!(hash.verify(secret2.as_str()));
+            assert!(!hash.verify(secret.as_str()));
+
+            // Lifetimes are capped.
+            let (_, expiry) = server_txn
+                .break_glass(audit, "admin", Duration::from_secs(365 * 86400))
+                .expect("break glass failed");
+            assert!(
+                expiry_of(expiry.as_str()) <= server.now().as_secs() + BREAK_GLASS_MAX_LIFETIME
+            );
+
+            assert!(
+                server_txn.break_glass(audit, "anonymous", Duration::from_secs(600))
+                    == Err(OperationError::InvalidAccountState(
+                        "anonymous can't break glass"
+                    ))
+            );
+            assert!(
+                server_txn.break_glass(audit, "nobody", Duration::from_secs(600))
+                    == Err(OperationError::NoMatchingEntries)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_credential_reset() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let target = "cc8e95b4-c24f-4d68-ba54-8bed76f639f1";
+            let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "attributetype"],
-                    "name": ["testattr"],
-                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
-                    "description": ["Test Attribute"],
-                    "multivalue": ["false"],
-                    "syntax": ["UTF8STRING"]
+                    "class": ["object", "account"],
+                    "name": ["cr_user"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f639f1"],
+                    "displayname": ["cr_user"]
                 }
             }"#,
             )
             .expect("json failure");
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            let admin = format!(
+                r#"{{
+                    "valid": {{"uuid": "{}"}},
+                    "state": null,
+                    "attrs": {{
+                        "class": ["object", "account"],
+                        "name": ["admin"],
+                        "uuid": ["{}"],
+                        "memberof": ["{}"]
+                    }}
+                }}"#,
+                UUID_ADMIN, UUID_ADMIN, UUID_IDM_ADMINS
+            );
+            let issue = |txn: &mut QueryServerWriteTransaction,
+                         audit: &mut AuditScope,
+                         initiator: &str,
+                         lifetime: u64| {
+                let cre = unsafe {
+                    CredentialResetIssueEvent::new_impersonate_entry_ser(
+                        initiator,
+                        target,
+                        Some(Duration::from_secs(lifetime)),
+                    )
+                };
+                txn.issue_credential_reset(audit, &cre)
+            };
+            let password_of = |txn: &QueryServerWriteTransaction, audit: &mut AuditScope| {
+                txn.internal_search_uuid(audit, target)
+                    .expect("search failed")
+                    .get_ava_single("password")
+                    .and_then(|v| Password::from_stored(v.to_string().as_str()))
+                    .expect("no password")
+            };
 
-            let mut server_txn = server.write();
-            // Add a new attribute.
-            let ce_attr = CreateEvent::new_internal(vec![e_ad.clone()]);
-            assert!(server_txn.create(audit, &ce_attr).is_ok());
-            // Trying to add it now should fail. (use extensible object)
-            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_fail).is_err());
+            // Only idm_admins may issue tokens, and only the hash is kept.
+            assert!(issue(&mut server_txn, audit, JSON_ANONYMOUS_V1, 600).is_err());
+            let r = issue(&mut server_txn, audit, admin.as_str(), 600).expect("issue failed");
+            assert!(r.token.starts_with(target));
+            let stored = server_txn
+                .internal_search_uuid(audit, target)
+                .expect("search failed")
+                .get_ava_single("credential_reset_token")
+                .expect("no token")
+                .to_string();
+            assert!(!r.token.contains(stored.as_str()));
+
+            // Redeeming sets the password and spends the token.
+            assert!(server_txn
+                .redeem_credential_reset(audit, r.token.as_str(), "correct horse")
+                .is_ok());
+            assert!(password_of(&server_txn, audit).verify("correct horse"));
+            let e = server_txn
+                .internal_search_uuid(audit, target)
+                .expect("search failed");
+            assert!(e.get_ava_single("credential_reset_token").is_none());
+            assert!(e.get_ava_single("credential_reset_expiry").is_none());
+            assert!(
+                server_txn.redeem_credential_reset(audit, r.token.as_str(), "battery staple")
+                    == Err(OperationError::InvalidCredentialResetToken)
+            );
 
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            // A new token replaces the last, and expired, wrong or malformed
+            // tokens are all refused the same way.
+            let r1 = issue(&mut server_txn, audit, admin.as_str(), 600).expect("issue failed");
+            let r2 = issue(&mut server_txn, audit, admin.as_str(), 600).expect("issue failed");
+            let expired = issue(&mut server_txn, audit, admin.as_str(), 0).expect("issue failed");
+            for bad in &[
+                r1.token.as_str(),
+                r2.token.as_str(),
+                expired.token.as_str(),
+                "nonsense",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f639f2.secret",
+            ] {
+                assert!(
+                    server_txn.redeem_credential_reset(audit, bad, "battery staple")
+                        == Err(OperationError::InvalidCredentialResetToken)
+                );
+            }
+            assert!(password_of(&server_txn, audit).verify("correct horse"));
+
+            // Past the rate limit even a good token is refused.
+            let r = issue(&mut server_txn, audit, admin.as_str(), 600).expect("issue failed");
+            for _ in 7..CREDENTIAL_RESET_MAX_ATTEMPTS {
+                assert!(server_txn
+                    .redeem_credential_reset(audit, "nonsense", "battery staple")
+                    .is_err());
+            }
+            assert!(
+                server_txn.redeem_credential_reset(audit, r.token.as_str(), "battery staple")
+                    == Err(OperationError::RateLimited)
+            );
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 
-            // Start a new write
+    #[test]
+    fn test_qs_log_levels() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
-            // Add the attr to an object
-            // should work
-            let ce_work = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_work).is_ok());
-
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            let admin = server_txn
+                .internal_search_uuid(audit, UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, UUID_ANONYMOUS)
+                .expect("failed");
+            let level_of = |levels: &Vec<LogLevel>, s: &str| {
+                levels
+                    .iter()
+                    .find(|l| l.subsystem == s)
+                    .map(|l| l.level.clone())
+                    .expect("missing subsystem")
+            };
 
-            // Start a new write
-            let mut server_txn = server.write();
-            // delete the attr
-            let de_attr =
-                unsafe { DeleteEvent::new_internal_invalid(filter!(f_eq("name", "testattr"))) };
-            assert!(server_txn.delete(audit, &de_attr).is_ok());
-            // Commit
-            server_txn.commit(audit).expect("should not fail");
+            // Only idm_admins may change them.
+            let lle = unsafe {
+                LogLevelEvent::new_impersonate_entry(anon, vec![("be", AuditLevel::Debug)])
+            };
+            assert!(server_txn.set_log_levels(audit, &lle) == Err(OperationError::AccessDenied));
 
-            // Start a new write
-            let mut server_txn = server.write();
-            // Trying to add now should fail
-            let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
-            assert!(server_txn.create(audit, &ce_fail).is_err());
-            // Search our attribute - should FAIL
-            let filt = filter!(f_eq("testattr", "test"));
-            assert!(server_txn.internal_search(audit, filt).is_err());
-            // Search the entry - the attribute will still be present
-            // even if we can't search on it.
-            let testobj1 = server_txn
-                .internal_search_uuid(audit, "cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+            let lle = unsafe {
+                LogLevelEvent::new_impersonate_entry(
+                    admin.clone(),
+                    vec![("be", AuditLevel::Debug), ("access", AuditLevel::Debug)],
+                )
+            };
+            let levels = server_txn.set_log_levels(audit, &lle).expect("set failed");
+            assert!(levels.len() == 4);
+            assert!(level_of(&levels, "be") == "debug");
+            assert!(level_of(&levels, "access") == "debug");
+            assert!(level_of(&levels, "idm") == "info");
+
+            // Others are kept, and the default isn't stored.
+            let lle = unsafe {
+                LogLevelEvent::new_impersonate_entry(admin, vec![("be", AuditLevel::Info)])
+            };
+            let levels = server_txn.set_log_levels(audit, &lle).expect("set failed");
+            assert!(level_of(&levels, "be") == "info");
+            assert!(level_of(&levels, "access") == "debug");
+            let config = server_txn
+                .internal_search_uuid(audit, UUID_SYSTEM_CONFIG)
                 .expect("failed");
-            assert!(testobj1.attribute_value_pres("testattr", "test"));
+            assert!(config.get_ava("log_level").map(|vs| vs.len()) == Some(1));
+            assert!(config.attribute_equality("log_level", "access=debug"));
+            assert!(server_txn.commit(audit).is_ok());
 
-            server_txn.commit(audit).expect("should not fail");
-            // Commit.
+            // What's stored is what is applied, at commit and startup.
+            let server_txn = server.read();
+            let stored = server_txn.log_levels(audit);
+            assert!(stored.len() == 1);
+            assert!(stored.get("access") == Some(&AuditLevel::Debug));
         })
     }
 }
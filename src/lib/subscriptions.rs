@@ -0,0 +1,502 @@
+// Persistent searches, so sync consumers can follow changes rather than
+// polling with full searches.
+//
+// Subscriptions are told of changes by the change bus, so only after they
+// commit, and never slow down the writer. Each subscription keeps the uuids
+// of the entries it has been able to see. Created and modified entries are
+// searched for again as the subscriber, so every notification is access
+// checked and reduced as any search would be; those that no longer match
+// but were seen before are sent as deleted.
+//
+// A subscription ends when its session expires or can no longer search,
+// when its subscriber falls SUBSCRIPTION_BUFFER notifications behind, or at
+// the first notification after its receiver is dropped. Sessions are
+// checked at each notification, and every SUBSCRIPTION_EXPIRY_INTERVAL
+// for subscriptions that have nothing to be told.
+
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::access::AccessControlsTransaction;
+use crate::audit::AuditScope;
+use crate::changes::{ChangeOp, ChangeSubscriber, ChangeSummary};
+use crate::constants::{SUBSCRIPTION_BUFFER, SUBSCRIPTION_MAX_PER_USER};
+use crate::error::OperationError;
+use crate::event::{Event, SearchEvent};
+use crate::proto::v1::{
+    Entry as ProtoEntry, Filter as ProtoFilter, SubscribeRequest, SubscriptionNotification,
+    SubscriptionOp, UserAuthToken,
+};
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServer, QueryServerReadTransaction, QueryServerTransaction};
+
+struct Subscription {
+    id: Uuid,
+    uat: UserAuthToken,
+    filter: ProtoFilter,
+    attrs: Option<BTreeSet<String>>,
+    visible: BTreeSet<String>,
+    tx: Sender<SubscriptionNotification>,
+}
+
+impl Subscription {
+    // The session may have expired, or the account been disabled or
+    // deleted, since the last notification.
+    fn session_valid(&self, au: &mut AuditScope, qs_read: &QueryServerReadTransaction) -> bool {
+        Event::from_ro_uat(au, qs_read, Some(self.uat.clone())).is_ok()
+    }
+
+    fn entry(&self, pe: ProtoEntry) -> ProtoEntry {
+        match &self.attrs {
+            Some(attrs) => ProtoEntry {
+                attrs: pe
+                    .attrs
+                    .into_iter()
+                    .filter(|(k, _)| attrs.contains(k))
+                    .collect(),
+            },
+            None => pe,
+        }
+    }
+
+    // What this subscriber should be told of the changes. An entry changed
+    // more than once in the transaction is only sent once.
+    fn changes(
+        &mut self,
+        au: &mut AuditScope,
+        qs_read: &QueryServerReadTransaction,
+        changes: &[ChangeSummary],
+    ) -> Result<Vec<SubscriptionNotification>, OperationError> {
+        let changed: BTreeSet<&String> = changes
+            .iter()
+            .filter(|c| c.op != ChangeOp::Delete)
+            .map(|c| &c.uuid)
+            .collect();
+        let mut found: BTreeMap<String, ProtoEntry> = BTreeMap::new();
+        if !changed.is_empty() {
+            let filter = ProtoFilter::And(vec![
+                self.filter.clone(),
+                ProtoFilter::Or(
+                    changed
+                        .iter()
+                        .map(|u| ProtoFilter::Eq("uuid".to_string(), u.to_string()))
+                        .collect(),
+                ),
+            ]);
+            let se = SearchEvent::from_uat(au, Some(self.uat.clone()), &filter, qs_read)?;
+            let entries = qs_read.search(au, &se)?;
+            for e in qs_read
+                .get_accesscontrols()
+                .search_filter_entry_attributes(au, &se, entries)?
+            {
+                // The filter is on uuid, so it can be read.
+                let pe = e.into_pe();
+                if let Some(u) = pe.attrs.get("uuid").and_then(|vs| vs.first()) {
+                    found.insert(u.clone(), pe);
+                }
+            }
+        }
+
+        let mut sent = BTreeSet::new();
+        let mut notes = Vec::new();
+        for c in changes.iter() {
+            if !sent.insert(c.uuid.as_str()) {
+                continue;
+            }
+            let (op, entry) = match found.remove(&c.uuid) {
+                Some(pe) => {
+                    let op = if self.visible.insert(c.uuid.clone()) {
+                        SubscriptionOp::Added
+                    } else {
+                        SubscriptionOp::Modified
+                    };
+                    (op, Some(self.entry(pe)))
+                }
+                None if self.visible.contains(&c.uuid) => {
+                    self.visible.remove(&c.uuid);
+                    (SubscriptionOp::Deleted, None)
+                }
+                None => continue,
+            };
+            notes.push(SubscriptionNotification {
+                op: op,
+                uuid: c.uuid.clone(),
+                entry: entry,
+            });
+        }
+        Ok(notes)
+    }
+}
+
+pub struct Subscriptions {
+    qs: QueryServer,
+    subs: Mutex<Vec<Subscription>>,
+}
+
+impl Subscriptions {
+    pub fn new(qs: QueryServer) -> Self {
+        Subscriptions {
+            qs: qs,
+            subs: Mutex::new(Vec::new()),
+        }
+    }
+
+    // The filter is checked now, so a bad one is refused rather than ending
+    // the subscription at the first change.
+    pub fn subscribe(
+        &self,
+        au: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        req: SubscribeRequest,
+    ) -> Result<Receiver<SubscriptionNotification>, OperationError> {
+        let qs_read = self.qs.read();
+        let se = SearchEvent::from_uat(au, uat.clone(), &req.filter, &qs_read)?;
+        let visible = qs_read
+            .search(au, &se)?
+            .iter()
+            .map(|e| e.get_uuid().clone())
+            .collect();
+        let uat = uat.ok_or(OperationError::NotAuthenticated)?;
+        let schema = qs_read.get_schema();
+        let attrs = req.attrs.map(|attrs| {
+            attrs
+                .iter()
+                .map(|a| schema.normalise_attr_name(a))
+                .collect()
+        });
+
+        let mut subs = self.subs.lock().map_err(|_| OperationError::InvalidState)?;
+        if subs.iter().filter(|s| s.uat.uuid == uat.uuid).count() >= SUBSCRIPTION_MAX_PER_USER {
+            audit_log!(au, "{} has too many subscriptions", uat.name);
+            return Err(OperationError::TooManySubscriptions(
+                SUBSCRIPTION_MAX_PER_USER,
+            ));
+        }
+        let (tx, rx) = channel(SUBSCRIPTION_BUFFER);
+        let id = Uuid::new_v4();
+        audit_log!(au, "Subscription {} for {}", id, uat.name);
+        subs.push(Subscription {
+            id: id,
+            uat: uat,
+            filter: req.filter,
+            attrs: attrs,
+            visible: visible,
+            tx: tx,
+        });
+        Ok(rx)
+    }
+
+    fn notify_all(&self, au: &mut AuditScope, changes: &[ChangeSummary]) {
+        let mut subs = match self.subs.lock() {
+            Ok(subs) => subs,
+            Err(_) => {
+                error!("Unable to notify subscriptions, the lock is poisoned");
+                return;
+            }
+        };
+        if subs.is_empty() {
+            return;
+        }
+        let qs_read = self.qs.read();
+        let kept: Vec<Subscription> = subs
+            .drain(..)
+            .filter_map(|mut s| {
+                if !s.session_valid(au, &qs_read) {
+                    audit_log!(au, "Ending subscription {}, its session has ended", s.id);
+                    return None;
+                }
+                // A subscriber that has fallen behind is dropped, rather than
+                // its notifications being held for it.
+                let sent = s
+                    .changes(au, &qs_read, changes)
+                    .map(|notes| notes.into_iter().all(|n| s.tx.try_send(n).is_ok()));
+                match sent {
+                    Ok(true) => Some(s),
+                    Ok(false) => {
+                        audit_log!(au, "Subscription {} was closed or fell behind", s.id);
+                        None
+                    }
+                    Err(e) => {
+                        audit_log!(au, "Ending subscription {}: {:?}", s.id, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        *subs = kept;
+    }
+
+    // End the subscriptions whose sessions have ended.
+    pub fn expire(&self, au: &mut AuditScope) -> usize {
+        let mut subs = match self.subs.lock() {
+            Ok(subs) => subs,
+            Err(_) => {
+                error!("Unable to expire subscriptions, the lock is poisoned");
+                return 0;
+            }
+        };
+        let qs_read = self.qs.read();
+        let before = subs.len();
+        subs.retain(|s| s.session_valid(au, &qs_read));
+        before - subs.len()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.subs.lock().expect("lock poisoned").len()
+    }
+}
+
+impl ChangeSubscriber for Subscriptions {
+    fn id(&self) -> &'static str {
+        "subscriptions"
+    }
+
+    fn notify(&self, changes: &[ChangeSummary]) {
+        let mut au = AuditScope::new("subscriptions_notify");
+        self.notify_all(&mut au, changes);
+        debug!("{}", au);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::changes::{ChangeOp, ChangeSummary};
+    use crate::clock::MockClock;
+    use crate::constants::{SUBSCRIPTION_BUFFER, SUBSCRIPTION_MAX_PER_USER};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::OperationError;
+    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
+    use crate::modify::{Modify, ModifyList};
+    use crate::proto::v1::{
+        Filter as ProtoFilter, SubscribeRequest, SubscriptionNotification, SubscriptionOp,
+        UserAuthToken,
+    };
+    use crate::server::QueryServer;
+    use crate::subscriptions::Subscriptions;
+    use crate::value::Value;
+    use futures::sync::mpsc::Receiver;
+    use futures::{future, Async, Future, Stream};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    static UUID_SUB_GROUP: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63c02";
+
+    fn group(name: &str, uuid: &str) -> Entry<EntryInvalid, EntryNew> {
+        let mut e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "description": ["hidden"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        e.add_ava("name", name);
+        e.add_ava("uuid", uuid);
+        e
+    }
+
+    fn rename(au: &mut AuditScope, qs: &QueryServer, name: &str) {
+        let mut qs_write = qs.write();
+        let me = unsafe {
+            ModifyEvent::new_internal_invalid(
+                filter!(f_eq("uuid", UUID_SUB_GROUP)),
+                ModifyList::new_list(vec![
+                    Modify::Purged("name".to_string()),
+                    Modify::Present("name".to_string(), Value::from(name)),
+                ]),
+            )
+        };
+        assert!(qs_write.modify(au, &me).is_ok());
+        qs_write.commit(au).expect("Must not fail");
+    }
+
+    fn change(uuid: &str, op: ChangeOp) -> Vec<ChangeSummary> {
+        vec![ChangeSummary {
+            uuid: uuid.to_string(),
+            classes: vec!["object".to_string(), "group".to_string()],
+            op: op,
+        }]
+    }
+
+    // The next notification, if one has been sent.
+    fn next(rx: &mut Receiver<SubscriptionNotification>) -> Option<SubscriptionNotification> {
+        match future::lazy(|| rx.poll()).wait() {
+            Ok(Async::Ready(n)) => n,
+            _ => None,
+        }
+    }
+
+    // A subscriber that may only read the names of some groups.
+    fn sub_user(au: &mut AuditScope, qs: &QueryServer) -> UserAuthToken {
+        {
+            let mut qs_write = qs.write();
+            let mut entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "account"],
+                        "name": ["sub_user"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63c01"],
+                        "displayname": ["Sub User"]
+                    }
+                }"#,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_search"],
+                        "name": ["sub_acp"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63c03"],
+                        "acp_enable": ["true"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"sub_user\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"And\":[{\"Eq\":[\"class\",\"group\"]},{\"Sub\":[\"name\",\"sub\"]}]}"
+                        ],
+                        "acp_search_attr": ["class", "name", "uuid"]
+                    }
+                }"#,
+            ]
+            .into_iter()
+            .map(|s| serde_json::from_str(s).expect("json failure"))
+            .collect();
+            entries.push(group("sub_group", UUID_SUB_GROUP));
+            assert!(qs_write.internal_create(au, entries).is_ok());
+            qs_write.commit(au).expect("Must not fail");
+        }
+        UserAuthToken {
+            name: "sub_user".to_string(),
+            displayname: "Sub User".to_string(),
+            uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63c01".to_string(),
+            spn: String::new(),
+            application: None,
+            groups: Vec::new(),
+            claims: Vec::new(),
+            expiry: None,
+        }
+    }
+
+    #[test]
+    fn test_subscriptions_notify() {
+        run_test!(|qs: &QueryServer, au: &mut AuditScope| {
+            let uat = sub_user(au, qs);
+            let subs = Subscriptions::new(qs.clone());
+            let req = || {
+                SubscribeRequest::new(
+                    ProtoFilter::Eq("class".to_string(), "group".to_string()),
+                    Some(vec!["Name"]),
+                )
+            };
+            assert!(subs.subscribe(au, None, req()).is_err());
+            let mut rx = subs
+                .subscribe(au, Some(uat), req())
+                .expect("subscribe failure");
+
+            // Only what the subscriber can read is sent.
+            rename(au, qs, "sub_group2");
+            subs.notify_all(au, &change(UUID_SUB_GROUP, ChangeOp::Modify));
+            let n = next(&mut rx).expect("no notification");
+            assert!(n.op == SubscriptionOp::Modified);
+            assert!(n.uuid == UUID_SUB_GROUP);
+            let pe = n.entry.expect("no entry");
+            assert!(pe.attrs["name"] == vec!["sub_group2".to_string()]);
+            assert!(pe.attrs.len() == 1);
+
+            // Renamed out of what it can see.
+            rename(au, qs, "other_group");
+            subs.notify_all(au, &change(UUID_SUB_GROUP, ChangeOp::Modify));
+            let n = next(&mut rx).expect("no notification");
+            assert!(n.op == SubscriptionOp::Deleted && n.entry.is_none());
+
+            // Deleting what it can't see sends nothing.
+            {
+                let mut qs_write = qs.write();
+                let de = unsafe {
+                    DeleteEvent::new_internal_invalid(filter!(f_eq("uuid", UUID_SUB_GROUP)))
+                };
+                assert!(qs_write.delete(au, &de).is_ok());
+                qs_write.commit(au).expect("Must not fail");
+            }
+            subs.notify_all(au, &change(UUID_SUB_GROUP, ChangeOp::Delete));
+            assert!(next(&mut rx).is_none());
+
+            // A closed subscription is dropped at its next notification.
+            drop(rx);
+            let new_group = "cc8e95b4-c24f-4d68-ba54-8bed76f63c04";
+            {
+                let mut qs_write = qs.write();
+                let ce = CreateEvent::new_internal(vec![group("sub_new", new_group)]);
+                assert!(qs_write.create(au, &ce).is_ok());
+                qs_write.commit(au).expect("Must not fail");
+            }
+            assert!(subs.len() == 1);
+            subs.notify_all(au, &change(new_group, ChangeOp::Create));
+            assert!(subs.len() == 0);
+        });
+    }
+
+    #[test]
+    fn test_subscriptions_limits() {
+        run_test!(|qs: &QueryServer, au: &mut AuditScope| {
+            let uat = sub_user(au, qs);
+            let req = || {
+                SubscribeRequest::new(
+                    ProtoFilter::Eq("class".to_string(), "group".to_string()),
+                    None,
+                )
+            };
+
+            // Each account may only have so many.
+            let subs = Subscriptions::new(qs.clone());
+            let mut rxs: Vec<_> = (0..SUBSCRIPTION_MAX_PER_USER)
+                .map(|_| {
+                    subs.subscribe(au, Some(uat.clone()), req())
+                        .expect("subscribe failure")
+                })
+                .collect();
+            assert!(
+                subs.subscribe(au, Some(uat.clone()), req()).err()
+                    == Some(OperationError::TooManySubscriptions(
+                        SUBSCRIPTION_MAX_PER_USER
+                    ))
+            );
+
+            // Those that keep up are kept, those that fall behind are ended.
+            let mut behind = rxs.pop().expect("no subscription");
+            let change = change(UUID_SUB_GROUP, ChangeOp::Modify);
+            for _ in 0..SUBSCRIPTION_BUFFER + 2 {
+                subs.notify_all(au, &change);
+                rxs.iter_mut().for_each(|rx| {
+                    assert!(next(rx).is_some());
+                });
+            }
+            assert!(subs.len() == SUBSCRIPTION_MAX_PER_USER - 1);
+            assert!(next(&mut behind).is_some());
+
+            // A session that expires ends its subscriptions, even with no
+            // changes to send.
+            let clock = Arc::new(MockClock::new(Duration::from_secs(1000)));
+            let mut qs_clocked = qs.clone();
+            qs_clocked.set_clock(clock.clone());
+            let subs = Subscriptions::new(qs_clocked);
+            let mut expiring = uat.clone();
+            expiring.expiry = Some("1970-01-01T00:33:20+00:00".to_string());
+            let _rx = subs
+                .subscribe(au, Some(expiring), req())
+                .expect("subscribe failure");
+            assert!(subs.expire(au) == 0);
+            clock.advance(Duration::from_secs(1000));
+            assert!(subs.expire(au) == 1);
+            assert!(subs.len() == 0);
+        });
+    }
+}
@@ -0,0 +1,88 @@
+// A self contained server for integration tests.
+//
+// This starts the complete stack - the http actors, the query server and an
+// in memory backend - on an ephemeral port, and hands back a client that is
+// able to talk to it. Because requests go over the wire, tests built on this
+// exercise serialisation, authentication and access controls the same way a
+// real deployment does.
+
+use actix::prelude::*;
+
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::config::Configuration;
+use crate::core::create_server_core;
+
+pub struct TestServer {
+    client: reqwest::Client,
+    addr: String,
+    sys: System,
+}
+
+impl TestServer {
+    // The client keeps a cookie store, so once it has authenticated, the
+    // session is reused for all following requests.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn addr(&self) -> &str {
+        self.addr.as_str()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.addr, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // We DO NOT need teardown, as sqlite is in mem
+        // let the tables hit the floor
+        self.sys.stop();
+    }
+}
+
+// Ask the OS for a free port. The listener is dropped straight away so the
+// server can bind it - there is a small window where something else could
+// take the port, but that's acceptable for tests.
+fn ephemeral_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind ephemeral port");
+    let addr = listener
+        .local_addr()
+        .expect("Unable to determine ephemeral port");
+    format!("127.0.0.1:{}", addr.port())
+}
+
+pub fn spawn_test_server() -> TestServer {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+
+    let mut config = Configuration::new();
+    config.address = ephemeral_address();
+    // An empty db path is an in memory database.
+    config.db_path = String::from("");
+    let addr = format!("http://{}", config.address);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().expect("Test server failed to start");
+    System::set_current(sys.clone());
+
+    let client = reqwest::Client::builder()
+        .cookie_store(true)
+        .build()
+        .expect("Unexpected reqwest builder failure!");
+
+    TestServer {
+        client: client,
+        addr: addr,
+        sys: sys,
+    }
+}
@@ -0,0 +1,477 @@
+// Typed attribute values.
+//
+// An entry's values arrive as strings - from the protocol, from json in tests
+// and from older databases. Until the entry is normalised with schema we don't
+// know what syntax they are, so they are held as Utf8. Normalisation turns
+// them into the typed value for their attribute's syntax, so that the rest of
+// the server can ask for a uuid or a bool rather than re-parsing strings.
+//
+// Values compare by their normalised string form. This means a typed value
+// and an untyped one with the same content are equal, which keeps matching
+// correct for entries and filters that have not yet been through schema.
+
+use crate::error::SchemaError;
+use crate::filter::SubMatch;
+use crate::schema::{IndexType, SyntaxType};
+
+use chrono::{DateTime, Utc};
+use openssl::base64;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use url::Url;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Utf8(String),
+    // Case insensitive, stored lowercased.
+    Iutf8(String),
+    Principal(String),
+    Uuid(Uuid),
+    Bool(bool),
+    Syntax(SyntaxType),
+    Index(IndexType),
+    // A uuid that must refer to another entry. Refint checks this.
+    Reference(Uuid),
+    JsonFilter(String),
+    // Always held in UTC.
+    DateTime(DateTime<Utc>),
+    Url(Url),
+    Integer(i64),
+    // An ssh public key - its tag, and the key in authorized_keys form.
+    SshKey(String, String),
+}
+
+// The key types OpenSSH currently accepts. ssh-dss is left out, as recent
+// versions refuse it.
+const SSHKEY_TYPES: [&'static str; 7] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+// An ssh public key is held as "tag: key", so that a person with several
+// can tell them apart. The key is as it would be in authorized_keys:
+// "type base64 [comment]". Gives the tag and the key with single spaces.
+pub fn parse_sshkey(v: &str) -> Result<(String, String), String> {
+    let (tag, key) = match v.find(':') {
+        Some(i) => (v[..i].trim(), v[i + 1..].trim()),
+        None => return Err("no tag".to_string()),
+    };
+    if !is_sshkey_tag(tag) {
+        return Err(format!("invalid tag {:?}", tag));
+    }
+    parse_openssh_key(key).map(|key| (tag.to_string(), key))
+}
+
+fn is_sshkey_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 64
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '@')
+}
+
+// The key data names its type again, which must agree with the one given.
+fn parse_openssh_key(key: &str) -> Result<String, String> {
+    let mut parts = key.split_whitespace();
+    let ktype = parts.next().ok_or_else(|| "no key".to_string())?;
+    if !SSHKEY_TYPES.contains(&ktype) {
+        return Err(format!("unsupported key type {}", ktype));
+    }
+    let data = parts.next().ok_or_else(|| "no key data".to_string())?;
+    let blob = base64::decode_block(data).map_err(|_| "key data isn't base64".to_string())?;
+    let named = if blob.len() >= 4 {
+        let len = ((blob[0] as usize) << 24)
+            | ((blob[1] as usize) << 16)
+            | ((blob[2] as usize) << 8)
+            | (blob[3] as usize);
+        blob.get(4..4 + len)
+    } else {
+        None
+    };
+    if named != Some(ktype.as_bytes()) {
+        return Err(format!("key data isn't a {} key", ktype));
+    }
+    let comment: Vec<&str> = parts.collect();
+    Ok(if comment.is_empty() {
+        format!("{} {}", ktype, data)
+    } else {
+        format!("{} {} {}", ktype, data, comment.join(" "))
+    })
+}
+
+// Keys stored before they were tagged have none, so are tagged with their
+// comment if it makes a tag, and otherwise with their type.
+pub fn normalise_sshkey(v: &str) -> String {
+    if let Ok((tag, key)) = parse_sshkey(v) {
+        return format!("{}: {}", tag, key);
+    }
+    match parse_openssh_key(v) {
+        Ok(key) => {
+            let mut parts = key.splitn(3, ' ');
+            let ktype = parts.next().unwrap_or("key");
+            let tag = parts.nth(1).filter(|c| is_sshkey_tag(c)).unwrap_or(ktype);
+            format!("{}: {}", tag, key)
+        }
+        Err(_) => v.to_string(),
+    }
+}
+
+impl Value {
+    // Build the typed value for a syntax from its normalised string form.
+    pub fn new(syntax: &SyntaxType, v: &str) -> Result<Self, SchemaError> {
+        match syntax {
+            SyntaxType::UTF8STRING => Ok(Value::Utf8(v.to_string())),
+            SyntaxType::UTF8STRING_INSENSITIVE => Ok(Value::Iutf8(v.to_lowercase())),
+            SyntaxType::UTF8STRING_PRINCIPAL => Ok(Value::Principal(v.to_lowercase())),
+            SyntaxType::UUID => Uuid::parse_str(v)
+                .map(Value::Uuid)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::REFERENCE_UUID => Uuid::parse_str(v)
+                .map(Value::Reference)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::BOOLEAN => bool::from_str(v)
+                .map(Value::Bool)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::SYNTAX_ID => SyntaxType::try_from(v.to_uppercase().as_str())
+                .map(Value::Syntax)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::INDEX_ID => IndexType::try_from(v.to_uppercase().as_str())
+                .map(Value::Index)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::JSON_FILTER => Ok(Value::JsonFilter(v.to_string())),
+            SyntaxType::DATETIME => DateTime::parse_from_rfc3339(v)
+                .map(|dt| Value::DateTime(dt.with_timezone(&Utc)))
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::URL => Url::parse(v)
+                .map(Value::Url)
+                .map_err(|e| SchemaError::InvalidUrl(v.to_string(), e.to_string())),
+            SyntaxType::INTEGER => i64::from_str(v)
+                .map(Value::Integer)
+                .map_err(|_| SchemaError::InvalidAttributeSyntax),
+            SyntaxType::SSHKEY => parse_sshkey(v)
+                .map(|(tag, key)| Value::SshKey(tag, key))
+                .map_err(|e| SchemaError::InvalidSshKey(v.to_string(), e)),
+        }
+    }
+
+    // Has this value been typed with schema yet?
+    #[allow(dead_code)]
+    pub fn is_utf8(&self) -> bool {
+        match self {
+            Value::Utf8(_) => true,
+            _ => false,
+        }
+    }
+
+    // The string of a string backed value.
+    pub fn to_str(&self) -> Option<&str> {
+        match self {
+            Value::Utf8(s) | Value::Iutf8(s) | Value::Principal(s) | Value::JsonFilter(s) => {
+                Some(s.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn to_uuid(&self) -> Option<&Uuid> {
+        match self {
+            Value::Uuid(u) | Value::Reference(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn to_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn to_syntax(&self) -> Option<&SyntaxType> {
+        match self {
+            Value::Syntax(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn to_index(&self) -> Option<&IndexType> {
+        match self {
+            Value::Index(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    // Order two values of the same ordered syntax. Unlike cmp, this is by
+    // the values themselves rather than their string forms, and there is
+    // no answer for values that can't be ordered against each other.
+    pub fn cmp_ordered(&self, rhs: &Value) -> Option<Ordering> {
+        match (self, rhs) {
+            (Value::DateTime(a), Value::DateTime(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn to_datetime(&self) -> Option<&DateTime<Utc>> {
+        match self {
+            Value::DateTime(dt) => Some(dt),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn to_url(&self) -> Option<&Url> {
+        match self {
+            Value::Url(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn to_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    // The key, without its tag.
+    pub fn to_sshkey(&self) -> Option<&str> {
+        match self {
+            Value::SshKey(_, key) => Some(key.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, subvalue: &str) -> bool {
+        self.as_cow().contains(subvalue)
+    }
+
+    pub fn matches_sub(&self, sub: &SubMatch) -> bool {
+        sub.matches(&self.as_cow())
+    }
+
+    // Roughly how much memory this value takes.
+    pub fn approx_size(&self) -> usize {
+        mem::size_of::<Value>() + self.as_cow().len()
+    }
+
+    // The normalised string form, which is what we compare, index and send
+    // to clients.
+    fn as_cow(&self) -> Cow<str> {
+        match self {
+            Value::Utf8(s) | Value::Iutf8(s) | Value::Principal(s) | Value::JsonFilter(s) => {
+                Cow::Borrowed(s.as_str())
+            }
+            Value::Uuid(u) | Value::Reference(u) => Cow::Owned(u.to_hyphenated().to_string()),
+            Value::Bool(true) => Cow::Borrowed("true"),
+            Value::Bool(false) => Cow::Borrowed("false"),
+            Value::Syntax(s) => Cow::Owned(s.to_string()),
+            Value::Index(i) => Cow::Owned(i.to_string()),
+            Value::DateTime(dt) => Cow::Owned(dt.to_rfc3339()),
+            Value::Url(u) => Cow::Borrowed(u.as_str()),
+            Value::Integer(i) => Cow::Owned(i.to_string()),
+            Value::SshKey(tag, key) => Cow::Owned(format!("{}: {}", tag, key)),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_cow())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Utf8(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Utf8(s)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, rhs: &Value) -> bool {
+        self.as_cow() == rhs.as_cow()
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, rhs: &Value) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, rhs: &Value) -> Ordering {
+        self.as_cow().cmp(&rhs.as_cow())
+    }
+}
+
+// On the wire and in json a value is just its string. It's typed again when
+// the entry or filter it's in is checked with schema.
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.as_cow())
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string value")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Value::from(v))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Value::from(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_string(ValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::SchemaError;
+    use crate::schema::{IndexType, SyntaxType};
+    use crate::value::{normalise_sshkey, parse_sshkey, Value};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn test_value_new() {
+        assert!(
+            Value::new(&SyntaxType::UTF8STRING_INSENSITIVE, "TestPerson")
+                == Ok(Value::Iutf8("testperson".to_string()))
+        );
+        let u = Value::new(&SyntaxType::UUID, "DB237E8A-0079-4B8C-8A56-593B22AA44D1")
+            .expect("Failed to parse uuid");
+        assert!(u.to_string() == "db237e8a-0079-4b8c-8a56-593b22aa44d1");
+        assert!(u.to_uuid().is_some());
+        assert!(Value::new(&SyntaxType::BOOLEAN, "true").map(|v| v.to_bool()) == Ok(Some(true)));
+        assert!(
+            Value::new(&SyntaxType::INDEX_ID, "equality")
+                .map(|v| v.to_index() == Some(&IndexType::EQUALITY))
+                == Ok(true)
+        );
+        assert!(
+            Value::new(&SyntaxType::BOOLEAN, "yes") == Err(SchemaError::InvalidAttributeSyntax)
+        );
+        assert!(
+            Value::new(&SyntaxType::REFERENCE_UUID, "testperson")
+                == Err(SchemaError::InvalidAttributeSyntax)
+        );
+        // Times are kept in UTC.
+        let dt = Value::new(&SyntaxType::DATETIME, "2019-07-01T10:00:00+10:00")
+            .expect("Failed to parse datetime");
+        assert!(dt.to_string() == "2019-07-01T00:00:00+00:00");
+        assert!(dt.to_datetime().is_some());
+        assert!(
+            Value::new(&SyntaxType::DATETIME, "yesterday")
+                == Err(SchemaError::InvalidAttributeSyntax)
+        );
+        let url = Value::new(&SyntaxType::URL, "HTTPS://Example.COM/callback")
+            .expect("Failed to parse url");
+        assert!(url.to_string() == "https://example.com/callback");
+        assert!(url.to_url().map(|u| u.scheme()) == Some("https"));
+        let i = Value::new(&SyntaxType::INTEGER, "-12").expect("Failed to parse integer");
+        assert!(i.to_integer() == Some(-12));
+        assert!(
+            Value::new(&SyntaxType::INTEGER, "1e3") == Err(SchemaError::InvalidAttributeSyntax)
+        );
+    }
+
+    #[test]
+    fn test_value_sshkey() {
+        let ed25519 =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+        let k = Value::new(
+            &SyntaxType::SSHKEY,
+            format!("laptop: {}  william@laptop", ed25519).as_str(),
+        )
+        .expect("Failed to parse ssh key");
+        assert!(k.to_string() == format!("laptop: {} william@laptop", ed25519));
+        assert!(k.to_sshkey() == Some(format!("{} william@laptop", ed25519).as_str()));
+
+        // Keys need a tag, a type we know, and data of that type.
+        assert!(parse_sshkey(ed25519).is_err());
+        assert!(parse_sshkey(format!("my laptop: {}", ed25519).as_str()).is_err());
+        assert!(parse_sshkey("laptop: ssh-dss AAAAB3NzaC1kc3M=").is_err());
+        assert!(parse_sshkey("laptop: ssh-ed25519 !!!").is_err());
+        assert!(parse_sshkey(
+            "laptop: ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f"
+        )
+        .is_err());
+        assert!(parse_sshkey("laptop: ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAABADBAgM=").is_ok());
+
+        // Untagged keys are tagged when normalised.
+        assert!(
+            normalise_sshkey(format!(" {} william@laptop ", ed25519).as_str())
+                == format!("william@laptop: {} william@laptop", ed25519)
+        );
+        assert!(
+            normalise_sshkey(format!("{} a comment", ed25519).as_str())
+                == format!("ssh-ed25519: {} a comment", ed25519)
+        );
+        assert!(normalise_sshkey("nonsense") == "nonsense");
+    }
+
+    #[test]
+    fn test_value_cmp() {
+        // Typed and untyped values with the same content are the same value.
+        let u = Value::new(&SyntaxType::UUID, "db237e8a-0079-4b8c-8a56-593b22aa44d1")
+            .expect("Failed to parse uuid");
+        assert!(u == Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        assert!(Value::Bool(false) < Value::Bool(true));
+        // Integers order by number where it matters, not by their strings.
+        let (nine, ten) = (Value::Integer(9), Value::Integer(10));
+        assert!(nine > ten);
+        assert!(nine.cmp_ordered(&ten) == Some(Ordering::Less));
+        assert!(Value::from("a") != Value::from("A"));
+        assert!(Value::from("william").contains("lli"));
+
+        let v: Value = serde_json::from_str("\"william\"").expect("json parse failure");
+        assert!(v.is_utf8());
+        assert!(serde_json::to_string(&Value::Bool(true)).expect("json failure") == "\"true\"");
+    }
+}
@@ -0,0 +1,336 @@
+// Checking that a database is consistent.
+//
+// Verification is a list of checks, each looking for one kind of problem and
+// reporting every instance of it that it finds, so a damaged database can be
+// assessed as a whole rather than one fault at a time. The checks run in
+// order, and only the backend and schema checks stop the rest - if entries
+// can't be read, or the schema itself is broken, nothing after can be trusted.
+//
+// The plugins that maintain references, memberships and unique values check
+// their own work, and are reported as checks of their own.
+
+use std::collections::BTreeSet;
+
+use crate::audit::AuditScope;
+use crate::be::BackendTransaction;
+use crate::error::{ConsistencyError, SchemaError};
+use crate::plugins::Plugins;
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServerReadTransaction, QueryServerTransaction};
+
+pub trait VerifyCheck {
+    fn id() -> &'static str;
+
+    // When a check fails, are the checks after it still meaningful?
+    fn fatal() -> bool {
+        false
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>>;
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub check: &'static str,
+    // Set when the check wasn't run, because an earlier one failed.
+    pub skipped: bool,
+    pub errors: Vec<ConsistencyError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub checks: Vec<CheckReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.errors.is_empty())
+    }
+
+    // Every error found, in the order of the checks.
+    pub fn into_results(self) -> Vec<Result<(), ConsistencyError>> {
+        self.checks
+            .into_iter()
+            .flat_map(|c| c.errors.into_iter().map(Err))
+            .collect()
+    }
+}
+
+// Entries moved to quarantine because they couldn't be read.
+struct BackendCheck;
+
+impl VerifyCheck for BackendCheck {
+    fn id() -> &'static str {
+        "backend"
+    }
+
+    fn fatal() -> bool {
+        true
+    }
+
+    fn verify(
+        _au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        qs.get_be_txn().verify()
+    }
+}
+
+// The in memory schema, as loaded from the schema entries.
+struct SchemaCheck;
+
+impl VerifyCheck for SchemaCheck {
+    fn id() -> &'static str {
+        "schema"
+    }
+
+    fn fatal() -> bool {
+        true
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        qs.get_schema().validate(au)
+    }
+}
+
+// Every index key, against what the entries say it should hold.
+struct IndexCheck;
+
+impl VerifyCheck for IndexCheck {
+    fn id() -> &'static str {
+        "indexes"
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        qs.get_be_txn().verify_indexes(au)
+    }
+}
+
+// Every committed entry, including recycled entries and tombstones, against
+// the schema as it is now.
+//
+// A class or attribute may be removed from schema while entries still carry
+// it - they are kept as they are, and only new writes are refused. So what
+// schema no longer defines is set aside before an entry is checked. Once a
+// class is gone there's no knowing which attributes it allowed, so an entry
+// that had one is only checked for its remaining must attributes and the
+// syntax of its values.
+struct EntrySchemaCheck;
+
+impl VerifyCheck for EntrySchemaCheck {
+    fn id() -> &'static str {
+        "entry_schema"
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let entries = match qs.internal_search(au, filter_all!(f_pres("class"))) {
+            Ok(e) => e,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+        let schema = qs.get_schema();
+        let classes = schema.get_classes();
+        let attributes = schema.get_attributes();
+        entries
+            .into_iter()
+            .filter_map(|e| {
+                let id = e.get_id();
+                let removed_classes: Vec<String> = e
+                    .classes()
+                    .map(|cs| {
+                        cs.filter(|c| !classes.contains_key(*c))
+                            .map(|c| c.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let removed_attrs: Vec<String> = e
+                    .get_ava_names()
+                    .into_iter()
+                    .filter(|a| !attributes.contains_key(*a))
+                    .map(|a| a.to_string())
+                    .collect();
+
+                let mut e = e.invalidate();
+                removed_classes
+                    .iter()
+                    .for_each(|c| e.remove_ava("class", c.as_str()));
+                removed_attrs.iter().for_each(|a| e.purge_ava(a.as_str()));
+
+                e.validate(schema)
+                    .err()
+                    .map(|errs| {
+                        errs.into_iter()
+                            .filter(|err| match err {
+                                SchemaError::InvalidAttribute(_) => removed_classes.is_empty(),
+                                _ => true,
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|errs| !errs.is_empty())
+                    .map(|errs| Err(ConsistencyError::EntrySchemaInvalid(id, errs)))
+            })
+            .collect()
+    }
+}
+
+// A tombstone only remembers that an entry existed: it holds the uuid and
+// its classes, and nothing else.
+struct TombstoneCheck;
+
+impl VerifyCheck for TombstoneCheck {
+    fn id() -> &'static str {
+        "tombstones"
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let entries = match qs.internal_search(au, filter_all!(f_eq("class", "tombstone"))) {
+            Ok(e) => e,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+        let attrs: BTreeSet<&str> = ["class", "uuid"].iter().cloned().collect();
+        let classes: BTreeSet<&str> = ["object", "tombstone"].iter().cloned().collect();
+        entries
+            .iter()
+            .filter(|e| {
+                e.get_ava_names() != attrs || e.get_ava_set("class").unwrap_or_default() != classes
+            })
+            .map(|e| Err(ConsistencyError::TombstoneInvalid(e.get_id())))
+            .collect()
+    }
+}
+
+macro_rules! run_verify_check {
+    (
+        $au:ident,
+        $qs:ident,
+        $checks:expr,
+        $failed:ident,
+        $target_check:ty
+    ) => {{
+        if $failed {
+            $checks.push(CheckReport {
+                check: <($target_check)>::id(),
+                skipped: true,
+                errors: Vec::new(),
+            });
+        } else {
+            let mut audit_scope = AuditScope::new(<($target_check)>::id());
+            let r = audit_segment!(audit_scope, || <($target_check)>::verify(
+                &mut audit_scope,
+                $qs,
+            ));
+            $au.append_scope(audit_scope);
+            let errors: Vec<ConsistencyError> = r.into_iter().filter_map(|r| r.err()).collect();
+            $failed = <($target_check)>::fatal() && !errors.is_empty();
+            $checks.push(CheckReport {
+                check: <($target_check)>::id(),
+                skipped: false,
+                errors: errors,
+            });
+        }
+    }};
+}
+
+pub fn run_verify(au: &mut AuditScope, qs: &QueryServerReadTransaction) -> VerifyReport {
+    let mut checks = Vec::new();
+    let mut failed = false;
+    run_verify_check!(au, qs, checks, failed, BackendCheck);
+    run_verify_check!(au, qs, checks, failed, SchemaCheck);
+    run_verify_check!(au, qs, checks, failed, IndexCheck);
+    run_verify_check!(au, qs, checks, failed, EntrySchemaCheck);
+    run_verify_check!(au, qs, checks, failed, TombstoneCheck);
+
+    if failed {
+        // The plugin checks are still listed, so the report shows all that
+        // wasn't looked at.
+        checks.extend(Plugins::verify_ids().into_iter().map(|id| CheckReport {
+            check: id,
+            skipped: true,
+            errors: Vec::new(),
+        }));
+    } else {
+        checks.extend(
+            Plugins::run_verify(au, qs)
+                .into_iter()
+                .map(|(id, r)| CheckReport {
+                    check: id,
+                    skipped: false,
+                    errors: r.into_iter().filter_map(|r| r.err()).collect(),
+                }),
+        );
+    }
+    VerifyReport { checks: checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::error::ConsistencyError;
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+
+    #[test]
+    fn test_verify_report() {
+        // Not run_test!, as this leaves the database inconsistent on purpose.
+        let mut audit = AuditScope::new("test_verify_report");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let server = QueryServer::new(be, schema);
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let report = server.verify_report(&mut audit);
+        assert!(report.is_ok());
+        let ids: Vec<&str> = report.checks.iter().map(|c| c.check).collect();
+        assert!(ids.starts_with(&["backend", "schema", "indexes", "entry_schema", "tombstones"]));
+        assert!(ids.contains(&"plugin_base"));
+        assert!(report.checks.iter().all(|c| !c.skipped));
+
+        // A tombstone that kept more than its uuid.
+        let mut server_txn = server.write();
+        let e: Entry<EntryInvalid, EntryNew> = serde_json::from_str(
+            r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["tombstone", "object"],
+                    "uuid": ["9557f49c-97a5-4277-a9a5-097d17eb8318"],
+                    "description": ["left behind"]
+                }
+            }"#,
+        )
+        .expect("json failure");
+        assert!(server_txn.internal_create(&mut audit, vec![e]).is_ok());
+        assert!(server_txn.commit(&mut audit).is_ok());
+
+        let report = server.verify_report(&mut audit);
+        assert!(!report.is_ok());
+        let ts = report
+            .checks
+            .iter()
+            .find(|c| c.check == "tombstones")
+            .expect("no tombstone check");
+        match ts.errors.as_slice() {
+            [ConsistencyError::TombstoneInvalid(_)] => {}
+            e => panic!("unexpected errors {:?}", e),
+        }
+        // The other checks still ran.
+        assert!(report.checks.iter().all(|c| !c.skipped));
+        assert!(server.verify(&mut audit).len() >= 1);
+    }
+}
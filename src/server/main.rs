@@ -8,20 +8,41 @@ extern crate structopt;
 #[macro_use]
 extern crate log;
 
-use rsidm::config::Configuration;
+use rsidm::config::{Configuration, DbSync};
+use rsidm::constants::BREAK_GLASS_DEFAULT_LIFETIME;
 use rsidm::core::{
-    backup_server_core, create_server_core, restore_server_core, verify_server_core,
+    backup_server_core, break_glass_core, create_server_core, export_server_core,
+    import_server_core, quarantine_server_core, recover_account_core, reindex_server_core,
+    rekey_server_core, restore_server_core, verify_server_core,
 };
 
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+fn parse_db_sync(s: &str) -> Result<DbSync, String> {
+    match s {
+        "full" => Ok(DbSync::Full),
+        "normal" => Ok(DbSync::Normal),
+        "off" => Ok(DbSync::Off),
+        _ => Err(format!(
+            "unknown synchronous mode {}, expected full, normal or off",
+            s
+        )),
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct ServerOpt {
     #[structopt(short = "d", long = "debug")]
     debug: bool,
     #[structopt(parse(from_os_str), short = "D", long = "db_path")]
     db_path: PathBuf,
+    // The database is encrypted at rest with the hex key in this file.
+    #[structopt(parse(from_os_str), long = "db_key_file")]
+    db_key_file: Option<PathBuf>,
+    // The name a new domain is given. An existing domain keeps its own.
+    #[structopt(long = "domain")]
+    domain: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,16 +61,172 @@ struct RestoreOpt {
     serveropts: ServerOpt,
 }
 
+// Entries as json, one per line, for reading and editing by hand.
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RecoverAccountOpt {
+    // The name of the account to give a new password.
+    #[structopt(short = "n", long = "name")]
+    name: String,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct BreakGlassOpt {
+    // The account to give an emergency secret.
+    #[structopt(short = "n", long = "name")]
+    name: String,
+    // Seconds until the secret, and any session from it, expire.
+    #[structopt(long = "lifetime")]
+    lifetime: Option<u64>,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RekeyOpt {
+    // The file holding the key to re-encrypt the database with.
+    #[structopt(parse(from_os_str))]
+    new_key_file: PathBuf,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct QuarantineOpt {
+    // Write the raw bytes of this quarantined entry to --out.
+    #[structopt(long = "dump")]
+    dump: Option<u64>,
+    #[structopt(parse(from_os_str), long = "out")]
+    out: Option<PathBuf>,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpt {
+    // Accept requests containing unknown fields, for older clients.
+    #[structopt(long = "lenient_requests")]
+    lenient_requests: bool,
+    // Refuse access control profiles without a description.
+    #[structopt(long = "acp_require_metadata")]
+    acp_require_metadata: bool,
+    // Let sqlite memory map up to this many bytes of the database for reads.
+    #[structopt(long = "db_mmap_size", default_value = "0")]
+    db_mmap_size: u64,
+    // How sqlite syncs commits: full, normal or off.
+    #[structopt(long = "db_synchronous", parse(try_from_str = "parse_db_sync"))]
+    db_synchronous: Option<DbSync>,
+    // Use a rollback journal instead of a write-ahead log.
+    #[structopt(long = "db_no_wal")]
+    db_no_wal: bool,
+    // With synchronous normal, sync the log this often, in milliseconds.
+    #[structopt(long = "db_group_commit_ms")]
+    db_group_commit_ms: Option<u64>,
+    // Seconds before recycled entries become tombstones.
+    #[structopt(long = "recycle_window")]
+    recycle_window: Option<u64>,
+    // Seconds before tombstones are removed.
+    #[structopt(long = "tombstone_window")]
+    tombstone_window: Option<u64>,
+    // Log every committed change to an entry.
+    #[structopt(long = "log_changes")]
+    log_changes: bool,
+    // Failed auths from one address in 5 minutes before an alert, 0 for none.
+    #[structopt(long = "anomaly_auth_failures")]
+    anomaly_auth_failures: Option<usize>,
+    // Access denials for one account in an hour before an alert, 0 for none.
+    #[structopt(long = "anomaly_access_denied")]
+    anomaly_access_denied: Option<usize>,
+    // Entries deleted in an hour before an alert, 0 for none.
+    #[structopt(long = "anomaly_deletes")]
+    anomaly_deletes: Option<usize>,
+    // Post each alert as json to this url, as well as logging it.
+    #[structopt(long = "anomaly_webhook")]
+    anomaly_webhook: Option<String>,
+    // PEM file of the CAs that issue client certificates.
+    #[structopt(long = "client_ca")]
+    client_ca: Option<String>,
+    // Header the TLS proxy puts the client certificate in.
+    #[structopt(long = "client_cert_header")]
+    client_cert_header: Option<String>,
+    // Address of a TLS proxy allowed to send that header. May be repeated.
+    #[structopt(long = "client_cert_proxy")]
+    client_cert_proxy: Vec<String>,
+    // Directory to write backups requested through the api to.
+    #[structopt(long = "backup_path")]
+    backup_path: Option<String>,
+    // File to keep the audit records of security relevant operations in.
+    #[structopt(long = "audit_log_path")]
+    audit_log_path: Option<String>,
+    // Largest request body, in bytes.
+    #[structopt(long = "maximum_request")]
+    maximum_request: Option<usize>,
+    // Most entries in one create request.
+    #[structopt(long = "maximum_create_entries")]
+    maximum_create_entries: Option<usize>,
+    // Most changes in one modify request.
+    #[structopt(long = "maximum_modlist")]
+    maximum_modlist: Option<usize>,
+    // Deepest nesting of a filter.
+    #[structopt(long = "filter_max_depth")]
+    filter_max_depth: Option<usize>,
+    // Most terms in a filter.
+    #[structopt(long = "filter_max_terms")]
+    filter_max_terms: Option<usize>,
+    // Most entries in a search result, or a page of one.
+    #[structopt(long = "search_max_results")]
+    search_max_results: Option<usize>,
+    // Fewest entries a search scans before it uses every core.
+    #[structopt(long = "parallel_search_min")]
+    parallel_search_min: Option<usize>,
+    // Most ids an index key may hold and still narrow a search.
+    #[structopt(long = "idl_allids_threshold")]
+    idl_allids_threshold: Option<usize>,
+    // Address to serve read only ldap on, such as 127.0.0.1:3389.
+    #[structopt(long = "ldap_address")]
+    ldap_address: Option<String>,
+    // PEM files of the certificate chain and key to serve ldap over TLS with.
+    #[structopt(long = "ldap_tls_chain")]
+    ldap_tls_chain: Option<String>,
+    #[structopt(long = "ldap_tls_key")]
+    ldap_tls_key: Option<String>,
+    #[structopt(flatten)]
+    serveropts: ServerOpt,
+}
+
 #[derive(Debug, StructOpt)]
 enum Opt {
     #[structopt(name = "server")]
-    Server(ServerOpt),
+    Server(RunOpt),
     #[structopt(name = "backup")]
     Backup(BackupOpt),
     #[structopt(name = "restore")]
     Restore(RestoreOpt),
     #[structopt(name = "verify")]
     Verify(ServerOpt),
+    #[structopt(name = "rekey")]
+    Rekey(RekeyOpt),
+    #[structopt(name = "quarantine")]
+    Quarantine(QuarantineOpt),
+    #[structopt(name = "reindex")]
+    Reindex(ServerOpt),
+    #[structopt(name = "export")]
+    Export(ExportOpt),
+    #[structopt(name = "import")]
+    Import(ExportOpt),
+    #[structopt(name = "recover_account")]
+    RecoverAccount(RecoverAccountOpt),
+    #[structopt(name = "break_glass")]
+    BreakGlass(BreakGlassOpt),
 }
 
 fn main() {
@@ -66,10 +243,73 @@ fn main() {
     env_logger::init();
 
     match opt {
-        Opt::Server(sopt) => {
+        Opt::Server(ropt) => {
             info!("Running in server mode ...");
 
-            config.update_db_path(&sopt.db_path);
+            config.update_db_path(&ropt.serveropts.db_path);
+            config.update_domain(&ropt.serveropts.domain);
+            config.update_db_key_file(&ropt.serveropts.db_key_file);
+            config.strict_requests = !ropt.lenient_requests;
+            config.acp_require_metadata = ropt.acp_require_metadata;
+            config.db_mmap_size = ropt.db_mmap_size;
+            if let Some(s) = ropt.db_synchronous {
+                config.db_durability.synchronous = s;
+            }
+            config.db_durability.wal = !ropt.db_no_wal;
+            if let Some(ms) = ropt.db_group_commit_ms {
+                config.db_durability.group_commit_ms = ms;
+            }
+            config.log_changes = ropt.log_changes;
+            config.client_ca = ropt.client_ca;
+            config.client_cert_header = ropt.client_cert_header;
+            config.client_cert_proxies = ropt.client_cert_proxy;
+            config.backup_path = ropt.backup_path;
+            config.audit_log_path = ropt.audit_log_path;
+            if let Some(a) = ropt.anomaly_auth_failures {
+                config.anomaly_auth_failures = a;
+            }
+            if let Some(a) = ropt.anomaly_access_denied {
+                config.anomaly_access_denied = a;
+            }
+            if let Some(a) = ropt.anomaly_deletes {
+                config.anomaly_deletes = a;
+            }
+            config.anomaly_webhook = ropt.anomaly_webhook;
+            if let Some(w) = ropt.recycle_window {
+                config.recycle_window = w;
+            }
+            if let Some(w) = ropt.tombstone_window {
+                config.tombstone_window = w;
+            }
+            if let Some(m) = ropt.maximum_request {
+                config.maximum_request = m;
+            }
+            if let Some(m) = ropt.maximum_create_entries {
+                config.maximum_create_entries = m;
+            }
+            if let Some(m) = ropt.maximum_modlist {
+                config.maximum_modlist = m;
+            }
+            if let Some(m) = ropt.filter_max_depth {
+                config.filter_max_depth = m;
+            }
+            if let Some(m) = ropt.filter_max_terms {
+                config.filter_max_terms = m;
+            }
+            if let Some(m) = ropt.search_max_results {
+                config.search_max_results = m;
+            }
+            if ropt.parallel_search_min.is_some() {
+                config.parallel_search_min = ropt.parallel_search_min;
+            }
+            if let Some(m) = ropt.idl_allids_threshold {
+                config.idl_allids_threshold = m;
+            }
+            if ropt.ldap_address.is_some() {
+                config.ldap_address = ropt.ldap_address;
+            }
+            config.ldap_tls_chain = ropt.ldap_tls_chain;
+            config.ldap_tls_key = ropt.ldap_tls_key;
 
             let sys = actix::System::new("rsidm-server");
             create_server_core(config);
@@ -79,6 +319,7 @@ fn main() {
             info!("Running in backup mode ...");
 
             config.update_db_path(&bopt.serveropts.db_path);
+            config.update_db_key_file(&bopt.serveropts.db_key_file);
 
             let p = match bopt.path.to_str() {
                 Some(p) => p,
@@ -93,6 +334,7 @@ fn main() {
             info!("Running in restore mode ...");
 
             config.update_db_path(&ropt.serveropts.db_path);
+            config.update_db_key_file(&ropt.serveropts.db_key_file);
 
             let p = match ropt.path.to_str() {
                 Some(p) => p,
@@ -107,7 +349,100 @@ fn main() {
             info!("Running in restore mode ...");
 
             config.update_db_path(&vopt.db_path);
+            config.update_db_key_file(&vopt.db_key_file);
             verify_server_core(config);
         }
+        Opt::Rekey(kopt) => {
+            info!("Running in rekey mode ...");
+
+            config.update_db_path(&kopt.serveropts.db_path);
+            config.update_db_key_file(&kopt.serveropts.db_key_file);
+
+            let p = match kopt.new_key_file.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid new key file path");
+                    std::process::exit(1);
+                }
+            };
+            rekey_server_core(config, p);
+        }
+        Opt::Quarantine(qopt) => {
+            info!("Running in quarantine mode ...");
+
+            config.update_db_path(&qopt.serveropts.db_path);
+            config.update_db_key_file(&qopt.serveropts.db_key_file);
+
+            let dump = match (qopt.dump, qopt.out.as_ref()) {
+                (None, None) => None,
+                (Some(id), Some(out)) => match out.to_str() {
+                    Some(p) => Some((id, p)),
+                    None => {
+                        error!("Invalid dump path");
+                        std::process::exit(1);
+                    }
+                },
+                _ => {
+                    error!("--dump and --out must be given together");
+                    std::process::exit(1);
+                }
+            };
+            quarantine_server_core(config, dump);
+        }
+        Opt::Reindex(sopt) => {
+            info!("Running in reindex mode ...");
+
+            config.update_db_path(&sopt.db_path);
+            config.update_db_key_file(&sopt.db_key_file);
+            reindex_server_core(config);
+        }
+        Opt::Export(eopt) => {
+            info!("Running in export mode ...");
+
+            config.update_db_path(&eopt.serveropts.db_path);
+            config.update_db_key_file(&eopt.serveropts.db_key_file);
+
+            let p = match eopt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid export path");
+                    std::process::exit(1);
+                }
+            };
+            export_server_core(config, p);
+        }
+        Opt::Import(iopt) => {
+            info!("Running in import mode ...");
+
+            config.update_db_path(&iopt.serveropts.db_path);
+            config.update_domain(&iopt.serveropts.domain);
+            config.update_db_key_file(&iopt.serveropts.db_key_file);
+
+            let p = match iopt.path.to_str() {
+                Some(p) => p,
+                None => {
+                    error!("Invalid import path");
+                    std::process::exit(1);
+                }
+            };
+            import_server_core(config, p);
+        }
+        Opt::RecoverAccount(ropt) => {
+            info!("Running account recovery ...");
+
+            config.update_db_path(&ropt.serveropts.db_path);
+            config.update_db_key_file(&ropt.serveropts.db_key_file);
+            config.update_domain(&ropt.serveropts.domain);
+            recover_account_core(config, ropt.name.as_str());
+        }
+        Opt::BreakGlass(bopt) => {
+            info!("Running break glass ...");
+
+            config.update_db_path(&bopt.serveropts.db_path);
+            config.update_db_key_file(&bopt.serveropts.db_key_file);
+            config.update_domain(&bopt.serveropts.domain);
+            let lifetime = bopt.lifetime.unwrap_or(BREAK_GLASS_DEFAULT_LIFETIME);
+            break_glass_core(config, bopt.name.as_str(), lifetime);
+        }
     }
 }
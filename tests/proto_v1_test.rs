@@ -3,17 +3,13 @@
 #[macro_use]
 extern crate log;
 
-extern crate actix;
-use actix::prelude::*;
-
 extern crate rsidm;
-use rsidm::config::Configuration;
 use rsidm::constants::UUID_ADMIN;
-use rsidm::core::create_server_core;
 use rsidm::proto::v1::{
     AuthCredential, AuthRequest, AuthResponse, AuthState, AuthStep, CreateRequest, Entry,
     OperationResponse,
 };
+use rsidm::testkit::spawn_test_server;
 
 extern crate reqwest;
 
@@ -21,55 +17,15 @@ extern crate futures;
 // use futures::future;
 // use futures::future::Future;
 
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc;
-use std::thread;
-
-extern crate env_logger;
-extern crate tokio;
-
-static PORT_ALLOC: AtomicUsize = AtomicUsize::new(8080);
-
 // Test external behaviorus of the service.
 
 fn run_test(test_fn: fn(reqwest::Client, &str) -> ()) {
-    let _ = env_logger::builder().is_test(true).try_init();
-    let (tx, rx) = mpsc::channel();
-    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
-    let mut config = Configuration::new();
-    config.address = format!("127.0.0.1:{}", port);
-    // Setup the config ...
-
-    thread::spawn(move || {
-        // Spawn a thread for the test runner, this should have a unique
-        // port....
-        System::run(move || {
-            create_server_core(config);
-
-            // This appears to be bind random ...
-            // let srv = srv.bind("127.0.0.1:0").unwrap();
-            let _ = tx.send(System::current());
-        });
-    });
-    let sys = rx.recv().unwrap();
-    System::set_current(sys.clone());
+    let server = spawn_test_server();
 
     // Do we need any fixtures?
     // Yes probably, but they'll need to be futures as well ...
     // later we could accept fixture as it's own future for re-use
-
-    // Setup the client, and the address we selected.
-    let client = reqwest::Client::builder()
-        .cookie_store(true)
-        .build()
-        .expect("Unexpected reqwest builder failure!");
-    let addr = format!("http://127.0.0.1:{}", port);
-
-    test_fn(client, addr.as_str());
-
-    // We DO NOT need teardown, as sqlite is in mem
-    // let the tables hit the floor
-    let _ = sys.stop();
+    test_fn(server.client().clone(), server.addr());
 }
 
 #[test]